@@ -0,0 +1,389 @@
+use crate::datatypes::Value;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stmt {
+    Select(Select),
+    Insert(Insert),
+    Delete(Delete),
+    CreateTable(CreateTable),
+    CreateIndex(CreateIndex),
+    CreateVirtualTable(CreateVirtualTable),
+    CreateView(CreateView),
+    AlterTable(AlterTable),
+    Pragma(Pragma),
+    /// `VACUUM`, which rebuilds the database to leave out its free
+    /// pages.
+    Vacuum,
+    /// `EXPLAIN QUERY PLAN stmt` (if `query_plan` is set) or `EXPLAIN
+    /// stmt`, which describe how a statement would be run instead of
+    /// running it.
+    Explain {
+        query_plan: bool,
+        stmt: Box<Stmt>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Select {
+    /// Set for `SELECT DISTINCT`, which leaves out duplicate rows.
+    pub distinct: bool,
+    pub columns: Vec<ResultColumn>,
+    pub from: Option<TableRef>,
+    pub joins: Vec<Join>,
+    pub where_clause: Option<Expr>,
+    /// The SELECTs joined on by UNION, INTERSECT or EXCEPT, in order. When
+    /// there are any, the ORDER BY and LIMIT apply to the combined result.
+    pub compound: Vec<CompoundSelect>,
+    pub order_by: Vec<OrderingTerm>,
+    pub limit: Option<Expr>,
+    pub offset: Option<Expr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompoundSelect {
+    pub operator: CompoundOperator,
+    /// Has no ORDER BY, LIMIT or compound parts of its own.
+    pub select: Select,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompoundOperator {
+    Union,
+    UnionAll,
+    Intersect,
+    Except,
+}
+
+impl CompoundOperator {
+    pub fn name(&self) -> &'static str {
+        return match self {
+            CompoundOperator::Union => "UNION",
+            CompoundOperator::UnionAll => "UNION ALL",
+            CompoundOperator::Intersect => "INTERSECT",
+            CompoundOperator::Except => "EXCEPT",
+        };
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResultColumn {
+    /// `*`
+    Star,
+    /// `table.*`
+    TableStar(String),
+    Expr {
+        expr: Expr,
+        alias: Option<String>,
+        /// The expression as written, which names the column in the
+        /// output when there is no alias.
+        text: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableRef {
+    /// The name of the table, which is empty for a subquery.
+    pub name: String,
+    pub alias: Option<String>,
+    /// A subquery (`(SELECT ...) AS alias`), whose result is read as if
+    /// it were a table.
+    pub subquery: Option<Box<Select>>,
+    /// `TABLESAMPLE`, to read a random sample of the table's rows rather
+    /// than all of them.
+    pub sample: Option<TableSample>,
+}
+
+/// `TABLESAMPLE method (percent) [REPEATABLE (seed)]`. Each method keeps
+/// about `percent` percent of the rows, but they differ in what they
+/// read and in how good a sample they give:
+///
+/// - `BERNOULLI` keeps each row independently with probability
+///   `percent / 100`, so the number of rows kept out of `n` is binomial,
+///   with a mean of `n * percent / 100`, and any statistic of the sample
+///   is an unbiased estimate for the table. Every row is still read.
+/// - `SYSTEM` keeps each leaf page of the table independently with
+///   probability `percent / 100`, and reads only the pages kept, so it
+///   takes time in proportion to the size of the sample. Rows on the
+///   same page are kept or left out together, so when the order of the
+///   rows (by row ID) is related to their values, as with rows inserted
+///   over time, estimates vary more than those from a `BERNOULLI` sample
+///   of the same size. The number of rows kept also varies with the
+///   number of rows on each page.
+///
+/// With `REPEATABLE`, the same seed picks the same sample for as long as
+/// the table is unchanged; without it, each query picks a new one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableSample {
+    pub method: SampleMethod,
+    pub percent: Expr,
+    pub seed: Option<Expr>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleMethod {
+    Bernoulli,
+    System,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinKind {
+    Inner,
+    Cross,
+    Left,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Join {
+    pub kind: JoinKind,
+    pub table: TableRef,
+    pub on: Option<Expr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderingTerm {
+    pub expr: Expr,
+    /// The collating sequence given with `COLLATE`, if any.
+    pub collation: Option<String>,
+    pub descending: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Insert {
+    pub table: String,
+    pub columns: Option<Vec<String>>,
+    pub source: InsertSource,
+}
+
+/// Where the rows of an INSERT come from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum InsertSource {
+    /// `VALUES (...), (...)`, one list of expressions per row.
+    Values(Vec<Vec<Expr>>),
+    /// `SELECT ...`, whose rows are all found before any are inserted.
+    Select(Box<Select>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delete {
+    pub table: String,
+    pub where_clause: Option<Expr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CreateTable {
+    pub name: String,
+    pub if_not_exists: bool,
+    pub columns: Vec<ColumnDef>,
+    pub constraints: Vec<TableConstraint>,
+    pub without_rowid: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CreateIndex {
+    pub name: String,
+    pub table: String,
+    pub unique: bool,
+    pub if_not_exists: bool,
+    pub columns: Vec<IndexedColumn>,
+    /// The condition of a partial index.
+    pub where_clause: Option<Expr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CreateView {
+    pub name: String,
+    pub if_not_exists: bool,
+    /// Names for the view's columns, in place of those of the SELECT.
+    pub columns: Option<Vec<String>>,
+    pub select: Select,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CreateVirtualTable {
+    pub name: String,
+    pub if_not_exists: bool,
+    /// The name of the module implementing the table, e.g. `rtree`.
+    pub module: String,
+    /// The module arguments, as written. Their meaning is up to the
+    /// module.
+    pub args: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlterTable {
+    pub table: String,
+    pub action: AlterAction,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AlterAction {
+    RenameColumn { old: String, new: String },
+    DropColumn(String),
+}
+
+/// `PRAGMA [schema.]name`, `PRAGMA [schema.]name = value` or `PRAGMA
+/// [schema.]name(value)`. A value given as a bare word (e.g., `ON`) is
+/// text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pragma {
+    pub schema: Option<String>,
+    pub name: String,
+    pub value: Option<Value>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnDef {
+    pub name: String,
+    /// The declared type, exactly as written (e.g., `VARCHAR(255)`), if
+    /// any. SQLite derives a column's affinity from this.
+    pub type_name: Option<String>,
+    pub constraints: Vec<ColumnConstraint>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColumnConstraint {
+    PrimaryKey {
+        descending: bool,
+        autoincrement: bool,
+    },
+    NotNull,
+    Unique,
+    Check(Expr),
+    Default(Expr),
+    Collate(String),
+    References(ForeignKey),
+    Generated {
+        expr: Expr,
+        stored: bool,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TableConstraint {
+    PrimaryKey(Vec<IndexedColumn>),
+    Unique(Vec<IndexedColumn>),
+    Check(Expr),
+    ForeignKey {
+        columns: Vec<String>,
+        references: ForeignKey,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForeignKey {
+    pub table: String,
+    pub columns: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexedColumn {
+    pub name: String,
+    pub collation: Option<String>,
+    pub descending: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Literal(Value),
+    /// A parameter placeholder, identified by its 1-based index among the
+    /// statement's parameters. Its value is supplied when the statement
+    /// is executed.
+    Parameter(usize),
+    Column {
+        table: Option<String>,
+        name: String,
+    },
+    Unary {
+        op: UnaryOp,
+        expr: Box<Expr>,
+    },
+    Binary {
+        left: Box<Expr>,
+        op: BinaryOp,
+        right: Box<Expr>,
+    },
+    /// `expr IS NULL`, `expr ISNULL`, `expr NOT NULL`, etc.
+    IsNull {
+        expr: Box<Expr>,
+        negated: bool,
+    },
+    Like {
+        expr: Box<Expr>,
+        pattern: Box<Expr>,
+        escape: Option<Box<Expr>>,
+        op: LikeOp,
+        negated: bool,
+    },
+    /// `expr [NOT] BETWEEN low AND high`
+    Between {
+        expr: Box<Expr>,
+        low: Box<Expr>,
+        high: Box<Expr>,
+        negated: bool,
+    },
+    /// `expr [NOT] IN (list)` or `expr [NOT] IN (SELECT ...)`. Before a
+    /// query is run, the rows of the subquery are put in `list` in its
+    /// place.
+    In {
+        expr: Box<Expr>,
+        list: Vec<Expr>,
+        subquery: Option<Box<Select>>,
+        negated: bool,
+    },
+    /// `CAST(expr AS type_name)`
+    Cast {
+        expr: Box<Expr>,
+        type_name: String,
+    },
+    Function {
+        name: String,
+        args: Vec<Expr>,
+        distinct: bool,
+        /// Set for calls like `count(*)`.
+        star: bool,
+    },
+    /// A scalar subquery, `(SELECT ...)`. Its value is the first column
+    /// of the first row of its result, or NULL if there are no rows.
+    Subquery(Box<Select>),
+    /// `EXISTS (SELECT ...)`, which is 1 if the subquery has any rows and
+    /// 0 if not. `NOT EXISTS` is `NOT` applied to it.
+    Exists(Box<Select>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Negate,
+    Plus,
+    Not,
+    BitNot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Concat,
+    Multiply,
+    Divide,
+    Remainder,
+    Add,
+    Subtract,
+    BitAnd,
+    BitOr,
+    ShiftLeft,
+    ShiftRight,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+    Eq,
+    NotEq,
+    Is,
+    IsNot,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LikeOp {
+    Like,
+    Glob,
+}
@@ -0,0 +1,408 @@
+//! Reading database files from async code. `AsyncPager` reads pages
+//! through `tokio::fs`, which hands the blocking reads to tokio's pool of
+//! blocking threads, so a service running on an async runtime can query
+//! a database without holding up its worker threads. `AsyncBtree` scans
+//! a table or index over it, a page at a time as the scan reaches it.
+//!
+//! Only reading is supported. Pages are read without taking the locks in
+//! `lock`, so the file must not be written to, by this library or by
+//! SQLite, while it is being read this way.
+
+use eyre::{eyre, Result};
+use lru::LruCache;
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::btree::{check_overflow_link, BtreePage, OverflowPayload, Record};
+use crate::datatypes::{Value, VarInt};
+use crate::integrity;
+use crate::limits::Limits;
+use crate::pagefile::{CacheSize, PagerConfig};
+use crate::pager::{self, CACHE_SIZE};
+use crate::parsing;
+use crate::schema::SCHEMA_ROOT_PAGE;
+use crate::DbOptions;
+
+/// A read-only pager for async code. It can be shared between tasks
+/// (e.g., in an `Arc`), which read pages through the same cache.
+#[derive(Debug)]
+pub struct AsyncPager {
+    file: tokio::sync::Mutex<File>,
+    db_options: DbOptions,
+    num_pages: usize,
+    cache: Mutex<LruCache<usize, Arc<BtreePage>>>,
+    verify_pages: bool,
+    limits: Limits,
+}
+
+impl AsyncPager {
+    pub async fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        return Self::with_config(path, PagerConfig::default()).await;
+    }
+
+    /// Opens a database file with the cache size, limits and page
+    /// checks of `config`, which mean the same as for `Pager`. The
+    /// settings for how the file is read do not apply.
+    pub async fn with_config<P: AsRef<Path>>(path: P, config: PagerConfig) -> Result<Self> {
+        let mut file = File::open(path).await?;
+        let file_length = file.metadata().await?.len() as usize;
+        if file_length < 100 {
+            return Err(eyre!("Not a valid sqlite file -- too short for a header!"));
+        }
+        let mut header = vec![0; 100];
+        file.read_exact(&mut header).await?;
+        let db_options = DbOptions::deserialize(&header)?;
+        if !file_length.is_multiple_of(db_options.page_size) {
+            return Err(eyre!(
+                "DB file is not a whole number of pages. Corrupt file."
+            ));
+        }
+        let num_pages = file_length / db_options.page_size;
+        if num_pages > config.limits.max_page_count {
+            return Err(eyre!(
+                "DB file has {} pages, more than the limit of {}.",
+                num_pages,
+                config.limits.max_page_count
+            ));
+        }
+        let cache_size = match config.cache_size {
+            Some(size) => size,
+            None if db_options.cache_size != 0 => {
+                CacheSize::from_pragma(db_options.cache_size as i64)
+            }
+            None => CacheSize::Pages(CACHE_SIZE),
+        };
+        return Ok(Self {
+            file: tokio::sync::Mutex::new(file),
+            db_options: db_options,
+            num_pages: num_pages,
+            cache: Mutex::new(LruCache::new(cache_size.pages(db_options.page_size))),
+            verify_pages: config.verify_pages,
+            limits: config.limits,
+        });
+    }
+
+    pub fn db_options(&self) -> &DbOptions {
+        return &self.db_options;
+    }
+
+    pub fn limits(&self) -> &Limits {
+        return &self.limits;
+    }
+
+    pub fn page_size(&self) -> usize {
+        return self.db_options.page_size;
+    }
+
+    pub fn reserved_space(&self) -> u8 {
+        return self.db_options.reserved_space;
+    }
+
+    pub fn num_pages(&self) -> usize {
+        return self.num_pages;
+    }
+
+    /// Reads a page as it is stored in the file, for pages that are not
+    /// B-tree pages, such as overflow pages. These are not cached.
+    pub async fn get_raw_page(&self, page_num: usize) -> Result<Vec<u8>> {
+        if page_num == 0 || page_num > self.num_pages {
+            return Err(eyre!("Trying to access page that does not exist."));
+        }
+        let page_size = self.page_size();
+        let mut bytes = vec![0; page_size];
+        let mut file = self.file.lock().await;
+        file.seek(SeekFrom::Start(((page_num - 1) * page_size) as u64))
+            .await?;
+        file.read_exact(&mut bytes).await?;
+        return Ok(bytes);
+    }
+
+    /// Gets a B-tree page, from the cache if it has been read before.
+    /// As with `Pager::get_page`, the page is shared with the cache.
+    pub async fn get_page(&self, page_num: usize) -> Result<Arc<BtreePage>> {
+        if self.is_ptrmap_page(page_num) {
+            return Err(eyre!("Page {} is a pointer map page.", page_num));
+        }
+        if let Some(page) = self.cache()?.get(&page_num) {
+            return Ok(page.clone());
+        }
+        let bytes = self.get_raw_page(page_num).await?;
+        if self.verify_pages {
+            integrity::validate_page(
+                &bytes,
+                page_num,
+                self.page_size(),
+                self.reserved_space(),
+                self.num_pages,
+            )?;
+        }
+        let page = Arc::new(BtreePage::deserialize(
+            &bytes,
+            page_num,
+            self.page_size(),
+            self.reserved_space(),
+        )?);
+        self.cache()?.put(page_num, page.clone());
+        return Ok(page);
+    }
+
+    /// Whether the page is a pointer map page of an auto-vacuum database,
+    /// as for `Pager::is_ptrmap_page`.
+    fn is_ptrmap_page(&self, page_num: usize) -> bool {
+        let usable_size = self.page_size() - self.reserved_space() as usize;
+        return self.db_options.largest_root_page != 0
+            && pager::is_ptrmap_page(usable_size, page_num);
+    }
+
+    /// Locks the cache, failing rather than panicking if a task panicked
+    /// while holding it.
+    fn cache(&self) -> Result<MutexGuard<'_, LruCache<usize, Arc<BtreePage>>>> {
+        return self
+            .cache
+            .lock()
+            .map_err(|_| eyre!("The page cache was poisoned by a panic."));
+    }
+}
+
+/// A table or index B-tree read through an `AsyncPager`.
+#[derive(Debug, Clone)]
+pub struct AsyncBtree {
+    pub name: String,
+    pub root_page: usize,
+    pager: Arc<AsyncPager>,
+}
+
+impl AsyncBtree {
+    pub fn new(name: &str, root_page: usize, pager: Arc<AsyncPager>) -> Self {
+        return Self {
+            name: name.to_string(),
+            root_page: root_page,
+            pager: pager,
+        };
+    }
+
+    /// Finds a table or index by name in the schema table.
+    pub async fn open(name: &str, pager: Arc<AsyncPager>) -> Result<Self> {
+        let schema = Self::new("sqlite_schema", SCHEMA_ROOT_PAGE, pager.clone());
+        let mut scan = schema.scan();
+        while let Some(row) = scan.next().await {
+            let (_, record) = row?;
+            let found = match record.values.get(1) {
+                Some(Value::String(s)) => s.eq_ignore_ascii_case(name),
+                _ => false,
+            };
+            if !found {
+                continue;
+            }
+            let root_page = record
+                .values
+                .get(3)
+                .and_then(|value| value.get_int_val())
+                .filter(|&root| root > 0)
+                .ok_or_else(|| eyre!("{} is not stored in a B-tree", name))?;
+            return Ok(Self::new(name, root_page as usize, pager));
+        }
+        return Err(eyre!("No such table: {}", name));
+    }
+
+    /// Iterates over the rows of a table, or the entries of an index, in
+    /// order, as `Btree::scan` does. Call `AsyncTableScan::next` for each
+    /// row.
+    pub fn scan(&self) -> AsyncTableScan<'_> {
+        return AsyncTableScan {
+            btree: self,
+            stack: Vec::new(),
+            started: false,
+        };
+    }
+
+    /// Reads a page of the tree, along with the rest of each payload on
+    /// it that continues on overflow pages, as `Btree::get_page` does.
+    async fn get_page(&self, page_num: usize) -> Result<Arc<BtreePage>> {
+        let page = self.pager.get_page(page_num).await?;
+        if page.largest_payload() > self.pager.limits().max_record_size {
+            return Err(eyre!("string or blob too big"));
+        }
+        let payloads = page.overflow_payloads();
+        if payloads.is_empty() {
+            return Ok(page);
+        }
+        let mut page = (*page).clone();
+        let mut overflow = HashMap::new();
+        for payload in payloads {
+            let contents = self.read_overflow(page_num, &payload).await?;
+            overflow.insert(payload.first_page, contents);
+        }
+        if let Some(map) = page.overflow_mut() {
+            *map = overflow;
+        }
+        return Ok(Arc::new(page));
+    }
+
+    async fn read_overflow(&self, cell_page: usize, payload: &OverflowPayload) -> Result<Vec<u8>> {
+        let per_page = self.pager.page_size() - self.pager.reserved_space() as usize - 4;
+        let mut contents = payload.local.clone();
+        let mut previous = cell_page;
+        let mut page_num = payload.first_page as usize;
+        while contents.len() < payload.size {
+            check_overflow_link(&self.name, previous, page_num, self.pager.num_pages())?;
+            let bytes = self.pager.get_raw_page(page_num).await?;
+            let n = std::cmp::min(per_page, payload.size - contents.len());
+            contents.extend(&bytes[4..4 + n]);
+            previous = page_num;
+            page_num = parsing::be_u32(&bytes[..4])? as usize;
+        }
+        return Ok(contents);
+    }
+}
+
+/// An in-order scan over an `AsyncBtree`, reading each page as the scan
+/// reaches it.
+pub struct AsyncTableScan<'a> {
+    btree: &'a AsyncBtree,
+    /// The pages from the root down to the current one, each with its
+    /// next step: for an interior page, an even step is its child of
+    /// half that index, and an odd one the cell between two children,
+    /// which for an index is an entry of its own.
+    stack: Vec<(Arc<BtreePage>, usize)>,
+    started: bool,
+}
+
+impl AsyncTableScan<'_> {
+    /// The next row ID and record, or the next entry of an index along
+    /// with the row ID it refers to (0 for a WITHOUT ROWID table), or
+    /// None once the scan is done. After an error, the scan ends.
+    pub async fn next(&mut self) -> Option<Result<(VarInt, Record)>> {
+        let result = self.step().await;
+        if result.is_err() {
+            self.stack.clear();
+        }
+        return result.transpose();
+    }
+
+    async fn step(&mut self) -> Result<Option<(VarInt, Record)>> {
+        if !self.started {
+            self.started = true;
+            let root = self.btree.get_page(self.btree.root_page).await?;
+            self.stack.push((root, 0));
+        }
+        while let Some((page, step)) = self.stack.last_mut() {
+            let step_num = *step;
+            *step += 1;
+            let num_cells = page.header().cell_pointers.len();
+            if page.is_leaf() {
+                if step_num >= num_cells {
+                    self.stack.pop();
+                    continue;
+                }
+                return Ok(Some(entry(page, step_num)?));
+            }
+            if step_num > 2 * num_cells {
+                self.stack.pop();
+            } else if step_num % 2 == 0 {
+                let child = page.children()[step_num / 2] as usize;
+                if self.stack.len() >= self.btree.pager.limits().max_btree_depth {
+                    return Err(eyre!(
+                        "{} is deeper than the limit of {} levels; database disk image is malformed",
+                        self.btree.name,
+                        self.btree.pager.limits().max_btree_depth
+                    ));
+                }
+                let child = self.btree.get_page(child).await?;
+                self.stack.push((child, 0));
+            } else if let BtreePage::IndexInterior(_) = &**page {
+                return Ok(Some(entry(page, step_num / 2)?));
+            }
+        }
+        return Ok(None);
+    }
+}
+
+/// The row ID and record of the cell at `index` of a leaf page or an
+/// index interior page.
+fn entry(page: &BtreePage, index: usize) -> Result<(VarInt, Record)> {
+    let record = match page {
+        BtreePage::TableLeaf(pg) => return pg.row(index).ok_or_else(|| eyre!("No such cell")),
+        BtreePage::IndexLeaf(pg) => pg.record(index),
+        BtreePage::IndexInterior(pg) => pg.entry(index).map(|(_, record)| record),
+        BtreePage::TableInterior(_) => None,
+    }
+    .ok_or_else(|| eyre!("No such cell"))?;
+    let row_id = record
+        .values
+        .last()
+        .and_then(|value| value.get_int_val())
+        .unwrap_or(0);
+    return Ok((VarInt::new(row_id), record));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::Connection;
+
+    fn assert_send<T: Send>(_: &T) {}
+
+    #[test]
+    fn scan_from_async_code() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let path = file.path().to_str().unwrap();
+        {
+            let mut conn = Connection::new(path).unwrap();
+            conn.execute("CREATE TABLE t(a, b)").unwrap();
+            for i in 1..=500 {
+                conn.execute(&format!(
+                    "INSERT INTO t VALUES ({}, '{}')",
+                    i,
+                    "x".repeat(i * 10 % 5000)
+                ))
+                .unwrap();
+            }
+            conn.execute("CREATE INDEX t_b ON t(b)").unwrap();
+        }
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let config = PagerConfig {
+                verify_pages: true,
+                ..PagerConfig::default()
+            };
+            let pager = Arc::new(AsyncPager::with_config(path, config).await.unwrap());
+            let table = AsyncBtree::open("t", pager.clone()).await.unwrap();
+            // the scan can be run by a task on a multi-threaded runtime
+            assert_send(&table.scan().next());
+            let mut scan = table.scan();
+            let mut row_ids = Vec::new();
+            while let Some(row) = scan.next().await {
+                let (row_id, record) = row.unwrap();
+                assert_eq!(record.values[0].get_int_val(), Some(row_id.0));
+                let len = row_id.0 as usize * 10 % 5000;
+                assert_eq!(record.values[1], Value::String("x".repeat(len)));
+                row_ids.push(row_id.0);
+            }
+            assert_eq!(row_ids, (1..=500).collect::<Vec<_>>());
+
+            // the entries of an index come in index order, each with the
+            // row ID it refers to
+            let index = AsyncBtree::open("t_b", pager).await.unwrap();
+            let mut scan = index.scan();
+            let mut entries = Vec::new();
+            while let Some(entry) = scan.next().await {
+                let (row_id, record) = entry.unwrap();
+                assert_eq!(record.values[1].get_int_val(), Some(row_id.0));
+                entries.push(record.values[0].clone());
+            }
+            assert_eq!(entries.len(), 500);
+            assert!(entries.windows(2).all(|pair| match (&pair[0], &pair[1]) {
+                (Value::String(a), Value::String(b)) => a <= b,
+                _ => false,
+            }));
+        });
+    }
+}
@@ -1,143 +1,2203 @@
 use derive_try_from_primitive::TryFromPrimitive;
-use eyre::Result;
-use std::cell::RefCell;
+use eyre::{eyre, Result};
 use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::TryFrom;
-use std::rc::Rc;
+use std::fmt;
+use std::ops::Bound;
+use std::sync::Arc;
 
 use crate::datatypes::*;
-use crate::pager::Pager;
+use crate::exec;
+use crate::integrity::{self, CorruptPage};
+use crate::pager::{Pager, PtrmapEntry, PtrmapType, SharedPager};
 use crate::parsing;
+use crate::sorter::SortOrder;
+use crate::vacuum;
 use crate::DbOptions;
 
 #[derive(Debug)]
-pub struct Btree<'a> {
+pub struct Btree {
     pub name: String,
     pub table_name: String,
     pub root_page: usize,
-    db_options: &'a DbOptions,
-    pager: Rc<RefCell<Pager>>,
+    pager: SharedPager,
+    /// How the values of each key column of an index are ordered, for
+    /// seeking a key (see `with_key_order`). Columns past the end are in
+    /// ascending order, with the binary collating sequence.
+    key_order: Vec<SortOrder>,
 }
 
-impl<'a> Btree<'a> {
-    pub fn new(
-        name: String,
-        table_name: String,
-        root_page: usize,
-        db_options: &'a DbOptions,
-        pager: Rc<RefCell<Pager>>,
-    ) -> Self {
+impl Btree {
+    pub fn new(name: String, table_name: String, root_page: usize, pager: SharedPager) -> Self {
         return Self {
             name: name,
             table_name: table_name,
             root_page: root_page,
-            db_options: db_options,
             pager: pager,
+            key_order: Vec::new(),
         };
     }
 
-    pub fn get_row(&self, row_id: VarInt) -> Option<Record> {
-        return self.get_row_rcrs(row_id, self.root_page);
+    /// Sets how the values of each key column of an index are ordered,
+    /// as given by the direction and collating sequence of each indexed
+    /// column, so that seeking a key (see `index_range`) follows the
+    /// order the entries are stored in.
+    pub fn with_key_order(mut self, key_order: Vec<SortOrder>) -> Self {
+        self.key_order = key_order;
+        return self;
+    }
+
+    /// Creates an empty tree of the given kind, allocating a leaf page as
+    /// its root, and returns a handle to it. Recording the tree in the
+    /// schema is up to the caller.
+    pub fn create(
+        name: String,
+        table_name: String,
+        kind: BtreeKind,
+        pager: SharedPager,
+    ) -> Result<Self> {
+        let root_page = vacuum::allocate_root(&mut pager.write(), kind.leaf_type())?;
+        return Ok(Self::new(name, table_name, root_page, pager));
     }
 
-    fn get_row_rcrs(&self, row_id: VarInt, page_num: usize) -> Option<Record> {
-        let page = self.get_page(page_num);
-        if let Err(_) = page {
+    pub fn get_row(&self, row_id: VarInt) -> Option<Record> {
+        let mut cursor = self.cursor();
+        if !cursor.seek(&[Value::Int64(row_id.0)]).ok()? || cursor.row_id()? != row_id.0 {
             return None;
         }
-        match page.unwrap() {
-            BtreePage::TableLeaf(pg) => {
-                for (row, rec) in pg.iter() {
-                    if row == row_id {
-                        return Some(rec);
-                    }
+        return cursor.current();
+    }
+
+    /// Finds the leaf page a row is on, or would go on, by following the
+    /// child pointers down from the root. The descent is a loop rather
+    /// than a recursion, so a corrupt tree (e.g., one with a cycle) is
+    /// stopped by the depth limit rather than overflowing the stack.
+    pub fn find_leaf(&self, row_id: i64) -> Result<LeafPath> {
+        let mut path = Vec::new();
+        let mut page_num = self.root_page;
+        loop {
+            self.check_depth(path.len() + 1)?;
+            match &*self.get_page(page_num)? {
+                BtreePage::TableLeaf(pg) => {
+                    return Ok(LeafPath {
+                        path: path,
+                        page_num: page_num,
+                        leaf: pg.clone(),
+                    })
                 }
-                return None;
+                BtreePage::TableInterior(pg) => {
+                    let child = pg
+                        .iter()
+                        .position(|(_, key)| row_id <= key.0)
+                        .unwrap_or(pg.header.cell_pointers.len());
+                    path.push((page_num, child));
+                    page_num = pg.children()[child] as usize;
+                }
+                _ => return Err(eyre!("{} is not a table", self.name)),
             }
-            BtreePage::TableInterior(pg) => {
-                let mut child_page = None;
-                for (child_ptr, key) in pg.iter() {
-                    if row_id <= key {
-                        child_page = Some(child_ptr);
-                        break;
+        }
+    }
+
+    pub fn get_index(&self, index: Record) -> Option<Record> {
+        let mut page_num = self.root_page;
+        let mut depth = 1;
+        loop {
+            self.check_depth(depth).ok()?;
+            depth += 1;
+            match &*self.get_page(page_num).ok()? {
+                BtreePage::IndexLeaf(pg) => return pg.iter().find(|record| index == *record),
+                BtreePage::IndexInterior(pg) => {
+                    let mut child_page = None;
+                    for (child_ptr, record) in pg.iter() {
+                        if index == record {
+                            return Some(record);
+                        } else if index.cmp_sqlite(&record) == Ordering::Less {
+                            child_page = Some(child_ptr);
+                            break;
+                        }
                     }
+                    page_num = child_page.or(pg.header.right_pointer)? as usize;
                 }
-                if child_page.is_none() {
-                    child_page = Some(pg.header.right_pointer.unwrap());
+                _ => return None, // not defined for table pages
+            }
+        }
+    }
+
+    /// Finds every entry of an index whose leading values equal `key`.
+    /// The entries are returned in index order.
+    pub fn index_lookup(&self, key: &[Value]) -> Result<Vec<Record>> {
+        let key = Record::from_values(key.to_vec());
+        return self
+            .index_range(Bound::Included(key.clone()), Bound::Included(key))
+            .collect();
+    }
+
+    /// Finds every entry of an index whose leading values equal any of
+    /// `keys`, which must be in index order. The entries are returned in
+    /// index order, and are found with a single cursor that moves on from
+    /// each key to the next (see `BtreeCursor::advance_to`).
+    pub fn index_lookup_keys(&self, keys: &[Vec<Value>]) -> Result<Vec<Record>> {
+        let order = &self.key_order;
+        let mut cursor = self.cursor();
+        let mut entries = Vec::new();
+        for key in keys {
+            let start = |payload: &[u8]| match compare_prefix_payload(key, payload, order) {
+                Ordering::Greater => Ordering::Greater,
+                _ => Ordering::Equal,
+            };
+            let mut found = cursor.advance_to(&start)?;
+            while found {
+                let record = cursor.current().unwrap();
+                if compare_prefix(key, &record, order) != Ordering::Equal {
+                    break;
                 }
-                return self.get_row_rcrs(row_id, child_page.unwrap() as usize);
+                entries.push(record);
+                found = cursor.next()?;
+            }
+            if !found {
+                // past the last entry, which no later key can come before
+                break;
             }
-            _ => return None, // not defined for index pages
         }
+        return Ok(entries);
     }
 
-    pub fn get_index(&self, index: Record) -> Option<Record> {
-        return self.get_index_rcrs(index, self.root_page);
+    /// Finds the entries of an index that lie between two bounds, in
+    /// index order. A bound is compared with as many of the leading
+    /// values of each entry as it has, so that, e.g., an `Included`
+    /// bound of one value takes in every entry that starts with it. The
+    /// entries are read as the iterator is advanced.
+    pub fn index_range(&self, lower: Bound<Record>, upper: Bound<Record>) -> IndexRange<'_> {
+        return IndexRange {
+            cursor: self.cursor(),
+            lower: Some(lower),
+            upper: upper,
+            guard: ScanGuard::new(self.pager.clone()),
+        };
     }
 
-    fn get_index_rcrs(&self, index: Record, page_num: usize) -> Option<Record> {
-        let page = self.get_page(page_num);
-        if let Err(_) = page {
-            return None;
+    /// Finds the first entry of an index, in index order or (if
+    /// `reverse`) in reverse order, for which `accept` is true. Only the
+    /// entries before it are read.
+    pub fn index_find(
+        &self,
+        reverse: bool,
+        accept: &dyn Fn(&Record) -> bool,
+    ) -> Result<Option<Record>> {
+        let mut cursor = self.cursor();
+        let mut valid = if reverse {
+            cursor.last()?
+        } else {
+            cursor.first()?
+        };
+        while valid {
+            let record = cursor.current().unwrap();
+            if accept(&record) {
+                return Ok(Some(record));
+            }
+            valid = if reverse {
+                cursor.prev()?
+            } else {
+                cursor.next()?
+            };
+        }
+        return Ok(None);
+    }
+
+    /// A cursor over the entries of the tree, which starts out on none
+    /// of them.
+    pub fn cursor(&self) -> BtreeCursor<'_> {
+        return BtreeCursor {
+            btree: self,
+            stack: Vec::new(),
+        };
+    }
+
+    /// Reads every row of a table, or every entry of an index, in order
+    /// (see `scan`).
+    pub fn list_records(&self) -> Vec<(VarInt, Record)> {
+        return self.scan().map_while(Result::ok).collect();
+    }
+
+    /// Iterates over the rows of a table in row ID order, or the entries
+    /// of an index in index order, each along with the row ID of the row
+    /// it refers to (or 0 for the entries of a WITHOUT ROWID table, which
+    /// have none). The entries of an interior page of an index come
+    /// between those of the child pages either side. Pages are only read
+    /// as the scan reaches them, so stopping the iteration early avoids
+    /// reading the rest of the tree.
+    pub fn scan(&self) -> TableScan<'_> {
+        return TableScan {
+            cursor: self.cursor(),
+            started: false,
+            guard: ScanGuard::new(self.pager.clone()),
+        };
+    }
+
+    /// The number of rows in the table, found by adding up the number of
+    /// cells on each leaf page, of which only the header is read (see
+    /// `Pager::read_page_header`).
+    pub fn count_rows(&self) -> Result<usize> {
+        let mut count = 0;
+        let mut stack = vec![(self.root_page, 1)];
+        while let Some((page_num, depth)) = stack.pop() {
+            self.check_depth(depth)?;
+            let pager = self.pager.read();
+            // only the header of a leaf page is needed
+            let header = pager.read_page_header(page_num)?;
+            match header.page_type {
+                PageType::TableLeaf => count += header.num_cells as usize,
+                PageType::TableInterior => {
+                    let children = pager.get_page(page_num)?.children().into_iter();
+                    stack.extend(children.map(|child| (child as usize, depth + 1)));
+                }
+                _ => return Err(eyre!("{} is not a table", self.name)),
+            }
+        }
+        return Ok(count);
+    }
+
+    /// Iterates over the rows of a random sample of the leaf pages of a
+    /// table, each kept with probability `fraction`, in row ID order.
+    /// Only the interior pages and the leaf pages kept are read, apart
+    /// from the first leaf page, which is read to find the depth of the
+    /// leaves. The same seed picks the same pages of the same tree.
+    pub fn sample_pages(&self, fraction: f64, seed: u64) -> PageSample<'_> {
+        return PageSample {
+            btree: self,
+            fraction: fraction,
+            rng: SampleRng::new(seed),
+            pending: vec![(self.root_page, 1)],
+            leaf_depth: None,
+            rows: VecDeque::new(),
+            guard: ScanGuard::new(self.pager.clone()),
+        };
+    }
+
+    /// The largest row ID in the table, or 0 if it is empty.
+    pub fn max_row_id(&self) -> Result<i64> {
+        let mut page_num = self.root_page;
+        let mut depth = 1;
+        loop {
+            self.check_depth(depth)?;
+            depth += 1;
+            match &*self.get_page(page_num)? {
+                BtreePage::TableLeaf(pg) => {
+                    return Ok(pg.iter().last().map(|(row_id, _)| row_id.0).unwrap_or(0));
+                }
+                BtreePage::TableInterior(pg) => {
+                    page_num = pg.header.right_pointer.unwrap() as usize;
+                }
+                _ => return Err(eyre!("{} is not a table", self.name)),
+            }
+        }
+    }
+
+    /// Inserts a row into the table. If the leaf page the row belongs on
+    /// is full, its cells are shared out among it and its siblings (see
+    /// `balance_siblings`), unless the row goes after all those on the
+    /// right-most leaf under its parent, when it starts a new page (see
+    /// `balance_quick`).
+    pub fn insert(&self, row_id: i64, record: &Record) -> Result<()> {
+        let cell = self.table_cell(row_id, record)?;
+        return self.insert_cell(row_id, cell);
+    }
+
+    /// Inserts the cell for a row, as built by `table_cell`, into the
+    /// table.
+    fn insert_cell(&self, row_id: i64, cell: Vec<u8>) -> Result<()> {
+        let LeafPath {
+            mut path,
+            mut page_num,
+            mut leaf,
+        } = self.find_leaf(row_id)?;
+
+        let index = match leaf.search(row_id) {
+            Ok(_) => return Err(eyre!("Row ID {} already exists in {}", row_id, self.name)),
+            Err(index) => index,
+        };
+        if !leaf.header.has_room_for(cell.len()) && leaf.free_space()? >= needed_space(&cell) {
+            leaf.defragment();
+        }
+        if leaf.header.has_room_for(cell.len()) {
+            place_cell(&mut leaf.header, &mut leaf.bytes, index, &cell);
+            return self
+                .pager
+                .write()
+                .insert(page_num, BtreePage::TableLeaf(leaf));
+        }
+
+        let mut cells = leaf.cells();
+        cells.insert(index, cell);
+        if path.is_empty() && index == cells.len() - 1 {
+            // a full root moves its rows to a new child first, which the
+            // new row can then start a page after
+            let child_num = self.balance_deeper(page_num, PageType::TableLeaf)?;
+            let child =
+                TableLeafPage::from_cells(&cells[..index], 0, leaf.page_size, leaf.reserved_space)
+                    .ok_or_else(|| eyre!("Could not split page {} of {}", page_num, self.name))?;
+            self.pager
+                .write()
+                .insert(child_num, BtreePage::TableLeaf(child))?;
+            path.push((page_num, 0));
+            page_num = child_num;
+        }
+        if let Some(&(parent_num, child)) = path.last() {
+            let parent = self.get_page(parent_num)?;
+            if index == cells.len() - 1 && child == parent.header().cell_pointers.len() {
+                return self.balance_quick(path, page_num, cells, &parent);
+            }
+        }
+        return self.balance_siblings(path, page_num, PageType::TableLeaf, cells, None);
+    }
+
+    /// Makes the root an interior page whose only child is a new page,
+    /// which is returned for the root's cells to be moved to, so that
+    /// the tree grows a level taller while the root keeps its page
+    /// number. The root's type is that of the page before.
+    fn balance_deeper(&self, root_num: usize, page_type: PageType) -> Result<usize> {
+        let mut pager = self.pager.write();
+        let interior_type = match page_type {
+            PageType::TableLeaf | PageType::TableInterior => PageType::TableInterior,
+            PageType::IndexLeaf | PageType::IndexInterior => PageType::IndexInterior,
+        };
+        let child_num = pager.allocate_page(page_type)?;
+        let offset = if root_num == 1 { 100 } else { 0 };
+        let root = BtreePage::from_cells(
+            interior_type,
+            &[],
+            Some(child_num as u32),
+            offset,
+            pager.page_size(),
+            pager.reserved_space(),
+        )
+        .ok_or_else(|| eyre!("Could not split page {} of {}", root_num, self.name))?;
+        pager.insert(root_num, root)?;
+        return Ok(child_num);
+    }
+
+    /// Adds a row to the end of a full leaf that is the right-most child
+    /// of its parent, as when rows are inserted in order of row ID: the
+    /// new row goes on a page of its own, which becomes the parent's
+    /// right-most child, and the full leaf is left as it is rather than
+    /// having its cells shared out with its siblings. The parent is then
+    /// balanced, as it may no longer fit its cells. `cells` are those of
+    /// the leaf, ending with the new row's.
+    fn balance_quick(
+        &self,
+        mut path: Vec<(usize, usize)>,
+        page_num: usize,
+        mut cells: Vec<Vec<u8>>,
+        parent: &BtreePage,
+    ) -> Result<()> {
+        let (parent_num, _) = path.pop().unwrap();
+        let cell = cells.pop().unwrap();
+        let key = match cells.last() {
+            Some(last) => cell_row_id(last),
+            None => return Err(eyre!("Page {} of {} is empty", page_num, self.name)),
+        };
+        let mut pager = self.pager.write();
+        let page = TableLeafPage::from_cells(&[cell], 0, pager.page_size(), pager.reserved_space())
+            .ok_or_else(|| eyre!("Could not split page {} of {}", page_num, self.name))?;
+        let new_num = pager.allocate_page(PageType::TableLeaf)?;
+        pager.insert(new_num, BtreePage::TableLeaf(page))?;
+        drop(pager);
+
+        let mut parent_cells = parent.cells();
+        parent_cells.push(interior_cell(page_num as u32, key));
+        return self.balance_siblings(
+            path,
+            parent_num,
+            PageType::TableInterior,
+            parent_cells,
+            Some(new_num as u32),
+        );
+    }
+
+    /// Replaces the record of an existing row. The row stays on its
+    /// leaf page if the new record fits there; otherwise it is deleted
+    /// and inserted again. Any overflow pages the old record used are
+    /// put on the freelist.
+    pub fn update(&self, row_id: i64, record: &Record) -> Result<()> {
+        let LeafPath { page_num, leaf, .. } = self.find_leaf(row_id)?;
+        let index = leaf
+            .search(row_id)
+            .map_err(|_| eyre!("Row ID {} does not exist in {}", row_id, self.name))?;
+
+        let mut cells = leaf.cells();
+        let new_cell = self.table_cell(row_id, record)?;
+        let old_cell = std::mem::replace(&mut cells[index], new_cell);
+        let page = TableLeafPage::from_cells(
+            &cells,
+            leaf.header.offset,
+            leaf.page_size,
+            leaf.reserved_space,
+        );
+        match page {
+            Some(page) => {
+                self.free_overflow(PageType::TableLeaf, &old_cell)?;
+                return self
+                    .pager
+                    .write()
+                    .insert(page_num, BtreePage::TableLeaf(page));
+            }
+            None => {
+                let cell = cells.swap_remove(index);
+                self.delete(row_id)?;
+                return self.insert_cell(row_id, cell);
+            }
+        }
+    }
+
+    /// Deletes a row from the table, returning whether it was there. If
+    /// that leaves its leaf page less than a third full, its cells are
+    /// shared out among it and its siblings (see `balance_siblings`).
+    /// The row's overflow pages, if any, are put on the freelist.
+    pub fn delete(&self, row_id: i64) -> Result<bool> {
+        let LeafPath {
+            path,
+            page_num,
+            mut leaf,
+        } = self.find_leaf(row_id)?;
+
+        let index = match leaf.search(row_id) {
+            Ok(index) => index,
+            Err(_) => return Ok(false),
+        };
+        let start = leaf.header.cell_pointers[index] as usize;
+        let cell = leaf.bytes[start..start + leaf.cell_size(start)].to_vec();
+        leaf.delete(index)?;
+        self.free_overflow(PageType::TableLeaf, &cell)?;
+        let leaf = BtreePage::TableLeaf(leaf);
+        if !path.is_empty() && leaf.is_underfull()? {
+            self.balance_siblings(path, page_num, PageType::TableLeaf, leaf.cells(), None)?;
+        } else {
+            self.pager.write().insert(page_num, leaf)?;
+        }
+        return Ok(true);
+    }
+
+    /// Deletes an entry from an index, returning whether it was there.
+    /// `position` tells where the entry is relative to each entry, as for
+    /// `BtreeCursor::seek_to`, and must give `Equal` for that entry alone. An
+    /// entry on an interior page is replaced by the entry just before it,
+    /// taken from the leaf at the end of its left-hand subtree. That entry
+    /// may be longer, so the interior page is balanced with its siblings
+    /// if it no longer fits, and then the leaf if it is left less than a
+    /// third full, as for `delete`.
+    pub fn index_delete(&self, position: &dyn Fn(&Record) -> Ordering) -> Result<bool> {
+        let EntryPath {
+            path,
+            page_num,
+            page,
+            index,
+        } = match self.find_entry(position)? {
+            Some(found) => found,
+            None => return Ok(false),
+        };
+        let (page_size, reserved_space) = {
+            let pager = self.pager.read();
+            (pager.page_size(), pager.reserved_space())
+        };
+        let build = |page_type: PageType, cells: &[Vec<u8>], right_pointer: Option<u32>| {
+            return BtreePage::from_cells(
+                page_type,
+                cells,
+                right_pointer,
+                0,
+                page_size,
+                reserved_space,
+            )
+            .ok_or_else(|| eyre!("Could not rebuild page {} of {}", page_num, self.name));
+        };
+
+        let mut cells = page.cells();
+        self.free_overflow(page.header().page_type, &cells[index])?;
+        if page.is_leaf() {
+            cells.remove(index);
+            let leaf = build(PageType::IndexLeaf, &cells, None)?;
+            if !path.is_empty() && leaf.is_underfull()? {
+                self.balance_siblings(path, page_num, PageType::IndexLeaf, cells, None)?;
+            } else {
+                self.pager.write().insert(page_num, leaf)?;
+            }
+            return Ok(true);
+        }
+
+        // the entry just before is the last one in the left-hand subtree
+        let child = page.children()[index];
+        let (leaf_num, leaf) = self.last_leaf(child as usize, path.len() + 2, &mut Vec::new())?;
+        let previous = match &*leaf {
+            BtreePage::IndexLeaf(pg) => pg.iter().last(),
+            _ => None,
+        }
+        .ok_or_else(|| eyre!("{} is malformed", self.name))?;
+        let mut leaf_cells = leaf.cells();
+        let previous_cell = leaf_cells.pop().unwrap();
+        // removing an entry always leaves room, and the leaf is balanced
+        // once the interior page is in place
+        let leaf = build(PageType::IndexLeaf, &leaf_cells, None)?;
+        self.pager.write().insert(leaf_num, leaf)?;
+        cells[index] = with_child(&previous_cell, Some(child));
+        let right_pointer = page.header().right_pointer;
+        self.balance_siblings(
+            path,
+            page_num,
+            PageType::IndexInterior,
+            cells,
+            right_pointer,
+        )?;
+
+        // balancing the interior page may have moved the entry to another
+        // page, so the path down to the leaf is found again
+        let previous = previous.serialize();
+        let position = |record: &Record| {
+            if record.serialize() == previous {
+                return Ordering::Equal;
+            }
+            return position(record);
+        };
+        let EntryPath {
+            mut path,
+            page_num,
+            page,
+            index,
+        } = self
+            .find_entry(&position)?
+            .ok_or_else(|| eyre!("{} is malformed", self.name))?;
+        path.push((page_num, index));
+        let depth = path.len() + 1;
+        let child = page
+            .children()
+            .get(index)
+            .copied()
+            .ok_or_else(|| eyre!("{} is malformed", self.name))?;
+        let (leaf_num, leaf) = self.last_leaf(child as usize, depth, &mut path)?;
+        if leaf.is_underfull()? {
+            self.balance_siblings(path, leaf_num, PageType::IndexLeaf, leaf.cells(), None)?;
+        }
+        return Ok(true);
+    }
+
+    /// Finds an entry of an index, as located by `position` (see
+    /// `index_delete`), returning the path of interior pages above it,
+    /// and the page it is on, along with its index there.
+    fn find_entry(&self, position: &dyn Fn(&Record) -> Ordering) -> Result<Option<EntryPath>> {
+        let mut path = Vec::new();
+        let mut page_num = self.root_page;
+        loop {
+            self.check_depth(path.len() + 1)?;
+            let page = self.get_page(page_num)?;
+            let records: Vec<Record> = match &*page {
+                BtreePage::IndexLeaf(pg) => pg.iter().collect(),
+                BtreePage::IndexInterior(pg) => pg.iter().map(|(_, record)| record).collect(),
+                _ => return Err(eyre!("{} is not an index", self.name)),
+            };
+            // the first entry that is not before the one wanted
+            let index = records
+                .iter()
+                .position(|record| position(record) != Ordering::Greater)
+                .unwrap_or(records.len());
+            if index < records.len() && position(&records[index]) == Ordering::Equal {
+                return Ok(Some(EntryPath {
+                    path: path,
+                    page_num: page_num,
+                    page: page,
+                    index: index,
+                }));
+            }
+            if page.is_leaf() {
+                return Ok(None);
+            }
+            path.push((page_num, index));
+            page_num = page.children()[index] as usize;
+        }
+    }
+
+    /// Follows the right-most children down from `page_num`, at `depth`
+    /// in the tree, to the last leaf under it, adding the interior pages
+    /// passed through to `path`.
+    fn last_leaf(
+        &self,
+        mut page_num: usize,
+        mut depth: usize,
+        path: &mut Vec<(usize, usize)>,
+    ) -> Result<(usize, Arc<BtreePage>)> {
+        loop {
+            self.check_depth(depth)?;
+            let page = self.get_page(page_num)?;
+            if page.is_leaf() {
+                return Ok((page_num, page));
+            }
+            path.push((page_num, page.header().cell_pointers.len()));
+            page_num = page.header().right_pointer.unwrap() as usize;
+            depth += 1;
         }
-        match page.unwrap() {
-            BtreePage::IndexLeaf(pg) => {
-                for record in pg.iter() {
-                    if index == record {
-                        return Some(record);
+    }
+
+    /// Inserts an entry into an index. `position` tells where the entry
+    /// goes relative to each entry, as for `index_delete`; if it gives
+    /// `Equal` for any of them, the entry is already there. The cells of
+    /// a full leaf are shared out among it and its siblings, as for
+    /// `insert`.
+    pub fn index_insert(
+        &self,
+        record: &Record,
+        position: &dyn Fn(&Record) -> Ordering,
+    ) -> Result<()> {
+        let mut path = Vec::new();
+        let mut page_num = self.root_page;
+        let (page, index) = loop {
+            self.check_depth(path.len() + 1)?;
+            let page = self.get_page(page_num)?;
+            let records: Vec<Record> = match &*page {
+                BtreePage::IndexLeaf(pg) => pg.iter().collect(),
+                BtreePage::IndexInterior(pg) => pg.iter().map(|(_, record)| record).collect(),
+                _ => return Err(eyre!("{} is not an index", self.name)),
+            };
+            let index = records
+                .iter()
+                .position(|record| position(record) != Ordering::Greater)
+                .unwrap_or(records.len());
+            if index < records.len() && position(&records[index]) == Ordering::Equal {
+                return Err(eyre!("Entry already exists in {}", self.name));
+            }
+            if page.is_leaf() {
+                break (page, index);
+            }
+            path.push((page_num, index));
+            page_num = page.children()[index] as usize;
+        };
+        let payload = record.serialize();
+        let cell = self.build_cell(
+            VarInt::new(payload.len() as i64).serialize(),
+            &payload,
+            true,
+        )?;
+        let mut cells = page.cells();
+        cells.insert(index, cell);
+        return self.balance_siblings(path, page_num, PageType::IndexLeaf, cells, None);
+    }
+
+    /// Writes the page `page_num` of the given type, with the cells and
+    /// right-most child given, at the end of `path` (the interior pages
+    /// above it, from the root, each with the index of the child
+    /// followed). If the cells do not fit on the page, or leave it less
+    /// than a third full, they are shared out among it and up to two of
+    /// its siblings, as SQLite does: the cells of the siblings, and
+    /// except between the leaves of a table the cells in the parent that
+    /// divide them, are laid out on as few pages as will hold them, which
+    /// are then evened out (see `distribute`). The parent's dividers are
+    /// replaced with the new pages', and the parent is balanced in turn
+    /// if that leaves it too full or too empty.
+    ///
+    /// A root that is too full keeps its page number (which the schema,
+    /// or for page 1 the file header, refers to), so its cells move to a
+    /// new child page that is then balanced, and the tree grows a level
+    /// taller. A root left with a single child takes over that child's
+    /// cells if they fit, making the tree a level shorter. Pages removed
+    /// from the tree are put on the freelist.
+    fn balance_siblings(
+        &self,
+        mut path: Vec<(usize, usize)>,
+        mut page_num: usize,
+        mut page_type: PageType,
+        mut cells: Vec<Vec<u8>>,
+        mut right_pointer: Option<u32>,
+    ) -> Result<()> {
+        let (page_size, reserved_space) = {
+            let pager = self.pager.read();
+            (pager.page_size(), pager.reserved_space())
+        };
+        let build = |page_type: PageType,
+                     cells: &[Vec<u8>],
+                     right_pointer: Option<u32>,
+                     page_num: usize| {
+            // page 1 starts with the file header
+            let offset = if page_num == 1 { 100 } else { 0 };
+            return BtreePage::from_cells(
+                page_type,
+                cells,
+                right_pointer,
+                offset,
+                page_size,
+                reserved_space,
+            );
+        };
+
+        loop {
+            self.check_depth(path.len() + 1)?;
+            let page = build(page_type, &cells, right_pointer, page_num);
+            let (parent_num, child) = match path.pop() {
+                Some(parent) => parent,
+                None => match page {
+                    Some(root) if root.is_interior() && cells.is_empty() => {
+                        let child_num = right_pointer.unwrap() as usize;
+                        let child = self.get_page(child_num)?;
+                        let header = child.header();
+                        let mut pager = self.pager.write();
+                        match build(
+                            header.page_type,
+                            &child.cells(),
+                            header.right_pointer,
+                            page_num,
+                        ) {
+                            Some(merged) => {
+                                pager.insert(page_num, merged)?;
+                                return pager.free_page(child_num);
+                            }
+                            None => return pager.insert(page_num, root),
+                        }
+                    }
+                    Some(root) => return self.pager.write().insert(page_num, root),
+                    None => {
+                        let child_num = self.balance_deeper(page_num, page_type)?;
+                        path.push((page_num, 0));
+                        page_num = child_num;
+                        continue;
                     }
+                },
+            };
+            if let Some(page) = page {
+                if !page.is_underfull()? {
+                    return self.pager.write().insert(page_num, page);
                 }
-                return None;
             }
-            BtreePage::IndexInterior(pg) => {
-                let mut child_page = None;
-                for (child_ptr, record) in pg.iter() {
-                    if index == record {
-                        return Some(record);
-                    } else if index <= record {
-                        child_page = Some(child_ptr);
-                        break;
+
+            let parent = self.get_page(parent_num)?;
+            let children = parent.children();
+            let mut parent_cells = parent.cells();
+            if child >= children.len() || children[child] as usize != page_num {
+                return Err(eyre!("{} is malformed", self.name));
+            }
+            // up to three siblings, with the page in the middle where it
+            // has a sibling on each side
+            let first = std::cmp::min(child.saturating_sub(1), children.len().saturating_sub(3));
+            let end = std::cmp::min(first + 3, children.len());
+            let moves_up = page_type != PageType::TableLeaf;
+            let mut all_cells = Vec::new();
+            let mut last_right_pointer = None;
+            for i in first..end {
+                let (sibling_cells, sibling_right_pointer) = if i == child {
+                    (std::mem::take(&mut cells), right_pointer)
+                } else {
+                    let sibling = self.get_page(children[i] as usize)?;
+                    if sibling.header().page_type != page_type {
+                        return Err(eyre!("{} is malformed", self.name));
                     }
+                    (sibling.cells(), sibling.header().right_pointer)
+                };
+                all_cells.extend(sibling_cells);
+                if moves_up && i + 1 < end {
+                    // the divider comes down, pointing to what was the
+                    // left page's right-most child
+                    all_cells.push(with_child(&parent_cells[i][4..], sibling_right_pointer));
+                }
+                last_right_pointer = sibling_right_pointer;
+            }
+
+            let capacity =
+                PageHeader::new(page_type, page_size, reserved_space).unallocated_space();
+            let groups = distribute(&all_cells, moves_up, capacity)
+                .ok_or_else(|| eyre!("Could not balance page {} of {}", page_num, self.name))?;
+            let mut page_nums: Vec<usize> =
+                children[first..end].iter().map(|c| *c as usize).collect();
+            for &unused in page_nums.iter().skip(groups.len()) {
+                self.pager.write().free_page(unused)?;
+            }
+            page_nums.truncate(groups.len());
+            while page_nums.len() < groups.len() {
+                page_nums.push(self.pager.write().allocate_page(page_type)?);
+            }
+            let mut dividers = Vec::new();
+            for (j, &(start, stop)) in groups.iter().enumerate() {
+                let new_num = page_nums[j] as u32;
+                let (new_right_pointer, divider) = if j + 1 == groups.len() {
+                    (last_right_pointer, None)
+                } else if !moves_up {
+                    let key = cell_row_id(&all_cells[stop - 1]);
+                    (None, Some(interior_cell(new_num, key)))
+                } else if page_type.is_interior() {
+                    // the cell between the pages moves up to divide them,
+                    // and its child becomes the left page's right-most
+                    // child
+                    let middle = &all_cells[stop];
+                    let middle_child = parsing::be_u32(&middle[..4])?;
+                    (
+                        Some(middle_child),
+                        Some(with_child(&middle[4..], Some(new_num))),
+                    )
+                } else {
+                    (None, Some(with_child(&all_cells[stop], Some(new_num))))
+                };
+                let page = build(
+                    page_type,
+                    &all_cells[start..stop],
+                    new_right_pointer,
+                    page_nums[j],
+                )
+                .ok_or_else(|| eyre!("Could not balance page {} of {}", page_num, self.name))?;
+                self.pager.write().insert(page_nums[j], page)?;
+                dividers.extend(divider);
+            }
+
+            // the last sibling keeps its place in the parent, whether that
+            // is a cell or the right-most child
+            let last_num = *page_nums.last().unwrap() as u32;
+            let mut parent_right_pointer = parent.header().right_pointer;
+            if end == children.len() {
+                parent_right_pointer = Some(last_num);
+            } else {
+                dividers.push(with_child(&parent_cells[end - 1][4..], Some(last_num)));
+            }
+            let replaced = first..std::cmp::min(end, parent_cells.len());
+            parent_cells.splice(replaced, dividers);
+
+            page_num = parent_num;
+            page_type = parent.header().page_type;
+            cells = parent_cells;
+            right_pointer = parent_right_pointer;
+        }
+    }
+
+    /// Fills an empty index with entries, which must already be in index
+    /// order. Pages are packed as full as they will go, from left to
+    /// right, and the entry that does not fit on a page moves up to the
+    /// level above, to divide that page from the next. The entries are
+    /// written as they are read, so only those that move up are held in
+    /// memory.
+    pub fn build_index(&self, entries: &mut dyn Iterator<Item = Result<Record>>) -> Result<()> {
+        let (page_size, reserved_space) = match &*self.get_page(self.root_page)? {
+            BtreePage::IndexLeaf(pg) if pg.header.num_cells == 0 => {
+                (pg.page_size, pg.reserved_space)
+            }
+            _ => return Err(eyre!("{} is not an empty index", self.name)),
+        };
+
+        // the cell of each entry, along with the child page to its left
+        // (for interior pages)
+        let mut level = entries.map(|entry| {
+            let payload = entry?.serialize();
+            let cell = self.build_cell(
+                VarInt::new(payload.len() as i64).serialize(),
+                &payload,
+                true,
+            )?;
+            return Ok((None, cell));
+        });
+        let (mut dividers, mut right_child) =
+            self.build_index_level(&mut level, None, page_size, reserved_space)?;
+        while !dividers.is_empty() {
+            let (next, right) = self.build_index_level(
+                &mut dividers.into_iter().map(Ok),
+                Some(right_child),
+                page_size,
+                reserved_space,
+            )?;
+            dividers = next;
+            right_child = right;
+        }
+        return Ok(());
+    }
+
+    /// Writes one level of an index being built by `build_index`, and
+    /// returns the entries that divide its pages, which make up the level
+    /// above, and the right-most page. `right_child` is the child page to
+    /// the right of the last entry, for interior pages. If the level fits
+    /// on one page, it is written to the root, and there are no dividers.
+    fn build_index_level(
+        &self,
+        level: &mut dyn Iterator<Item = Result<IndexBuildItem>>,
+        right_child: Option<u32>,
+        page_size: usize,
+        reserved_space: u8,
+    ) -> Result<(Vec<IndexBuildItem>, u32)> {
+        let usable_space = page_size - reserved_space as usize;
+        let page_type = match right_child {
+            None => PageType::IndexLeaf,
+            Some(_) => PageType::IndexInterior,
+        };
+        let cell = |(child, contents): &IndexBuildItem| {
+            return with_child(contents, *child);
+        };
+        let write_page = |group: Vec<IndexBuildItem>,
+                          right_pointer: Option<u32>,
+                          is_root: bool|
+         -> Result<u32> {
+            let cells: Vec<Vec<u8>> = group.iter().map(cell).collect();
+            let mut pager = self.pager.write();
+            let page_num = if is_root {
+                self.root_page
+            } else {
+                pager.allocate_page(page_type)?
+            };
+            let page = match right_pointer {
+                None => IndexLeafPage::from_cells(&cells, page_size, reserved_space)
+                    .map(BtreePage::IndexLeaf),
+                Some(right_pointer) => {
+                    IndexInteriorPage::from_cells(&cells, right_pointer, page_size, reserved_space)
+                        .map(BtreePage::IndexInterior)
+                }
+            };
+            pager.insert(page_num, page.unwrap())?;
+            return Ok(page_num as u32);
+        };
+
+        // the entries on the pages not yet written, and those that divide
+        // them; the last two pages are held back, as the last one may
+        // need an entry from the one before
+        let mut groups = VecDeque::from(vec![Vec::new()]);
+        let mut dividers = VecDeque::new();
+        let mut next_level = Vec::new();
+        let mut used = PageHeader::new(page_type, page_size, reserved_space).size();
+        for item in level {
+            let item = item?;
+            let size = std::cmp::max(cell(&item).len(), MIN_CELL_SIZE) + 2;
+            if used + size <= usable_space {
+                used += size;
+                groups.back_mut().unwrap().push(item);
+                continue;
+            }
+            dividers.push_back(item);
+            groups.push_back(Vec::new());
+            used = PageHeader::new(page_type, page_size, reserved_space).size();
+            if groups.len() > 2 {
+                // a page's right-most child is the child to the left of
+                // the entry that follows it
+                let (child, contents) = dividers.pop_front().unwrap();
+                let page_num = write_page(groups.pop_front().unwrap(), child, false)?;
+                next_level.push((Some(page_num), contents));
+            }
+        }
+        // the last page must not be left empty, so it takes the last
+        // divider, and the entry before that divides it instead
+        if groups.len() > 1 && groups.back().unwrap().is_empty() {
+            let n = groups.len();
+            let divider = dividers.pop_back().unwrap();
+            groups[n - 1].push(divider);
+            let previous = groups[n - 2].pop().unwrap();
+            dividers.push_back(previous);
+        }
+
+        let is_root = next_level.is_empty() && groups.len() == 1;
+        loop {
+            let group = groups.pop_front().unwrap();
+            match dividers.pop_front() {
+                Some((child, contents)) => {
+                    let page_num = write_page(group, child, false)?;
+                    next_level.push((Some(page_num), contents));
                 }
-                if child_page.is_none() {
-                    child_page = Some(pg.header.right_pointer.unwrap());
+                None => {
+                    let page_num = write_page(group, right_child, is_root)?;
+                    return Ok((next_level, page_num));
                 }
-                return self.get_index_rcrs(index, child_page.unwrap() as usize);
             }
-            _ => return None, // not defined for table pages
         }
     }
 
-    pub fn list_records(&self) -> Vec<(VarInt, Record)> {
-        return self.list_records_rcrs(self.root_page);
+    /// Whether the tree has no entries: that is, its root is an empty
+    /// leaf page.
+    pub fn is_empty(&self) -> Result<bool> {
+        return Ok(match &*self.get_page(self.root_page)? {
+            BtreePage::TableLeaf(pg) => pg.header.num_cells == 0,
+            BtreePage::IndexLeaf(pg) => pg.header.num_cells == 0,
+            _ => false,
+        });
     }
 
-    fn list_records_rcrs(&self, page_num: usize) -> Vec<(VarInt, Record)> {
-        let mut output = Vec::new();
-        let page = self.get_page(page_num);
-        if let Err(_) = page {
-            return output;
+    /// Fills an empty table with rows, which must already be in row ID
+    /// order. Leaf pages are packed as full as they will go, from left to
+    /// right, and so is each level of interior pages above them, until a
+    /// level fits on the root page.
+    pub fn build_table(&self, rows: &[(i64, Record)]) -> Result<()> {
+        let page = self.get_page(self.root_page)?;
+        let root = match &*page {
+            BtreePage::TableLeaf(pg) if pg.header.num_cells == 0 => pg,
+            _ => return Err(eyre!("{} is not an empty table", self.name)),
+        };
+        let (page_size, reserved_space) = (root.page_size, root.reserved_space);
+        let usable_space = page_size - reserved_space as usize;
+
+        // the cells of the current level, each with the largest row ID
+        // in it or below it
+        let mut level = Vec::with_capacity(rows.len());
+        for (row_id, record) in rows {
+            level.push((*row_id, self.table_cell(*row_id, record)?));
         }
-        match page.unwrap() {
-            BtreePage::TableLeaf(pg) => {
-                for row in pg.iter() {
-                    output.push(row);
+        let mut page_type = PageType::TableLeaf;
+
+        let mut pager = self.pager.write();
+        loop {
+            let header_size = PageHeader::new(page_type, page_size, reserved_space).size();
+            let mut groups = vec![Vec::new()];
+            let mut used = header_size;
+            for item in level {
+                let size = std::cmp::max(item.1.len(), MIN_CELL_SIZE) + 2;
+                if used + size > usable_space {
+                    groups.push(Vec::new());
+                    used = header_size;
                 }
+                used += size;
+                groups.last_mut().unwrap().push(item);
+            }
+            // an interior page needs a cell as well as its right-most
+            // child, so the last page takes one from the page before it
+            let n = groups.len();
+            if page_type == PageType::TableInterior && n > 1 && groups[n - 1].len() == 1 {
+                let item = groups[n - 2].pop().unwrap();
+                groups[n - 1].insert(0, item);
+            }
+
+            let mut next_level = Vec::with_capacity(n);
+            for mut group in groups {
+                let max_row_id = group.last().map_or(0, |(row_id, _)| *row_id);
+                let page_num = if n == 1 {
+                    self.root_page
+                } else {
+                    pager.allocate_page(page_type)?
+                };
+                let page = if page_type == PageType::TableLeaf {
+                    let cells: Vec<Vec<u8>> = group.into_iter().map(|(_, cell)| cell).collect();
+                    TableLeafPage::from_cells(&cells, 0, page_size, reserved_space)
+                        .map(BtreePage::TableLeaf)
+                } else {
+                    // the last child is the page's right pointer, which
+                    // has no cell
+                    let (_, last) = group.pop().unwrap();
+                    let right_pointer = u32::from_be_bytes([last[0], last[1], last[2], last[3]]);
+                    let cells: Vec<Vec<u8>> = group.into_iter().map(|(_, cell)| cell).collect();
+                    TableInteriorPage::from_cells(
+                        &cells,
+                        right_pointer,
+                        0,
+                        page_size,
+                        reserved_space,
+                    )
+                    .map(BtreePage::TableInterior)
+                };
+                pager.insert(page_num, page.unwrap())?;
+                let mut cell = (page_num as u32).to_be_bytes().to_vec();
+                cell.extend(VarInt::new(max_row_id).serialize());
+                next_level.push((max_row_id, cell));
+            }
+            if n == 1 {
+                return Ok(());
+            }
+            level = next_level;
+            page_type = PageType::TableInterior;
+        }
+    }
+
+    /// Reads a page of the tree, along with the rest of each payload on
+    /// it that continues on overflow pages.
+    fn get_page(&self, page_num: usize) -> Result<Arc<BtreePage>> {
+        let pager = self.pager.read();
+        let max_record_size = pager.limits().max_record_size;
+        let page = pager.get_page(page_num)?;
+        if page.largest_payload() > max_record_size {
+            return Err(eyre!("string or blob too big"));
+        }
+        let payloads = page.overflow_payloads();
+        if payloads.is_empty() {
+            // shared with the pager's cache
+            return Ok(page);
+        }
+        // only a page with payloads on overflow pages is copied, to hold
+        // them
+        let mut page = (*page).clone();
+        let mut overflow = HashMap::new();
+        for payload in payloads {
+            let contents = self.read_overflow(&pager, page_num, &payload)?;
+            overflow.insert(payload.first_page, contents);
+        }
+        if let Some(map) = page.overflow_mut() {
+            *map = overflow;
+        }
+        return Ok(Arc::new(page));
+    }
+
+    /// Puts together a payload that continues on overflow pages, by
+    /// following the chain of pages from the first. `cell_page` is the
+    /// page holding the cell, which is blamed if the chain is broken
+    /// from the start.
+    fn read_overflow(
+        &self,
+        pager: &Pager,
+        cell_page: usize,
+        payload: &OverflowPayload,
+    ) -> Result<Vec<u8>> {
+        let per_page = pager.page_size() - pager.reserved_space() as usize - 4;
+        let mut contents = payload.local.clone();
+        let mut previous = cell_page;
+        let mut page_num = payload.first_page as usize;
+        while contents.len() < payload.size {
+            check_overflow_link(&self.name, previous, page_num, pager.num_pages())?;
+            let bytes = pager.get_raw_page(page_num)?;
+            let n = std::cmp::min(per_page, payload.size - contents.len());
+            contents.extend(&bytes[4..4 + n]);
+            previous = page_num;
+            page_num = parsing::be_u32(&bytes[..4])? as usize;
+        }
+        return Ok(contents);
+    }
+
+    /// Puts every page of the tree on the freelist: the root, the pages
+    /// below it, and the overflow pages of their cells. Removing the
+    /// tree from the schema is up to the caller, and the tree cannot be
+    /// used afterwards.
+    pub fn destroy(&self) -> Result<()> {
+        let mut stack = vec![(self.root_page, 1)];
+        while let Some((page_num, depth)) = stack.pop() {
+            self.check_depth(depth)?;
+            let page = self.get_page(page_num)?;
+            for cell in page.cells() {
+                self.free_overflow(page.header().page_type, &cell)?;
+            }
+            stack.extend(
+                page.children()
+                    .into_iter()
+                    .map(|child| (child as usize, depth + 1)),
+            );
+            self.pager.write().free_page(page_num)?;
+        }
+        return Ok(());
+    }
+
+    /// Puts the overflow pages a cell's payload continues on, if any, on
+    /// the freelist, as the cell is being removed from the tree.
+    fn free_overflow(&self, page_type: PageType, cell: &[u8]) -> Result<()> {
+        let mut pager = self.pager.write();
+        let (page_size, reserved_space) = (pager.page_size(), pager.reserved_space() as usize);
+        let cell = Cell::parse(page_type, cell, page_size, reserved_space)
+            .ok_or_else(|| eyre!("{} is malformed", self.name))?;
+        let per_page = page_size - reserved_space - 4;
+        let mut remaining = cell.payload_size - cell.payload.len();
+        let mut page_num = cell.overflow_page.unwrap_or(0) as usize;
+        while remaining > 0 && page_num != 0 {
+            let bytes = pager.get_raw_page(page_num)?;
+            pager.free_page(page_num)?;
+            remaining = remaining.saturating_sub(per_page);
+            page_num = parsing::be_u32(&bytes[..4])? as usize;
+        }
+        return Ok(());
+    }
+
+    /// Builds the cell of a row of a table: the size of the record, the
+    /// row ID, and the record itself.
+    fn table_cell(&self, row_id: i64, record: &Record) -> Result<Vec<u8>> {
+        let payload = record.serialize();
+        let mut cell = VarInt::new(payload.len() as i64).serialize();
+        cell.extend(VarInt::new(row_id).serialize());
+        return self.build_cell(cell, &payload, false);
+    }
+
+    /// Finishes a cell, which so far holds what comes before the payload,
+    /// by adding the payload. As much of it as the cell can hold is kept
+    /// in the cell, and the rest goes on a chain of new overflow pages,
+    /// each starting with the number of the next, and the number of the
+    /// first ends the cell.
+    fn build_cell(&self, mut cell: Vec<u8>, payload: &[u8], is_index: bool) -> Result<Vec<u8>> {
+        self.check_record_size(payload.len())?;
+        let mut pager = self.pager.write();
+        let (page_size, reserved_space) = (pager.page_size(), pager.reserved_space() as usize);
+        let on_page = calc_payload_on_page(page_size, reserved_space, payload.len(), is_index);
+        cell.extend(&payload[..on_page]);
+        if on_page == payload.len() {
+            return Ok(cell);
+        }
+
+        let chunks: Vec<&[u8]> = payload[on_page..]
+            .chunks(page_size - reserved_space - 4)
+            .collect();
+        let mut page_nums = Vec::with_capacity(chunks.len());
+        for _ in &chunks {
+            page_nums.push(pager.allocate_raw_page(vec![0; page_size])?);
+        }
+        for (i, chunk) in chunks.iter().enumerate() {
+            let next = page_nums.get(i + 1).map_or(0, |&next| next as u32);
+            let mut bytes = next.to_be_bytes().to_vec();
+            bytes.extend(*chunk);
+            bytes.resize(page_size, 0);
+            pager.insert_raw_page(page_nums[i], bytes)?;
+        }
+        // the first page is recorded in the pointer map once the cell is
+        // put on a page, and each later page follows the one before
+        if pager.is_auto_vacuum() {
+            let entries = page_nums.windows(2).map(|pair| {
+                let entry = PtrmapEntry {
+                    kind: PtrmapType::Overflow2,
+                    parent: pair[0] as u32,
+                };
+                return (pair[1], entry);
+            });
+            pager.ptrmap_put_all(entries.collect())?;
+        }
+        cell.extend((page_nums[0] as u32).to_be_bytes().iter());
+        return Ok(cell);
+    }
+
+    /// Errors if a descent from the root has reached more levels than the
+    /// B-tree depth limit allows, which in a file of any reasonable size
+    /// only happens if it is corrupt (e.g., its child pointers form a
+    /// cycle). The root is at depth 1.
+    fn check_depth(&self, depth: usize) -> Result<()> {
+        let max_depth = self.pager.read().limits().max_btree_depth;
+        if depth > max_depth {
+            return Err(eyre!(
+                "{} is deeper than the limit of {} levels; database disk image is malformed",
+                self.name,
+                max_depth
+            ));
+        }
+        return Ok(());
+    }
+
+    fn check_record_size(&self, size: usize) -> Result<()> {
+        if size > self.pager.read().limits().max_record_size {
+            return Err(eyre!("string or blob too big"));
+        }
+        return Ok(());
+    }
+}
+
+/// An entry of an index being built by `Btree::build_index`: the child
+/// page to its left (for interior pages), and its cell without the
+/// child page.
+type IndexBuildItem = (Option<u32>, Vec<u8>);
+
+/// A problem with the structure of a B-tree, as found by `check`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Infraction {
+    pub tree: String,
+    pub page_num: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Infraction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(
+            f,
+            "page {} of {}: {}",
+            self.page_num, self.tree, self.message
+        );
+    }
+}
+
+/// Checks the structure of a B-tree: that each page is valid (see
+/// `integrity::validate_page`), with its cells and free blocks not
+/// overlapping and taking up all of its cell content area but for the
+/// fragments it records; that the keys on each page are in order, and
+/// within the range its parent gives its subtree; that its pages are all
+/// of the tree's kind, with every leaf at the same depth; and that no
+/// page is reached twice. The order of an index's entries depends on its
+/// columns, which the tree does not know, so only row IDs are checked
+/// for order here (see `check_index`).
+pub fn check(tree: &Btree) -> Vec<Infraction> {
+    return check_tree(tree, None);
+}
+
+/// Checks the structure of an index tree as `check` does, and that its
+/// entries are in the order `compare` gives.
+pub fn check_index(
+    tree: &Btree,
+    compare: &dyn Fn(&Record, &Record) -> Ordering,
+) -> Vec<Infraction> {
+    return check_tree(tree, Some(compare));
+}
+
+/// A key on a page being checked: a row ID in a table, or an entry in
+/// an index.
+#[derive(Clone)]
+enum CheckKey {
+    RowId(i64),
+    Entry(usize, Record),
+}
+
+impl CheckKey {
+    fn describe(&self) -> String {
+        return match self {
+            Self::RowId(row_id) => format!("row ID {}", row_id),
+            Self::Entry(index, _) => format!("entry {}", index),
+        };
+    }
+}
+
+/// The order of the entries of an index, as given to `check_index`.
+type EntryOrder<'a> = dyn Fn(&Record, &Record) -> Ordering + 'a;
+
+fn check_tree(tree: &Btree, compare: Option<&EntryOrder<'_>>) -> Vec<Infraction> {
+    let mut infractions = Vec::new();
+    let mut report = |page_num: usize, message: String| {
+        infractions.push(Infraction {
+            tree: tree.name.clone(),
+            page_num: page_num,
+            message: message,
+        });
+    };
+    let (page_size, reserved_space, num_pages, max_depth) = {
+        let pager = tree.pager.read();
+        (
+            pager.page_size(),
+            pager.reserved_space(),
+            pager.num_pages,
+            pager.limits().max_btree_depth,
+        )
+    };
+    let usable_size = page_size - reserved_space as usize;
+    // None where the keys cannot be compared
+    let order = |a: &CheckKey, b: &CheckKey| match (a, b) {
+        (CheckKey::RowId(a), CheckKey::RowId(b)) => Some(a.cmp(b)),
+        (CheckKey::Entry(_, a), CheckKey::Entry(_, b)) => compare.map(|compare| compare(a, b)),
+        _ => None,
+    };
+
+    let mut is_table = None;
+    let mut leaf_depth = None;
+    let mut seen = HashSet::new();
+    // each page to check, with its depth, and the keys of its parent
+    // that its keys must be after and no later than (or, in an index,
+    // before)
+    let mut stack = vec![(tree.root_page, 1, None, None)];
+    while let Some((page_num, depth, lower, upper)) = stack.pop() {
+        if depth > max_depth {
+            report(
+                page_num,
+                format!("the tree is deeper than the limit of {} levels", max_depth),
+            );
+            continue;
+        }
+        if !seen.insert(page_num) {
+            report(page_num, "the page is used more than once".to_string());
+            continue;
+        }
+        let raw_bytes = match tree.pager.read().get_page(page_num) {
+            Ok(page) => page.raw_bytes(),
+            Err(err) => {
+                report(page_num, err.to_string());
+                continue;
+            }
+        };
+        if let Err(err) =
+            integrity::validate_page(&raw_bytes, page_num, page_size, reserved_space, num_pages)
+        {
+            report(page_num, err.reason);
+            continue;
+        }
+        let page = match tree.get_page(page_num) {
+            Ok(page) => page,
+            Err(err) => {
+                report(page_num, err.to_string());
+                continue;
+            }
+        };
+        let header = page.header();
+        let page_type = header.page_type;
+        if *is_table.get_or_insert(page_type.is_table()) != page_type.is_table() {
+            report(
+                page_num,
+                format!("a {:?} page is in the wrong kind of tree", page_type),
+            );
+            continue;
+        }
+        if page.is_leaf() {
+            let expected = *leaf_depth.get_or_insert(depth);
+            if depth != expected {
+                report(
+                    page_num,
+                    format!(
+                        "the leaf is at depth {} where others are at depth {}",
+                        depth, expected
+                    ),
+                );
+            }
+        }
+
+        // the cells and free blocks, which must not overlap, and with the
+        // fragments make up the cell content area
+        let bytes = page.bytes();
+        let mut spans: Vec<(usize, usize)> = header
+            .cell_pointers
+            .iter()
+            .map(|ptr| {
+                let start = *ptr as usize;
+                let cell = &bytes[start..usable_size];
+                let size = stored_cell_size(page_type, page_size, reserved_space as usize, cell);
+                (start, std::cmp::max(size, MIN_CELL_SIZE))
+            })
+            .collect();
+        match read_freeblocks(header, bytes) {
+            Ok(blocks) => spans.extend(blocks),
+            Err(err) => report(page_num, err.to_string()),
+        }
+        spans.sort_unstable();
+        for pair in spans.windows(2) {
+            if pair[0].0 + pair[0].1 > pair[1].0 {
+                report(
+                    page_num,
+                    format!(
+                        "the cell or free block at offset {} overlaps another",
+                        pair[1].0
+                    ),
+                );
             }
+        }
+        let used: usize = spans.iter().map(|(_, size)| size).sum();
+        let content_size = usable_size.saturating_sub(header.content_start());
+        let fragments = content_size as i64 - used as i64;
+        if fragments != header.fragmented_bytes as i64 {
+            report(
+                page_num,
+                format!(
+                    "{} fragmented bytes are recorded, but {} were found",
+                    header.fragmented_bytes, fragments
+                ),
+            );
+        }
+
+        let keys: Vec<CheckKey> = match &*page {
+            BtreePage::TableLeaf(pg) => (0..header.cell_pointers.len())
+                .map(|i| CheckKey::RowId(pg.row_id(i)))
+                .collect(),
             BtreePage::TableInterior(pg) => {
-                for (ptr, _) in pg.iter() {
-                    output.append(&mut self.list_records_rcrs(ptr as usize));
+                pg.iter().map(|(_, key)| CheckKey::RowId(key.0)).collect()
+            }
+            BtreePage::IndexLeaf(pg) => pg
+                .iter()
+                .enumerate()
+                .map(|(i, record)| CheckKey::Entry(i, record))
+                .collect(),
+            BtreePage::IndexInterior(pg) => pg
+                .iter()
+                .enumerate()
+                .map(|(i, (_, record))| CheckKey::Entry(i, record))
+                .collect(),
+        };
+        for pair in keys.windows(2) {
+            if order(&pair[0], &pair[1]).is_some_and(|o| o != Ordering::Less) {
+                report(page_num, format!("{} is out of order", pair[1].describe()));
+            }
+        }
+        for key in &keys {
+            let after_lower = lower.as_ref().map_or(Some(true), |lower| {
+                order(key, lower).map(|o| o == Ordering::Greater)
+            });
+            // a table's divider is the last row ID in the subtree to its
+            // left, while an index's is an entry of its own
+            let before_upper = upper.as_ref().map_or(Some(true), |upper| {
+                order(key, upper)
+                    .map(|o| o == Ordering::Less || (page_type.is_table() && o == Ordering::Equal))
+            });
+            if after_lower == Some(false) || before_upper == Some(false) {
+                report(
+                    page_num,
+                    format!(
+                        "{} is outside of the range its parent gives",
+                        key.describe()
+                    ),
+                );
+            }
+        }
+
+        let children = page.children();
+        for (i, child) in children.iter().enumerate().rev() {
+            let child_lower = if i == 0 {
+                lower.clone()
+            } else {
+                Some(keys[i - 1].clone())
+            };
+            let child_upper = if i < keys.len() {
+                Some(keys[i].clone())
+            } else {
+                upper.clone()
+            };
+            stack.push((*child as usize, depth + 1, child_lower, child_upper));
+        }
+    }
+    return infractions;
+}
+
+/// A payload that is too large to be kept whole in its cell, as found by
+/// `BtreePage::overflow_payloads`.
+pub struct OverflowPayload {
+    pub size: usize,
+    /// The part of the payload stored in the cell.
+    pub local: Vec<u8>,
+    pub first_page: u32,
+}
+
+/// The way down a table B-tree to a leaf page, as found by
+/// `Btree::find_leaf`.
+pub struct LeafPath {
+    /// The interior pages above the leaf, from the root, along with the
+    /// index of the child followed from each.
+    pub path: Vec<(usize, usize)>,
+    pub page_num: usize,
+    pub leaf: TableLeafPage,
+}
+
+/// The way down an index B-tree to an entry, as found by
+/// `Btree::find_entry`.
+pub struct EntryPath {
+    /// The interior pages above the entry's page, as for `LeafPath`.
+    pub path: Vec<(usize, usize)>,
+    pub page_num: usize,
+    pub page: Arc<BtreePage>,
+    /// The index of the entry on the page.
+    pub index: usize,
+}
+
+/// A position in a B-tree, which can be moved from entry to entry in
+/// either direction. The entries of a table are its rows, which are all
+/// on leaf pages; those of an index are on interior pages too, each
+/// coming between the entries of the child pages on either side of it.
+/// The pages on the way down to the current entry are kept, so moving
+/// to a neighbouring entry usually reads no pages at all.
+pub struct BtreeCursor<'a> {
+    btree: &'a Btree,
+    /// The pages from the root down to the current entry, each with the
+    /// index of the child followed from it, or for the last page, the
+    /// index of the current entry's cell. This is empty when the cursor
+    /// is not on an entry.
+    stack: Vec<(Arc<BtreePage>, usize)>,
+}
+
+impl BtreeCursor<'_> {
+    /// Whether the cursor is on an entry.
+    pub fn is_valid(&self) -> bool {
+        return !self.stack.is_empty();
+    }
+
+    /// Moves to the first entry, returning false if there are none.
+    pub fn first(&mut self) -> Result<bool> {
+        self.stack.clear();
+        return self.descend(self.btree.root_page, false);
+    }
+
+    /// Moves to the last entry, returning false if there are none.
+    pub fn last(&mut self) -> Result<bool> {
+        self.stack.clear();
+        return self.descend(self.btree.root_page, true);
+    }
+
+    /// Moves to the first row of a table with a row ID of at least
+    /// `key[0]`, or the first entry of an index that is not before
+    /// `key`, comparing only as many values as there are in `key`.
+    /// Returns false if there is no such entry.
+    pub fn seek(&mut self, key: &[Value]) -> Result<bool> {
+        let root = self.btree.get_page(self.btree.root_page)?;
+        if !matches!(*root, BtreePage::TableLeaf(_) | BtreePage::TableInterior(_)) {
+            let order = &self.btree.key_order;
+            return self.seek_payload(&|payload| compare_prefix_payload(key, payload, order));
+        }
+        let row_id = match key {
+            [value] => value.get_int_val(),
+            _ => None,
+        }
+        .ok_or_else(|| {
+            eyre!(
+                "The rows of {} can only be found by row ID",
+                self.btree.name
+            )
+        })?;
+
+        self.stack.clear();
+        let mut page_num = self.btree.root_page;
+        loop {
+            self.btree.check_depth(self.stack.len() + 1)?;
+            let page = self.btree.get_page(page_num)?;
+            let index = match &*page {
+                BtreePage::TableLeaf(pg) => pg.search(row_id).unwrap_or_else(|index| index),
+                BtreePage::TableInterior(pg) => pg
+                    .iter()
+                    .position(|(_, key)| row_id <= key.0)
+                    .unwrap_or(pg.header.cell_pointers.len()),
+                _ => return Err(eyre!("{} is not a table", self.btree.name)),
+            };
+            if page.is_leaf() {
+                return self.settle(page, index);
+            }
+            page_num = page.children()[index] as usize;
+            self.stack.push((page, index));
+        }
+    }
+
+    /// Moves to the first entry of an index that is not before a
+    /// position. `position` tells where the position is relative to an
+    /// entry: `Greater` if it comes after it, and `Less` or `Equal`
+    /// otherwise. Returns false if there is no such entry.
+    pub fn seek_to(&mut self, position: &dyn Fn(&Record) -> Ordering) -> Result<bool> {
+        self.stack.clear();
+        let mut page_num = self.btree.root_page;
+        loop {
+            self.btree.check_depth(self.stack.len() + 1)?;
+            let page = self.btree.get_page(page_num)?;
+            let records: Vec<Record> = match &*page {
+                BtreePage::IndexLeaf(pg) => pg.iter().collect(),
+                BtreePage::IndexInterior(pg) => pg.iter().map(|(_, record)| record).collect(),
+                _ => return Err(eyre!("{} is not an index", self.btree.name)),
+            };
+            // any entries before this one that are not before the
+            // position are in the child to its left
+            let index = records
+                .iter()
+                .position(|record| position(record) != Ordering::Greater)
+                .unwrap_or(records.len());
+            if page.is_leaf() {
+                return self.settle(page, index);
+            }
+            page_num = page.children()[index] as usize;
+            self.stack.push((page, index));
+        }
+    }
+
+    /// Moves to the first entry of an index that is not before a
+    /// position, as `seek_to` does, but is given each entry as it is
+    /// serialized, so that it need only decode as much of it as it takes
+    /// to tell (see `compare_prefix_payload`). The entries of each page
+    /// are searched by bisection.
+    pub fn seek_payload(&mut self, position: &dyn Fn(&[u8]) -> Ordering) -> Result<bool> {
+        self.stack.clear();
+        let root = self.btree.get_page(self.btree.root_page)?;
+        return self.bisect_down(root, position);
+    }
+
+    /// Moves forward to the first entry of an index that is not before a
+    /// position, as `seek_payload` does, where the position must not be
+    /// before the current entry. Rather than starting again from the
+    /// root, the cursor only goes as far back up as the lowest page whose
+    /// entries run past the position, so moving through positions in
+    /// order (e.g., the sorted keys of an IN list) reads each page on the
+    /// way once.
+    pub fn advance_to(&mut self, position: &dyn Fn(&[u8]) -> Ordering) -> Result<bool> {
+        // the entries under a page all come before the cell its parent
+        // has after it, if there is one
+        let mut level = self.stack.len();
+        while level > 1 {
+            let (parent, child) = &self.stack[level - 2];
+            match index_payload(parent, *child) {
+                Some(bound) if position(bound) != Ordering::Greater => break,
+                _ => level -= 1,
+            }
+        }
+        if level == 0 {
+            return self.seek_payload(position);
+        }
+        self.stack.truncate(level);
+        let (page, _) = self.stack.pop().unwrap();
+        return self.bisect_down(page, position);
+    }
+
+    /// Goes down from `page` to the first entry below it that is not
+    /// before a position, adding the pages on the way to the stack. The
+    /// entries of each page are searched by bisection.
+    fn bisect_down(
+        &mut self,
+        mut page: Arc<BtreePage>,
+        position: &dyn Fn(&[u8]) -> Ordering,
+    ) -> Result<bool> {
+        loop {
+            self.btree.check_depth(self.stack.len() + 1)?;
+            if !matches!(*page, BtreePage::IndexLeaf(_) | BtreePage::IndexInterior(_)) {
+                return Err(eyre!("{} is not an index", self.btree.name));
+            }
+            // any entries before this one that are not before the
+            // position are in the child to its left
+            let (mut low, mut high) = (0, page.header().cell_pointers.len());
+            while low < high {
+                let mid = (low + high) / 2;
+                let entry = index_payload(&page, mid)
+                    .ok_or_else(|| eyre!("{} is malformed", self.btree.name))?;
+                if position(entry) == Ordering::Greater {
+                    low = mid + 1;
+                } else {
+                    high = mid;
                 }
             }
-            _ => (), // TODO: define for index pages
+            if page.is_leaf() {
+                return self.settle(page, low);
+            }
+            let child = self.btree.get_page(page.children()[low] as usize)?;
+            self.stack.push((page, low));
+            page = child;
         }
-        return output;
     }
 
-    fn get_page(&self, page_num: usize) -> Result<BtreePage> {
-        let mut pager = self.pager.borrow_mut();
-        let page = pager.get_page(page_num)?;
-        return Ok((*page).clone()); // TODO: get rid of clone
+    /// Moves to the next entry, returning false (and leaving the cursor
+    /// on no entry) if this was the last. Unlike `Iterator::next`, this
+    /// can fail, and moves the cursor rather than returning an entry.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<bool> {
+        let (page, index) = match self.stack.last_mut() {
+            Some(top) => top,
+            None => return Ok(false),
+        };
+        *index += 1;
+        if page.is_interior() {
+            // an entry on an interior page is followed by those in the
+            // child to its right
+            let child = page.children()[*index] as usize;
+            return self.descend(child, false);
+        }
+        if *index < page.header().cell_pointers.len() {
+            return Ok(true);
+        }
+        self.stack.pop();
+        while let Some((page, index)) = self.stack.last_mut() {
+            if let BtreePage::IndexInterior(pg) = &**page {
+                // the entry to the right of the child just left
+                if *index < pg.header.cell_pointers.len() {
+                    return Ok(true);
+                }
+            } else if *index + 1 < page.children().len() {
+                *index += 1;
+                let child = page.children()[*index] as usize;
+                return self.descend(child, false);
+            }
+            self.stack.pop();
+        }
+        return Ok(false);
+    }
+
+    /// Moves to the previous entry, returning false (and leaving the
+    /// cursor on no entry) if this was the first.
+    pub fn prev(&mut self) -> Result<bool> {
+        let (page, index) = match self.stack.last_mut() {
+            Some(top) => top,
+            None => return Ok(false),
+        };
+        if page.is_interior() {
+            // an entry on an interior page comes after those in the
+            // child to its left
+            let child = page.children()[*index] as usize;
+            return self.descend(child, true);
+        }
+        if *index > 0 {
+            *index -= 1;
+            return Ok(true);
+        }
+        self.stack.pop();
+        while let Some((page, index)) = self.stack.last_mut() {
+            if *index > 0 {
+                *index -= 1;
+                if page.header().page_type == PageType::IndexInterior {
+                    // the entry to the left of the child just left
+                    return Ok(true);
+                }
+                let child = page.children()[*index] as usize;
+                return self.descend(child, true);
+            }
+            self.stack.pop();
+        }
+        return Ok(false);
+    }
+
+    /// The record of the current entry, which counts as a row examined
+    /// (see `Metrics`).
+    pub fn current(&self) -> Option<Record> {
+        let record = self.entry()?;
+        self.btree.pager.read().count_rows_examined(1);
+        return Some(record);
+    }
+
+    fn entry(&self) -> Option<Record> {
+        let (page, index) = self.stack.last()?;
+        return match &**page {
+            BtreePage::TableLeaf(pg) => pg.row(*index).map(|(_, record)| record),
+            BtreePage::IndexLeaf(pg) => pg.record(*index),
+            BtreePage::IndexInterior(pg) => pg.entry(*index).map(|(_, record)| record),
+            BtreePage::TableInterior(_) => None,
+        };
+    }
+
+    /// The row ID of the current row of a table, or of the row the
+    /// current entry of an index refers to, which is the entry's last
+    /// value.
+    pub fn row_id(&self) -> Option<i64> {
+        let (page, index) = self.stack.last()?;
+        return match &**page {
+            BtreePage::TableLeaf(pg) => Some(pg.row_id(*index)),
+            _ => self.entry()?.values.last()?.get_int_val(),
+        };
+    }
+
+    /// Goes down from `page_num` to the first entry below it, or the last
+    /// if `last`, adding the pages on the way to the stack.
+    fn descend(&mut self, mut page_num: usize, last: bool) -> Result<bool> {
+        loop {
+            self.btree.check_depth(self.stack.len() + 1)?;
+            if !last {
+                self.read_ahead()?;
+            }
+            let page = self.btree.get_page(page_num)?;
+            if page.is_leaf() {
+                let num_cells = page.header().cell_pointers.len();
+                return self.settle(page, if last { num_cells.saturating_sub(1) } else { 0 });
+            }
+            let children = page.children();
+            let index = if last { children.len() - 1 } else { 0 };
+            page_num = children[index] as usize;
+            self.stack.push((page, index));
+        }
+    }
+
+    /// Reads ahead the pages a forward scan goes through after the one it
+    /// is about to go down to, which are the rest of the children of the
+    /// page on top of the stack (see `Pager::prefetch`).
+    fn read_ahead(&self) -> Result<()> {
+        let (parent, index) = match self.stack.last() {
+            Some(top) => top,
+            None => return Ok(()),
+        };
+        let pager = self.btree.pager.read();
+        if pager.config().scan_read_ahead == 0 {
+            return Ok(());
+        }
+        let children: Vec<usize> = parent.children()[*index..]
+            .iter()
+            .map(|&child| child as usize)
+            .collect();
+        return pager.prefetch(&children);
+    }
+
+    /// Puts the cursor on the cell at `index` of a leaf page, which has
+    /// just been reached from the pages on the stack. If it is past the
+    /// last cell, the cursor moves on to the next entry after the page.
+    fn settle(&mut self, page: Arc<BtreePage>, index: usize) -> Result<bool> {
+        let num_cells = page.header().cell_pointers.len();
+        if num_cells == 0 {
+            // only a root page can be empty
+            self.stack.clear();
+            return Ok(false);
+        }
+        let past_end = index >= num_cells;
+        self.stack.push((page, std::cmp::min(index, num_cells - 1)));
+        if past_end {
+            return self.next();
+        }
+        return Ok(true);
+    }
+}
+
+/// The serialized record of the cell at `index` of an index page, or
+/// None for a table page or a cell that is not there.
+fn index_payload(page: &BtreePage, index: usize) -> Option<&[u8]> {
+    return match page {
+        BtreePage::IndexLeaf(pg) => pg.payload(index),
+        BtreePage::IndexInterior(pg) => pg.payload(index).map(|(_, payload)| payload),
+        _ => None,
+    };
+}
+
+/// Checks the next link in the overflow chain of a payload of `name`,
+/// which `previous` points to: a chain that ends early, or goes off the
+/// end of the file, is a `CorruptPage` error for the page with the
+/// broken pointer.
+pub fn check_overflow_link(
+    name: &str,
+    previous: usize,
+    page_num: usize,
+    num_pages: usize,
+) -> Result<()> {
+    let reason = if page_num == 0 {
+        format!("overflow chain of {} ends early", name)
+    } else if page_num > num_pages {
+        format!("overflow page {} of {} does not exist", page_num, name)
+    } else {
+        return Ok(());
+    };
+    return Err(CorruptPage {
+        page_num: previous,
+        reason: reason,
+    }
+    .into());
+}
+
+/// The versions of the database a scan started from. Pages are read as
+/// a scan goes, so if the database is written to before it finishes
+/// (e.g., through another connection sharing the pager), the rest of
+/// the scan could see a tree that no longer fits with the rows it has
+/// already returned. Scans check their guard before each step, and end
+/// with a `ScanChanged` error instead.
+#[derive(Debug, Clone)]
+pub struct ScanGuard {
+    pager: SharedPager,
+    pub schema_version: u64,
+    pub data_version: u64,
+}
+
+impl ScanGuard {
+    pub fn new(pager: SharedPager) -> Self {
+        let (schema_version, data_version) = {
+            let pager = pager.read();
+            (pager.schema_version(), pager.generation())
+        };
+        return Self {
+            pager: pager,
+            schema_version: schema_version,
+            data_version: data_version,
+        };
+    }
+
+    /// Errors if the database has changed since the scan started.
+    pub fn check(&self) -> Result<()> {
+        let pager = self.pager.read();
+        if pager.schema_version() != self.schema_version {
+            return Err(ScanChanged::SchemaChanged.into());
+        }
+        if pager.generation() != self.data_version {
+            return Err(ScanChanged::DataChanged.into());
+        }
+        return Ok(());
+    }
+}
+
+/// The error a scan ends with if the database changed while it was
+/// running (see `ScanGuard`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanChanged {
+    SchemaChanged,
+    DataChanged,
+}
+
+impl fmt::Display for ScanChanged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            Self::SchemaChanged => write!(f, "database schema has changed during scan"),
+            Self::DataChanged => write!(f, "database was modified during scan"),
+        };
+    }
+}
+
+impl std::error::Error for ScanChanged {}
+
+/// A lazy, in-order scan over the entries of an index between two
+/// bounds, as made by `Btree::index_range`.
+pub struct IndexRange<'a> {
+    cursor: BtreeCursor<'a>,
+    /// The lower bound, until the cursor has been moved to it.
+    lower: Option<Bound<Record>>,
+    upper: Bound<Record>,
+    guard: ScanGuard,
+}
+
+impl IndexRange<'_> {
+    /// The versions of the database the scan started from.
+    pub fn guard(&self) -> &ScanGuard {
+        return &self.guard;
+    }
+}
+
+impl Iterator for IndexRange<'_> {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.lower.is_some() || self.cursor.is_valid() {
+            if let Err(e) = self.guard.check() {
+                self.lower = None;
+                self.cursor.stack.clear();
+                return Some(Err(e));
+            }
+        }
+        let order = &self.cursor.btree.key_order;
+        let moved = match self.lower.take() {
+            Some(Bound::Included(lower)) => {
+                self.cursor
+                    .seek_payload(&|payload| match compare_prefix_payload(
+                        &lower.values,
+                        payload,
+                        order,
+                    ) {
+                        Ordering::Greater => Ordering::Greater,
+                        _ => Ordering::Equal,
+                    })
+            }
+            Some(Bound::Excluded(lower)) => {
+                self.cursor
+                    .seek_payload(&|payload| match compare_prefix_payload(
+                        &lower.values,
+                        payload,
+                        order,
+                    ) {
+                        Ordering::Less => Ordering::Equal,
+                        _ => Ordering::Greater,
+                    })
+            }
+            Some(Bound::Unbounded) => self.cursor.first(),
+            None => self.cursor.next(),
+        };
+        match moved {
+            Ok(true) => {
+                let record = self.cursor.current()?;
+                let in_range = match &self.upper {
+                    Bound::Included(upper) => {
+                        compare_prefix(&upper.values, &record, order) != Ordering::Less
+                    }
+                    Bound::Excluded(upper) => {
+                        compare_prefix(&upper.values, &record, order) == Ordering::Greater
+                    }
+                    Bound::Unbounded => true,
+                };
+                if !in_range {
+                    self.cursor.stack.clear();
+                    return None;
+                }
+                return Some(Ok(record));
+            }
+            Ok(false) => return None,
+            Err(e) => {
+                // the scan cannot continue past a page it could not read
+                self.cursor.stack.clear();
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+/// A lazy, in-order scan over the rows of a table B-tree.
+pub struct TableScan<'a> {
+    cursor: BtreeCursor<'a>,
+    started: bool,
+    guard: ScanGuard,
+}
+
+impl TableScan<'_> {
+    /// The versions of the database the scan started from.
+    pub fn guard(&self) -> &ScanGuard {
+        return &self.guard;
+    }
+}
+
+impl Iterator for TableScan<'_> {
+    type Item = Result<(VarInt, Record)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.started || self.cursor.is_valid() {
+            if let Err(e) = self.guard.check() {
+                self.started = true;
+                self.cursor.stack.clear();
+                return Some(Err(e));
+            }
+        }
+        let moved = if self.started {
+            self.cursor.next()
+        } else {
+            self.started = true;
+            self.cursor.first()
+        };
+        match moved {
+            Ok(true) => {
+                let record = self.cursor.current()?;
+                let row_id = self.cursor.row_id().unwrap_or(0);
+                return Some(Ok((VarInt::new(row_id), record)));
+            }
+            Ok(false) => return None,
+            Err(e) => {
+                // the scan cannot continue past a page it could not read
+                self.cursor.stack.clear();
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+/// A lazy scan over the rows of a random sample of the leaf pages of a
+/// table B-tree, as made by `Btree::sample_pages`.
+pub struct PageSample<'a> {
+    btree: &'a Btree,
+    fraction: f64,
+    rng: SampleRng,
+    /// The pages still to visit, with their depths, the next one last.
+    pending: Vec<(usize, usize)>,
+    /// The depth of the leaf pages, once the first has been read.
+    leaf_depth: Option<usize>,
+    /// The rest of the rows of the leaf page being read.
+    rows: VecDeque<(VarInt, Record)>,
+    guard: ScanGuard,
+}
+
+impl PageSample<'_> {
+    /// The versions of the database the scan started from.
+    pub fn guard(&self) -> &ScanGuard {
+        return &self.guard;
+    }
+
+    fn visit(&mut self, page_num: usize, depth: usize) -> Result<()> {
+        self.guard.check()?;
+        self.btree.check_depth(depth)?;
+        // all leaves are at the same depth, so once one has been found,
+        // the others can be left out without reading them
+        if self.leaf_depth == Some(depth) && !self.rng.chance(self.fraction) {
+            return Ok(());
+        }
+        match &*self.btree.get_page(page_num)? {
+            BtreePage::TableLeaf(pg) => {
+                if self.leaf_depth.is_none() {
+                    self.leaf_depth = Some(depth);
+                    if !self.rng.chance(self.fraction) {
+                        return Ok(());
+                    }
+                }
+                let before = self.rows.len();
+                self.rows.extend(pg.iter());
+                let examined = (self.rows.len() - before) as u64;
+                self.btree.pager.read().count_rows_examined(examined);
+            }
+            BtreePage::TableInterior(pg) => {
+                let children = pg.children().into_iter().rev();
+                self.pending
+                    .extend(children.map(|child| (child as usize, depth + 1)));
+            }
+            _ => return Err(eyre!("{} is not a table", self.btree.name)),
+        }
+        return Ok(());
+    }
+}
+
+impl Iterator for PageSample<'_> {
+    type Item = Result<(VarInt, Record)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(row) = self.rows.pop_front() {
+                return Some(Ok(row));
+            }
+            let (page_num, depth) = self.pending.pop()?;
+            if let Err(e) = self.visit(page_num, depth) {
+                self.pending.clear();
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+/// A small pseudo-random number generator (SplitMix64) for sampling,
+/// random() and picking row IDs at random, which gives the same numbers
+/// from the same seed on every platform. It is not suitable for anything that
+/// needs to be unpredictable.
+#[derive(Debug, Clone)]
+pub struct SampleRng {
+    state: u64,
+}
+
+impl SampleRng {
+    pub fn new(seed: u64) -> Self {
+        return Self { state: seed };
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        return z ^ (z >> 31);
+    }
+
+    /// Returns true with the given probability.
+    pub fn chance(&mut self, probability: f64) -> bool {
+        // the top 53 bits, as a number in [0, 1)
+        let x = (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        return x < probability;
     }
 }
 
@@ -152,25 +2212,26 @@ pub enum BtreePage {
 impl BtreePage {
     pub fn new(page_type: PageType, page_size: usize, reserved_space: u8) -> Self {
         let page_header = PageHeader::new(page_type, page_size, reserved_space);
+        let bytes = vec![0; page_size];
         return match page_type {
             PageType::TableLeaf => Self::TableLeaf(TableLeafPage::new(
                 page_header,
-                &Vec::new(),
+                &bytes,
                 page_size,
                 reserved_space,
             )),
             PageType::IndexLeaf => Self::IndexLeaf(IndexLeafPage::new(
                 page_header,
-                &Vec::new(),
+                &bytes,
                 page_size,
                 reserved_space,
             )),
             PageType::TableInterior => {
-                Self::TableInterior(TableInteriorPage::new(page_header, &Vec::new()))
+                Self::TableInterior(TableInteriorPage::new(page_header, &bytes))
             }
             PageType::IndexInterior => Self::IndexInterior(IndexInteriorPage::new(
                 page_header,
-                &Vec::new(),
+                &bytes,
                 page_size,
                 reserved_space,
             )),
@@ -217,20 +2278,255 @@ impl BtreePage {
         };
     }
 
-    pub fn is_interior(&self) -> bool {
-        match self {
-            Self::TableInterior(_) => true,
-            Self::IndexInterior(_) => true,
-            _ => false,
+    /// The whole page as it is written to the file. `serialize` starts at
+    /// the page header, so on page 1 it leaves out the file header, which
+    /// is written here from `header`.
+    pub fn serialize_page(&self, page_num: usize, header: &DbOptions) -> Result<Vec<u8>> {
+        let offset = if page_num == 1 { 100 } else { 0 };
+        if self.header().offset != offset {
+            return Err(eyre!(
+                "The header of page {} is at offset {}, not {}",
+                page_num,
+                self.header().offset,
+                offset
+            ));
+        }
+        let mut output = Vec::with_capacity(header.page_size);
+        if page_num == 1 {
+            output.extend(header.serialize());
         }
+        output.extend(self.serialize());
+        return Ok(output);
+    }
+
+    pub fn header(&self) -> &PageHeader {
+        return match self {
+            Self::TableLeaf(pg) => &pg.header,
+            Self::IndexLeaf(pg) => &pg.header,
+            Self::TableInterior(pg) => &pg.header,
+            Self::IndexInterior(pg) => &pg.header,
+        };
+    }
+
+    pub fn header_mut(&mut self) -> &mut PageHeader {
+        return match self {
+            Self::TableLeaf(pg) => &mut pg.header,
+            Self::IndexLeaf(pg) => &mut pg.header,
+            Self::TableInterior(pg) => &mut pg.header,
+            Self::IndexInterior(pg) => &mut pg.header,
+        };
+    }
+
+    pub fn is_interior(&self) -> bool {
+        return matches!(self, Self::TableInterior(_) | Self::IndexInterior(_));
     }
 
     pub fn is_leaf(&self) -> bool {
-        match self {
-            Self::TableLeaf(_) => true,
-            Self::IndexLeaf(_) => true,
-            _ => false,
+        return matches!(self, Self::TableLeaf(_) | Self::IndexLeaf(_));
+    }
+
+    /// The size of the largest record on the page, as given by its cells,
+    /// which may be more than is stored on the page.
+    pub fn largest_payload(&self) -> usize {
+        let (header, bytes, skip) = match self {
+            Self::TableLeaf(pg) => (&pg.header, &pg.bytes, 0),
+            Self::IndexLeaf(pg) => (&pg.header, &pg.bytes, 0),
+            Self::IndexInterior(pg) => (&pg.header, &pg.bytes, 4),
+            Self::TableInterior(_) => return 0,
+        };
+        return header
+            .cell_pointers
+            .iter()
+            .filter_map(|ptr| bytes.get(*ptr as usize + skip..))
+            .map(cell_payload_size)
+            .max()
+            .unwrap_or(0);
+    }
+
+    /// The bytes of the page as they are written to the file, with its
+    /// header and cell pointers in place.
+    fn raw_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.bytes().to_vec();
+        let header = self.header().serialize();
+        let offset = self.header().offset;
+        bytes[offset..offset + header.len()].copy_from_slice(&header);
+        return bytes;
+    }
+
+    fn bytes(&self) -> &[u8] {
+        return match self {
+            Self::TableLeaf(pg) => &pg.bytes,
+            Self::IndexLeaf(pg) => &pg.bytes,
+            Self::TableInterior(pg) => &pg.bytes,
+            Self::IndexInterior(pg) => &pg.bytes,
+        };
+    }
+
+    /// The raw bytes of each cell on the page, in order, starting with
+    /// the child page number for an interior page.
+    pub fn cells(&self) -> Vec<Vec<u8>> {
+        if let Self::TableLeaf(pg) = self {
+            return pg.cells();
+        }
+        let (page_size, reserved_space) = (self.bytes().len(), self.reserved_space());
+        let page_type = self.header().page_type;
+        let bytes = self.bytes();
+        return self
+            .header()
+            .cell_pointers
+            .iter()
+            .map(|ptr| {
+                let cell = &bytes[*ptr as usize..];
+                cell[..stored_cell_size(page_type, page_size, reserved_space, cell)].to_vec()
+            })
+            .collect();
+    }
+
+    fn reserved_space(&self) -> usize {
+        return match self {
+            Self::TableLeaf(pg) => pg.reserved_space as usize,
+            Self::IndexLeaf(pg) => pg.reserved_space as usize,
+            Self::IndexInterior(pg) => pg.reserved_space as usize,
+            // table interior pages do not need it
+            Self::TableInterior(_) => 0,
+        };
+    }
+
+    /// The child pages, in order, ending with the right-most one. Leaf
+    /// pages have none.
+    pub fn children(&self) -> Vec<u32> {
+        if self.is_leaf() {
+            return Vec::new();
+        }
+        let bytes = self.bytes();
+        let mut children: Vec<u32> = self
+            .header()
+            .cell_pointers
+            .iter()
+            .map(|ptr| {
+                let ptr = *ptr as usize;
+                return u32::from_be_bytes([
+                    bytes[ptr],
+                    bytes[ptr + 1],
+                    bytes[ptr + 2],
+                    bytes[ptr + 3],
+                ]);
+            })
+            .collect();
+        children.extend(self.header().right_pointer);
+        return children;
+    }
+
+    /// The payloads on the page that continue on overflow pages.
+    pub fn overflow_payloads(&self) -> Vec<OverflowPayload> {
+        let (skip_row_id, skip) = match self {
+            Self::TableLeaf(_) => (true, 0),
+            Self::IndexLeaf(_) => (false, 0),
+            Self::IndexInterior(_) => (false, 4),
+            Self::TableInterior(_) => return Vec::new(),
+        };
+        let (page_size, reserved_space) = (self.bytes().len(), self.reserved_space());
+        let bytes = self.bytes();
+        let mut output = Vec::new();
+        for ptr in &self.header().cell_pointers {
+            let mut pos = *ptr as usize + skip;
+            let (size, b) = VarInt::deserialize(&bytes[pos..]);
+            pos += b;
+            if skip_row_id {
+                pos += VarInt::deserialize(&bytes[pos..]).1;
+            }
+            let size = size.0 as usize;
+            let on_page = calc_payload_on_page(page_size, reserved_space, size, !skip_row_id);
+            if on_page < size {
+                output.push(OverflowPayload {
+                    size: size,
+                    local: bytes[pos..pos + on_page].to_vec(),
+                    first_page: u32::from_be_bytes([
+                        bytes[pos + on_page],
+                        bytes[pos + on_page + 1],
+                        bytes[pos + on_page + 2],
+                        bytes[pos + on_page + 3],
+                    ]),
+                });
+            }
+        }
+        return output;
+    }
+
+    /// The whole payloads of the cells that continue on overflow pages,
+    /// to be filled in by whoever reads the page (see `Btree::get_page`),
+    /// or None for a page whose cells have no payloads.
+    pub fn overflow_mut(&mut self) -> Option<&mut HashMap<u32, Vec<u8>>> {
+        return match self {
+            Self::TableLeaf(pg) => Some(&mut pg.overflow),
+            Self::IndexLeaf(pg) => Some(&mut pg.overflow),
+            Self::IndexInterior(pg) => Some(&mut pg.overflow),
+            Self::TableInterior(_) => None,
+        };
+    }
+
+    /// Moves the cells on the page together at the end of it, in the
+    /// order of their pointers, so that all of its free space is
+    /// unallocated space between the cell pointer array and the cells,
+    /// with no free blocks or fragments left.
+    pub fn defragment(&mut self) {
+        let cells = self.cells();
+        let reserved_space = self.reserved_space();
+        let (header, bytes) = self.parts_mut();
+        defragment(header, bytes, reserved_space, &cells);
+    }
+
+    /// The number of bytes on the page not used by cells: the
+    /// unallocated space, free blocks, and fragments.
+    pub fn free_space(&self) -> Result<usize> {
+        let header = self.header();
+        let free_blocks: usize = read_freeblocks(header, self.bytes())?
+            .iter()
+            .map(|(_, size)| size)
+            .sum();
+        return Ok(header.unallocated_space() + free_blocks + header.fragmented_bytes as usize);
+    }
+
+    fn parts_mut(&mut self) -> (&mut PageHeader, &mut Vec<u8>) {
+        return match self {
+            Self::TableLeaf(pg) => (&mut pg.header, &mut pg.bytes),
+            Self::IndexLeaf(pg) => (&mut pg.header, &mut pg.bytes),
+            Self::TableInterior(pg) => (&mut pg.header, &mut pg.bytes),
+            Self::IndexInterior(pg) => (&mut pg.header, &mut pg.bytes),
+        };
+    }
+
+    /// Creates a page of the given type holding the given cells, in
+    /// order, or returns `None` if they do not fit. `right_pointer` is
+    /// the right-most child, for interior pages, and `offset` is where
+    /// the page header starts (100 on page 1, after the file header).
+    pub fn from_cells(
+        page_type: PageType,
+        cells: &[Vec<u8>],
+        right_pointer: Option<u32>,
+        offset: usize,
+        page_size: usize,
+        reserved_space: u8,
+    ) -> Option<Self> {
+        let mut page = Self::new(page_type, page_size, reserved_space);
+        page.header_mut().offset = offset;
+        page.header_mut().right_pointer = right_pointer;
+        let (header, bytes) = page.parts_mut();
+        for (i, cell) in cells.iter().enumerate() {
+            if !header.has_room_for(cell.len()) {
+                return None;
+            }
+            place_cell(header, bytes, i, cell);
         }
+        return Some(page);
+    }
+
+    /// Whether the cells on the page take up less than a third of it,
+    /// so that it should be balanced with its siblings.
+    pub fn is_underfull(&self) -> Result<bool> {
+        let free_space = self.free_space()?;
+        let usable_space = self.bytes().len() - self.reserved_space();
+        return Ok(free_space * 3 > usable_space * 2);
     }
 
     pub fn get_page_type(&self) -> String {
@@ -243,7 +2539,7 @@ impl BtreePage {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PageHeader {
     pub page_type: PageType,
     pub offset: usize,
@@ -275,9 +2571,19 @@ impl PageHeader {
     }
 
     pub fn deserialize(i: &[u8], offset: usize) -> Result<Self> {
+        let mut header = Self::deserialize_header(i, offset)?;
+        header.deserialize_cell_pointers(&i[header.size()..])?;
+        return Ok(header);
+    }
+
+    /// Reads the header of a page without its cell pointer array, which
+    /// is left empty: just the first 8 bytes, or 12 for an interior page.
+    pub fn deserialize_header(i: &[u8], offset: usize) -> Result<Self> {
         let mut pos = parsing::Position::new();
 
-        let page_type = PageType::try_from(parsing::be_u8(&i[pos.v()..pos.incr(1)])?).unwrap();
+        let page_type = parsing::be_u8(&i[pos.v()..pos.incr(1)])?;
+        let page_type =
+            PageType::try_from(page_type).map_err(|_| eyre!("Invalid page type {}", page_type))?;
         let first_freeblock = parsing::be_u16(&i[pos.v()..pos.incr(2)])?;
         let num_cells = parsing::be_u16(&i[pos.v()..pos.incr(2)])?;
         let cell_start = parsing::be_u16(&i[pos.v()..pos.incr(2)])?;
@@ -288,11 +2594,6 @@ impl PageHeader {
             right_pointer = Some(parsing::be_u32(&i[pos.v()..pos.incr(4)])?);
         }
 
-        let mut cell_pointers = Vec::new();
-        for _ in 0..num_cells as usize {
-            cell_pointers.push(parsing::be_u16(&i[pos.v()..pos.incr(2)])?);
-        }
-
         Ok(Self {
             page_type: page_type,
             offset: offset,
@@ -301,10 +2602,50 @@ impl PageHeader {
             cell_start: cell_start,
             fragmented_bytes: fragmented_bytes,
             right_pointer: right_pointer,
-            cell_pointers: cell_pointers,
+            cell_pointers: Vec::new(),
         })
     }
 
+    /// Reads the cell pointer array of a header read with
+    /// `deserialize_header`, from the bytes that follow the header.
+    pub fn deserialize_cell_pointers(&mut self, i: &[u8]) -> Result<()> {
+        let mut pos = parsing::Position::new();
+        let mut cell_pointers = Vec::with_capacity(self.num_cells as usize);
+        for _ in 0..self.num_cells as usize {
+            cell_pointers.push(parsing::be_u16(&i[pos.v()..pos.incr(2)])?);
+        }
+        self.cell_pointers = cell_pointers;
+        return Ok(());
+    }
+
+    /// The size of the header, not counting the cell pointer array.
+    pub fn size(&self) -> usize {
+        return if self.page_type.is_interior() { 12 } else { 8 };
+    }
+
+    /// The offset of the cell content area. A `cell_start` of zero
+    /// stands for 65536, on pages of that size.
+    pub fn content_start(&self) -> usize {
+        return if self.cell_start == 0 {
+            65536
+        } else {
+            self.cell_start as usize
+        };
+    }
+
+    /// The space between the end of the cell pointer array and the start
+    /// of the cell content area, which new cells are allocated from.
+    pub fn unallocated_space(&self) -> usize {
+        let pointers_end = self.offset + self.size() + 2 * self.cell_pointers.len();
+        return self.content_start().saturating_sub(pointers_end);
+    }
+
+    /// Tests whether a cell of the given size, and a pointer to it, fit
+    /// in the unallocated space.
+    pub fn has_room_for(&self, cell_size: usize) -> bool {
+        return self.unallocated_space() >= std::cmp::max(cell_size, MIN_CELL_SIZE) + 2;
+    }
+
     pub fn serialize(&self) -> Vec<u8> {
         let mut output = Vec::new();
         output.push(self.page_type as u8);
@@ -322,12 +2663,188 @@ impl PageHeader {
     }
 }
 
+/// A cell of a B-tree page, decoded from its bytes (see `DecodedPage`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cell {
+    /// The child page to the left of the cell, on an interior page.
+    pub left_child: Option<u32>,
+    /// The row ID, on a table page.
+    pub row_id: Option<i64>,
+    /// The size of the whole payload, which is more than `payload` holds
+    /// if the rest is on overflow pages. Table interior cells have none.
+    pub payload_size: usize,
+    /// The part of the payload stored in the cell.
+    pub payload: Vec<u8>,
+    /// The first of the overflow pages the rest of the payload is on.
+    pub overflow_page: Option<u32>,
+}
+
+impl Cell {
+    /// Decodes the cell that `bytes` starts with, on a page of the given
+    /// type. Returns `None` if the cell runs past the end of `bytes`.
+    pub fn parse(
+        page_type: PageType,
+        bytes: &[u8],
+        page_size: usize,
+        reserved_space: usize,
+    ) -> Option<Self> {
+        let mut pos = 0;
+        let mut left_child = None;
+        if page_type.is_interior() {
+            let child = bytes.get(..4)?;
+            left_child = Some(u32::from_be_bytes([child[0], child[1], child[2], child[3]]));
+            pos += 4;
+        }
+        let mut payload_size = 0;
+        if page_type != PageType::TableInterior {
+            let (size, b) = VarInt::deserialize(bytes.get(pos..)?);
+            payload_size = usize::try_from(size.0).ok()?;
+            pos += b;
+        }
+        let mut row_id = None;
+        if page_type.is_table() {
+            let (key, b) = VarInt::deserialize(bytes.get(pos..)?);
+            row_id = Some(key.0);
+            pos += b;
+        }
+        if pos > bytes.len() {
+            return None;
+        }
+
+        let mut payload = Vec::new();
+        let mut overflow_page = None;
+        if page_type != PageType::TableInterior {
+            let on_page = calc_payload_on_page(
+                page_size,
+                reserved_space,
+                payload_size,
+                !page_type.is_table(),
+            );
+            payload = bytes.get(pos..pos + on_page)?.to_vec();
+            if on_page < payload_size {
+                let page = bytes.get(pos + on_page..pos + on_page + 4)?;
+                overflow_page = Some(u32::from_be_bytes([page[0], page[1], page[2], page[3]]));
+            }
+        }
+        return Some(Self {
+            left_child: left_child,
+            row_id: row_id,
+            payload_size: payload_size,
+            payload: payload,
+            overflow_page: overflow_page,
+        });
+    }
+
+    /// The bytes of the cell, as stored on a page of the given type.
+    pub fn serialize(&self, page_type: PageType) -> Vec<u8> {
+        let mut output = Vec::new();
+        if let Some(child) = self.left_child {
+            output.extend(child.to_be_bytes());
+        }
+        if page_type != PageType::TableInterior {
+            output.extend(VarInt::new(self.payload_size as i64).serialize());
+        }
+        if let Some(row_id) = self.row_id {
+            output.extend(VarInt::new(row_id).serialize());
+        }
+        output.extend(&self.payload);
+        if let Some(page) = self.overflow_page {
+            output.extend(page.to_be_bytes());
+        }
+        return output;
+    }
+}
+
+/// A B-tree page with all of its cells decoded when it is read, unlike
+/// the page types of `BtreePage`, which keep the bytes of the page and
+/// decode each cell as it is looked at. Decoding everything up front
+/// costs more when only a few cells are needed, but a malformed cell is
+/// found straight away, and the cells can be changed, added, and removed
+/// freely before the page is serialized again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedPage {
+    pub page_type: PageType,
+    /// Where the page header starts: 100 on page 1, after the file
+    /// header, and 0 otherwise.
+    pub offset: usize,
+    pub right_pointer: Option<u32>,
+    pub cells: Vec<Cell>,
+    pub page_size: usize,
+    pub reserved_space: u8,
+}
+
+impl DecodedPage {
+    pub fn deserialize(
+        bytes: &[u8],
+        page_num: usize,
+        page_size: usize,
+        reserved_space: u8,
+    ) -> Result<Self> {
+        let offset = if page_num == 1 { 100 } else { 0 };
+        let header = PageHeader::deserialize(&bytes[offset..], offset)?;
+        // cells cannot run into the reserved space at the end
+        let usable = &bytes[..std::cmp::min(bytes.len(), page_size - reserved_space as usize)];
+        let mut cells = Vec::with_capacity(header.cell_pointers.len());
+        for ptr in &header.cell_pointers {
+            let cell = usable
+                .get(*ptr as usize..)
+                .and_then(|cell| {
+                    Cell::parse(header.page_type, cell, page_size, reserved_space as usize)
+                })
+                .ok_or_else(|| {
+                    eyre!(
+                        "The cell at offset {} of page {} runs past the end of the page",
+                        ptr,
+                        page_num
+                    )
+                })?;
+            cells.push(cell);
+        }
+        return Ok(Self {
+            page_type: header.page_type,
+            offset: offset,
+            right_pointer: header.right_pointer,
+            cells: cells,
+            page_size: page_size,
+            reserved_space: reserved_space,
+        });
+    }
+
+    /// Builds the page from its cells, packed together at the end of it,
+    /// or fails if they do not fit.
+    pub fn to_page(&self) -> Result<BtreePage> {
+        let cells: Vec<Vec<u8>> = self
+            .cells
+            .iter()
+            .map(|cell| cell.serialize(self.page_type))
+            .collect();
+        return BtreePage::from_cells(
+            self.page_type,
+            &cells,
+            self.right_pointer,
+            self.offset,
+            self.page_size,
+            self.reserved_space,
+        )
+        .ok_or_else(|| eyre!("The cells do not fit on a page"));
+    }
+
+    /// The bytes of the page, as `BtreePage::serialize` gives them.
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        return Ok(self.to_page()?.serialize());
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct TableLeafPage {
     pub header: PageHeader,
     pub bytes: Vec<u8>,
     pub page_size: usize,
     pub reserved_space: u8,
+    /// The whole payload of each cell that continues on overflow pages,
+    /// by the number of the first overflow page, as loaded by
+    /// `Btree::get_page`.
+    pub overflow: HashMap<u32, Vec<u8>>,
 }
 
 impl TableLeafPage {
@@ -342,6 +2859,7 @@ impl TableLeafPage {
             bytes: bytes.to_vec(),
             page_size: page_size,
             reserved_space: reserved_space,
+            overflow: HashMap::new(),
         };
     }
 
@@ -352,8 +2870,178 @@ impl TableLeafPage {
         return output;
     }
 
-    pub fn iter(&self) -> TableLeafIter {
-        return TableLeafIter::new(&self);
+    pub fn iter(&self) -> TableLeafIter<'_> {
+        return TableLeafIter::new(self);
+    }
+
+    /// Builds the cell holding a row: the size of the record, the row
+    /// ID, and the record itself.
+    pub fn build_cell(&self, row_id: i64, record: &Record) -> Result<Vec<u8>> {
+        let payload = record.serialize();
+        let on_page = calc_payload_on_page(
+            self.page_size,
+            self.reserved_space as usize,
+            payload.len(),
+            false,
+        );
+        if on_page < payload.len() {
+            return Err(eyre!(
+                "Row is too large to fit on a page without overflow pages."
+            ));
+        }
+        let mut cell = VarInt::new(payload.len() as i64).serialize();
+        cell.extend(VarInt::new(row_id).serialize());
+        cell.extend(payload);
+        return Ok(cell);
+    }
+
+    /// The row ID of the cell at `index`.
+    pub fn row_id(&self, index: usize) -> i64 {
+        let ptr = self.header.cell_pointers[index] as usize;
+        return cell_row_id(&self.bytes[ptr..]);
+    }
+
+    /// Searches the page for a row ID. Returns the index of its cell if
+    /// it is found, or else the index at which it would be inserted.
+    pub fn search(&self, row_id: i64) -> std::result::Result<usize, usize> {
+        let mut low = 0;
+        let mut high = self.header.cell_pointers.len();
+        while low < high {
+            let mid = (low + high) / 2;
+            match self.row_id(mid).cmp(&row_id) {
+                Ordering::Less => low = mid + 1,
+                Ordering::Equal => return Ok(mid),
+                Ordering::Greater => high = mid,
+            }
+        }
+        return Err(low);
+    }
+
+    /// Creates a page holding the given cells, in order, or returns
+    /// `None` if they do not fit.
+    pub fn from_cells(
+        cells: &[Vec<u8>],
+        offset: usize,
+        page_size: usize,
+        reserved_space: u8,
+    ) -> Option<Self> {
+        let mut page = Self::new(
+            PageHeader::new(PageType::TableLeaf, page_size, reserved_space),
+            &vec![0; page_size],
+            page_size,
+            reserved_space,
+        );
+        page.header.offset = offset;
+        for (i, cell) in cells.iter().enumerate() {
+            if !page.header.has_room_for(cell.len()) {
+                return None;
+            }
+            place_cell(&mut page.header, &mut page.bytes, i, cell);
+        }
+        return Some(page);
+    }
+
+    /// The size of the cell starting at `start`.
+    fn cell_size(&self, start: usize) -> usize {
+        return stored_cell_size(
+            PageType::TableLeaf,
+            self.page_size,
+            self.reserved_space as usize,
+            &self.bytes[start..],
+        );
+    }
+
+    /// The raw bytes of each cell on the page, in order.
+    pub fn cells(&self) -> Vec<Vec<u8>> {
+        return self
+            .header
+            .cell_pointers
+            .iter()
+            .map(|ptr| {
+                let start = *ptr as usize;
+                self.bytes[start..start + self.cell_size(start)].to_vec()
+            })
+            .collect();
+    }
+
+    /// Removes the cell at `index` from the page. Its space is added to
+    /// the page's free blocks.
+    pub fn delete(&mut self, index: usize) -> Result<()> {
+        let start = self.header.cell_pointers.remove(index) as usize;
+        self.header.num_cells -= 1;
+        let size = std::cmp::max(self.cell_size(start), MIN_CELL_SIZE);
+        return free_space(&mut self.header, &mut self.bytes, start, size);
+    }
+
+    /// The number of bytes on the page not used by cells: the
+    /// unallocated space, free blocks, and fragments.
+    pub fn free_space(&self) -> Result<usize> {
+        let free_blocks: usize = read_freeblocks(&self.header, &self.bytes)?
+            .iter()
+            .map(|(_, size)| size)
+            .sum();
+        return Ok(self.header.unallocated_space()
+            + free_blocks
+            + self.header.fragmented_bytes as usize);
+    }
+
+    /// Moves the cells on the page together at the end of it, as for
+    /// `BtreePage::defragment`.
+    pub fn defragment(&mut self) {
+        let cells = self.cells();
+        defragment(
+            &mut self.header,
+            &mut self.bytes,
+            self.reserved_space as usize,
+            &cells,
+        );
+    }
+
+    /// Adds a row to the page, keeping the cells in row ID order. The
+    /// cell is placed in the unallocated space, and if that is too small
+    /// but the free blocks left by deleted cells would make up the
+    /// difference, the page is defragmented first.
+    pub fn insert(&mut self, row_id: i64, record: &Record) -> Result<()> {
+        let cell = self.build_cell(row_id, record)?;
+        let index = match self.search(row_id) {
+            Ok(_) => return Err(eyre!("Row ID {} already exists", row_id)),
+            Err(index) => index,
+        };
+        if !self.header.has_room_for(cell.len()) && self.free_space()? >= needed_space(&cell) {
+            self.defragment();
+        }
+        if !self.header.has_room_for(cell.len()) {
+            return Err(eyre!("Page is full"));
+        }
+        place_cell(&mut self.header, &mut self.bytes, index, &cell);
+        return Ok(());
+    }
+
+    /// The row ID and record of the cell at `index`.
+    pub fn row(&self, index: usize) -> Option<(VarInt, Record)> {
+        let ptr = *self.header.cell_pointers.get(index)?;
+        let mut pos = parsing::Position::new();
+        pos.set(ptr as usize);
+        let (payload_size, b) = VarInt::deserialize(&self.bytes[pos.v()..]);
+        pos.incr(b);
+        let (row_id, b) = VarInt::deserialize(&self.bytes[pos.v()..]);
+        pos.incr(b);
+
+        let payload_on_page = calc_payload_on_page(
+            self.page_size,
+            self.reserved_space as usize,
+            payload_size.0 as usize,
+            false,
+        );
+        let payload = cell_payload(
+            &self.bytes,
+            &self.overflow,
+            pos.v(),
+            payload_size.0 as usize,
+            payload_on_page,
+        );
+        let rec = Record::deserialize(payload).unwrap();
+        return Some((row_id, rec));
     }
 }
 
@@ -375,28 +3063,9 @@ impl<'a> Iterator for TableLeafIter<'a> {
     type Item = (VarInt, Record);
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.page.header.cell_pointers.get(self.cursor) {
-            None => return None,
-            Some(ptr) => {
-                let mut pos = parsing::Position::new();
-                pos.set(*ptr as usize);
-                let (payload_size, b) = VarInt::deserialize(&self.page.bytes[pos.v()..]);
-                pos.incr(b);
-                let (row_id, b) = VarInt::deserialize(&self.page.bytes[pos.v()..]);
-                pos.incr(b);
-
-                let payload_on_page = calc_payload_on_page(
-                    self.page.page_size,
-                    self.page.reserved_space as usize,
-                    payload_size.0 as usize,
-                    false,
-                );
-                let rec = Record::deserialize(&self.page.bytes[pos.v()..pos.incr(payload_on_page)])
-                    .unwrap();
-                self.cursor += 1;
-                return Some((row_id, rec));
-            }
-        }
+        let item = self.page.row(self.cursor)?;
+        self.cursor += 1;
+        return Some(item);
     }
 }
 
@@ -406,6 +3075,10 @@ pub struct IndexLeafPage {
     pub bytes: Vec<u8>,
     pub page_size: usize,
     pub reserved_space: u8,
+    /// The whole payload of each cell that continues on overflow pages,
+    /// by the number of the first overflow page, as loaded by
+    /// `Btree::get_page`.
+    pub overflow: HashMap<u32, Vec<u8>>,
 }
 
 impl IndexLeafPage {
@@ -420,6 +3093,7 @@ impl IndexLeafPage {
             bytes: bytes.to_vec(),
             page_size: page_size,
             reserved_space: reserved_space,
+            overflow: HashMap::new(),
         };
     }
 
@@ -430,8 +3104,56 @@ impl IndexLeafPage {
         return output;
     }
 
-    pub fn iter(&self) -> IndexLeafIter {
-        return IndexLeafIter::new(&self);
+    pub fn iter(&self) -> IndexLeafIter<'_> {
+        return IndexLeafIter::new(self);
+    }
+
+    /// Creates a page holding the given cells, in order, or returns
+    /// `None` if they do not fit.
+    pub fn from_cells(cells: &[Vec<u8>], page_size: usize, reserved_space: u8) -> Option<Self> {
+        let mut page = Self::new(
+            PageHeader::new(PageType::IndexLeaf, page_size, reserved_space),
+            &vec![0; page_size],
+            page_size,
+            reserved_space,
+        );
+        for (i, cell) in cells.iter().enumerate() {
+            if !page.header.has_room_for(cell.len()) {
+                return None;
+            }
+            place_cell(&mut page.header, &mut page.bytes, i, cell);
+        }
+        return Some(page);
+    }
+
+    /// The entry in the cell at `index`.
+    pub fn record(&self, index: usize) -> Option<Record> {
+        let rec = Record::deserialize(self.payload(index)?).unwrap();
+        return Some(rec);
+    }
+
+    /// The serialized entry in the cell at `index`, which can be compared
+    /// with a key without decoding all of it (see `compare_prefix_payload`).
+    pub fn payload(&self, index: usize) -> Option<&[u8]> {
+        let ptr = *self.header.cell_pointers.get(index)?;
+        let mut pos = parsing::Position::new();
+        pos.set(ptr as usize);
+        let (payload_size, b) = VarInt::deserialize(&self.bytes[pos.v()..]);
+        pos.incr(b);
+
+        let payload_on_page = calc_payload_on_page(
+            self.page_size,
+            self.reserved_space as usize,
+            payload_size.0 as usize,
+            true,
+        );
+        return Some(cell_payload(
+            &self.bytes,
+            &self.overflow,
+            pos.v(),
+            payload_size.0 as usize,
+            payload_on_page,
+        ));
     }
 }
 
@@ -453,26 +3175,9 @@ impl<'a> Iterator for IndexLeafIter<'a> {
     type Item = Record;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.page.header.cell_pointers.get(self.cursor) {
-            None => return None,
-            Some(ptr) => {
-                let mut pos = parsing::Position::new();
-                pos.set(*ptr as usize);
-                let (payload_size, b) = VarInt::deserialize(&self.page.bytes[pos.v()..]);
-                pos.incr(b);
-
-                let payload_on_page = calc_payload_on_page(
-                    self.page.page_size,
-                    self.page.reserved_space as usize,
-                    payload_size.0 as usize,
-                    true,
-                );
-                let rec = Record::deserialize(&self.page.bytes[pos.v()..pos.incr(payload_on_page)])
-                    .unwrap();
-                self.cursor += 1;
-                return Some(rec);
-            }
-        }
+        let item = self.page.record(self.cursor)?;
+        self.cursor += 1;
+        return Some(item);
     }
 }
 
@@ -497,8 +3202,39 @@ impl TableInteriorPage {
         return output;
     }
 
-    pub fn iter(&self) -> TableInteriorIter {
-        return TableInteriorIter::new(&self);
+    pub fn iter(&self) -> TableInteriorIter<'_> {
+        return TableInteriorIter::new(self);
+    }
+
+    /// Creates a page holding the given cells, in order, or returns
+    /// `None` if they do not fit.
+    pub fn from_cells(
+        cells: &[Vec<u8>],
+        right_pointer: u32,
+        offset: usize,
+        page_size: usize,
+        reserved_space: u8,
+    ) -> Option<Self> {
+        let mut page = Self::new(
+            PageHeader::new(PageType::TableInterior, page_size, reserved_space),
+            &vec![0; page_size],
+        );
+        page.header.offset = offset;
+        page.header.right_pointer = Some(right_pointer);
+        for (i, cell) in cells.iter().enumerate() {
+            if !page.header.has_room_for(cell.len()) {
+                return None;
+            }
+            place_cell(&mut page.header, &mut page.bytes, i, cell);
+        }
+        return Some(page);
+    }
+
+    /// The child pages, in order, ending with the right-most one.
+    pub fn children(&self) -> Vec<u32> {
+        let mut children: Vec<u32> = self.iter().map(|(child, _)| child).collect();
+        children.extend(self.header.right_pointer);
+        return children;
     }
 }
 
@@ -542,6 +3278,10 @@ pub struct IndexInteriorPage {
     pub bytes: Vec<u8>,
     pub page_size: usize,
     pub reserved_space: u8,
+    /// The whole payload of each cell that continues on overflow pages,
+    /// by the number of the first overflow page, as loaded by
+    /// `Btree::get_page`.
+    pub overflow: HashMap<u32, Vec<u8>>,
 }
 
 impl IndexInteriorPage {
@@ -556,6 +3296,7 @@ impl IndexInteriorPage {
             bytes: bytes.to_vec(),
             page_size: page_size,
             reserved_space: reserved_space,
+            overflow: HashMap::new(),
         };
     }
 
@@ -566,8 +3307,66 @@ impl IndexInteriorPage {
         return output;
     }
 
-    pub fn iter(&self) -> IndexInteriorIter {
-        return IndexInteriorIter::new(&self);
+    pub fn iter(&self) -> IndexInteriorIter<'_> {
+        return IndexInteriorIter::new(self);
+    }
+
+    /// Creates a page holding the given cells, in order, or returns
+    /// `None` if they do not fit.
+    pub fn from_cells(
+        cells: &[Vec<u8>],
+        right_pointer: u32,
+        page_size: usize,
+        reserved_space: u8,
+    ) -> Option<Self> {
+        let mut page = Self::new(
+            PageHeader::new(PageType::IndexInterior, page_size, reserved_space),
+            &vec![0; page_size],
+            page_size,
+            reserved_space,
+        );
+        page.header.right_pointer = Some(right_pointer);
+        for (i, cell) in cells.iter().enumerate() {
+            if !page.header.has_room_for(cell.len()) {
+                return None;
+            }
+            place_cell(&mut page.header, &mut page.bytes, i, cell);
+        }
+        return Some(page);
+    }
+
+    /// The child page and entry in the cell at `index`.
+    pub fn entry(&self, index: usize) -> Option<(u32, Record)> {
+        let (child_ptr, payload) = self.payload(index)?;
+        let rec = Record::deserialize(payload).unwrap();
+        return Some((child_ptr, rec));
+    }
+
+    /// The child page and serialized entry in the cell at `index` (see
+    /// `IndexLeafPage::payload`).
+    pub fn payload(&self, index: usize) -> Option<(u32, &[u8])> {
+        let ptr = *self.header.cell_pointers.get(index)?;
+        let mut pos = parsing::Position::new();
+        pos.set(ptr as usize);
+        let child_ptr = parsing::be_u32(&self.bytes[pos.v()..pos.incr(4)]).unwrap();
+
+        let (payload_size, b) = VarInt::deserialize(&self.bytes[pos.v()..]);
+        pos.incr(b);
+
+        let payload_on_page = calc_payload_on_page(
+            self.page_size,
+            self.reserved_space as usize,
+            payload_size.0 as usize,
+            true,
+        );
+        let payload = cell_payload(
+            &self.bytes,
+            &self.overflow,
+            pos.v(),
+            payload_size.0 as usize,
+            payload_on_page,
+        );
+        return Some((child_ptr, payload));
     }
 }
 
@@ -585,33 +3384,13 @@ impl<'a> IndexInteriorIter<'a> {
     }
 }
 
-impl<'a> Iterator for IndexInteriorIter<'a> {
-    type Item = (u32, Record);
-
-    fn next(&mut self) -> Option<Self::Item> {
-        match self.page.header.cell_pointers.get(self.cursor) {
-            None => return None,
-            Some(ptr) => {
-                let mut pos = parsing::Position::new();
-                pos.set(*ptr as usize);
-                let child_ptr = parsing::be_u32(&self.page.bytes[pos.v()..pos.incr(4)]).unwrap();
-
-                let (payload_size, b) = VarInt::deserialize(&self.page.bytes[pos.v()..]);
-                pos.incr(b);
-
-                let payload_on_page = calc_payload_on_page(
-                    self.page.page_size as usize,
-                    self.page.reserved_space as usize,
-                    payload_size.0 as usize,
-                    true,
-                );
+impl<'a> Iterator for IndexInteriorIter<'a> {
+    type Item = (u32, Record);
 
-                let rec = Record::deserialize(&self.page.bytes[pos.v()..pos.incr(payload_on_page)])
-                    .unwrap();
-                self.cursor += 1;
-                return Some((child_ptr, rec));
-            }
-        }
+    fn next(&mut self) -> Option<Self::Item> {
+        let item = self.page.entry(self.cursor)?;
+        self.cursor += 1;
+        return Some(item);
     }
 }
 
@@ -626,19 +3405,34 @@ pub enum PageType {
 
 impl PageType {
     pub fn is_interior(&self) -> bool {
-        match self {
-            PageType::IndexInterior => true,
-            PageType::TableInterior => true,
-            _ => false,
-        }
+        return matches!(self, PageType::IndexInterior | PageType::TableInterior);
     }
 
     pub fn is_leaf(&self) -> bool {
-        match self {
-            PageType::IndexLeaf => true,
-            PageType::TableLeaf => true,
-            _ => false,
-        }
+        return matches!(self, PageType::IndexLeaf | PageType::TableLeaf);
+    }
+
+    pub fn is_table(&self) -> bool {
+        return matches!(self, PageType::TableInterior | PageType::TableLeaf);
+    }
+}
+
+/// The two kinds of B-tree: a table, keyed by row ID, or an index (or
+/// WITHOUT ROWID table), keyed by its records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BtreeKind {
+    Table,
+    Index,
+}
+
+impl BtreeKind {
+    /// The type of the pages at the bottom of a tree of this kind, which
+    /// is also the type of the root of an empty one.
+    pub fn leaf_type(&self) -> PageType {
+        return match self {
+            BtreeKind::Table => PageType::TableLeaf,
+            BtreeKind::Index => PageType::IndexLeaf,
+        };
     }
 }
 
@@ -649,6 +3443,12 @@ pub struct Freeblock {
 }
 
 impl Freeblock {
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut output = self.next.unwrap_or(0).to_be_bytes().to_vec();
+        output.extend(self.size.to_be_bytes().iter());
+        return output;
+    }
+
     pub fn deserialize(i: &[u8]) -> Result<Self> {
         let mut pos = parsing::Position::new();
         let next = parsing::be_u16(&i[pos.v()..pos.incr(2)])?;
@@ -674,32 +3474,40 @@ impl Record {
         };
     }
 
+    /// Reads a record: a header of the size of the header, as a varint,
+    /// followed by the serial type of each value, and then the values
+    /// themselves. Errors if the header or the values run past the end of
+    /// the record, or a serial type is not a valid one.
     pub fn deserialize(i: &[u8]) -> Result<Self> {
-        let mut pos = parsing::Position::new();
-        let (header_size, b) = VarInt::deserialize(&i[pos.v()..]);
-        pos.incr(b);
-        let header_size_size = header_size.0 as usize - b;
-
-        // get the rest of the header
-        let header = &i[pos.v()..pos.incr(header_size_size)];
+        let (header_size, b) = VarInt::deserialize(i);
+        let header_size = header_size.0 as usize;
+        if b == 0 || header_size < b || header_size > i.len() {
+            return Err(eyre!(
+                "Corrupt record: a header of {} bytes in a record of {}",
+                header_size,
+                i.len()
+            ));
+        }
         let mut col_types = Vec::new();
-        let mut header_left = header.len();
-        pos.set(0);
-        while header_left > 0 {
-            let next_bytes = std::cmp::min(header_left, 9);
-            let (col_type_int, b) = VarInt::deserialize(&header[pos.v()..pos.incr(next_bytes)]);
-            pos.decr(next_bytes - b);
-            let col_type = DataType::from_varint(col_type_int).expect("Not a valid data type.");
-            col_types.push(col_type);
-            header_left -= b;
-        }
-
-        let values_input = &i[header_size.0 as usize..];
+        let mut pos = b;
+        while pos < header_size {
+            let (col_type, b) = VarInt::deserialize(&i[pos..header_size]);
+            pos += b;
+            col_types.push(DataType::from_varint(col_type)?);
+        }
+
         let mut values = Vec::new();
-        pos.set(0);
+        let mut pos = header_size;
         for col in &col_types {
             if let Some(size) = col.get_size() {
-                values.push(Value::new(col, &values_input[pos.v()..pos.incr(size)]));
+                if size > i.len() - pos {
+                    return Err(eyre!(
+                        "Corrupt record: value {} runs past the end of the record",
+                        values.len() + 1
+                    ));
+                }
+                values.push(Value::new(col, &i[pos..pos + size]));
+                pos += size;
             }
         }
 
@@ -709,14 +3517,56 @@ impl Record {
         })
     }
 
-    pub fn serialize(&self) -> Vec<u8> {
-        let mut output = Vec::new();
-        for col in &self.col_types {
-            output.extend(col.to_varint().serialize());
+    /// Creates a record holding the given values, each stored with the
+    /// smallest serial type that holds it.
+    pub fn from_values(values: Vec<Value>) -> Self {
+        let values: Vec<Value> = values
+            .into_iter()
+            .map(|value| match value.get_int_val() {
+                Some(i) => Value::from_int(i),
+                None => value,
+            })
+            .collect();
+        return Self {
+            col_types: values.iter().map(|v| v.data_type()).collect(),
+            values: values,
+        };
+    }
+
+    /// Compares two records value by value with `Value::cmp_sqlite`, a
+    /// record that is the start of the other sorting first.
+    pub fn cmp_sqlite(&self, other: &Self) -> Ordering {
+        for (value, other) in self.values.iter().zip(other.values.iter()) {
+            match value.cmp_sqlite(other) {
+                Ordering::Equal => (),
+                ordering => return ordering,
+            }
         }
+        return self.values.len().cmp(&other.values.len());
+    }
+
+    /// Writes the record in the format SQLite reads. Each integer is
+    /// stored with the smallest serial type that holds it, whichever it
+    /// was read or created with, and the serial types are taken from the
+    /// values rather than `col_types`.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut types = Vec::new();
+        let mut body = Vec::new();
         for val in &self.values {
-            output.extend(val.serialize());
+            let int = val.get_int_val().map(Value::from_int);
+            let val = int.as_ref().unwrap_or(val);
+            types.extend(val.data_type().to_varint().serialize());
+            body.extend(val.serialize());
+        }
+        // the header size includes the varint holding it, so growing
+        // that varint by a byte may in turn grow the size it records
+        let mut header_size = types.len() + 1;
+        while VarInt::new(header_size as i64).serialize().len() + types.len() != header_size {
+            header_size = VarInt::new(header_size as i64).serialize().len() + types.len();
         }
+        let mut output = VarInt::new(header_size as i64).serialize();
+        output.extend(types);
+        output.extend(body);
         return output;
     }
 }
@@ -747,21 +3597,344 @@ impl PartialEq for Record {
     }
 }
 
-impl PartialOrd for Record {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        for (i, sval) in self.values.iter().enumerate() {
-            let oval = other.values.get(i);
-            match oval {
-                Some(oval) => {
-                    if sval != oval {
-                        return sval.partial_cmp(oval);
-                    }
-                }
-                None => return Some(Ordering::Greater),
+/// Compares a search key with the leading values of an index record, in
+/// the order of the index: each value by the collating sequence and
+/// direction `order` gives for its column.
+fn compare_prefix(key: &[Value], record: &Record, order: &[SortOrder]) -> Ordering {
+    for (i, value) in key.iter().enumerate() {
+        match record.values.get(i) {
+            Some(other) => match compare_key_value(value, other, order.get(i)) {
+                Ordering::Equal => (),
+                ordering => return ordering,
+            },
+            None => return Ordering::Greater,
+        }
+    }
+    return Ordering::Equal;
+}
+
+fn compare_key_value(value: &Value, other: &Value, order: Option<&SortOrder>) -> Ordering {
+    return match order {
+        Some(order) if order.descending => order.collation.compare(value, other).reverse(),
+        Some(order) => order.collation.compare(value, other),
+        None => exec::compare(value, other),
+    };
+}
+
+/// Compares a search key with the leading values of a serialized index
+/// record, as `compare_prefix` does with the deserialized record. Only
+/// the values up to the first that differs from the key are decoded.
+pub fn compare_prefix_payload(key: &[Value], payload: &[u8], order: &[SortOrder]) -> Ordering {
+    let (header_size, b) = VarInt::deserialize(payload);
+    let header_end = header_size.0 as usize;
+    let mut type_pos = b;
+    let mut value_pos = header_end;
+    let mut key = key.iter().enumerate();
+    let (mut i, mut value) = match key.next() {
+        Some(value) => value,
+        None => return Ordering::Equal,
+    };
+    while type_pos < header_end {
+        let (col_type, b) = VarInt::deserialize(&payload[type_pos..header_end]);
+        type_pos += b;
+        let col_type = DataType::from_varint(col_type).expect("Not a valid data type.");
+        let size = match col_type.get_size() {
+            Some(size) => size,
+            None => continue,
+        };
+        let other = Value::new(&col_type, &payload[value_pos..value_pos + size]);
+        value_pos += size;
+        match compare_key_value(value, &other, order.get(i)) {
+            Ordering::Equal => (),
+            ordering => return ordering,
+        }
+        (i, value) = match key.next() {
+            Some(next) => next,
+            None => return Ordering::Equal,
+        };
+    }
+    return Ordering::Greater;
+}
+
+/// The least space a cell takes up on a page, so that it can become a
+/// free block when it is deleted.
+const MIN_CELL_SIZE: usize = 4;
+
+/// Writes a cell into the unallocated space of a page and adds a
+/// pointer to it at position `index` of the cell pointer array. The
+/// caller must have checked that there is room for it.
+fn place_cell(header: &mut PageHeader, bytes: &mut [u8], index: usize, cell: &[u8]) {
+    let start = header.content_start() - std::cmp::max(cell.len(), MIN_CELL_SIZE);
+    bytes[start..start + cell.len()].copy_from_slice(cell);
+    header.cell_pointers.insert(index, start as u16);
+    header.num_cells += 1;
+    header.cell_start = start as u16;
+}
+
+/// The space a cell and its pointer take up on a page.
+fn needed_space(cell: &[u8]) -> usize {
+    return std::cmp::max(cell.len(), MIN_CELL_SIZE) + 2;
+}
+
+/// Rewrites a page's cells, which are given in order, packed together at
+/// the end of its usable space, with the pointer array to match. The
+/// free blocks and fragments they were spread among become part of the
+/// unallocated space, which is zeroed.
+fn defragment(header: &mut PageHeader, bytes: &mut [u8], reserved_space: usize, cells: &[Vec<u8>]) {
+    let end = bytes.len() - reserved_space;
+    let pointers_end = header.offset + header.size();
+    bytes[pointers_end..end].fill(0);
+    header.cell_pointers.clear();
+    header.num_cells = 0;
+    // a content area starting at 65536 is recorded as 0
+    header.cell_start = end as u16;
+    header.first_freeblock = 0;
+    header.fragmented_bytes = 0;
+    for (i, cell) in cells.iter().enumerate() {
+        place_cell(header, bytes, i, cell);
+    }
+}
+
+/// Reads the list of free blocks on a page, as (offset, size) pairs in
+/// order of offset.
+fn read_freeblocks(header: &PageHeader, bytes: &[u8]) -> Result<Vec<(usize, usize)>> {
+    let mut blocks = Vec::new();
+    let mut next = header.first_freeblock as usize;
+    while next != 0 {
+        if next + 4 > bytes.len() || blocks.len() > bytes.len() / 4 {
+            return Err(eyre!("Free block list is corrupt"));
+        }
+        let block = Freeblock::deserialize(&bytes[next..next + 4])?;
+        blocks.push((next, block.size as usize));
+        next = block.next.unwrap_or(0) as usize;
+    }
+    return Ok(blocks);
+}
+
+/// Releases `size` bytes of a page starting at `start`. The space is
+/// added to the list of free blocks, merged with the blocks next to it
+/// along with any fragments (gaps of less than 4 bytes) in between. If
+/// it ends up at the start of the cell content area, it goes back to the
+/// unallocated space instead.
+fn free_space(header: &mut PageHeader, bytes: &mut [u8], start: usize, size: usize) -> Result<()> {
+    let mut blocks = read_freeblocks(header, bytes)?;
+    let index = blocks
+        .iter()
+        .position(|(offset, _)| *offset > start)
+        .unwrap_or(blocks.len());
+    blocks.insert(index, (start, size));
+
+    let mut merged: Vec<(usize, usize)> = Vec::with_capacity(blocks.len());
+    for (offset, size) in blocks {
+        if let Some(last) = merged.last_mut() {
+            let end = last.0 + last.1;
+            if offset >= end && offset - end < MIN_CELL_SIZE {
+                header.fragmented_bytes =
+                    header.fragmented_bytes.saturating_sub((offset - end) as u8);
+                last.1 = offset + size - last.0;
+                continue;
+            }
+        }
+        merged.push((offset, size));
+    }
+    if merged
+        .first()
+        .is_some_and(|(offset, _)| *offset == header.content_start())
+    {
+        let (offset, size) = merged.remove(0);
+        // a content area starting at 65536 is recorded as 0
+        header.cell_start = (offset + size) as u16;
+    }
+
+    header.first_freeblock = merged.first().map_or(0, |(offset, _)| *offset as u16);
+    for (i, (offset, size)) in merged.iter().enumerate() {
+        let block = Freeblock {
+            next: merged.get(i + 1).map(|(next, _)| *next as u16),
+            size: *size as u16,
+        };
+        bytes[*offset..*offset + 4].copy_from_slice(&block.serialize());
+    }
+    return Ok(());
+}
+
+/// Reads the row ID of a table leaf cell, which follows the payload
+/// size.
+/// The size of the record in a cell that starts with it (i.e., a leaf
+/// cell, or an index interior cell after its child pointer).
+/// The payload of a cell, which starts at `start` on the page: the part
+/// stored in the cell, or if it continues on overflow pages, the whole
+/// payload, from those loaded into `overflow`.
+fn cell_payload<'a>(
+    bytes: &'a [u8],
+    overflow: &'a HashMap<u32, Vec<u8>>,
+    start: usize,
+    payload_size: usize,
+    on_page: usize,
+) -> &'a [u8] {
+    if on_page < payload_size {
+        let first = &bytes[start + on_page..start + on_page + 4];
+        let first = u32::from_be_bytes([first[0], first[1], first[2], first[3]]);
+        if let Some(payload) = overflow.get(&first) {
+            return payload;
+        }
+    }
+    return &bytes[start..start + on_page];
+}
+
+fn cell_payload_size(cell: &[u8]) -> usize {
+    return VarInt::deserialize(cell).0 .0 as usize;
+}
+
+fn cell_row_id(cell: &[u8]) -> i64 {
+    let (_, b) = VarInt::deserialize(cell);
+    return VarInt::deserialize(&cell[b..]).0 .0;
+}
+
+/// Builds a table interior cell: the number of the child page, followed
+/// by the largest row ID in it.
+fn interior_cell(child_page: u32, key: i64) -> Vec<u8> {
+    let mut cell = child_page.to_be_bytes().to_vec();
+    cell.extend(VarInt::new(key).serialize());
+    return cell;
+}
+
+/// Puts a child page number in front of a cell's contents, to make an
+/// interior cell, or leaves them as they are if there is no child.
+fn with_child(contents: &[u8], child: Option<u32>) -> Vec<u8> {
+    let mut cell = child.map(|c| c.to_be_bytes().to_vec()).unwrap_or_default();
+    cell.extend(contents);
+    return cell;
+}
+
+/// Shares out cells, in order, among as few pages as will hold them,
+/// each with room for `capacity` bytes of cells and their pointers, and
+/// returns the range of cells on each page. If `moves_up`, the cell
+/// between each page and the next goes up to the parent to divide them,
+/// and is on neither page. Cells then move from each page onto the one
+/// after it for as long as that leaves the earlier page at least as full,
+/// so that the last page is not left nearly empty. Returns `None` if
+/// there is no such layout (as with a cell too large for a page).
+fn distribute(cells: &[Vec<u8>], moves_up: bool, capacity: usize) -> Option<Vec<(usize, usize)>> {
+    let size = |i: usize| needed_space(&cells[i]);
+    if cells.is_empty() {
+        // a leaf left empty, which only the root may be
+        return Some(vec![(0, 0)]);
+    }
+    let mut groups = Vec::new();
+    let (mut start, mut used) = (0, 0);
+    let mut i = 0;
+    while i < cells.len() {
+        if used + size(i) > capacity && i > start {
+            groups.push((start, i));
+            start = if moves_up { i + 1 } else { i };
+            used = 0;
+            i = start;
+            continue;
+        }
+        used += size(i);
+        i += 1;
+    }
+    groups.push((start, cells.len()));
+
+    let mut sizes: Vec<usize> = groups
+        .iter()
+        .map(|&(start, stop)| (start..stop).map(size).sum())
+        .collect();
+    for j in (1..groups.len()).rev() {
+        loop {
+            let ((left_start, left_stop), (right_start, right_stop)) = (groups[j - 1], groups[j]);
+            if left_stop - left_start <= 1 {
+                break;
             }
+            // the cell that moves onto the right-hand page, and the one
+            // that leaves the left-hand page, which differ if the one
+            // between them moves up
+            let incoming = if moves_up { left_stop } else { left_stop - 1 };
+            let right_size = sizes[j] + size(incoming);
+            let left_size = sizes[j - 1] - size(left_stop - 1);
+            if right_size > capacity || (right_start < right_stop && right_size > left_size) {
+                break;
+            }
+            groups[j - 1].1 -= 1;
+            groups[j].0 -= 1;
+            sizes[j - 1] = left_size;
+            sizes[j] = right_size;
         }
+    }
+    if groups
+        .iter()
+        .zip(&sizes)
+        .any(|(&(start, stop), &size)| start == stop || size > capacity)
+    {
         return None;
     }
+    return Some(groups);
+}
+
+/// The number of bytes a cell takes up on a page of the given type, not
+/// counting its cell pointer: the child page number on an interior page,
+/// the size of the payload and the row ID as varints (for the types of
+/// page whose cells have them), the part of the payload kept on the page,
+/// and the number of the first overflow page, if the rest of the payload
+/// is kept on overflow pages. Table interior cells have no payload, and
+/// index cells no row ID, so `payload_size` and `row_id` are ignored for
+/// those. A cell smaller than `MIN_CELL_SIZE` still takes up that much
+/// space when placed on a page.
+pub fn cell_size(
+    page_type: PageType,
+    page_size: usize,
+    reserved_space: usize,
+    payload_size: usize,
+    row_id: i64,
+) -> usize {
+    let mut header = if page_type.is_interior() { 4 } else { 0 };
+    if page_type != PageType::TableInterior {
+        header += VarInt::new(payload_size as i64).serialize().len();
+    }
+    if page_type.is_table() {
+        header += VarInt::new(row_id).serialize().len();
+    }
+    return with_payload(page_type, page_size, reserved_space, header, payload_size);
+}
+
+/// The size of a cell on a page of the given type, which `cell` starts
+/// with, found from the sizes in its header (see `cell_size`).
+fn stored_cell_size(
+    page_type: PageType,
+    page_size: usize,
+    reserved_space: usize,
+    cell: &[u8],
+) -> usize {
+    let mut header = if page_type.is_interior() { 4 } else { 0 };
+    let mut payload_size = 0;
+    if page_type != PageType::TableInterior {
+        let (size, b) = VarInt::deserialize(&cell[header..]);
+        payload_size = size.0 as usize;
+        header += b;
+    }
+    if page_type.is_table() {
+        header += VarInt::deserialize(&cell[header..]).1;
+    }
+    return with_payload(page_type, page_size, reserved_space, header, payload_size);
+}
+
+/// The size of a cell, given the size of what comes before its payload.
+fn with_payload(
+    page_type: PageType,
+    page_size: usize,
+    reserved_space: usize,
+    header: usize,
+    payload_size: usize,
+) -> usize {
+    if page_type == PageType::TableInterior {
+        return header;
+    }
+    let is_index = page_type != PageType::TableLeaf;
+    let on_page = calc_payload_on_page(page_size, reserved_space, payload_size, is_index);
+    // cells that spill onto overflow pages end with the number of the
+    // first overflow page
+    let overflow = if on_page < payload_size { 4 } else { 0 };
+    return header + on_page + overflow;
 }
 
 fn calc_payload_on_page(
@@ -799,3 +3972,937 @@ fn calc_payload_on_page(
     };
     return payload_on_page;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::Connection;
+    use crate::pagefile::PagerConfig;
+
+    #[test]
+    fn insert_splits_pages() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        conn.execute("CREATE TABLE t(a, b)").unwrap();
+        let table = conn.table("t").unwrap();
+        // scrambled row IDs, so that pages are split in the middle as well
+        // as at the end
+        let row_ids: Vec<i64> = (0..500).map(|i| (i * 7919) % 500 + 1).collect();
+        conn.write_statement(|_| {
+            for id in &row_ids {
+                let values = vec![Value::Int64(*id), Value::String("x".repeat(50))];
+                table.btree.insert(*id, &Record::from_values(values))?;
+            }
+            return Ok(());
+        })
+        .unwrap();
+
+        let rows = table.btree.list_records();
+        assert_eq!(rows.len(), 500);
+        for (i, (row_id, record)) in rows.iter().enumerate() {
+            assert_eq!(row_id.0, i as i64 + 1);
+            assert_eq!(record.values[0].get_int_val(), Some(row_id.0));
+        }
+        assert_eq!(table.btree.count_rows().unwrap(), 500);
+        assert!(conn.pager.read().num_pages > 3);
+        assert!(table.btree.insert(1, &Record::from_values(vec![])).is_err());
+    }
+
+    #[test]
+    fn create_empty_trees() {
+        let pager = SharedPager::new(
+            Pager::in_memory(Vec::new(), &crate::DbOptions::from_image(&[]).unwrap()).unwrap(),
+        );
+        let pages = pager.read().num_pages;
+        let table = Btree::create(
+            "t".to_string(),
+            "t".to_string(),
+            BtreeKind::Table,
+            pager.clone(),
+        )
+        .unwrap();
+        let index = Btree::create(
+            "i".to_string(),
+            "t".to_string(),
+            BtreeKind::Index,
+            pager.clone(),
+        )
+        .unwrap();
+        assert_eq!(table.root_page, pages + 1);
+        assert_eq!(index.root_page, pages + 2);
+        assert!(table.is_empty().unwrap());
+        assert!(index.is_empty().unwrap());
+
+        pager.write().begin_statement();
+        let row = Record::from_values(vec![Value::Int64(5)]);
+        table.insert(1, &row).unwrap();
+        let entry = Record::from_values(vec![Value::Int64(5), Value::Int64(1)]);
+        index.index_insert(&entry, &|_| Ordering::Greater).unwrap();
+        assert_eq!(table.get_row(VarInt(1)).unwrap().values, row.values);
+        assert_eq!(index.list_records().len(), 1);
+        assert!(index.insert(1, &row).is_err());
+    }
+
+    #[test]
+    fn compare_payload_prefixes() {
+        let text = |s: &str| Value::String(s.to_string());
+        let records = [
+            vec![Value::Int64(1), text("a"), Value::Int64(7)],
+            vec![Value::Null, Value::Float(2.5), Value::Blob(vec![1, 2])],
+            vec![text("b"), Value::Int64(-3)],
+            vec![],
+        ];
+        let keys = [
+            vec![],
+            vec![Value::Int64(1)],
+            vec![Value::Int64(1), text("a")],
+            vec![Value::Int64(1), text("b"), Value::Int64(0)],
+            vec![Value::Null, Value::Int64(2)],
+            vec![text("b"), Value::Int64(-3), Value::Int64(9)],
+            vec![Value::Float(0.5)],
+        ];
+        for values in &records {
+            let record = Record::from_values(values.clone());
+            let payload = record.serialize();
+            for key in &keys {
+                assert_eq!(
+                    compare_prefix_payload(key, &payload, &[]),
+                    compare_prefix(key, &record, &[]),
+                    "{:?} against {:?}",
+                    key,
+                    values
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn lookup_keys_in_order() {
+        let mut conn = Connection::deserialize(Vec::new()).unwrap();
+        conn.execute("CREATE TABLE t(a, b)").unwrap();
+        let values: Vec<String> = (0..3000)
+            .map(|i| format!("({}, '{}')", i % 30, "x".repeat(i % 7 * 20)))
+            .collect();
+        conn.execute(&format!("INSERT INTO t VALUES {}", values.join(", ")))
+            .unwrap();
+        conn.execute("CREATE INDEX t_ab ON t(a, b)").unwrap();
+        let root_page = conn.schema.index("t_ab").unwrap().root_page;
+        let index = Btree::new(
+            "t_ab".to_string(),
+            "t".to_string(),
+            root_page,
+            conn.pager.clone(),
+        );
+
+        // each key's entries span several leaves, and the keys skip some
+        let keys: Vec<Vec<Value>> = [-1, 0, 1, 2, 7, 8, 15, 29, 40]
+            .iter()
+            .map(|k| vec![Value::Int64(*k)])
+            .collect();
+        let mut expected = Vec::new();
+        for key in &keys {
+            expected.extend(index.index_lookup(key).unwrap());
+        }
+        assert_eq!(expected.len(), 700);
+
+        let before = conn.pager.read().metrics().rows_examined;
+        assert_eq!(index.index_lookup_keys(&keys).unwrap(), expected);
+        // the entries found, and the one after each run of them
+        let examined = conn.pager.read().metrics().rows_examined - before;
+        assert!(examined <= 700 + keys.len() as u64);
+
+        // keys given one at a time pick up where the last left off
+        let mut cursor = index.cursor();
+        for key in [3, 3, 12, 13] {
+            let key = [Value::Int64(key)];
+            let start = |payload: &[u8]| match compare_prefix_payload(&key, payload, &[]) {
+                Ordering::Greater => Ordering::Greater,
+                _ => Ordering::Equal,
+            };
+            assert!(cursor.advance_to(&start).unwrap());
+            assert_eq!(cursor.current().unwrap().values[0], key[0]);
+        }
+    }
+
+    #[test]
+    fn seek_with_key_order() {
+        let mut conn = Connection::deserialize(Vec::new()).unwrap();
+        conn.execute("CREATE TABLE t(a, b)").unwrap();
+        let names = ["abc", "ABD", "Abc", "abe", "b"];
+        let values: Vec<String> = (0..1000)
+            .map(|i| format!("({}, '{}')", i % 10, names[i / 10 % 5]))
+            .collect();
+        conn.execute(&format!("INSERT INTO t VALUES {}", values.join(", ")))
+            .unwrap();
+        conn.execute("CREATE INDEX t_ab ON t(a DESC, b COLLATE NOCASE)")
+            .unwrap();
+        let schema = conn.schema.index("t_ab").unwrap().clone();
+        let table = conn.schema.table("t").unwrap().clone();
+        let (columns, _) = crate::ddl::index_columns(&table, &schema, &conn.collations).unwrap();
+        let index = Btree::new(
+            schema.name.clone(),
+            schema.table_name.clone(),
+            schema.root_page,
+            conn.pager.clone(),
+        )
+        .with_key_order(crate::ddl::index_key_order(&columns));
+
+        let text = |s: &str| Value::String(s.to_string());
+        let found = index.index_lookup(&[Value::Int64(3)]).unwrap();
+        assert_eq!(found.len(), 100);
+        assert!(found.iter().all(|e| e.values[0] == Value::Int64(3)));
+        // the second column matches without regard to case
+        let found = index.index_lookup(&[Value::Int64(3), text("ABC")]).unwrap();
+        assert_eq!(found.len(), 40);
+        let found = index.index_lookup(&[Value::Int64(4), text("ABF")]).unwrap();
+        assert_eq!(found.len(), 0);
+        // ranges run from the high end of a descending column
+        let lower = Bound::Included(Record::from_values(vec![Value::Int64(5)]));
+        let upper = Bound::Excluded(Record::from_values(vec![Value::Int64(2)]));
+        let found: Vec<Record> = index
+            .index_range(lower, upper)
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(found.len(), 300);
+        assert_eq!(found[0].values[0], Value::Int64(5));
+        assert_eq!(found[299].values[0], Value::Int64(3));
+
+        // queries look rows up through the index
+        let plan = conn
+            .execute("EXPLAIN QUERY PLAN SELECT b FROM t WHERE a > 7")
+            .unwrap();
+        assert_eq!(plan.rows[0][3], text("SEARCH t USING INDEX t_ab (a>?)"));
+        let result = conn.execute("SELECT a FROM t WHERE a > 7").unwrap();
+        assert_eq!(result.rows.len(), 200);
+        let result = conn
+            .execute("SELECT a FROM t WHERE a IN (2, 9, 4) ORDER BY a DESC")
+            .unwrap();
+        let expected: Vec<Vec<Value>> = [9, 4, 2]
+            .iter()
+            .flat_map(|a| std::iter::repeat_n(vec![Value::Int64(*a)], 100))
+            .collect();
+        assert_eq!(result.rows, expected);
+    }
+
+    #[test]
+    fn split_uneven_cells() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        conn.execute("CREATE TABLE t(a)").unwrap();
+        let table = conn.table("t").unwrap();
+        // the two large rows cannot share a page, so the page has to be
+        // split between them rather than in the middle of the bytes
+        conn.write_statement(|_| {
+            for (id, size) in &[(1, 3000), (3, 1000), (2, 3000)] {
+                let values = vec![Value::Blob(vec![0; *size])];
+                table.btree.insert(*id, &Record::from_values(values))?;
+            }
+            return Ok(());
+        })
+        .unwrap();
+        let row_ids: Vec<i64> = table.btree.list_records().iter().map(|r| r.0 .0).collect();
+        assert_eq!(row_ids, [1, 2, 3]);
+    }
+
+    #[test]
+    fn defragment() {
+        let mut leaf = TableLeafPage::new(
+            PageHeader::new(PageType::TableLeaf, 512, 0),
+            &[0; 512],
+            512,
+            0,
+        );
+        let row = |size: usize| Record::from_values(vec![Value::Blob(vec![size as u8; size])]);
+        for id in 1..=10 {
+            leaf.insert(id, &row(40)).unwrap();
+        }
+        for index in (0..10).step_by(2).rev() {
+            leaf.delete(index).unwrap();
+        }
+        let free_space = leaf.free_space().unwrap();
+        assert!(leaf.header.first_freeblock != 0);
+        assert!(!leaf.header.has_room_for(100));
+
+        // the free blocks left by the deleted rows are enough for this
+        // row once they are moved together
+        leaf.insert(11, &row(100)).unwrap();
+        assert_eq!(leaf.header.first_freeblock, 0);
+        assert_eq!(leaf.header.fragmented_bytes, 0);
+        // a 105-byte cell and its pointer
+        assert_eq!(leaf.free_space().unwrap(), free_space - 107);
+        let rows: Vec<(i64, Record)> = leaf.iter().map(|(id, record)| (id.0, record)).collect();
+        assert_eq!(rows.len(), 6);
+        for (id, record) in &rows[..5] {
+            assert_eq!(*id % 2, 0);
+            assert_eq!(*record, row(40));
+        }
+        assert_eq!(rows[5], (11, row(100)));
+    }
+
+    #[test]
+    fn interior_splits() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        conn.execute("PRAGMA page_size = 512").unwrap();
+        conn.execute("CREATE TABLE t(a)").unwrap();
+        let table = conn.table("t").unwrap();
+        // page 1 starts after the file header, so it fills up sooner
+        let schema = Btree::new(
+            "sqlite_schema".to_string(),
+            "sqlite_schema".to_string(),
+            1,
+            conn.pager.clone(),
+        );
+        let row_ids: Vec<i64> = (0..5000).map(|i| (i * 7919) % 5000 + 2).collect();
+        conn.write_statement(|_| {
+            for id in &row_ids {
+                // shaped like a trigger, so that the schema can still be read
+                let record = Record::from_values(vec![
+                    Value::String("trigger".to_string()),
+                    Value::String("x".repeat(30)),
+                    Value::String("t".to_string()),
+                ]);
+                table.btree.insert(*id, &record)?;
+                schema.insert(*id, &record)?;
+            }
+            return Ok(());
+        })
+        .unwrap();
+
+        for tree in &[&table.btree, &schema] {
+            assert!(tree.find_leaf(2500).unwrap().path.len() >= 2);
+            let rows = tree.list_records();
+            assert_eq!(rows.len(), 5000 + (tree.root_page == 1) as usize);
+            assert!(rows.windows(2).all(|w| w[0].0 .0 < w[1].0 .0));
+        }
+        match &*conn.pager.write().get_page(1).unwrap() {
+            BtreePage::TableInterior(pg) => assert_eq!(pg.header.offset, 100),
+            _ => panic!("page 1 is not an interior page"),
+        }
+        let problems = crate::integrity::check_structure(&conn).unwrap();
+        assert!(problems.is_empty(), "{:?}", problems);
+    }
+
+    #[test]
+    fn scan_guard() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let path = file.path().to_str().unwrap();
+        let config = PagerConfig {
+            shared_cache: true,
+            ..PagerConfig::default()
+        };
+        let mut first = Connection::with_config(path, config).unwrap();
+        let mut second = Connection::with_config(path, config).unwrap();
+        first.execute("CREATE TABLE t(a)").unwrap();
+        first.execute("INSERT INTO t VALUES (1), (2), (3)").unwrap();
+        let table = first.table("t").unwrap();
+
+        let mut scan = table.btree.scan();
+        assert!(scan.next().unwrap().is_ok());
+        second.execute("INSERT INTO t VALUES (4)").unwrap();
+        let err = scan.next().unwrap().unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ScanChanged>(),
+            Some(&ScanChanged::DataChanged)
+        );
+        assert!(scan.next().is_none());
+
+        let mut scan = table.btree.scan();
+        assert!(scan.next().unwrap().is_ok());
+        second.execute("CREATE TABLE u(b)").unwrap();
+        let err = scan.next().unwrap().unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ScanChanged>(),
+            Some(&ScanChanged::SchemaChanged)
+        );
+        assert_eq!(table.btree.scan().count(), 4);
+    }
+
+    #[test]
+    fn balance_siblings() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        conn.execute("PRAGMA page_size = 512").unwrap();
+        conn.execute("CREATE TABLE t(a)").unwrap();
+        conn.execute("CREATE INDEX t_a ON t(a)").unwrap();
+        let table = conn.table("t").unwrap();
+        let schema = conn.schema.index("t_a").unwrap();
+        let index = Btree::new(
+            schema.name.clone(),
+            schema.table_name.clone(),
+            schema.root_page,
+            conn.pager.clone(),
+        );
+        conn.write_statement(|_| {
+            for i in 0..3000 {
+                let id = (i * 7919) % 3000 + 1;
+                let record =
+                    Record::from_values(vec![Value::String("x".repeat(id as usize % 29 + 3))]);
+                table.btree.insert(id, &record)?;
+                let entry = Record::from_values(vec![Value::Int64(id), Value::Int64(id)]);
+                index.index_insert(&entry, &|other| {
+                    crate::exec::compare(&Value::Int64(id), &other.values[0])
+                })?;
+            }
+            return Ok(());
+        })
+        .unwrap();
+        assert!(crate::integrity::check_structure(&conn).unwrap().is_empty());
+        assert_eq!(table.btree.count_rows().unwrap(), 3000);
+        assert_eq!(
+            index
+                .index_range(Bound::Unbounded, Bound::Unbounded)
+                .count(),
+            3000
+        );
+
+        // sharing cells among three siblings, rather than splitting pages
+        // in half, keeps the leaves well over half full
+        let pager = conn.pager.clone();
+        let fill = |tree: &Btree| {
+            let (mut used, mut leaves) = (0, 0);
+            let mut pages = vec![tree.root_page];
+            while let Some(page_num) = pages.pop() {
+                let page = pager.write().get_page(page_num).unwrap().clone();
+                pages.extend(page.children().iter().map(|c| *c as usize));
+                if page.is_leaf() {
+                    used += 512 - page.free_space().unwrap();
+                    leaves += 1;
+                }
+            }
+            return used as f64 / (leaves * 512) as f64;
+        };
+        assert!(fill(&table.btree) > 0.7, "{}", fill(&table.btree));
+        assert!(fill(&index) > 0.7, "{}", fill(&index));
+    }
+
+    #[test]
+    fn balance_quick() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        conn.execute("PRAGMA page_size = 512").unwrap();
+        conn.execute("CREATE TABLE t(a)").unwrap();
+        let table = conn.table("t").unwrap();
+        let record = |id: i64| Record::from_values(vec![Value::String(format!("row {:>16}", id))]);
+        conn.write_statement(|_| {
+            for id in 1..=3000 {
+                table.btree.insert(id, &record(id))?;
+            }
+            return Ok(());
+        })
+        .unwrap();
+        assert!(crate::integrity::check_structure(&conn).unwrap().is_empty());
+
+        // rows added in order fill each leaf before starting the next,
+        // so all but the last have no room for another row
+        let row_size = needed_space(&table.btree.table_cell(3001, &record(3001)).unwrap());
+        let mut leaves = Vec::new();
+        let mut pages = vec![table.btree.root_page];
+        while let Some(page_num) = pages.pop() {
+            let page = conn.pager.write().get_page(page_num).unwrap().clone();
+            pages.extend(page.children().iter().rev().map(|c| *c as usize));
+            if page.is_leaf() {
+                leaves.push((page.header().num_cells as usize, page.free_space().unwrap()));
+            }
+        }
+        assert_eq!(leaves.iter().map(|(n, _)| n).sum::<usize>(), 3000);
+        for (_, free_space) in &leaves[..leaves.len() - 1] {
+            assert!(*free_space < row_size, "{:?}", leaves);
+        }
+    }
+
+    #[test]
+    fn check_finds_infractions() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        conn.execute("PRAGMA page_size = 512").unwrap();
+        conn.execute("CREATE TABLE t(a)").unwrap();
+        let table = conn.table("t").unwrap();
+        conn.write_statement(|_| {
+            for id in 1..=200 {
+                let record = Record::from_values(vec![Value::String("x".repeat(30))]);
+                table.btree.insert(id, &record)?;
+            }
+            return Ok(());
+        })
+        .unwrap();
+        assert_eq!(check(&table.btree), []);
+
+        // swap the first two rows of the first leaf, and make the root's
+        // first divider smaller than the rows to its left
+        let root = table.btree.get_page(table.btree.root_page).unwrap();
+        let leaf_num = root.children()[0] as usize;
+        let mut leaf = (*table.btree.get_page(leaf_num).unwrap()).clone();
+        leaf.header_mut().cell_pointers.swap(0, 1);
+        let mut cells = root.cells();
+        cells[0] = interior_cell(leaf_num as u32, 5);
+        let root = BtreePage::from_cells(
+            PageType::TableInterior,
+            &cells,
+            root.header().right_pointer,
+            0,
+            512,
+            0,
+        )
+        .unwrap();
+        let mut pager = conn.pager.write();
+        pager.insert(leaf_num, leaf).unwrap();
+        pager.insert(table.btree.root_page, root).unwrap();
+        drop(pager);
+
+        let messages: Vec<String> = check(&table.btree)
+            .iter()
+            .map(|infraction| infraction.to_string())
+            .collect();
+        assert_eq!(
+            messages[0],
+            format!("page {} of t: row ID 1 is out of order", leaf_num)
+        );
+        assert_eq!(
+            messages[1],
+            format!(
+                "page {} of t: row ID 6 is outside of the range its parent gives",
+                leaf_num
+            )
+        );
+    }
+
+    #[test]
+    fn delete_rebalances() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        conn.execute("PRAGMA page_size = 512").unwrap();
+        conn.execute("CREATE TABLE t(a, b)").unwrap();
+        let table = conn.table("t").unwrap();
+        conn.write_statement(|_| {
+            for i in 0..2000 {
+                let id = (i * 7919) % 2000 + 1;
+                let values = vec![
+                    Value::Int64(id),
+                    Value::String("x".repeat(id as usize % 37 + 5)),
+                ];
+                table.btree.insert(id, &Record::from_values(values))?;
+            }
+            return Ok(());
+        })
+        .unwrap();
+        conn.execute("CREATE INDEX t_b ON t(b, a)").unwrap();
+        let schema = conn.schema.index("t_b").unwrap();
+        let index = Btree::new(
+            schema.name.clone(),
+            schema.table_name.clone(),
+            schema.root_page,
+            conn.pager.clone(),
+        );
+        let pager = conn.pager.clone();
+        let height = |tree: &Btree| {
+            let mut page_num = tree.root_page;
+            let mut height = 1;
+            while let Some(&child) = pager.write().get_page(page_num).unwrap().children().first() {
+                page_num = child as usize;
+                height += 1;
+            }
+            return height;
+        };
+        assert!(height(&table.btree) >= 3 && height(&index) >= 3);
+
+        // deleting rows removes their entries from the index, whether on
+        // leaf or interior pages, and the trees shrink as pages empty
+        for m in &[7, 3, 2] {
+            conn.execute(&format!("DELETE FROM t WHERE a % {} <> 0", m))
+                .unwrap();
+            assert!(crate::integrity::check_structure(&conn).unwrap().is_empty());
+            assert!(crate::integrity::check_indexes(&conn).unwrap().is_empty());
+        }
+        assert_eq!(table.btree.count_rows().unwrap(), 47);
+        assert!(height(&table.btree) <= 2 && height(&index) <= 2);
+        let missing = [Value::String("y".to_string()), Value::Int64(1)];
+        let position = |other: &Record| {
+            return missing
+                .iter()
+                .zip(other.values.iter())
+                .map(|(a, b)| crate::exec::compare(a, b))
+                .find(|ordering| *ordering != Ordering::Equal)
+                .unwrap_or(Ordering::Equal);
+        };
+        assert!(!index.index_delete(&position).unwrap());
+    }
+
+    #[test]
+    fn delete_with_longer_predecessors() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        conn.execute("PRAGMA page_size = 512").unwrap();
+        conn.execute("CREATE TABLE t(a INTEGER, b TEXT)").unwrap();
+        // every short entry comes just after a long one, and building the
+        // index packs its interior pages full
+        let values: Vec<String> = (0..600)
+            .map(|i| {
+                let pad = if i % 2 == 0 { 60 } else { 1 };
+                return format!("({}, '{:04}{}')", i, i, "x".repeat(pad));
+            })
+            .collect();
+        conn.execute(&format!("INSERT INTO t VALUES {}", values.join(", ")))
+            .unwrap();
+        conn.execute("CREATE INDEX tb ON t(b)").unwrap();
+
+        // the short entries on interior pages are replaced by the longer
+        // ones before them, which no longer fit on those pages
+        conn.execute("DELETE FROM t WHERE a % 2 = 1").unwrap();
+        assert!(crate::integrity::check_structure(&conn).unwrap().is_empty());
+        assert!(crate::integrity::check_indexes(&conn).unwrap().is_empty());
+        let rows = conn.execute("SELECT a FROM t ORDER BY b").unwrap();
+        let expected: Vec<Vec<Value>> =
+            (0..600).step_by(2).map(|i| vec![Value::Int64(i)]).collect();
+        assert_eq!(rows.rows, expected);
+    }
+
+    #[test]
+    fn cyclic_tree() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        conn.execute("CREATE TABLE t(a)").unwrap();
+        for _ in 1..=100 {
+            conn.execute(&format!("INSERT INTO t VALUES ('{}')", "x".repeat(100)))
+                .unwrap();
+        }
+        let table = conn.table("t").unwrap();
+        assert!(table.btree.get_row(VarInt::new(100)).is_some());
+
+        // point the root's last child back at the root
+        let root = table.btree.root_page;
+        match conn.pager.write().get_page_mut(root).unwrap() {
+            BtreePage::TableInterior(pg) => pg.header.right_pointer = Some(root as u32),
+            _ => panic!("root is not an interior page"),
+        }
+        assert!(table.btree.get_row(VarInt::new(1)).is_some());
+        assert!(table.btree.get_row(VarInt::new(100)).is_none());
+        assert!(table.btree.find_leaf(100).is_err());
+        assert!(table.btree.max_row_id().is_err());
+        assert!(table.btree.count_rows().is_err());
+        assert!(table.btree.scan().any(|row| row.is_err()));
+    }
+
+    #[test]
+    fn overflow_pages() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        conn.execute("PRAGMA page_size = 512").unwrap();
+        conn.execute("CREATE TABLE t(a, b)").unwrap();
+        let table = conn.table("t").unwrap();
+        // payloads that fit, that just spill over, and that need a long
+        // chain of overflow pages
+        let value = |id: i64| {
+            return Value::Blob((0..id * id * 7).map(|i| (i % 251) as u8).collect());
+        };
+        conn.write_statement(|_| {
+            for id in (1..=60).rev() {
+                table
+                    .btree
+                    .insert(id, &Record::from_values(vec![Value::Int64(id), value(id)]))?;
+            }
+            return Ok(());
+        })
+        .unwrap();
+        conn.execute("CREATE INDEX t_b ON t(b)").unwrap();
+        assert!(crate::integrity::check_structure(&conn).unwrap().is_empty());
+        assert!(crate::integrity::check_indexes(&conn).unwrap().is_empty());
+
+        let rows = table.btree.list_records();
+        assert_eq!(rows.len(), 60);
+        for (row_id, record) in rows {
+            assert_eq!(record.values[1], value(row_id.0));
+        }
+        let big = table.btree.get_row(VarInt::new(60)).unwrap();
+        assert_eq!(big.values[1], value(60));
+
+        // rows that move to another page take their chains with them
+        conn.execute("DELETE FROM t WHERE a % 2 = 0").unwrap();
+        assert!(crate::integrity::check_indexes(&conn).unwrap().is_empty());
+        table
+            .btree
+            .update(59, &Record::from_values(vec![Value::Int64(59), value(3)]))
+            .unwrap();
+        assert_eq!(
+            table.btree.get_row(VarInt::new(59)).unwrap().values[1],
+            value(3)
+        );
+        assert_eq!(table.btree.list_records().len(), 30);
+        assert!(crate::integrity::check_structure(&conn).unwrap().is_empty());
+    }
+
+    #[test]
+    fn cell_sizes() {
+        // (page type, payload size, row ID, cell size), as found in files
+        // with 512-byte pages written by sqlite3
+        let cases = [
+            (PageType::TableLeaf, 2, 1, 4),
+            (PageType::TableLeaf, 103, 200, 106),
+            (PageType::TableLeaf, 465, 70000, 470),
+            (PageType::TableLeaf, 473, 3, 476),
+            (PageType::TableLeaf, 474, 3, 477),
+            (PageType::TableLeaf, 483, 1 << 40, 51),
+            (PageType::TableLeaf, 1003, 5, 46),
+            (PageType::TableLeaf, 5003, 9, 438),
+            (PageType::IndexLeaf, 3, 0, 4),
+            (PageType::IndexLeaf, 95, 0, 96),
+            (PageType::IndexLeaf, 106, 0, 44),
+            (PageType::IndexLeaf, 469, 0, 45),
+            (PageType::IndexInterior, 20, 0, 25),
+            (PageType::IndexInterior, 102, 0, 107),
+            (PageType::IndexInterior, 104, 0, 48),
+            (PageType::IndexInterior, 129, 0, 49),
+            (PageType::TableInterior, 0, 6000, 6),
+            (PageType::TableInterior, 0, 20000, 7),
+        ];
+        for (page_type, payload_size, row_id, expected) in cases {
+            assert_eq!(
+                cell_size(page_type, 512, 0, payload_size, row_id),
+                expected,
+                "{:?} {}",
+                page_type,
+                payload_size
+            );
+        }
+
+        // the cells built for rows are the size expected
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        conn.execute("PRAGMA page_size = 512").unwrap();
+        conn.execute("CREATE TABLE t(a)").unwrap();
+        let table = conn.table("t").unwrap();
+        conn.write_statement(|_| {
+            for (i, size) in [0, 100, 470, 471, 1000, 5000].iter().enumerate() {
+                let record = Record::from_values(vec![Value::Blob(vec![1; *size])]);
+                let cell = table.btree.table_cell(i as i64 + 1, &record)?;
+                let payload_size = record.serialize().len();
+                let expected = cell_size(PageType::TableLeaf, 512, 0, payload_size, i as i64 + 1);
+                assert_eq!(cell.len(), expected, "{}", size);
+                assert_eq!(
+                    stored_cell_size(PageType::TableLeaf, 512, 0, &cell),
+                    expected
+                );
+            }
+            return Ok(());
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn cursor() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        conn.execute("PRAGMA page_size = 512").unwrap();
+        conn.execute("CREATE TABLE t(a, b)").unwrap();
+        let values: Vec<String> = (1..=400)
+            .map(|i| format!("({}, '{}')", i * 3, "x".repeat(i % 40)))
+            .collect();
+        conn.execute(&format!("INSERT INTO t VALUES {}", values.join(", ")))
+            .unwrap();
+        conn.execute("CREATE INDEX t_b ON t(b, a)").unwrap();
+        let table = conn.table("t").unwrap();
+        let schema = conn.schema.index("t_b").unwrap();
+        let index = Btree::new(
+            schema.name.clone(),
+            schema.table_name.clone(),
+            schema.root_page,
+            conn.pager.clone(),
+        );
+
+        // walking an index in either direction visits the entries on
+        // interior pages in their places between those on the leaves
+        let entries: Vec<Vec<Value>> = index
+            .index_lookup(&[])
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.values)
+            .collect();
+        assert_eq!(entries.len(), 400);
+        let mut cursor = index.cursor();
+        let mut forward = Vec::new();
+        let mut valid = cursor.first().unwrap();
+        while valid {
+            forward.push(cursor.current().unwrap().values);
+            valid = cursor.next().unwrap();
+        }
+        assert_eq!(forward, entries);
+        // a scan of an index gives each entry with the row it refers to
+        let listed = index.list_records();
+        assert_eq!(listed.len(), 400);
+        for (row_id, entry) in &listed {
+            let row = table.btree.get_row(*row_id).unwrap();
+            assert_eq!(row.values[0], entry.values[1]);
+        }
+        let listed: Vec<Vec<Value>> = listed.into_iter().map(|(_, e)| e.values).collect();
+        assert_eq!(listed, entries);
+        let mut backward = Vec::new();
+        let mut valid = cursor.last().unwrap();
+        while valid {
+            backward.push(cursor.current().unwrap().values);
+            valid = cursor.prev().unwrap();
+        }
+        backward.reverse();
+        assert_eq!(backward, entries);
+
+        // seeking finds the first entry not before the key, from which
+        // the cursor can go either way
+        let key = Value::String("x".repeat(20));
+        assert!(cursor.seek(std::slice::from_ref(&key)).unwrap());
+        let found = entries.iter().position(|entry| entry[0] == key).unwrap();
+        assert_eq!(cursor.current().unwrap().values, entries[found]);
+        assert!(cursor.prev().unwrap());
+        assert_eq!(cursor.current().unwrap().values, entries[found - 1]);
+        assert!(cursor.next().unwrap() && cursor.next().unwrap());
+        assert_eq!(cursor.current().unwrap().values, entries[found + 1]);
+        assert!(!cursor.seek(&[Value::String("y".to_string())]).unwrap());
+        assert!(!cursor.is_valid());
+
+        // only the odd row IDs are left
+        conn.execute("DELETE FROM t WHERE a % 2 = 0").unwrap();
+        let mut rows = table.btree.cursor();
+        assert!(rows.seek(&[Value::Int64(300)]).unwrap());
+        assert_eq!(rows.row_id(), Some(301));
+        assert!(rows.prev().unwrap());
+        assert_eq!(rows.row_id(), Some(299));
+        assert_eq!(rows.current().unwrap().values[0], Value::Int64(897));
+        assert!(rows.last().unwrap());
+        assert_eq!(rows.row_id(), Some(399));
+        assert!(!rows.next().unwrap());
+        assert!(rows.seek(&[Value::String("a".to_string())]).is_err());
+    }
+
+    #[test]
+    fn pages_round_trip() {
+        let mut conn = Connection::deserialize(Vec::new()).unwrap();
+        for i in 0..20 {
+            conn.execute(&format!("CREATE TABLE t{}(a, b)", i)).unwrap();
+        }
+        conn.execute("INSERT INTO t0 VALUES (1, 'one'), (2, 'two')")
+            .unwrap();
+        let image = conn.serialize().unwrap();
+        let page_size = conn.pager.read().page_size();
+        let options = DbOptions::deserialize(&image[..100]).unwrap();
+
+        // page 1 comes back with its file header, and every other page
+        // the same as it was read
+        for (i, bytes) in image.chunks(page_size).enumerate() {
+            let page_num = i + 1;
+            let page = BtreePage::deserialize(bytes, page_num, page_size, 0).unwrap();
+            assert_eq!(page.serialize_page(page_num, &options).unwrap(), bytes);
+        }
+        let page = BtreePage::deserialize(&image[..page_size], 1, page_size, 0).unwrap();
+        assert!(page.serialize_page(2, &options).is_err());
+    }
+
+    #[test]
+    fn records_round_trip() {
+        // a record written by SQLite for
+        // (NULL, 0, 1, 127, -129, 8388607, -2147483648, 2^47 - 1, 2^47,
+        // 1.5, 'text', x'0102')
+        let hex = "0d0008090102030405060715107fff7f7fffff800000007fffffffffff00\
+                   008000000000003ff8000000000000746578740102";
+        let bytes: Vec<u8> = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect();
+        let record = Record::deserialize(&bytes).unwrap();
+        let expected = vec![
+            Value::Null,
+            Value::Int64(0),
+            Value::Int64(1),
+            Value::Int64(127),
+            Value::Int64(-129),
+            Value::Int64(8388607),
+            Value::Int64(-2147483648),
+            Value::Int64((1 << 47) - 1),
+            Value::Int64(1 << 47),
+            Value::Float(1.5),
+            Value::String("text".to_string()),
+            Value::Blob(vec![1, 2]),
+        ];
+        assert_eq!(record.values, expected);
+        assert_eq!(record.serialize(), bytes);
+        // integers are written with the smallest serial type however
+        // they are held
+        assert_eq!(Record::new(Vec::new(), expected).serialize(), bytes);
+
+        // SQLite's record of 70 strings of 60 bytes, whose header size
+        // takes two bytes to hold
+        let record = Record::from_values(vec![Value::String("x".repeat(60)); 70]);
+        let bytes = record.serialize();
+        assert_eq!(bytes[..6], [0x81, 0x0e, 0x81, 0x05, 0x81, 0x05]);
+        assert_eq!(bytes.len(), 142 + 70 * 60);
+        assert_eq!(Record::deserialize(&bytes).unwrap().values, record.values);
+
+        assert!(Record::deserialize(&bytes[..100]).is_err());
+        assert!(Record::deserialize(&bytes[..200]).is_err());
+    }
+
+    #[test]
+    fn decoded_pages() {
+        let mut conn = Connection::deserialize(Vec::new()).unwrap();
+        conn.execute("CREATE TABLE t(a, b)").unwrap();
+        let values: Vec<String> = (0..400)
+            .map(|i| format!("({}, '{}')", i, "x".repeat(i % 9 * 700)))
+            .collect();
+        conn.execute(&format!("INSERT INTO t VALUES {}", values.join(", ")))
+            .unwrap();
+        conn.execute("CREATE INDEX t_b ON t(b)").unwrap();
+        let image = conn.serialize().unwrap();
+        let page_size = conn.pager.read().page_size();
+
+        // every page of the table and index, some with overflowing
+        // cells, comes back the same from its decoded cells
+        let mut page_types = Vec::new();
+        for (i, bytes) in image.chunks(page_size).enumerate() {
+            let page_num = i + 1;
+            let page = match BtreePage::deserialize(bytes, page_num, page_size, 0) {
+                Ok(page) => page,
+                // overflow pages
+                Err(_) => continue,
+            };
+            let decoded = DecodedPage::deserialize(bytes, page_num, page_size, 0).unwrap();
+            assert_eq!(decoded.cells.len(), page.header().cell_pointers.len());
+            let rebuilt = decoded.to_page().unwrap();
+            assert_eq!(rebuilt.cells(), page.cells());
+            assert_eq!(rebuilt.children(), page.children());
+            page_types.push(decoded.page_type);
+        }
+        for page_type in [
+            PageType::TableLeaf,
+            PageType::TableInterior,
+            PageType::IndexLeaf,
+            PageType::IndexInterior,
+        ] {
+            assert!(page_types.contains(&page_type));
+        }
+
+        // cells can be changed before the page is built again
+        let root_page = conn.schema.table("t").unwrap().root_page;
+        let tree = Btree::new(
+            "t".to_string(),
+            "t".to_string(),
+            root_page,
+            conn.pager.clone(),
+        );
+        let leaf_num = tree.find_leaf(0).unwrap().page_num;
+        let bytes = &image[(leaf_num - 1) * page_size..leaf_num * page_size];
+        let mut decoded = DecodedPage::deserialize(bytes, leaf_num, page_size, 0).unwrap();
+        let first = decoded.cells.remove(0);
+        assert_eq!(first.row_id, Some(1));
+        assert_eq!(
+            first.payload,
+            Record::from_values(vec![Value::Int64(0), Value::String(String::new())]).serialize()
+        );
+        let rebuilt = match decoded.to_page().unwrap() {
+            BtreePage::TableLeaf(pg) => pg,
+            _ => panic!("Expected a table leaf page"),
+        };
+        assert_eq!(rebuilt.row_id(0), 2);
+
+        // a cell pointer past the end of the page is caught when decoding
+        let mut bytes = bytes.to_vec();
+        bytes[8..10].copy_from_slice(&(page_size as u16 - 1).to_be_bytes());
+        assert!(DecodedPage::deserialize(&bytes, leaf_num, page_size, 0).is_err());
+    }
+}
@@ -0,0 +1,272 @@
+//! Keeping a database usable by an older version of SQLite. A database's
+//! header records the version of SQLite that last wrote it and the
+//! schema format it uses, which together say what the SQLite versions
+//! reading it can be expected to understand. With a `Compatibility` set
+//! on a connection, statements that would put something newer into the
+//! database (e.g., a WITHOUT ROWID table in a database written by SQLite
+//! 3.7) are refused, or are run and noted as warnings.
+
+use eyre::{eyre, Result};
+use std::fmt;
+
+use crate::ast::{AlterAction, ColumnConstraint, InsertSource, Stmt};
+use crate::{DbOptions, FileVersion};
+
+/// A version of SQLite, as recorded in a database header, e.g. 3035004
+/// for 3.35.4.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SqliteVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl SqliteVersion {
+    pub const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        return Self {
+            major: major,
+            minor: minor,
+            patch: patch,
+        };
+    }
+
+    pub fn from_number(number: u32) -> Self {
+        return Self::new(number / 1_000_000, number / 1000 % 1000, number % 1000);
+    }
+}
+
+impl fmt::Display for SqliteVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "{}.{}.{}", self.major, self.minor, self.patch);
+    }
+}
+
+/// Something a database or statement uses that is newer than the
+/// version of SQLite the database is kept usable by.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Incompatibility {
+    /// What is used, e.g. "WITHOUT ROWID tables".
+    pub feature: String,
+    /// The first version of SQLite that supports it.
+    pub since: SqliteVersion,
+}
+
+impl fmt::Display for Incompatibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "{} require SQLite {} or later", self.feature, self.since);
+    }
+}
+
+/// The versions of SQLite that introduced schema formats 2 to 4. A
+/// database of an earlier format must stay readable by the versions
+/// before the next one.
+const SCHEMA_FORMATS: [SqliteVersion; 3] = [
+    SqliteVersion::new(3, 1, 3),
+    SqliteVersion::new(3, 1, 4),
+    SqliteVersion::new(3, 3, 0),
+];
+
+pub struct Compatibility {
+    /// The oldest version of SQLite the database must stay usable by.
+    pub target: SqliteVersion,
+    /// The schema format of the database, from 1 to 4.
+    pub schema_format: u32,
+    /// Whether statements that need a newer SQLite fail, rather than
+    /// being run with their incompatibilities added to `warnings`.
+    pub strict: bool,
+    pub warnings: Vec<Incompatibility>,
+}
+
+impl Compatibility {
+    /// Targets the version of SQLite and the schema format declared in a
+    /// database's header. Files last written by SQLite before 3.7.0 do
+    /// not record a version, and are taken to target 3.0.0. Fails if the
+    /// header itself is not one the target version could have written.
+    pub fn for_database(options: &DbOptions, strict: bool) -> Result<Self> {
+        let target = match options.sqlite_version {
+            0 => SqliteVersion::new(3, 0, 0),
+            number => SqliteVersion::from_number(number),
+        };
+        if !(1..=4).contains(&options.schema_format) {
+            return Err(eyre!("unsupported schema format {}", options.schema_format));
+        }
+        let compat = Self {
+            target: target,
+            schema_format: options.schema_format,
+            strict: strict,
+            warnings: Vec::new(),
+        };
+
+        let mut header = Vec::new();
+        if options.schema_format > 1 {
+            header.push(Incompatibility {
+                feature: format!("Databases of schema format {}", options.schema_format),
+                since: SCHEMA_FORMATS[options.schema_format as usize - 2],
+            });
+        }
+        if options.file_read_version == FileVersion::WAL
+            || options.file_write_version == FileVersion::WAL
+        {
+            header.push(Incompatibility {
+                feature: "Databases in WAL mode".to_string(),
+                since: SqliteVersion::new(3, 7, 0),
+            });
+        }
+        if let Some(problem) = header.into_iter().find(|p| !compat.supports(p.since)) {
+            return Err(eyre!(
+                "{}, but the database is for SQLite {}",
+                problem,
+                target
+            ));
+        }
+        return Ok(compat);
+    }
+
+    /// Whether something introduced in the given version of SQLite can
+    /// be used: the target must have it, and so must the versions that
+    /// only know the database's schema format.
+    pub fn supports(&self, since: SqliteVersion) -> bool {
+        let format_limit = SCHEMA_FORMATS.get(self.schema_format as usize - 1);
+        return since <= self.target && format_limit.is_none_or(|limit| since < *limit);
+    }
+
+    /// Checks a statement before it is run, failing if it uses anything
+    /// that is not supported and the check is strict.
+    pub fn check(&mut self, stmt: &Stmt) -> Result<()> {
+        let problems: Vec<Incompatibility> = features(stmt)
+            .into_iter()
+            .filter(|feature| !self.supports(feature.since))
+            .collect();
+        if self.strict {
+            if let Some(problem) = problems.first() {
+                return Err(eyre!(
+                    "{}, but the database is for SQLite {}",
+                    problem,
+                    self.target
+                ));
+            }
+        }
+        self.warnings.extend(problems);
+        return Ok(());
+    }
+}
+
+/// The things a statement puts in the database that not every version
+/// of SQLite supports.
+fn features(stmt: &Stmt) -> Vec<Incompatibility> {
+    let mut output = Vec::new();
+    let mut uses = |feature: &str, since: SqliteVersion| {
+        output.push(Incompatibility {
+            feature: feature.to_string(),
+            since: since,
+        });
+    };
+    match stmt {
+        Stmt::CreateTable(table) => {
+            if table.if_not_exists {
+                uses("CREATE TABLE IF NOT EXISTS", SqliteVersion::new(3, 3, 0));
+            }
+            if table.without_rowid {
+                uses("WITHOUT ROWID tables", SqliteVersion::new(3, 8, 2));
+            }
+            let generated = table.columns.iter().any(|column| {
+                column
+                    .constraints
+                    .iter()
+                    .any(|c| matches!(c, ColumnConstraint::Generated { .. }))
+            });
+            if generated {
+                uses("Generated columns", SqliteVersion::new(3, 31, 0));
+            }
+        }
+        Stmt::CreateIndex(index) => {
+            if index.if_not_exists {
+                uses("CREATE INDEX IF NOT EXISTS", SqliteVersion::new(3, 3, 0));
+            }
+            if index.columns.iter().any(|column| column.descending) {
+                uses("Descending indexes", SqliteVersion::new(3, 3, 0));
+            }
+            if index.where_clause.is_some() {
+                uses("Partial indexes", SqliteVersion::new(3, 8, 0));
+            }
+        }
+        Stmt::CreateView(view) => {
+            if view.if_not_exists {
+                uses("CREATE VIEW IF NOT EXISTS", SqliteVersion::new(3, 3, 0));
+            }
+            if view.columns.is_some() {
+                uses("View column lists", SqliteVersion::new(3, 9, 0));
+            }
+        }
+        Stmt::AlterTable(alter) => match alter.action {
+            AlterAction::RenameColumn { .. } => {
+                uses("ALTER TABLE RENAME COLUMN", SqliteVersion::new(3, 25, 0));
+            }
+            AlterAction::DropColumn(_) => {
+                uses("ALTER TABLE DROP COLUMN", SqliteVersion::new(3, 35, 0));
+            }
+        },
+        Stmt::Insert(insert) => {
+            // the values 0 and 1 are stored without a body, in a way
+            // that came with schema format 4
+            uses(
+                "Records written by this library",
+                SqliteVersion::new(3, 3, 0),
+            );
+            if let InsertSource::Values(rows) = &insert.source {
+                if rows.len() > 1 {
+                    uses("Multi-row VALUES", SqliteVersion::new(3, 7, 11));
+                }
+            }
+        }
+        Stmt::Explain { stmt, .. } => return features(stmt),
+        _ => (),
+    }
+    return output;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::Connection;
+
+    #[test]
+    fn restricts_to_target_version() {
+        let mut conn = Connection::deserialize(Vec::new()).unwrap();
+        conn.execute("CREATE TABLE t(a, b)").unwrap();
+        conn.db_options.sqlite_version = 3_008_000;
+        conn.compat = Some(Compatibility::for_database(&conn.db_options, true).unwrap());
+
+        conn.execute("CREATE INDEX t_a ON t(a) WHERE a > 0")
+            .unwrap();
+        let err = conn
+            .execute("CREATE TABLE w(a PRIMARY KEY) WITHOUT ROWID")
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "WITHOUT ROWID tables require SQLite 3.8.2 or later, \
+             but the database is for SQLite 3.8.0"
+        );
+        assert!(conn.schema.table("w").is_none());
+
+        // without strictness, the statement runs and is noted
+        conn.compat.as_mut().unwrap().strict = false;
+        conn.execute("ALTER TABLE t DROP COLUMN b").unwrap();
+        let warnings = &conn.compat.as_ref().unwrap().warnings;
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].since, SqliteVersion::new(3, 35, 0));
+
+        // a database of an older schema format cannot use what came
+        // with a newer one, even if its version could
+        let mut options = conn.db_options;
+        options.schema_format = 3;
+        let compat = Compatibility::for_database(&options, true).unwrap();
+        assert!(compat.supports(SqliteVersion::new(3, 2, 8)));
+        assert!(!compat.supports(SqliteVersion::new(3, 3, 0)));
+        options.schema_format = 5;
+        assert!(Compatibility::for_database(&options, true).is_err());
+        options.schema_format = 4;
+        options.sqlite_version = 3_002_000;
+        assert!(Compatibility::for_database(&options, true).is_err());
+    }
+}
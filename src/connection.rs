@@ -1,14 +1,788 @@
+use eyre::{eyre, Result};
+use std::cell::RefCell;
+use std::io::{Read, Seek};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::ast::{Insert, InsertSource, Stmt};
+use crate::btree::SampleRng;
+#[cfg(feature = "compat")]
+use crate::compat::Compatibility;
+use crate::dump;
+use crate::exec::{self, CollatingFunction, Collations};
+use crate::functions;
+use crate::insert;
+use crate::journal::RollbackJournal;
+use crate::limits::Limits;
+use crate::lock::LockLevel;
+
+use crate::pagefile::PagerConfig;
+use crate::pager::{self, Pager, SharedPager};
+use crate::parser;
+use crate::replication::{self, Applier, Change, ChangeSet, ReplicationLog};
+use crate::schema::Schema;
+use crate::slowlog::SlowLog;
+use crate::sorter::DEFAULT_SORT_MEMORY;
+use crate::statement::{QueryResult, Statement};
 use crate::table::Table;
-use eyre::Result;
+use crate::vacuum::{self, AutoVacuum};
+use crate::DbOptions;
 
 pub struct Connection {
-    pub table: Table,
+    pub db_options: DbOptions,
+    pub pager: SharedPager,
+    pub schema: Schema,
+    schema_generation: u64,
+    /// The memory budget, in bytes, for sorting before spilling to disk.
+    pub sort_memory: usize,
+    /// If set, the changes made through the connection are sent to it
+    /// as each statement commits.
+    pub replication_log: Option<ReplicationLog>,
+    /// If set, statements that take long enough to run are passed to it.
+    pub slow_log: Option<SlowLog>,
+    /// The generator random(), randomblob() and the row IDs picked at
+    /// random for full tables draw from (see `set_random_seed`).
+    pub rng: Rc<RefCell<SampleRng>>,
+    /// The collating sequences added with `register_collation`.
+    pub collations: Collations,
+    /// The auto-vacuum mode asked for with `PRAGMA auto_vacuum` that could
+    /// not be taken on straight away, as the database already had pages,
+    /// to be taken on when it is next rebuilt by `VACUUM`.
+    pub next_auto_vacuum: Option<AutoVacuum>,
+    /// If set, statements are checked against the version of SQLite the
+    /// database is kept usable by before they run.
+    #[cfg(feature = "compat")]
+    pub compat: Option<Compatibility>,
+    /// Set for a follower (see `open_follower`), which can only be
+    /// changed by ingesting changesets. It is taken while a changeset is
+    /// applied, which is what lets the changes be written.
+    follower: Option<Applier>,
+    filename: Option<String>,
+}
+
+/// Reads the pages every query starts from into the cache: page 1, the
+/// root of each table and index, and the children of those roots that
+/// are interior pages. Each level is read in as few requests as possible.
+fn preload_pages(pager: &mut Pager, schema: &Schema) -> Result<()> {
+    let mut roots = vec![1];
+    roots.extend(schema.tables.iter().map(|table| table.root_page));
+    roots.extend(schema.indexes.iter().map(|index| index.root_page));
+    pager.preload(&roots)?;
+
+    let mut children = Vec::new();
+    for &root in &roots {
+        if let Ok(page) = pager.get_page(root) {
+            children.extend(page.children().into_iter().map(|child| child as usize));
+        }
+    }
+    pager.preload(&children)?;
+    return Ok(());
 }
 
 impl Connection {
+    /// Opens a database, with file access configured from the
+    /// environment (see `PagerConfig::from_env`).
     pub fn new(filename: &str) -> Result<Self> {
+        return Self::with_config(filename, PagerConfig::from_env()?);
+    }
+
+    pub fn with_config(filename: &str, config: PagerConfig) -> Result<Self> {
+        let db_options = DbOptions::init(filename)?;
+        let pager = if config.shared_cache {
+            pager::open_shared(filename, &db_options, config)?
+        } else {
+            SharedPager::new(Pager::with_config(filename, &db_options, config)?)
+        };
+        let mut conn = Self::open(db_options, pager)?;
+        conn.filename = Some(filename.to_string());
+        return Ok(conn);
+    }
+
+    /// Opens a database from an image of its file held in memory, such
+    /// as one made by `serialize`, without going through the file
+    /// system. Changes are made to the image, and can be read back with
+    /// `serialize`. An empty image opens a new, empty database.
+    pub fn deserialize(image: Vec<u8>) -> Result<Self> {
+        let db_options = DbOptions::from_image(&image)?;
+        let pager = Pager::in_memory(image, &db_options)?;
+        return Self::open(db_options, SharedPager::new(pager));
+    }
+
+    /// Opens a read-only database from an image of its file, which is
+    /// read in place rather than copied. This is how databases embedded
+    /// in the program are opened (see `include_db!`).
+    pub fn open_bytes(image: &'static [u8]) -> Result<Self> {
+        let db_options = DbOptions::from_image(image)?;
+        let pager = Pager::from_bytes(image, &db_options)?;
+        return Self::open(db_options, SharedPager::new(pager));
+    }
+
+    /// Opens a copy of a database as it was before the transaction that
+    /// is being written to it, or was interrupted, by putting back the
+    /// original pages kept in its rollback journal. Neither the database
+    /// nor the journal is changed. Returns `None` if there is no journal.
+    pub fn open_before_transaction(filename: &str) -> Result<Option<Self>> {
+        let journal = match RollbackJournal::open(filename)? {
+            Some(journal) => journal,
+            None => return Ok(None),
+        };
+        let mut image = std::fs::read(filename)?;
+        journal.restore(&mut image);
+        return Self::deserialize(image).map(Some);
+    }
+
+    /// Opens a database as a read-only follower of another, which is
+    /// changed only by ingesting the changesets sent by the other's
+    /// replication log. The file should start as a copy of the other
+    /// database at the point the changeset numbered `next_sequence` was
+    /// about to be made; `next_sequence` is only used the first time the
+    /// follower is opened, after which the number of the next changeset
+    /// to apply is kept in a `-follower` file alongside the database. The
+    /// follower holds a SHARED lock on the file for as long as it is
+    /// open, so that no other connection writes to it.
+    pub fn open_follower(filename: &str, next_sequence: u64) -> Result<Self> {
+        let mut conn = Self::new(filename)?;
+        conn.pager.write().lock(LockLevel::Shared)?;
+        conn.check_hot_journal()?;
+        conn.check_file_changed()?;
+        let change_counter = conn.db_options.change_counter;
+        let next_sequence = match replication::load_progress(filename, change_counter)? {
+            Some(saved) => saved,
+            None => {
+                replication::save_progress(filename, next_sequence, change_counter)?;
+                next_sequence
+            }
+        };
+        conn.follower = Some(Applier::new(next_sequence));
+        return Ok(conn);
+    }
+
+    /// The name of the database file, unless the database was opened
+    /// from an image in memory.
+    pub fn filename(&self) -> Option<&str> {
+        return self.filename.as_deref();
+    }
+
+    /// The sequence number of the next changeset a follower will ingest.
+    pub fn follower_sequence(&self) -> Option<u64> {
+        return self.follower.as_ref().map(|applier| applier.next_sequence);
+    }
+
+    /// Applies a changeset to a follower as a single statement, so that
+    /// queries see either all of its changes or none of them. Returns
+    /// false for a changeset that has already been applied.
+    pub fn ingest(&mut self, changeset: &ChangeSet) -> Result<bool> {
+        let mut applier = self
+            .follower
+            .take()
+            .ok_or_else(|| eyre!("Only a follower can ingest changesets."))?;
+        let result = applier.apply(self, changeset);
+        let next_sequence = applier.next_sequence;
+        self.follower = Some(applier);
+        if let (Ok(true), Some(filename)) = (&result, &self.filename) {
+            let change_counter = self.db_options.change_counter;
+            replication::save_progress(filename, next_sequence, change_counter)?;
+        }
+        return result;
+    }
+
+    /// Ingests the changesets in a stream written by a `WriteSink`, up to
+    /// the end of the stream, returning how many were applied. A
+    /// changeset still being written is left to be read next time.
+    pub fn ingest_stream<R: Read + Seek>(&mut self, reader: &mut R) -> Result<usize> {
+        let mut applied = 0;
+        while let Some(changeset) = replication::read_changeset(reader)? {
+            if self.ingest(&changeset)? {
+                applied += 1;
+            }
+        }
+        return Ok(applied);
+    }
+
+    fn open(db_options: DbOptions, pager: SharedPager) -> Result<Self> {
+        let schema = Schema::load(pager.clone())?;
+        if pager.read().config().preload {
+            preload_pages(&mut pager.write(), &schema)?;
+        }
+        let generation = pager.read().generation();
         return Ok(Self {
-            table: Table::new(filename)?,
+            db_options: db_options,
+            pager: pager,
+            schema: schema,
+            schema_generation: generation,
+            sort_memory: DEFAULT_SORT_MEMORY,
+            replication_log: None,
+            slow_log: None,
+            rng: Rc::new(RefCell::new(functions::clock_rng())),
+            collations: Collations::default(),
+            next_auto_vacuum: None,
+            #[cfg(feature = "compat")]
+            compat: None,
+            follower: None,
+            filename: None,
         });
     }
+
+    /// Reloads the schema if any pages have been written since it was
+    /// loaded, which may have been done through another connection
+    /// sharing the same pager.
+    pub fn refresh_schema(&mut self) -> Result<()> {
+        let generation = self.pager.read().generation();
+        if generation != self.schema_generation {
+            self.schema = Schema::load(self.pager.clone())?;
+            self.schema_generation = generation;
+        }
+        return Ok(());
+    }
+
+    /// Runs `f` as a statement that modifies the database. If it fails,
+    /// all of its changes are rolled back; otherwise they are written to
+    /// the file, along with the updated file header. Statements may be
+    /// nested, in which case nothing is written until the outermost one
+    /// finishes.
+    pub fn write_statement<T, F>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        if self.is_read_only() {
+            return Err(eyre!("attempt to write a readonly database"));
+        }
+        if self.pager.read().statement_depth() > 0 {
+            return self.run_write_statement(f);
+        }
+        // only one connection, in this process or another, can be
+        // writing to the file at a time
+        return self.with_shared_lock(|conn| {
+            conn.pager.write().lock(LockLevel::Reserved)?;
+            let result = conn.run_write_statement(f);
+            conn.pager.write().unlock(LockLevel::Shared)?;
+            return result;
+        });
+    }
+
+    fn run_write_statement<T, F>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        self.pager.write().begin_statement();
+        if let Some(log) = &mut self.replication_log {
+            log.begin_statement();
+        }
+        let schema_cookie = self.db_options.schema_cookie;
+        // the outermost statement can only keep its changes once it can
+        // write them, which waits for every reader to be done
+        let outermost = self.pager.read().statement_depth() == 1;
+        let result = f(self).and_then(|value| {
+            if outermost {
+                // a database in full auto-vacuum mode gives its free
+                // pages back as each transaction commits
+                let mut pager = self.pager.write();
+                if pager.is_auto_vacuum() && !self.db_options.incremental_vacuum {
+                    vacuum::free_pages(&mut pager, None)?;
+                }
+                pager.lock(LockLevel::Exclusive)?;
+            }
+            return Ok(value);
+        });
+        let value = match result {
+            Ok(value) => value,
+            Err(err) => {
+                if let Some(log) = &mut self.replication_log {
+                    log.rollback_statement();
+                }
+                self.pager.write().rollback_statement()?;
+                // nested statements may have loaded changes to the schema
+                self.refresh_schema()?;
+                return Err(err);
+            }
+        };
+        self.pager.write().commit_statement()?;
+        if self.db_options.schema_cookie != schema_cookie {
+            self.pager.write().schema_changed();
+        }
+        if let Some(log) = &mut self.replication_log {
+            log.commit_statement();
+        }
+        // an enclosing statement writes the changes once it is done
+        if self.pager.read().statement_depth() == 0 {
+            self.write_changes()?;
+        }
+        self.refresh_schema()?;
+        return Ok(value);
+    }
+
+    /// Runs `f` holding at least a SHARED lock on the database file, so
+    /// that no other process changes it meanwhile. If another process has
+    /// changed it since this connection last looked, the pages kept in
+    /// memory are dropped, and the header and schema are read again,
+    /// before `f` runs.
+    pub fn with_shared_lock<T, F>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        if self.pager.read().lock_level() != LockLevel::None {
+            return f(self);
+        }
+        self.pager.write().lock(LockLevel::Shared)?;
+        let result = self
+            .check_hot_journal()
+            .and_then(|_| self.check_file_changed())
+            .and_then(|_| f(self));
+        let unlocked = self.pager.write().unlock(LockLevel::None);
+        return result.and_then(|value| unlocked.map(|_| value));
+    }
+
+    /// Errors if SQLite left a rollback journal behind from a transaction
+    /// that was interrupted, as the database file may be half written.
+    /// The journal is only in use, rather than left behind, while another
+    /// connection holds RESERVED. SQLite rolls such a journal back the
+    /// next time it opens the database.
+    fn check_hot_journal(&self) -> Result<()> {
+        let filename = match &self.filename {
+            Some(filename) => filename,
+            None => return Ok(()),
+        };
+        let journal = std::fs::metadata(format!("{}-journal", filename));
+        if journal.map_or(true, |journal| journal.len() == 0) {
+            return Ok(());
+        }
+        if self.pager.read().is_reserved_elsewhere()? {
+            return Ok(());
+        }
+        if RollbackJournal::open(filename)?.is_some() {
+            return Err(eyre!(
+                "The database has a rollback journal left by an interrupted transaction; \
+                 open it with SQLite to roll the transaction back."
+            ));
+        }
+        return Ok(());
+    }
+
+    /// Reloads what is kept of the database if the change counter in the
+    /// file header is not the one the pager last read or wrote. The
+    /// header is read again if it has been changed by any connection,
+    /// including others sharing the pager.
+    fn check_file_changed(&mut self) -> Result<()> {
+        let header = match self.pager.read().read_file_header()? {
+            Some(header) => header,
+            None => return Ok(()),
+        };
+        if header.change_counter == self.db_options.change_counter {
+            return Ok(());
+        }
+        let mut pager = self.pager.write();
+        if pager.changed_since(&header) {
+            pager.reload(&header)?;
+            if header.schema_cookie != self.db_options.schema_cookie {
+                pager.schema_changed();
+            }
+        }
+        drop(pager);
+        self.db_options = header;
+        return self.refresh_schema();
+    }
+
+    /// Writes the changed pages and the file header, and sends any
+    /// changes waiting in the replication log.
+    fn write_changes(&mut self) -> Result<()> {
+        let mut pager = self.pager.write();
+        pager.lock(LockLevel::Exclusive)?;
+        pager.flush_all(&mut self.db_options)?;
+        drop(pager);
+        if let Some(log) = &mut self.replication_log {
+            log.flush()?;
+        }
+        return Ok(());
+    }
+
+    /// Whether the database cannot be written to, as for an image read in
+    /// place or a follower.
+    pub fn is_read_only(&self) -> bool {
+        return self.pager.read().is_read_only() || self.follower.is_some();
+    }
+
+    /// Records a change for the replication log, if there is one. The
+    /// change is sent once the statement making it commits, or at once
+    /// if it is made outside of a statement.
+    pub fn record_change<F>(&mut self, change: F) -> Result<()>
+    where
+        F: FnOnce() -> Change,
+    {
+        if let Some(log) = &mut self.replication_log {
+            log.record(change());
+            if self.pager.read().statement_depth() == 0 {
+                log.flush()?;
+            }
+        }
+        return Ok(());
+    }
+
+    /// Copies the whole database, in the format of a database file, for
+    /// sending elsewhere or storing without a temporary file. The copy
+    /// can be opened with `deserialize`, or written to a file and opened
+    /// by SQLite.
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        return self.pager.read().serialize();
+    }
+
+    pub fn table(&self, name: &str) -> Result<Table> {
+        let schema = self
+            .schema
+            .table(name)
+            .ok_or_else(|| eyre!("no such table: {}", name))?;
+        return Ok(Table::new(schema.clone(), self.pager.clone()));
+    }
+
+    pub fn prepare(&self, sql: &str) -> Result<Statement> {
+        return Statement::prepare_with_limits(sql, &self.limits());
+    }
+
+    /// The limits the database and the statements run on it are checked
+    /// against.
+    pub fn limits(&self) -> Limits {
+        return *self.pager.read().limits();
+    }
+
+    /// Changes the limits the database and the statements run on it are
+    /// checked against. They are kept by the pager, so they apply to
+    /// every connection sharing its cache.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.pager.write().set_limits(limits);
+    }
+
+    /// Seeds the generator behind random(), randomblob() and the row IDs
+    /// picked at random, which is otherwise seeded from the clock, so
+    /// that the statements run afterwards give the same results every
+    /// time.
+    pub fn set_random_seed(&mut self, seed: u64) {
+        *self.rng.borrow_mut() = SampleRng::new(seed);
+    }
+
+    /// Adds a collating sequence, which SQL run on this connection can
+    /// then name wherever it names a built-in one. Registering a name
+    /// again replaces its function for statements run from then on. The
+    /// built-in sequences cannot be replaced.
+    pub fn register_collation<F>(&mut self, name: &str, function: F) -> Result<()>
+    where
+        F: CollatingFunction + 'static,
+    {
+        return self.collations.register(name, Arc::new(function));
+    }
+
+    pub fn execute(&mut self, sql: &str) -> Result<QueryResult> {
+        return self.with_shared_lock(|conn| {
+            conn.refresh_schema()?;
+            let stmt = conn.prepare(sql)?;
+            return stmt.execute(conn);
+        });
+    }
+
+    /// Runs a script of statements separated by semicolons, in order,
+    /// stopping at the first one that fails. Any results are discarded.
+    pub fn execute_batch(&mut self, sql: &str) -> Result<()> {
+        let statements = parser::split_statements(sql)?;
+        return self.execute_statements(&statements);
+    }
+
+    /// Runs statements in order, stopping at the first one that fails.
+    /// Runs of INSERTs into the same table are written together (see
+    /// `insert::execute_inserts`), which makes loading many rows much
+    /// faster than running each INSERT on its own.
+    pub fn execute_statements(&mut self, statements: &[&str]) -> Result<()> {
+        return self.with_shared_lock(|conn| {
+            let rng = conn.rng.clone();
+            return functions::with_rng(rng, || conn.run_statements(statements));
+        });
+    }
+
+    fn run_statements(&mut self, statements: &[&str]) -> Result<()> {
+        let mut run: Vec<Insert> = Vec::new();
+        for sql in statements {
+            self.refresh_schema()?;
+            let stmt = self.prepare(sql)?;
+            if let Stmt::Insert(insert) = &stmt.stmt {
+                // a subquery could read rows written earlier in the run
+                let mut subqueries = Vec::new();
+                match &insert.source {
+                    InsertSource::Values(rows) => {
+                        for expr in rows.iter().flatten() {
+                            exec::subqueries(expr, &mut subqueries);
+                        }
+                    }
+                    InsertSource::Select(select) => subqueries.push((select.as_ref(), false)),
+                }
+                if stmt.parameter_count() == 0 && subqueries.is_empty() {
+                    #[cfg(feature = "compat")]
+                    if let Some(compat) = self.compat.as_mut() {
+                        compat.check(&stmt.stmt)?;
+                    }
+                    if run
+                        .first()
+                        .is_some_and(|first| !first.table.eq_ignore_ascii_case(&insert.table))
+                    {
+                        insert::execute_inserts(self, &run)?;
+                        run.clear();
+                    }
+                    run.push(insert.clone());
+                    continue;
+                }
+            }
+            insert::execute_inserts(self, &run)?;
+            run.clear();
+            stmt.execute(self)?;
+        }
+        insert::execute_inserts(self, &run)?;
+        return Ok(());
+    }
+
+    /// Restores a database from SQL text, such as the output of
+    /// `dump::dump`. See `dump::restore`.
+    pub fn restore_dump(&mut self, reader: &mut dyn Read) -> Result<()> {
+        return dump::restore(self, reader);
+    }
+}
+
+impl Drop for Connection {
+    /// Writes pages changed outside of a statement (such as through the
+    /// B-tree directly), which would otherwise be lost. Changes made by
+    /// statements are written as each one commits.
+    fn drop(&mut self) {
+        let pending = {
+            let pager = self.pager.read();
+            pager.statement_depth() == 0 && pager.num_dirty() > 0
+        };
+        if pending {
+            // errors can't be reported from here
+            let _ = self.write_changes();
+            let _ = self.pager.write().unlock(LockLevel::None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datatypes::Value;
+
+    #[test]
+    fn serialize_round_trip() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        assert!(conn.serialize().unwrap().is_empty());
+        conn.execute("CREATE TABLE t(a, b)").unwrap();
+        for i in 1..=200 {
+            conn.execute(&format!(
+                "INSERT INTO t VALUES ({}, '{}')",
+                i,
+                "x".repeat(50)
+            ))
+            .unwrap();
+        }
+        let image = conn.serialize().unwrap();
+        assert_eq!(image, std::fs::read(file.path()).unwrap());
+
+        // changes to a deserialized database are made to its image only
+        let mut copy = Connection::deserialize(image).unwrap();
+        copy.execute("DELETE FROM t WHERE a > 100").unwrap();
+        let count = |conn: &mut Connection| conn.execute("SELECT count(*) FROM t").unwrap().rows;
+        assert_eq!(count(&mut copy), [[Value::Int64(100)]]);
+        assert_eq!(count(&mut conn), [[Value::Int64(200)]]);
+
+        let copied = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(copied.path(), copy.serialize().unwrap()).unwrap();
+        let mut reopened = Connection::new(copied.path().to_str().unwrap()).unwrap();
+        assert_eq!(count(&mut reopened), [[Value::Int64(100)]]);
+
+        let mut empty = Connection::deserialize(Vec::new()).unwrap();
+        empty.execute("CREATE TABLE u(x)").unwrap();
+        assert_eq!(empty.serialize().unwrap().len(), 2 * 4096);
+        assert!(Connection::deserialize(vec![1; 50]).is_err());
+
+        // an image read in place cannot be changed
+        let image: &'static [u8] = Box::leak(conn.serialize().unwrap().into_boxed_slice());
+        let mut embedded = Connection::open_bytes(image).unwrap();
+        assert_eq!(count(&mut embedded), [[Value::Int64(200)]]);
+        let err = embedded.execute("DELETE FROM t").unwrap_err();
+        assert_eq!(err.to_string(), "attempt to write a readonly database");
+        assert_eq!(count(&mut embedded), [[Value::Int64(200)]]);
+    }
+
+    #[test]
+    fn header_follows_file_growth() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        conn.execute("CREATE TABLE t(a)").unwrap();
+        let header = |conn: &Connection| {
+            let bytes = std::fs::read(file.path()).unwrap();
+            let header = DbOptions::deserialize(&bytes).unwrap();
+            assert_eq!(header.version_valid_for, header.change_counter);
+            assert_eq!(header.num_pages as usize * 4096, bytes.len());
+            assert_eq!(header.change_counter, conn.db_options.change_counter);
+            return header;
+        };
+        let before = header(&conn);
+
+        let row = format!("('{}')", "x".repeat(10000));
+        let rows = vec![row; 20].join(", ");
+        conn.execute(&format!("INSERT INTO t VALUES {}", rows))
+            .unwrap();
+        let grown = header(&conn);
+        assert!(grown.num_pages > before.num_pages + 40);
+        assert_eq!(grown.change_counter, before.change_counter + 1);
+
+        // freed pages stay in the file, on the freelist
+        conn.execute("DELETE FROM t").unwrap();
+        let freed = header(&conn);
+        assert_eq!(freed.num_pages, grown.num_pages);
+        assert!(freed.num_freelist > 40);
+    }
+
+    #[test]
+    fn flush_changed_pages() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let path = file.path().to_str().unwrap();
+        let mut conn = Connection::new(path).unwrap();
+        conn.execute("CREATE TABLE t(a)").unwrap();
+        conn.execute("INSERT INTO t VALUES (1), (2), (3)").unwrap();
+        let count = |path: &str| {
+            let mut conn = Connection::new(path).unwrap();
+            return conn.execute("SELECT count(*) FROM t").unwrap().rows;
+        };
+
+        // pages changed outside of a statement are written when asked
+        let table = conn.table("t").unwrap();
+        assert!(table.btree.delete(2).unwrap());
+        assert!(conn.pager.read().is_dirty(table.btree.root_page));
+        assert_eq!(count(path), [[Value::Int64(3)]]);
+        conn.pager
+            .write()
+            .flush_page(table.btree.root_page, &conn.db_options)
+            .unwrap();
+        assert!(!conn.pager.read().is_dirty(table.btree.root_page));
+        assert_eq!(count(path), [[Value::Int64(2)]]);
+
+        // or when the connection is closed
+        assert!(table.btree.delete(3).unwrap());
+        drop(table);
+        drop(conn);
+        assert_eq!(count(path), [[Value::Int64(1)]]);
+    }
+
+    #[test]
+    fn follower() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut leader = Connection::new(file.path().to_str().unwrap()).unwrap();
+        leader.execute("CREATE TABLE t(a)").unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let copy_path = dir.path().join("copy.db");
+        let copy_path = copy_path.to_str().unwrap();
+        std::fs::copy(file.path(), copy_path).unwrap();
+
+        let log = tempfile::NamedTempFile::new().unwrap();
+        let sink = replication::WriteSink {
+            writer: log.reopen().unwrap(),
+        };
+        leader.replication_log = Some(ReplicationLog::new(Box::new(sink), 0));
+        leader.execute("INSERT INTO t VALUES (1), (2)").unwrap();
+        leader.execute("CREATE TABLE u(b)").unwrap();
+
+        let mut follower = Connection::open_follower(copy_path, 0).unwrap();
+        let err = follower.execute("INSERT INTO t VALUES (3)").unwrap_err();
+        assert_eq!(err.to_string(), "attempt to write a readonly database");
+        let mut other = Connection::new(copy_path).unwrap();
+        assert!(other.execute("INSERT INTO t VALUES (3)").is_err());
+        drop(other);
+        let mut reader = log.reopen().unwrap();
+        assert_eq!(follower.ingest_stream(&mut reader).unwrap(), 2);
+        assert_eq!(follower.follower_sequence(), Some(2));
+
+        // later changes are picked up from where the stream left off
+        leader.execute("INSERT INTO u VALUES ('x')").unwrap();
+        assert_eq!(follower.ingest_stream(&mut reader).unwrap(), 1);
+        let query = "SELECT * FROM t, u";
+        assert_eq!(
+            follower.execute(query).unwrap(),
+            leader.execute(query).unwrap()
+        );
+        let empty = ChangeSet {
+            sequence: 0,
+            changes: Vec::new(),
+        };
+        assert!(leader.ingest(&empty).is_err());
+
+        // a follower opened again carries on from where it stopped, even
+        // if it stopped before it could save that it applied the last one
+        let change_counter = follower.db_options.change_counter;
+        drop(follower);
+        replication::save_progress(copy_path, 2, change_counter - 1).unwrap();
+        let mut follower = Connection::open_follower(copy_path, 0).unwrap();
+        assert_eq!(follower.follower_sequence(), Some(3));
+        let mut reader = log.reopen().unwrap();
+        assert_eq!(follower.ingest_stream(&mut reader).unwrap(), 0);
+        assert_eq!(
+            follower.execute(query).unwrap(),
+            leader.execute(query).unwrap()
+        );
+    }
+
+    #[test]
+    fn limits() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        conn.execute("CREATE TABLE t(a, b)").unwrap();
+        for i in 1..=100 {
+            conn.execute(&format!(
+                "INSERT INTO t VALUES ({}, '{}')",
+                i,
+                "x".repeat(100)
+            ))
+            .unwrap();
+        }
+        let mut limits = Limits {
+            max_sql_length: 50,
+            max_expr_depth: 3,
+            ..conn.limits()
+        };
+        conn.set_limits(limits);
+
+        let err = conn
+            .execute(&format!("SELECT '{}'", "y".repeat(50)))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "statement too long");
+        // `a + a + 1` is a tree of height 3
+        assert!(conn.execute("SELECT a + a + 1 FROM t").is_ok());
+        assert!(conn.execute("SELECT abs(-a) FROM t").is_ok());
+        for sql in &[
+            "SELECT a + a + a + 1 FROM t",
+            "SELECT abs(-(a)) FROM t",
+            "SELECT abs(-(-a)) FROM t",
+        ] {
+            let err = conn.execute(sql).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "Expression tree is too large (maximum depth 3)"
+            );
+        }
+
+        // the table no longer fits on its root page
+        limits.max_btree_depth = 1;
+        conn.set_limits(limits);
+        assert!(conn.execute("SELECT count(*) FROM t").is_err());
+        assert!(conn.execute("SELECT * FROM t").is_err());
+        assert!(conn.execute("INSERT INTO t VALUES (0, 'z')").is_err());
+
+        // the page count limit cannot be set below the current size
+        limits = Limits::default();
+        limits.max_page_count = 1;
+        conn.set_limits(limits);
+        let num_pages = conn.pager.read().num_pages;
+        assert_eq!(conn.limits().max_page_count, num_pages);
+        let err = conn.execute("CREATE TABLE u(a)").unwrap_err();
+        assert_eq!(err.to_string(), "database or disk is full");
+
+        limits.max_record_size = 50;
+        conn.set_limits(limits);
+        let err = conn.execute("SELECT * FROM t").unwrap_err();
+        assert_eq!(err.to_string(), "string or blob too big");
+    }
 }
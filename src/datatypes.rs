@@ -29,30 +29,29 @@ impl VarInt {
         return (Self(varint), bytes_read);
     }
 
-    // based off: https://docs.rs/sqlite_varint/0.1.2/src/sqlite_varint/lib.rs.html
+    /// The canonical encoding of the varint, the shortest one that holds
+    /// it: seven bits to each of up to eight bytes, most significant
+    /// first, with the high bit set on every byte but the last. A value
+    /// that needs more than 56 bits, as every negative value does, takes
+    /// nine bytes, the last of which holds eight bits.
     pub fn serialize(&self) -> Vec<u8> {
-        let mut result = std::collections::VecDeque::new();
-        let mut shifted_input = self.0;
-
-        if self.0 as u64 > 0x00ff_ffff_ffff_ffff {
-            // we first push the entire last byte
-            result.push_front((shifted_input & 0b1111_1111) as u8);
-            shifted_input >>= 8;
+        let value = self.0 as u64;
+        if value > 0x00ff_ffff_ffff_ffff {
+            let mut result: Vec<u8> = (0..8)
+                .rev()
+                .map(|i| ((value >> (8 + 7 * i)) & 0x7f) as u8 | 0x80)
+                .collect();
+            result.push(value as u8);
+            return result;
         }
-        for _ in 0..8 {
-            result.push_front((shifted_input & 0b0111_1111) as u8);
-
-            shifted_input >>= 7;
-            if result.len() > 1 {
-                let p = result.front_mut().unwrap();
-                *p |= 0b1000_0000;
-            }
-            if shifted_input == 0 {
-                // we reached the last one in case we don't use all 9 bytes.
-                break;
-            }
+        let mut result = vec![(value & 0x7f) as u8];
+        let mut rest = value >> 7;
+        while rest != 0 {
+            result.push((rest & 0x7f) as u8 | 0x80);
+            rest >>= 7;
         }
-        return result.into_iter().collect();
+        result.reverse();
+        return result;
     }
 }
 
@@ -76,7 +75,7 @@ pub enum DataType {
 
 impl DataType {
     pub fn from_varint(value: VarInt) -> Result<Self> {
-        let non_neg = value.0.try_into()?;
+        let non_neg: usize = value.0.try_into()?;
         Ok(match non_neg {
             0 => Self::Null(0),
             1 => Self::Int8(1),
@@ -90,7 +89,7 @@ impl DataType {
             9 => Self::Integer1(0),
             10 => Self::Internal10,
             11 => Self::Internal11,
-            x if x % 2 == 0 => Self::Blob((x - 12) / 2),
+            x if x.is_multiple_of(2) => Self::Blob((x - 12) / 2),
             x => Self::String((x - 13) / 2),
         })
     }
@@ -160,15 +159,11 @@ impl Value {
             DataType::Int16(_) => Self::Int16(i16::from_be_bytes(
                 value.try_into().expect("Slice with incorrect length"),
             )),
-            DataType::Int24(_) => Self::Int24(i32::from_be_bytes(
-                value.try_into().expect("Slice with incorrect length"),
-            )),
+            DataType::Int24(_) => Self::Int24(be_int(value) as i32),
             DataType::Int32(_) => Self::Int32(i32::from_be_bytes(
                 value.try_into().expect("Slice with incorrect length"),
             )),
-            DataType::Int48(_) => Self::Int48(i64::from_be_bytes(
-                value.try_into().expect("Slice with incorrect length"),
-            )),
+            DataType::Int48(_) => Self::Int48(be_int(value)),
             DataType::Int64(_) => Self::Int64(i64::from_be_bytes(
                 value.try_into().expect("Slice with incorrect length"),
             )),
@@ -190,24 +185,60 @@ impl Value {
             Self::Int16(v) => Some(*v as i64),
             Self::Int24(v) => Some(*v as i64),
             Self::Int32(v) => Some(*v as i64),
-            Self::Int48(v) => Some(*v as i64),
-            Self::Int64(v) => Some(*v as i64),
+            Self::Int48(v) => Some(*v),
+            Self::Int64(v) => Some(*v),
             Self::Integer0 => Some(0),
             Self::Integer1 => Some(1),
             _ => None,
         };
     }
 
+    /// Creates an integer value, using the smallest representation that
+    /// holds it, as SQLite does when writing records.
+    pub fn from_int(value: i64) -> Self {
+        return match value {
+            0 => Self::Integer0,
+            1 => Self::Integer1,
+            v if v >= i8::MIN as i64 && v <= i8::MAX as i64 => Self::Int8(v as i8),
+            v if v >= i16::MIN as i64 && v <= i16::MAX as i64 => Self::Int16(v as i16),
+            v if (-(1 << 23)..(1 << 23)).contains(&v) => Self::Int24(v as i32),
+            v if v >= i32::MIN as i64 && v <= i32::MAX as i64 => Self::Int32(v as i32),
+            v if (-(1 << 47)..(1 << 47)).contains(&v) => Self::Int48(v),
+            v => Self::Int64(v),
+        };
+    }
+
+    /// The serial type under which this value is stored in a record.
+    pub fn data_type(&self) -> DataType {
+        return match self {
+            Self::Null => DataType::Null(0),
+            Self::Int8(_) => DataType::Int8(1),
+            Self::Int16(_) => DataType::Int16(2),
+            Self::Int24(_) => DataType::Int24(3),
+            Self::Int32(_) => DataType::Int32(4),
+            Self::Int48(_) => DataType::Int48(6),
+            Self::Int64(_) => DataType::Int64(8),
+            Self::Float(_) => DataType::Float(8),
+            Self::Integer0 => DataType::Integer0(0),
+            Self::Integer1 => DataType::Integer1(0),
+            Self::Internal(_) => DataType::Internal10,
+            Self::Blob(v) => DataType::Blob(v.len()),
+            Self::String(v) => DataType::String(v.len()),
+        };
+    }
+
+    /// The bytes of the value in the body of a record, which are
+    /// big-endian for numbers.
     pub fn serialize(&self) -> Vec<u8> {
         let output: Vec<u8> = match self {
             Self::Null => vec![],
-            Self::Int8(v) => vec![*v as u8],
-            Self::Int16(v) => vec![*v as u8],
-            Self::Int24(v) => vec![*v as u8],
-            Self::Int32(v) => vec![*v as u8],
-            Self::Int48(v) => vec![*v as u8],
-            Self::Int64(v) => vec![*v as u8],
-            Self::Float(v) => vec![*v as u8],
+            Self::Int8(v) => v.to_be_bytes().to_vec(),
+            Self::Int16(v) => v.to_be_bytes().to_vec(),
+            Self::Int24(v) => v.to_be_bytes()[1..].to_vec(),
+            Self::Int32(v) => v.to_be_bytes().to_vec(),
+            Self::Int48(v) => v.to_be_bytes()[2..].to_vec(),
+            Self::Int64(v) => v.to_be_bytes().to_vec(),
+            Self::Float(v) => v.to_be_bytes().to_vec(),
             Self::Integer0 => vec![],
             Self::Integer1 => vec![],
             Self::Internal(v) => v.clone(),
@@ -218,134 +249,26 @@ impl Value {
     }
 }
 
+/// Reads a big-endian two's complement integer of any width up to 8
+/// bytes, sign-extending it to 64 bits. SQLite stores 24-bit and 48-bit
+/// integers, which have no native Rust type.
+fn be_int(value: &[u8]) -> i64 {
+    let mut output: i64 = if value.first().is_some_and(|b| b & 0x80 != 0) {
+        -1
+    } else {
+        0
+    };
+    for byte in value {
+        output = (output << 8) | *byte as i64;
+    }
+    return output;
+}
+
 impl PartialEq for Value {
+    /// Values are equal if they sort the same (see `cmp_sqlite`), so an
+    /// integer equals a float only if it has exactly the same value.
     fn eq(&self, other: &Self) -> bool {
-        return match self {
-            Value::Null => match other {
-                Value::Null => true,
-                _ => false,
-            },
-            Value::Int8(s) => match other {
-                Value::Int8(o) => *s == *o,
-                Value::Int16(o) => *s as i16 == *o,
-                Value::Int24(o) => *s as i32 == *o,
-                Value::Int32(o) => *s as i32 == *o,
-                Value::Int48(o) => *s as i64 == *o,
-                Value::Int64(o) => *s as i64 == *o,
-                Value::Float(o) => *s as f64 == *o,
-                Value::Integer0 => *s == 0,
-                Value::Integer1 => *s == 1,
-                _ => false,
-            },
-            Value::Int16(s) => match other {
-                Value::Int8(o) => *s == *o as i16,
-                Value::Int16(o) => *s == *o,
-                Value::Int24(o) => *s as i32 == *o,
-                Value::Int32(o) => *s as i32 == *o,
-                Value::Int48(o) => *s as i64 == *o,
-                Value::Int64(o) => *s as i64 == *o,
-                Value::Float(o) => *s as f64 == *o,
-                Value::Integer0 => *s == 0,
-                Value::Integer1 => *s == 1,
-                _ => false,
-            },
-            Value::Int24(s) => match other {
-                Value::Int8(o) => *s == *o as i32,
-                Value::Int16(o) => *s == *o as i32,
-                Value::Int24(o) => *s == *o,
-                Value::Int32(o) => *s == *o,
-                Value::Int48(o) => *s as i64 == *o,
-                Value::Int64(o) => *s as i64 == *o,
-                Value::Float(o) => *s as f64 == *o,
-                Value::Integer0 => *s == 0,
-                Value::Integer1 => *s == 1,
-                _ => false,
-            },
-            Value::Int32(s) => match other {
-                Value::Int8(o) => *s == *o as i32,
-                Value::Int16(o) => *s == *o as i32,
-                Value::Int24(o) => *s == *o,
-                Value::Int32(o) => *s == *o,
-                Value::Int48(o) => *s as i64 == *o,
-                Value::Int64(o) => *s as i64 == *o,
-                Value::Float(o) => *s as f64 == *o,
-                Value::Integer0 => *s == 0,
-                Value::Integer1 => *s == 1,
-                _ => false,
-            },
-            Value::Int48(s) => match other {
-                Value::Int8(o) => *s == *o as i64,
-                Value::Int16(o) => *s == *o as i64,
-                Value::Int24(o) => *s == *o as i64,
-                Value::Int32(o) => *s == *o as i64,
-                Value::Int48(o) => *s == *o,
-                Value::Int64(o) => *s == *o,
-                Value::Float(o) => *s as f64 == *o,
-                Value::Integer0 => *s == 0,
-                Value::Integer1 => *s == 1,
-                _ => false,
-            },
-            Value::Int64(s) => match other {
-                Value::Int8(o) => *s == *o as i64,
-                Value::Int16(o) => *s == *o as i64,
-                Value::Int24(o) => *s == *o as i64,
-                Value::Int32(o) => *s == *o as i64,
-                Value::Int48(o) => *s == *o,
-                Value::Int64(o) => *s == *o,
-                Value::Float(o) => *s as f64 == *o,
-                Value::Integer0 => *s == 0,
-                Value::Integer1 => *s == 1,
-                _ => false,
-            },
-            Value::Float(s) => match other {
-                Value::Int8(o) => *s == *o as f64,
-                Value::Int16(o) => *s == *o as f64,
-                Value::Int24(o) => *s == *o as f64,
-                Value::Int32(o) => *s == *o as f64,
-                Value::Int48(o) => *s == *o as f64,
-                Value::Int64(o) => *s == *o as f64,
-                Value::Float(o) => *s == *o,
-                Value::Integer0 => *s == 0.0,
-                Value::Integer1 => *s == 1.0,
-                _ => false,
-            },
-            Value::Integer0 => match other {
-                Value::Int8(o) => *o == 0,
-                Value::Int16(o) => *o == 0,
-                Value::Int24(o) => *o == 0,
-                Value::Int32(o) => *o == 0,
-                Value::Int48(o) => *o == 0,
-                Value::Int64(o) => *o == 0,
-                Value::Float(o) => *o == 0.0,
-                Value::Integer0 => true,
-                Value::Integer1 => false,
-                _ => false,
-            },
-            Value::Integer1 => match other {
-                Value::Int8(o) => *o == 1,
-                Value::Int16(o) => *o == 1,
-                Value::Int24(o) => *o == 1,
-                Value::Int32(o) => *o == 1,
-                Value::Int48(o) => *o == 1,
-                Value::Int64(o) => *o == 1,
-                Value::Float(o) => *o == 1.0,
-                Value::Integer0 => false,
-                Value::Integer1 => true,
-                _ => false,
-            },
-            Value::Internal(s) => match other {
-                Value::Internal(o) => *s == *o,
-                _ => false,
-            },
-            Value::Blob(s) => match other {
-                Value::Blob(o) => *s == *o,
-                _ => false,
-            },
-            Value::String(s) => match other {
-                Value::String(o) => *s == *o,
-                _ => false,
-            },
-        };
+        return self.cmp_sqlite(other) == Ordering::Equal;
     }
 }
 
@@ -358,158 +281,91 @@ impl PartialOrd for Value {
     ///    collating function.
     /// 4. BLOB values (even serial types 12 and larger) sort last and
     ///    in the order determined by memcmp().
+    ///
+    /// Internal values are not ordered, and neither is a NaN against
+    /// any other number; `cmp_sqlite` orders every value.
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        return match self {
-            Value::Null => match other {
-                Value::Null => Some(Ordering::Equal),
-                Value::Internal(_) => None,
-                _ => Some(Ordering::Less),
-            },
-            Value::Int8(s) => match other {
-                Value::Null => Some(Ordering::Greater),
-                Value::Int8(o) => s.partial_cmp(o),
-                Value::Int16(o) => (*s as i16).partial_cmp(o),
-                Value::Int24(o) => (*s as i32).partial_cmp(o),
-                Value::Int32(o) => (*s as i32).partial_cmp(o),
-                Value::Int48(o) => (*s as i64).partial_cmp(o),
-                Value::Int64(o) => (*s as i64).partial_cmp(o),
-                Value::Float(o) => (*s as f64).partial_cmp(o),
-                Value::Integer0 => s.partial_cmp(&0),
-                Value::Integer1 => s.partial_cmp(&1),
-                Value::Internal(_) => None,
-                _ => Some(Ordering::Less),
-            },
-            Value::Int16(s) => match other {
-                Value::Null => Some(Ordering::Greater),
-                Value::Int8(o) => s.partial_cmp(&(*o as i16)),
-                Value::Int16(o) => s.partial_cmp(o),
-                Value::Int24(o) => (*s as i32).partial_cmp(o),
-                Value::Int32(o) => (*s as i32).partial_cmp(o),
-                Value::Int48(o) => (*s as i64).partial_cmp(o),
-                Value::Int64(o) => (*s as i64).partial_cmp(o),
-                Value::Float(o) => (*s as f64).partial_cmp(o),
-                Value::Integer0 => s.partial_cmp(&0),
-                Value::Integer1 => s.partial_cmp(&1),
-                Value::Internal(_) => None,
-                _ => Some(Ordering::Less),
-            },
-            Value::Int24(s) => match other {
-                Value::Null => Some(Ordering::Greater),
-                Value::Int8(o) => s.partial_cmp(&(*o as i32)),
-                Value::Int16(o) => s.partial_cmp(&(*o as i32)),
-                Value::Int24(o) => s.partial_cmp(o),
-                Value::Int32(o) => s.partial_cmp(o),
-                Value::Int48(o) => (*s as i64).partial_cmp(o),
-                Value::Int64(o) => (*s as i64).partial_cmp(o),
-                Value::Float(o) => (*s as f64).partial_cmp(o),
-                Value::Integer0 => s.partial_cmp(&0),
-                Value::Integer1 => s.partial_cmp(&1),
-                Value::Internal(_) => None,
-                _ => Some(Ordering::Less),
-            },
-            Value::Int32(s) => match other {
-                Value::Null => Some(Ordering::Greater),
-                Value::Int8(o) => s.partial_cmp(&(*o as i32)),
-                Value::Int16(o) => s.partial_cmp(&(*o as i32)),
-                Value::Int24(o) => s.partial_cmp(o),
-                Value::Int32(o) => s.partial_cmp(o),
-                Value::Int48(o) => (*s as i64).partial_cmp(o),
-                Value::Int64(o) => (*s as i64).partial_cmp(o),
-                Value::Float(o) => (*s as f64).partial_cmp(o),
-                Value::Integer0 => s.partial_cmp(&0),
-                Value::Integer1 => s.partial_cmp(&1),
-                Value::Internal(_) => None,
-                _ => Some(Ordering::Less),
-            },
-            Value::Int48(s) => match other {
-                Value::Null => Some(Ordering::Greater),
-                Value::Int8(o) => s.partial_cmp(&(*o as i64)),
-                Value::Int16(o) => s.partial_cmp(&(*o as i64)),
-                Value::Int24(o) => s.partial_cmp(&(*o as i64)),
-                Value::Int32(o) => s.partial_cmp(&(*o as i64)),
-                Value::Int48(o) => s.partial_cmp(o),
-                Value::Int64(o) => s.partial_cmp(o),
-                Value::Float(o) => (*s as f64).partial_cmp(o),
-                Value::Integer0 => s.partial_cmp(&0),
-                Value::Integer1 => s.partial_cmp(&1),
-                Value::Internal(_) => None,
-                _ => Some(Ordering::Less),
-            },
-            Value::Int64(s) => match other {
-                Value::Null => Some(Ordering::Greater),
-                Value::Int8(o) => s.partial_cmp(&(*o as i64)),
-                Value::Int16(o) => s.partial_cmp(&(*o as i64)),
-                Value::Int24(o) => s.partial_cmp(&(*o as i64)),
-                Value::Int32(o) => s.partial_cmp(&(*o as i64)),
-                Value::Int48(o) => s.partial_cmp(o),
-                Value::Int64(o) => s.partial_cmp(o),
-                Value::Float(o) => (*s as f64).partial_cmp(o),
-                Value::Integer0 => s.partial_cmp(&0),
-                Value::Integer1 => s.partial_cmp(&1),
-                Value::Internal(_) => None,
-                _ => Some(Ordering::Less),
-            },
-            Value::Float(s) => match other {
-                Value::Null => Some(Ordering::Greater),
-                Value::Int8(o) => s.partial_cmp(&(*o as f64)),
-                Value::Int16(o) => s.partial_cmp(&(*o as f64)),
-                Value::Int24(o) => s.partial_cmp(&(*o as f64)),
-                Value::Int32(o) => s.partial_cmp(&(*o as f64)),
-                Value::Int48(o) => s.partial_cmp(&(*o as f64)),
-                Value::Int64(o) => s.partial_cmp(&(*o as f64)),
-                Value::Float(o) => (*s as f64).partial_cmp(&(*o as f64)),
-                Value::Integer0 => s.partial_cmp(&0.0),
-                Value::Integer1 => s.partial_cmp(&1.0),
-                Value::Internal(_) => None,
-                _ => Some(Ordering::Less),
-            },
-            Value::Integer0 => match other {
-                Value::Null => Some(Ordering::Greater),
-                Value::Int8(o) => 0.partial_cmp(o),
-                Value::Int16(o) => 0.partial_cmp(o),
-                Value::Int24(o) => 0.partial_cmp(o),
-                Value::Int32(o) => 0.partial_cmp(o),
-                Value::Int48(o) => 0.partial_cmp(o),
-                Value::Int64(o) => 0.partial_cmp(o),
-                Value::Float(o) => 0.0.partial_cmp(o),
-                Value::Integer0 => Some(Ordering::Equal),
-                Value::Integer1 => Some(Ordering::Less),
-                Value::Internal(_) => None,
-                _ => Some(Ordering::Less),
-            },
-            Value::Integer1 => match other {
-                Value::Null => Some(Ordering::Greater),
-                Value::Int8(o) => 1.partial_cmp(o),
-                Value::Int16(o) => 1.partial_cmp(o),
-                Value::Int24(o) => 1.partial_cmp(o),
-                Value::Int32(o) => 1.partial_cmp(o),
-                Value::Int48(o) => 1.partial_cmp(o),
-                Value::Int64(o) => 1.partial_cmp(o),
-                Value::Float(o) => 1.0.partial_cmp(o),
-                Value::Integer0 => Some(Ordering::Greater),
-                Value::Integer1 => Some(Ordering::Equal),
-                Value::Internal(_) => None,
-                _ => Some(Ordering::Less),
-            },
-            Value::String(s) => match other {
-                Value::String(o) => s.partial_cmp(o),
-                Value::Blob(_) => Some(Ordering::Less),
-                Value::Internal(_) => None,
-                _ => Some(Ordering::Greater),
-            },
-            Value::Blob(s) => match other {
-                Value::Blob(o) => s.partial_cmp(o),
-                Value::Internal(_) => None,
-                _ => Some(Ordering::Greater),
+        let classes = (self.sort_class(), other.sort_class());
+        let is_nan = |v: &Value| matches!(v, Value::Float(f) if f.is_nan());
+        if classes.0 == SORT_INTERNAL || classes.1 == SORT_INTERNAL {
+            return None;
+        }
+        if classes == (SORT_NUMERIC, SORT_NUMERIC) && is_nan(self) != is_nan(other) {
+            return None;
+        }
+        return Some(self.cmp_sqlite(other));
+    }
+}
+
+const SORT_NULL: u8 = 0;
+const SORT_NUMERIC: u8 = 1;
+const SORT_TEXT: u8 = 2;
+const SORT_BLOB: u8 = 3;
+const SORT_INTERNAL: u8 = 4;
+
+impl Value {
+    /// Compares two values in the order SQLite sorts them (see
+    /// `partial_cmp`), with text compared byte by byte. Integers and
+    /// floats are compared exactly, as SQLite does, rather than by
+    /// converting the integer to a float. The order is total: a NaN,
+    /// which SQLite never stores, sorts before every other number, and
+    /// internal values sort after blobs, by their bytes.
+    pub fn cmp_sqlite(&self, other: &Self) -> Ordering {
+        let classes = (self.sort_class(), other.sort_class());
+        if classes.0 != classes.1 {
+            return classes.0.cmp(&classes.1);
+        }
+        return match (self, other) {
+            (Value::String(s), Value::String(o)) => s.as_bytes().cmp(o.as_bytes()),
+            (Value::Blob(s), Value::Blob(o)) | (Value::Internal(s), Value::Internal(o)) => s.cmp(o),
+            (Value::Float(s), Value::Float(o)) => match (s.is_nan(), o.is_nan()) {
+                (true, true) => Ordering::Equal,
+                (true, false) => Ordering::Less,
+                (false, true) => Ordering::Greater,
+                (false, false) => s.partial_cmp(o).unwrap(),
             },
-            Value::Internal(_) => None,
+            (Value::Float(s), _) => cmp_int_float(other.get_int_val().unwrap(), *s).reverse(),
+            (_, Value::Float(o)) => cmp_int_float(self.get_int_val().unwrap(), *o),
+            // two integers, or two NULLs
+            _ => self.get_int_val().cmp(&other.get_int_val()),
+        };
+    }
+
+    fn sort_class(&self) -> u8 {
+        return match self {
+            Value::Null => SORT_NULL,
+            Value::String(_) => SORT_TEXT,
+            Value::Blob(_) => SORT_BLOB,
+            Value::Internal(_) => SORT_INTERNAL,
+            _ => SORT_NUMERIC,
         };
     }
 }
 
+/// Compares an integer with a float exactly, as SQLite's
+/// `sqlite3IntFloatCompare` does: a float beyond the range of an integer
+/// is compared as such, and otherwise the float's integer part is
+/// compared first, then its fraction.
+fn cmp_int_float(i: i64, f: f64) -> Ordering {
+    if f.is_nan() {
+        return Ordering::Greater;
+    }
+    if f < -9_223_372_036_854_775_808.0 {
+        return Ordering::Greater;
+    }
+    if f >= 9_223_372_036_854_775_808.0 {
+        return Ordering::Less;
+    }
+    return match i.cmp(&(f as i64)) {
+        Ordering::Equal => (i as f64).partial_cmp(&f).unwrap(),
+        ordering => ordering,
+    };
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::btree::Record;
 
     #[test]
     fn varint_1byte() {
@@ -621,6 +477,83 @@ mod tests {
         assert_eq!(varint.1, 9);
     }
 
+    #[test]
+    fn varint_round_trip() {
+        // the largest value of each length, and the smallest of the next
+        let mut values = vec![0, -1, i64::MIN, i64::MAX];
+        for bits in (7..=56).step_by(7) {
+            values.push((1 << bits) - 1);
+            values.push(1 << bits);
+        }
+        let mut rng = crate::btree::SampleRng::new(2818);
+        for _ in 0..10_000 {
+            // random values of every width
+            let value = rng.next_u64() >> (rng.next_u64() % 64);
+            values.push(value as i64);
+        }
+        for value in values {
+            let bytes = VarInt::new(value).serialize();
+            let size = match value as u64 {
+                v if v > 0x00ff_ffff_ffff_ffff => 9,
+                v => (64 - v.leading_zeros() as usize).max(1).div_ceil(7),
+            };
+            assert_eq!(bytes.len(), size, "{}", value);
+            let mut padded = bytes.clone();
+            padded.extend([0xff; 9]);
+            assert_eq!(VarInt::deserialize(&padded), (VarInt(value), size));
+        }
+        assert_eq!(VarInt::new(1 << 56).serialize()[8], 0);
+        assert_eq!(VarInt::new(-1).serialize(), vec![0xff; 9]);
+    }
+
+    #[test]
+    fn total_order() {
+        let values = vec![
+            Value::Null,
+            Value::Float(f64::NAN),
+            Value::Float(f64::NEG_INFINITY),
+            Value::Int64(i64::MIN),
+            Value::Float(-1.5),
+            Value::Integer0,
+            Value::Float(0.5),
+            Value::Integer1,
+            Value::Int64((1 << 53) - 1),
+            Value::Float((1u64 << 53) as f64),
+            Value::Int64((1 << 53) + 1),
+            Value::Int64(i64::MAX),
+            Value::Float(9_223_372_036_854_775_808.0),
+            Value::String("A".to_string()),
+            Value::String("a".to_string()),
+            Value::Blob(vec![]),
+            Value::Blob(vec![0]),
+            Value::Internal(vec![0]),
+        ];
+        for (i, a) in values.iter().enumerate() {
+            for (j, b) in values.iter().enumerate() {
+                assert_eq!(a.cmp_sqlite(b), i.cmp(&j), "{:?} and {:?}", a, b);
+            }
+        }
+        // integers are compared exactly with floats, not as floats
+        let max = Value::Int64(i64::MAX);
+        assert_eq!(
+            max.cmp_sqlite(&Value::Float(i64::MAX as f64)),
+            Ordering::Less
+        );
+        assert!(Value::Float(f64::NAN)
+            .partial_cmp(&Value::Integer0)
+            .is_none());
+        assert!(Value::Internal(vec![0]).partial_cmp(&Value::Null).is_none());
+        // and are only equal if they sort the same
+        assert_ne!(max, Value::Float(i64::MAX as f64));
+        assert_eq!(Value::Int8(3), Value::Float(3.0));
+        assert_eq!(Value::Float(f64::NAN), Value::Float(f64::NAN));
+
+        let short = Record::from_values(vec![Value::Int64(1)]);
+        let long = Record::from_values(vec![Value::Int64(1), Value::Null]);
+        assert_eq!(short.cmp_sqlite(&long), Ordering::Less);
+        assert_eq!(long.cmp_sqlite(&short), Ordering::Greater);
+    }
+
     #[test]
     fn value_order() {
         let val_null = Value::Null;
@@ -0,0 +1,1292 @@
+use eyre::{eyre, Result};
+use std::cmp::Ordering;
+
+use crate::ast::{
+    AlterAction, AlterTable, ColumnConstraint, CreateIndex, CreateTable, CreateView, Expr,
+    IndexedColumn, TableConstraint,
+};
+use crate::btree::{Btree, BtreeKind, PageType, Record};
+use crate::connection::Connection;
+use crate::datatypes::Value;
+use crate::exec::{self, Collation, Collations, TableRow};
+use crate::parser;
+use crate::schema::{IndexSchema, TableSchema, SCHEMA_ROOT_PAGE};
+use crate::sorter::{SortOrder, Sorter};
+use crate::table::Table;
+use crate::tokenizer::{self, Token, TokenKind};
+use crate::vacuum;
+
+/// Creates a table: allocates its root page, along with the root pages of
+/// the indexes that enforce its UNIQUE and PRIMARY KEY constraints, and
+/// records them all in sqlite_schema.
+pub fn create_table(conn: &mut Connection, table: &CreateTable, sql: &str) -> Result<()> {
+    if conn.schema.table(&table.name).is_some() || conn.schema.virtual_table(&table.name).is_some()
+    {
+        if table.if_not_exists {
+            return Ok(());
+        }
+        return Err(eyre!("table {} already exists", table.name));
+    }
+    if conn.schema.view(&table.name).is_some() {
+        return Err(eyre!("view {} already exists", table.name));
+    }
+    if conn.schema.index(&table.name).is_some() {
+        return Err(eyre!("there is already an index named {}", table.name));
+    }
+    if table.name.to_lowercase().starts_with("sqlite_") {
+        return Err(eyre!(
+            "object name reserved for internal use: {}",
+            table.name
+        ));
+    }
+    if table.without_rowid {
+        return Err(eyre!("WITHOUT ROWID tables are not supported yet."));
+    }
+    for (i, col) in table.columns.iter().enumerate() {
+        if table.columns[..i]
+            .iter()
+            .any(|c| c.name.eq_ignore_ascii_case(&col.name))
+        {
+            return Err(eyre!("duplicate column name: {}", col.name));
+        }
+    }
+    let autoincrement = table.columns.iter().position(|col| {
+        col.constraints.iter().any(|c| {
+            matches!(
+                c,
+                ColumnConstraint::PrimaryKey {
+                    autoincrement: true,
+                    ..
+                }
+            )
+        })
+    });
+    if autoincrement.is_some() && autoincrement != row_id_alias(table) {
+        return Err(eyre!(
+            "AUTOINCREMENT is only allowed on an INTEGER PRIMARY KEY"
+        ));
+    }
+    let indexes = unique_keys(table)?;
+    let sql = schema_sql(sql, "TABLE")?;
+
+    return conn.write_statement(|conn| {
+        let schema_tree = Btree::new(
+            "sqlite_schema".to_string(),
+            "sqlite_schema".to_string(),
+            SCHEMA_ROOT_PAGE,
+            conn.pager.clone(),
+        );
+        let mut row_id = schema_tree.max_row_id()?;
+
+        let root_page = vacuum::allocate_root(&mut conn.pager.write(), PageType::TableLeaf)?;
+        row_id += 1;
+        schema_tree.insert(
+            row_id,
+            &schema_record("table", &table.name, &table.name, root_page, Some(&sql)),
+        )?;
+        for i in 0..indexes.len() {
+            let name = format!("sqlite_autoindex_{}_{}", table.name, i + 1);
+            let root_page = vacuum::allocate_root(&mut conn.pager.write(), PageType::IndexLeaf)?;
+            row_id += 1;
+            schema_tree.insert(
+                row_id,
+                &schema_record("index", &name, &table.name, root_page, None),
+            )?;
+        }
+        // the first AUTOINCREMENT table brings the table that keeps the
+        // largest row ID each has used
+        if autoincrement.is_some() && conn.schema.table("sqlite_sequence").is_none() {
+            let root_page = vacuum::allocate_root(&mut conn.pager.write(), PageType::TableLeaf)?;
+            row_id += 1;
+            schema_tree.insert(
+                row_id,
+                &schema_record(
+                    "table",
+                    "sqlite_sequence",
+                    "sqlite_sequence",
+                    root_page,
+                    Some("CREATE TABLE sqlite_sequence(name,seq)"),
+                ),
+            )?;
+        }
+
+        conn.db_options.schema_cookie = conn.db_options.schema_cookie.wrapping_add(1);
+        return Ok(());
+    });
+}
+
+/// Creates an index: allocates its root page, fills it with an entry for
+/// each row of the table (the indexed values, followed by the row ID),
+/// and records it in sqlite_schema.
+pub fn create_index(conn: &mut Connection, index: &CreateIndex, sql: &str) -> Result<()> {
+    if conn.schema.index(&index.name).is_some() {
+        if index.if_not_exists {
+            return Ok(());
+        }
+        return Err(eyre!("index {} already exists", index.name));
+    }
+    if conn.schema.table(&index.name).is_some()
+        || conn.schema.virtual_table(&index.name).is_some()
+        || conn.schema.view(&index.name).is_some()
+    {
+        return Err(eyre!("there is already a table named {}", index.name));
+    }
+    if index.name.to_lowercase().starts_with("sqlite_") {
+        return Err(eyre!(
+            "object name reserved for internal use: {}",
+            index.name
+        ));
+    }
+    if conn.schema.virtual_table(&index.table).is_some() {
+        return Err(eyre!("virtual tables may not be indexed"));
+    }
+    if conn.schema.view(&index.table).is_some() {
+        return Err(eyre!("views may not be indexed"));
+    }
+    let table = conn.table(&index.table)?;
+    if table.schema.name == "sqlite_schema" {
+        return Err(eyre!("table sqlite_master may not be indexed"));
+    }
+
+    let columns = index_key_columns(&table.schema, &index.columns, &conn.collations)?;
+    // the entries are sorted by their key, then by row ID, spilling to
+    // disk if they do not fit in the connection's sort memory
+    let mut order = index_key_order(&columns);
+    order.push(SortOrder {
+        descending: false,
+        collation: Collation::Binary,
+    });
+    let mut sorter = Sorter::new(order, conn.sort_memory);
+    for_each_index_entry(
+        &table,
+        &columns,
+        index.where_clause.as_ref(),
+        &conn.collations,
+        &mut |entry| sorter.push(entry, Vec::new()),
+    )?;
+    let mut sorted = sorter.finish()?;
+    let kind = if index.unique {
+        "UNIQUE INDEX"
+    } else {
+        "INDEX"
+    };
+    let sql = schema_sql(sql, kind)?;
+
+    return conn.write_statement(|conn| {
+        let schema_tree = Btree::new(
+            "sqlite_schema".to_string(),
+            "sqlite_schema".to_string(),
+            SCHEMA_ROOT_PAGE,
+            conn.pager.clone(),
+        );
+        let row_id = schema_tree.max_row_id()? + 1;
+        let index_tree = Btree::create(
+            index.name.clone(),
+            table.schema.name.clone(),
+            BtreeKind::Index,
+            conn.pager.clone(),
+        )?;
+        let root_page = index_tree.root_page;
+        let mut previous: Option<Vec<Value>> = None;
+        let mut entries = std::iter::from_fn(|| {
+            let entry = match sorted.next_entry()? {
+                Ok((entry, _)) => entry,
+                Err(err) => return Some(Err(err)),
+            };
+            if index.unique {
+                // NULLs are distinct from each other, so do not conflict
+                let key = &entry[..columns.len()];
+                if let Some(previous) = &previous {
+                    if compare_index_keys(&columns, previous, key) == Ordering::Equal
+                        && !key.contains(&Value::Null)
+                    {
+                        return Some(Err(unique_error(&table.schema, &columns)));
+                    }
+                }
+                previous = Some(key.to_vec());
+            }
+            return Some(Ok(Record::from_values(entry)));
+        });
+        index_tree.build_index(&mut entries)?;
+        schema_tree.insert(
+            row_id,
+            &schema_record(
+                "index",
+                &index.name,
+                &table.schema.name,
+                root_page,
+                Some(&sql),
+            ),
+        )?;
+
+        conn.db_options.schema_cookie = conn.db_options.schema_cookie.wrapping_add(1);
+        return Ok(());
+    });
+}
+
+/// Creates a view, which is only recorded in sqlite_schema. As in SQLite,
+/// the SELECT is not checked until the view is queried.
+pub fn create_view(conn: &mut Connection, view: &CreateView, sql: &str) -> Result<()> {
+    if conn.schema.view(&view.name).is_some() {
+        if view.if_not_exists {
+            return Ok(());
+        }
+        return Err(eyre!("view {} already exists", view.name));
+    }
+    if conn.schema.table(&view.name).is_some() || conn.schema.virtual_table(&view.name).is_some() {
+        return Err(eyre!("table {} already exists", view.name));
+    }
+    if conn.schema.index(&view.name).is_some() {
+        return Err(eyre!("there is already an index named {}", view.name));
+    }
+    if view.name.to_lowercase().starts_with("sqlite_") {
+        return Err(eyre!(
+            "object name reserved for internal use: {}",
+            view.name
+        ));
+    }
+    let sql = schema_sql(sql, "VIEW")?;
+
+    return conn.write_statement(|conn| {
+        let schema_tree = Btree::new(
+            "sqlite_schema".to_string(),
+            "sqlite_schema".to_string(),
+            SCHEMA_ROOT_PAGE,
+            conn.pager.clone(),
+        );
+        let row_id = schema_tree.max_row_id()? + 1;
+        schema_tree.insert(
+            row_id,
+            &schema_record("view", &view.name, &view.name, 0, Some(&sql)),
+        )?;
+        conn.db_options.schema_cookie = conn.db_options.schema_cookie.wrapping_add(1);
+        return Ok(());
+    });
+}
+
+/// An index's key columns: each one's position in the table's records,
+/// collating sequence, and whether it is descending.
+pub type IndexKeyColumns = Vec<(usize, Collation, bool)>;
+
+/// Finds the position of each indexed column in the table's records,
+/// along with how its values are ordered in the index: by collating
+/// sequence, and whether descending.
+pub fn index_key_columns(
+    table: &TableSchema,
+    columns: &[IndexedColumn],
+    collations: &Collations,
+) -> Result<IndexKeyColumns> {
+    let mut output = Vec::with_capacity(columns.len());
+    for col in columns {
+        let idx = table
+            .column_index(&col.name)
+            .ok_or_else(|| eyre!("no such column: {}", col.name))?;
+        let collation = match &col.collation {
+            Some(name) => Collation::from_name(name, collations)?,
+            None => table
+                .column_collation(idx)
+                .map_or(Ok(Collation::Binary), |name| {
+                    Collation::from_name(name, collations)
+                })?,
+        };
+        output.push((idx, collation, col.descending));
+    }
+    return Ok(output);
+}
+
+/// How the values of each of an index's key columns are ordered, for
+/// sorting its entries or seeking them (see `Btree::with_key_order`).
+pub fn index_key_order(columns: &[(usize, Collation, bool)]) -> Vec<SortOrder> {
+    return columns
+        .iter()
+        .map(|(_, collation, descending)| SortOrder {
+            descending: *descending,
+            collation: collation.clone(),
+        })
+        .collect();
+}
+
+/// Produces the entries an index on the given columns holds for the rows
+/// of a table (the indexed values, followed by the row ID), in row ID
+/// order. Only rows satisfying `where_clause` are indexed.
+pub fn index_entries(
+    table: &Table,
+    columns: &[(usize, Collation, bool)],
+    where_clause: Option<&Expr>,
+    collations: &Collations,
+) -> Result<Vec<Vec<Value>>> {
+    let mut entries = Vec::new();
+    for_each_index_entry(table, columns, where_clause, collations, &mut |entry| {
+        entries.push(entry);
+        return Ok(());
+    })?;
+    return Ok(entries);
+}
+
+/// Calls `f` with each of the entries `index_entries` produces, without
+/// holding them all in memory.
+pub fn for_each_index_entry(
+    table: &Table,
+    columns: &[(usize, Collation, bool)],
+    where_clause: Option<&Expr>,
+    collations: &Collations,
+    f: &mut dyn FnMut(Vec<Value>) -> Result<()>,
+) -> Result<()> {
+    if let Some(where_clause) = where_clause {
+        exec::check_table_expr(&table.schema, where_clause)?;
+    }
+    for row in table.btree.scan() {
+        let (row_id, record) = row?;
+        let record = table.schema.with_row_id(row_id.0, record);
+        if let Some(where_clause) = where_clause {
+            let row = TableRow {
+                table: &table.schema,
+                record: &record,
+                collations: collations,
+            };
+            if exec::truth_value(&exec::eval_expr(where_clause, &row)?) != Some(true) {
+                continue;
+            }
+        }
+        f(index_entry(&table.schema, columns, row_id.0, &record)?)?;
+    }
+    return Ok(());
+}
+
+/// The entry an index on the given columns holds for a row: the indexed
+/// values, followed by the row ID.
+pub fn index_entry(
+    table: &TableSchema,
+    columns: &[(usize, Collation, bool)],
+    row_id: i64,
+    record: &Record,
+) -> Result<Vec<Value>> {
+    let row_id_alias = row_id_alias(&table.definition);
+    let mut values = Vec::with_capacity(columns.len() + 1);
+    for (idx, _, _) in columns {
+        // the row ID is stored in place of a column that is an alias for
+        // it
+        values.push(if Some(*idx) == row_id_alias {
+            Value::from_int(row_id)
+        } else {
+            table.column_value(record, *idx)?
+        });
+    }
+    values.push(Value::from_int(row_id));
+    return Ok(values);
+}
+
+/// Compares two index entries in index order: by the indexed values,
+/// each with its column's collating sequence and direction, then by row
+/// ID.
+pub fn compare_index_entries(
+    columns: &[(usize, Collation, bool)],
+    a: &[Value],
+    b: &[Value],
+) -> Ordering {
+    let ordering = compare_index_keys(columns, a, b);
+    if ordering != Ordering::Equal {
+        return ordering;
+    }
+    return exec::compare(&a[columns.len()], &b[columns.len()]);
+}
+
+/// Compares the keys of two index entries (their indexed values, leaving
+/// out the row ID) in index order.
+pub fn compare_index_keys(
+    columns: &[(usize, Collation, bool)],
+    a: &[Value],
+    b: &[Value],
+) -> Ordering {
+    for (i, (_, collation, descending)) in columns.iter().enumerate() {
+        let ordering = collation.compare(&a[i], &b[i]);
+        if ordering != Ordering::Equal {
+            return if *descending {
+                ordering.reverse()
+            } else {
+                ordering
+            };
+        }
+    }
+    return Ordering::Equal;
+}
+
+/// The error for a row whose key is already in a unique index.
+fn unique_error(table: &TableSchema, columns: &[(usize, Collation, bool)]) -> eyre::Report {
+    let names: Vec<String> = columns
+        .iter()
+        .map(|(idx, _, _)| format!("{}.{}", table.name, table.columns()[*idx].name))
+        .collect();
+    return eyre!("UNIQUE constraint failed: {}", names.join(", "));
+}
+
+/// An index of a table, opened so that its entries can be kept up to
+/// date as rows are added to and removed from the table.
+pub struct TableIndex {
+    pub btree: Btree,
+    pub columns: IndexKeyColumns,
+    pub where_clause: Option<Expr>,
+    /// Whether no two entries may have the same key, as for a UNIQUE
+    /// index or one made for a UNIQUE or PRIMARY KEY constraint. Keys
+    /// holding a NULL never clash.
+    pub unique: bool,
+    /// The collating sequences the WHERE clause may name.
+    pub collations: Collations,
+}
+
+/// Opens every index of a table (see `TableIndex`).
+pub fn table_indexes(conn: &Connection, table: &TableSchema) -> Result<Vec<TableIndex>> {
+    let mut indexes = Vec::new();
+    for index in conn.schema.indexes_for_table(&table.name) {
+        let (columns, where_clause) = index_columns(table, index, &conn.collations)?;
+        let btree = Btree::new(
+            index.name.clone(),
+            index.table_name.clone(),
+            index.root_page,
+            conn.pager.clone(),
+        );
+        indexes.push(TableIndex {
+            btree: btree,
+            columns: columns,
+            where_clause: where_clause.cloned(),
+            unique: index.definition.as_ref().is_none_or(|def| def.unique),
+            collations: conn.collations.clone(),
+        });
+    }
+    return Ok(indexes);
+}
+
+impl TableIndex {
+    /// Tests whether the index has an entry for a row, which a partial
+    /// index only has if its WHERE clause is true of the row.
+    fn covers(&self, table: &TableSchema, row_id: i64, record: &Record) -> Result<bool> {
+        let where_clause = match &self.where_clause {
+            Some(where_clause) => where_clause,
+            None => return Ok(true),
+        };
+        let record = table.with_row_id(row_id, record.clone());
+        let row = TableRow {
+            table: table,
+            record: &record,
+            collations: &self.collations,
+        };
+        return Ok(exec::truth_value(&exec::eval_expr(where_clause, &row)?) == Some(true));
+    }
+
+    /// Adds the entry for a row that has been added to the table. Fails
+    /// if the index is unique and already has an entry with the same
+    /// key.
+    pub fn insert_row(&self, table: &TableSchema, row_id: i64, record: &Record) -> Result<()> {
+        if !self.covers(table, row_id, record)? {
+            return Ok(());
+        }
+        let entry = index_entry(table, &self.columns, row_id, record)?;
+        let key = &entry[..self.columns.len()];
+        if self.unique && !key.contains(&Value::Null) {
+            let mut cursor = self.btree.cursor();
+            let found =
+                cursor.seek_to(&|other| compare_index_keys(&self.columns, key, &other.values))?;
+            let clashes = found
+                && cursor.current().is_some_and(|other| {
+                    compare_index_keys(&self.columns, key, &other.values) == Ordering::Equal
+                });
+            if clashes {
+                return Err(unique_error(table, &self.columns));
+            }
+        }
+        return self
+            .btree
+            .index_insert(&Record::from_values(entry.clone()), &|other| {
+                compare_index_entries(&self.columns, &entry, &other.values)
+            });
+    }
+
+    /// Removes the entry for a row that is being removed from the table.
+    pub fn delete_row(&self, table: &TableSchema, row_id: i64, record: &Record) -> Result<()> {
+        if !self.covers(table, row_id, record)? {
+            return Ok(());
+        }
+        let entry = index_entry(table, &self.columns, row_id, record)?;
+        self.btree
+            .index_delete(&|other| compare_index_entries(&self.columns, &entry, &other.values))?;
+        return Ok(());
+    }
+}
+
+/// The key columns of an index, as given by `index_key_columns`, and its
+/// WHERE clause if it is a partial index.
+pub fn index_columns<'a>(
+    table: &TableSchema,
+    index: &'a IndexSchema,
+    collations: &Collations,
+) -> Result<(IndexKeyColumns, Option<&'a Expr>)> {
+    let (columns, where_clause) = match &index.definition {
+        Some(definition) => (definition.columns.clone(), definition.where_clause.as_ref()),
+        None => (autoindex_columns(table, &index.name)?, None),
+    };
+    return Ok((
+        index_key_columns(table, &columns, collations)?,
+        where_clause,
+    ));
+}
+
+/// The columns of an index created for a UNIQUE or PRIMARY KEY
+/// constraint, which has no SQL of its own. The constraint is found from
+/// the number at the end of the index's name.
+fn autoindex_columns(table: &TableSchema, name: &str) -> Result<Vec<IndexedColumn>> {
+    let keys = unique_keys(&table.definition)?;
+    let key = name
+        .rsplit('_')
+        .next()
+        .and_then(|n| n.parse::<usize>().ok())
+        .and_then(|n| keys.get(n.checked_sub(1)?))
+        .ok_or_else(|| eyre!("Malformed database schema ({})", name))?;
+    return Ok(key
+        .iter()
+        .map(|name| IndexedColumn {
+            name: name.clone(),
+            collation: None,
+            descending: false,
+        })
+        .collect());
+}
+
+/// Runs `ALTER TABLE ... RENAME COLUMN` or `ALTER TABLE ... DROP COLUMN`.
+pub fn alter_table(conn: &mut Connection, alter: &AlterTable) -> Result<()> {
+    if conn.schema.virtual_table(&alter.table).is_some() {
+        return Err(eyre!("virtual tables may not be altered"));
+    }
+    if conn.schema.view(&alter.table).is_some() {
+        return Err(match &alter.action {
+            AlterAction::RenameColumn { .. } => {
+                eyre!("cannot rename columns of view \"{}\"", alter.table)
+            }
+            AlterAction::DropColumn(_) => eyre!("cannot drop column from view \"{}\"", alter.table),
+        });
+    }
+    let table = conn.table(&alter.table)?;
+    if table.schema.name == "sqlite_schema" {
+        return Err(eyre!("table sqlite_master may not be altered"));
+    } else if table.schema.name.to_lowercase().starts_with("sqlite_") {
+        return Err(eyre!("table {} may not be altered", table.schema.name));
+    }
+    return match &alter.action {
+        AlterAction::RenameColumn { old, new } => rename_column(conn, &table, old, new),
+        AlterAction::DropColumn(name) => drop_column(conn, &table, name),
+    };
+}
+
+/// Renames a column, along with every reference to it in the SQL of the
+/// table and of its indexes.
+fn rename_column(conn: &mut Connection, table: &Table, old: &str, new: &str) -> Result<()> {
+    let idx = table
+        .schema
+        .column_index(old)
+        .ok_or_else(|| eyre!("no such column: \"{}\"", old))?;
+    if table
+        .schema
+        .column_index(new)
+        .is_some_and(|other| other != idx)
+    {
+        return Err(eyre!(
+            "error in table {} after rename: duplicate column name: {}",
+            table.schema.name,
+            new
+        ));
+    }
+
+    let mut changes = vec![(
+        table.schema.name.clone(),
+        rename_in_sql(&table.schema.sql, old, new, true)?,
+    )];
+    for index in conn.schema.indexes_for_table(&table.schema.name) {
+        if let Some(sql) = &index.sql {
+            changes.push((index.name.clone(), rename_in_sql(sql, old, new, false)?));
+        }
+    }
+    return conn.write_statement(|conn| {
+        for (name, sql) in &changes {
+            update_schema_sql(conn, name, sql)?;
+        }
+        conn.db_options.schema_cookie = conn.db_options.schema_cookie.wrapping_add(1);
+        return Ok(());
+    });
+}
+
+/// Drops a column, removing its definition from the table's SQL and its
+/// value from every row. Columns that are part of a key, that are
+/// indexed, or that other parts of the table definition refer to cannot
+/// be dropped.
+fn drop_column(conn: &mut Connection, table: &Table, name: &str) -> Result<()> {
+    let definition = &table.schema.definition;
+    let idx = table
+        .schema
+        .column_index(name)
+        .ok_or_else(|| eyre!("no such column: \"{}\"", name))?;
+    let col_name = &definition.columns[idx].name;
+    if definition.columns.len() == 1 {
+        return Err(eyre!(
+            "cannot drop column \"{}\": no other columns exist",
+            col_name
+        ));
+    }
+    let in_list = |cols: &[IndexedColumn]| cols.iter().any(|c| c.name.eq_ignore_ascii_case(name));
+    for constraint in &definition.columns[idx].constraints {
+        match constraint {
+            ColumnConstraint::PrimaryKey { .. } => {
+                return Err(eyre!("cannot drop PRIMARY KEY column: \"{}\"", col_name));
+            }
+            ColumnConstraint::Unique => {
+                return Err(eyre!("cannot drop UNIQUE column: \"{}\"", col_name));
+            }
+            _ => (),
+        }
+    }
+    for constraint in &definition.constraints {
+        match constraint {
+            TableConstraint::PrimaryKey(cols) if in_list(cols) => {
+                return Err(eyre!("cannot drop PRIMARY KEY column: \"{}\"", col_name));
+            }
+            TableConstraint::Unique(cols) if in_list(cols) => {
+                return Err(eyre!("cannot drop UNIQUE column: \"{}\"", col_name));
+            }
+            TableConstraint::ForeignKey { columns, .. }
+                if columns.iter().any(|c| c.eq_ignore_ascii_case(name)) =>
+            {
+                return Err(eyre!(
+                    "error in table {} after drop column: unknown column \"{}\" in foreign key definition",
+                    table.schema.name,
+                    name
+                ));
+            }
+            _ => (),
+        }
+    }
+
+    let sql = drop_from_sql(&table.schema.sql, idx)?;
+    // whatever is left must not refer to the column
+    let new_schema = TableSchema::new(table.schema.name.clone(), table.schema.root_page, sql)?;
+    let mut exprs = Vec::new();
+    for col in new_schema.columns() {
+        for constraint in &col.constraints {
+            match constraint {
+                ColumnConstraint::Check(expr) | ColumnConstraint::Generated { expr, .. } => {
+                    exprs.push(expr)
+                }
+                _ => (),
+            }
+        }
+    }
+    for constraint in &new_schema.definition.constraints {
+        if let TableConstraint::Check(expr) = constraint {
+            exprs.push(expr);
+        }
+    }
+    for expr in exprs {
+        exec::check_table_expr(&new_schema, expr).map_err(|err| {
+            eyre!(
+                "error in table {} after drop column: {}",
+                table.schema.name,
+                err
+            )
+        })?;
+    }
+    for index in conn.schema.indexes_for_table(&table.schema.name) {
+        let uses_column = match &index.definition {
+            Some(def) => {
+                in_list(&def.columns)
+                    || def
+                        .where_clause
+                        .as_ref()
+                        .is_some_and(|expr| exec::check_table_expr(&new_schema, expr).is_err())
+            }
+            None => false,
+        };
+        if uses_column {
+            return Err(eyre!(
+                "error in index {} after drop column: no such column: {}",
+                index.name,
+                name
+            ));
+        }
+    }
+
+    let mut rows = Vec::new();
+    for row in table.btree.scan() {
+        let (row_id, record) = row?;
+        let mut values = record.values;
+        if idx < values.len() {
+            values.remove(idx);
+        }
+        rows.push((row_id.0, Record::from_values(values)));
+    }
+    return conn.write_statement(|conn| {
+        for (row_id, record) in &rows {
+            table.btree.update(*row_id, record)?;
+        }
+        update_schema_sql(conn, &table.schema.name, &new_schema.sql)?;
+        conn.db_options.schema_cookie = conn.db_options.schema_cookie.wrapping_add(1);
+        return Ok(());
+    });
+}
+
+/// Replaces the SQL stored in sqlite_schema for a table or index.
+fn update_schema_sql(conn: &Connection, name: &str, sql: &str) -> Result<()> {
+    let schema_tree = Btree::new(
+        "sqlite_schema".to_string(),
+        "sqlite_schema".to_string(),
+        SCHEMA_ROOT_PAGE,
+        conn.pager.clone(),
+    );
+    for row in schema_tree.scan() {
+        let (row_id, record) = row?;
+        if record.values.get(1) != Some(&Value::String(name.to_string())) {
+            continue;
+        }
+        let mut values = record.values;
+        values[4] = Value::String(sql.to_string());
+        return schema_tree.update(row_id.0, &Record::from_values(values));
+    }
+    return Err(eyre!("no such table: {}", name));
+}
+
+/// The column definitions and table constraints of a CREATE TABLE
+/// statement, as ranges of token indexes, not including the commas
+/// between them.
+fn table_elements(tokens: &[Token]) -> Vec<(usize, usize)> {
+    let mut elements = Vec::new();
+    let mut depth = 0;
+    let mut start = None;
+    for (i, token) in tokens.iter().enumerate() {
+        match token.kind {
+            TokenKind::LeftParen => {
+                depth += 1;
+                if depth == 1 {
+                    start = Some(i + 1);
+                    continue;
+                }
+            }
+            TokenKind::RightParen => {
+                depth -= 1;
+                if depth == 0 {
+                    elements.extend(start.map(|start| (start, i)));
+                    break;
+                }
+            }
+            TokenKind::Comma if depth == 1 => {
+                elements.extend(start.map(|start| (start, i)));
+                start = Some(i + 1);
+            }
+            _ => (),
+        }
+    }
+    return elements;
+}
+
+/// Tests whether an element of a CREATE TABLE statement is a table
+/// constraint rather than a column definition.
+fn is_table_constraint(first: &Token) -> bool {
+    return ["CONSTRAINT", "PRIMARY", "UNIQUE", "CHECK", "FOREIGN"]
+        .iter()
+        .any(|kw| first.is_keyword(kw));
+}
+
+/// Tests whether a token names the given column. Function names,
+/// collating sequence names, and the table and columns of a foreign key
+/// are not column names, even if they look the same.
+fn names_column(tokens: &[Token], i: usize, column: &str) -> bool {
+    let name = match &tokens[i].kind {
+        TokenKind::Identifier(s) | TokenKind::QuotedIdentifier(s) => s,
+        _ => return false,
+    };
+    let prev = i.checked_sub(1).map(|p| &tokens[p]);
+    return name.eq_ignore_ascii_case(column)
+        && tokens[i + 1].kind != TokenKind::LeftParen
+        && !prev.is_some_and(|p| p.is_keyword("COLLATE") || p.is_keyword("REFERENCES"));
+}
+
+/// Renames a column in the SQL of a table (or, if not `is_table`, of an
+/// index on it).
+fn rename_in_sql(sql: &str, old: &str, new: &str, is_table: bool) -> Result<String> {
+    let tokens = tokenizer::tokenize(sql)?;
+    let body_start = tokens
+        .iter()
+        .position(|t| t.kind == TokenKind::LeftParen)
+        .ok_or_else(|| eyre!("Malformed schema SQL: {}", sql))?;
+    let mut ranges = Vec::new();
+    if is_table {
+        for (start, end) in table_elements(&tokens) {
+            let mut i = start;
+            if !is_table_constraint(&tokens[start]) {
+                // a column's name is followed by its type, which is never
+                // renamed
+                ranges.push((start, start + 1));
+                i += 1;
+                while i < end
+                    && matches!(tokens[i].kind, TokenKind::Identifier(_))
+                    && !COLUMN_CONSTRAINT_KEYWORDS
+                        .iter()
+                        .any(|kw| tokens[i].is_keyword(kw))
+                {
+                    i += 1;
+                }
+            }
+            ranges.push((i, end));
+        }
+    } else {
+        ranges.push((body_start + 1, tokens.len() - 1));
+    }
+
+    let mut renamed = Vec::new();
+    for (start, end) in ranges {
+        let mut i = start;
+        while i < end {
+            if tokens[i].is_keyword("REFERENCES") {
+                // skip over the parent table and its column list
+                i += 2;
+                if tokens[i].kind == TokenKind::LeftParen {
+                    while i < end && tokens[i].kind != TokenKind::RightParen {
+                        i += 1;
+                    }
+                }
+            } else if names_column(&tokens, i, old) {
+                renamed.push(i);
+            }
+            i += 1;
+        }
+    }
+
+    let mut output = sql.to_string();
+    for i in renamed.into_iter().rev() {
+        output.replace_range(tokens[i].offset..tokens[i].end, &parser::quote_name(new));
+    }
+    return Ok(output);
+}
+
+/// The keywords that start a column constraint, which end the column's
+/// type.
+const COLUMN_CONSTRAINT_KEYWORDS: &[&str] = &[
+    "CONSTRAINT",
+    "PRIMARY",
+    "NOT",
+    "NULL",
+    "UNIQUE",
+    "CHECK",
+    "DEFAULT",
+    "COLLATE",
+    "REFERENCES",
+    "GENERATED",
+    "AS",
+];
+
+/// Removes the definition of the column at `idx` from the SQL of a table.
+/// As in SQLite, the text up to the next element goes with it, or for
+/// the last element, the text back to the end of the previous one.
+fn drop_from_sql(sql: &str, idx: usize) -> Result<String> {
+    let tokens = tokenizer::tokenize(sql)?;
+    let elements = table_elements(&tokens);
+    let position = elements
+        .iter()
+        .enumerate()
+        .filter(|(_, (start, _))| !is_table_constraint(&tokens[*start]))
+        .nth(idx)
+        .map(|(position, _)| position)
+        .ok_or_else(|| eyre!("Malformed schema SQL: {}", sql))?;
+    let (start, end) = elements[position];
+    let range = match elements.get(position + 1) {
+        Some((next, _)) => tokens[start].offset..tokens[*next].offset,
+        None => {
+            let (_, prev_end) = elements[position - 1];
+            tokens[prev_end - 1].end..tokens[end - 1].end
+        }
+    };
+    let mut output = sql.to_string();
+    output.replace_range(range, "");
+    return Ok(output);
+}
+
+pub fn schema_record(
+    obj_type: &str,
+    name: &str,
+    table_name: &str,
+    root_page: usize,
+    sql: Option<&str>,
+) -> Record {
+    return Record::from_values(vec![
+        Value::String(obj_type.to_string()),
+        Value::String(name.to_string()),
+        Value::String(table_name.to_string()),
+        Value::Int64(root_page as i64),
+        match sql {
+            Some(sql) => Value::String(sql.to_string()),
+            None => Value::Null,
+        },
+    ]);
+}
+
+/// The column lists of the UNIQUE and PRIMARY KEY constraints of a table
+/// that need an index of their own, in the order SQLite numbers their
+/// `sqlite_autoindex_<table>_<N>` indexes: column constraints first, then
+/// table constraints. An INTEGER PRIMARY KEY is an alias for the row ID,
+/// and a constraint on the same columns as an earlier one shares its
+/// index.
+pub fn unique_keys(table: &CreateTable) -> Result<Vec<Vec<String>>> {
+    // each key is listed with whether it is a primary key that may be an
+    // alias for the row ID (INTEGER PRIMARY KEY DESC is not)
+    let mut keys: Vec<(Vec<String>, bool)> = Vec::new();
+    let mut num_primary_keys = 0;
+    for col in &table.columns {
+        for constraint in &col.constraints {
+            match constraint {
+                ColumnConstraint::PrimaryKey { descending, .. } => {
+                    keys.push((vec![col.name.clone()], !descending));
+                    num_primary_keys += 1;
+                }
+                ColumnConstraint::Unique => keys.push((vec![col.name.clone()], false)),
+                _ => (),
+            }
+        }
+    }
+    let names = |cols: &[IndexedColumn]| cols.iter().map(|c| c.name.clone()).collect();
+    for constraint in &table.constraints {
+        match constraint {
+            TableConstraint::PrimaryKey(cols) => {
+                keys.push((names(cols), true));
+                num_primary_keys += 1;
+            }
+            TableConstraint::Unique(cols) => keys.push((names(cols), false)),
+            _ => (),
+        }
+    }
+    if num_primary_keys > 1 {
+        return Err(eyre!(
+            "table \"{}\" has more than one primary key",
+            table.name
+        ));
+    }
+
+    let mut output: Vec<Vec<String>> = Vec::new();
+    for (key, may_alias_row_id) in keys {
+        if may_alias_row_id && key.len() == 1 && is_integer_column(table, &key[0]) {
+            continue;
+        }
+        let duplicate = output.iter().any(|other| {
+            other.len() == key.len()
+                && other
+                    .iter()
+                    .zip(key.iter())
+                    .all(|(a, b)| a.eq_ignore_ascii_case(b))
+        });
+        if !duplicate {
+            output.push(key);
+        }
+    }
+    return Ok(output);
+}
+
+/// Tests whether a column is declared with the type `INTEGER`, the only
+/// type for which a single-column primary key becomes the row ID.
+fn is_integer_column(table: &CreateTable, name: &str) -> bool {
+    return table.columns.iter().any(|c| {
+        c.name.eq_ignore_ascii_case(name)
+            && c.type_name
+                .as_deref()
+                .is_some_and(|t| t.eq_ignore_ascii_case("INTEGER"))
+    });
+}
+
+/// The column that is an alias for the row ID, if any: the column of an
+/// INTEGER PRIMARY KEY (other than INTEGER PRIMARY KEY DESC). Its value
+/// is stored as the row ID, with NULL in its place in the record.
+pub fn row_id_alias(table: &CreateTable) -> Option<usize> {
+    let mut key = None;
+    for col in &table.columns {
+        for constraint in &col.constraints {
+            if let ColumnConstraint::PrimaryKey { descending, .. } = constraint {
+                if *descending {
+                    return None;
+                }
+                key = Some(col.name.as_str());
+            }
+        }
+    }
+    for constraint in &table.constraints {
+        if let TableConstraint::PrimaryKey(cols) = constraint {
+            if cols.len() != 1 {
+                return None;
+            }
+            key = Some(cols[0].name.as_str());
+        }
+    }
+    let key = key?;
+    if !is_integer_column(table, key) {
+        return None;
+    }
+    return table
+        .columns
+        .iter()
+        .position(|c| c.name.eq_ignore_ascii_case(key));
+}
+
+/// Whether the table's INTEGER PRIMARY KEY is AUTOINCREMENT, in which
+/// case its row IDs are never reused, even after the rows that had them
+/// are deleted. The largest row ID it has had is kept in sqlite_sequence.
+pub fn is_autoincrement(table: &CreateTable) -> bool {
+    let alias = match row_id_alias(table) {
+        Some(idx) => &table.columns[idx],
+        None => return false,
+    };
+    return alias.constraints.iter().any(|c| {
+        matches!(
+            c,
+            ColumnConstraint::PrimaryKey {
+                autoincrement: true,
+                ..
+            }
+        )
+    });
+}
+
+/// The SQL to store in sqlite_schema for a CREATE statement. As in
+/// SQLite, this is the text from the name of the object to the end of
+/// the statement, prefixed with `CREATE <kind> ` (so that, e.g., `IF NOT
+/// EXISTS` and a `main.` qualifier are dropped).
+fn schema_sql(sql: &str, kind: &str) -> Result<String> {
+    let tokens = tokenizer::tokenize(sql)?;
+    let keyword = kind.rsplit(' ').next().unwrap();
+    let mut pos = tokens
+        .iter()
+        .position(|t| t.is_keyword(keyword))
+        .ok_or_else(|| eyre!("Not a CREATE {} statement: {}", kind, sql))?
+        + 1;
+    if tokens[pos].is_keyword("IF") {
+        pos += 3;
+    }
+    if tokens.len() > pos + 1 && tokens[pos + 1].kind == TokenKind::Dot {
+        pos += 2;
+    }
+    let end = tokens
+        .iter()
+        .find(|t| t.kind == TokenKind::Semicolon || t.kind == TokenKind::Eof)
+        .map(|t| t.offset)
+        .unwrap_or(sql.len());
+    return Ok(format!(
+        "CREATE {} {}",
+        kind,
+        sql[tokens[pos].offset..end].trim_end()
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Stmt;
+    use crate::parser;
+    use std::ops::Bound;
+
+    #[test]
+    fn autoindexes_and_schema_sql() {
+        let sql = "CREATE TABLE IF NOT EXISTS t (
+            id INTEGER PRIMARY KEY,
+            a TEXT UNIQUE,
+            b,
+            UNIQUE (a),
+            UNIQUE (a, b)
+        );";
+        let table = match parser::parse(sql).unwrap() {
+            Stmt::CreateTable(t) => t,
+            _ => panic!("Expected CREATE TABLE"),
+        };
+        let keys = unique_keys(&table).unwrap();
+        assert_eq!(keys, vec![vec!["a"], vec!["a", "b"]]);
+
+        let stored = schema_sql(sql, "TABLE").unwrap();
+        assert!(stored.starts_with("CREATE TABLE t (\n"));
+        assert!(stored.ends_with(')'));
+        assert_eq!(
+            schema_sql("CREATE INDEX IF NOT EXISTS main.[i] ON t(a)", "INDEX").unwrap(),
+            "CREATE INDEX [i] ON t(a)"
+        );
+    }
+
+    #[test]
+    fn alter_table_sql() {
+        let sql = "CREATE TABLE t(a text, b CHECK (lower(b) > a) COLLATE a, \
+                   FOREIGN KEY (a) REFERENCES p(a))";
+        assert_eq!(
+            rename_in_sql(sql, "A", "select", true).unwrap(),
+            "CREATE TABLE t(\"select\" text, b CHECK (lower(b) > \"select\") COLLATE a, \
+             FOREIGN KEY (\"select\") REFERENCES p(a))"
+        );
+        assert_eq!(
+            rename_in_sql("CREATE INDEX a ON t(a, b) WHERE a > 1", "a", "c", false).unwrap(),
+            "CREATE INDEX a ON t(c, b) WHERE c > 1"
+        );
+
+        let sql = "CREATE TABLE t(a /* first */ int, b, c, PRIMARY KEY (b))";
+        assert_eq!(
+            drop_from_sql(sql, 0).unwrap(),
+            "CREATE TABLE t(b, c, PRIMARY KEY (b))"
+        );
+        assert_eq!(
+            drop_from_sql("CREATE TABLE t(a, b, c)", 2).unwrap(),
+            "CREATE TABLE t(a, b)"
+        );
+    }
+
+    #[test]
+    fn create_view() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        conn.execute("CREATE TABLE t(a, b TEXT COLLATE NOCASE)")
+            .unwrap();
+        conn.execute("INSERT INTO t VALUES (1, 'x'), (2, 'Y'), (3, 'z')")
+            .unwrap();
+        conn.execute("CREATE VIEW v(n, s) AS SELECT a * 2, b FROM t WHERE a > 1")
+            .unwrap();
+        conn.execute("CREATE VIEW IF NOT EXISTS v AS SELECT 1")
+            .unwrap();
+        conn.execute("CREATE VIEW w AS SELECT * FROM v UNION SELECT 0, 'w'")
+            .unwrap();
+        assert_eq!(
+            conn.schema.view("v").unwrap().sql,
+            "CREATE VIEW v(n, s) AS SELECT a * 2, b FROM t WHERE a > 1"
+        );
+
+        // s keeps the collating sequence of b, so 'Y' sorts after 'w'
+        let result = conn.execute("SELECT w.n FROM w ORDER BY s DESC").unwrap();
+        assert_eq!(result.columns, ["n"]);
+        assert_eq!(
+            result.rows,
+            [[Value::Int64(6)], [Value::Int64(4)], [Value::Int64(0)]]
+        );
+
+        // the query is only checked when the view is used
+        conn.execute("CREATE VIEW bad AS SELECT c FROM t").unwrap();
+        assert!(conn.execute("SELECT * FROM bad").is_err());
+        assert!(conn.execute("CREATE VIEW t AS SELECT 1").is_err());
+        assert!(conn.execute("CREATE TABLE v(a)").is_err());
+        assert!(conn.execute("INSERT INTO v VALUES (1, 2)").is_err());
+        assert!(conn.execute("DELETE FROM v").is_err());
+        assert!(conn.execute("CREATE INDEX v_n ON v(n)").is_err());
+    }
+
+    #[test]
+    fn create_index_builds_tree() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        conn.execute("CREATE TABLE t(a, b)").unwrap();
+        let table = conn.table("t").unwrap();
+        conn.write_statement(|_| {
+            for id in 1..=1000 {
+                let values = vec![Value::Int64(id % 100), Value::String(format!("row {}", id))];
+                table.btree.insert(id, &Record::from_values(values))?;
+            }
+            return Ok(());
+        })
+        .unwrap();
+
+        // little enough sort memory that the entries are spilled to disk
+        // and merged
+        conn.sort_memory = 4096;
+        conn.execute("CREATE INDEX t_ab ON t(a, b)").unwrap();
+        assert!(conn.execute("CREATE INDEX t_ab ON t(b)").is_err());
+        let num_pages = conn.pager.read().num_pages;
+        assert!(conn.execute("CREATE UNIQUE INDEX t_a ON t(a)").is_err());
+        assert!(conn.schema.index("t_a").is_none());
+        assert_eq!(conn.pager.read().num_pages, num_pages);
+        assert!(crate::integrity::check_indexes(&conn).unwrap().is_empty());
+
+        let schema = conn.schema.index("t_ab").unwrap();
+        let index = Btree::new(
+            schema.name.clone(),
+            schema.table_name.clone(),
+            schema.root_page,
+            conn.pager.clone(),
+        );
+        assert!(conn
+            .pager
+            .write()
+            .get_page(schema.root_page)
+            .unwrap()
+            .is_interior());
+        let entries = index.index_lookup(&[]).unwrap();
+        assert_eq!(entries.len(), 1000);
+        assert_eq!(entries[0].values[0].get_int_val(), Some(0));
+        assert_eq!(entries[999].values[0].get_int_val(), Some(99));
+        let matches = index.index_lookup(&[Value::Int64(50)]).unwrap();
+        assert_eq!(matches.len(), 10);
+        assert!(matches.iter().all(|m| m.values[1] != Value::Null));
+        let key = |a: i64| Record::from_values(vec![Value::Int64(a)]);
+        let range: Vec<Record> = index
+            .index_range(Bound::Excluded(key(10)), Bound::Included(key(12)))
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(range.len(), 20);
+        assert_eq!(range[0].values[0].get_int_val(), Some(11));
+    }
+
+    #[test]
+    fn create_index_from_sorted_runs() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        conn.execute("PRAGMA page_size = 1024").unwrap();
+        conn.execute("CREATE TABLE t(a, b)").unwrap();
+        let names = ["pear", "Apple", "apple", "FIG", "plum"];
+        let rows: Vec<(i64, i64, String)> = (1..=3000)
+            .map(|id| {
+                // some keys continue on overflow pages
+                let pad = if id % 97 == 0 { 3000 } else { id as usize % 40 };
+                let b = format!("{}{}", names[id as usize % 5], "x".repeat(pad));
+                return (id, (id * 7919) % 50, b);
+            })
+            .collect();
+        let values: Vec<String> = rows
+            .iter()
+            .map(|(_, a, b)| format!("({}, '{}')", a, b))
+            .collect();
+        conn.execute(&format!("INSERT INTO t VALUES {}", values.join(", ")))
+            .unwrap();
+
+        // the keys come to many times the sort memory, so they are sorted
+        // in several runs, which are merged as the index is built
+        conn.sort_memory = 16 * 1024;
+        let key_bytes: usize = rows.iter().map(|(_, _, b)| b.len()).sum();
+        assert!(key_bytes > 4 * conn.sort_memory);
+        conn.execute("CREATE INDEX t_ba ON t(b COLLATE NOCASE DESC, a)")
+            .unwrap();
+        assert!(crate::integrity::check_structure(&conn).unwrap().is_empty());
+        assert!(crate::integrity::check_indexes(&conn).unwrap().is_empty());
+
+        let schema = conn.schema.index("t_ba").unwrap();
+        let index = Btree::new(
+            schema.name.clone(),
+            schema.table_name.clone(),
+            schema.root_page,
+            conn.pager.clone(),
+        );
+        let entries: Vec<(i64, i64, String)> = index
+            .index_lookup(&[])
+            .unwrap()
+            .into_iter()
+            .map(|entry| match entry.values.as_slice() {
+                [Value::String(b), a, row_id] => (
+                    row_id.get_int_val().unwrap(),
+                    a.get_int_val().unwrap(),
+                    b.clone(),
+                ),
+                other => panic!("unexpected entry {:?}", other),
+            })
+            .collect();
+        let mut expected = rows;
+        expected.sort_by(|(x_id, x_a, x_b), (y_id, y_a, y_b)| {
+            return y_b
+                .to_lowercase()
+                .cmp(&x_b.to_lowercase())
+                .then(x_a.cmp(y_a))
+                .then(x_id.cmp(y_id));
+        });
+        assert_eq!(entries.len(), expected.len());
+        assert!(entries == expected);
+    }
+}
@@ -0,0 +1,121 @@
+use eyre::{eyre, Result};
+
+use crate::ast::Delete;
+use crate::connection::Connection;
+use crate::ddl;
+use crate::exec::{self, TableRow};
+use crate::replication::Change;
+use crate::select;
+use crate::statement::QueryResult;
+
+pub fn execute_delete(conn: &mut Connection, delete: &Delete) -> Result<QueryResult> {
+    if conn.schema.view(&delete.table).is_some() {
+        return Err(eyre!("cannot modify {} because it is a view", delete.table));
+    }
+    let table = conn.table(&delete.table)?;
+    if table.schema.name == "sqlite_schema" {
+        return Err(eyre!("table {} may not be modified", delete.table));
+    }
+    let indexes = ddl::table_indexes(conn, &table.schema)?;
+
+    let mut where_clause = delete.where_clause.clone();
+    if let Some(where_clause) = &mut where_clause {
+        exec::check_table_expr(&table.schema, where_clause)?;
+        select::eval_subqueries(conn, where_clause)?;
+    }
+
+    // the rows are found before any are deleted, so that the scan does
+    // not see the tree change underneath it
+    let mut rows = Vec::new();
+    for row in table.btree.scan() {
+        let (row_id, record) = row?;
+        let record = table.schema.with_row_id(row_id.0, record);
+        if let Some(where_clause) = &where_clause {
+            let row = TableRow {
+                table: &table.schema,
+                record: &record,
+                collations: &conn.collations,
+            };
+            if exec::truth_value(&exec::eval_expr(where_clause, &row)?) != Some(true) {
+                continue;
+            }
+        }
+        rows.push((row_id.0, record));
+    }
+
+    conn.write_statement(|conn| {
+        for (row_id, record) in rows {
+            table.btree.delete(row_id)?;
+            for index in &indexes {
+                index.delete_row(&table.schema, row_id, &record)?;
+            }
+            conn.record_change(|| Change::Delete {
+                table: table.schema.name.clone(),
+                row_id: row_id,
+            })?;
+        }
+        return Ok(());
+    })?;
+    return Ok(QueryResult::default());
+}
+
+/// Describes how a DELETE statement would be run, as the depths and
+/// details of the rows of `EXPLAIN QUERY PLAN`.
+pub fn explain_delete(conn: &Connection, delete: &Delete) -> Result<Vec<(usize, String)>> {
+    let table = conn.table(&delete.table)?;
+    if let Some(where_clause) = &delete.where_clause {
+        exec::check_table_expr(&table.schema, where_clause)?;
+    }
+    return Ok(vec![(0, format!("SCAN {}", table.schema.name))]);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::connection::Connection;
+
+    #[test]
+    fn delete_rows() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        conn.execute("CREATE TABLE t(a, b)").unwrap();
+        for i in 1..=300 {
+            conn.execute(&format!(
+                "INSERT INTO t VALUES ({}, '{}')",
+                i,
+                "x".repeat(i % 90)
+            ))
+            .unwrap();
+        }
+        let table = conn.table("t").unwrap();
+        assert!(conn
+            .pager
+            .write()
+            .get_page(table.schema.root_page)
+            .unwrap()
+            .is_interior());
+
+        conn.execute("CREATE INDEX t_b ON t(b DESC, a)").unwrap();
+        conn.execute("CREATE INDEX t_a ON t(a) WHERE a % 2 = 0")
+            .unwrap();
+        conn.execute("DELETE FROM t WHERE a % 3 = 0 OR a > 100")
+            .unwrap();
+        assert!(crate::integrity::check_indexes(&conn).unwrap().is_empty());
+        let rows = table.btree.list_records();
+        assert_eq!(rows.len(), 67);
+        assert!(rows
+            .iter()
+            .all(|(_, r)| r.values[0].get_int_val().unwrap() % 3 != 0));
+        assert!(conn.execute("DELETE FROM t WHERE c = 1").is_err());
+
+        // once the rows fit on one page, the root becomes a leaf again
+        conn.execute("DELETE FROM t").unwrap();
+        assert!(table.btree.list_records().is_empty());
+        assert!(crate::integrity::check_structure(&conn).unwrap().is_empty());
+        assert!(conn
+            .pager
+            .write()
+            .get_page(table.schema.root_page)
+            .unwrap()
+            .is_leaf());
+    }
+}
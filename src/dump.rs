@@ -0,0 +1,358 @@
+use eyre::{eyre, Result};
+use std::io::{Read, Write};
+
+use crate::btree::Btree;
+use crate::connection::Connection;
+use crate::datatypes::Value;
+use crate::ddl;
+use crate::exec;
+use crate::parser::{self, quote_name};
+use crate::schema::SCHEMA_ROOT_PAGE;
+use crate::tokenizer;
+
+/// What to include in a dump, as given to the shell's `.dump` command.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DumpOptions {
+    /// LIKE patterns for the names of the tables, indexes, views, and
+    /// triggers to dump. Everything is dumped if there are none.
+    pub patterns: Vec<String>,
+    /// Leave out the rows of tables.
+    pub schema_only: bool,
+    /// Leave out the CREATE statements, and the statements that wrap the
+    /// dump in a transaction.
+    pub data_only: bool,
+    /// Include the row ID of each row in its INSERT, so that it is the
+    /// same once the dump is restored. Tables with an INTEGER PRIMARY KEY
+    /// keep their row IDs anyway.
+    pub preserve_rowids: bool,
+}
+
+impl DumpOptions {
+    /// Parses the arguments of `.dump`, e.g. `--data-only users%`.
+    pub fn parse(args: &[&str]) -> Result<Self> {
+        let mut options = Self::default();
+        for arg in args {
+            match *arg {
+                "--schema-only" => options.schema_only = true,
+                "--data-only" => options.data_only = true,
+                "--preserve-rowids" => options.preserve_rowids = true,
+                arg if arg.starts_with('-') => {
+                    return Err(eyre!("unknown option: {}", arg));
+                }
+                pattern => options.patterns.push(pattern.to_string()),
+            }
+        }
+        if options.schema_only && options.data_only {
+            return Err(eyre!(
+                "--schema-only and --data-only are mutually exclusive"
+            ));
+        }
+        return Ok(options);
+    }
+
+    fn selects(&self, name: &str) -> bool {
+        return self.patterns.is_empty()
+            || self
+                .patterns
+                .iter()
+                .any(|pattern| exec::like(pattern, name, Some('\\')));
+    }
+}
+
+/// An entry of sqlite_schema.
+struct SchemaEntry {
+    obj_type: String,
+    name: String,
+    root_page: i64,
+    sql: String,
+}
+
+/// Writes the contents of a database as SQL text which recreates it, in
+/// the same form as the SQLite shell's `.dump`: each table's CREATE
+/// statement followed by an INSERT for each of its rows, then the
+/// indexes, triggers, and views. Text is written as is, including any
+/// newlines, which string literals may contain.
+pub fn dump(conn: &Connection, options: &DumpOptions, out: &mut dyn Write) -> Result<()> {
+    let schema_tree = Btree::new(
+        "sqlite_schema".to_string(),
+        "sqlite_schema".to_string(),
+        SCHEMA_ROOT_PAGE,
+        conn.pager.clone(),
+    );
+    let mut entries = Vec::new();
+    for (_, record) in schema_tree.list_records() {
+        let text = |i: usize| match record.values.get(i) {
+            Some(Value::String(s)) => Some(s.clone()),
+            _ => None,
+        };
+        // objects without SQL are created along with their table
+        if let (Some(obj_type), Some(name), Some(sql)) = (text(0), text(1), text(4)) {
+            if options.selects(&name) {
+                entries.push(SchemaEntry {
+                    obj_type: obj_type,
+                    name: name,
+                    root_page: record
+                        .values
+                        .get(3)
+                        .and_then(|v| v.get_int_val())
+                        .unwrap_or(0),
+                    sql: sql,
+                });
+            }
+        }
+    }
+    let (tables, mut others): (Vec<SchemaEntry>, Vec<SchemaEntry>) = entries
+        .into_iter()
+        .partition(|entry| entry.obj_type == "table");
+    // views first, then triggers, then indexes
+    others.sort_by(|a, b| b.obj_type.cmp(&a.obj_type));
+
+    let virtual_tables = tables.iter().any(|table| table.root_page == 0);
+    if !options.data_only {
+        if virtual_tables {
+            writeln!(
+                out,
+                "/* WARNING: Script requires that SQLITE_DBCONFIG_DEFENSIVE be disabled */"
+            )?;
+        }
+        writeln!(out, "PRAGMA foreign_keys=OFF;")?;
+        writeln!(out, "BEGIN TRANSACTION;")?;
+    }
+    let mut writable_schema = false;
+    // sqlite_sequence is filled in as rows are inserted into other
+    // tables, so it is emptied before its own rows are restored
+    let (sequence, tables): (Vec<SchemaEntry>, Vec<SchemaEntry>) = tables
+        .into_iter()
+        .partition(|table| table.name == "sqlite_sequence");
+    for table in tables.iter().chain(sequence.iter()) {
+        if !options.data_only {
+            if table.root_page == 0 {
+                // virtual tables cannot be created with CREATE TABLE,
+                // so are added to sqlite_schema directly
+                if !writable_schema {
+                    writeln!(out, "PRAGMA writable_schema=ON;")?;
+                    writable_schema = true;
+                }
+                writeln!(
+                    out,
+                    "INSERT INTO sqlite_schema(type,name,tbl_name,rootpage,sql)VALUES('table',{},{},0,{});",
+                    quote_text(&table.name),
+                    quote_text(&table.name),
+                    quote_text(&table.sql)
+                )?;
+                continue;
+            } else if table.name == "sqlite_sequence" {
+                writeln!(out, "DELETE FROM sqlite_sequence;")?;
+            } else if table.name == "sqlite_stat1" {
+                writeln!(out, "ANALYZE sqlite_schema;")?;
+            } else if table.name.starts_with("sqlite_") {
+                continue;
+            } else if table.sql.starts_with("CREATE TABLE \"")
+                || table.sql.starts_with("CREATE TABLE '")
+            {
+                // so that restoring into a database that already has
+                // the table is not an error
+                writeln!(out, "CREATE TABLE IF NOT EXISTS {};", &table.sql[13..])?;
+            } else {
+                writeln!(out, "{};", table.sql)?;
+            }
+        }
+        if !options.schema_only && table.root_page != 0 {
+            dump_rows(conn, &table.name, options.preserve_rowids, out)?;
+        }
+    }
+    if !options.data_only {
+        for entry in &others {
+            writeln!(out, "{};", entry.sql)?;
+        }
+        if writable_schema {
+            writeln!(out, "PRAGMA writable_schema=OFF;")?;
+        }
+        writeln!(out, "COMMIT;")?;
+    }
+    return Ok(());
+}
+
+/// Writes an INSERT for each row of a table.
+fn dump_rows(
+    conn: &Connection,
+    name: &str,
+    preserve_rowids: bool,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let table = conn.table(name)?;
+    let row_id_alias = ddl::row_id_alias(&table.schema.definition);
+    let names = table.schema.column_names();
+
+    let mut target = quote_name(&table.schema.name);
+    let preserve_rowids = preserve_rowids && row_id_alias.is_none();
+    if preserve_rowids {
+        // the row ID goes by the first of its names that is not also
+        // the name of a column
+        let row_id_name = ["rowid", "_rowid_", "oid"]
+            .iter()
+            .find(|n| table.schema.column_index(n).is_none())
+            .ok_or_else(|| eyre!("cannot preserve the row IDs of table {}", name))?;
+        let mut columns = vec![row_id_name.to_string()];
+        columns.extend(names.iter().map(|name| quote_name(name)));
+        target = format!("{}({})", target, columns.join(","));
+    }
+
+    for row in table.btree.scan() {
+        let (row_id, record) = row?;
+        let mut values = Vec::with_capacity(names.len() + 1);
+        if preserve_rowids {
+            values.push(row_id.0.to_string());
+        }
+        for idx in 0..names.len() {
+            // the row ID is stored in place of a column that is an alias
+            // for it
+            values.push(if Some(idx) == row_id_alias {
+                row_id.0.to_string()
+            } else {
+                sql_literal(&table.schema.column_value(&record, idx)?)
+            });
+        }
+        writeln!(out, "INSERT INTO {} VALUES({});", target, values.join(","))?;
+    }
+    return Ok(());
+}
+
+/// Restores a database from SQL text, such as the output of `dump`, as a
+/// single statement: if any part of it fails, the database is left as it
+/// was. The statements that make a dump a transaction (`BEGIN
+/// TRANSACTION` and `COMMIT`) are skipped, since the restore is one
+/// anyway, as is `PRAGMA foreign_keys=OFF`, since foreign keys are not
+/// enforced.
+pub fn restore(conn: &mut Connection, reader: &mut dyn Read) -> Result<()> {
+    let mut sql = String::new();
+    reader.read_to_string(&mut sql)?;
+    let mut statements = Vec::new();
+    for statement in parser::split_statements(&sql)? {
+        let tokens = tokenizer::tokenize(statement)?;
+        let skip = ["BEGIN", "COMMIT", "END"]
+            .iter()
+            .any(|kw| tokens[0].is_keyword(kw))
+            || (tokens[0].is_keyword("PRAGMA") && tokens[1].is_keyword("foreign_keys"));
+        if !skip {
+            statements.push(statement);
+        }
+    }
+    return conn.write_statement(|conn| conn.execute_statements(&statements));
+}
+
+fn quote_text(text: &str) -> String {
+    return format!("'{}'", text.replace('\'', "''"));
+}
+
+/// Writes a value as an SQL literal, which evaluates to the same value.
+pub fn sql_literal(value: &Value) -> String {
+    if let Some(n) = value.get_int_val() {
+        return n.to_string();
+    }
+    return match value {
+        Value::Float(f) if f.is_nan() => "NULL".to_string(),
+        // SQLite reads numbers too large for a double as infinity
+        Value::Float(f) if f.is_infinite() => {
+            if *f > 0.0 {
+                "9.0e+999".to_string()
+            } else {
+                "-9.0e+999".to_string()
+            }
+        }
+        // the literal must contain a decimal point or exponent, so that
+        // it is read back as a real number
+        Value::Float(f) if f.fract() == 0.0 && f.abs() < 1e15 => format!("{}.0", *f as i64),
+        Value::Float(f) => format!("{:?}", f),
+        Value::String(s) => quote_text(s),
+        Value::Blob(bytes) | Value::Internal(bytes) => {
+            let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            format!("X'{}'", hex)
+        }
+        _ => "NULL".to_string(),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dump_options() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        conn.execute("CREATE TABLE t(id INTEGER PRIMARY KEY, a, b)")
+            .unwrap();
+        conn.execute("INSERT INTO t VALUES (1, 'it''s', 2.5)")
+            .unwrap();
+        conn.execute("INSERT INTO t VALUES (2, NULL, 3.0)").unwrap();
+        conn.execute("CREATE TABLE \"u v\"(x)").unwrap();
+        conn.execute("INSERT INTO \"u v\" VALUES (1e300)").unwrap();
+        conn.execute("CREATE INDEX t_a ON t(a)").unwrap();
+
+        let run = |args: &[&str]| -> String {
+            let mut out = Vec::new();
+            dump(&conn, &DumpOptions::parse(args).unwrap(), &mut out).unwrap();
+            return String::from_utf8(out).unwrap();
+        };
+        assert_eq!(
+            run(&[]),
+            "PRAGMA foreign_keys=OFF;\n\
+             BEGIN TRANSACTION;\n\
+             CREATE TABLE t(id INTEGER PRIMARY KEY, a, b);\n\
+             INSERT INTO t VALUES(1,'it''s',2.5);\n\
+             INSERT INTO t VALUES(2,NULL,3.0);\n\
+             CREATE TABLE IF NOT EXISTS \"u v\"(x);\n\
+             INSERT INTO \"u v\" VALUES(1e300);\n\
+             CREATE INDEX t_a ON t(a);\n\
+             COMMIT;\n"
+        );
+        assert_eq!(
+            run(&["--schema-only", "t%"]),
+            "PRAGMA foreign_keys=OFF;\n\
+             BEGIN TRANSACTION;\n\
+             CREATE TABLE t(id INTEGER PRIMARY KEY, a, b);\n\
+             CREATE INDEX t_a ON t(a);\n\
+             COMMIT;\n"
+        );
+        assert_eq!(
+            run(&["--data-only", "--preserve-rowids", "u_v"]),
+            "INSERT INTO \"u v\"(rowid,x) VALUES(1,1e300);\n"
+        );
+        assert!(DumpOptions::parse(&["--schema-only", "--data-only"]).is_err());
+        assert!(DumpOptions::parse(&["--bogus"]).is_err());
+    }
+
+    #[test]
+    fn restore_dump() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        let mut script = String::from("BEGIN TRANSACTION;\nCREATE TABLE t(a, b);\n");
+        // enough rows for the table to need interior pages
+        for i in 1..=3000 {
+            script.push_str(&format!("INSERT INTO t VALUES({},'row; {}');\n", i, i));
+        }
+        script.push_str("CREATE INDEX t_a ON t(a);\nCOMMIT;\n");
+        conn.restore_dump(&mut script.as_bytes()).unwrap();
+        let result = conn.execute("SELECT b FROM t WHERE a = 2999").unwrap();
+        assert_eq!(result.rows, [[Value::String("row; 2999".to_string())]]);
+        assert!(crate::integrity::check_indexes(&conn).unwrap().is_empty());
+        let mut out = Vec::new();
+        dump(&conn, &DumpOptions::default(), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            script.replace("BEGIN", "PRAGMA foreign_keys=OFF;\nBEGIN")
+        );
+
+        // a restore that fails changes nothing
+        let mut bad =
+            "CREATE TABLE u(x); INSERT INTO u VALUES(1); INSERT INTO v VALUES(2);".as_bytes();
+        assert!(conn.restore_dump(&mut bad).is_err());
+        assert!(conn.table("u").is_err());
+
+        // whereas a batch keeps the statements before the one that fails
+        conn.execute_batch("CREATE TABLE u(x NOT NULL); INSERT INTO u VALUES(1); INSERT INTO u VALUES(NULL); INSERT INTO u VALUES(3)")
+            .unwrap_err();
+        assert_eq!(conn.table("u").unwrap().btree.list_records().len(), 1);
+    }
+}
@@ -0,0 +1,1379 @@
+use eyre::{eyre, Result};
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+use crate::ast::{BinaryOp, Expr, LikeOp, Select, UnaryOp};
+use crate::btree::Record;
+use crate::datatypes::Value;
+use crate::functions;
+use crate::schema::TableSchema;
+
+/// Supplies the values of the columns referenced by an expression for the
+/// row currently being evaluated.
+pub trait RowSource {
+    fn column(&self, table: Option<&str>, name: &str) -> Result<Value>;
+
+    /// The affinity of a column, which decides how values compared with
+    /// it are converted.
+    fn column_affinity(&self, table: Option<&str>, name: &str) -> Result<Affinity>;
+
+    /// The collating sequence declared for a column, which text compared
+    /// with it is compared by.
+    fn column_collation(&self, table: Option<&str>, name: &str) -> Result<Collation>;
+}
+
+/// A row source with no columns, for expressions that are evaluated
+/// outside of any table (e.g., `SELECT 1 + 1`).
+pub struct NoRow;
+
+impl RowSource for NoRow {
+    fn column(&self, table: Option<&str>, name: &str) -> Result<Value> {
+        return match table {
+            Some(table) => Err(eyre!("no such column: {}.{}", table, name)),
+            None => Err(eyre!("no such column: {}", name)),
+        };
+    }
+
+    fn column_affinity(&self, table: Option<&str>, name: &str) -> Result<Affinity> {
+        return Err(self.column(table, name).unwrap_err());
+    }
+
+    fn column_collation(&self, table: Option<&str>, name: &str) -> Result<Collation> {
+        return Err(self.column(table, name).unwrap_err());
+    }
+}
+
+/// A row of a single table, for statements that work on one table at a
+/// time (e.g., DELETE).
+pub struct TableRow<'a> {
+    pub table: &'a TableSchema,
+    pub record: &'a Record,
+    /// The collating sequences the table's columns may name.
+    pub collations: &'a Collations,
+}
+
+impl RowSource for TableRow<'_> {
+    fn column(&self, table: Option<&str>, name: &str) -> Result<Value> {
+        let idx = resolve_table_column(self.table, table, name)?;
+        return self.table.column_value(self.record, idx);
+    }
+
+    fn column_affinity(&self, table: Option<&str>, name: &str) -> Result<Affinity> {
+        let idx = resolve_table_column(self.table, table, name)?;
+        return Ok(self.table.column_affinity(idx));
+    }
+
+    fn column_collation(&self, table: Option<&str>, name: &str) -> Result<Collation> {
+        let idx = resolve_table_column(self.table, table, name)?;
+        return self
+            .table
+            .column_collation(idx)
+            .map_or(Ok(Collation::Binary), |name| {
+                Collation::from_name(name, self.collations)
+            });
+    }
+}
+
+/// Finds the position of a column in the records of a table, checking
+/// the table name it is qualified with, if any.
+pub fn resolve_table_column(
+    schema: &TableSchema,
+    table: Option<&str>,
+    name: &str,
+) -> Result<usize> {
+    return match table {
+        Some(table) if !table.eq_ignore_ascii_case(&schema.name) => {
+            Err(eyre!("no such column: {}.{}", table, name))
+        }
+        Some(table) => schema
+            .column_index(name)
+            .ok_or_else(|| eyre!("no such column: {}.{}", table, name)),
+        None => schema
+            .column_index(name)
+            .ok_or_else(|| eyre!("no such column: {}", name)),
+    };
+}
+
+/// Checks that an expression only refers to columns of a table and only
+/// calls known functions, so that errors are reported even if no rows
+/// are evaluated.
+pub fn check_table_expr(schema: &TableSchema, expr: &Expr) -> Result<()> {
+    functions::check_calls(expr)?;
+    let mut refs = Vec::new();
+    column_refs(expr, &mut refs);
+    for (table, name) in refs {
+        resolve_table_column(schema, table, name)?;
+    }
+    return Ok(());
+}
+
+/// Evaluates an expression against a single row, following SQLite's
+/// rules for NULL propagation and type conversion.
+pub fn eval_expr(expr: &Expr, row: &dyn RowSource) -> Result<Value> {
+    return match expr {
+        Expr::Literal(value) => Ok(value.clone()),
+        Expr::Column { table, name } => row.column(table.as_deref(), name),
+        Expr::Parameter(index) => Err(eyre!("parameter ?{} is not bound", index)),
+        Expr::Unary { op, expr } => {
+            let value = eval_expr(expr, row)?;
+            Ok(eval_unary(*op, &value))
+        }
+        Expr::Binary { left, op, right } => match op {
+            BinaryOp::And => {
+                let left = truth_value(&eval_expr(left, row)?);
+                if left == Some(false) {
+                    return Ok(Value::Integer0);
+                }
+                let right = truth_value(&eval_expr(right, row)?);
+                Ok(match (left, right) {
+                    (_, Some(false)) => Value::Integer0,
+                    (Some(true), Some(true)) => Value::Integer1,
+                    _ => Value::Null,
+                })
+            }
+            BinaryOp::Or => {
+                let left = truth_value(&eval_expr(left, row)?);
+                if left == Some(true) {
+                    return Ok(Value::Integer1);
+                }
+                let right = truth_value(&eval_expr(right, row)?);
+                Ok(match (left, right) {
+                    (_, Some(true)) => Value::Integer1,
+                    (Some(false), Some(false)) => Value::Integer0,
+                    _ => Value::Null,
+                })
+            }
+            BinaryOp::Eq
+            | BinaryOp::NotEq
+            | BinaryOp::Lt
+            | BinaryOp::LtEq
+            | BinaryOp::Gt
+            | BinaryOp::GtEq
+            | BinaryOp::Is
+            | BinaryOp::IsNot => {
+                let (left_value, right_value) = coerce_operands(
+                    eval_expr(left, row)?,
+                    expr_affinity(left, row)?,
+                    eval_expr(right, row)?,
+                    expr_affinity(right, row)?,
+                );
+                let collation = comparison_collation(left, right, row)?;
+                Ok(eval_binary(&left_value, *op, &right_value, &collation))
+            }
+            _ => {
+                let left = eval_expr(left, row)?;
+                let right = eval_expr(right, row)?;
+                Ok(eval_binary(&left, *op, &right, &Collation::Binary))
+            }
+        },
+        Expr::IsNull { expr, negated } => {
+            let is_null = matches!(eval_expr(expr, row)?, Value::Null);
+            Ok(bool_value(is_null != *negated))
+        }
+        Expr::Like {
+            expr,
+            pattern,
+            escape,
+            op,
+            negated,
+        } => {
+            let value = eval_expr(expr, row)?;
+            let pattern = eval_expr(pattern, row)?;
+            let escape = match escape {
+                Some(escape) => Some(eval_expr(escape, row)?),
+                None => None,
+            };
+            let (value, pattern) = match (to_text(&value), to_text(&pattern)) {
+                (Some(value), Some(pattern)) => (value, pattern),
+                _ => return Ok(Value::Null),
+            };
+            let matched = match op {
+                LikeOp::Like => {
+                    let escape = match escape {
+                        Some(escape) => match to_text(&escape) {
+                            Some(text) => {
+                                let mut chars = text.chars();
+                                match (chars.next(), chars.next()) {
+                                    (Some(c), None) => Some(c),
+                                    _ => {
+                                        return Err(eyre!(
+                                            "ESCAPE expression must be a single character"
+                                        ))
+                                    }
+                                }
+                            }
+                            None => return Ok(Value::Null),
+                        },
+                        None => None,
+                    };
+                    like(&pattern, &value, escape)
+                }
+                LikeOp::Glob => glob(&pattern, &value),
+            };
+            Ok(bool_value(matched != *negated))
+        }
+        Expr::Between {
+            expr,
+            low,
+            high,
+            negated,
+        } => {
+            let value = eval_expr(expr, row)?;
+            let affinity = expr_affinity(expr, row)?;
+            let collation = expr_collation(expr, row)?.unwrap_or(Collation::Binary);
+            let (left, low) = coerce_operands(
+                value.clone(),
+                affinity,
+                eval_expr(low, row)?,
+                expr_affinity(low, row)?,
+            );
+            let low = eval_binary(&left, BinaryOp::GtEq, &low, &collation);
+            let (left, high) = coerce_operands(
+                value,
+                affinity,
+                eval_expr(high, row)?,
+                expr_affinity(high, row)?,
+            );
+            let high = eval_binary(&left, BinaryOp::LtEq, &high, &collation);
+            let between = match (truth_value(&low), truth_value(&high)) {
+                (Some(false), _) | (_, Some(false)) => Some(false),
+                (Some(true), Some(true)) => Some(true),
+                _ => None,
+            };
+            Ok(match between {
+                Some(between) => bool_value(between != *negated),
+                None => Value::Null,
+            })
+        }
+        Expr::In {
+            expr,
+            list,
+            subquery,
+            negated,
+        } => {
+            if subquery.is_some() {
+                return Err(eyre!("subqueries prohibited in this context"));
+            }
+            // nothing is in an empty list, not even NULL
+            if list.is_empty() {
+                return Ok(bool_value(*negated));
+            }
+            let value = eval_expr(expr, row)?;
+            let affinity = expr_affinity(expr, row)?;
+            let collation = expr_collation(expr, row)?.unwrap_or(Collation::Binary);
+            let mut found = Some(false);
+            for item in list {
+                // the items of the list are converted using the affinity
+                // of the left operand only
+                let (left, item) =
+                    coerce_operands(value.clone(), affinity, eval_expr(item, row)?, None);
+                match truth_value(&eval_binary(&left, BinaryOp::Eq, &item, &collation)) {
+                    Some(true) => {
+                        found = Some(true);
+                        break;
+                    }
+                    Some(false) => (),
+                    // without a match, a NULL makes the result unknown
+                    None => found = None,
+                }
+            }
+            Ok(match found {
+                Some(found) => bool_value(found != *negated),
+                None => Value::Null,
+            })
+        }
+        Expr::Cast { expr, type_name } => {
+            let value = eval_expr(expr, row)?;
+            Ok(cast(value, Affinity::from_type_name(Some(type_name))))
+        }
+        Expr::Function {
+            name, args, star, ..
+        } => {
+            let mut values = Vec::with_capacity(args.len());
+            for arg in args {
+                values.push(eval_expr(arg, row)?);
+            }
+            functions::call(name, &values, *star)
+        }
+        // queries replace their subqueries with their values before
+        // evaluating anything, so any left are somewhere SQLite does not
+        // allow them either (e.g., CHECK constraints)
+        Expr::Subquery(_) | Expr::Exists(_) => Err(eyre!("subqueries prohibited in this context")),
+    };
+}
+
+/// Collects the column references in an expression. The columns a
+/// subquery refers to are those of its own tables, so are not included.
+pub fn column_refs<'a>(expr: &'a Expr, output: &mut Vec<(Option<&'a str>, &'a str)>) {
+    match expr {
+        Expr::Literal(_) | Expr::Parameter(_) | Expr::Subquery(_) | Expr::Exists(_) => (),
+        Expr::Column { table, name } => output.push((table.as_deref(), name)),
+        Expr::Unary { expr, .. } | Expr::IsNull { expr, .. } | Expr::Cast { expr, .. } => {
+            column_refs(expr, output)
+        }
+        Expr::Binary { left, right, .. } => {
+            column_refs(left, output);
+            column_refs(right, output);
+        }
+        Expr::Like {
+            expr,
+            pattern,
+            escape,
+            ..
+        } => {
+            column_refs(expr, output);
+            column_refs(pattern, output);
+            if let Some(escape) = escape {
+                column_refs(escape, output);
+            }
+        }
+        Expr::Between {
+            expr, low, high, ..
+        } => {
+            column_refs(expr, output);
+            column_refs(low, output);
+            column_refs(high, output);
+        }
+        Expr::In { expr, list, .. } => {
+            column_refs(expr, output);
+            for item in list {
+                column_refs(item, output);
+            }
+        }
+        Expr::Function { args, .. } => {
+            for arg in args {
+                column_refs(arg, output);
+            }
+        }
+    }
+}
+
+/// Collects the subqueries in an expression, not counting those nested
+/// inside them, each with whether it supplies the list of an IN rather
+/// than a single value.
+pub fn subqueries<'a>(expr: &'a Expr, output: &mut Vec<(&'a Select, bool)>) {
+    match expr {
+        Expr::Literal(_) | Expr::Parameter(_) | Expr::Column { .. } => (),
+        Expr::Subquery(select) | Expr::Exists(select) => output.push((select, false)),
+        Expr::Unary { expr, .. } | Expr::IsNull { expr, .. } | Expr::Cast { expr, .. } => {
+            subqueries(expr, output)
+        }
+        Expr::Binary { left, right, .. } => {
+            subqueries(left, output);
+            subqueries(right, output);
+        }
+        Expr::Like {
+            expr,
+            pattern,
+            escape,
+            ..
+        } => {
+            subqueries(expr, output);
+            subqueries(pattern, output);
+            if let Some(escape) = escape {
+                subqueries(escape, output);
+            }
+        }
+        Expr::Between {
+            expr, low, high, ..
+        } => {
+            subqueries(expr, output);
+            subqueries(low, output);
+            subqueries(high, output);
+        }
+        Expr::In {
+            expr,
+            list,
+            subquery,
+            ..
+        } => {
+            subqueries(expr, output);
+            for item in list {
+                subqueries(item, output);
+            }
+            if let Some(select) = subquery {
+                output.push((select, true));
+            }
+        }
+        Expr::Function { args, .. } => {
+            for arg in args {
+                subqueries(arg, output);
+            }
+        }
+    }
+}
+
+/// Tests whether a value counts as true in a boolean context (e.g., a
+/// WHERE clause). NULL is neither true nor false.
+pub fn truth_value(value: &Value) -> Option<bool> {
+    return match to_numeric(value)? {
+        Numeric::Integer(i) => Some(i != 0),
+        Numeric::Real(f) => Some(f != 0.0),
+    };
+}
+
+/// Compares two values using SQLite's ordering rules: NULLs first, then
+/// numbers, then text, then blobs (see `Value::cmp_sqlite`).
+pub fn compare(left: &Value, right: &Value) -> Ordering {
+    return left.cmp_sqlite(right);
+}
+
+/// How a collating sequence orders text. The built-in sequences are
+/// implemented with it, and others can be added to a connection with
+/// `Connection::register_collation`.
+pub trait CollatingFunction: Send + Sync {
+    fn compare(&self, left: &str, right: &str) -> Ordering;
+
+    /// Converts text to a form that is the same for all the texts this
+    /// function treats as equal, so that it can be hashed. The default,
+    /// the text itself, is only right for a function that treats no two
+    /// different texts as equal.
+    fn normalize(&self, text: &str) -> String {
+        return text.to_string();
+    }
+}
+
+/// Byte by byte.
+pub struct BinaryCollation;
+
+impl CollatingFunction for BinaryCollation {
+    fn compare(&self, left: &str, right: &str) -> Ordering {
+        return left.as_bytes().cmp(right.as_bytes());
+    }
+}
+
+/// Ignoring the case of ASCII letters.
+pub struct NoCaseCollation;
+
+impl CollatingFunction for NoCaseCollation {
+    fn compare(&self, left: &str, right: &str) -> Ordering {
+        return left
+            .bytes()
+            .map(|c| c.to_ascii_lowercase())
+            .cmp(right.bytes().map(|c| c.to_ascii_lowercase()));
+    }
+
+    fn normalize(&self, text: &str) -> String {
+        return text.to_ascii_lowercase();
+    }
+}
+
+/// Ignoring trailing spaces.
+pub struct RTrimCollation;
+
+impl CollatingFunction for RTrimCollation {
+    fn compare(&self, left: &str, right: &str) -> Ordering {
+        return left.trim_end_matches(' ').cmp(right.trim_end_matches(' '));
+    }
+
+    fn normalize(&self, text: &str) -> String {
+        return text.trim_end_matches(' ').to_string();
+    }
+}
+
+/// A collating function can also be given as a closure.
+impl<F> CollatingFunction for F
+where
+    F: Fn(&str, &str) -> Ordering + Send + Sync,
+{
+    fn compare(&self, left: &str, right: &str) -> Ordering {
+        return self(left, right);
+    }
+}
+
+/// The collating sequences a connection has added to the built-in ones
+/// (see `Connection::register_collation`). SQL run on the connection can
+/// name them wherever it names a built-in one: in a column definition,
+/// an index or an ORDER BY term.
+#[derive(Clone, Default)]
+pub struct Collations {
+    added: Vec<Collation>,
+}
+
+impl Collations {
+    /// Adds a collating sequence. Adding a name again replaces its
+    /// function for statements run from then on. The built-in sequences
+    /// cannot be replaced.
+    pub fn register(&mut self, name: &str, function: Arc<dyn CollatingFunction>) -> Result<()> {
+        if ["BINARY", "NOCASE", "RTRIM"].contains(&name.to_uppercase().as_str()) {
+            return Err(eyre!("cannot replace the built-in collation {}", name));
+        }
+        self.added.retain(|c| !c.name().eq_ignore_ascii_case(name));
+        self.added.push(Collation::Custom(name.into(), function));
+        return Ok(());
+    }
+
+    fn find(&self, name: &str) -> Option<Collation> {
+        return self
+            .added
+            .iter()
+            .find(|c| c.name().eq_ignore_ascii_case(name))
+            .cloned();
+    }
+}
+
+/// A collating sequence, which decides how text values are ordered.
+/// Values of other types are always compared as they are.
+#[derive(Clone)]
+pub enum Collation {
+    Binary,
+    NoCase,
+    RTrim,
+    /// One added to a connection's `Collations`, by its name.
+    Custom(Arc<str>, Arc<dyn CollatingFunction>),
+}
+
+impl Collation {
+    /// Finds a collating sequence by name: one of the built-in ones, or
+    /// else one of those in `collations`.
+    pub fn from_name(name: &str, collations: &Collations) -> Result<Self> {
+        return match name.to_uppercase().as_str() {
+            "BINARY" => Ok(Self::Binary),
+            "NOCASE" => Ok(Self::NoCase),
+            "RTRIM" => Ok(Self::RTrim),
+            _ => collations
+                .find(name)
+                .ok_or_else(|| eyre!("no such collation sequence: {}", name)),
+        };
+    }
+
+    pub fn name(&self) -> &str {
+        return match self {
+            Self::Binary => "BINARY",
+            Self::NoCase => "NOCASE",
+            Self::RTrim => "RTRIM",
+            Self::Custom(name, _) => name,
+        };
+    }
+
+    pub fn function(&self) -> &dyn CollatingFunction {
+        return match self {
+            Self::Binary => &BinaryCollation,
+            Self::NoCase => &NoCaseCollation,
+            Self::RTrim => &RTrimCollation,
+            Self::Custom(_, function) => function.as_ref(),
+        };
+    }
+
+    /// Converts text to a form that is the same for all the texts this
+    /// collating sequence treats as equal, so that it can be hashed.
+    pub fn normalize(&self, text: &str) -> String {
+        return self.function().normalize(text);
+    }
+
+    pub fn compare(&self, left: &Value, right: &Value) -> Ordering {
+        return match (self, left, right) {
+            (Self::Binary, _, _) => compare(left, right),
+            (_, Value::String(a), Value::String(b)) => self.function().compare(a, b),
+            _ => compare(left, right),
+        };
+    }
+}
+
+impl PartialEq for Collation {
+    fn eq(&self, other: &Self) -> bool {
+        return self.name().eq_ignore_ascii_case(other.name());
+    }
+}
+
+impl Eq for Collation {}
+
+impl std::fmt::Debug for Collation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return write!(f, "{}", self.name());
+    }
+}
+
+/// A column's type affinity: the type SQLite prefers for the values
+/// stored in it. Values are converted to it when that loses nothing.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Affinity {
+    Text,
+    Numeric,
+    Integer,
+    Real,
+    Blob,
+}
+
+impl Affinity {
+    /// Derives an affinity from a declared type, using the first of
+    /// SQLite's rules that matches. A column with no declared type has
+    /// BLOB affinity.
+    pub fn from_type_name(type_name: Option<&str>) -> Self {
+        let name = match type_name {
+            Some(name) => name.to_uppercase(),
+            None => return Self::Blob,
+        };
+        if name.contains("INT") {
+            return Self::Integer;
+        }
+        if ["CHAR", "CLOB", "TEXT"].iter().any(|s| name.contains(s)) {
+            return Self::Text;
+        }
+        if name.contains("BLOB") {
+            return Self::Blob;
+        }
+        if ["REAL", "FLOA", "DOUB"].iter().any(|s| name.contains(s)) {
+            return Self::Real;
+        }
+        return Self::Numeric;
+    }
+
+    pub fn is_numeric(&self) -> bool {
+        return matches!(self, Self::Numeric | Self::Integer | Self::Real);
+    }
+
+    /// Converts a value being stored in a column with this affinity. Text
+    /// is only made a number if all of it is a well-formed number, and a
+    /// real number is only made an integer if it has no fractional part.
+    pub fn apply(&self, value: Value) -> Value {
+        return match (self, value) {
+            (_, Value::Null) => Value::Null,
+            (Self::Blob, value) | (_, value @ Value::Blob(_)) => value,
+            (Self::Text, Value::String(s)) => Value::String(s),
+            (Self::Text, value) => Value::String(to_text(&value).unwrap()),
+            (Self::Real, value @ Value::Float(_)) => value,
+            (Self::Real, Value::String(s)) => match parse_numeric_literal(&s) {
+                Some(n) => Value::Float(n.as_real()),
+                None => Value::String(s),
+            },
+            (Self::Real, value) => match value.get_int_val() {
+                Some(i) => Value::Float(i as f64),
+                None => value,
+            },
+            (_, Value::Float(f)) => Numeric::Real(f).into_exact_value(),
+            (_, Value::String(s)) => match parse_numeric_literal(&s) {
+                Some(n) => n.into_exact_value(),
+                None => Value::String(s),
+            },
+            (_, value) => value,
+        };
+    }
+}
+
+/// The affinity of an expression: that of the column it refers to, or of
+/// the type it is cast to. Other expressions have none.
+pub fn expr_affinity(expr: &Expr, row: &dyn RowSource) -> Result<Option<Affinity>> {
+    return match expr {
+        Expr::Column { table, name } => Ok(Some(row.column_affinity(table.as_deref(), name)?)),
+        Expr::Cast { type_name, .. } => Ok(Some(Affinity::from_type_name(Some(type_name)))),
+        _ => Ok(None),
+    };
+}
+
+/// The collating sequence of an expression, which is that of the column
+/// it is, if it is just a column.
+pub fn expr_collation(expr: &Expr, row: &dyn RowSource) -> Result<Option<Collation>> {
+    return match expr {
+        Expr::Column { table, name } => Ok(Some(row.column_collation(table.as_deref(), name)?)),
+        _ => Ok(None),
+    };
+}
+
+/// The collating sequence a comparison compares text by, as in SQLite:
+/// that of the left operand if it is a column, or else of the right
+/// operand if that is, or else BINARY.
+pub fn comparison_collation(left: &Expr, right: &Expr, row: &dyn RowSource) -> Result<Collation> {
+    return match expr_collation(left, row)? {
+        Some(collation) => Ok(collation),
+        None => Ok(expr_collation(right, row)?.unwrap_or(Collation::Binary)),
+    };
+}
+
+/// Works out the affinity that the operands of a comparison are both
+/// converted to, given the affinities of the expressions they come from.
+/// Numeric affinity wins; otherwise, an operand with no affinity takes
+/// that of the other.
+pub fn comparison_affinity(left: Option<Affinity>, right: Option<Affinity>) -> Option<Affinity> {
+    return match (left, right) {
+        (Some(left), Some(right)) if left.is_numeric() || right.is_numeric() => {
+            Some(Affinity::Numeric)
+        }
+        (Some(_), Some(_)) | (None, None) => None,
+        (Some(affinity), None) | (None, Some(affinity)) => Some(affinity),
+    };
+}
+
+/// Converts the operands of a comparison, as SQLite does before
+/// comparing them (see `comparison_affinity`).
+pub fn coerce_operands(
+    left: Value,
+    left_affinity: Option<Affinity>,
+    right: Value,
+    right_affinity: Option<Affinity>,
+) -> (Value, Value) {
+    return match comparison_affinity(left_affinity, right_affinity) {
+        Some(affinity) => (affinity.apply(left), affinity.apply(right)),
+        None => (left, right),
+    };
+}
+
+/// Converts a value to the type named in a CAST expression. Unlike
+/// storing a value in a column, this always succeeds: text that is not a
+/// number becomes the number at its start, or zero.
+pub fn cast(value: Value, affinity: Affinity) -> Value {
+    if matches!(value, Value::Null) {
+        return Value::Null;
+    }
+    return match affinity {
+        Affinity::Blob => match value {
+            Value::Blob(b) | Value::Internal(b) => Value::Blob(b),
+            value => Value::Blob(to_text(&value).unwrap().into_bytes()),
+        },
+        Affinity::Text => Value::String(to_text(&value).unwrap()),
+        Affinity::Real => Value::Float(to_numeric(&value).unwrap().as_real()),
+        Affinity::Integer => match value {
+            Value::String(s) => Value::Int64(parse_integer_prefix(&s)),
+            Value::Blob(b) | Value::Internal(b) => {
+                Value::Int64(parse_integer_prefix(&String::from_utf8_lossy(&b)))
+            }
+            // real numbers are truncated, saturating at the limits
+            Value::Float(f) => Value::Int64(f as i64),
+            value => value,
+        },
+        Affinity::Numeric => match value {
+            Value::String(_) | Value::Blob(_) | Value::Internal(_) => {
+                to_numeric(&value).unwrap().into_exact_value()
+            }
+            value => value,
+        },
+    };
+}
+
+/// Converts a value to text, as SQLite does when a string is expected.
+/// Returns `None` for NULL.
+pub fn to_text(value: &Value) -> Option<String> {
+    return match value {
+        Value::Null => None,
+        Value::Float(f) => Some(format_real(*f)),
+        Value::String(s) => Some(s.clone()),
+        Value::Blob(b) | Value::Internal(b) => Some(String::from_utf8_lossy(b).into()),
+        _ => value.get_int_val().map(|i| i.to_string()),
+    };
+}
+
+/// Formats a floating point value the way SQLite prints it: to 15
+/// significant digits, in exponential notation if it is very large or
+/// small, and always with a decimal point.
+pub fn format_real(f: f64) -> String {
+    if f.is_nan() {
+        return "NaN".to_string();
+    }
+    if f.is_infinite() {
+        return if f > 0.0 { "Inf" } else { "-Inf" }.to_string();
+    }
+    if f == 0.0 {
+        // including negative zero
+        return "0.0".to_string();
+    }
+    // rounds to 15 significant digits, which also decides the exponent
+    let exp_form = format!("{:.14e}", f);
+    let (mantissa, exp) = exp_form.split_at(exp_form.find('e').unwrap());
+    let exp: i32 = exp[1..].parse().unwrap();
+    if !(-4..15).contains(&exp) {
+        let sign = if exp < 0 { '-' } else { '+' };
+        return format!("{}e{}{:02}", trim_fraction(mantissa), sign, exp.abs());
+    }
+    let fixed = format!("{:.*}", (14 - exp) as usize, f);
+    if !fixed.contains('.') {
+        return fixed + ".0";
+    }
+    return trim_fraction(&fixed).to_string();
+}
+
+/// Removes the trailing zeros after a decimal point, keeping at least one
+/// digit after it.
+fn trim_fraction(number: &str) -> &str {
+    let trimmed = number.trim_end_matches('0');
+    if trimmed.ends_with('.') {
+        return &number[..trimmed.len() + 1];
+    }
+    return trimmed;
+}
+
+/// 2^63, the first real number too large for an integer.
+const I64_LIMIT: f64 = 9_223_372_036_854_775_808.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Numeric {
+    Integer(i64),
+    Real(f64),
+}
+
+impl Numeric {
+    fn as_real(&self) -> f64 {
+        return match self {
+            Self::Integer(i) => *i as f64,
+            Self::Real(f) => *f,
+        };
+    }
+
+    fn into_value(self) -> Value {
+        return match self {
+            Self::Integer(i) => Value::Int64(i),
+            Self::Real(f) => Value::Float(f),
+        };
+    }
+
+    /// Like `into_value`, but a real number with no fractional part that
+    /// fits in an integer becomes one.
+    fn into_exact_value(self) -> Value {
+        return match self {
+            Self::Real(f) if f.fract() == 0.0 && (-I64_LIMIT..I64_LIMIT).contains(&f) => {
+                Value::Int64(f as i64)
+            }
+            number => number.into_value(),
+        };
+    }
+}
+
+/// Converts a value to a number, as SQLite does for arithmetic. Text and
+/// blobs are converted using their longest numeric prefix, or zero if
+/// there is none. Returns `None` for NULL.
+pub fn to_numeric(value: &Value) -> Option<Numeric> {
+    return match value {
+        Value::Null => None,
+        Value::Float(f) => Some(Numeric::Real(*f)),
+        Value::String(s) => Some(parse_numeric_prefix(s).0),
+        Value::Blob(b) | Value::Internal(b) => {
+            Some(parse_numeric_prefix(&String::from_utf8_lossy(b)).0)
+        }
+        _ => value.get_int_val().map(Numeric::Integer),
+    };
+}
+
+/// Parses text that is entirely a number, apart from any whitespace
+/// around it. Returns `None` if it is not.
+fn parse_numeric_literal(text: &str) -> Option<Numeric> {
+    let (number, rest) = parse_numeric_prefix(text);
+    if text.trim().is_empty() || !rest.trim().is_empty() {
+        return None;
+    }
+    return Some(number);
+}
+
+/// Parses the integer at the start of some text, ignoring any fraction or
+/// exponent after it. Integers too large to represent saturate.
+fn parse_integer_prefix(text: &str) -> i64 {
+    let text = text.trim_start();
+    let bytes = text.as_bytes();
+    let mut len = 0;
+    if matches!(bytes.first(), Some(b'+') | Some(b'-')) {
+        len += 1;
+    }
+    let digits_start = len;
+    while bytes.get(len).is_some_and(|b| b.is_ascii_digit()) {
+        len += 1;
+    }
+    if len == digits_start {
+        return 0;
+    }
+    return match text[..len].parse::<i64>() {
+        Ok(i) => i,
+        Err(_) if bytes[0] == b'-' => i64::MIN,
+        Err(_) => i64::MAX,
+    };
+}
+
+/// Parses the longest number at the start of some text, returning it and
+/// the text after it. Text that does not start with a number is zero.
+fn parse_numeric_prefix(text: &str) -> (Numeric, &str) {
+    let text = text.trim_start();
+    let bytes = text.as_bytes();
+    let mut len = 0;
+    if matches!(bytes.first(), Some(b'+') | Some(b'-')) {
+        len += 1;
+    }
+    let digits_start = len;
+    while bytes.get(len).is_some_and(|b| b.is_ascii_digit()) {
+        len += 1;
+    }
+    let mut is_real = false;
+    if bytes.get(len) == Some(&b'.') {
+        is_real = true;
+        len += 1;
+        while bytes.get(len).is_some_and(|b| b.is_ascii_digit()) {
+            len += 1;
+        }
+    }
+    if len == digits_start || (is_real && len == digits_start + 1) {
+        return (Numeric::Integer(0), text);
+    }
+    if matches!(bytes.get(len), Some(b'e') | Some(b'E')) {
+        let mut exp_len = len + 1;
+        if matches!(bytes.get(exp_len), Some(b'+') | Some(b'-')) {
+            exp_len += 1;
+        }
+        if bytes.get(exp_len).is_some_and(|b| b.is_ascii_digit()) {
+            is_real = true;
+            len = exp_len;
+            while bytes.get(len).is_some_and(|b| b.is_ascii_digit()) {
+                len += 1;
+            }
+        }
+    }
+    let (number, rest) = text.split_at(len);
+    if !is_real {
+        if let Ok(i) = number.parse::<i64>() {
+            return (Numeric::Integer(i), rest);
+        }
+    }
+    return (Numeric::Real(number.parse::<f64>().unwrap_or(0.0)), rest);
+}
+
+fn bool_value(b: bool) -> Value {
+    return if b { Value::Integer1 } else { Value::Integer0 };
+}
+
+fn eval_unary(op: UnaryOp, value: &Value) -> Value {
+    if matches!(value, Value::Null) {
+        return Value::Null;
+    }
+    return match op {
+        UnaryOp::Plus => value.clone(),
+        UnaryOp::Negate => match to_numeric(value).unwrap() {
+            Numeric::Integer(i) => match i.checked_neg() {
+                Some(i) => Value::Int64(i),
+                None => Value::Float(-(i as f64)),
+            },
+            Numeric::Real(f) => Value::Float(-f),
+        },
+        UnaryOp::Not => bool_value(!truth_value(value).unwrap()),
+        UnaryOp::BitNot => Value::Int64(!to_integer(value)),
+    };
+}
+
+fn to_integer(value: &Value) -> i64 {
+    return match to_numeric(value) {
+        Some(Numeric::Integer(i)) => i,
+        Some(Numeric::Real(f)) => f as i64,
+        None => 0,
+    };
+}
+
+/// Evaluates a binary operator on two values. Comparisons compare text by
+/// `collation`.
+fn eval_binary(left: &Value, op: BinaryOp, right: &Value, collation: &Collation) -> Value {
+    match op {
+        BinaryOp::Is => return bool_value(values_equal(left, right, collation)),
+        BinaryOp::IsNot => return bool_value(!values_equal(left, right, collation)),
+        _ => {}
+    }
+    if matches!(left, Value::Null) || matches!(right, Value::Null) {
+        return Value::Null;
+    }
+    return match op {
+        BinaryOp::Concat => Value::String(to_text(left).unwrap() + &to_text(right).unwrap()),
+        BinaryOp::Add | BinaryOp::Subtract | BinaryOp::Multiply => {
+            arithmetic(to_numeric(left).unwrap(), op, to_numeric(right).unwrap())
+        }
+        BinaryOp::Divide | BinaryOp::Remainder => {
+            divide(to_numeric(left).unwrap(), op, to_numeric(right).unwrap())
+        }
+        BinaryOp::BitAnd => Value::Int64(to_integer(left) & to_integer(right)),
+        BinaryOp::BitOr => Value::Int64(to_integer(left) | to_integer(right)),
+        BinaryOp::ShiftLeft => Value::Int64(shift_left(to_integer(left), to_integer(right))),
+        BinaryOp::ShiftRight => Value::Int64(shift_left(
+            to_integer(left),
+            to_integer(right).saturating_neg(),
+        )),
+        BinaryOp::Lt => bool_value(collation.compare(left, right) == Ordering::Less),
+        BinaryOp::LtEq => bool_value(collation.compare(left, right) != Ordering::Greater),
+        BinaryOp::Gt => bool_value(collation.compare(left, right) == Ordering::Greater),
+        BinaryOp::GtEq => bool_value(collation.compare(left, right) != Ordering::Less),
+        BinaryOp::Eq => bool_value(collation.compare(left, right) == Ordering::Equal),
+        BinaryOp::NotEq => bool_value(collation.compare(left, right) != Ordering::Equal),
+        BinaryOp::Is | BinaryOp::IsNot | BinaryOp::And | BinaryOp::Or => unreachable!(),
+    };
+}
+
+fn values_equal(left: &Value, right: &Value, collation: &Collation) -> bool {
+    return match (left, right) {
+        (Value::Null, Value::Null) => true,
+        (Value::Null, _) | (_, Value::Null) => false,
+        _ => collation.compare(left, right) == Ordering::Equal,
+    };
+}
+
+fn arithmetic(left: Numeric, op: BinaryOp, right: Numeric) -> Value {
+    if let (Numeric::Integer(l), Numeric::Integer(r)) = (left, right) {
+        let result = match op {
+            BinaryOp::Add => l.checked_add(r),
+            BinaryOp::Subtract => l.checked_sub(r),
+            _ => l.checked_mul(r),
+        };
+        // integer overflow falls back to floating point
+        if let Some(i) = result {
+            return Value::Int64(i);
+        }
+    }
+    let (l, r) = (left.as_real(), right.as_real());
+    let result = match op {
+        BinaryOp::Add => l + r,
+        BinaryOp::Subtract => l - r,
+        _ => l * r,
+    };
+    // e.g., infinity minus infinity
+    if result.is_nan() {
+        return Value::Null;
+    }
+    return Numeric::Real(result).into_value();
+}
+
+fn divide(left: Numeric, op: BinaryOp, right: Numeric) -> Value {
+    // division by zero gives NULL rather than an error
+    if right.as_real() == 0.0 {
+        return Value::Null;
+    }
+    if op == BinaryOp::Remainder {
+        let l = match left {
+            Numeric::Integer(i) => i,
+            Numeric::Real(f) => f as i64,
+        };
+        let r = match right {
+            Numeric::Integer(i) => i,
+            Numeric::Real(f) => f as i64,
+        };
+        if r == 0 {
+            return Value::Null;
+        }
+        let result = Value::Int64(l.wrapping_rem(r));
+        return match (left, right) {
+            (Numeric::Integer(_), Numeric::Integer(_)) => result,
+            _ => Value::Float(l.wrapping_rem(r) as f64),
+        };
+    }
+    if let (Numeric::Integer(l), Numeric::Integer(r)) = (left, right) {
+        if let Some(i) = l.checked_div(r) {
+            return Value::Int64(i);
+        }
+    }
+    let result = left.as_real() / right.as_real();
+    if result.is_nan() {
+        return Value::Null;
+    }
+    return Value::Float(result);
+}
+
+fn shift_left(value: i64, amount: i64) -> i64 {
+    if amount >= 64 {
+        return 0;
+    }
+    if amount <= -64 {
+        return if value < 0 { -1 } else { 0 };
+    }
+    if amount >= 0 {
+        return value << amount;
+    }
+    return value >> -amount;
+}
+
+/// Matches text against a LIKE pattern, where `%` matches any sequence
+/// of characters and `_` matches any single character. Matching is case
+/// insensitive for ASCII characters only, as in SQLite.
+pub fn like(pattern: &str, text: &str, escape: Option<char>) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    return like_rcrs(&pattern, &text, escape);
+}
+
+fn like_rcrs(pattern: &[char], text: &[char], escape: Option<char>) -> bool {
+    let mut p = 0;
+    let mut t = 0;
+    while p < pattern.len() {
+        let c = pattern[p];
+        if Some(c) == escape {
+            p += 1;
+            match (pattern.get(p), text.get(t)) {
+                (Some(pc), Some(tc)) if pc.eq_ignore_ascii_case(tc) => {}
+                _ => return false,
+            }
+        } else if c == '%' {
+            // collapse runs of wildcards
+            while p + 1 < pattern.len() && matches!(pattern[p + 1], '%' | '_') {
+                if pattern[p + 1] == '_' {
+                    if t >= text.len() {
+                        return false;
+                    }
+                    t += 1;
+                }
+                p += 1;
+            }
+            if p + 1 == pattern.len() {
+                return true;
+            }
+            return (t..=text.len())
+                .any(|start| like_rcrs(&pattern[p + 1..], &text[start..], escape));
+        } else if c == '_' {
+            if t >= text.len() {
+                return false;
+            }
+        } else {
+            match text.get(t) {
+                Some(tc) if c.eq_ignore_ascii_case(tc) => {}
+                _ => return false,
+            }
+        }
+        p += 1;
+        t += 1;
+    }
+    return t == text.len();
+}
+
+/// Matches text against a GLOB pattern, where `*` matches any sequence of
+/// characters, `?` matches any single character, and `[...]` matches
+/// one character from a set. Matching is case sensitive.
+pub fn glob(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    return glob_rcrs(&pattern, &text);
+}
+
+fn glob_rcrs(pattern: &[char], text: &[char]) -> bool {
+    let mut p = 0;
+    let mut t = 0;
+    while p < pattern.len() {
+        match pattern[p] {
+            '*' => {
+                while p + 1 < pattern.len() && pattern[p + 1] == '*' {
+                    p += 1;
+                }
+                if p + 1 == pattern.len() {
+                    return true;
+                }
+                return (t..=text.len()).any(|start| glob_rcrs(&pattern[p + 1..], &text[start..]));
+            }
+            '?' => {
+                if t >= text.len() {
+                    return false;
+                }
+            }
+            '[' => {
+                let c = match text.get(t) {
+                    Some(c) => *c,
+                    None => return false,
+                };
+                match match_char_class(&pattern[p + 1..], c) {
+                    Some((true, len)) => p += len,
+                    _ => return false,
+                }
+            }
+            c => {
+                if text.get(t) != Some(&c) {
+                    return false;
+                }
+            }
+        }
+        p += 1;
+        t += 1;
+    }
+    return t == text.len();
+}
+
+/// Matches a character against a `[...]` class, where `class` starts
+/// just after the opening bracket. Returns whether it matched and the
+/// length of the class including the closing bracket, or `None` if the
+/// class is not terminated.
+fn match_char_class(class: &[char], c: char) -> Option<(bool, usize)> {
+    let mut i = 0;
+    let invert = class.first() == Some(&'^');
+    if invert {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    while i < class.len() {
+        let start = class[i];
+        if start == ']' && !first {
+            return Some((matched != invert, i + 1));
+        }
+        first = false;
+        if class.get(i + 1) == Some(&'-') && class.get(i + 2).is_some_and(|e| *e != ']') {
+            if start <= c && c <= class[i + 2] {
+                matched = true;
+            }
+            i += 3;
+        } else {
+            if start == c {
+                matched = true;
+            }
+            i += 1;
+        }
+    }
+    return None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_expr;
+
+    fn eval(sql: &str) -> Value {
+        return eval_expr(&parse_expr(sql).unwrap(), &NoRow).unwrap();
+    }
+
+    #[test]
+    fn null_propagation() {
+        assert_eq!(eval("1 + NULL"), Value::Null);
+        assert_eq!(eval("NULL = NULL"), Value::Null);
+        assert_eq!(eval("NULL IS NULL"), Value::Integer1);
+        assert_eq!(eval("NULL AND 0"), Value::Integer0);
+        assert_eq!(eval("NULL OR 1"), Value::Integer1);
+        assert_eq!(eval("NOT NULL"), Value::Null);
+        assert_eq!(eval("5 / 0"), Value::Null);
+        assert_eq!(eval("NULL IN ()"), Value::Integer0);
+        assert_eq!(eval("NULL IN (1)"), Value::Null);
+        assert_eq!(eval("1 IN (NULL, 1)"), Value::Integer1);
+        assert_eq!(eval("2 NOT IN (NULL, 1)"), Value::Null);
+        assert_eq!(eval("5 BETWEEN 1 AND NULL"), Value::Null);
+        assert_eq!(eval("5 NOT BETWEEN 6 AND NULL"), Value::Integer1);
+    }
+
+    #[test]
+    fn operators() {
+        assert_eq!(eval("7 / 2"), Value::Int64(3));
+        assert_eq!(eval("7 / 2.0"), Value::Float(3.5));
+        assert_eq!(eval("'3abc' + 1"), Value::Int64(4));
+        assert_eq!(eval("1.0 || 'x'"), Value::String("1.0x".to_string()));
+        assert_eq!(eval("1 < 'a'"), Value::Integer1);
+        assert_eq!(eval("'abc' LIKE 'A%C'"), Value::Integer1);
+        assert_eq!(eval("'a_c' LIKE 'a\\_%' ESCAPE '\\'"), Value::Integer1);
+        assert_eq!(eval("'abc' GLOB 'A*'"), Value::Integer0);
+        assert_eq!(eval("'abc' GLOB '[a-c]?[^d]'"), Value::Integer1);
+    }
+
+    #[test]
+    fn overflow() {
+        let real = |sql| match eval(sql) {
+            Value::Float(f) => f,
+            value => panic!("{} is not real: {:?}", sql, value),
+        };
+        let max = i64::MAX;
+        assert_eq!(real("9223372036854775807 + 1"), max as f64);
+        assert_eq!(real("-9223372036854775808 - 1"), i64::MIN as f64);
+        assert_eq!(real("4611686018427387904 * 2"), max as f64);
+        assert_eq!(real("-9223372036854775808 * -1"), max as f64);
+        assert_eq!(real("-9223372036854775808 / -1"), max as f64);
+        assert_eq!(real("-(-9223372036854775808)"), max as f64);
+        assert_eq!(real("1e308 * 10"), f64::INFINITY);
+        assert!(matches!(
+            eval("-9223372036854775808"),
+            Value::Int64(i64::MIN)
+        ));
+        assert!(matches!(
+            eval("-9223372036854775807 - 1"),
+            Value::Int64(i64::MIN)
+        ));
+        assert!(matches!(eval("3037000499 * 3037000499"), Value::Int64(_)));
+        assert!(matches!(eval("-9223372036854775808 % -1"), Value::Int64(0)));
+        assert_eq!(eval("1e400 - 1e400"), Value::Null);
+        assert_eq!(eval("0 * 1e400"), Value::Null);
+        assert!(eval_expr(&parse_expr("abs(-9223372036854775808)").unwrap(), &NoRow).is_err());
+
+        // division and remainder by zero are NULL
+        assert_eq!(eval("5 / 0"), Value::Null);
+        assert_eq!(eval("5.0 / 0"), Value::Null);
+        assert_eq!(eval("5 % 0"), Value::Null);
+        assert_eq!(eval("5 % 0.5"), Value::Null);
+        assert_eq!(eval("5 % 'a'"), Value::Null);
+
+        // the remainder has the sign of the dividend, and is real if
+        // either operand is
+        assert!(matches!(eval("-7 % 3"), Value::Int64(-1)));
+        assert!(matches!(eval("7 % -3"), Value::Int64(1)));
+        assert_eq!(real("5.5 % 2"), 1.0);
+        assert_eq!(real("-7.5 % 2"), -1.0);
+        assert!(matches!(eval("'9' % '4'"), Value::Int64(1)));
+        assert!(matches!(eval("7 / -2"), Value::Int64(-3)));
+    }
+
+    #[test]
+    fn collations() {
+        let text = |s: &str| Value::String(s.to_string());
+        let nocase = Collation::from_name("nocase", &Collations::default()).unwrap();
+        assert_eq!(nocase.compare(&text("ABC"), &text("abc")), Ordering::Equal);
+        assert_eq!(nocase.compare(&text("a"), &text("B")), Ordering::Less);
+        assert_eq!(
+            Collation::Binary.compare(&text("a"), &text("B")),
+            Ordering::Greater
+        );
+        assert_eq!(
+            Collation::RTrim.compare(&text("a  "), &text("a")),
+            Ordering::Equal
+        );
+        assert_eq!(nocase.compare(&Value::Int64(1), &text("a")), Ordering::Less);
+        assert!(Collation::from_name("foo", &Collations::default()).is_err());
+    }
+
+    #[test]
+    fn registered_collations() {
+        let text = |s: &str| Value::String(s.to_string());
+        let reverse = |left: &str, right: &str| right.cmp(left);
+        let mut conn = crate::connection::Connection::deserialize(Vec::new()).unwrap();
+        conn.register_collation("reverse", reverse).unwrap();
+        assert!(conn.register_collation("NoCase", reverse).is_err());
+        conn.execute("CREATE TABLE t(a TEXT COLLATE REVERSE, b TEXT COLLATE NOCASE)")
+            .unwrap();
+        conn.execute("INSERT INTO t VALUES ('a', 'X'), ('c', 'y'), ('b', 'x')")
+            .unwrap();
+        conn.execute("CREATE INDEX t_b ON t(b)").unwrap();
+        let result = conn.execute("SELECT a FROM t ORDER BY a").unwrap();
+        assert_eq!(result.rows, [[text("c")], [text("b")], [text("a")]]);
+
+        // comparisons with a column use its collating sequence, so the
+        // index on it can be searched
+        let result = conn.execute("SELECT a FROM t WHERE b = 'x'").unwrap();
+        assert_eq!(result.rows, [[text("a")], [text("b")]]);
+        let plan = conn
+            .execute("EXPLAIN QUERY PLAN SELECT a FROM t WHERE b = 'x'")
+            .unwrap();
+        assert_eq!(plan.rows[0][3], text("SEARCH t USING INDEX t_b (b=?)"));
+        let result = conn.execute("SELECT a FROM t WHERE 'X' = b").unwrap();
+        assert_eq!(result.rows.len(), 2);
+
+        // collating sequences belong to the connection they were added to
+        let mut other = crate::connection::Connection::deserialize(Vec::new()).unwrap();
+        let err = other
+            .execute("CREATE TABLE u(a TEXT COLLATE reverse)")
+            .and_then(|_| other.execute("SELECT a FROM u ORDER BY a"))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "no such collation sequence: reverse");
+    }
+
+    #[test]
+    fn affinity_and_cast() {
+        let text = |s: &str| Value::String(s.to_string());
+        let affinity = |name| Affinity::from_type_name(Some(name));
+        assert_eq!(affinity("VARCHAR(255)"), Affinity::Text);
+        assert_eq!(affinity("CHARINT"), Affinity::Integer);
+        assert_eq!(affinity("FLOATING POINT"), Affinity::Integer);
+        assert_eq!(affinity("DOUBLE"), Affinity::Real);
+        assert_eq!(affinity("DECIMAL(10,5)"), Affinity::Numeric);
+        assert_eq!(Affinity::from_type_name(None), Affinity::Blob);
+
+        let numeric = Affinity::Numeric;
+        assert!(matches!(numeric.apply(text(" 1e3 ")), Value::Int64(1000)));
+        assert!(matches!(numeric.apply(Value::Float(3.0)), Value::Int64(3)));
+        assert!(matches!(numeric.apply(text("1.5")), Value::Float(_)));
+        assert!(matches!(numeric.apply(text("0x10")), Value::String(_)));
+        assert!(matches!(
+            Affinity::Real.apply(Value::Int64(3)),
+            Value::Float(_)
+        ));
+        assert_eq!(Affinity::Text.apply(Value::Float(4.0)), text("4.0"));
+        assert!(matches!(Affinity::Blob.apply(text("5")), Value::String(_)));
+
+        assert!(matches!(eval("CAST('1e3' AS INTEGER)"), Value::Int64(1)));
+        assert!(matches!(eval("CAST(-3.9 AS INT)"), Value::Int64(-3)));
+        assert!(matches!(eval("CAST('5.' AS NUMERIC)"), Value::Int64(5)));
+        assert!(matches!(eval("CAST(3.0 AS NUMERIC)"), Value::Float(_)));
+        assert_eq!(eval("CAST('abc' AS REAL)"), Value::Float(0.0));
+        assert_eq!(eval("CAST(12 AS BLOB)"), Value::Blob(b"12".to_vec()));
+        assert_eq!(eval("CAST(1e20 AS TEXT)"), text("1.0e+20"));
+        assert_eq!(eval("CAST(NULL AS TEXT)"), Value::Null);
+        assert_eq!(
+            eval("CAST('99999999999999999999' AS INTEGER)"),
+            Value::Int64(i64::MAX)
+        );
+    }
+}
@@ -0,0 +1,346 @@
+use eyre::{eyre, Result};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::ast::Expr;
+use crate::btree::SampleRng;
+use crate::datatypes::Value;
+use crate::exec::{self, Numeric};
+use crate::limits::Limits;
+
+/// A built-in scalar function, which computes one value from the values
+/// of its arguments.
+pub struct ScalarFunction {
+    pub name: &'static str,
+    pub min_args: usize,
+    /// `None` if the function takes any number of arguments.
+    pub max_args: Option<usize>,
+    func: fn(&[Value]) -> Result<Value>,
+}
+
+impl ScalarFunction {
+    fn accepts(&self, num_args: usize) -> bool {
+        return num_args >= self.min_args && self.max_args.is_none_or(|max| num_args <= max);
+    }
+}
+
+const BUILTINS: &[ScalarFunction] = &[
+    ScalarFunction {
+        name: "abs",
+        min_args: 1,
+        max_args: Some(1),
+        func: abs,
+    },
+    ScalarFunction {
+        name: "coalesce",
+        min_args: 2,
+        max_args: None,
+        func: coalesce,
+    },
+    ScalarFunction {
+        name: "length",
+        min_args: 1,
+        max_args: Some(1),
+        func: length,
+    },
+    ScalarFunction {
+        name: "lower",
+        min_args: 1,
+        max_args: Some(1),
+        func: lower,
+    },
+    ScalarFunction {
+        name: "random",
+        min_args: 0,
+        max_args: Some(0),
+        func: random,
+    },
+    ScalarFunction {
+        name: "randomblob",
+        min_args: 1,
+        max_args: Some(1),
+        func: random_blob,
+    },
+    ScalarFunction {
+        name: "typeof",
+        min_args: 1,
+        max_args: Some(1),
+        func: type_of,
+    },
+    ScalarFunction {
+        name: "upper",
+        min_args: 1,
+        max_args: Some(1),
+        func: upper,
+    },
+];
+
+thread_local! {
+    /// The generator random() and randomblob() draw from: that of the
+    /// connection running the current statement (see `with_rng`), or one
+    /// seeded from the clock outside of any statement.
+    static RNG: RefCell<Option<Rc<RefCell<SampleRng>>>> = const { RefCell::new(None) };
+}
+
+/// Runs `f` with random() and randomblob() drawing from `rng`, which is
+/// how a connection's seed (see `Connection::set_random_seed`) makes the
+/// statements it runs reproducible.
+pub fn with_rng<T, F>(rng: Rc<RefCell<SampleRng>>, f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    let outer = RNG.with(|current| current.replace(Some(rng)));
+    let value = f();
+    RNG.with(|current| current.replace(outer));
+    return value;
+}
+
+/// A generator seeded from the clock, for a connection that has not been
+/// given a seed.
+pub fn clock_rng() -> SampleRng {
+    let seed = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |time| time.as_nanos() as u64);
+    return SampleRng::new(seed);
+}
+
+fn next_random() -> u64 {
+    return RNG.with(|current| {
+        let rng = current
+            .borrow_mut()
+            .get_or_insert_with(|| Rc::new(RefCell::new(clock_rng())))
+            .clone();
+        let value = rng.borrow_mut().next_u64();
+        return value;
+    });
+}
+
+/// Finds the built-in scalar function with the given name, if any.
+pub fn lookup(name: &str) -> Option<&'static ScalarFunction> {
+    return BUILTINS
+        .iter()
+        .find(|func| func.name.eq_ignore_ascii_case(name));
+}
+
+/// Checks that a call to a function names a known function and passes it
+/// an acceptable number of arguments.
+fn check_call(name: &str, num_args: usize, star: bool) -> Result<&'static ScalarFunction> {
+    let func = lookup(name).ok_or_else(|| eyre!("no such function: {}", name))?;
+    if star || !func.accepts(num_args) {
+        return Err(eyre!("wrong number of arguments to function {}()", name));
+    }
+    return Ok(func);
+}
+
+/// Checks every function call in an expression, so that bad calls are
+/// reported when a statement is run even if no rows are evaluated.
+/// Subqueries are checked when they are run.
+pub fn check_calls(expr: &Expr) -> Result<()> {
+    match expr {
+        Expr::Literal(_)
+        | Expr::Column { .. }
+        | Expr::Parameter(_)
+        | Expr::Subquery(_)
+        | Expr::Exists(_) => (),
+        Expr::Unary { expr, .. } | Expr::IsNull { expr, .. } | Expr::Cast { expr, .. } => {
+            check_calls(expr)?
+        }
+        Expr::Binary { left, right, .. } => {
+            check_calls(left)?;
+            check_calls(right)?;
+        }
+        Expr::Like {
+            expr,
+            pattern,
+            escape,
+            ..
+        } => {
+            check_calls(expr)?;
+            check_calls(pattern)?;
+            if let Some(escape) = escape {
+                check_calls(escape)?;
+            }
+        }
+        Expr::Between {
+            expr, low, high, ..
+        } => {
+            check_calls(expr)?;
+            check_calls(low)?;
+            check_calls(high)?;
+        }
+        Expr::In { expr, list, .. } => {
+            check_calls(expr)?;
+            for item in list {
+                check_calls(item)?;
+            }
+        }
+        Expr::Function {
+            name, args, star, ..
+        } => {
+            check_call(name, args.len(), *star)?;
+            for arg in args {
+                check_calls(arg)?;
+            }
+        }
+    }
+    return Ok(());
+}
+
+/// Calls a scalar function with the values of its arguments.
+pub fn call(name: &str, args: &[Value], star: bool) -> Result<Value> {
+    let func = check_call(name, args.len(), star)?;
+    return (func.func)(args);
+}
+
+/// `abs(X)`: the absolute value of X. Text and blobs are converted to a
+/// real number.
+fn abs(args: &[Value]) -> Result<Value> {
+    return match &args[0] {
+        Value::Null => Ok(Value::Null),
+        Value::String(_) | Value::Blob(_) | Value::Internal(_) => {
+            let n = exec::to_numeric(&args[0]).unwrap();
+            let f = match n {
+                Numeric::Integer(i) => i as f64,
+                Numeric::Real(f) => f,
+            };
+            Ok(Value::Float(f.abs()))
+        }
+        Value::Float(f) => Ok(Value::Float(f.abs())),
+        value => {
+            let i = value.get_int_val().unwrap();
+            let abs = i.checked_abs().ok_or_else(|| eyre!("integer overflow"))?;
+            Ok(Value::Int64(abs))
+        }
+    };
+}
+
+/// `coalesce(X, Y, ...)`: the first argument that is not NULL.
+fn coalesce(args: &[Value]) -> Result<Value> {
+    return Ok(args
+        .iter()
+        .find(|value| !matches!(value, Value::Null))
+        .cloned()
+        .unwrap_or(Value::Null));
+}
+
+/// `length(X)`: the number of characters in a string, up to the first
+/// NUL character, or the number of bytes in a blob. Numbers are measured
+/// as text.
+fn length(args: &[Value]) -> Result<Value> {
+    let len = match &args[0] {
+        Value::Null => return Ok(Value::Null),
+        Value::Blob(b) | Value::Internal(b) => b.len(),
+        value => exec::to_text(value)
+            .unwrap()
+            .chars()
+            .take_while(|c| *c != '\0')
+            .count(),
+    };
+    return Ok(Value::Int64(len as i64));
+}
+
+/// `lower(X)`: X as text, with ASCII characters converted to lower case.
+fn lower(args: &[Value]) -> Result<Value> {
+    return Ok(match exec::to_text(&args[0]) {
+        Some(text) => Value::String(text.to_ascii_lowercase()),
+        None => Value::Null,
+    });
+}
+
+/// `upper(X)`: X as text, with ASCII characters converted to upper case.
+/// `random()`: a pseudo-random integer, anywhere in the range of a
+/// 64-bit signed integer.
+fn random(_args: &[Value]) -> Result<Value> {
+    return Ok(Value::Int64(next_random() as i64));
+}
+
+/// `randomblob(N)`: a blob of N pseudo-random bytes, or of one byte if N
+/// is less than 1.
+fn random_blob(args: &[Value]) -> Result<Value> {
+    let len = match &args[0] {
+        Value::Null => 1,
+        value => exec::to_numeric(value).map_or(1, |n| match n {
+            Numeric::Integer(i) => i,
+            Numeric::Real(f) => f as i64,
+        }),
+    };
+    let len = std::cmp::max(len, 1) as usize;
+    if len > Limits::default().max_record_size {
+        return Err(eyre!("string or blob too big"));
+    }
+    let mut bytes = Vec::with_capacity(len + 7);
+    while bytes.len() < len {
+        bytes.extend(next_random().to_le_bytes().iter());
+    }
+    bytes.truncate(len);
+    return Ok(Value::Blob(bytes));
+}
+
+/// `typeof(X)`: the name of the storage class of X: "null", "integer",
+/// "real", "text" or "blob".
+fn type_of(args: &[Value]) -> Result<Value> {
+    let name = match &args[0] {
+        Value::Null => "null",
+        Value::Float(_) => "real",
+        Value::String(_) => "text",
+        Value::Blob(_) | Value::Internal(_) => "blob",
+        _ => "integer",
+    };
+    return Ok(Value::String(name.to_string()));
+}
+
+fn upper(args: &[Value]) -> Result<Value> {
+    return Ok(match exec::to_text(&args[0]) {
+        Some(text) => Value::String(text.to_ascii_uppercase()),
+        None => Value::Null,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn null_handling() {
+        for name in &["abs", "length", "lower", "upper"] {
+            assert_eq!(call(name, &[Value::Null], false).unwrap(), Value::Null);
+        }
+        let args = [Value::Null, Value::Int64(2), Value::Int64(3)];
+        assert_eq!(call("COALESCE", &args, false).unwrap(), Value::Int64(2));
+        assert_eq!(
+            call("coalesce", &[Value::Null, Value::Null], false).unwrap(),
+            Value::Null
+        );
+
+        assert_eq!(
+            call("length", &[Value::Float(12.5)], false).unwrap(),
+            Value::Int64(4)
+        );
+        assert_eq!(
+            call("abs", &[Value::String("-5".to_string())], false).unwrap(),
+            Value::Float(5.0)
+        );
+        assert!(call("abs", &[Value::Int64(i64::MIN)], false).is_err());
+        assert!(call("coalesce", &[Value::Null], false).is_err());
+        assert!(call("length", &[], true).is_err());
+        assert!(call("nosuch", &[], false).is_err());
+    }
+
+    #[test]
+    fn seeded_random() {
+        let draw = |seed| {
+            let rng = Rc::new(RefCell::new(SampleRng::new(seed)));
+            return with_rng(rng, || {
+                let int = call("random", &[], false).unwrap();
+                let blob = call("randomblob", &[Value::Int64(10)], false).unwrap();
+                return (int, blob);
+            });
+        };
+        assert_eq!(draw(1), draw(1));
+        assert_ne!(draw(1), draw(2));
+        let (_, blob) = draw(1);
+        assert_eq!(call("length", &[blob], false).unwrap(), Value::Int64(10));
+        let blob = call("randomblob", &[Value::Int64(-5)], false).unwrap();
+        assert_eq!(call("length", &[blob], false).unwrap(), Value::Int64(1));
+    }
+}
@@ -0,0 +1,570 @@
+use eyre::{eyre, Result};
+use std::collections::HashSet;
+
+use crate::ast::{ColumnConstraint, Insert, InsertSource};
+use crate::btree::{Record, SampleRng};
+use crate::connection::Connection;
+use crate::datatypes::{Value, VarInt};
+use crate::ddl::{self, TableIndex};
+use crate::exec::{self, NoRow};
+use crate::functions;
+use crate::replication::Change;
+use crate::select;
+use crate::statement::QueryResult;
+use crate::table::Table;
+
+pub fn execute_insert(conn: &mut Connection, insert: &Insert) -> Result<QueryResult> {
+    return execute_inserts(conn, std::slice::from_ref(insert));
+}
+
+/// Runs a series of INSERTs into the same table, writing their rows as a
+/// single statement. If the table is empty, its B-tree is built from the
+/// rows in one pass rather than by inserting them one at a time. If an
+/// INSERT fails, the rows of those before it are still written, as if
+/// each had been run on its own. Every index of the table is given
+/// entries for the new rows.
+pub fn execute_inserts(conn: &mut Connection, inserts: &[Insert]) -> Result<QueryResult> {
+    let first = match inserts.first() {
+        Some(first) => first,
+        None => return Ok(QueryResult::default()),
+    };
+    if conn.schema.view(&first.table).is_some() {
+        return Err(eyre!("cannot modify {} because it is a view", first.table));
+    }
+    let table = conn.table(&first.table)?;
+    if table.schema.name == "sqlite_schema" {
+        return Err(eyre!("table {} may not be modified", first.table));
+    }
+    let indexes = ddl::table_indexes(conn, &table.schema)?;
+
+    let mut records = Vec::new();
+    // the row IDs given by the INSERTs so far
+    let mut taken = HashSet::new();
+    let mut error = None;
+    for insert in inserts {
+        if !insert.table.eq_ignore_ascii_case(&first.table) {
+            error = Some(eyre!("INSERTs run together must be into the same table"));
+            break;
+        }
+        // an INSERT that fails adds none of its rows
+        match build_records(conn, &table, insert, &taken) {
+            // a row may clash with the index entry of a row before it,
+            // so each INSERT is written on its own, to be undone alone
+            Ok(rows) if !indexes.is_empty() => {
+                if let Err(err) = write_rows(conn, &table, &indexes, rows) {
+                    error = Some(err);
+                    break;
+                }
+            }
+            Ok(rows) => {
+                taken.extend(rows.iter().filter_map(|(row_id, _)| *row_id));
+                records.extend(rows);
+            }
+            Err(err) => {
+                error = Some(err);
+                break;
+            }
+        }
+    }
+
+    if !records.is_empty() {
+        write_rows(conn, &table, &indexes, records)?;
+    }
+    return match error {
+        Some(err) => Err(err),
+        None => Ok(QueryResult::default()),
+    };
+}
+
+/// Writes new rows to a table and its indexes as one statement, giving
+/// row IDs to those that have none. If the table is empty, its B-tree is
+/// built from the rows in one pass.
+fn write_rows(
+    conn: &mut Connection,
+    table: &Table,
+    indexes: &[TableIndex],
+    records: Vec<(Option<i64>, Record)>,
+) -> Result<()> {
+    return conn.write_statement(|conn| {
+        let given: Vec<Option<i64>> = records.iter().map(|(row_id, _)| *row_id).collect();
+        let row_ids = allocate_row_ids(conn, table, &given)?;
+        let mut rows: Vec<(i64, Record)> = row_ids
+            .into_iter()
+            .zip(records.into_iter().map(|(_, record)| record))
+            .collect();
+        if rows.len() > 1 && table.btree.is_empty()? {
+            // row IDs given in the INSERTs may be in any order
+            rows.sort_by_key(|(row_id, _)| *row_id);
+            table.btree.build_table(&rows)?;
+        } else {
+            for (row_id, record) in &rows {
+                table.btree.insert(*row_id, record)?;
+            }
+        }
+        for (row_id, record) in &rows {
+            for index in indexes {
+                index.insert_row(&table.schema, *row_id, record)?;
+            }
+        }
+        for (row_id, record) in rows {
+            conn.record_change(|| Change::Insert {
+                table: table.schema.name.clone(),
+                row_id: row_id,
+                values: record.values,
+            })?;
+        }
+        return Ok(());
+    });
+}
+
+/// Picks the row IDs of new rows of a table, given the row IDs the
+/// INSERT gave for them, if any. The others follow on from the largest
+/// row ID in the table so far, found from its right-most leaf page, or
+/// for an AUTOINCREMENT table, from the largest it has ever had, as kept
+/// in sqlite_sequence (which is updated to match). If the largest
+/// possible row ID has been used, an AUTOINCREMENT table is full, and
+/// other tables try unused row IDs picked at random, as SQLite does.
+fn allocate_row_ids(
+    conn: &mut Connection,
+    table: &Table,
+    given: &[Option<i64>],
+) -> Result<Vec<i64>> {
+    let full = || eyre!("database or disk is full");
+    let autoincrement = ddl::is_autoincrement(&table.schema.definition);
+    let sequence = if autoincrement {
+        read_sequence(conn, &table.schema.name)?
+    } else {
+        None
+    };
+    let mut last = std::cmp::max(table.btree.max_row_id()?, sequence.unwrap_or(0));
+    let mut row_ids: Vec<i64> = Vec::with_capacity(given.len());
+    for row_id in given {
+        let row_id = match row_id {
+            Some(row_id) => *row_id,
+            None if last < i64::MAX => last + 1,
+            None if autoincrement => return Err(full()),
+            None => {
+                let mut rng = conn.rng.borrow_mut();
+                random_row_id(table, &mut rng, &row_ids, given).ok_or_else(full)?
+            }
+        };
+        last = std::cmp::max(last, row_id);
+        row_ids.push(row_id);
+    }
+    if autoincrement && sequence.is_none_or(|sequence| sequence < last) {
+        write_sequence(conn, &table.schema.name, std::cmp::max(last, 0))?;
+    }
+    return Ok(row_ids);
+}
+
+/// Picks a row ID at random that is not in the table, nor among those of
+/// the other new rows, giving up after 100 tries.
+fn random_row_id(
+    table: &Table,
+    rng: &mut SampleRng,
+    picked: &[i64],
+    given: &[Option<i64>],
+) -> Option<i64> {
+    for _ in 0..100 {
+        let row_id = std::cmp::max((rng.next_u64() >> 1) as i64, 1);
+        if !picked.contains(&row_id)
+            && !given.contains(&Some(row_id))
+            && table.btree.get_row(VarInt::new(row_id)).is_none()
+        {
+            return Some(row_id);
+        }
+    }
+    return None;
+}
+
+/// The largest row ID an AUTOINCREMENT table has had, if it has had any
+/// rows.
+fn read_sequence(conn: &Connection, name: &str) -> Result<Option<i64>> {
+    let sequence = conn.table("sqlite_sequence")?;
+    for row in sequence.btree.scan() {
+        let (_, record) = row?;
+        if is_sequence_of(&record, name) {
+            let seq = record.values.get(1).and_then(|v| v.get_int_val());
+            return Ok(Some(seq.unwrap_or(0)));
+        }
+    }
+    return Ok(None);
+}
+
+/// Sets the largest row ID an AUTOINCREMENT table has had.
+fn write_sequence(conn: &mut Connection, name: &str, seq: i64) -> Result<()> {
+    let sequence = conn.table("sqlite_sequence")?;
+    let mut existing = None;
+    for row in sequence.btree.scan() {
+        let (row_id, record) = row?;
+        if is_sequence_of(&record, name) {
+            existing = Some(row_id.0);
+            break;
+        }
+    }
+    let row_id = match existing {
+        Some(row_id) => {
+            sequence.btree.delete(row_id)?;
+            conn.record_change(|| Change::Delete {
+                table: "sqlite_sequence".to_string(),
+                row_id: row_id,
+            })?;
+            row_id
+        }
+        None => sequence.btree.max_row_id()? + 1,
+    };
+    let values = vec![Value::String(name.to_string()), Value::Int64(seq)];
+    sequence
+        .btree
+        .insert(row_id, &Record::from_values(values.clone()))?;
+    return conn.record_change(|| Change::Insert {
+        table: "sqlite_sequence".to_string(),
+        row_id: row_id,
+        values: values,
+    });
+}
+
+fn is_sequence_of(record: &Record, name: &str) -> bool {
+    return match record.values.first() {
+        Some(Value::String(s)) => s.eq_ignore_ascii_case(name),
+        _ => false,
+    };
+}
+
+/// Works out the records an INSERT adds to a table, along with the row
+/// IDs it gives them, if any, checking their constraints. `taken` holds
+/// the row IDs given to other rows not yet in the table.
+fn build_records(
+    conn: &Connection,
+    table: &Table,
+    insert: &Insert,
+    taken: &HashSet<i64>,
+) -> Result<Vec<(Option<i64>, Record)>> {
+    let (rows, num_values) = match &insert.source {
+        InsertSource::Values(rows) => {
+            let mut values = Vec::with_capacity(rows.len());
+            for row in rows {
+                let mut row_values = Vec::with_capacity(row.len());
+                for expr in row {
+                    functions::check_calls(expr)?;
+                    let mut expr = expr.clone();
+                    select::eval_subqueries(conn, &mut expr)?;
+                    row_values.push(exec::eval_expr(&expr, &NoRow)?);
+                }
+                values.push(row_values);
+            }
+            (values, rows[0].len())
+        }
+        InsertSource::Select(select) => {
+            let result = select::execute_select(conn, select)?;
+            (result.rows, result.columns.len())
+        }
+    };
+    let positions = value_positions(table, insert.columns.as_deref(), num_values)?;
+    let mut records = Vec::with_capacity(rows.len());
+    let mut given = HashSet::new();
+    for row in rows {
+        let (row_id, record) = build_record(table, &positions, row)?;
+        if let Some(row_id) = row_id {
+            let exists = taken.contains(&row_id)
+                || !given.insert(row_id)
+                || table.btree.get_row(VarInt::new(row_id)).is_some();
+            if exists {
+                let alias = ddl::row_id_alias(&table.schema.definition).unwrap();
+                return Err(eyre!(
+                    "UNIQUE constraint failed: {}.{}",
+                    table.schema.name,
+                    table.schema.columns()[alias].name
+                ));
+            }
+        }
+        records.push((row_id, record));
+    }
+    return Ok(records);
+}
+
+/// Finds the position in a table's records of each value an INSERT
+/// gives, which is the order of the columns listed, or of the table's
+/// columns if none are.
+fn value_positions(
+    table: &Table,
+    columns: Option<&[String]>,
+    num_values: usize,
+) -> Result<Vec<usize>> {
+    return match columns {
+        Some(columns) => {
+            if columns.len() != num_values {
+                return Err(eyre!("{} values for {} columns", num_values, columns.len()));
+            }
+            let mut positions = Vec::with_capacity(columns.len());
+            for name in columns {
+                let idx = table.schema.column_index(name).ok_or_else(|| {
+                    eyre!("table {} has no column named {}", table.schema.name, name)
+                })?;
+                positions.push(idx);
+            }
+            Ok(positions)
+        }
+        None => {
+            let num_columns = table.schema.columns().len();
+            if num_values != num_columns {
+                return Err(eyre!(
+                    "table {} has {} columns but {} values were supplied",
+                    table.schema.name,
+                    num_columns,
+                    num_values
+                ));
+            }
+            Ok((0..num_columns).collect())
+        }
+    };
+}
+
+/// Builds the record for one row of an INSERT, given the values for the
+/// columns at `positions`, and checks its constraints. A value given for
+/// the column that is an alias for the row ID is returned as the row's
+/// row ID, with NULL in its place in the record.
+fn build_record(
+    table: &Table,
+    positions: &[usize],
+    row: Vec<Value>,
+) -> Result<(Option<i64>, Record)> {
+    // columns that are not given take their default value, or NULL
+    let mut values = Vec::with_capacity(table.schema.columns().len());
+    for idx in 0..table.schema.columns().len() {
+        values.push(table.schema.column_default(idx)?);
+    }
+    for (value, idx) in row.into_iter().zip(positions) {
+        values[*idx] = value;
+    }
+
+    let mut values: Vec<Value> = values
+        .into_iter()
+        .enumerate()
+        .map(|(idx, value)| table.schema.column_affinity(idx).apply(value))
+        .collect();
+    // a NULL for the row ID alias is replaced by a new row ID, so is
+    // allowed even if the column is NOT NULL
+    let row_id_alias = ddl::row_id_alias(&table.schema.definition);
+    for (idx, (col, value)) in table.schema.columns().iter().zip(values.iter()).enumerate() {
+        let not_null = col
+            .constraints
+            .iter()
+            .any(|c| matches!(c, ColumnConstraint::NotNull));
+        if not_null && *value == Value::Null && Some(idx) != row_id_alias {
+            return Err(eyre!(
+                "NOT NULL constraint failed: {}.{}",
+                table.schema.name,
+                col.name
+            ));
+        }
+    }
+
+    let row_id = match row_id_alias {
+        Some(idx) => match std::mem::replace(&mut values[idx], Value::Null) {
+            Value::Null => None,
+            value => Some(
+                value
+                    .get_int_val()
+                    .ok_or_else(|| eyre!("datatype mismatch"))?,
+            ),
+        },
+        None => None,
+    };
+    return Ok((row_id, Record::from_values(values)));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::connection::Connection;
+    use crate::datatypes::Value;
+
+    #[test]
+    fn insert_rows() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        conn.execute("CREATE TABLE t(a INTEGER, b TEXT DEFAULT 'd')")
+            .unwrap();
+        conn.execute("INSERT INTO t VALUES (1, 'x'), ('2', 2), (3, NULL)")
+            .unwrap();
+        conn.execute("INSERT INTO t (a) SELECT a + 10 FROM t")
+            .unwrap();
+        let result = conn.execute("SELECT a, b FROM t").unwrap();
+        let text = |s: &str| Value::String(s.to_string());
+        assert_eq!(
+            result.rows,
+            [
+                [Value::Int64(1), text("x")],
+                [Value::Int64(2), text("2")],
+                [Value::Int64(3), Value::Null],
+                [Value::Int64(11), text("d")],
+                [Value::Int64(12), text("d")],
+                [Value::Int64(13), text("d")],
+            ]
+        );
+
+        // an INSERT that fails part way adds none of its rows
+        conn.execute("CREATE TABLE u(x NOT NULL)").unwrap();
+        assert!(conn.execute("INSERT INTO u VALUES (1), (NULL)").is_err());
+        assert!(conn.execute("INSERT INTO u SELECT a, b FROM t").is_err());
+        assert!(conn.table("u").unwrap().btree.list_records().is_empty());
+    }
+
+    #[test]
+    fn insert_indexed() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        conn.execute("CREATE TABLE t(id TEXT PRIMARY KEY, a UNIQUE, b)")
+            .unwrap();
+        conn.execute("CREATE INDEX t_b ON t(b DESC, a)").unwrap();
+        conn.execute("CREATE INDEX t_a ON t(a) WHERE a % 2 = 0")
+            .unwrap();
+        let rows: Vec<String> = (1..=400)
+            .map(|i| format!("('k{}', {}, '{}')", i, i, "x".repeat(i % 70)))
+            .collect();
+        conn.execute(&format!("INSERT INTO t VALUES {}", rows.join(", ")))
+            .unwrap();
+        conn.execute("INSERT INTO t VALUES ('n1', NULL, 1), ('n2', NULL, 2)")
+            .unwrap();
+        let check = conn.execute("PRAGMA integrity_check").unwrap();
+        assert_eq!(check.rows, [[Value::String("ok".to_string())]]);
+        let result = conn.execute("SELECT id FROM t WHERE a = 250").unwrap();
+        assert_eq!(result.rows, [[Value::String("k250".to_string())]]);
+
+        // a row whose key is taken adds none of the INSERT's rows
+        let err = conn
+            .execute("INSERT INTO t VALUES ('k401', 401, 'y'), ('k402', 7, 'y')")
+            .unwrap_err();
+        assert_eq!(err.to_string(), "UNIQUE constraint failed: t.a");
+        let err = conn
+            .execute("INSERT INTO t VALUES ('k3', 0, 'y')")
+            .unwrap_err();
+        assert_eq!(err.to_string(), "UNIQUE constraint failed: t.id");
+        let result = conn.execute("SELECT count(*) FROM t").unwrap();
+        assert_eq!(result.rows, [[Value::Int64(402)]]);
+        conn.execute("DELETE FROM t WHERE a > 100").unwrap();
+        conn.execute("INSERT INTO t VALUES ('k300', 300, 'z')")
+            .unwrap();
+        let check = conn.execute("PRAGMA integrity_check").unwrap();
+        assert_eq!(check.rows, [[Value::String("ok".to_string())]]);
+    }
+
+    #[test]
+    fn row_ids() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        conn.execute("CREATE TABLE a(id INTEGER PRIMARY KEY AUTOINCREMENT, x)")
+            .unwrap();
+        conn.execute("CREATE TABLE b(id INTEGER PRIMARY KEY, x)")
+            .unwrap();
+        assert!(conn
+            .execute("CREATE TABLE c(id INT PRIMARY KEY AUTOINCREMENT)")
+            .is_err());
+        for table in ["a", "b"] {
+            conn.execute(&format!("INSERT INTO {} (x) VALUES (1), (2), (3)", table))
+                .unwrap();
+            conn.execute(&format!("DELETE FROM {} WHERE x = 3", table))
+                .unwrap();
+            conn.execute(&format!("INSERT INTO {} (x) VALUES (4)", table))
+                .unwrap();
+        }
+        let row_ids = |conn: &Connection, table: &str| -> Vec<i64> {
+            let records = conn.table(table).unwrap().btree.list_records();
+            return records.iter().map(|(row_id, _)| row_id.0).collect();
+        };
+        // AUTOINCREMENT row IDs are not reused
+        assert_eq!(row_ids(&conn, "a"), [1, 2, 4]);
+        assert_eq!(row_ids(&conn, "b"), [1, 2, 3]);
+        let result = conn.execute("SELECT * FROM sqlite_sequence").unwrap();
+        assert_eq!(
+            result.rows,
+            [[Value::String("a".to_string()), Value::Int64(4)]]
+        );
+
+        // a value for an INTEGER PRIMARY KEY is the row ID, and is read
+        // back from it
+        conn.execute("INSERT INTO b VALUES ('10', 'x'), (NULL, 'y')")
+            .unwrap();
+        conn.execute("INSERT INTO a VALUES (20, 'z')").unwrap();
+        assert_eq!(row_ids(&conn, "b"), [1, 2, 3, 10, 11]);
+        let records = conn.table("b").unwrap().btree.list_records();
+        assert!(records.iter().all(|(_, r)| r.values[0] == Value::Null));
+        let result = conn.execute("SELECT id, x FROM b WHERE id > 3").unwrap();
+        let text = |s: &str| Value::String(s.to_string());
+        assert_eq!(
+            result.rows,
+            [[Value::Int64(10), text("x")], [Value::Int64(11), text("y")]]
+        );
+        let result = conn.execute("SELECT seq FROM sqlite_sequence").unwrap();
+        assert_eq!(result.rows, [[Value::Int64(20)]]);
+        let err = conn.execute("INSERT INTO b VALUES (10, 'w')").unwrap_err();
+        assert_eq!(err.to_string(), "UNIQUE constraint failed: b.id");
+        assert!(conn
+            .execute("INSERT INTO b VALUES (12, 1), (12, 2)")
+            .is_err());
+        assert!(conn.execute("INSERT INTO b VALUES (1.5, 'w')").is_err());
+
+        // once the largest row ID is taken, unused ones are picked at
+        // random, unless that could reuse one
+        let b = conn.table("b").unwrap();
+        let record = crate::btree::Record::from_values(vec![Value::Null, Value::Null]);
+        b.btree.insert(i64::MAX, &record).unwrap();
+        conn.set_random_seed(7);
+        conn.execute("INSERT INTO b (x) VALUES (5)").unwrap();
+        let mut rng = crate::btree::SampleRng::new(7);
+        let expected = (rng.next_u64() >> 1) as i64;
+        assert!(row_ids(&conn, "b").contains(&expected));
+        assert_eq!(row_ids(&conn, "b").len(), 7);
+        let a = conn.table("a").unwrap();
+        a.btree.insert(i64::MAX, &record).unwrap();
+        assert!(conn.execute("INSERT INTO a (x) VALUES (5)").is_err());
+    }
+
+    #[test]
+    fn integer_primary_key() {
+        let mut conn = Connection::deserialize(Vec::new()).unwrap();
+        conn.execute("CREATE TABLE t(x, id INTEGER PRIMARY KEY)")
+            .unwrap();
+        let text = |s: &str| Value::String(s.to_string());
+
+        // an explicit value is used as the row ID, NULL takes the next
+        // one, and text that is an integer is converted
+        conn.execute("INSERT INTO t VALUES ('a', 5)").unwrap();
+        conn.execute("INSERT INTO t VALUES ('b', NULL)").unwrap();
+        conn.execute("INSERT INTO t VALUES ('c', '10')").unwrap();
+        conn.execute("INSERT INTO t (x) VALUES ('d')").unwrap();
+        let result = conn.execute("SELECT id, typeof(id), x FROM t").unwrap();
+        let ids: Vec<Option<i64>> = result.rows.iter().map(|row| row[0].get_int_val()).collect();
+        assert_eq!(ids, [Some(5), Some(6), Some(10), Some(11)]);
+        let rest: Vec<&[Value]> = result.rows.iter().map(|row| &row[1..]).collect();
+        assert_eq!(
+            rest,
+            [
+                [text("integer"), text("a")],
+                [text("integer"), text("b")],
+                [text("integer"), text("c")],
+                [text("integer"), text("d")],
+            ]
+        );
+        let result = conn.execute("SELECT x FROM t WHERE id = 10").unwrap();
+        assert_eq!(result.rows, [[text("c")]]);
+
+        // the value is kept only as the row ID, so the record holds NULL
+        let records = conn.table("t").unwrap().btree.list_records();
+        let row_ids: Vec<i64> = records.iter().map(|(row_id, _)| row_id.0).collect();
+        assert_eq!(row_ids, [5, 6, 10, 11]);
+        assert!(records.iter().all(|(_, r)| r.values[1] == Value::Null));
+
+        // anything that is not an integer is rejected, and so is a row ID
+        // that is taken
+        for value in &["'abc'", "2.5", "x'01'"] {
+            let err = conn
+                .execute(&format!("INSERT INTO t VALUES ('e', {})", value))
+                .unwrap_err();
+            assert_eq!(err.to_string(), "datatype mismatch");
+        }
+        let err = conn.execute("INSERT INTO t VALUES ('f', 10)").unwrap_err();
+        assert_eq!(err.to_string(), "UNIQUE constraint failed: t.id");
+        assert_eq!(conn.table("t").unwrap().btree.list_records().len(), 4);
+    }
+}
@@ -0,0 +1,597 @@
+use eyre::Result;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+
+use crate::btree::{self, Btree, PageType, Record};
+use crate::connection::Connection;
+use crate::datatypes::{DataType, Value, VarInt};
+use crate::ddl;
+use crate::exec;
+use crate::pager::{PtrmapEntry, PtrmapType};
+use crate::parsing;
+
+/// A page whose contents are not a valid B-tree page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorruptPage {
+    pub page_num: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for CorruptPage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(
+            f,
+            "database disk image is malformed (page {}: {})",
+            self.page_num, self.reason
+        );
+    }
+}
+
+impl std::error::Error for CorruptPage {}
+
+/// Checks the structure of a B-tree page before it is parsed: the page
+/// header, that every cell lies within the usable area of the page, that
+/// the freeblock list is well-formed, and that the record header of every
+/// cell can be parsed and agrees with the payload size.
+pub fn validate_page(
+    bytes: &[u8],
+    page_num: usize,
+    page_size: usize,
+    reserved_space: u8,
+    num_pages: usize,
+) -> Result<(), CorruptPage> {
+    let corrupt = |reason: String| CorruptPage {
+        page_num: page_num,
+        reason: reason,
+    };
+    if bytes.len() != page_size {
+        return Err(corrupt(format!(
+            "expected {} bytes but found {}",
+            page_size,
+            bytes.len()
+        )));
+    }
+    let usable_size = page_size - reserved_space as usize;
+    let offset = if page_num == 1 { 100 } else { 0 };
+
+    let page_type = PageType::try_from(bytes[offset])
+        .map_err(|_| corrupt(format!("invalid page type {:#04x}", bytes[offset])))?;
+    let header_size = if page_type.is_interior() { 12 } else { 8 };
+    let be_u16 = |at: usize| parsing::be_u16(&bytes[at..at + 2]).unwrap() as usize;
+    let first_freeblock = be_u16(offset + 1);
+    let num_cells = be_u16(offset + 3);
+    let cell_start = match be_u16(offset + 5) {
+        0 => 65536,
+        n => n,
+    };
+    let fragmented_bytes = bytes[offset + 7] as usize;
+
+    let pointers_end = offset + header_size + 2 * num_cells;
+    if pointers_end > usable_size {
+        return Err(corrupt(format!(
+            "{} cell pointers do not fit in the page",
+            num_cells
+        )));
+    }
+    if cell_start < pointers_end || cell_start > usable_size {
+        return Err(corrupt(format!(
+            "cell content area starts at {}, outside of {}..{}",
+            cell_start, pointers_end, usable_size
+        )));
+    }
+    if fragmented_bytes > 60 {
+        return Err(corrupt(format!(
+            "{} fragmented bytes exceeds the maximum of 60",
+            fragmented_bytes
+        )));
+    }
+    if page_type.is_interior() {
+        let right_pointer = parsing::be_u32(&bytes[offset + 8..offset + 12]).unwrap() as usize;
+        check_child(right_pointer, num_pages).map_err(corrupt)?;
+    }
+
+    // freeblocks must be in ascending order and inside the content area
+    let mut freeblock = first_freeblock;
+    let mut min_next = cell_start;
+    while freeblock != 0 {
+        if freeblock < min_next || freeblock + 4 > usable_size {
+            return Err(corrupt(format!(
+                "freeblock at invalid offset {}",
+                freeblock
+            )));
+        }
+        let size = be_u16(freeblock + 2);
+        if size < 4 || freeblock + size > usable_size {
+            return Err(corrupt(format!(
+                "freeblock at {} has invalid size {}",
+                freeblock, size
+            )));
+        }
+        min_next = freeblock + size;
+        freeblock = be_u16(freeblock);
+    }
+
+    for i in 0..num_cells {
+        let ptr = be_u16(offset + header_size + 2 * i);
+        if ptr < cell_start || ptr >= usable_size {
+            return Err(corrupt(format!(
+                "cell {} at offset {} is outside of the content area",
+                i, ptr
+            )));
+        }
+        validate_cell(
+            &bytes[..usable_size],
+            ptr,
+            page_type,
+            usable_size,
+            num_pages,
+        )
+        .map_err(|reason| corrupt(format!("cell {}: {}", i, reason)))?;
+    }
+    return Ok(());
+}
+
+fn check_child(page_num: usize, num_pages: usize) -> Result<(), String> {
+    if page_num == 0 || page_num > num_pages {
+        return Err(format!("child page {} does not exist", page_num));
+    }
+    return Ok(());
+}
+
+/// Reads a varint that must end before `bytes` does.
+fn read_varint(bytes: &[u8], at: usize) -> Result<(i64, usize), String> {
+    let available = bytes.len().saturating_sub(at);
+    let complete = bytes[at.min(bytes.len())..]
+        .iter()
+        .take(9)
+        .position(|b| b & 0x80 == 0)
+        .map_or(available >= 9, |_| true);
+    if !complete {
+        return Err(format!("truncated varint at offset {}", at));
+    }
+    let (value, len) = VarInt::deserialize(&bytes[at..]);
+    return Ok((value.0, len));
+}
+
+fn validate_cell(
+    bytes: &[u8],
+    ptr: usize,
+    page_type: PageType,
+    usable_size: usize,
+    num_pages: usize,
+) -> Result<(), String> {
+    let mut pos = ptr;
+    if page_type.is_interior() {
+        if pos + 4 > bytes.len() {
+            return Err("truncated child pointer".to_string());
+        }
+        let child = parsing::be_u32(&bytes[pos..pos + 4]).unwrap() as usize;
+        check_child(child, num_pages)?;
+        pos += 4;
+    }
+    if page_type == PageType::TableInterior {
+        read_varint(bytes, pos)?;
+        return Ok(());
+    }
+
+    let (payload_size, len) = read_varint(bytes, pos)?;
+    pos += len;
+    if payload_size < 0 {
+        return Err(format!("invalid payload size {}", payload_size));
+    }
+    let payload_size = payload_size as usize;
+    if page_type == PageType::TableLeaf {
+        let (_, len) = read_varint(bytes, pos)?;
+        pos += len;
+    }
+    let local_size = local_payload_size(usable_size, payload_size, page_type);
+    let overflow_ptr_size = if local_size < payload_size { 4 } else { 0 };
+    if pos + local_size + overflow_ptr_size > bytes.len() {
+        return Err(format!(
+            "payload of {} bytes extends past the end of the page",
+            payload_size
+        ));
+    }
+    if overflow_ptr_size > 0 {
+        let overflow = parsing::be_u32(&bytes[pos + local_size..pos + local_size + 4]).unwrap();
+        check_child(overflow as usize, num_pages).map_err(|_| {
+            return format!("overflow page {} does not exist", overflow);
+        })?;
+    }
+
+    // the record header must be stored on the page
+    let payload = &bytes[pos..pos + local_size];
+    let (header_size, len) = read_varint(payload, 0)?;
+    if header_size < len as i64 || header_size as usize > local_size {
+        return Err(format!("invalid record header size {}", header_size));
+    }
+    let header_size = header_size as usize;
+    let mut body_size = 0;
+    let mut at = len;
+    while at < header_size {
+        let (serial_type, len) = read_varint(&payload[..header_size], at)?;
+        at += len;
+        if serial_type == 10 || serial_type == 11 || serial_type < 0 {
+            return Err(format!("invalid serial type {}", serial_type));
+        }
+        let data_type =
+            DataType::from_varint(VarInt::new(serial_type)).map_err(|e| e.to_string())?;
+        body_size += data_type.get_size().unwrap_or(0);
+    }
+    if header_size + body_size != payload_size {
+        return Err(format!(
+            "record size {} does not match payload size {}",
+            header_size + body_size,
+            payload_size
+        ));
+    }
+    return Ok(());
+}
+
+/// The number of bytes of a payload that are stored on the B-tree page
+/// itself, with the rest going to overflow pages.
+fn local_payload_size(usable_size: usize, payload_size: usize, page_type: PageType) -> usize {
+    let max_local = if page_type == PageType::TableLeaf {
+        usable_size - 35
+    } else {
+        (usable_size - 12) * 64 / 255 - 23
+    };
+    if payload_size <= max_local {
+        return payload_size;
+    }
+    let min_local = (usable_size - 12) * 32 / 255 - 23;
+    let k = min_local + (payload_size - min_local) % (usable_size - 4);
+    return if k <= max_local { k } else { min_local };
+}
+
+/// Checks the structure of every B-tree in the database, as `PRAGMA
+/// quick_check` does: each tree on its own (see `btree::check`, and
+/// `btree::check_index`, which also checks the order of an index's
+/// entries), and then that each overflow chain is long enough for its
+/// payload, that the freelist holds as many pages as the header says,
+/// that every page other than a pointer map page is used exactly once:
+/// by a tree, an overflow chain, or the freelist, and in an auto-vacuum
+/// database, that the pointer map has each page as what it is used for.
+/// Returns a description of each problem found.
+pub fn check_structure(conn: &Connection) -> Result<Vec<String>> {
+    let mut problems = Vec::new();
+    let mut roots = vec![1];
+    let schema_tree = Btree::new(
+        "sqlite_schema".to_string(),
+        "sqlite_schema".to_string(),
+        1,
+        conn.pager.clone(),
+    );
+    problems.extend(btree::check(&schema_tree).iter().map(|i| i.to_string()));
+    for table in &conn.schema.tables {
+        if table.root_page != 1 {
+            roots.push(table.root_page);
+            let tree = Btree::new(
+                table.name.clone(),
+                table.name.clone(),
+                table.root_page,
+                conn.pager.clone(),
+            );
+            problems.extend(btree::check(&tree).iter().map(|i| i.to_string()));
+        }
+    }
+    for index in &conn.schema.indexes {
+        roots.push(index.root_page);
+        let tree = Btree::new(
+            index.name.clone(),
+            index.table_name.clone(),
+            index.root_page,
+            conn.pager.clone(),
+        );
+        let infractions = match conn.schema.table(&index.table_name) {
+            Some(table) => {
+                let (columns, _) = ddl::index_columns(table, index, &conn.collations)?;
+                let compare = |a: &Record, b: &Record| {
+                    if a.values.len() <= columns.len() || b.values.len() <= columns.len() {
+                        return Ordering::Equal;
+                    }
+                    return ddl::compare_index_entries(&columns, &a.values, &b.values);
+                };
+                btree::check_index(&tree, &compare)
+            }
+            None => btree::check(&tree),
+        };
+        problems.extend(infractions.iter().map(|i| i.to_string()));
+    }
+
+    let mut pager = conn.pager.write();
+    let num_pages = pager.num_pages;
+    let page_size = pager.page_size();
+    // the tree each page was reached from; the problems within a tree
+    // have been found by checking it
+    let mut seen = HashMap::new();
+    // what the pointer map should have each page as
+    let mut ptrmap = Vec::new();
+    for root in roots {
+        let mut stack = vec![root];
+        ptrmap.push((root, ptrmap_entry(PtrmapType::RootPage, 0)));
+        while let Some(page_num) = stack.pop() {
+            if let Some(other) = seen.insert(page_num, root) {
+                if other != root {
+                    problems.push(format!("page {} is used more than once", page_num));
+                }
+                continue;
+            }
+            let bytes = pager.read_from_file(page_num)?;
+            let reserved_space = conn.db_options.reserved_space;
+            if validate_page(&bytes, page_num, page_size, reserved_space, num_pages).is_err() {
+                continue;
+            }
+            let page = pager.get_page(page_num)?.clone();
+            for child in page.children() {
+                stack.push(child as usize);
+                ptrmap.push((child as usize, ptrmap_entry(PtrmapType::Btree, page_num)));
+            }
+            for payload in page.overflow_payloads() {
+                // each overflow page holds the number of the next, and
+                // then as much of the payload as fits
+                let per_page = page_size - reserved_space as usize - 4;
+                let mut remaining = payload.size - payload.local.len();
+                let mut overflow_page = payload.first_page as usize;
+                let mut entry = ptrmap_entry(PtrmapType::Overflow1, page_num);
+                while remaining > 0 {
+                    if overflow_page == 0 || overflow_page > num_pages {
+                        problems.push(format!(
+                            "overflow chain from page {} ends early",
+                            payload.first_page
+                        ));
+                        break;
+                    }
+                    if seen.insert(overflow_page, root).is_some() {
+                        problems.push(format!("page {} is used more than once", overflow_page));
+                        break;
+                    }
+                    ptrmap.push((overflow_page, entry));
+                    entry = ptrmap_entry(PtrmapType::Overflow2, overflow_page);
+                    let bytes = pager.get_raw_page(overflow_page)?;
+                    remaining = remaining.saturating_sub(per_page);
+                    overflow_page = parsing::be_u32(&bytes[..4])? as usize;
+                }
+            }
+        }
+    }
+    match pager.freelist_pages() {
+        Ok(pages) => {
+            for page_num in pages {
+                if seen.insert(page_num, 0).is_some() {
+                    problems.push(format!("page {} is used more than once", page_num));
+                }
+                ptrmap.push((page_num, ptrmap_entry(PtrmapType::FreePage, 0)));
+            }
+        }
+        Err(err) => problems.push(err.to_string()),
+    }
+    for page_num in 1..=num_pages {
+        let unused = pager.is_ptrmap_page(page_num) || page_num == pager.lock_byte_page();
+        if !seen.contains_key(&page_num) && !unused {
+            problems.push(format!("page {} is never used", page_num));
+        }
+    }
+    if pager.is_auto_vacuum() {
+        // page 1 has no entry, and pages that are out of range have been
+        // reported already
+        for (page_num, expected) in ptrmap {
+            if page_num < 3 || page_num > num_pages {
+                continue;
+            }
+            match pager.ptrmap_get(page_num) {
+                Ok(Some(entry)) if entry == expected => (),
+                Ok(entry) => problems.push(format!(
+                    "Bad ptr map entry key={} expected=({},{}) got=({},{})",
+                    page_num,
+                    expected.kind as u8,
+                    expected.parent,
+                    entry.map_or(0, |entry| entry.kind as u8),
+                    entry.map_or(0, |entry| entry.parent)
+                )),
+                Err(_) => problems.push(format!("Failed to read ptrmap key={}", page_num)),
+            }
+        }
+    }
+    return Ok(problems);
+}
+
+fn ptrmap_entry(kind: PtrmapType, parent: usize) -> PtrmapEntry {
+    return PtrmapEntry {
+        kind: kind,
+        parent: parent as u32,
+    };
+}
+
+/// Checks that each index holds exactly the entries it should for the
+/// rows of its table (every row, or for a partial index, the rows that
+/// satisfy its WHERE clause), as `PRAGMA integrity_check` does. Returns
+/// a description of each missing or extra entry, which is empty if all
+/// indexes agree with their tables.
+pub fn check_indexes(conn: &Connection) -> Result<Vec<String>> {
+    let mut problems = Vec::new();
+    for schema in &conn.schema.tables {
+        if schema.name == "sqlite_schema" {
+            continue;
+        }
+        let table = conn.table(&schema.name)?;
+        for index in conn.schema.indexes_for_table(&schema.name) {
+            let (columns, where_clause) = ddl::index_columns(schema, index, &conn.collations)?;
+            let mut expected =
+                ddl::index_entries(&table, &columns, where_clause, &conn.collations)?;
+            let index_tree = Btree::new(
+                index.name.clone(),
+                index.table_name.clone(),
+                index.root_page,
+                conn.pager.clone(),
+            );
+            // an empty key matches every entry
+            let mut actual: Vec<Vec<Value>> = index_tree
+                .index_lookup(&[])?
+                .into_iter()
+                .map(|entry| entry.values)
+                .collect();
+
+            // with both lists sorted the same way, an entry that is in
+            // one but not the other is found by walking them together
+            expected.sort_by(|a, b| compare_entries(a, b));
+            actual.sort_by(|a, b| compare_entries(a, b));
+            let (mut i, mut j) = (0, 0);
+            while i < expected.len() || j < actual.len() {
+                let ordering = match (expected.get(i), actual.get(j)) {
+                    (Some(a), Some(b)) => compare_entries(a, b),
+                    (Some(_), None) => Ordering::Less,
+                    _ => Ordering::Greater,
+                };
+                match ordering {
+                    Ordering::Equal => {
+                        i += 1;
+                        j += 1;
+                    }
+                    Ordering::Less => {
+                        problems.push(format!(
+                            "row {} missing from index {}",
+                            row_id(&expected[i]).unwrap_or(0),
+                            index.name
+                        ));
+                        i += 1;
+                    }
+                    Ordering::Greater => {
+                        problems.push(match row_id(&actual[j]) {
+                            Some(row_id) => format!(
+                                "index {} has an extra entry for row {}",
+                                index.name, row_id
+                            ),
+                            None => format!("index {} has an entry with no row ID", index.name),
+                        });
+                        j += 1;
+                    }
+                }
+            }
+        }
+    }
+    return Ok(problems);
+}
+
+/// The row ID an index entry points to, which is its last value.
+fn row_id(entry: &[Value]) -> Option<i64> {
+    return entry.last().and_then(|v| v.get_int_val());
+}
+
+/// Orders index entries by row ID, then by the indexed values, so that
+/// problems are reported in row ID order.
+fn compare_entries(a: &[Value], b: &[Value]) -> Ordering {
+    return row_id(a).cmp(&row_id(b)).then_with(|| {
+        for (x, y) in a.iter().zip(b.iter()) {
+            let ordering = exec::compare(x, y);
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        return a.len().cmp(&b.len());
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf_page(cell: &[u8]) -> Vec<u8> {
+        let mut page = vec![0; 512];
+        let start = 512 - cell.len();
+        page[0] = 0x0d;
+        page[3..5].copy_from_slice(&1_u16.to_be_bytes());
+        page[5..7].copy_from_slice(&(start as u16).to_be_bytes());
+        page[8..10].copy_from_slice(&(start as u16).to_be_bytes());
+        page[start..].copy_from_slice(cell);
+        return page;
+    }
+
+    #[test]
+    fn detects_corruption() {
+        // payload size, row id, record header size, serial type, value
+        let good = leaf_page(&[3, 1, 2, 1, 42]);
+        assert_eq!(validate_page(&good, 2, 512, 0, 2), Ok(()));
+
+        let mut bad_type = good.clone();
+        bad_type[0] = 0x07;
+        assert!(validate_page(&bad_type, 2, 512, 0, 2).is_err());
+
+        let mut bad_pointer = good.clone();
+        bad_pointer[8..10].copy_from_slice(&600_u16.to_be_bytes());
+        assert!(validate_page(&bad_pointer, 2, 512, 0, 2).is_err());
+
+        // the record says it holds an 8-byte integer
+        let bad_record = leaf_page(&[3, 1, 2, 6, 42]);
+        let err = validate_page(&bad_record, 2, 512, 0, 2).unwrap_err();
+        assert_eq!(err.page_num, 2);
+        assert!(err.reason.contains("does not match payload size"));
+    }
+
+    #[test]
+    fn broken_overflow_chain() {
+        use positioned_io::WriteAt;
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let filename = file.path().to_str().unwrap();
+        let first_page = {
+            let mut conn = Connection::new(filename).unwrap();
+            conn.execute("PRAGMA page_size = 512").unwrap();
+            conn.execute("CREATE TABLE t(a)").unwrap();
+            conn.execute(&format!("INSERT INTO t VALUES ('{}')", "x".repeat(2000)))
+                .unwrap();
+            let root_page = conn.table("t").unwrap().btree.root_page;
+            let page = conn.pager.read().get_page(root_page).unwrap();
+            page.overflow_payloads()[0].first_page as usize
+        };
+        // end the chain after its first page
+        let mut f = std::fs::OpenOptions::new()
+            .write(true)
+            .open(filename)
+            .unwrap();
+        f.write_all_at((first_page as u64 - 1) * 512, &[0; 4])
+            .unwrap();
+
+        let mut conn = Connection::new(filename).unwrap();
+        let err = conn.execute("SELECT * FROM t").unwrap_err();
+        let corrupt = err.downcast_ref::<CorruptPage>().unwrap();
+        assert_eq!(corrupt.page_num, first_page);
+        assert_eq!(corrupt.reason, "overflow chain of t ends early");
+    }
+
+    #[test]
+    fn detects_index_mismatches() {
+        use crate::btree::Record;
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        conn.execute("CREATE TABLE t(a, b)").unwrap();
+        for i in 1..=50 {
+            conn.execute(&format!("INSERT INTO t VALUES ({}, 'x{}')", i, i))
+                .unwrap();
+        }
+        conn.execute("CREATE INDEX t_a ON t(a) WHERE a > 10")
+            .unwrap();
+        conn.execute("CREATE UNIQUE INDEX t_b ON t(b)").unwrap();
+        assert_eq!(check_indexes(&conn).unwrap(), Vec::<String>::new());
+
+        // change the table without updating its indexes
+        let table = conn.table("t").unwrap();
+        table.btree.delete(5).unwrap();
+        table.btree.delete(20).unwrap();
+        let record = Record::from_values(vec![Value::Int64(60), Value::String("y".to_string())]);
+        table.btree.insert(51, &record).unwrap();
+        assert_eq!(
+            check_indexes(&conn).unwrap(),
+            [
+                "index t_a has an extra entry for row 20",
+                "row 51 missing from index t_a",
+                "index t_b has an extra entry for row 5",
+                "index t_b has an extra entry for row 20",
+                "row 51 missing from index t_b",
+            ]
+        );
+    }
+}
@@ -0,0 +1,273 @@
+use eyre::{eyre, Result};
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+use crate::pager::Page;
+
+/// Original images of the pages touched since a savepoint was opened. An
+/// image of `None` means the page did not exist yet when the savepoint
+/// was opened, so rolling back should discard it entirely.
+pub type PageImages = HashMap<usize, Option<Page>>;
+
+/// An in-memory statement journal. SQLite uses a separate statement
+/// journal so that a single statement that fails partway through (e.g.,
+/// a constraint violation on the 100th row of an INSERT...SELECT) can be
+/// undone without rolling back the whole transaction. Each statement
+/// opens a savepoint on this stack; savepoints can nest, so a statement
+/// run inside an outer savepoint only undoes its own changes.
+#[derive(Debug, Default)]
+pub struct StatementJournal {
+    savepoints: Vec<PageImages>,
+}
+
+impl StatementJournal {
+    pub fn new() -> Self {
+        return Self {
+            savepoints: Vec::new(),
+        };
+    }
+
+    pub fn is_active(&self) -> bool {
+        return !self.savepoints.is_empty();
+    }
+
+    pub fn depth(&self) -> usize {
+        return self.savepoints.len();
+    }
+
+    pub fn open(&mut self) {
+        self.savepoints.push(HashMap::new());
+    }
+
+    /// Whether the innermost savepoint still needs an original image of
+    /// this page, i.e., this is the first time it is being modified
+    /// since the savepoint was opened.
+    pub fn needs_image(&self, page_num: usize) -> bool {
+        return match self.savepoints.last() {
+            Some(images) => !images.contains_key(&page_num),
+            None => false,
+        };
+    }
+
+    /// Records the original image of a page in the innermost savepoint.
+    /// Only the first image recorded for a page is kept.
+    pub fn record(&mut self, page_num: usize, original: Option<Page>) {
+        if let Some(images) = self.savepoints.last_mut() {
+            images.entry(page_num).or_insert(original);
+        }
+    }
+
+    /// Closes the innermost savepoint, keeping its changes. Any images it
+    /// holds are folded into the enclosing savepoint (unless that one
+    /// already has an older image of the same page), so that rolling back
+    /// the enclosing savepoint still undoes them.
+    pub fn release(&mut self) -> bool {
+        return match self.savepoints.pop() {
+            Some(images) => {
+                if let Some(parent) = self.savepoints.last_mut() {
+                    for (page_num, image) in images {
+                        parent.entry(page_num).or_insert(image);
+                    }
+                }
+                true
+            }
+            None => false,
+        };
+    }
+
+    /// Closes the innermost savepoint and returns the original images of
+    /// every page it touched, which the caller is responsible for
+    /// restoring.
+    pub fn rollback(&mut self) -> Option<PageImages> {
+        return self.savepoints.pop();
+    }
+}
+
+/// The original images of the pages changed by a transaction, as kept in
+/// the rollback journal SQLite writes next to a database (with
+/// `-journal` after its name) while the transaction is in progress, or
+/// leaves behind if it is interrupted. Reading it does not roll anything
+/// back: it shows what the transaction has changed, and what a rollback
+/// would restore (see `Connection::open_before_transaction`).
+#[derive(Debug, Clone)]
+pub struct RollbackJournal {
+    pub page_size: usize,
+    /// The number of pages the database had when the transaction began.
+    pub initial_pages: usize,
+    images: HashMap<usize, Vec<u8>>,
+}
+
+impl RollbackJournal {
+    const MAGIC: [u8; 8] = [0xd9, 0xd5, 0x05, 0xf9, 0x20, 0xa1, 0x63, 0xd7];
+
+    /// Reads the rollback journal of a database, if there is one. A
+    /// journal that was emptied or zeroed once its transaction was
+    /// committed (as with `journal_mode` TRUNCATE or PERSIST) counts as
+    /// none.
+    pub fn open(db_filename: &str) -> Result<Option<Self>> {
+        let bytes = match std::fs::read(format!("{}-journal", db_filename)) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        return Self::parse(&bytes);
+    }
+
+    /// Reads the contents of a rollback journal. As when SQLite plays one
+    /// back, the records end at the first whose checksum does not match,
+    /// as they were not all written before the journal was interrupted.
+    pub fn parse(bytes: &[u8]) -> Result<Option<Self>> {
+        if bytes.len() < 28 || bytes[..8] != Self::MAGIC {
+            return Ok(None);
+        }
+        let header = |offset: usize| -> u32 {
+            return u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        };
+        let initial_pages = header(16) as usize;
+        let sector_size = header(20) as usize;
+        let page_size = header(24) as usize;
+        if !(512..=65536).contains(&page_size)
+            || !page_size.is_power_of_two()
+            || !(32..=65536).contains(&sector_size)
+            || !sector_size.is_power_of_two()
+        {
+            return Err(eyre!("The rollback journal has a malformed header."));
+        }
+
+        let mut journal = Self {
+            page_size: page_size,
+            initial_pages: initial_pages,
+            images: HashMap::new(),
+        };
+        let record_size = page_size + 8;
+        // a journal may hold several segments, each with a header of its
+        // own, which starts on a sector boundary
+        let mut offset = 0;
+        while offset + 28 <= bytes.len() && bytes[offset..offset + 8] == Self::MAGIC {
+            let num_records = header(offset + 8);
+            let nonce = header(offset + 12);
+            offset += sector_size;
+            let available = bytes.len().saturating_sub(offset) / record_size;
+            // a count of 0 or all ones means the records run to the end
+            // of the file, as the count was not filled in
+            let num_records = match num_records {
+                0 | u32::MAX => available,
+                n => std::cmp::min(n as usize, available),
+            };
+            for _ in 0..num_records {
+                let record = &bytes[offset..offset + record_size];
+                let page_num = u32::from_be_bytes(record[..4].try_into().unwrap()) as usize;
+                let image = &record[4..4 + page_size];
+                let checksum = u32::from_be_bytes(record[4 + page_size..].try_into().unwrap());
+                if page_num == 0 || checksum != journal_checksum(nonce, image) {
+                    return Ok(Some(journal));
+                }
+                // a page is only journaled again in a later segment if
+                // the first image was lost, so the first is the original
+                journal
+                    .images
+                    .entry(page_num)
+                    .or_insert_with(|| image.to_vec());
+                offset += record_size;
+            }
+            offset = offset.div_ceil(sector_size) * sector_size;
+        }
+        return Ok(Some(journal));
+    }
+
+    /// The image of a page from before the transaction, if the
+    /// transaction changed it.
+    pub fn page(&self, page_num: usize) -> Option<&[u8]> {
+        return self.images.get(&page_num).map(|image| image.as_slice());
+    }
+
+    /// The numbers of the pages the transaction changed, in order.
+    pub fn page_numbers(&self) -> Vec<usize> {
+        let mut pages: Vec<usize> = self.images.keys().copied().collect();
+        pages.sort_unstable();
+        return pages;
+    }
+
+    /// Puts the original images of the journaled pages back into an image
+    /// of the database file, and cuts it back to its original size,
+    /// which gives the database as it was before the transaction.
+    pub fn restore(&self, image: &mut Vec<u8>) {
+        image.resize(self.initial_pages * self.page_size, 0);
+        for (&page_num, original) in &self.images {
+            if page_num <= self.initial_pages {
+                let start = (page_num - 1) * self.page_size;
+                image[start..start + self.page_size].copy_from_slice(original);
+            }
+        }
+    }
+}
+
+/// The checksum of a journal record, which SQLite takes over a sample of
+/// the page's bytes only: every 200th byte, working back from the end.
+fn journal_checksum(nonce: u32, image: &[u8]) -> u32 {
+    let mut checksum = nonce;
+    let mut i = image.len() as isize - 200;
+    while i > 0 {
+        checksum = checksum.wrapping_add(image[i as usize] as u32);
+        i -= 200;
+    }
+    return checksum;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A journal in SQLite's format, with a segment per list of pages.
+    fn make_journal(segments: &[&[(u32, u8)]], page_size: usize) -> Vec<u8> {
+        let sector_size = 512;
+        let mut bytes = Vec::new();
+        for pages in segments {
+            let nonce = 0x1234_5678u32;
+            let mut header = RollbackJournal::MAGIC.to_vec();
+            for field in [pages.len() as u32, nonce, 3, sector_size, page_size as u32] {
+                header.extend(field.to_be_bytes());
+            }
+            header.resize(sector_size as usize, 0);
+            bytes.extend(header);
+            for &(page_num, fill) in pages.iter() {
+                let image = vec![fill; page_size];
+                bytes.extend(page_num.to_be_bytes());
+                bytes.extend(&image);
+                bytes.extend(journal_checksum(nonce, &image).to_be_bytes());
+            }
+            bytes.resize(
+                bytes.len().div_ceil(sector_size as usize) * sector_size as usize,
+                0,
+            );
+        }
+        return bytes;
+    }
+
+    #[test]
+    fn read_rollback_journal() {
+        assert!(RollbackJournal::parse(&[0; 512]).unwrap().is_none());
+
+        let bytes = make_journal(&[&[(2, 0xaa), (1, 0xbb)], &[(2, 0xcc), (5, 0xdd)]], 1024);
+        let journal = RollbackJournal::parse(&bytes).unwrap().unwrap();
+        assert_eq!(journal.page_numbers(), [1, 2, 5]);
+        assert_eq!(journal.page(2), Some(&[0xaa; 1024][..]));
+        assert_eq!(journal.page(3), None);
+
+        // the database grew from 3 pages to 6, and had pages 1 and 2
+        // changed; page 5 was new, so is dropped
+        let mut image = vec![0x11; 6 * 1024];
+        journal.restore(&mut image);
+        assert_eq!(image.len(), 3 * 1024);
+        assert!(image[..1024].iter().all(|b| *b == 0xbb));
+        assert!(image[1024..2048].iter().all(|b| *b == 0xaa));
+        assert!(image[2048..].iter().all(|b| *b == 0x11));
+
+        // a record that was only partly written ends the journal
+        let mut bytes = make_journal(&[&[(2, 0xaa), (4, 0xee)]], 1024);
+        let second = 512 + (1024 + 8) + 4;
+        bytes[second + 824] ^= 1;
+        let journal = RollbackJournal::parse(&bytes).unwrap().unwrap();
+        assert_eq!(journal.page_numbers(), [2]);
+    }
+}
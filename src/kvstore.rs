@@ -0,0 +1,214 @@
+//! A key/value store kept in the database file, for programs that want
+//! the storage engine without SQL. Each store is a WITHOUT ROWID table
+//! with a BLOB key and a BLOB value, so its entries are kept in key order
+//! on an index B-tree, and SQLite can read and write it like any other
+//! table:
+//!
+//! ```sql
+//! CREATE TABLE "<name>"(key BLOB PRIMARY KEY, value BLOB) WITHOUT ROWID
+//! ```
+//!
+//! Changes to a store are not recorded in the replication log.
+
+use eyre::{eyre, Result};
+use std::cmp::Ordering;
+use std::ops::Bound;
+
+use crate::btree::{Btree, IndexRange, PageType, Record};
+use crate::connection::Connection;
+use crate::datatypes::Value;
+use crate::ddl;
+use crate::exec;
+use crate::parser;
+use crate::schema::SCHEMA_ROOT_PAGE;
+use crate::vacuum;
+
+pub struct KvStore<'a> {
+    conn: &'a mut Connection,
+    btree: Btree,
+}
+
+impl<'a> KvStore<'a> {
+    /// Opens the store called `name`, creating it if there is no table
+    /// of that name yet.
+    pub fn open(conn: &'a mut Connection, name: &str) -> Result<Self> {
+        let root_page = match conn.schema.table(name) {
+            Some(schema) => {
+                let columns = schema.column_names();
+                if !schema.definition.without_rowid || columns != ["key", "value"] {
+                    return Err(eyre!("table {} is not a key/value store", name));
+                }
+                schema.root_page
+            }
+            None => create(conn, name)?,
+        };
+        let btree = Btree::new(
+            name.to_string(),
+            name.to_string(),
+            root_page,
+            conn.pager.clone(),
+        );
+        return Ok(Self {
+            conn: conn,
+            btree: btree,
+        });
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut cursor = self.btree.cursor();
+        if cursor.seek_to(&position(key))? {
+            if let Some(record) = cursor.current() {
+                if position(key)(&record) == Ordering::Equal {
+                    return Ok(Some(entry(record)?.1));
+                }
+            }
+        }
+        return Ok(None);
+    }
+
+    /// Sets the value of a key, replacing any value it already has.
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let btree = &self.btree;
+        let record =
+            Record::from_values(vec![Value::Blob(key.to_vec()), Value::Blob(value.to_vec())]);
+        return self.conn.write_statement(|_| {
+            btree.index_delete(&position(key))?;
+            return btree.index_insert(&record, &position(key));
+        });
+    }
+
+    /// Removes a key, returning whether it was there.
+    pub fn delete(&mut self, key: &[u8]) -> Result<bool> {
+        let btree = &self.btree;
+        return self
+            .conn
+            .write_statement(|_| btree.index_delete(&position(key)));
+    }
+
+    /// The entries of the store, in key order.
+    pub fn iter(&self) -> KvIter<'_> {
+        return KvIter {
+            range: self.btree.index_range(Bound::Unbounded, Bound::Unbounded),
+        };
+    }
+
+    /// The entries of the store with keys from `start` on, in key order.
+    pub fn iter_from(&self, start: &[u8]) -> KvIter<'_> {
+        let start = Record::from_values(vec![Value::Blob(start.to_vec())]);
+        return KvIter {
+            range: self
+                .btree
+                .index_range(Bound::Included(start), Bound::Unbounded),
+        };
+    }
+}
+
+/// A lazy, in-order scan over the entries of a key/value store.
+pub struct KvIter<'a> {
+    range: IndexRange<'a>,
+}
+
+impl Iterator for KvIter<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        return self.range.next().map(|record| entry(record?));
+    }
+}
+
+/// Creates the table for a store, returning its root page.
+fn create(conn: &mut Connection, name: &str) -> Result<usize> {
+    if conn.schema.index(name).is_some()
+        || conn.schema.view(name).is_some()
+        || conn.schema.virtual_table(name).is_some()
+    {
+        return Err(eyre!("there is already an object named {}", name));
+    }
+    if name.to_lowercase().starts_with("sqlite_") {
+        return Err(eyre!("object name reserved for internal use: {}", name));
+    }
+    let sql = format!(
+        "CREATE TABLE {}(key BLOB PRIMARY KEY, value BLOB) WITHOUT ROWID",
+        parser::quote_name(name)
+    );
+    return conn.write_statement(|conn| {
+        let schema_tree = Btree::new(
+            "sqlite_schema".to_string(),
+            "sqlite_schema".to_string(),
+            SCHEMA_ROOT_PAGE,
+            conn.pager.clone(),
+        );
+        let row_id = schema_tree.max_row_id()? + 1;
+        let root_page = vacuum::allocate_root(&mut conn.pager.write(), PageType::IndexLeaf)?;
+        schema_tree.insert(
+            row_id,
+            &ddl::schema_record("table", name, name, root_page, Some(&sql)),
+        )?;
+        conn.db_options.schema_cookie = conn.db_options.schema_cookie.wrapping_add(1);
+        return Ok(root_page);
+    });
+}
+
+/// Where an entry with the given key goes relative to each entry.
+fn position(key: &[u8]) -> impl Fn(&Record) -> Ordering + '_ {
+    return move |record: &Record| match record.values.first() {
+        Some(Value::Blob(other)) => key.cmp(other.as_slice()),
+        // keys that are not blobs sort before all blobs
+        Some(other) => exec::compare(&Value::Blob(key.to_vec()), other),
+        None => Ordering::Greater,
+    };
+}
+
+/// The key and value of an entry.
+fn entry(record: Record) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut values = record.values.into_iter();
+    return match (values.next(), values.next()) {
+        (Some(Value::Blob(key)), Some(Value::Blob(value))) => Ok((key, value)),
+        (Some(Value::Blob(key)), Some(Value::Null)) => Ok((key, Vec::new())),
+        _ => Err(eyre!("Malformed key/value store entry")),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn put_get_delete() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let path = file.path().to_str().unwrap();
+        let mut conn = Connection::new(path).unwrap();
+        conn.execute("PRAGMA page_size = 512").unwrap();
+        let mut store = KvStore::open(&mut conn, "kv").unwrap();
+        for i in (0..1000u32).rev() {
+            let key = format!("key{:04}", i);
+            store.put(key.as_bytes(), &i.to_be_bytes()).unwrap();
+        }
+        store.put(b"key0005", b"five").unwrap();
+        store.put(b"big", &vec![7; 2000]).unwrap();
+        assert!(store.delete(b"key0006").unwrap());
+        assert!(!store.delete(b"key0006").unwrap());
+        assert_eq!(store.get(b"key0005").unwrap(), Some(b"five".to_vec()));
+        assert_eq!(store.get(b"key0006").unwrap(), None);
+        assert_eq!(store.get(b"big").unwrap(), Some(vec![7; 2000]));
+
+        let keys: Vec<Vec<u8>> = store
+            .iter_from(b"key0990")
+            .map(|entry| entry.unwrap().0)
+            .collect();
+        assert_eq!(keys.len(), 10);
+        assert_eq!(keys[0], b"key0990".to_vec());
+        assert_eq!(store.iter().count(), 1000);
+        drop(store);
+
+        let mut conn = Connection::new(path).unwrap();
+        let store = KvStore::open(&mut conn, "kv").unwrap();
+        assert_eq!(
+            store.get(b"key0999").unwrap(),
+            Some(999u32.to_be_bytes().to_vec())
+        );
+        drop(store);
+        let problems = crate::integrity::check_structure(&conn).unwrap();
+        assert!(problems.is_empty(), "{:?}", problems);
+    }
+}
@@ -1,13 +1,68 @@
+#![allow(clippy::needless_return, clippy::redundant_field_names)]
+
 use derive_try_from_primitive::TryFromPrimitive;
 use eyre::{eyre, Result};
 use positioned_io::ReadAt;
-use std::convert::{TryFrom, TryInto};
-use std::fs::File;
+use std::convert::TryFrom;
+use std::fs::OpenOptions;
 
+pub mod ast;
+#[cfg(feature = "async")]
+pub mod async_pager;
 pub mod btree;
+#[cfg(feature = "compat")]
+pub mod compat;
+pub mod connection;
 pub mod datatypes;
+pub mod ddl;
+pub mod delete;
+pub mod dump;
+pub mod exec;
+pub mod functions;
+pub mod insert;
+pub mod integrity;
+pub mod journal;
+pub mod kvstore;
+pub mod limits;
+pub mod lock;
+pub mod pagefile;
 pub mod pager;
+pub mod parser;
 pub mod parsing;
+pub mod pragma;
+pub mod prelude;
+pub mod replication;
+#[cfg(feature = "rtree")]
+pub mod rtree;
+pub mod schema;
+pub mod select;
+pub mod slowlog;
+pub mod sorter;
+pub mod statement;
+pub mod stats;
+pub mod table;
+pub mod tenant;
+pub mod tokenizer;
+pub mod vacuum;
+
+pub use crate::connection::Connection;
+pub use crate::datatypes::Value;
+
+/// Opens a database file embedded in the program, so that a tool can
+/// ship with the data it needs. The file is included at compile time
+/// with `include_bytes!`, so its path is relative to the source file
+/// the macro is used in. The database is read in place and cannot be
+/// modified.
+///
+/// ```ignore
+/// let conn = sqlite_clone::include_db!("../data/lookup.db")?;
+/// ```
+#[macro_export]
+macro_rules! include_db {
+    ($path:expr) => {
+        $crate::connection::Connection::open_bytes(include_bytes!($path))
+    };
+}
 
 const SQLITE_MAJOR_VERSION: u16 = 3;
 const SQLITE_MINOR_VERSION: u16 = 35;
@@ -42,7 +97,12 @@ impl DbOptions {
     const MAGIC: &'static [u8] = "SQLite format 3\0".as_bytes();
 
     pub fn init(filename: &str) -> Result<Self> {
-        let file = File::open(filename)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(filename)?;
         let file_length = file.metadata()?.len() as usize;
 
         if file_length > 0 {
@@ -50,35 +110,49 @@ impl DbOptions {
             let mut buf = vec![0; 100];
             let _ = file.read_at(0, &mut buf)?;
             return Self::deserialize(&buf);
-        } else {
-            // set defaults
-            let sqlite_version = SQLITE_MAJOR_VERSION as u32 * 1_000_000
-                + SQLITE_MINOR_VERSION as u32 * 1000
-                + SQLITE_PATCH_VERSION as u32;
-            return Ok(Self {
-                page_size: 4096,
-                file_write_version: FileVersion::Legacy,
-                file_read_version: FileVersion::Legacy,
-                reserved_space: 0,
-                max_payload: 64,
-                min_payload: 32,
-                leaf_payload: 32,
-                change_counter: 0,
-                num_pages: 0,
-                first_freelist: 0,
-                num_freelist: 0,
-                schema_cookie: 0,
-                schema_format: 4,
-                cache_size: 0,
-                largest_root_page: 0,
-                encoding: TextEncoding::Utf8,
-                user_version: 0,
-                incremental_vacuum: false,
-                app_id: 0,
-                version_valid_for: 0,
-                sqlite_version: sqlite_version,
-            });
         }
+        return Ok(Self::new_database());
+    }
+
+    /// Reads the header of an image of a database file, such as one made
+    /// by `Connection::serialize`. An empty image is a new database.
+    pub fn from_image(image: &[u8]) -> Result<Self> {
+        if image.is_empty() {
+            return Ok(Self::new_database());
+        } else if image.len() < 100 {
+            return Err(eyre!("Not a valid sqlite file -- too short for a header!"));
+        }
+        return Self::deserialize(&image[..100]);
+    }
+
+    /// The header of a new, empty database.
+    fn new_database() -> Self {
+        let sqlite_version = SQLITE_MAJOR_VERSION as u32 * 1_000_000
+            + SQLITE_MINOR_VERSION as u32 * 1000
+            + SQLITE_PATCH_VERSION as u32;
+        return Self {
+            page_size: 4096,
+            file_write_version: FileVersion::Legacy,
+            file_read_version: FileVersion::Legacy,
+            reserved_space: 0,
+            max_payload: 64,
+            min_payload: 32,
+            leaf_payload: 32,
+            change_counter: 0,
+            num_pages: 0,
+            first_freelist: 0,
+            num_freelist: 0,
+            schema_cookie: 0,
+            schema_format: 4,
+            cache_size: 0,
+            largest_root_page: 0,
+            encoding: TextEncoding::Utf8,
+            user_version: 0,
+            incremental_vacuum: false,
+            app_id: 0,
+            version_valid_for: 0,
+            sqlite_version: sqlite_version,
+        };
     }
 
     pub fn deserialize(i: &[u8]) -> Result<Self> {
@@ -92,15 +166,19 @@ impl DbOptions {
         // page size must be a power of two between 512 and 32768
         // inclusive, or the value 1 representing a page size of 65536
         let mut page_size = parsing::be_u16(&i[pos.v()..pos.incr(2)])? as usize;
-        if page_size != 1 && (page_size <= 512 || page_size >= 32768 || page_size % 2 != 0) {
+        if page_size != 1 && (!(512..=32768).contains(&page_size) || !page_size.is_power_of_two()) {
             return Err(eyre!("Page size is invalid."));
         } else if page_size == 1 {
             page_size = 65536; // this value does not fit into a u16 and
                                // is thus represented by 0x00 0x01
         }
 
-        let file_write = FileVersion::try_from(parsing::be_u8(&i[pos.v()..pos.incr(1)])?).unwrap();
-        let file_read = FileVersion::try_from(parsing::be_u8(&i[pos.v()..pos.incr(1)])?).unwrap();
+        let file_write = parsing::be_u8(&i[pos.v()..pos.incr(1)])?;
+        let file_write = FileVersion::try_from(file_write)
+            .map_err(|_| eyre!("Invalid file format write version {}", file_write))?;
+        let file_read = parsing::be_u8(&i[pos.v()..pos.incr(1)])?;
+        let file_read = FileVersion::try_from(file_read)
+            .map_err(|_| eyre!("Invalid file format read version {}", file_read))?;
 
         let reserved_space = parsing::be_u8(&i[pos.v()..pos.incr(1)])?;
         let max_payload = parsing::be_u8(&i[pos.v()..pos.incr(1)])?;
@@ -118,7 +196,9 @@ impl DbOptions {
         let schema_format = parsing::be_u32(&i[pos.v()..pos.incr(4)])?;
         let cache_size = parsing::be_i32(&i[pos.v()..pos.incr(4)])?;
         let largest_root_page = parsing::be_u32(&i[pos.v()..pos.incr(4)])?;
-        let encoding = TextEncoding::try_from(parsing::be_u32(&i[pos.v()..pos.incr(4)])?).unwrap();
+        let encoding = parsing::be_u32(&i[pos.v()..pos.incr(4)])?;
+        let encoding = TextEncoding::try_from(encoding)
+            .map_err(|_| eyre!("Invalid text encoding {}", encoding))?;
         let user_version = parsing::be_u32(&i[pos.v()..pos.incr(4)])?;
         let incremental_vacuum = parsing::be_u32(&i[pos.v()..pos.incr(4)])? != 0;
         let app_id = parsing::be_u32(&i[pos.v()..pos.incr(4)])?;
@@ -199,7 +279,7 @@ impl DbOptions {
         output.extend(self.cache_size.to_be_bytes().iter());
         output.extend(self.largest_root_page.to_be_bytes().iter());
 
-        let encoding: u32 = (self.encoding as u32).try_into().unwrap();
+        let encoding = self.encoding as u32;
         output.extend(encoding.to_be_bytes().iter());
         output.extend(self.user_version.to_be_bytes().iter());
 
@@ -0,0 +1,38 @@
+/// Upper bounds on what a database file or a statement may make the
+/// library do, so that a hostile file or query is reported as an error
+/// rather than using unbounded memory or overflowing the stack. The
+/// defaults are SQLite's, except as noted. Set them when opening a
+/// database (see `PagerConfig::limits`) or later with
+/// `Connection::set_limits`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Limits {
+    /// The largest number of pages the database may have. A file with
+    /// more pages is refused, and the database cannot grow past it.
+    pub max_page_count: usize,
+    /// The largest record, in bytes, that may be read or written.
+    pub max_record_size: usize,
+    /// The largest number of pages on the path from the root of a B-tree
+    /// to a leaf. A file that claims a deeper tree (e.g., one whose child
+    /// pointers form a cycle) is reported as corrupt.
+    pub max_btree_depth: usize,
+    /// The longest statement, in bytes, that may be prepared.
+    pub max_sql_length: usize,
+    /// The deepest an expression may nest, with each operator, function
+    /// call, and pair of parentheses a level. Parsing and evaluating
+    /// expressions recurse once per level, so this is what keeps them
+    /// within the stack; the default is lower than SQLite's 1000 so that
+    /// an optimized build stays within a 2 MiB thread stack.
+    pub max_expr_depth: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        return Self {
+            max_page_count: 1073741823,
+            max_record_size: 1_000_000_000,
+            max_btree_depth: 20,
+            max_sql_length: 1_000_000_000,
+            max_expr_depth: 250,
+        };
+    }
+}
@@ -0,0 +1,247 @@
+//! Locking the database file the way SQLite does on Unix, so that the
+//! file can be shared with other processes, SQLite itself included.
+//!
+//! The locks are advisory byte-range locks on the lock-byte page, the
+//! page holding the byte 1 GiB into the file, which is never used for
+//! data. A reader holds a read lock on one of the SHARED bytes. A writer
+//! first takes RESERVED, which only one connection can hold at a time
+//! but which lets readers carry on, and then PENDING, which keeps new
+//! readers out, and finally EXCLUSIVE, a write lock on every SHARED byte,
+//! once the last reader is gone.
+//!
+//! On Linux these are open file description locks, which conflict with
+//! the POSIX locks SQLite takes, but unlike them belong to the open file
+//! rather than the process: two connections in the same process exclude
+//! each other, and closing another handle on the file does not drop the
+//! locks.
+
+use eyre::{eyre, Result, WrapErr};
+use std::fmt;
+use std::fs::File;
+
+/// The first byte of the lock-byte page: write locked by a writer that is
+/// waiting for EXCLUSIVE, and read locked briefly by every reader taking
+/// SHARED.
+pub const PENDING_BYTE: u64 = 0x4000_0000;
+pub const RESERVED_BYTE: u64 = PENDING_BYTE + 1;
+/// The range a reader read locks, and a writer with EXCLUSIVE write locks
+/// in full.
+pub const SHARED_FIRST: u64 = PENDING_BYTE + 2;
+pub const SHARED_SIZE: u64 = 510;
+
+/// How much of the database a connection has locked, from nothing to
+/// the whole file, in the order they are taken.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum LockLevel {
+    #[default]
+    None,
+    Shared,
+    Reserved,
+    Pending,
+    Exclusive,
+}
+
+/// The error for a lock that could not be taken because another
+/// connection holds one that conflicts with it (`SQLITE_BUSY`). The
+/// statement can be tried again once the other connection is done.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DatabaseLocked;
+
+impl fmt::Display for DatabaseLocked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "database is locked");
+    }
+}
+
+impl std::error::Error for DatabaseLocked {}
+
+/// The lock a connection holds on a database file. For a database held
+/// in memory, which no one else can see, only the level is kept.
+#[derive(Debug, Default)]
+pub struct FileLock {
+    level: LockLevel,
+}
+
+impl FileLock {
+    pub fn level(&self) -> LockLevel {
+        return self.level;
+    }
+
+    /// Raises the lock to `level`, through each level below it, or does
+    /// nothing if it is already held. Errors with `DatabaseLocked` if
+    /// another connection holds a lock in the way, in which case the
+    /// levels reached so far are kept: a writer left holding PENDING
+    /// keeps new readers out until it tries again or unlocks.
+    pub fn lock(&mut self, file: Option<&File>, level: LockLevel) -> Result<()> {
+        if level <= self.level {
+            return Ok(());
+        }
+        if level == LockLevel::Pending {
+            return Err(eyre!("PENDING is only taken on the way to EXCLUSIVE"));
+        }
+        if self.level == LockLevel::None {
+            // PENDING is read locked while SHARED is taken, so that no new
+            // reader gets in while a writer is waiting for EXCLUSIVE
+            set_lock(file, LockType::Read, PENDING_BYTE, 1)?;
+            let shared = set_lock(file, LockType::Read, SHARED_FIRST, SHARED_SIZE);
+            set_lock(file, LockType::Unlock, PENDING_BYTE, 1)?;
+            shared?;
+            self.level = LockLevel::Shared;
+        }
+        if level >= LockLevel::Reserved && self.level < LockLevel::Reserved {
+            set_lock(file, LockType::Write, RESERVED_BYTE, 1)?;
+            self.level = LockLevel::Reserved;
+        }
+        if level == LockLevel::Exclusive {
+            if self.level < LockLevel::Pending {
+                set_lock(file, LockType::Write, PENDING_BYTE, 1)?;
+                self.level = LockLevel::Pending;
+            }
+            set_lock(file, LockType::Write, SHARED_FIRST, SHARED_SIZE)?;
+            self.level = LockLevel::Exclusive;
+        }
+        return Ok(());
+    }
+
+    /// Lowers the lock to SHARED or NONE, or does nothing if it is
+    /// already no higher than that.
+    pub fn unlock(&mut self, file: Option<&File>, level: LockLevel) -> Result<()> {
+        if level >= self.level {
+            return Ok(());
+        }
+        match level {
+            LockLevel::None => set_lock(file, LockType::Unlock, 0, 0)?,
+            LockLevel::Shared => {
+                if self.level == LockLevel::Exclusive {
+                    set_lock(file, LockType::Read, SHARED_FIRST, SHARED_SIZE)?;
+                }
+                // PENDING and RESERVED
+                set_lock(file, LockType::Unlock, PENDING_BYTE, 2)?;
+            }
+            _ => return Err(eyre!("Cannot unlock the database to {:?}", level)),
+        }
+        self.level = level;
+        return Ok(());
+    }
+
+    /// Whether another connection holds RESERVED or higher, as a writer
+    /// does for as long as its rollback journal is in use.
+    pub fn is_reserved_elsewhere(&self, file: Option<&File>) -> Result<bool> {
+        return test_lock(file, RESERVED_BYTE);
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum LockType {
+    Read,
+    Write,
+    Unlock,
+}
+
+#[cfg(target_os = "linux")]
+const SET_LOCK: libc::c_int = libc::F_OFD_SETLK;
+#[cfg(target_os = "linux")]
+const GET_LOCK: libc::c_int = libc::F_OFD_GETLK;
+#[cfg(all(unix, not(target_os = "linux")))]
+const SET_LOCK: libc::c_int = libc::F_SETLK;
+#[cfg(all(unix, not(target_os = "linux")))]
+const GET_LOCK: libc::c_int = libc::F_GETLK;
+
+/// Sets the lock on `len` bytes of the file from `start`, where a length
+/// of 0 runs to the end of the file, without waiting for a conflicting
+/// lock to go away.
+#[cfg(unix)]
+fn set_lock(file: Option<&File>, lock_type: LockType, start: u64, len: u64) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let file = match file {
+        Some(file) => file,
+        None => return Ok(()),
+    };
+    // SAFETY: flock is plain old data, for which all zeroes is valid (and
+    // OFD locks require l_pid to be 0)
+    let mut flock: libc::flock = unsafe { std::mem::zeroed() };
+    flock.l_type = match lock_type {
+        LockType::Read => libc::F_RDLCK,
+        LockType::Write => libc::F_WRLCK,
+        LockType::Unlock => libc::F_UNLCK,
+    } as libc::c_short;
+    flock.l_whence = libc::SEEK_SET as libc::c_short;
+    flock.l_start = start as libc::off_t;
+    flock.l_len = len as libc::off_t;
+    // SAFETY: the descriptor stays open for as long as `file` is
+    // borrowed, and fcntl only reads the flock it is given
+    let result = unsafe { libc::fcntl(file.as_raw_fd(), SET_LOCK, &flock) };
+    if result == -1 {
+        let err = std::io::Error::last_os_error();
+        if matches!(err.raw_os_error(), Some(libc::EAGAIN) | Some(libc::EACCES)) {
+            return Err(DatabaseLocked.into());
+        }
+        return Err(err).wrap_err("Could not lock the database file.");
+    }
+    return Ok(());
+}
+
+/// Whether anyone else holds a lock on the byte at `offset`.
+#[cfg(unix)]
+fn test_lock(file: Option<&File>, offset: u64) -> Result<bool> {
+    use std::os::unix::io::AsRawFd;
+    let file = match file {
+        Some(file) => file,
+        None => return Ok(false),
+    };
+    // SAFETY: as in `set_lock`
+    let mut flock: libc::flock = unsafe { std::mem::zeroed() };
+    flock.l_type = libc::F_WRLCK as libc::c_short;
+    flock.l_whence = libc::SEEK_SET as libc::c_short;
+    flock.l_start = offset as libc::off_t;
+    flock.l_len = 1;
+    // SAFETY: as in `set_lock`, except that fcntl writes the lock in the
+    // way, if any, to flock
+    let result = unsafe { libc::fcntl(file.as_raw_fd(), GET_LOCK, &mut flock) };
+    if result == -1 {
+        let err = std::io::Error::last_os_error();
+        return Err(err).wrap_err("Could not check the locks on the database file.");
+    }
+    return Ok(flock.l_type != libc::F_UNLCK as libc::c_short);
+}
+
+#[cfg(not(unix))]
+fn test_lock(_file: Option<&File>, _offset: u64) -> Result<bool> {
+    return Ok(false);
+}
+
+#[cfg(not(unix))]
+fn set_lock(_file: Option<&File>, _lock_type: LockType, _start: u64, _len: u64) -> Result<()> {
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::Connection;
+
+    #[test]
+    fn connections_exclude_each_other() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let path = file.path().to_str().unwrap();
+        let mut writer = Connection::new(path).unwrap();
+        writer.execute("CREATE TABLE t(a)").unwrap();
+        let mut other = Connection::new(path).unwrap();
+
+        // a reserved lock leaves readers alone but keeps other writers out
+        writer.pager.write().lock(LockLevel::Reserved).unwrap();
+        assert!(other.execute("SELECT * FROM t").is_ok());
+        let err = other.execute("INSERT INTO t VALUES (1)").unwrap_err();
+        assert!(err.downcast_ref::<DatabaseLocked>().is_some());
+
+        // and an exclusive one keeps everyone out
+        writer.pager.write().lock(LockLevel::Exclusive).unwrap();
+        let err = other.execute("SELECT * FROM t").unwrap_err();
+        assert!(err.downcast_ref::<DatabaseLocked>().is_some());
+
+        writer.pager.write().unlock(LockLevel::None).unwrap();
+        other.execute("INSERT INTO t VALUES (1)").unwrap();
+        let result = writer.execute("SELECT count(*) FROM t").unwrap();
+        assert_eq!(result.rows[0][0].get_int_val(), Some(1));
+    }
+}
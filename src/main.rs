@@ -1,13 +1,13 @@
-use eyre::Result;
-use std::cell::RefCell;
-// use std::collections::HashMap;
-use std::rc::Rc;
+#![allow(clippy::needless_return, clippy::redundant_field_names)]
+
+use eyre::{eyre, Context};
+use std::io::{self, BufRead, Write};
 use std::{env, process::exit};
 
-// use sqlite_clone::btree::{Btree, Record};
-// use sqlite_clone::datatypes::{DataType, Value, VarInt};
-use sqlite_clone::pager::{FreelistPage, Pager};
-use sqlite_clone::DbOptions;
+use sqlite_clone::btree::Btree;
+use sqlite_clone::journal::RollbackJournal;
+use sqlite_clone::prelude::*;
+use sqlite_clone::{dump, exec, stats};
 
 fn main() -> Result<()> {
     let args: Vec<String> = env::args().collect();
@@ -16,158 +16,291 @@ fn main() -> Result<()> {
         exit(1);
     }
     let filename = &args[1];
-    let db_options = DbOptions::init(&filename)?;
-    println!("{:?}", db_options);
-
-    let pager = Rc::new(RefCell::new(Pager::new(&filename, &db_options)?));
-    let mut pgr_borrow = pager.borrow_mut();
-
-    let mut freelist_pages = Vec::new();
-    freelist_pages.push(db_options.first_freelist as usize);
-    let freelist =
-        FreelistPage::deserialize(&pgr_borrow.read_from_file(db_options.first_freelist as usize)?)?;
-    freelist_pages.extend(freelist.free_pages);
-
-    while let Some(next) = freelist.next_page_link {
-        freelist_pages.push(next);
-        let freelist = FreelistPage::deserialize(&pgr_borrow.read_from_file(next)?)?;
-        freelist_pages.extend(&freelist.free_pages);
+
+    let mut connection = Connection::new(filename).wrap_err("Failed to open or read file.")?;
+    let mut options = ShellOptions::default();
+    let stdin = io::stdin();
+    let mut stdout = io::stdout();
+    while let Some(input) = prompt(stdin.lock(), &mut stdout, "db > ") {
+        if !handle_input(&input, &mut connection, &mut options, &mut stdout) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Settings changed by meta-commands, which last for the session.
+#[derive(Debug, Default)]
+struct ShellOptions {
+    /// The most characters of a value to print, or 0 for no limit (see
+    /// `.maxwidth`).
+    max_width: usize,
+}
+
+/// Reads a line of input, or returns `None` at the end of the input.
+fn prompt<R, W>(mut reader: R, mut writer: W, prompt: &str) -> Option<String>
+where
+    R: BufRead,
+    W: Write,
+{
+    write!(&mut writer, "{}", prompt).expect("Unable to write");
+    writer.flush().unwrap();
+    let mut buffer = String::new();
+    let read = reader.read_line(&mut buffer).expect("Error reading input");
+    if read == 0 {
+        return None;
     }
-    // println!("{} {:?}", freelist_page_nums.len(), freelist_page_nums);
-
-    // let mut ints = Vec::new();
-    // let pg = pgr_borrow.read_from_file(db_options.first_freelist as usize)?;
-    // for n in 0..(pg.len() / 4) {
-    //     ints.push(sqlite_clone::parsing::be_u32(&pg[(n * 4)..(n * 4) + 4])? as usize);
-    // }
-    // println!("{:?}", ints);
-
-    let bytes_in = std::fs::read(&filename)?;
-
-    let mut bytes_out = Vec::new();
-    bytes_out.extend(db_options.serialize());
-    for pg_num in 1..=10 {
-        if !freelist_pages.contains(&pg_num) {
-            let page = pgr_borrow.get_page(pg_num)?;
-            println!("{} {}", pg_num, page.get_page_type());
-            bytes_out.extend(page.serialize());
-        } else {
+    return Some(buffer);
+}
+
+/// Runs a line of input: a "meta-command" starting with '.', or SQL.
+/// Returns false if the shell should exit.
+fn handle_input<W>(
+    input: &str,
+    conn: &mut Connection,
+    options: &mut ShellOptions,
+    writer: &mut W,
+) -> bool
+where
+    W: Write,
+{
+    let input = input.trim();
+    let result = if input.starts_with('.') {
+        if input == ".exit" {
+            return false;
         }
+        do_meta_command(conn, options, input, writer)
+    } else if input.is_empty() {
+        Ok(())
+    } else {
+        run_sql(conn, options, input, writer)
+    };
+    if let Err(err) = result {
+        writeln!(writer, "Error: {}", err).expect("Unable to write");
     }
+    writer.flush().unwrap();
+    return true;
+}
 
-    println!("Output length: {}", bytes_out.len());
-    let mut all_identical = None;
-    for (i, b) in bytes_out.iter().enumerate() {
-        if *b != bytes_in[i] {
-            println!("Output file is not the same as the input! At length {}", i);
-            all_identical = Some(i);
-            break;
+fn do_meta_command(
+    conn: &mut Connection,
+    options: &mut ShellOptions,
+    input: &str,
+    writer: &mut dyn Write,
+) -> Result<()> {
+    let args: Vec<&str> = input.split_whitespace().collect();
+    return match args[0] {
+        ".dump" => dump::dump(conn, &DumpOptions::parse(&args[1..])?, writer),
+        ".journal" => show_journal(conn, options, args.get(1), writer),
+        ".maxwidth" => match args.get(1).and_then(|width| width.parse().ok()) {
+            Some(width) => {
+                options.max_width = width;
+                Ok(())
+            }
+            None => Err(eyre!("usage: .maxwidth CHARACTERS (0 for no limit)")),
+        },
+        ".stats" => match args.get(1) {
+            Some(table) => stats::write_stats(&stats::table_stats(conn, table)?, writer),
+            None => Err(eyre!("usage: .stats TABLE")),
+        },
+        ".tables" => run_sql(
+            conn,
+            options,
+            "SELECT name FROM sqlite_schema WHERE type IN ('table', 'view') \
+             AND name NOT LIKE 'sqlite_%' ORDER BY name",
+            writer,
+        ),
+        command => Err(eyre!("unknown command: {}", command)),
+    };
+}
+
+/// Shows what the rollback journal of the database holds: with no
+/// argument, the pages the transaction in progress has changed; given a
+/// page number, the rows or index entries stored under that page before
+/// the transaction.
+fn show_journal(
+    conn: &Connection,
+    options: &ShellOptions,
+    page: Option<&&str>,
+    writer: &mut dyn Write,
+) -> Result<()> {
+    let filename = conn
+        .filename()
+        .ok_or_else(|| eyre!("the database is not in a file"))?;
+    let journal = match RollbackJournal::open(filename)? {
+        Some(journal) => journal,
+        None => return Err(eyre!("no rollback journal for {}", filename)),
+    };
+    let page_num = match page {
+        Some(page) => page
+            .parse::<usize>()
+            .map_err(|_| eyre!("usage: .journal [PAGE]"))?,
+        None => {
+            writeln!(
+                writer,
+                "{} pages before the transaction; changed: {:?}",
+                journal.initial_pages,
+                journal.page_numbers()
+            )?;
+            return Ok(());
         }
+    };
+    if journal.page(page_num).is_none() {
+        writeln!(writer, "page {} is unchanged", page_num)?;
     }
-    if all_identical.is_none() {
-        println!("Output file is the same as the input");
-    } else {
-        let i = all_identical.unwrap();
-        // let min_val = if i < 5 { 0 } else { i - 5 };
-        let min_val = i - 20;
-        let max_val = if i + 5 > bytes_in.len() || i + 5 > bytes_out.len() {
-            std::cmp::min(bytes_in.len(), bytes_out.len())
-        } else {
-            i + 5
-        };
-        println!("{:?}", &bytes_in[min_val..max_val]);
-        println!("{:?}", &bytes_out[min_val..max_val]);
+    let before = Connection::open_before_transaction(filename)?
+        .ok_or_else(|| eyre!("no rollback journal for {}", filename))?;
+    let tree = Btree::new(
+        format!("page {}", page_num),
+        String::new(),
+        page_num,
+        before.pager.clone(),
+    );
+    for row in tree.scan() {
+        let (row_id, record) = row?;
+        let mut columns = vec![row_id.0.to_string()];
+        columns.extend(
+            record
+                .values
+                .iter()
+                .map(|value| display_value(value, options.max_width)),
+        );
+        writeln!(writer, "{}", columns.join("|"))?;
     }
+    return Ok(());
+}
 
-    // let schema = Btree::new(
-    //     "sqlite_schema".to_string(),
-    //     "sqlite_schema".to_string(),
-    //     1,
-    //     &db_options,
-    //     pager.clone(),
-    // );
-    // let sqlite_schema = schema.list_records();
-
-    // sqlite_schema has the following layout:
-    // CREATE TABLE sqlite_schema(
-    //     type text,
-    //     name text,
-    //     tbl_name text,
-    //     rootpage integer,
-    //     sql text
-    // );
-
-    // let mut tables = HashMap::new();
-    // let mut indexes = HashMap::new();
-    // for (_, table) in sqlite_schema {
-    //     let table_vals = table.values;
-
-    //     match &table_vals[0] {
-    //         Value::String(ttype) if ttype == "table" || ttype == "index" => {
-    //             // rootpage should always be an i8 value for tables and
-    //             // indexes, and 0 or NULL for views, triggers, and
-    //             // virtual tables
-    //             let name = match &table_vals[1] {
-    //                 Value::String(val) => Ok(val.clone()),
-    //                 _ => Err("Unexpected value"),
-    //             }
-    //             .unwrap();
-    //             let table_name = match &table_vals[2] {
-    //                 Value::String(val) => Ok(val.clone()),
-    //                 _ => Err("Unexpected value"),
-    //             }
-    //             .unwrap();
-    //             let root_page = match &table_vals[3] {
-    //                 Value::Int8(val) => Ok(*val as usize),
-    //                 _ => Err("Unexpected value"),
-    //             }
-    //             .unwrap();
-
-    //             if ttype == "table" {
-    //                 tables.insert(
-    //                     name.clone(),
-    //                     Btree::new(name, table_name, root_page, &db_options, pager.clone()),
-    //                 );
-    //             } else if ttype == "index" {
-    //                 indexes.insert(
-    //                     name.clone(),
-    //                     Btree::new(name, table_name, root_page, &db_options, pager.clone()),
-    //                 );
-    //             }
-    //         }
-    //         _ => (),
-    //     }
-    // }
-
-    // println!("Tables:");
-    // for key in tables.keys() {
-    //     println!(" - {}", key);
-    // }
-    // println!("Indexes:");
-    // for key in indexes.keys() {
-    //     println!(" - {}", key);
-    // }
-
-    // // navigate an index
-    // let podcasts_index = indexes.get("sqlite_autoindex_podcasts_1").unwrap();
-    // let index_str = "https://feeds.megaphone.fm/replyall".to_string();
-
-    // let index = Record::new(
-    //     vec![DataType::String(index_str.len())],
-    //     vec![Value::String(index_str)],
-    // );
-    // let res = podcasts_index.get_index(index);
-    // println!("{:?}", res);
-
-    // // pull corresponding row from table
-    // if let Some(rec) = res {
-    //     let row_id = rec.values.last().unwrap().get_int_val();
-    //     if let Some(row_id) = row_id {
-    //         let podcasts_table = tables.get("podcasts").unwrap();
-    //         let row = podcasts_table.get_row(VarInt::new(row_id));
-    //         println!("{:?}", row);
-    //     }
-    // }
+/// Runs a statement and prints each row of the result, with the columns
+/// separated by '|' as in the SQLite shell.
+fn run_sql(
+    conn: &mut Connection,
+    options: &ShellOptions,
+    sql: &str,
+    writer: &mut dyn Write,
+) -> Result<()> {
+    let result = conn.execute(sql)?;
+    for row in result.rows {
+        let columns: Vec<String> = row
+            .iter()
+            .map(|value| display_value(value, options.max_width))
+            .collect();
+        writeln!(writer, "{}", columns.join("|"))?;
+    }
+    return Ok(());
+}
 
-    Ok(())
+/// Formats a value for the terminal. Text is printed as it is, except
+/// that control characters (including NUL and newlines) are escaped, as
+/// are the bytes of a blob that are not valid UTF-8, so that nothing
+/// printed can garble the terminal or be mistaken for a row separator.
+/// Values longer than `max_width` characters (unless it is 0) are cut
+/// short, ending in "...".
+fn display_value(value: &Value, max_width: usize) -> String {
+    let mut text = String::new();
+    match value {
+        Value::Blob(bytes) | Value::Internal(bytes) => {
+            for chunk in bytes.utf8_chunks() {
+                escape_text(chunk.valid(), &mut text);
+                for byte in chunk.invalid() {
+                    text.push_str(&format!("\\x{:02x}", byte));
+                }
+            }
+        }
+        value => escape_text(&exec::to_text(value).unwrap_or_default(), &mut text),
+    }
+    if max_width > 0 && text.chars().count() > max_width {
+        let cut = text
+            .char_indices()
+            .nth(max_width.saturating_sub(3))
+            .map_or(text.len(), |(i, _)| i);
+        text.truncate(cut);
+        text.push_str("...");
+    }
+    return text;
+}
+
+/// Appends text to `output` with its control characters escaped.
+fn escape_text(text: &str, output: &mut String) {
+    for c in text.chars() {
+        match c {
+            '\0' => output.push_str("\\0"),
+            '\t' => output.push_str("\\t"),
+            '\n' => output.push_str("\\n"),
+            '\r' => output.push_str("\\r"),
+            c if c.is_control() => output.push_str(&format!("\\x{:02x}", c as u32)),
+            c => output.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_insert_retrieve() {
+        let file = NamedTempFile::new().unwrap();
+        let mut connection = Connection::new(file.path().to_str().unwrap()).unwrap();
+        let commands = [
+            "CREATE TABLE users(id INTEGER PRIMARY KEY, name TEXT, email TEXT)",
+            "INSERT INTO users VALUES (1, 'user1', 'person1@example.com'), (2, NULL, 1.5)",
+            "SELECT * FROM users",
+            ".tables",
+            ".stats users",
+            "SELECT * FROM nothing",
+            ".nothing",
+        ];
+        let expected_outputs = [
+            "",
+            "",
+            "1|user1|person1@example.com\n2||1.5\n",
+            "users\n",
+            "table users: 2 rows\ncolumn|nulls|distinct|min|max|avg_width\n\
+             id|0|2|1|2|0.0\nname|1|1|'user1'|'user1'|2.5\n\
+             email|0|2|'1.5'|'person1@example.com'|11.0\n",
+            "Error: no such table: nothing\n",
+            "Error: unknown command: .nothing\n",
+        ];
+
+        for (i, command) in commands.iter().enumerate() {
+            let mut output = Vec::new();
+            let input = prompt(command.as_bytes(), &mut output, "db > ").unwrap();
+            let mut output2 = Vec::new();
+            assert!(handle_input(
+                &input,
+                &mut connection,
+                &mut ShellOptions::default(),
+                &mut output2
+            ));
+            let output2 = String::from_utf8(output2).expect("Not UTF-8");
+            assert_eq!(expected_outputs[i], output2);
+        }
+        let mut options = ShellOptions::default();
+        assert!(!handle_input(
+            ".exit\n",
+            &mut connection,
+            &mut options,
+            &mut Vec::new()
+        ));
+    }
+
+    #[test]
+    fn display_values() {
+        let text = |s: &str| Value::String(s.to_string());
+        assert_eq!(display_value(&text("plain"), 0), "plain");
+        assert_eq!(
+            display_value(&text("a\0b\nc\x1b[2J"), 0),
+            "a\\0b\\nc\\x1b[2J"
+        );
+        assert_eq!(
+            display_value(&Value::Blob(vec![b'o', b'k', 0xff, 0x07]), 0),
+            "ok\\xff\\x07"
+        );
+        assert_eq!(display_value(&Value::Null, 0), "");
+        assert_eq!(display_value(&text("abcdefghij"), 10), "abcdefghij");
+        assert_eq!(display_value(&text("abcdefghijk"), 10), "abcdefg...");
+        assert_eq!(display_value(&text("ééééé"), 4), "é...");
+        assert_eq!(display_value(&Value::Int64(123456), 0), "123456");
+    }
 }
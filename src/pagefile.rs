@@ -0,0 +1,527 @@
+use eyre::{eyre, Result, WrapErr};
+use memmap2::Mmap;
+use positioned_io::{ReadAt, WriteAt};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::ErrorKind;
+
+use crate::limits::Limits;
+use crate::lock::{FileLock, LockLevel};
+
+/// How pages are read from the database file when they are not covered
+/// by the memory map.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IoMode {
+    /// Reads go through the operating system's page cache.
+    Buffered,
+    /// Reads bypass the operating system's page cache (`O_DIRECT`).
+    /// Only supported on Linux.
+    Direct,
+}
+
+/// How much the page cache holds, as a number of pages or of bytes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CacheSize {
+    Pages(usize),
+    Bytes(usize),
+}
+
+impl CacheSize {
+    /// Reads a size as `PRAGMA cache_size` and the file header give it:
+    /// a number of pages, or if negative, a number of KiB.
+    pub fn from_pragma(size: i64) -> Self {
+        if size < 0 {
+            return Self::Bytes(size.unsigned_abs() as usize * 1024);
+        }
+        return Self::Pages(size as usize);
+    }
+
+    /// The size as `PRAGMA cache_size` gives it, the inverse of
+    /// `from_pragma`.
+    pub fn to_pragma(&self) -> i64 {
+        return match *self {
+            Self::Pages(pages) => pages as i64,
+            Self::Bytes(bytes) => -((bytes / 1024) as i64),
+        };
+    }
+
+    /// The number of pages of the given size that the cache holds, which
+    /// is at least one.
+    pub fn pages(&self, page_size: usize) -> usize {
+        let pages = match *self {
+            Self::Pages(pages) => pages,
+            Self::Bytes(bytes) => bytes / page_size,
+        };
+        return std::cmp::max(pages, 1);
+    }
+}
+
+/// Settings for how the pager accesses the database file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PagerConfig {
+    /// The maximum number of bytes at the start of the file to access
+    /// through a memory map, as with `PRAGMA mmap_size`. 0 disables
+    /// memory mapping.
+    pub mmap_size: u64,
+    /// The number of pages following a page read from the file that are
+    /// read along with it, in the same request.
+    pub read_ahead: usize,
+    /// The number of pages a scan moving forward through a B-tree reads
+    /// ahead of itself: when it comes to a page that is not in memory,
+    /// that page and the pages it will visit after it, as far as the
+    /// page above them lists them, are read in batches of consecutive
+    /// pages rather than one at a time as they are reached.
+    pub scan_read_ahead: usize,
+    pub io_mode: IoMode,
+    /// Validate the structure of every page read from the file before
+    /// it is used, so that corruption is reported as a `CorruptPage`
+    /// error rather than causing wrong results or panics later.
+    pub verify_pages: bool,
+    /// Share one page cache between all connections to the same file in
+    /// the process. See `pager::open_shared`.
+    pub shared_cache: bool,
+    /// Read page 1, the root page of every table and index, and the
+    /// pages directly below those roots into the cache as the database
+    /// is opened, so that the first queries don't wait on them one at a
+    /// time.
+    pub preload: bool,
+    /// Bounds on the size and shape of the database, checked as it is
+    /// opened and read.
+    pub limits: Limits,
+    /// How much of the file the page cache holds. If not set, the cache
+    /// size suggested in the database header is used, or if the header
+    /// suggests none, 500 pages.
+    pub cache_size: Option<CacheSize>,
+}
+
+impl Default for PagerConfig {
+    fn default() -> Self {
+        return Self {
+            mmap_size: 0,
+            read_ahead: 0,
+            scan_read_ahead: 0,
+            io_mode: IoMode::Buffered,
+            verify_pages: false,
+            shared_cache: false,
+            preload: false,
+            limits: Limits::default(),
+            cache_size: None,
+        };
+    }
+}
+
+impl PagerConfig {
+    pub const MMAP_SIZE_VAR: &'static str = "SQLITE_CLONE_MMAP_SIZE";
+    pub const READ_AHEAD_VAR: &'static str = "SQLITE_CLONE_READ_AHEAD";
+    pub const SCAN_READ_AHEAD_VAR: &'static str = "SQLITE_CLONE_SCAN_READ_AHEAD";
+    pub const IO_MODE_VAR: &'static str = "SQLITE_CLONE_IO_MODE";
+    pub const VERIFY_PAGES_VAR: &'static str = "SQLITE_CLONE_VERIFY_PAGES";
+    pub const SHARED_CACHE_VAR: &'static str = "SQLITE_CLONE_SHARED_CACHE";
+    pub const PRELOAD_VAR: &'static str = "SQLITE_CLONE_PRELOAD";
+    pub const CACHE_SIZE_VAR: &'static str = "SQLITE_CLONE_CACHE_SIZE";
+
+    /// Reads the configuration from the environment, so that it can be
+    /// tuned per deployment. Settings that are not given keep their
+    /// default values.
+    pub fn from_env() -> Result<Self> {
+        let mut config = Self::default();
+        if let Ok(value) = std::env::var(Self::MMAP_SIZE_VAR) {
+            config.mmap_size = value
+                .trim()
+                .parse()
+                .wrap_err_with(|| format!("Invalid {}: {}", Self::MMAP_SIZE_VAR, value))?;
+        }
+        if let Ok(value) = std::env::var(Self::READ_AHEAD_VAR) {
+            config.read_ahead = value
+                .trim()
+                .parse()
+                .wrap_err_with(|| format!("Invalid {}: {}", Self::READ_AHEAD_VAR, value))?;
+        }
+        if let Ok(value) = std::env::var(Self::SCAN_READ_AHEAD_VAR) {
+            config.scan_read_ahead = value
+                .trim()
+                .parse()
+                .wrap_err_with(|| format!("Invalid {}: {}", Self::SCAN_READ_AHEAD_VAR, value))?;
+        }
+        if let Ok(value) = std::env::var(Self::IO_MODE_VAR) {
+            config.io_mode = match value.trim().to_lowercase().as_str() {
+                "buffered" => IoMode::Buffered,
+                "direct" => IoMode::Direct,
+                _ => return Err(eyre!("Invalid {}: {}", Self::IO_MODE_VAR, value)),
+            };
+        }
+        if let Ok(value) = std::env::var(Self::VERIFY_PAGES_VAR) {
+            config.verify_pages = parse_flag(Self::VERIFY_PAGES_VAR, &value)?;
+        }
+        if let Ok(value) = std::env::var(Self::SHARED_CACHE_VAR) {
+            config.shared_cache = parse_flag(Self::SHARED_CACHE_VAR, &value)?;
+        }
+        if let Ok(value) = std::env::var(Self::PRELOAD_VAR) {
+            config.preload = parse_flag(Self::PRELOAD_VAR, &value)?;
+        }
+        if let Ok(value) = std::env::var(Self::CACHE_SIZE_VAR) {
+            let size = value
+                .trim()
+                .parse()
+                .wrap_err_with(|| format!("Invalid {}: {}", Self::CACHE_SIZE_VAR, value))?;
+            config.cache_size = Some(CacheSize::from_pragma(size));
+        }
+        return Ok(config);
+    }
+}
+
+fn parse_flag(name: &str, value: &str) -> Result<bool> {
+    return match value.trim().to_lowercase().as_str() {
+        "1" | "true" | "on" => Ok(true),
+        "0" | "false" | "off" => Ok(false),
+        _ => Err(eyre!("Invalid {}: {}", name, value)),
+    };
+}
+
+/// The number of times a read that returns no data before the end of
+/// the file is tried again before giving up, as happens on some network
+/// file systems.
+const MAX_READ_RETRIES: usize = 3;
+
+/// A read that ended before the requested bytes were filled, because
+/// the file ended early or stopped returning data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShortRead {
+    pub offset: u64,
+    pub expected: usize,
+    pub read: usize,
+}
+
+impl fmt::Display for ShortRead {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(
+            f,
+            "disk I/O error (short read at offset {}: expected {} bytes, got {})",
+            self.offset, self.expected, self.read
+        );
+    }
+}
+
+impl std::error::Error for ShortRead {}
+
+/// Direct I/O requires buffers and offsets aligned to the device's block
+/// size; 4096 covers all common devices.
+const DIRECT_IO_ALIGNMENT: usize = 4096;
+
+/// The database file, read according to a `PagerConfig`, or an image of
+/// one held in memory.
+#[derive(Debug)]
+pub struct PageFile {
+    storage: Storage,
+    lock: FileLock,
+}
+
+#[derive(Debug)]
+enum Storage {
+    File {
+        file: File,
+        mmap: Option<Mmap>,
+        direct: Option<File>,
+    },
+    Memory(Vec<u8>),
+    /// An image that is only read, in place, such as one included in the
+    /// program with `include_bytes!`.
+    Bytes(&'static [u8]),
+}
+
+impl PageFile {
+    pub fn open(filename: &str, config: &PagerConfig) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(filename)
+            .wrap_err("Could not open file.")?;
+
+        let file_length = file.metadata()?.len();
+        let mmap = if config.mmap_size > 0 && file_length > 0 {
+            let len = std::cmp::min(config.mmap_size, file_length) as usize;
+            // SAFETY: the map is only read through `read_at`, which
+            // copies out of it, and never beyond the length of the file
+            // when it was opened. Pages are only ever replaced in place,
+            // except by `set_len`, which drops the map before shrinking
+            // the file.
+            let mmap = unsafe { memmap2::MmapOptions::new().len(len).map(&file) }
+                .wrap_err("Could not memory map file.")?;
+            Some(mmap)
+        } else {
+            None
+        };
+
+        let direct = match config.io_mode {
+            IoMode::Buffered => None,
+            IoMode::Direct => Some(open_direct(filename)?),
+        };
+
+        return Ok(Self {
+            storage: Storage::File {
+                file: file,
+                mmap: mmap,
+                direct: direct,
+            },
+            lock: FileLock::default(),
+        });
+    }
+
+    /// Uses an image of a database file, held in memory, in place of a
+    /// file. Writes are made to the image.
+    pub fn memory(image: Vec<u8>) -> Self {
+        return Self {
+            storage: Storage::Memory(image),
+            lock: FileLock::default(),
+        };
+    }
+
+    /// Reads a database from an image of its file without copying it.
+    /// The image can never be written to.
+    pub fn bytes(image: &'static [u8]) -> Self {
+        return Self {
+            storage: Storage::Bytes(image),
+            lock: FileLock::default(),
+        };
+    }
+
+    /// The underlying file, unless the database is held in memory.
+    pub fn file(&self) -> Option<&File> {
+        return match &self.storage {
+            Storage::File { file, .. } => Some(file),
+            Storage::Memory(_) | Storage::Bytes(_) => None,
+        };
+    }
+
+    pub fn file_mut(&mut self) -> Option<&mut File> {
+        return match &mut self.storage {
+            Storage::File { file, .. } => Some(file),
+            Storage::Memory(_) | Storage::Bytes(_) => None,
+        };
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        return matches!(self.storage, Storage::Bytes(_));
+    }
+
+    pub fn len(&self) -> Result<u64> {
+        return match &self.storage {
+            Storage::File { file, .. } => Ok(file.metadata()?.len()),
+            Storage::Memory(image) => Ok(image.len() as u64),
+            Storage::Bytes(image) => Ok(image.len() as u64),
+        };
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        return Ok(self.len()? == 0);
+    }
+
+    /// Reads `buf.len()` bytes starting at `offset`. It is a `ShortRead`
+    /// error if they are not all there to be read, except for an image
+    /// held in memory, where bytes past its end are left as they are.
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let (file, mmap, direct) = match &self.storage {
+            Storage::File { file, mmap, direct } => (file, mmap, direct),
+            Storage::Memory(image) => return read_image(image, offset, buf),
+            Storage::Bytes(image) => return read_image(image, offset, buf),
+        };
+        if let Some(mmap) = mmap {
+            let start = offset as usize;
+            if start + buf.len() <= mmap.len() {
+                buf.copy_from_slice(&mmap[start..start + buf.len()]);
+                return Ok(());
+            }
+        }
+        if let Some(direct) = direct {
+            return read_direct(direct, offset, buf);
+        }
+        let read = read_fully(file, offset, buf)?;
+        if read < buf.len() {
+            return Err(short_read(offset, buf.len(), read));
+        }
+        return Ok(());
+    }
+
+    /// Writes all of `buf` at `offset`, extending the file if needed.
+    /// Writes always go through the regular file handle.
+    pub fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<()> {
+        match &mut self.storage {
+            Storage::File { file, .. } => file.write_all_at(offset, buf)?,
+            Storage::Memory(image) => {
+                let start = offset as usize;
+                if image.len() < start + buf.len() {
+                    image.resize(start + buf.len(), 0);
+                }
+                image[start..start + buf.len()].copy_from_slice(buf);
+            }
+            Storage::Bytes(_) => return Err(eyre!("attempt to write a readonly database")),
+        }
+        return Ok(());
+    }
+
+    pub fn lock_level(&self) -> LockLevel {
+        return self.lock.level();
+    }
+
+    /// Raises the lock held on the file to `level` (see `FileLock`). An
+    /// image held in memory only keeps track of the level.
+    pub fn lock(&mut self, level: LockLevel) -> Result<()> {
+        let file = match &self.storage {
+            Storage::File { file, .. } => Some(file),
+            Storage::Memory(_) | Storage::Bytes(_) => None,
+        };
+        return self.lock.lock(file, level);
+    }
+
+    /// Lowers the lock held on the file to SHARED or NONE.
+    pub fn unlock(&mut self, level: LockLevel) -> Result<()> {
+        let file = match &self.storage {
+            Storage::File { file, .. } => Some(file),
+            Storage::Memory(_) | Storage::Bytes(_) => None,
+        };
+        return self.lock.unlock(file, level);
+    }
+
+    pub fn is_reserved_elsewhere(&self) -> Result<bool> {
+        return self.lock.is_reserved_elsewhere(self.file());
+    }
+
+    /// Drops the memory map if the file is now shorter than it, as it
+    /// may be once another process has truncated the file.
+    pub fn check_map(&mut self) -> Result<()> {
+        if let Storage::File { file, mmap, .. } = &mut self.storage {
+            let len = file.metadata()?.len();
+            if mmap.as_ref().is_some_and(|mmap| mmap.len() as u64 > len) {
+                *mmap = None;
+            }
+        }
+        return Ok(());
+    }
+
+    /// Truncates or extends the file to `len` bytes. A memory map that
+    /// would reach past the new end is dropped first, as reading a mapped
+    /// page there would fault, and pages are read from the file instead.
+    pub fn set_len(&mut self, len: u64) -> Result<()> {
+        match &mut self.storage {
+            Storage::File { file, mmap, .. } => {
+                if mmap.as_ref().is_some_and(|mmap| mmap.len() as u64 > len) {
+                    *mmap = None;
+                }
+                file.set_len(len)?;
+            }
+            Storage::Memory(image) => image.resize(len as usize, 0),
+            Storage::Bytes(_) => return Err(eyre!("attempt to write a readonly database")),
+        }
+        return Ok(());
+    }
+}
+
+/// Reads from an image of a file held in memory. Bytes past the end of
+/// the image are left as they are.
+fn read_image(image: &[u8], offset: u64, buf: &mut [u8]) -> Result<()> {
+    let start = std::cmp::min(offset as usize, image.len());
+    let end = std::cmp::min(start + buf.len(), image.len());
+    buf[..end - start].copy_from_slice(&image[start..end]);
+    return Ok(());
+}
+
+fn short_read(offset: u64, expected: usize, read: usize) -> eyre::Report {
+    return eyre::Report::new(ShortRead {
+        offset: offset,
+        expected: expected,
+        read: read,
+    });
+}
+
+/// Reads as much of `buf` as the file holds, returning the number of
+/// bytes read. Reads interrupted by a signal are restarted, and a read
+/// that returns nothing before the end of the file is tried again a few
+/// times before it is taken as the end.
+fn read_fully(file: &File, offset: u64, buf: &mut [u8]) -> Result<usize> {
+    let mut total = 0;
+    let mut retries = 0;
+    while total < buf.len() {
+        let n = match file.read_at(offset + total as u64, &mut buf[total..]) {
+            Ok(n) => n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        };
+        if n == 0 {
+            if retries == MAX_READ_RETRIES || offset + total as u64 >= file.metadata()?.len() {
+                break;
+            }
+            retries += 1;
+            continue;
+        }
+        total += n;
+    }
+    return Ok(total);
+}
+
+fn read_direct(file: &File, offset: u64, buf: &mut [u8]) -> Result<()> {
+    let align = DIRECT_IO_ALIGNMENT as u64;
+    let start = offset - offset % align;
+    let end = (offset + buf.len() as u64).div_ceil(align) * align;
+    let mut aligned = AlignedBuffer::new((end - start) as usize);
+    let read = read_fully(file, start, aligned.as_mut_slice())?;
+    let skip = (offset - start) as usize;
+    // the aligned range may run past the end of the file; only the part
+    // that was asked for has to be there
+    if read < skip + buf.len() {
+        return Err(short_read(offset, buf.len(), read.saturating_sub(skip)));
+    }
+    buf.copy_from_slice(&aligned.as_mut_slice()[skip..skip + buf.len()]);
+    return Ok(());
+}
+
+#[cfg(target_os = "linux")]
+fn open_direct(filename: &str) -> Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    return OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(filename)
+        .wrap_err("Could not open file for direct I/O.");
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_direct(_filename: &str) -> Result<File> {
+    return Err(eyre!("Direct I/O is not supported on this platform."));
+}
+
+/// A zeroed heap buffer aligned for direct I/O.
+struct AlignedBuffer {
+    ptr: *mut u8,
+    layout: std::alloc::Layout,
+}
+
+impl AlignedBuffer {
+    fn new(len: usize) -> Self {
+        let layout = std::alloc::Layout::from_size_align(len.max(1), DIRECT_IO_ALIGNMENT)
+            .expect("Invalid buffer layout");
+        // SAFETY: the layout has a non-zero size
+        let ptr = unsafe { std::alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            std::alloc::handle_alloc_error(layout);
+        }
+        return Self {
+            ptr: ptr,
+            layout: layout,
+        };
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        // SAFETY: ptr points to an initialized allocation of layout.size()
+        // bytes that is owned by this buffer
+        return unsafe { std::slice::from_raw_parts_mut(self.ptr, self.layout.size()) };
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        // SAFETY: ptr was allocated with this layout in `new`
+        unsafe { std::alloc::dealloc(self.ptr, self.layout) };
+    }
+}
@@ -1,136 +1,1738 @@
+use derive_try_from_primitive::TryFromPrimitive;
 use eyre::{eyre, Context, Result};
+use lazy_static::lazy_static;
 use lru::LruCache;
-use positioned_io::ReadAt;
+use positioned_io::{ReadAt, WriteAt};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
 use std::fs::{File, OpenOptions};
+use std::path::PathBuf;
+use std::sync::{
+    Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak,
+};
 
-use crate::btree::BtreePage;
+use crate::btree::{BtreePage, PageHeader, PageType};
+use crate::integrity::{self, CorruptPage};
+use crate::journal::StatementJournal;
+use crate::limits::Limits;
+use crate::lock::{LockLevel, PENDING_BYTE};
+use crate::pagefile::{CacheSize, PageFile, PagerConfig};
 use crate::parsing;
 use crate::DbOptions;
 
-const CACHE_SIZE: usize = 500;
+/// The number of pages the cache holds unless it is configured to hold
+/// some other amount.
+pub const CACHE_SIZE: usize = 500;
+
+/// A page held in memory by the pager. B-tree pages are parsed when they
+/// are first used as such; other pages, such as overflow pages, are kept
+/// as the raw bytes of the page. Parsed pages are shared with those
+/// reading them (see `Pager::get_page`), and copied only when a page is
+/// changed while it is still being read, or its original image is kept
+/// for the statement journal.
+#[derive(Debug, Clone)]
+pub enum Page {
+    Btree(Arc<BtreePage>),
+    Raw(Vec<u8>),
+}
+
+impl Page {
+    /// The bytes of the page as they are stored, which begin after the
+    /// file header on page 1.
+    fn serialize(&self) -> Vec<u8> {
+        return match self {
+            Self::Btree(pg) => pg.serialize(),
+            Self::Raw(bytes) => bytes.clone(),
+        };
+    }
+
+    /// The whole page as it is written to the file, with the file header
+    /// from `header` at the start of page 1.
+    fn serialize_page(&self, page_num: usize, header: &DbOptions) -> Result<Vec<u8>> {
+        return match self {
+            Self::Btree(pg) => pg.serialize_page(page_num, header),
+            Self::Raw(bytes) => {
+                let mut bytes = bytes.clone();
+                if page_num == 1 {
+                    let file_header = header.serialize();
+                    bytes[..file_header.len()].copy_from_slice(&file_header);
+                }
+                Ok(bytes)
+            }
+        };
+    }
+}
 
 #[derive(Debug)]
 pub struct Pager {
-    file_descriptor: File,
-    file_length: usize,
-    cache: LruCache<usize, BtreePage>,
+    file_descriptor: PageFile,
+    config: PagerConfig,
+    /// The pages read from the file, which readers add to while holding
+    /// the pager for reading only, so they have a lock of their own.
+    cache: Mutex<LruCache<usize, Page>>,
+    dirty: LruCache<usize, Page>,
+    spill: SpillFile,
+    spill_threshold: Option<usize>,
+    /// The size the cache was last given, which is kept so that a size
+    /// in bytes can be turned into pages again if the page size changes.
+    cache_size: CacheSize,
     pub num_pages: usize,
+    /// The number of pages in the file itself, which doesn't include
+    /// pages added since the last flush, and still includes those
+    /// truncated since then.
+    file_pages: usize,
+    /// The first freelist trunk page, or 0 if the freelist is empty, and
+    /// the number of pages on the freelist (trunks included). Like
+    /// `num_pages`, these are copied into the file header when it is
+    /// written.
+    pub first_freelist: usize,
+    pub num_freelist: usize,
+    /// The largest root page of an auto-vacuum database, which keeps its
+    /// root pages together at the start of the file, so that `PRAGMA
+    /// incremental_vacuum` never has to move one (see
+    /// `vacuum::allocate_root`). 0 for any other database. Also copied
+    /// into the file header when it is written.
+    pub largest_root: usize,
+    /// `num_pages`, `first_freelist`, `num_freelist` and `largest_root` as
+    /// they were when each active statement began, for rolling them back.
+    count_saves: Vec<(usize, usize, usize, usize)>,
+    /// Whether the database is an auto-vacuum database, which has pointer
+    /// map pages among its other pages (see `PtrmapPage`). Every page
+    /// written with `insert` has its children and overflow pages recorded
+    /// in the pointer map, and every page freed is recorded as free.
+    auto_vacuum: bool,
+    /// The change counter in the file header as this pager last read or
+    /// wrote it, for telling when another process has changed the file.
+    change_counter: u32,
+    page_size: usize,
+    reserved_space: u8,
+    journal: StatementJournal,
+    generation: u64,
+    schema_version: u64,
+    metrics: Mutex<Metrics>,
+}
+
+/// Counts of the work done reading the database, which only go up: the
+/// work a statement does is the difference between the counts before
+/// and after it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// Pages read from the file. Pages found in the cache are not
+    /// counted.
+    pub pages_read: u64,
+    /// Rows of tables and entries of indexes read from their B-trees.
+    pub rows_examined: u64,
+    /// Pages asked for that were already in memory, and that had to be
+    /// read from the file.
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+}
+
+impl Pager {
+    pub fn new(filename: &str, db_options: &DbOptions) -> Result<Self> {
+        return Self::with_config(filename, db_options, PagerConfig::default());
+    }
+
+    pub fn with_config(
+        filename: &str,
+        db_options: &DbOptions,
+        config: PagerConfig,
+    ) -> Result<Self> {
+        let file = PageFile::open(filename, &config)?;
+        return Self::with_page_file(file, db_options, config);
+    }
+
+    /// Opens a pager over an image of a database file held in memory,
+    /// such as one made by `serialize`.
+    pub fn in_memory(image: Vec<u8>, db_options: &DbOptions) -> Result<Self> {
+        return Self::with_page_file(PageFile::memory(image), db_options, PagerConfig::default());
+    }
+
+    /// Reads a database from an image of its file, in place, without
+    /// copying it. The database cannot be modified.
+    pub fn from_bytes(image: &'static [u8], db_options: &DbOptions) -> Result<Self> {
+        return Self::with_page_file(PageFile::bytes(image), db_options, PagerConfig::default());
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        return self.file_descriptor.is_read_only();
+    }
+
+    fn with_page_file(file: PageFile, db_options: &DbOptions, config: PagerConfig) -> Result<Self> {
+        let file_length = file.len()? as usize;
+
+        if !file_length.is_multiple_of(db_options.page_size) {
+            return Err(eyre!(
+                "DB file is not a whole number of pages. Corrupt file."
+            ));
+        }
+
+        let num_pages = file_length / db_options.page_size;
+        if num_pages > config.limits.max_page_count {
+            return Err(eyre!(
+                "DB file has {} pages, more than the limit of {}.",
+                num_pages,
+                config.limits.max_page_count
+            ));
+        }
+
+        // the suggested cache size in the header is used unless the
+        // configuration gives one
+        let cache_size = match config.cache_size {
+            Some(size) => size,
+            None if db_options.cache_size != 0 => {
+                CacheSize::from_pragma(db_options.cache_size as i64)
+            }
+            None => CacheSize::Pages(CACHE_SIZE),
+        };
+        let cache_pages = cache_size.pages(db_options.page_size);
+        let mut pager = Self {
+            file_descriptor: file,
+            config: config,
+            cache: Mutex::new(LruCache::new(cache_pages)),
+            dirty: LruCache::unbounded(),
+            spill: SpillFile::new(),
+            spill_threshold: Some(cache_pages),
+            cache_size: cache_size,
+            num_pages: num_pages,
+            file_pages: num_pages,
+            first_freelist: db_options.first_freelist as usize,
+            num_freelist: db_options.num_freelist as usize,
+            largest_root: db_options.largest_root_page as usize,
+            count_saves: Vec::new(),
+            auto_vacuum: db_options.largest_root_page != 0,
+            change_counter: db_options.change_counter,
+            page_size: db_options.page_size,
+            reserved_space: db_options.reserved_space,
+            journal: StatementJournal::new(),
+            generation: 0,
+            schema_version: 0,
+            metrics: Mutex::new(Metrics::default()),
+        };
+        if file_length == 0 {
+            // New database file. Page 1 is the root of an empty
+            // sqlite_schema table, which is only written out once
+            // something is added to the database.
+            let mut page =
+                BtreePage::new(PageType::TableLeaf, pager.page_size, pager.reserved_space);
+            page.header_mut().offset = 100;
+            pager.insert(1, page)?;
+            pager.num_pages = 1;
+        }
+        return Ok(pager);
+    }
+
+    pub fn config(&self) -> &PagerConfig {
+        return &self.config;
+    }
+
+    pub fn limits(&self) -> &Limits {
+        return &self.config.limits;
+    }
+
+    /// Changes the limits the database is checked against. As with
+    /// `PRAGMA max_page_count`, the page count limit is never set below
+    /// the number of pages the database already has.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.config.limits = limits;
+        self.config.limits.max_page_count = std::cmp::max(limits.max_page_count, self.num_pages);
+    }
+
+    /// Locks the cache of pages read from the file. Only whole pages are
+    /// ever put in it, so a thread that panicked while holding the lock
+    /// cannot have left it half changed, and the lock is taken anyway.
+    fn cache(&self) -> MutexGuard<'_, LruCache<usize, Page>> {
+        return self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+    }
+
+    /// The cache, for a caller holding the pager for writing, who needs
+    /// no lock.
+    fn cache_mut(&mut self) -> &mut LruCache<usize, Page> {
+        return self.cache.get_mut().unwrap_or_else(PoisonError::into_inner);
+    }
+
+    /// The work done reading the database so far.
+    pub fn metrics(&self) -> Metrics {
+        return *self.metrics.lock().unwrap_or_else(PoisonError::into_inner);
+    }
+
+    /// Adds to the metrics. They are counted by readers holding the pager
+    /// for reading only, perhaps several at once, so they have a lock of
+    /// their own.
+    fn update_metrics<F: FnOnce(&mut Metrics)>(&self, f: F) {
+        f(&mut self.metrics.lock().unwrap_or_else(PoisonError::into_inner));
+    }
+
+    pub fn count_rows_examined(&self, count: u64) {
+        self.update_metrics(|metrics| metrics.rows_examined += count);
+    }
+
+    /// Counts a page asked for as found in memory, or as read from the
+    /// file.
+    fn count_cache_access(&self, hit: bool) {
+        self.update_metrics(|metrics| {
+            if hit {
+                metrics.cache_hits += 1;
+            } else {
+                metrics.cache_misses += 1;
+            }
+        });
+    }
+
+    pub fn read_from_file(&self, page_num: usize) -> Result<Vec<u8>> {
+        return self.read_pages_from_file(page_num, 1);
+    }
+
+    /// Reads `count` consecutive pages, starting at `page_num`, in a
+    /// single request.
+    fn read_pages_from_file(&self, page_num: usize, count: usize) -> Result<Vec<u8>> {
+        if page_num == 0 || page_num + count - 1 > self.num_pages {
+            return Err(eyre!("Tried to access non-existent page."));
+        }
+        let mut pages = vec![0; self.page_size * count];
+        self.file_descriptor
+            .read_at(((page_num - 1) * self.page_size) as u64, &mut pages)?;
+        self.update_metrics(|metrics| metrics.pages_read += count as u64);
+        return Ok(pages);
+    }
+
+    /// Reads a page from the file, along with the configured number of
+    /// pages that follow it. The following pages are added to the cache
+    /// if they are B-tree pages that are not already loaded.
+    fn load_page_with_read_ahead(&self, page_num: usize) -> Result<BtreePage> {
+        let count = 1 + std::cmp::min(
+            self.config.read_ahead,
+            self.file_pages.saturating_sub(page_num),
+        );
+        if count == 1 {
+            return self.load_page(page_num);
+        }
+        let pages = self.read_pages_from_file(page_num, count)?;
+        for (i, bytes) in pages.chunks(self.page_size).enumerate().skip(1) {
+            let next = page_num + i;
+            if self.cache().contains(&next) || self.is_dirty(next) || self.is_ptrmap_page(next) {
+                continue;
+            }
+            // freelist and overflow pages are not B-tree pages, so they
+            // are simply left to be read on demand
+            if let Ok(page) = self.parse_page(bytes, next) {
+                self.cache().put(next, Page::Btree(Arc::new(page)));
+            }
+        }
+        return self.parse_page(&pages[..self.page_size], page_num);
+    }
+
+    /// Loads the given pages into the cache, reading each run of
+    /// consecutive pages that aren't already in memory in a single
+    /// request. No more pages are loaded than the cache can hold, and
+    /// pages that aren't B-tree pages are left to be read on demand.
+    /// Returns the number of pages loaded.
+    pub fn preload(&self, page_nums: &[usize]) -> Result<usize> {
+        let mut wanted: Vec<usize> = page_nums
+            .iter()
+            .copied()
+            .filter(|&page_num| {
+                return page_num >= 1
+                    && page_num <= self.file_pages
+                    && !self.cache().contains(&page_num)
+                    && !self.is_dirty(page_num)
+                    && !self.is_ptrmap_page(page_num);
+            })
+            .collect();
+        wanted.sort_unstable();
+        wanted.dedup();
+        let room = {
+            let cache = self.cache();
+            cache.cap().saturating_sub(cache.len())
+        };
+        wanted.truncate(room);
+
+        let mut loaded = 0;
+        let mut start = 0;
+        while start < wanted.len() {
+            let mut end = start + 1;
+            while end < wanted.len() && wanted[end] == wanted[end - 1] + 1 {
+                end += 1;
+            }
+            let pages = self.read_pages_from_file(wanted[start], end - start)?;
+            for (bytes, &page_num) in pages.chunks(self.page_size).zip(&wanted[start..end]) {
+                if let Ok(page) = self.parse_page(bytes, page_num) {
+                    self.cache().put(page_num, Page::Btree(Arc::new(page)));
+                    loaded += 1;
+                }
+            }
+            start = end;
+        }
+        return Ok(loaded);
+    }
+
+    /// Loads the pages a scan is about to visit, given in the order it
+    /// will visit them, if the first of them is not in memory yet. Up to
+    /// `PagerConfig::scan_read_ahead` of them are loaded, as `preload`
+    /// does, so a scan over pages that are not cached reads them in
+    /// batches; once they are used up, the next page it comes to is the
+    /// start of another batch.
+    pub fn prefetch(&self, page_nums: &[usize]) -> Result<()> {
+        let count = std::cmp::min(self.config.scan_read_ahead, page_nums.len());
+        let first = match page_nums.first() {
+            Some(&first) if count > 1 => first,
+            _ => return Ok(()),
+        };
+        if self.cache().contains(&first) || self.is_dirty(first) || self.spill.contains(first) {
+            return Ok(());
+        }
+        self.preload(&page_nums[..count])?;
+        return Ok(());
+    }
+
+    fn load_page(&self, page_num: usize) -> Result<BtreePage> {
+        let page = self.read_from_file(page_num)?;
+        return self.parse_page(&page, page_num);
+    }
+
+    fn parse_page(&self, bytes: &[u8], page_num: usize) -> Result<BtreePage> {
+        if self.config.verify_pages {
+            integrity::validate_page(
+                bytes,
+                page_num,
+                self.page_size,
+                self.reserved_space,
+                self.num_pages,
+            )?;
+        }
+        return BtreePage::deserialize(bytes, page_num, self.page_size, self.reserved_space);
+    }
+
+    /// Gets a page for reading. The page is shared with the cache rather
+    /// than copied, so holding on to it costs nothing unless the page is
+    /// changed in the meantime, when the holder keeps the old version.
+    /// Only the cache of pages read from the file is changed, and it has
+    /// a lock of its own, so several threads can get pages at once while
+    /// they hold the pager for reading.
+    pub fn get_page(&self, page_num: usize) -> Result<Arc<BtreePage>> {
+        // the first byte of a pointer map page can pass for a page type
+        if self.is_ptrmap_page(page_num) {
+            return Err(eyre!("Page {} is a pointer map page.", page_num));
+        }
+        // pages modified in the current transaction always take
+        // precedence over what is in the file. A spilled page is read
+        // back without being moved back into memory.
+        let (page_size, reserved_space) = (self.page_size, self.reserved_space);
+        if let Some(bytes) = self.spill.read(page_num, page_size)? {
+            self.count_cache_access(true);
+            let page = BtreePage::deserialize(&bytes, page_num, page_size, reserved_space)?;
+            return Ok(Arc::new(page));
+        }
+        match self.dirty.peek(&page_num) {
+            Some(Page::Btree(page)) => {
+                self.count_cache_access(true);
+                return Ok(page.clone());
+            }
+            Some(Page::Raw(bytes)) => {
+                self.count_cache_access(true);
+                let page = BtreePage::deserialize(bytes, page_num, page_size, reserved_space)?;
+                return Ok(Arc::new(page));
+            }
+            None => (),
+        }
+        if page_num > self.num_pages {
+            return Err(eyre!("Trying to access page that does not exist."));
+        }
+        let cached = self.cache().get(&page_num).cloned();
+        self.count_cache_access(cached.is_some());
+        let page = match cached {
+            Some(Page::Btree(page)) => return Ok(page),
+            // read from the file as some other kind of page, so it has
+            // not been checked as a B-tree page yet
+            Some(Page::Raw(bytes)) => self.parse_page(&bytes, page_num)?,
+            // cache miss; allocate memory and load from file
+            None => self.load_page_with_read_ahead(page_num)?,
+        };
+        let page = Arc::new(page);
+        self.cache().put(page_num, Page::Btree(page.clone()));
+        return Ok(page);
+    }
+
+    /// Gets a page for modification. The page is moved out of the LRU
+    /// cache into the set of dirty pages, which are never evicted, so
+    /// that later reads in the same transaction always see the change.
+    /// If the page is still held by a reader, it is copied first.
+    pub fn get_page_mut(&mut self, page_num: usize) -> Result<&mut BtreePage> {
+        if self.is_ptrmap_page(page_num) {
+            return Err(eyre!("Page {} is a pointer map page.", page_num));
+        }
+        if self.spill.contains(page_num) {
+            self.unspill(page_num)?;
+        }
+        if !self.dirty.contains(&page_num) {
+            if page_num > self.num_pages {
+                return Err(eyre!("Trying to access page that does not exist."));
+            }
+            let cached = self.cache_mut().contains(&page_num);
+            self.count_cache_access(cached);
+            let page = match self.cache_mut().pop(&page_num) {
+                Some(Page::Raw(bytes)) => Page::Btree(Arc::new(self.parse_page(&bytes, page_num)?)),
+                Some(page) => page,
+                None => Page::Btree(Arc::new(self.load_page(page_num)?)),
+            };
+            self.put_dirty(page_num, page)?;
+        } else {
+            self.count_cache_access(true);
+        }
+        if self.journal.needs_image(page_num) {
+            let original = self.dirty.peek(&page_num).cloned();
+            self.journal.record(page_num, original);
+        }
+        self.generation += 1;
+        let (page_size, reserved_space) = (self.page_size, self.reserved_space);
+        let page = self.dirty.get_mut(&page_num).unwrap();
+        return Ok(Arc::make_mut(as_btree(
+            page,
+            page_num,
+            page_size,
+            reserved_space,
+        )?));
+    }
+
+    /// Reads just the header of a B-tree page, for tools that only need
+    /// to know what kind of page it is and how many cells it has: only
+    /// its first 12 bytes are read from the file, and the page is not
+    /// added to the cache. The cell pointers are left out, to be read
+    /// with `read_cell_pointers` if they are needed. A page that is
+    /// already in memory is not read at all.
+    pub fn read_page_header(&self, page_num: usize) -> Result<PageHeader> {
+        if self.is_ptrmap_page(page_num) {
+            return Err(eyre!("Page {} is a pointer map page.", page_num));
+        }
+        if page_num == 0 || page_num > self.num_pages {
+            return Err(eyre!("Trying to access page that does not exist."));
+        }
+        let offset = if page_num == 1 { 100 } else { 0 };
+        let mut header = match self.page_in_memory(page_num)? {
+            Some(Page::Btree(pg)) => pg.header().clone(),
+            Some(Page::Raw(bytes)) => PageHeader::deserialize_header(&bytes[offset..], offset)?,
+            None => {
+                let mut bytes = [0; 12];
+                let start = (page_num - 1) * self.page_size + offset;
+                self.file_descriptor.read_at(start as u64, &mut bytes)?;
+                PageHeader::deserialize_header(&bytes, offset)?
+            }
+        };
+        header.cell_pointers.clear();
+        return Ok(header);
+    }
+
+    /// Reads the cell pointer array of a page whose header was read with
+    /// `read_page_header` into the header, again without reading the
+    /// rest of the page.
+    pub fn read_cell_pointers(&self, page_num: usize, header: &mut PageHeader) -> Result<()> {
+        let start = header.offset + header.size();
+        let len = 2 * header.num_cells as usize;
+        if start + len > self.usable_size() {
+            return Err(CorruptPage {
+                page_num: page_num,
+                reason: format!("{} cell pointers do not fit in the page", header.num_cells),
+            }
+            .into());
+        }
+        let bytes = match self.page_in_memory(page_num)? {
+            Some(Page::Btree(pg)) => {
+                header.cell_pointers = pg.header().cell_pointers.clone();
+                return Ok(());
+            }
+            Some(Page::Raw(bytes)) => bytes[start..start + len].to_vec(),
+            None => {
+                let mut bytes = vec![0; len];
+                let file_start = (page_num - 1) * self.page_size + start;
+                self.file_descriptor
+                    .read_at(file_start as u64, &mut bytes)?;
+                bytes
+            }
+        };
+        return header.deserialize_cell_pointers(&bytes);
+    }
+
+    /// The page as it is held in memory, modified or not, if it is,
+    /// without counting it as a use of the cache. A spilled page is read
+    /// back as it was spilled.
+    fn page_in_memory(&self, page_num: usize) -> Result<Option<Page>> {
+        if let Some(bytes) = self.spill.read(page_num, self.page_size)? {
+            return Ok(Some(Page::Raw(bytes)));
+        }
+        if let Some(page) = self.dirty.peek(&page_num) {
+            return Ok(Some(page.clone()));
+        }
+        return Ok(self.cache().peek(&page_num).cloned());
+    }
+
+    /// Reads a page that is not part of a B-tree, such as an overflow
+    /// page, as the raw bytes of the page.
+    pub fn get_raw_page(&self, page_num: usize) -> Result<Vec<u8>> {
+        if let Some(bytes) = self.spill.read(page_num, self.page_size)? {
+            self.count_cache_access(true);
+            return Ok(bytes);
+        }
+        if let Some(page) = self.dirty.peek(&page_num) {
+            let bytes = page.serialize();
+            self.count_cache_access(true);
+            return Ok(bytes);
+        }
+        if page_num > self.num_pages {
+            return Err(eyre!("Trying to access page that does not exist."));
+        }
+        if let Some(Page::Raw(bytes)) = self.cache().get(&page_num) {
+            let bytes = bytes.clone();
+            self.count_cache_access(true);
+            return Ok(bytes);
+        }
+        self.count_cache_access(false);
+        // a B-tree page in the cache may have been read ahead from what
+        // is really a raw page, so the page is read again
+        let bytes = self.read_from_file(page_num)?;
+        self.cache().put(page_num, Page::Raw(bytes.clone()));
+        return Ok(bytes);
+    }
+
+    /// Replaces the contents of a page (or adds a new one), marking it
+    /// as dirty. In an auto-vacuum database, the page's children and the
+    /// first overflow page of each of its cells are recorded as its own
+    /// in the pointer map.
+    pub fn insert(&mut self, page_num: usize, page: BtreePage) -> Result<()> {
+        if self.auto_vacuum {
+            let mut entries = Vec::new();
+            for child in page.children() {
+                entries.push((child as usize, PtrmapType::Btree));
+            }
+            for payload in page.overflow_payloads() {
+                entries.push((payload.first_page as usize, PtrmapType::Overflow1));
+            }
+            let parent = page_num as u32;
+            let entries = entries.into_iter().map(|(child, kind)| {
+                let entry = PtrmapEntry {
+                    kind: kind,
+                    parent: parent,
+                };
+                return (child, entry);
+            });
+            self.ptrmap_put_all(entries.collect())?;
+        }
+        return self.insert_page(page_num, Page::Btree(Arc::new(page)));
+    }
+
+    /// Replaces the contents of a page that is not part of a B-tree (or
+    /// adds a new one) with the given bytes, marking it as dirty.
+    pub fn insert_raw_page(&mut self, page_num: usize, bytes: Vec<u8>) -> Result<()> {
+        if bytes.len() != self.page_size {
+            return Err(eyre!(
+                "Page {} is not {} bytes long",
+                page_num,
+                self.page_size
+            ));
+        }
+        return self.insert_page(page_num, Page::Raw(bytes));
+    }
+
+    fn insert_page(&mut self, page_num: usize, page: Page) -> Result<()> {
+        if self.journal.needs_image(page_num) {
+            if self.spill.contains(page_num) {
+                self.unspill(page_num)?;
+            }
+            let original = if let Some(pg) = self.dirty.peek(&page_num) {
+                Some(pg.clone())
+            } else if let Some(pg) = self.cache_mut().peek(&page_num) {
+                Some(pg.clone())
+            } else if page_num <= self.num_pages {
+                Some(Page::Raw(self.read_from_file(page_num)?))
+            } else {
+                None
+            };
+            self.journal.record(page_num, original);
+        }
+        self.cache_mut().pop(&page_num);
+        self.put_dirty(page_num, page)?;
+        self.generation += 1;
+        return Ok(());
+    }
+
+    /// Adds a new, empty page of the given type, reusing a page from the
+    /// freelist if there is one and otherwise adding it to the end of the
+    /// file, and returns its page number.
+    pub fn allocate_page(&mut self, page_type: PageType) -> Result<usize> {
+        let page_num = self.next_free_page()?;
+        let page = BtreePage::new(page_type, self.page_size, self.reserved_space);
+        self.insert(page_num, page)?;
+        self.num_pages = std::cmp::max(self.num_pages, page_num);
+        return Ok(page_num);
+    }
+
+    /// Adds a page holding the given bytes, for a page that is not part
+    /// of a B-tree, and returns its page number. As for `allocate_page`,
+    /// a page on the freelist is used first.
+    pub fn allocate_raw_page(&mut self, bytes: Vec<u8>) -> Result<usize> {
+        let page_num = self.next_free_page()?;
+        self.insert_raw_page(page_num, bytes)?;
+        self.num_pages = std::cmp::max(self.num_pages, page_num);
+        return Ok(page_num);
+    }
+
+    /// Takes the next page off the freelist, or if it is empty, picks
+    /// the page after the end of the file. The page's contents are up to
+    /// the caller, and a page past the end is only counted in
+    /// `num_pages` once the caller has added it.
+    fn next_free_page(&mut self) -> Result<usize> {
+        if self.first_freelist == 0 {
+            let mut page_num = self.num_pages + 1;
+            while (self.is_ptrmap_page(page_num) || page_num == self.lock_byte_page())
+                && page_num <= self.config.limits.max_page_count
+            {
+                // the file grows past the next pointer map page, which
+                // starts out with no entries, or the lock-byte page,
+                // which is never used
+                self.insert_raw_page(page_num, vec![0; self.page_size])?;
+                self.num_pages = page_num;
+                page_num += 1;
+            }
+            if page_num > self.config.limits.max_page_count {
+                return Err(eyre!("database or disk is full"));
+            }
+            return Ok(page_num);
+        }
+        // leaves are taken from the first trunk page, and once it has
+        // none left, the trunk page itself
+        let trunk_num = self.first_freelist;
+        let mut trunk = self.freelist_trunk(trunk_num)?;
+        self.num_freelist = self.num_freelist.saturating_sub(1);
+        if let Some(leaf_num) = trunk.free_pages.pop() {
+            if leaf_num < 2 || leaf_num > self.num_pages {
+                return Err(eyre!("freelist leaf page {} is out of range", leaf_num));
+            }
+            self.insert_raw_page(trunk_num, trunk.serialize(self.page_size))?;
+            return Ok(leaf_num);
+        }
+        self.first_freelist = trunk.next_page_link.unwrap_or(0);
+        return Ok(trunk_num);
+    }
+
+    /// Puts a page that is no longer used on the freelist, for
+    /// `allocate_page` to hand out again. A page added as a leaf keeps
+    /// its contents, as SQLite never reads them; once the first trunk
+    /// page is full, the page becomes the new first trunk page.
+    pub fn free_page(&mut self, page_num: usize) -> Result<()> {
+        if page_num < 2 || page_num > self.num_pages {
+            return Err(eyre!("Cannot free page {}", page_num));
+        }
+        if self.auto_vacuum {
+            let entry = PtrmapEntry {
+                kind: PtrmapType::FreePage,
+                parent: 0,
+            };
+            self.ptrmap_put(page_num, entry)?;
+        }
+        let capacity = FreelistTrunk::capacity(self.usable_size());
+        if self.first_freelist != 0 {
+            let trunk_num = self.first_freelist;
+            let mut trunk = self.freelist_trunk(trunk_num)?;
+            if trunk.free_pages.len() < capacity {
+                trunk.free_pages.push(page_num);
+                self.insert_raw_page(trunk_num, trunk.serialize(self.page_size))?;
+                self.num_freelist += 1;
+                return Ok(());
+            }
+        }
+        let trunk = FreelistTrunk {
+            free_pages: Vec::new(),
+            next_page_link: match self.first_freelist {
+                0 => None,
+                next => Some(next),
+            },
+        };
+        self.insert_raw_page(page_num, trunk.serialize(self.page_size))?;
+        self.first_freelist = page_num;
+        self.num_freelist += 1;
+        return Ok(());
+    }
+
+    /// Every page on the freelist, trunks and leaves, in the order they
+    /// are chained. Errors if the chain does not hold as many pages as
+    /// `num_freelist` says, or runs off the end of the file.
+    pub fn freelist_pages(&mut self) -> Result<Vec<usize>> {
+        let mut pages = Vec::new();
+        let mut trunk_num = self.first_freelist;
+        while trunk_num != 0 {
+            if trunk_num > self.num_pages || pages.len() >= self.num_freelist {
+                return Err(eyre!("freelist trunk page {} is out of range", trunk_num));
+            }
+            let trunk = self.freelist_trunk(trunk_num)?;
+            pages.push(trunk_num);
+            pages.extend(&trunk.free_pages);
+            trunk_num = trunk.next_page_link.unwrap_or(0);
+        }
+        if pages.len() != self.num_freelist {
+            return Err(eyre!(
+                "freelist holds {} pages, but the header says {}",
+                pages.len(),
+                self.num_freelist
+            ));
+        }
+        return Ok(pages);
+    }
+
+    /// Takes a particular page off the freelist, for the caller to use,
+    /// returning whether it was there. The freelist is built again from
+    /// the pages left on it.
+    pub fn take_free_page(&mut self, page_num: usize) -> Result<bool> {
+        let pages = self.freelist_pages()?;
+        if !pages.contains(&page_num) {
+            return Ok(false);
+        }
+        self.first_freelist = 0;
+        self.num_freelist = 0;
+        for free in pages.into_iter().rev() {
+            if free != page_num {
+                self.free_page(free)?;
+            }
+        }
+        return Ok(true);
+    }
+
+    fn freelist_trunk(&mut self, page_num: usize) -> Result<FreelistTrunk> {
+        let bytes = self.get_raw_page(page_num)?;
+        return FreelistTrunk::deserialize(&bytes[..self.usable_size()]);
+    }
+
+    pub fn is_auto_vacuum(&self) -> bool {
+        return self.auto_vacuum;
+    }
+
+    /// Turns auto-vacuum on or off, which can only be done before
+    /// anything is written to the database, as the pointer map pages are
+    /// laid out from the start of the file. Returns whether the database
+    /// now has the mode asked for.
+    pub fn set_auto_vacuum(&mut self, auto_vacuum: bool) -> Result<bool> {
+        if auto_vacuum == self.auto_vacuum {
+            return Ok(true);
+        }
+        if self.file_descriptor.len()? > 0 || self.num_pages > 1 || self.journal.is_active() {
+            return Ok(false);
+        }
+        self.auto_vacuum = auto_vacuum;
+        // page 1 is the only root so far
+        self.largest_root = auto_vacuum as usize;
+        return Ok(true);
+    }
+
+    /// Whether the page is a pointer map page of an auto-vacuum database.
+    pub fn is_ptrmap_page(&self, page_num: usize) -> bool {
+        return self.auto_vacuum && is_ptrmap_page(self.usable_size(), page_num);
+    }
+
+    fn ptrmap_page_for(&self, page_num: usize) -> usize {
+        return ptrmap_page_for(self.usable_size(), page_num);
+    }
+
+    /// The position of a page's entry in its pointer map page, erroring
+    /// if the database has no pointer map or the page has no entry.
+    fn ptrmap_position(&self, page_num: usize) -> Result<(usize, usize)> {
+        if !self.auto_vacuum {
+            return Err(eyre!("The database has no pointer map."));
+        }
+        if page_num < 3 || page_num > self.num_pages || self.is_ptrmap_page(page_num) {
+            return Err(eyre!("Page {} has no pointer map entry.", page_num));
+        }
+        let ptrmap_num = self.ptrmap_page_for(page_num);
+        return Ok((ptrmap_num, page_num - ptrmap_num - 1));
+    }
+
+    /// Looks up what a page is, and the page that refers to it, in the
+    /// pointer map. Returns `None` if the page's entry was never written.
+    pub fn ptrmap_get(&mut self, page_num: usize) -> Result<Option<PtrmapEntry>> {
+        let (ptrmap_num, index) = self.ptrmap_position(page_num)?;
+        let bytes = self.get_raw_page(ptrmap_num)?;
+        let ptrmap = PtrmapPage::deserialize(&bytes[..self.usable_size()])?;
+        return Ok(ptrmap.entries[index]);
+    }
+
+    /// Records what a page is, and the page that refers to it, in the
+    /// pointer map.
+    pub fn ptrmap_put(&mut self, page_num: usize, entry: PtrmapEntry) -> Result<()> {
+        return self.ptrmap_put_all(vec![(page_num, entry)]);
+    }
+
+    /// Records several pages in the pointer map at once, reading and
+    /// writing each pointer map page only once.
+    pub fn ptrmap_put_all(&mut self, entries: Vec<(usize, PtrmapEntry)>) -> Result<()> {
+        let mut by_ptrmap: BTreeMap<usize, Vec<(usize, PtrmapEntry)>> = BTreeMap::new();
+        for (page_num, entry) in entries {
+            let (ptrmap_num, index) = self.ptrmap_position(page_num)?;
+            by_ptrmap
+                .entry(ptrmap_num)
+                .or_default()
+                .push((index, entry));
+        }
+        for (ptrmap_num, entries) in by_ptrmap {
+            let bytes = self.get_raw_page(ptrmap_num)?;
+            let mut ptrmap = PtrmapPage::deserialize(&bytes[..self.usable_size()])?;
+            let mut changed = false;
+            for (index, entry) in entries {
+                changed |= ptrmap.entries[index] != Some(entry);
+                ptrmap.entries[index] = Some(entry);
+            }
+            if changed {
+                self.insert_raw_page(ptrmap_num, ptrmap.serialize(self.page_size))?;
+            }
+        }
+        return Ok(());
+    }
+
+    /// The page holding the bytes other processes lock (see `lock`),
+    /// which is left unused.
+    pub fn lock_byte_page(&self) -> usize {
+        return PENDING_BYTE as usize / self.page_size + 1;
+    }
+
+    fn usable_size(&self) -> usize {
+        return self.page_size - self.reserved_space as usize;
+    }
+
+    /// Writes a single dirty page to the file, moving it back to the
+    /// regular cache. Page 1 is written with the file header, which is
+    /// kept by the connection rather than the pager. Does nothing if the
+    /// page is not dirty.
+    pub fn flush_page(&mut self, page_num: usize, header: &DbOptions) -> Result<()> {
+        if self.journal.is_active() {
+            return Err(eyre!("Cannot write pages while a statement is active."));
+        }
+        if page_num > self.num_pages {
+            // the page was truncated, and is never written
+            self.dirty.pop(&page_num);
+            self.spill.remove(page_num);
+            return Ok(());
+        }
+        let offset = ((page_num - 1) * self.page_size) as u64;
+        if let Some(bytes) = self.spill.take(page_num, self.page_size)? {
+            self.file_descriptor.write_at(offset, &bytes)?;
+        } else if let Some(page) = self.dirty.pop(&page_num) {
+            self.file_descriptor
+                .write_at(offset, &page.serialize_page(page_num, header)?)?;
+            self.cache_mut().put(page_num, page);
+        } else {
+            return Ok(());
+        }
+        self.file_pages = std::cmp::max(self.file_pages, page_num);
+        return Ok(());
+    }
+
+    /// Writes every dirty page to the file, along with the file header.
+    /// The header is first brought up to date: the page count and the
+    /// freelist are the pager's, and the change counter is incremented,
+    /// with `version_valid_for` following it so that SQLite trusts the
+    /// page count. Page 1, and with it the header, is written after every
+    /// other page, so that the header never describes pages that are not
+    /// there yet; the file is grown or shrunk to the page count before
+    /// it. Written pages are moved back to the regular cache.
+    pub fn flush_all(&mut self, header: &mut DbOptions) -> Result<()> {
+        if self.journal.is_active() {
+            return Err(eyre!("Cannot write pages while a statement is active."));
+        }
+        let mut updated = *header;
+        updated.num_pages = self.num_pages as u32;
+        updated.first_freelist = self.first_freelist as u32;
+        updated.num_freelist = self.num_freelist as u32;
+        updated.largest_root_page = self.largest_root as u32;
+        updated.change_counter = updated.change_counter.wrapping_add(1);
+        updated.version_valid_for = updated.change_counter;
+
+        // spilled pages are written straight from the spill file, as
+        // reading them back in could push others out (page 1, the only
+        // one with the file header, is never spilled). Each page is only
+        // forgotten once it has been written, so that if a write fails,
+        // flushing again writes whatever is left.
+        let spilled: Vec<usize> = self.spill.slots.keys().copied().collect();
+        for page_num in spilled {
+            if page_num <= self.num_pages {
+                let bytes = self.spill.read(page_num, self.page_size)?.unwrap();
+                self.file_descriptor
+                    .write_at(((page_num - 1) * self.page_size) as u64, &bytes)?;
+            }
+            self.spill.remove(page_num);
+        }
+        let dirty: Vec<usize> = self.dirty.iter().map(|(page_num, _)| *page_num).collect();
+        for page_num in dirty {
+            if page_num == 1 {
+                continue;
+            }
+            if page_num <= self.num_pages {
+                let page = self.dirty.peek(&page_num).unwrap();
+                let offset = (page_num - 1) * self.page_size;
+                self.file_descriptor
+                    .write_at(offset as u64, &page.serialize_page(page_num, &updated)?)?;
+            }
+            let page = self.dirty.pop(&page_num).unwrap();
+            if page_num <= self.num_pages {
+                self.cache_mut().put(page_num, page);
+            }
+        }
+
+        // the file is grown before the header says it has more pages, and
+        // shrunk only once the header says it has fewer, so that it is
+        // never shorter than the header says. Pages that are never
+        // written, such as freelist leaves, still have to be in the file.
+        let len = (self.num_pages * self.page_size) as u64;
+        let file_len = self.file_descriptor.len()?;
+        if file_len < len {
+            self.file_descriptor.set_len(len)?;
+        }
+        match self.dirty.peek(&1) {
+            Some(page) => {
+                self.file_descriptor
+                    .write_at(0, &page.serialize_page(1, &updated)?)?;
+            }
+            None => self.file_descriptor.write_at(0, &updated.serialize())?,
+        }
+        if let Some(page) = self.dirty.pop(&1) {
+            self.cache_mut().put(1, page);
+        }
+        if file_len > len {
+            self.file_descriptor.set_len(len)?;
+        }
+        if self.file_pages > self.num_pages {
+            let num_pages = self.num_pages;
+            let truncated: Vec<usize> = self
+                .cache_mut()
+                .iter()
+                .map(|(page_num, _)| *page_num)
+                .filter(|page_num| *page_num > num_pages)
+                .collect();
+            for page_num in truncated {
+                self.cache_mut().pop(&page_num);
+            }
+        }
+        self.file_pages = self.num_pages;
+
+        self.change_counter = updated.change_counter;
+        *header = updated;
+        return Ok(());
+    }
+
+    /// Removes the pages after `num_pages` from the end of the database.
+    /// Any changes to them are dropped, and the file is shrunk when it is
+    /// next flushed. It is up to the caller that nothing refers to them.
+    pub fn truncate(&mut self, num_pages: usize) -> Result<()> {
+        if num_pages < 1 || num_pages > self.num_pages {
+            return Err(eyre!("Cannot truncate the database to {} pages", num_pages));
+        }
+        self.num_pages = num_pages;
+        self.generation += 1;
+        return Ok(());
+    }
+
+    /// Replaces the whole database file with an image of another, such
+    /// as one `VACUUM` has built, dropping every page held in memory.
+    /// The image must have the same page size, and its header gives the
+    /// freelist.
+    pub fn replace_file(&mut self, image: &[u8]) -> Result<()> {
+        if self.journal.is_active() {
+            return Err(eyre!("Cannot write pages while a statement is active."));
+        }
+        let header = DbOptions::deserialize(image)?;
+        if header.page_size != self.page_size || !image.len().is_multiple_of(self.page_size) {
+            return Err(eyre!("The image does not have the database's page size."));
+        }
+        self.file_descriptor.write_at(0, image)?;
+        self.file_descriptor.set_len(image.len() as u64)?;
+        self.cache_mut().clear();
+        self.dirty.clear();
+        self.spill = SpillFile::new();
+        self.num_pages = image.len() / self.page_size;
+        self.file_pages = self.num_pages;
+        self.first_freelist = header.first_freelist as usize;
+        self.num_freelist = header.num_freelist as usize;
+        self.largest_root = header.largest_root_page as usize;
+        self.auto_vacuum = header.largest_root_page != 0;
+        self.change_counter = header.change_counter;
+        self.generation += 1;
+        return Ok(());
+    }
+
+    pub fn lock_level(&self) -> LockLevel {
+        return self.file_descriptor.lock_level();
+    }
+
+    /// Raises the lock held on the database file, which other processes
+    /// using it see (see `lock`).
+    pub fn lock(&mut self, level: LockLevel) -> Result<()> {
+        return self.file_descriptor.lock(level);
+    }
+
+    pub fn unlock(&mut self, level: LockLevel) -> Result<()> {
+        return self.file_descriptor.unlock(level);
+    }
+
+    /// Whether another connection is writing to the database file.
+    pub fn is_reserved_elsewhere(&self) -> Result<bool> {
+        return self.file_descriptor.is_reserved_elsewhere();
+    }
+
+    /// Reads the file header as it is in the file, which may have been
+    /// changed by another process. `None` if nothing has been written to
+    /// the file yet.
+    pub fn read_file_header(&self) -> Result<Option<DbOptions>> {
+        if self.file_descriptor.len()? < 100 {
+            return Ok(None);
+        }
+        let mut buf = vec![0; 100];
+        self.file_descriptor.read_at(0, &mut buf)?;
+        return DbOptions::deserialize(&buf).map(Some);
+    }
+
+    /// Drops every page read from the file, after another process has
+    /// changed it, and takes the size of the file and its freelist from
+    /// the new header. Pages changed here but not yet written are kept.
+    pub fn reload(&mut self, header: &DbOptions) -> Result<()> {
+        if header.page_size != self.page_size {
+            return Err(eyre!("The page size of the database has been changed."));
+        }
+        self.file_descriptor.check_map()?;
+        self.cache_mut().clear();
+        let file_pages = self.file_descriptor.len()? as usize / self.page_size;
+        self.num_pages = if self.num_dirty() == 0 {
+            file_pages
+        } else {
+            std::cmp::max(self.num_pages, file_pages)
+        };
+        self.file_pages = file_pages;
+        self.first_freelist = header.first_freelist as usize;
+        self.num_freelist = header.num_freelist as usize;
+        self.largest_root = header.largest_root_page as usize;
+        self.auto_vacuum = header.largest_root_page != 0;
+        self.change_counter = header.change_counter;
+        self.generation += 1;
+        return Ok(());
+    }
+
+    /// Whether the file has been changed by another process since this
+    /// pager last read or wrote its header, as the header read from the
+    /// file tells.
+    pub fn changed_since(&self, header: &DbOptions) -> bool {
+        return header.change_counter != self.change_counter;
+    }
+
+    /// Copies the whole database file. This is empty for a new database
+    /// that nothing has been written to. Changes that have not been
+    /// written to the file yet are not included, so this is an error while
+    /// a statement is active.
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        if self.journal.is_active() {
+            return Err(eyre!("Cannot serialize while a statement is active."));
+        }
+        let mut image = vec![0; self.file_descriptor.len()? as usize];
+        self.file_descriptor.read_at(0, &mut image)?;
+        return Ok(image);
+    }
+
+    /// A counter that changes whenever any page is modified, so that
+    /// users of a shared pager can tell when their cached view of the
+    /// database (such as the schema) may be out of date.
+    pub fn generation(&self) -> u64 {
+        return self.generation;
+    }
+
+    /// A counter that changes whenever a statement changes the schema of
+    /// the database, which every user of a shared pager sees.
+    pub fn schema_version(&self) -> u64 {
+        return self.schema_version;
+    }
+
+    pub fn schema_changed(&mut self) {
+        self.schema_version += 1;
+    }
+
+    pub fn is_dirty(&self, page_num: usize) -> bool {
+        return self.dirty.contains(&page_num) || self.spill.contains(page_num);
+    }
+
+    pub fn num_dirty(&self) -> usize {
+        return self.dirty.len() + self.spill.len();
+    }
+
+    pub fn num_spilled(&self) -> usize {
+        return self.spill.len();
+    }
+
+    /// Sets the maximum number of dirty pages kept in memory before
+    /// the least recently used ones are spilled to a temporary file, as
+    /// SQLite's `cache_spill` does. `None` disables spilling.
+    pub fn set_spill_threshold(&mut self, threshold: Option<usize>) {
+        self.spill_threshold = threshold;
+    }
+
+    pub fn spill_threshold(&self) -> Option<usize> {
+        return self.spill_threshold;
+    }
+
+    /// The number of pages read from the file that are kept in memory,
+    /// as with `PRAGMA cache_size`.
+    pub fn cache_size(&self) -> usize {
+        return self.cache().cap();
+    }
+
+    /// The size of the cache as it was set, which may be in bytes rather
+    /// than pages.
+    pub fn cache_size_setting(&self) -> CacheSize {
+        return self.cache_size;
+    }
+
+    /// Sets how many of the pages read from the file are kept in
+    /// memory, which is at least one.
+    pub fn set_cache_size(&mut self, size: CacheSize) {
+        self.cache_size = size;
+        let pages = size.pages(self.page_size);
+        self.cache_mut().resize(pages);
+    }
+
+    /// The number of pages in the database, including any added in the
+    /// current transaction.
+    pub fn num_pages(&self) -> usize {
+        return self.num_pages;
+    }
+
+    pub fn page_size(&self) -> usize {
+        return self.page_size;
+    }
+
+    pub fn reserved_space(&self) -> u8 {
+        return self.reserved_space;
+    }
+
+    /// Changes the page size of a new database that nothing has been
+    /// written to yet, returning whether it was changed. As in SQLite,
+    /// the page size of an existing database stays as it is.
+    pub fn set_page_size(&mut self, page_size: usize) -> Result<bool> {
+        if self.file_descriptor.len()? > 0 || self.num_pages > 1 || self.journal.is_active() {
+            return Ok(false);
+        }
+        self.page_size = page_size;
+        self.cache_mut().clear();
+        let pages = self.cache_size.pages(page_size);
+        self.cache_mut().resize(pages);
+        self.dirty.clear();
+        let mut page = BtreePage::new(PageType::TableLeaf, page_size, self.reserved_space);
+        page.header_mut().offset = 100;
+        self.insert(1, page)?;
+        return Ok(true);
+    }
+
+    fn put_dirty(&mut self, page_num: usize, page: Page) -> Result<()> {
+        self.spill.remove(page_num);
+        self.dirty.put(page_num, page);
+        return self.spill_if_needed();
+    }
+
+    fn spill_if_needed(&mut self) -> Result<()> {
+        let threshold = match self.spill_threshold {
+            // always keep at least the page currently being worked on
+            Some(threshold) => std::cmp::max(threshold, 1),
+            None => return Ok(()),
+        };
+        // page 1 is never spilled, as it also holds the file header
+        let mut page_one = None;
+        while self.dirty.len() > threshold {
+            let (page_num, page) = match self.dirty.pop_lru() {
+                Some(entry) => entry,
+                None => break,
+            };
+            if page_num == 1 {
+                page_one = Some(page);
+                continue;
+            }
+            self.spill.write(page_num, &page.serialize())?;
+        }
+        if let Some(page) = page_one {
+            self.dirty.put(1, page);
+        }
+        return Ok(());
+    }
+
+    /// Brings a spilled page back into memory. It comes back as raw
+    /// bytes, and a B-tree page is parsed again when it is next used.
+    fn unspill(&mut self, page_num: usize) -> Result<()> {
+        if let Some(bytes) = self.spill.take(page_num, self.page_size)? {
+            self.put_dirty(page_num, Page::Raw(bytes))?;
+        }
+        return Ok(());
+    }
+
+    /// Opens a statement-level savepoint. Every page modified through
+    /// `get_page_mut` or `insert` from here on has its original image
+    /// kept, so that the statement can be undone on its own without
+    /// touching changes made earlier in the transaction. Statements may
+    /// be nested.
+    pub fn begin_statement(&mut self) {
+        self.journal.open();
+        self.count_saves.push((
+            self.num_pages,
+            self.first_freelist,
+            self.num_freelist,
+            self.largest_root,
+        ));
+    }
+
+    /// Keeps the changes made by the innermost statement.
+    pub fn commit_statement(&mut self) -> Result<()> {
+        if !self.journal.release() {
+            return Err(eyre!("No statement is active."));
+        }
+        self.count_saves.pop();
+        return Ok(());
+    }
+
+    /// Undoes every page change made by the innermost statement.
+    pub fn rollback_statement(&mut self) -> Result<()> {
+        let images = self
+            .journal
+            .rollback()
+            .ok_or_else(|| eyre!("No statement is active."))?;
+        if let Some((num_pages, first_freelist, num_freelist, largest_root)) =
+            self.count_saves.pop()
+        {
+            self.num_pages = num_pages;
+            self.first_freelist = first_freelist;
+            self.num_freelist = num_freelist;
+            self.largest_root = largest_root;
+        }
+        for (page_num, image) in images {
+            self.cache_mut().pop(&page_num);
+            match image {
+                Some(page) => {
+                    self.put_dirty(page_num, page)?;
+                }
+                None => {
+                    // the page was added by the statement
+                    self.dirty.pop(&page_num);
+                    self.spill.remove(page_num);
+                }
+            }
+            self.generation += 1;
+        }
+        return Ok(());
+    }
+
+    /// Runs `f` as a single statement: if it returns an error, all of its
+    /// page changes are rolled back before the error is passed on.
+    pub fn run_statement<T, F>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        self.begin_statement();
+        match f(self) {
+            Ok(value) => {
+                self.commit_statement()?;
+                return Ok(value);
+            }
+            Err(err) => {
+                self.rollback_statement()?;
+                return Err(err);
+            }
+        }
+    }
+
+    pub fn statement_depth(&self) -> usize {
+        return self.journal.depth();
+    }
+}
+
+/// The B-tree page held in `page`, parsing it first if it is held as raw
+/// bytes.
+fn as_btree(
+    page: &mut Page,
+    page_num: usize,
     page_size: usize,
     reserved_space: u8,
+) -> Result<&mut Arc<BtreePage>> {
+    if let Page::Raw(bytes) = page {
+        let parsed = BtreePage::deserialize(bytes, page_num, page_size, reserved_space)?;
+        *page = Page::Btree(Arc::new(parsed));
+    }
+    return match page {
+        Page::Btree(pg) => Ok(pg),
+        Page::Raw(_) => unreachable!(),
+    };
 }
 
-impl Pager {
-    pub fn new(filename: &str, db_options: &DbOptions) -> Result<Self> {
-        let file = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .open(filename)
-            .wrap_err("Could not open file.")?;
-        let file_length = file.metadata()?.len() as usize;
+/// A pager shared between the connections and B-trees that use it. It
+/// can be sent to other threads, so that several threads can read the
+/// same database at once: each takes the lock only for as long as it
+/// takes to get a page, and the pages it gets are its to read without
+/// it (see `Pager::get_page`). Changing the database takes the lock for
+/// writing, which waits for those reading to let go.
+#[derive(Debug, Clone)]
+pub struct SharedPager(Arc<RwLock<Pager>>);
 
-        if file_length == 0 {
-            // New database file. Initialize page 0 as leaf node.
+impl SharedPager {
+    pub fn new(pager: Pager) -> Self {
+        return Self(Arc::new(RwLock::new(pager)));
+    }
+
+    /// Locks the pager for reading, alongside any other readers. A
+    /// thread that panicked while holding the lock leaves the pager as it
+    /// was, as a panic while a `RefCell` was borrowed did, so the lock is
+    /// taken anyway.
+    pub fn read(&self) -> RwLockReadGuard<'_, Pager> {
+        return self.0.read().unwrap_or_else(PoisonError::into_inner);
+    }
+
+    /// Locks the pager for writing, to change the database or its
+    /// settings.
+    pub fn write(&self) -> RwLockWriteGuard<'_, Pager> {
+        return self.0.write().unwrap_or_else(PoisonError::into_inner);
+    }
+
+    /// Whether the two are the same pager.
+    pub fn ptr_eq(&self, other: &SharedPager) -> bool {
+        return Arc::ptr_eq(&self.0, &other.0);
+    }
+}
+
+lazy_static! {
+    static ref SHARED_PAGERS: Mutex<HashMap<PathBuf, Weak<RwLock<Pager>>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Opens a pager for a file, sharing it with any other pager for the same
+/// file that is still open in this process, as SQLite's shared-cache mode
+/// does. Connections that share a pager share its page cache and always
+/// see each other's writes, including uncommitted ones. If a shared pager
+/// already exists, it keeps the configuration it was opened with.
+pub fn open_shared(
+    filename: &str,
+    db_options: &DbOptions,
+    config: PagerConfig,
+) -> Result<SharedPager> {
+    // make sure the file exists, so that it can be canonicalized
+    OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(filename)
+        .wrap_err("Could not open file.")?;
+    let path = std::fs::canonicalize(filename)?;
+    // held while the pager is opened, so that two threads opening the
+    // same file at once end up with the same pager
+    let mut pagers = SHARED_PAGERS.lock().unwrap_or_else(PoisonError::into_inner);
+    pagers.retain(|_, pager| pager.strong_count() > 0);
+    if let Some(pager) = pagers.get(&path).and_then(|pager| pager.upgrade()) {
+        return Ok(SharedPager(pager));
+    }
+    let pager = SharedPager::new(Pager::with_config(filename, db_options, config)?);
+    pagers.insert(path, Arc::downgrade(&pager.0));
+    return Ok(pager);
+}
+
+/// Temporary storage for pages that have been pushed out of memory,
+/// whether dirty pages of the database or those of a `TempStore`. The
+/// backing file is anonymous, created on first use, and removed
+/// automatically when closed.
+#[derive(Debug, Default)]
+struct SpillFile {
+    file: Option<File>,
+    slots: HashMap<usize, u64>,
+    free_slots: Vec<u64>,
+    next_offset: u64,
+}
+
+impl SpillFile {
+    fn new() -> Self {
+        return Self::default();
+    }
+
+    fn contains(&self, page_num: usize) -> bool {
+        return self.slots.contains_key(&page_num);
+    }
+
+    fn len(&self) -> usize {
+        return self.slots.len();
+    }
+
+    fn write(&mut self, page_num: usize, bytes: &[u8]) -> Result<()> {
+        if self.file.is_none() {
+            self.file = Some(tempfile::tempfile().wrap_err("Could not create spill file.")?);
+        }
+        let offset = match self.slots.get(&page_num) {
+            Some(offset) => *offset,
+            None => match self.free_slots.pop() {
+                Some(offset) => offset,
+                None => {
+                    let offset = self.next_offset;
+                    self.next_offset += bytes.len() as u64;
+                    offset
+                }
+            },
+        };
+        self.file.as_mut().unwrap().write_all_at(offset, bytes)?;
+        self.slots.insert(page_num, offset);
+        return Ok(());
+    }
+
+    /// Reads a spilled page back and releases its slot.
+    fn take(&mut self, page_num: usize, page_size: usize) -> Result<Option<Vec<u8>>> {
+        let offset = match self.slots.remove(&page_num) {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+        let mut bytes = vec![0; page_size];
+        self.file
+            .as_ref()
+            .unwrap()
+            .read_exact_at(offset, &mut bytes)?;
+        self.free_slots.push(offset);
+        return Ok(Some(bytes));
+    }
+
+    /// Reads a spilled page back, leaving it in place.
+    fn read(&self, page_num: usize, page_size: usize) -> Result<Option<Vec<u8>>> {
+        let offset = match self.slots.get(&page_num) {
+            Some(offset) => *offset,
+            None => return Ok(None),
+        };
+        let mut bytes = vec![0; page_size];
+        self.file
+            .as_ref()
+            .unwrap()
+            .read_exact_at(offset, &mut bytes)?;
+        return Ok(Some(bytes));
+    }
+
+    fn remove(&mut self, page_num: usize) {
+        if let Some(offset) = self.slots.remove(&page_num) {
+            self.free_slots.push(offset);
+        }
+    }
+}
+
+/// Pages of temporary data that need not outlive a statement, such as
+/// the runs of a sort or the rows of a materialized subquery. Pages are
+/// allocated, read and written like those of the pager, but are numbered
+/// from 1 within the store alone. Up to `memory_pages` of them are held
+/// in memory; past that, the oldest are written to an anonymous temporary
+/// file, which is removed along with everything else when the store is
+/// dropped.
+#[derive(Debug)]
+pub struct TempStore {
+    page_size: usize,
+    memory_pages: usize,
+    pages: LruCache<usize, Vec<u8>>,
+    spill: SpillFile,
+    num_pages: usize,
+    free_pages: Vec<usize>,
+}
+
+impl TempStore {
+    pub fn new(page_size: usize, memory_pages: usize) -> Self {
+        return Self {
+            page_size: page_size,
+            memory_pages: memory_pages,
+            pages: LruCache::unbounded(),
+            spill: SpillFile::new(),
+            num_pages: 0,
+            free_pages: Vec::new(),
+        };
+    }
+
+    pub fn page_size(&self) -> usize {
+        return self.page_size;
+    }
+
+    /// The number of pages in use.
+    pub fn num_pages(&self) -> usize {
+        return self.num_pages - self.free_pages.len();
+    }
+
+    /// The number of pages that have been written to the temporary file.
+    pub fn num_spilled(&self) -> usize {
+        return self.spill.len();
+    }
+
+    /// Stores a new page, returning its number. Pages shorter than the
+    /// page size are padded with zeroes.
+    pub fn allocate_raw_page(&mut self, bytes: Vec<u8>) -> Result<usize> {
+        let page_num = match self.free_pages.pop() {
+            Some(page_num) => page_num,
+            None => {
+                self.num_pages += 1;
+                self.num_pages
+            }
+        };
+        self.insert_raw_page(page_num, bytes)?;
+        return Ok(page_num);
+    }
+
+    pub fn get_raw_page(&self, page_num: usize) -> Result<Vec<u8>> {
+        if let Some(bytes) = self.pages.peek(&page_num) {
+            return Ok(bytes.clone());
+        }
+        return match self.spill.read(page_num, self.page_size)? {
+            Some(bytes) => Ok(bytes),
+            None => Err(eyre!("Temporary page {} does not exist", page_num)),
+        };
+    }
+
+    pub fn insert_raw_page(&mut self, page_num: usize, mut bytes: Vec<u8>) -> Result<()> {
+        if page_num == 0 || page_num > self.num_pages || self.free_pages.contains(&page_num) {
+            return Err(eyre!("Temporary page {} does not exist", page_num));
         }
-        if file_length % db_options.page_size != 0 {
+        if bytes.len() > self.page_size {
             return Err(eyre!(
-                "DB file is not a whole number of pages. Corrupt file."
+                "Page {} is longer than {} bytes",
+                page_num,
+                self.page_size
             ));
         }
+        bytes.resize(self.page_size, 0);
+        self.spill.remove(page_num);
+        self.pages.put(page_num, bytes);
+        while self.pages.len() > self.memory_pages {
+            let (spilled, bytes) = self.pages.pop_lru().unwrap();
+            self.spill.write(spilled, &bytes)?;
+        }
+        return Ok(());
+    }
 
-        return Ok(Self {
-            file_descriptor: file,
-            file_length: file_length,
-            cache: LruCache::new(CACHE_SIZE),
-            num_pages: file_length / db_options.page_size,
-            page_size: db_options.page_size,
-            reserved_space: db_options.reserved_space,
-        });
+    pub fn free_page(&mut self, page_num: usize) -> Result<()> {
+        if page_num == 0 || page_num > self.num_pages || self.free_pages.contains(&page_num) {
+            return Err(eyre!("Temporary page {} does not exist", page_num));
+        }
+        self.pages.pop(&page_num);
+        self.spill.remove(page_num);
+        self.free_pages.push(page_num);
+        return Ok(());
     }
 
-    pub fn read_from_file(&self, page_num: usize) -> Result<Vec<u8>> {
-        if page_num <= self.num_pages {
-            let mut page = vec![0; self.page_size];
-            let _ = self
-                .file_descriptor
-                .read_at(((page_num - 1) * self.page_size) as u64, &mut page)?;
-            return Ok(page);
-        } else {
-            return Err(eyre!("Tried to access non-existent page."));
+    /// Starts writing a run of bytes, which is laid out across as many
+    /// new pages as it needs.
+    pub fn writer(&mut self) -> TempWriter<'_> {
+        return TempWriter {
+            store: self,
+            pages: Vec::new(),
+            buffer: Vec::new(),
+            len: 0,
+        };
+    }
+}
+
+/// A run of bytes written to a `TempStore`: the pages it was laid out
+/// across, in order, and its length.
+#[derive(Debug, Clone, Default)]
+pub struct TempRun {
+    pub pages: Vec<usize>,
+    pub len: usize,
+}
+
+/// Writes a run of bytes to a `TempStore` a page at a time. `finish`
+/// writes the last, partly filled page.
+pub struct TempWriter<'a> {
+    store: &'a mut TempStore,
+    pages: Vec<usize>,
+    buffer: Vec<u8>,
+    len: usize,
+}
+
+impl<'a> TempWriter<'a> {
+    pub fn finish(mut self) -> Result<TempRun> {
+        if !self.buffer.is_empty() {
+            let bytes = std::mem::take(&mut self.buffer);
+            self.pages.push(self.store.allocate_raw_page(bytes)?);
         }
+        return Ok(TempRun {
+            pages: self.pages,
+            len: self.len,
+        });
     }
+}
 
-    pub fn get_page(&mut self, page_num: usize) -> Result<&BtreePage> {
-        if page_num > self.num_pages {
-            return Err(eyre!("Trying to access page that does not exist."));
+impl<'a> std::io::Write for TempWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let page_size = self.store.page_size;
+        let n = std::cmp::min(buf.len(), page_size - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        self.len += n;
+        if self.buffer.len() == page_size {
+            let bytes = std::mem::replace(&mut self.buffer, Vec::with_capacity(page_size));
+            let page_num = self
+                .store
+                .allocate_raw_page(bytes)
+                .map_err(|e| std::io::Error::other(e.to_string()))?;
+            self.pages.push(page_num);
         }
-        if self.cache.peek(&page_num).is_none() {
-            // if page_num >= self.num_pages {
-            //     // page does not exist yet; allocate
-            //     // new one
-            //     let page = Page::with_capacity(self.page_size);
-            //     self.cache.put(page_num, page);
-            //     self.num_pages += 1;
-            // } else {
-            // cache miss; allocate memory and load
-            // from file
-            let page = self.read_from_file(page_num)?;
-            let parsed_page =
-                BtreePage::deserialize(&page, page_num, self.page_size, self.reserved_space)?;
-            self.cache.put(page_num, parsed_page);
-            // }
-        }
-        return Ok(self.cache.get(&page_num).unwrap());
+        return Ok(n);
     }
 
-    pub fn get_page_mut(&mut self, page_num: usize) -> Result<&mut BtreePage> {
-        if page_num > self.num_pages {
-            return Err(eyre!("Trying to access page that does not exist."));
+    fn flush(&mut self) -> std::io::Result<()> {
+        return Ok(());
+    }
+}
+
+/// Reads a run back from a `TempStore`, which is shared so that several
+/// runs can be read at once, as when they are merged.
+pub struct TempReader {
+    store: Arc<TempStore>,
+    run: TempRun,
+    pos: usize,
+    page: Vec<u8>,
+}
+
+impl TempReader {
+    pub fn new(store: Arc<TempStore>, run: TempRun) -> Self {
+        return Self {
+            store: store,
+            run: run,
+            pos: 0,
+            page: Vec::new(),
+        };
+    }
+}
+
+impl std::io::Read for TempReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos >= self.run.len || buf.is_empty() {
+            return Ok(0);
         }
-        if self.cache.peek(&page_num).is_none() {
-            // if page_num >= self.num_pages {
-            //     // page does not exist yet; allocate
-            //     // new one
-            //     let page = Page::with_capacity(self.page_size);
-            //     self.cache.put(page_num, page);
-            //     self.num_pages += 1;
-            // } else {
-            // cache miss; allocate memory and load
-            // from file
-            let page = self.read_from_file(page_num)?;
-            let parsed_page =
-                BtreePage::deserialize(&page, page_num, self.page_size, self.reserved_space)?;
-            self.cache.put(page_num, parsed_page);
-            // }
-        }
-        return Ok(self.cache.get_mut(&page_num).unwrap());
-    }
-
-    pub fn insert(&mut self, page_num: usize, page: BtreePage) {
-        self.cache.put(page_num, page);
-    }
-}
-
-// impl Drop for Pager {
-//     fn drop(&mut self) {
-//         for (i, page) in self.pages.iter().enumerate() {
-//             if let Some(pg) = page {
-//                 let bytes = pg.serialize();
-//                 self.file_descriptor
-//                     .write_all_at((i * PAGE_SIZE) as u64, &bytes)
-//                     .expect("Error writing data to file.");
-//             }
-//         }
-//     }
-// }
+        let page_size = self.store.page_size;
+        let offset = self.pos % page_size;
+        if offset == 0 || self.page.is_empty() {
+            let page_num = self.run.pages[self.pos / page_size];
+            self.page = self
+                .store
+                .get_raw_page(page_num)
+                .map_err(|e| std::io::Error::other(e.to_string()))?;
+        }
+        let end = std::cmp::min(page_size, offset + self.run.len - self.pos);
+        let n = std::cmp::min(buf.len(), end - offset);
+        buf[..n].copy_from_slice(&self.page[offset..offset + n]);
+        self.pos += n;
+        return Ok(n);
+    }
+}
 
-#[derive(Debug, Clone)]
-pub struct FreelistPage {
+/// A trunk page of the freelist. The trunks form a chain from the one
+/// the file header points to, and each holds the numbers of some of the
+/// freelist's leaf pages. Leaf pages hold nothing that is ever read, so
+/// a page is put on the freelist as a leaf without being written.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FreelistTrunk {
     pub free_pages: Vec<usize>,
     pub next_page_link: Option<usize>,
 }
 
-impl FreelistPage {
+impl FreelistTrunk {
+    /// The most leaf pages a trunk page with the given usable size holds.
+    /// As in SQLite, the last six slots are left unused, as versions
+    /// before 3.6.0 took a trunk that used them to be corrupt.
+    pub fn capacity(usable_size: usize) -> usize {
+        return usable_size / 4 - 8;
+    }
+
+    /// Reads a freelist trunk page from its usable bytes (the page
+    /// without its reserved space).
     pub fn deserialize(i: &[u8]) -> Result<Self> {
         let next_page = parsing::be_u32(&i[0..4])?;
         let next_page_link = if next_page > 0 {
@@ -139,6 +1741,9 @@ impl FreelistPage {
             None
         };
         let list_size = parsing::be_u32(&i[4..8])? as usize;
+        if list_size > i.len() / 4 - 2 {
+            return Err(eyre!("freelist trunk page holds too many leaves"));
+        }
         let mut ints = Vec::new();
         if list_size > 0 {
             for n in 2..=(list_size + 1) {
@@ -152,19 +1757,791 @@ impl FreelistPage {
         });
     }
 
+    /// Writes the trunk page: the next trunk's number, the number of
+    /// leaves, and their numbers, each as a 32-bit big-endian integer.
     pub fn serialize(&self, page_size: usize) -> Vec<u8> {
         let mut output = Vec::new();
-        match self.next_page_link {
-            Some(pg) => output.extend(&pg.to_be_bytes()),
-            None => output.extend(&0_u32.to_be_bytes()),
-        }
-
-        output.extend(&self.free_pages.len().to_be_bytes());
+        let next_page = self.next_page_link.unwrap_or(0) as u32;
+        output.extend(&next_page.to_be_bytes());
+        output.extend(&(self.free_pages.len() as u32).to_be_bytes());
         for i in &self.free_pages {
-            output.extend(&i.to_be_bytes());
+            output.extend(&(*i as u32).to_be_bytes());
         }
 
         output.extend(&vec![0x0; page_size - output.len()]);
         return output;
     }
 }
+
+/// What a page is, as recorded in the pointer map of an auto-vacuum
+/// database, which also records the page that refers to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum PtrmapType {
+    /// The root of a B-tree, which has no parent.
+    RootPage = 1,
+    /// A page on the freelist, which has no parent.
+    FreePage = 2,
+    /// The first overflow page of a cell, whose parent is the B-tree
+    /// page the cell is on.
+    Overflow1 = 3,
+    /// A later overflow page, whose parent is the page before it.
+    Overflow2 = 4,
+    /// A B-tree page other than a root, whose parent is the page above
+    /// it in the tree.
+    Btree = 5,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PtrmapEntry {
+    pub kind: PtrmapType,
+    pub parent: u32,
+}
+
+/// A pointer map page, which an auto-vacuum database has in place of
+/// every `usable_size / 5 + 1`th page from page 2 on, so that a page can
+/// be moved by updating whatever refers to it. Each holds a five-byte
+/// entry for each of the pages after it, up to the next pointer map
+/// page: a type, then the parent's page number.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PtrmapPage {
+    /// The entry of each page after this one, or `None` where the entry
+    /// has not been written (as for a page past the end of the file).
+    pub entries: Vec<Option<PtrmapEntry>>,
+}
+
+/// Whether a page of an auto-vacuum database, whose pages have the given
+/// usable size, is a pointer map page.
+pub fn is_ptrmap_page(usable_size: usize, page_num: usize) -> bool {
+    return page_num >= 2 && ptrmap_page_for(usable_size, page_num) == page_num;
+}
+
+/// The pointer map page holding the entry of a page (or the page itself,
+/// if it is a pointer map page).
+fn ptrmap_page_for(usable_size: usize, page_num: usize) -> usize {
+    let span = PtrmapPage::entries_per_page(usable_size) + 1;
+    return (page_num - 2) / span * span + 2;
+}
+
+impl PtrmapPage {
+    /// The number of pages whose entries a pointer map page holds.
+    pub fn entries_per_page(usable_size: usize) -> usize {
+        return usable_size / 5;
+    }
+
+    /// Reads a pointer map page from its usable bytes (the page without
+    /// its reserved space).
+    pub fn deserialize(i: &[u8]) -> Result<Self> {
+        let mut entries = Vec::with_capacity(Self::entries_per_page(i.len()));
+        for entry in i.chunks_exact(5) {
+            if entry[0] == 0 {
+                entries.push(None);
+                continue;
+            }
+            let kind = PtrmapType::try_from(entry[0])
+                .map_err(|_| eyre!("Invalid pointer map entry type {}", entry[0]))?;
+            entries.push(Some(PtrmapEntry {
+                kind: kind,
+                parent: parsing::be_u32(&entry[1..5])?,
+            }));
+        }
+        return Ok(Self { entries: entries });
+    }
+
+    pub fn serialize(&self, page_size: usize) -> Vec<u8> {
+        let mut output = Vec::with_capacity(page_size);
+        for entry in &self.entries {
+            match entry {
+                Some(entry) => {
+                    output.push(entry.kind as u8);
+                    output.extend(&entry.parent.to_be_bytes());
+                }
+                None => output.extend(&[0; 5]),
+            }
+        }
+        output.resize(page_size, 0);
+        return output;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btree::Btree;
+    use crate::datatypes::VarInt;
+    use crate::pagefile::{CacheSize, IoMode, ShortRead};
+    use positioned_io::WriteAt;
+    use std::io::{Read, Write};
+
+    /// Writes a database of `num_pages` empty table leaf pages to a temp
+    /// file and returns it along with its options.
+    fn empty_db(num_pages: usize) -> (tempfile::NamedTempFile, DbOptions) {
+        return sized_db(4096, num_pages);
+    }
+
+    fn sized_db(page_size: usize, num_pages: usize) -> (tempfile::NamedTempFile, DbOptions) {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        let mut db_options = DbOptions::init(file.path().to_str().unwrap()).unwrap();
+        db_options.page_size = page_size;
+        db_options.num_pages = num_pages as u32;
+
+        let mut bytes = db_options.serialize();
+        bytes.extend(table_leaf(page_size, 100, &[]));
+        for _ in 2..=num_pages {
+            bytes.extend(table_leaf(page_size, 0, &[]));
+        }
+        file.write_all(&bytes).unwrap();
+        return (file, db_options);
+    }
+
+    /// Builds the bytes of a table leaf page holding rows with a single
+    /// small integer column. `offset` is where the page header starts
+    /// (100 on page 1, 0 otherwise); the returned bytes begin there.
+    fn table_leaf(page_size: usize, offset: usize, rows: &[(u8, i8)]) -> Vec<u8> {
+        let mut page = vec![0; page_size - offset];
+        let mut cell_start = page_size;
+        let mut pointers = Vec::new();
+        for (row_id, value) in rows {
+            // payload size, row id, record header size, serial type, value
+            let cell = [3, *row_id, 2, 1, *value as u8];
+            cell_start -= cell.len();
+            page[cell_start - offset..cell_start - offset + cell.len()].copy_from_slice(&cell);
+            pointers.extend(&(cell_start as u16).to_be_bytes());
+        }
+        page[0] = 0x0d;
+        page[3..5].copy_from_slice(&(rows.len() as u16).to_be_bytes());
+        page[5..7].copy_from_slice(&(cell_start as u16).to_be_bytes());
+        page[8..8 + pointers.len()].copy_from_slice(&pointers);
+        return page;
+    }
+
+    fn fragmented_bytes(pager: &mut Pager, page_num: usize) -> u8 {
+        return match &*pager.get_page(page_num).unwrap() {
+            BtreePage::TableLeaf(pg) => pg.header.fragmented_bytes,
+            _ => panic!("Expected a table leaf page"),
+        };
+    }
+
+    fn set_fragmented_bytes(pager: &mut Pager, page_num: usize, value: u8) {
+        match pager.get_page_mut(page_num).unwrap() {
+            BtreePage::TableLeaf(pg) => pg.header.fragmented_bytes = value,
+            _ => panic!("Expected a table leaf page"),
+        }
+    }
+
+    #[test]
+    fn failed_statement_is_rolled_back() {
+        let (file, db_options) = empty_db(3);
+        let mut pager = Pager::new(file.path().to_str().unwrap(), &db_options).unwrap();
+
+        // a change from an earlier, successful statement
+        pager
+            .run_statement(|pgr| {
+                set_fragmented_bytes(pgr, 2, 1);
+                Ok(())
+            })
+            .unwrap();
+
+        let result: Result<()> = pager.run_statement(|pgr| {
+            set_fragmented_bytes(pgr, 2, 5);
+            set_fragmented_bytes(pgr, 3, 5);
+            Err(eyre!("constraint failed"))
+        });
+        assert!(result.is_err());
+        assert_eq!(fragmented_bytes(&mut pager, 2), 1);
+        assert_eq!(fragmented_bytes(&mut pager, 3), 0);
+        assert_eq!(pager.statement_depth(), 0);
+    }
+
+    #[test]
+    fn nested_statement_rollback() {
+        let (file, db_options) = empty_db(3);
+        let mut pager = Pager::new(file.path().to_str().unwrap(), &db_options).unwrap();
+
+        pager.begin_statement();
+        set_fragmented_bytes(&mut pager, 2, 1);
+
+        // inner statement fails; only its own change is undone
+        pager.begin_statement();
+        set_fragmented_bytes(&mut pager, 2, 2);
+        set_fragmented_bytes(&mut pager, 3, 2);
+        pager.rollback_statement().unwrap();
+        assert_eq!(fragmented_bytes(&mut pager, 2), 1);
+        assert_eq!(fragmented_bytes(&mut pager, 3), 0);
+
+        // inner statement succeeds, but the outer one is rolled back
+        pager.begin_statement();
+        set_fragmented_bytes(&mut pager, 3, 3);
+        pager.commit_statement().unwrap();
+        pager.rollback_statement().unwrap();
+        assert_eq!(fragmented_bytes(&mut pager, 2), 0);
+        assert_eq!(fragmented_bytes(&mut pager, 3), 0);
+
+        assert!(pager.commit_statement().is_err());
+    }
+
+    #[test]
+    fn reads_see_earlier_writes() {
+        // more pages than the cache can hold, so that unmodified pages
+        // get evicted while scanning
+        let num_pages = CACHE_SIZE + 10;
+        let (mut file, db_options) = sized_db(512, num_pages);
+        file.as_file_mut()
+            .write_all_at(512, &table_leaf(512, 0, &[(1, 10), (2, 20)]))
+            .unwrap();
+
+        let filename = file.path().to_str().unwrap();
+        let pager = SharedPager::new(Pager::new(filename, &db_options).unwrap());
+        let tree = Btree::new("t".to_string(), "t".to_string(), 2, pager.clone());
+        assert_eq!(tree.list_records().len(), 2);
+
+        let new_page =
+            BtreePage::deserialize(&table_leaf(512, 0, &[(1, 10), (2, 20), (3, 30)]), 2, 512, 0)
+                .unwrap();
+        pager.write().insert(2, new_page).unwrap();
+        assert!(tree.get_row(VarInt::new(3)).is_some());
+
+        // cycle every other page through the cache
+        for page_num in 3..=num_pages {
+            pager.write().get_page(page_num).unwrap();
+        }
+        assert_eq!(tree.list_records().len(), 3);
+
+        match pager.write().get_page_mut(2).unwrap() {
+            BtreePage::TableLeaf(pg) => {
+                // drop the last row
+                pg.header.num_cells -= 1;
+                pg.header.cell_pointers.pop();
+            }
+            _ => panic!("Expected a table leaf page"),
+        }
+        for page_num in 3..=num_pages {
+            pager.write().get_page(page_num).unwrap();
+        }
+        assert_eq!(tree.list_records().len(), 2);
+        assert!(tree.get_row(VarInt::new(3)).is_none());
+        assert!(pager.read().is_dirty(2));
+    }
+
+    #[test]
+    fn pages_are_shared_until_changed() {
+        let (mut file, db_options) = sized_db(512, 3);
+        file.as_file_mut()
+            .write_all_at(512, &table_leaf(512, 0, &[(1, 10), (2, 20)]))
+            .unwrap();
+        let mut pager = Pager::new(file.path().to_str().unwrap(), &db_options).unwrap();
+
+        let first = pager.get_page(2).unwrap();
+        assert!(Arc::ptr_eq(&first, &pager.get_page(2).unwrap()));
+
+        // a reader holding the page keeps the version it was given
+        set_fragmented_bytes(&mut pager, 2, 3);
+        assert_eq!(first.header().fragmented_bytes, 0);
+        assert_eq!(fragmented_bytes(&mut pager, 2), 3);
+        assert!(!Arc::ptr_eq(&first, &pager.get_page(2).unwrap()));
+    }
+
+    #[test]
+    fn pointer_map() {
+        // with 512-byte pages, each pointer map page covers the 102
+        // pages after it
+        let (mut file, mut db_options) = sized_db(512, 104);
+        file.as_file_mut().write_all_at(512, &[0; 512]).unwrap();
+        db_options.largest_root_page = 3;
+        let mut pager = Pager::new(file.path().to_str().unwrap(), &db_options).unwrap();
+        assert!(pager.is_ptrmap_page(2));
+        assert!(!pager.is_ptrmap_page(104));
+        assert!(pager.is_ptrmap_page(105));
+        assert!(pager.get_page(2).is_err());
+
+        let entry = PtrmapEntry {
+            kind: PtrmapType::Btree,
+            parent: 3,
+        };
+        assert_eq!(pager.ptrmap_get(4).unwrap(), None);
+        pager.ptrmap_put(4, entry).unwrap();
+        assert_eq!(pager.ptrmap_get(4).unwrap(), Some(entry));
+        assert_eq!(&pager.get_raw_page(2).unwrap()[5..10], &[5, 0, 0, 0, 3]);
+        assert!(pager.ptrmap_get(2).is_err());
+        assert!(pager.ptrmap_get(105).is_err());
+
+        // the file grows past the next pointer map page
+        assert_eq!(pager.allocate_page(PageType::TableLeaf).unwrap(), 106);
+        assert!(pager.get_raw_page(105).unwrap().iter().all(|b| *b == 0));
+        let root = PtrmapEntry {
+            kind: PtrmapType::RootPage,
+            parent: 0,
+        };
+        pager.ptrmap_put(106, root).unwrap();
+        assert_eq!(pager.ptrmap_get(106).unwrap(), Some(root));
+    }
+
+    #[test]
+    fn freelist_trunks() {
+        let trunk = FreelistTrunk {
+            free_pages: vec![7, 300],
+            next_page_link: Some(5),
+        };
+        let bytes = trunk.serialize(512);
+        assert_eq!(bytes.len(), 512);
+        assert_eq!(
+            &bytes[..16],
+            &[0, 0, 0, 5, 0, 0, 0, 2, 0, 0, 0, 7, 0, 0, 1, 44]
+        );
+        assert_eq!(FreelistTrunk::deserialize(&bytes).unwrap(), trunk);
+        let mut full = bytes.clone();
+        full[4..8].copy_from_slice(&127_u32.to_be_bytes());
+        assert!(FreelistTrunk::deserialize(&full).is_err());
+
+        // the header points to the first trunk and counts every page
+        let mut conn = crate::connection::Connection::deserialize(Vec::new()).unwrap();
+        conn.execute("CREATE TABLE t(a)").unwrap();
+        let values: Vec<String> = (0..20)
+            .map(|i| format!("('{}')", "x".repeat(i * 500)))
+            .collect();
+        conn.execute(&format!("INSERT INTO t VALUES {}", values.join(", ")))
+            .unwrap();
+        conn.execute("DELETE FROM t").unwrap();
+        let image = conn.serialize().unwrap();
+        let freelist = conn.pager.write().freelist_pages().unwrap();
+        let header_u32 = |pos: usize| parsing::be_u32(&image[pos..pos + 4]).unwrap() as usize;
+        assert_eq!(header_u32(32), freelist[0]);
+        assert_eq!(header_u32(36), freelist.len());
+        assert!(freelist.len() > 20);
+    }
+
+    #[test]
+    fn cache_size_and_hits() {
+        let (file, mut db_options) = sized_db(512, 20);
+        let filename = file.path().to_str().unwrap();
+        // the header suggests 2 KiB
+        db_options.cache_size = -2;
+        let mut pager = Pager::new(filename, &db_options).unwrap();
+        assert_eq!(pager.cache_size(), 4);
+        let config = PagerConfig {
+            cache_size: Some(CacheSize::Pages(3)),
+            ..PagerConfig::default()
+        };
+        let configured = Pager::with_config(filename, &db_options, config).unwrap();
+        assert_eq!(configured.cache_size(), 3);
+
+        for page_num in 2..=6 {
+            pager.get_page(page_num).unwrap();
+        }
+        pager.get_page(6).unwrap();
+        // page 2 is the least recently used of the five
+        pager.get_page(2).unwrap();
+        let metrics = pager.metrics();
+        assert_eq!((metrics.cache_hits, metrics.cache_misses), (1, 6));
+
+        pager.set_cache_size(CacheSize::Bytes(512 * 10));
+        assert_eq!(pager.cache_size(), 10);
+        pager.set_cache_size(CacheSize::Pages(0));
+        assert_eq!(pager.cache_size(), 1);
+    }
+
+    #[test]
+    fn freed_pages_are_reused() {
+        let (file, db_options) = sized_db(512, 200);
+        let mut pager = Pager::new(file.path().to_str().unwrap(), &db_options).unwrap();
+
+        // a 512-byte trunk page holds 120 leaves, after which the next
+        // page freed starts a new trunk
+        pager.begin_statement();
+        for page_num in 2..=131 {
+            pager.free_page(page_num).unwrap();
+        }
+        pager.commit_statement().unwrap();
+        let mut expected = vec![123];
+        expected.extend(124..=131);
+        expected.push(2);
+        expected.extend(3..=122);
+        assert_eq!(pager.freelist_pages().unwrap(), expected);
+        assert!(pager.free_page(1).is_err());
+
+        // a statement that is rolled back gives its pages back
+        pager.begin_statement();
+        assert_eq!(pager.allocate_page(PageType::TableLeaf).unwrap(), 131);
+        pager.rollback_statement().unwrap();
+        assert_eq!(pager.freelist_pages().unwrap(), expected);
+
+        // leaves are handed out before the trunk that holds them
+        let allocated: Vec<usize> = (0..10)
+            .map(|_| pager.allocate_page(PageType::TableLeaf).unwrap())
+            .collect();
+        assert_eq!(
+            allocated,
+            vec![131, 130, 129, 128, 127, 126, 125, 124, 123, 122]
+        );
+        assert_eq!(pager.first_freelist, 2);
+        assert_eq!(pager.num_freelist, 120);
+        assert_eq!(pager.num_pages, 200);
+    }
+
+    #[test]
+    fn dirty_pages_spill_to_disk() {
+        let (file, db_options) = sized_db(512, 8);
+        let filename = file.path().to_str().unwrap();
+        let pager = SharedPager::new(Pager::new(filename, &db_options).unwrap());
+        pager.write().set_spill_threshold(Some(2));
+
+        pager.write().begin_statement();
+        for page_num in 2..=6 {
+            let rows = [(1, page_num as i8), (2, -(page_num as i8))];
+            let page =
+                BtreePage::deserialize(&table_leaf(512, 0, &rows), page_num, 512, 0).unwrap();
+            pager.write().insert(page_num, page).unwrap();
+        }
+        assert_eq!(pager.read().num_dirty(), 5);
+        assert_eq!(pager.read().num_spilled(), 3);
+
+        for page_num in 2..=6 {
+            assert!(pager.read().is_dirty(page_num));
+            let tree = Btree::new("t".to_string(), "t".to_string(), page_num, pager.clone());
+            let records = tree.list_records();
+            assert_eq!(records.len(), 2);
+            assert_eq!(
+                records[1].1.values[0].get_int_val(),
+                Some(-(page_num as i64))
+            );
+        }
+        assert_eq!(pager.read().num_spilled(), 3);
+
+        // spilled pages are restored by a rollback too
+        pager.write().rollback_statement().unwrap();
+        for page_num in 2..=6 {
+            let tree = Btree::new("t".to_string(), "t".to_string(), page_num, pager.clone());
+            assert!(tree.list_records().is_empty());
+        }
+    }
+
+    #[test]
+    fn failed_flush_keeps_pages() {
+        let (file, mut db_options) = sized_db(512, 8);
+        let filename = file.path().to_str().unwrap();
+        let mut pager = Pager::new(filename, &db_options).unwrap();
+        pager.set_spill_threshold(Some(2));
+        pager.begin_statement();
+        for page_num in 2..=6 {
+            let rows = [(1, page_num as i8)];
+            let page =
+                BtreePage::deserialize(&table_leaf(512, 0, &rows), page_num, 512, 0).unwrap();
+            pager.insert(page_num, page).unwrap();
+        }
+        pager.commit_statement().unwrap();
+        pager.truncate(6).unwrap();
+
+        // a flush that fails leaves every page to be written next time
+        let file_descriptor = std::mem::replace(&mut pager.file_descriptor, PageFile::bytes(&[]));
+        assert!(pager.flush_all(&mut db_options).is_err());
+        assert_eq!((pager.num_dirty(), pager.num_spilled()), (5, 3));
+        assert_eq!(db_options.num_pages, 8);
+        pager.file_descriptor = file_descriptor;
+        pager.flush_all(&mut db_options).unwrap();
+        assert_eq!((pager.num_dirty(), pager.num_spilled()), (0, 0));
+        drop(pager);
+
+        assert_eq!(std::fs::metadata(filename).unwrap().len(), 6 * 512);
+        let db_options = DbOptions::init(filename).unwrap();
+        assert_eq!(db_options.num_pages, 6);
+        let pager = SharedPager::new(Pager::new(filename, &db_options).unwrap());
+        for page_num in 2..=6 {
+            let tree = Btree::new("t".to_string(), "t".to_string(), page_num, pager.clone());
+            let records = tree.list_records();
+            assert_eq!(records[0].1.values[0].get_int_val(), Some(page_num as i64));
+        }
+    }
+
+    #[test]
+    fn configured_reads_match_buffered_reads() {
+        let (mut file, db_options) = sized_db(512, 20);
+        file.as_file_mut()
+            .write_all_at(512 * 4, &table_leaf(512, 0, &[(1, 10), (2, 20)]))
+            .unwrap();
+        let filename = file.path().to_str().unwrap();
+        let plain = Pager::new(filename, &db_options).unwrap();
+
+        let config = PagerConfig {
+            // only part of the file is mapped
+            mmap_size: 512 * 8,
+            read_ahead: 4,
+            io_mode: IoMode::Buffered,
+            verify_pages: true,
+            ..PagerConfig::default()
+        };
+        let pager = Pager::with_config(filename, &db_options, config).unwrap();
+        for page_num in 1..=20 {
+            assert_eq!(
+                pager.read_from_file(page_num).unwrap(),
+                plain.read_from_file(page_num).unwrap()
+            );
+        }
+        pager.get_page(3).unwrap();
+        assert!(pager.cache().contains(&5));
+        assert!(pager.cache().contains(&7));
+        assert!(!pager.cache().contains(&8));
+    }
+
+    #[test]
+    fn scans_read_ahead() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let filename = file.path().to_str().unwrap();
+        let mut conn = crate::connection::Connection::new(filename).unwrap();
+        conn.execute("PRAGMA page_size = 512").unwrap();
+        conn.execute("CREATE TABLE t(a, b)").unwrap();
+        let values: Vec<String> = (0..2000)
+            .map(|i| format!("({}, '{}')", i, "x".repeat(i % 40)))
+            .collect();
+        conn.execute(&format!("INSERT INTO t VALUES {}", values.join(", ")))
+            .unwrap();
+        drop(conn);
+
+        let scan = |scan_read_ahead| {
+            let config = PagerConfig {
+                scan_read_ahead: scan_read_ahead,
+                ..PagerConfig::default()
+            };
+            let conn = crate::connection::Connection::with_config(filename, config).unwrap();
+            let before = conn.pager.read().metrics();
+            let rows = conn.table("t").unwrap().btree.scan().count();
+            let after = conn.pager.read().metrics();
+            return (
+                rows,
+                after.pages_read - before.pages_read,
+                after.cache_misses - before.cache_misses,
+            );
+        };
+        let (rows, pages_read, misses) = scan(0);
+        let (ahead_rows, ahead_pages_read, ahead_misses) = scan(16);
+        assert_eq!((ahead_rows, ahead_pages_read), (rows, pages_read));
+        // every page but the root was read, in batches, before the scan
+        // came to it
+        assert!(pages_read > 100);
+        assert_eq!(misses, pages_read);
+        assert_eq!(ahead_misses, 1);
+    }
+
+    #[test]
+    fn page_headers_alone() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let filename = file.path().to_str().unwrap();
+        let mut conn = crate::connection::Connection::new(filename).unwrap();
+        conn.execute("PRAGMA page_size = 512").unwrap();
+        conn.execute("CREATE TABLE t(a, b)").unwrap();
+        let values: Vec<String> = (0..1000)
+            .map(|i| format!("({}, '{}')", i, "x".repeat(i % 40)))
+            .collect();
+        conn.execute(&format!("INSERT INTO t VALUES {}", values.join(", ")))
+            .unwrap();
+        drop(conn);
+
+        let conn = crate::connection::Connection::new(filename).unwrap();
+        let before = conn.pager.read().metrics();
+        assert_eq!(conn.table("t").unwrap().btree.count_rows().unwrap(), 1000);
+        // only the interior pages were read whole
+        let pages_read = conn.pager.read().metrics().pages_read - before.pages_read;
+        assert!(pages_read < 5);
+
+        let pager = conn.pager.read();
+        for page_num in 1..=pager.num_pages() {
+            let mut header = pager.read_page_header(page_num).unwrap();
+            assert!(header.cell_pointers.is_empty());
+            pager.read_cell_pointers(page_num, &mut header).unwrap();
+            assert_eq!(&header, pager.get_page(page_num).unwrap().header());
+        }
+    }
+
+    #[test]
+    fn shared_pagers() {
+        let (file, db_options) = sized_db(512, 4);
+        let filename = file.path().to_str().unwrap();
+        let config = PagerConfig::default();
+
+        let first = open_shared(filename, &db_options, config).unwrap();
+        let second = {
+            let filename = filename.to_string();
+            std::thread::spawn(move || open_shared(&filename, &db_options, config).unwrap())
+                .join()
+                .unwrap()
+        };
+        assert!(first.ptr_eq(&second));
+
+        let new_page = BtreePage::deserialize(&table_leaf(512, 0, &[(1, 10)]), 2, 512, 0).unwrap();
+        first.write().insert(2, new_page).unwrap();
+        let tree = Btree::new("t".to_string(), "t".to_string(), 2, second.clone());
+        assert_eq!(tree.list_records().len(), 1);
+
+        // once every user is gone, the next open starts afresh
+        drop((first, second, tree));
+        let third = open_shared(filename, &db_options, config).unwrap();
+        assert!(!third.read().is_dirty(2));
+    }
+
+    #[test]
+    fn readers_on_other_threads() {
+        let mut conn = crate::connection::Connection::deserialize(Vec::new()).unwrap();
+        conn.execute("PRAGMA page_size = 512").unwrap();
+        for name in &["t", "u", "v"] {
+            conn.execute(&format!("CREATE TABLE {}(a, b)", name))
+                .unwrap();
+            let values: Vec<String> = (1..=400)
+                .map(|i| format!("({}, '{}')", i, name.repeat(i % 20)))
+                .collect();
+            conn.execute(&format!(
+                "INSERT INTO {} VALUES {}",
+                name,
+                values.join(", ")
+            ))
+            .unwrap();
+        }
+
+        let threads: Vec<_> = ["t", "u", "v"]
+            .iter()
+            .map(|name| {
+                let root_page = conn.schema.table(name).unwrap().root_page;
+                let pager = conn.pager.clone();
+                return std::thread::spawn(move || {
+                    let tree = Btree::new(name.to_string(), name.to_string(), root_page, pager);
+                    return tree.scan().map(|row| row.unwrap().0 .0).sum::<i64>();
+                });
+            })
+            .collect();
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), (1..=400).sum::<i64>());
+        }
+    }
+
+    #[test]
+    fn readers_share_the_lock() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let filename = file.path().to_str().unwrap();
+        let mut conn = crate::connection::Connection::new(filename).unwrap();
+        conn.execute("PRAGMA page_size = 512").unwrap();
+        conn.execute("CREATE TABLE t(a, b)").unwrap();
+        let values: Vec<String> = (1..=400)
+            .map(|i| format!("({}, '{}')", i, "x".repeat(i % 300)))
+            .collect();
+        conn.execute(&format!("INSERT INTO t VALUES {}", values.join(", ")))
+            .unwrap();
+        let root_page = conn.schema.table("t").unwrap().root_page;
+        drop(conn);
+
+        let db_options = DbOptions::init(filename).unwrap();
+        let pager = open_shared(filename, &db_options, PagerConfig::default()).unwrap();
+        pager.write().set_cache_size(CacheSize::Pages(4));
+        // every reader reads the whole table, filling the cache and
+        // following overflow pages, while all of them, and this thread,
+        // hold the pager for reading
+        let held = pager.read();
+        let barrier = Arc::new(std::sync::Barrier::new(4));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let filename = filename.to_string();
+                let barrier = barrier.clone();
+                return std::thread::spawn(move || {
+                    let config = PagerConfig::default();
+                    let pager = open_shared(&filename, &db_options, config).unwrap();
+                    let guard = pager.read();
+                    barrier.wait();
+                    let tree =
+                        Btree::new("t".to_string(), "t".to_string(), root_page, pager.clone());
+                    let sum = tree.scan().map(|row| row.unwrap().0 .0).sum::<i64>();
+                    drop(guard);
+                    return sum;
+                });
+            })
+            .collect();
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), (1..=400).sum::<i64>());
+        }
+        drop(held);
+        assert!(pager.read().metrics().cache_misses > 0);
+    }
+
+    #[test]
+    fn preload_on_open() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let filename = file.path().to_str().unwrap();
+        let mut conn = crate::connection::Connection::new(filename).unwrap();
+        conn.execute("PRAGMA page_size = 512").unwrap();
+        conn.execute("CREATE TABLE t(a, b)").unwrap();
+        conn.execute("CREATE TABLE u(a)").unwrap();
+        let values: Vec<String> = (0..300)
+            .map(|i| format!("({}, '{}')", i, "x".repeat(i % 50)))
+            .collect();
+        conn.execute(&format!("INSERT INTO t VALUES {}", values.join(", ")))
+            .unwrap();
+        conn.execute("CREATE INDEX t_b ON t(b)").unwrap();
+        drop(conn);
+
+        let config = PagerConfig {
+            preload: true,
+            ..PagerConfig::default()
+        };
+        let conn = crate::connection::Connection::with_config(filename, config).unwrap();
+        let pager = conn.pager.read();
+        let mut expected = vec![1];
+        for root in &[
+            conn.schema.table("t").unwrap().root_page,
+            conn.schema.index("t_b").unwrap().root_page,
+        ] {
+            let children = pager.get_page(*root).unwrap().children();
+            assert!(!children.is_empty());
+            expected.extend(children.into_iter().map(|child| child as usize));
+        }
+        expected.push(conn.schema.table("u").unwrap().root_page);
+        for page_num in expected {
+            assert!(pager.cache().contains(&page_num));
+        }
+        // pages already in memory aren't read again
+        assert_eq!(pager.preload(&[1, 2, 3]).unwrap(), 0);
+    }
+
+    #[test]
+    fn short_reads() {
+        let (file, db_options) = sized_db(512, 4);
+        let filename = file.path().to_str().unwrap();
+        let config = PagerConfig {
+            read_ahead: 4,
+            ..PagerConfig::default()
+        };
+        let mut pager = Pager::with_config(filename, &db_options, config).unwrap();
+        // pages added since the last flush aren't read ahead from the file
+        let page_num = pager.allocate_page(PageType::TableLeaf).unwrap();
+        pager.get_page(2).unwrap();
+        assert!(pager.get_page(page_num).is_ok());
+
+        // the file is cut short underneath the pager, part way into page 3
+        file.as_file().set_len(512 * 2 + 100).unwrap();
+        let err = pager.read_from_file(3).unwrap_err();
+        let short = err.downcast_ref::<ShortRead>().unwrap();
+        assert_eq!((short.offset, short.expected, short.read), (1024, 512, 100));
+    }
+
+    #[test]
+    fn temp_store_spills_past_its_memory() {
+        let mut store = TempStore::new(512, 2);
+        let mut writer = store.writer();
+        let bytes: Vec<u8> = (0..2000).map(|i| (i % 251) as u8).collect();
+        writer.write_all(&bytes).unwrap();
+        let run = writer.finish().unwrap();
+        assert_eq!(run.pages.len(), 4);
+        // the last two pages written are the ones kept in memory
+        assert_eq!(store.num_spilled(), 2);
+
+        let mut store = Arc::new(store);
+        let mut read = Vec::new();
+        TempReader::new(store.clone(), run.clone())
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, bytes);
+
+        // freed pages are given out again
+        let store = Arc::get_mut(&mut store).unwrap();
+        store.free_page(run.pages[0]).unwrap();
+        assert!(store.get_raw_page(run.pages[0]).is_err());
+        assert_eq!(store.allocate_raw_page(vec![7; 10]).unwrap(), run.pages[0]);
+        let page = store.get_raw_page(run.pages[0]).unwrap();
+        assert_eq!((page.len(), &page[..10], page[10]), (512, &[7; 10][..], 0));
+    }
+}
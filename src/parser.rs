@@ -0,0 +1,2208 @@
+use eyre::{eyre, Result};
+
+use crate::ast::*;
+use crate::datatypes::Value;
+use crate::limits::Limits;
+use crate::tokenizer::{tokenize, Token, TokenKind};
+
+/// Keywords that can never be used as a bare (unquoted) identifier or
+/// as an alias without `AS`.
+const RESERVED: &[&str] = &[
+    "ALL",
+    "AND",
+    "AS",
+    "ASC",
+    "BETWEEN",
+    "BY",
+    "CASE",
+    "CHECK",
+    "COLLATE",
+    "CONSTRAINT",
+    "CREATE",
+    "CROSS",
+    "DEFAULT",
+    "DESC",
+    "DISTINCT",
+    "ELSE",
+    "END",
+    "ESCAPE",
+    "EXCEPT",
+    "EXISTS",
+    "FROM",
+    "GLOB",
+    "GROUP",
+    "HAVING",
+    "IN",
+    "INNER",
+    "INSERT",
+    "INTERSECT",
+    "INTO",
+    "IS",
+    "ISNULL",
+    "JOIN",
+    "LEFT",
+    "LIKE",
+    "LIMIT",
+    "NATURAL",
+    "NOT",
+    "NOTNULL",
+    "NULL",
+    "OFFSET",
+    "ON",
+    "OR",
+    "ORDER",
+    "OUTER",
+    "PRIMARY",
+    "REFERENCES",
+    "SELECT",
+    "TABLE",
+    "THEN",
+    "UNION",
+    "UNIQUE",
+    "USING",
+    "VALUES",
+    "WHEN",
+    "WHERE",
+];
+
+/// All of SQLite's keywords. Names that match one are quoted when written
+/// out, even where they could be read bare, so that SQLite can read the
+/// SQL too.
+const KEYWORDS: &[&str] = &[
+    "ABORT",
+    "ACTION",
+    "ADD",
+    "AFTER",
+    "ALL",
+    "ALTER",
+    "ALWAYS",
+    "ANALYZE",
+    "AND",
+    "AS",
+    "ASC",
+    "ATTACH",
+    "AUTOINCREMENT",
+    "BEFORE",
+    "BEGIN",
+    "BETWEEN",
+    "BY",
+    "CASCADE",
+    "CASE",
+    "CAST",
+    "CHECK",
+    "COLLATE",
+    "COLUMN",
+    "COMMIT",
+    "CONFLICT",
+    "CONSTRAINT",
+    "CREATE",
+    "CROSS",
+    "CURRENT",
+    "CURRENT_DATE",
+    "CURRENT_TIME",
+    "CURRENT_TIMESTAMP",
+    "DATABASE",
+    "DEFAULT",
+    "DEFERRABLE",
+    "DEFERRED",
+    "DELETE",
+    "DESC",
+    "DETACH",
+    "DISTINCT",
+    "DO",
+    "DROP",
+    "EACH",
+    "ELSE",
+    "END",
+    "ESCAPE",
+    "EXCEPT",
+    "EXCLUDE",
+    "EXCLUSIVE",
+    "EXISTS",
+    "EXPLAIN",
+    "FAIL",
+    "FILTER",
+    "FIRST",
+    "FOLLOWING",
+    "FOR",
+    "FOREIGN",
+    "FROM",
+    "FULL",
+    "GENERATED",
+    "GLOB",
+    "GROUP",
+    "GROUPS",
+    "HAVING",
+    "IF",
+    "IGNORE",
+    "IMMEDIATE",
+    "IN",
+    "INDEX",
+    "INDEXED",
+    "INITIALLY",
+    "INNER",
+    "INSERT",
+    "INSTEAD",
+    "INTERSECT",
+    "INTO",
+    "IS",
+    "ISNULL",
+    "JOIN",
+    "KEY",
+    "LAST",
+    "LEFT",
+    "LIKE",
+    "LIMIT",
+    "MATCH",
+    "MATERIALIZED",
+    "NATURAL",
+    "NO",
+    "NOT",
+    "NOTHING",
+    "NOTNULL",
+    "NULL",
+    "NULLS",
+    "OF",
+    "OFFSET",
+    "ON",
+    "OR",
+    "ORDER",
+    "OTHERS",
+    "OUTER",
+    "OVER",
+    "PARTITION",
+    "PLAN",
+    "PRAGMA",
+    "PRECEDING",
+    "PRIMARY",
+    "QUERY",
+    "RAISE",
+    "RANGE",
+    "RECURSIVE",
+    "REFERENCES",
+    "REGEXP",
+    "REINDEX",
+    "RELEASE",
+    "RENAME",
+    "REPLACE",
+    "RESTRICT",
+    "RETURNING",
+    "RIGHT",
+    "ROLLBACK",
+    "ROW",
+    "ROWS",
+    "SAVEPOINT",
+    "SELECT",
+    "SET",
+    "TABLE",
+    "TEMP",
+    "TEMPORARY",
+    "THEN",
+    "TIES",
+    "TO",
+    "TRANSACTION",
+    "TRIGGER",
+    "UNBOUNDED",
+    "UNION",
+    "UNIQUE",
+    "UPDATE",
+    "USING",
+    "VACUUM",
+    "VALUES",
+    "VIEW",
+    "VIRTUAL",
+    "WHEN",
+    "WHERE",
+    "WINDOW",
+    "WITH",
+    "WITHOUT",
+];
+
+/// Keywords that end a column's type name and begin its constraints.
+const COLUMN_CONSTRAINT_START: &[&str] = &[
+    "CONSTRAINT",
+    "PRIMARY",
+    "NOT",
+    "NULL",
+    "UNIQUE",
+    "CHECK",
+    "DEFAULT",
+    "COLLATE",
+    "REFERENCES",
+    "GENERATED",
+    "AS",
+];
+
+/// Parses a single SQL statement. A trailing semicolon is allowed.
+pub fn parse(sql: &str) -> Result<Stmt> {
+    return Ok(parse_with_parameters(sql)?.0);
+}
+
+/// Parses a single SQL statement, also returning the names of its
+/// parameters, in order of their indexes. Parameters written as a bare
+/// `?` have no name.
+pub fn parse_with_parameters(sql: &str) -> Result<(Stmt, Vec<Option<String>>)> {
+    return parse_with_limits(sql, &Limits::default());
+}
+
+/// Parses a single SQL statement, as with `parse_with_parameters`, but
+/// checking it against the given limits rather than the defaults.
+pub fn parse_with_limits(sql: &str, limits: &Limits) -> Result<(Stmt, Vec<Option<String>>)> {
+    let mut parser = Parser::with_limits(sql, limits)?;
+    let stmt = parser.parse_statement()?;
+    while parser.consume(&TokenKind::Semicolon) {}
+    parser.expect_eof()?;
+    return Ok((stmt, parser.parameters));
+}
+
+/// Splits a script into the text of each of its statements, without
+/// their semicolons. Semicolons in string literals, quoted identifiers,
+/// and comments do not end a statement.
+pub fn split_statements(sql: &str) -> Result<Vec<&str>> {
+    let mut statements = Vec::new();
+    let mut start = 0;
+    for token in tokenize(sql)? {
+        if token.kind == TokenKind::Semicolon || token.kind == TokenKind::Eof {
+            let text = sql[start..token.offset].trim();
+            if !text.is_empty() {
+                statements.push(text);
+            }
+            start = token.end;
+        }
+    }
+    return Ok(statements);
+}
+
+/// Parses a standalone expression, e.g. a column default.
+pub fn parse_expr(sql: &str) -> Result<Expr> {
+    let mut parser = Parser::new(sql)?;
+    let expr = parser.parse_expr()?;
+    parser.expect_eof()?;
+    return Ok(expr);
+}
+
+/// Quotes a name for use in SQL text, with double quotes, unless it is
+/// a plain ASCII word that is not a keyword.
+pub fn quote_name(name: &str) -> String {
+    let bare = name
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !KEYWORDS.iter().any(|kw| kw.eq_ignore_ascii_case(name));
+    if bare {
+        return name.to_string();
+    }
+    return format!("\"{}\"", name.replace('"', "\"\""));
+}
+
+/// Checks the name of the database qualifying a name, which can only be
+/// the main database.
+fn check_schema(name: &str) -> Result<()> {
+    if !name.eq_ignore_ascii_case("main") {
+        return Err(eyre!("unknown database {}", name));
+    }
+    return Ok(());
+}
+
+pub struct Parser {
+    sql: String,
+    tokens: Vec<Token>,
+    pos: usize,
+    /// The names of the parameters seen so far, indexed by parameter
+    /// index - 1.
+    parameters: Vec<Option<String>>,
+    max_expr_depth: usize,
+    /// How many expressions the parser is nested in at this point, which
+    /// bounds its recursion, e.g. through parentheses.
+    expr_depth: usize,
+    /// The height of the expression tree last parsed: 1 for a single
+    /// value or column, and one more than its tallest operand for an
+    /// operator or function.
+    height: usize,
+}
+
+/// The largest parameter index allowed, as in SQLite.
+pub const MAX_PARAMETER_INDEX: usize = 32766;
+
+impl Parser {
+    pub fn new(sql: &str) -> Result<Self> {
+        return Self::with_limits(sql, &Limits::default());
+    }
+
+    pub fn with_limits(sql: &str, limits: &Limits) -> Result<Self> {
+        if sql.len() > limits.max_sql_length {
+            return Err(eyre!("statement too long"));
+        }
+        return Ok(Self {
+            sql: sql.to_string(),
+            tokens: tokenize(sql)?,
+            pos: 0,
+            parameters: Vec::new(),
+            max_expr_depth: limits.max_expr_depth,
+            expr_depth: 0,
+            height: 0,
+        });
+    }
+
+    fn peek(&self) -> &Token {
+        return &self.tokens[self.pos];
+    }
+
+    fn peek_nth(&self, n: usize) -> &Token {
+        let idx = std::cmp::min(self.pos + n, self.tokens.len() - 1);
+        return &self.tokens[idx];
+    }
+
+    fn advance(&mut self) -> Token {
+        let token = self.tokens[self.pos].clone();
+        if self.pos < self.tokens.len() - 1 {
+            self.pos += 1;
+        }
+        return token;
+    }
+
+    fn error(&self) -> eyre::Report {
+        let token = self.peek();
+        if token.kind == TokenKind::Eof {
+            return eyre!("incomplete input");
+        }
+        return eyre!("near \"{}\": syntax error", token.describe());
+    }
+
+    fn at_keyword(&self, keyword: &str) -> bool {
+        return self.peek().is_keyword(keyword);
+    }
+
+    fn consume_keyword(&mut self, keyword: &str) -> bool {
+        if self.at_keyword(keyword) {
+            self.advance();
+            return true;
+        }
+        return false;
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<()> {
+        if self.consume_keyword(keyword) {
+            return Ok(());
+        }
+        return Err(self.error());
+    }
+
+    fn consume(&mut self, kind: &TokenKind) -> bool {
+        if &self.peek().kind == kind {
+            self.advance();
+            return true;
+        }
+        return false;
+    }
+
+    fn expect(&mut self, kind: &TokenKind) -> Result<()> {
+        if self.consume(kind) {
+            return Ok(());
+        }
+        return Err(self.error());
+    }
+
+    fn expect_eof(&self) -> Result<()> {
+        if self.peek().kind != TokenKind::Eof {
+            return Err(self.error());
+        }
+        return Ok(());
+    }
+
+    fn is_reserved(token: &Token) -> bool {
+        return RESERVED.iter().any(|kw| token.is_keyword(kw));
+    }
+
+    /// Parses an identifier: either a bare word that is not a reserved
+    /// keyword, or a quoted identifier. String literals are also accepted
+    /// as names, as SQLite does for compatibility.
+    fn parse_name(&mut self) -> Result<String> {
+        let token = self.peek().clone();
+        return match token.kind {
+            TokenKind::Identifier(s) if !Self::is_reserved(&token) => {
+                self.advance();
+                Ok(s)
+            }
+            TokenKind::QuotedIdentifier(s) | TokenKind::String(s) => {
+                self.advance();
+                Ok(s)
+            }
+            _ => Err(self.error()),
+        };
+    }
+
+    /// Parses the name of a table, view, or index, which may be qualified
+    /// by the name of the database it is in. There is only ever the main
+    /// database, so the qualifier is checked and then dropped.
+    fn parse_qualified_name(&mut self) -> Result<String> {
+        let name = self.parse_name()?;
+        if !self.consume(&TokenKind::Dot) {
+            return Ok(name);
+        }
+        check_schema(&name)?;
+        return self.parse_name();
+    }
+
+    fn parse_name_list(&mut self) -> Result<Vec<String>> {
+        self.expect(&TokenKind::LeftParen)?;
+        let mut names = vec![self.parse_name()?];
+        while self.consume(&TokenKind::Comma) {
+            names.push(self.parse_name()?);
+        }
+        self.expect(&TokenKind::RightParen)?;
+        return Ok(names);
+    }
+
+    /// Parses an optional alias, with or without a leading `AS`.
+    fn parse_alias(&mut self) -> Result<Option<String>> {
+        if self.consume_keyword("AS") {
+            return Ok(Some(self.parse_name()?));
+        }
+        let token = self.peek();
+        let is_alias = match &token.kind {
+            TokenKind::Identifier(_) => !Self::is_reserved(token),
+            TokenKind::QuotedIdentifier(_) | TokenKind::String(_) => true,
+            _ => false,
+        };
+        if is_alias {
+            return Ok(Some(self.parse_name()?));
+        }
+        return Ok(None);
+    }
+
+    pub fn parse_statement(&mut self) -> Result<Stmt> {
+        if self.consume_keyword("EXPLAIN") {
+            let query_plan = self.consume_keyword("QUERY");
+            if query_plan {
+                self.expect_keyword("PLAN")?;
+            }
+            if self.at_keyword("EXPLAIN") {
+                return Err(self.error());
+            }
+            return Ok(Stmt::Explain {
+                query_plan: query_plan,
+                stmt: Box::new(self.parse_statement()?),
+            });
+        } else if self.at_keyword("SELECT") {
+            return Ok(Stmt::Select(self.parse_select()?));
+        } else if self.at_keyword("INSERT") {
+            return Ok(Stmt::Insert(self.parse_insert()?));
+        } else if self.at_keyword("DELETE") {
+            return Ok(Stmt::Delete(self.parse_delete()?));
+        } else if self.at_keyword("CREATE") {
+            let next = self.peek_nth(1);
+            if next.is_keyword("INDEX") || next.is_keyword("UNIQUE") {
+                return Ok(Stmt::CreateIndex(self.parse_create_index()?));
+            } else if next.is_keyword("VIRTUAL") {
+                return Ok(Stmt::CreateVirtualTable(self.parse_create_virtual_table()?));
+            } else if next.is_keyword("VIEW")
+                || (next.is_keyword("TEMP") || next.is_keyword("TEMPORARY"))
+                    && self.peek_nth(2).is_keyword("VIEW")
+            {
+                return Ok(Stmt::CreateView(self.parse_create_view()?));
+            }
+            return Ok(Stmt::CreateTable(self.parse_create_table()?));
+        } else if self.at_keyword("ALTER") {
+            return Ok(Stmt::AlterTable(self.parse_alter_table()?));
+        } else if self.at_keyword("PRAGMA") {
+            return Ok(Stmt::Pragma(self.parse_pragma()?));
+        } else if self.consume_keyword("VACUUM") {
+            // the only schema that can be named is main
+            if !matches!(self.peek().kind, TokenKind::Semicolon | TokenKind::Eof) {
+                check_schema(&self.parse_name()?)?;
+            }
+            return Ok(Stmt::Vacuum);
+        }
+        return Err(self.error());
+    }
+
+    fn parse_select(&mut self) -> Result<Select> {
+        let mut select = self.parse_select_core()?;
+        while let Some(operator) = self.parse_compound_operator() {
+            select.compound.push(CompoundSelect {
+                operator: operator,
+                select: self.parse_select_core()?,
+            });
+        }
+
+        let mut order_by = Vec::new();
+        if self.consume_keyword("ORDER") {
+            self.expect_keyword("BY")?;
+            loop {
+                let expr = self.parse_expr()?;
+                let collation = if self.consume_keyword("COLLATE") {
+                    Some(self.parse_name()?)
+                } else {
+                    None
+                };
+                let descending = if self.consume_keyword("DESC") {
+                    true
+                } else {
+                    self.consume_keyword("ASC");
+                    false
+                };
+                order_by.push(OrderingTerm {
+                    expr: expr,
+                    collation: collation,
+                    descending: descending,
+                });
+                if !self.consume(&TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+
+        let mut limit = None;
+        let mut offset = None;
+        if self.consume_keyword("LIMIT") {
+            let first = self.parse_expr()?;
+            if self.consume_keyword("OFFSET") {
+                limit = Some(first);
+                offset = Some(self.parse_expr()?);
+            } else if self.consume(&TokenKind::Comma) {
+                // "LIMIT offset, count"
+                offset = Some(first);
+                limit = Some(self.parse_expr()?);
+            } else {
+                limit = Some(first);
+            }
+        }
+
+        select.order_by = order_by;
+        select.limit = limit;
+        select.offset = offset;
+        return Ok(select);
+    }
+
+    fn parse_compound_operator(&mut self) -> Option<CompoundOperator> {
+        if self.consume_keyword("UNION") {
+            if self.consume_keyword("ALL") {
+                return Some(CompoundOperator::UnionAll);
+            }
+            return Some(CompoundOperator::Union);
+        } else if self.consume_keyword("INTERSECT") {
+            return Some(CompoundOperator::Intersect);
+        } else if self.consume_keyword("EXCEPT") {
+            return Some(CompoundOperator::Except);
+        }
+        return None;
+    }
+
+    /// Parses a SELECT up to its WHERE clause, which is as much as each part
+    /// of a compound SELECT has.
+    fn parse_select_core(&mut self) -> Result<Select> {
+        self.expect_keyword("SELECT")?;
+        let distinct = self.consume_keyword("DISTINCT");
+        if !distinct {
+            self.consume_keyword("ALL");
+        }
+
+        let mut columns = vec![self.parse_result_column()?];
+        while self.consume(&TokenKind::Comma) {
+            columns.push(self.parse_result_column()?);
+        }
+
+        let mut from = None;
+        let mut joins = Vec::new();
+        if self.consume_keyword("FROM") {
+            from = Some(self.parse_table_ref()?);
+            while let Some(kind) = self.parse_join_operator()? {
+                let table = self.parse_table_ref()?;
+                let on = if self.consume_keyword("ON") {
+                    Some(self.parse_expr()?)
+                } else {
+                    None
+                };
+                joins.push(Join {
+                    kind: kind,
+                    table: table,
+                    on: on,
+                });
+            }
+        }
+
+        let mut where_clause = None;
+        if self.consume_keyword("WHERE") {
+            where_clause = Some(self.parse_expr()?);
+        }
+
+        return Ok(Select {
+            distinct: distinct,
+            columns: columns,
+            from: from,
+            joins: joins,
+            where_clause: where_clause,
+            compound: Vec::new(),
+            order_by: Vec::new(),
+            limit: None,
+            offset: None,
+        });
+    }
+
+    fn parse_table_ref(&mut self) -> Result<TableRef> {
+        if self.consume(&TokenKind::LeftParen) {
+            let select = self.parse_select()?;
+            self.expect(&TokenKind::RightParen)?;
+            return Ok(TableRef {
+                name: String::new(),
+                alias: self.parse_alias()?,
+                subquery: Some(Box::new(select)),
+                sample: None,
+            });
+        }
+        let name = self.parse_qualified_name()?;
+        // TABLESAMPLE is not a reserved word, but is not taken as an
+        // alias either
+        let alias = if self.at_keyword("TABLESAMPLE") {
+            None
+        } else {
+            self.parse_alias()?
+        };
+        return Ok(TableRef {
+            name: name,
+            alias: alias,
+            subquery: None,
+            sample: self.parse_table_sample()?,
+        });
+    }
+
+    /// Parses `TABLESAMPLE BERNOULLI|SYSTEM (percent) [REPEATABLE (seed)]`,
+    /// if it comes next.
+    fn parse_table_sample(&mut self) -> Result<Option<TableSample>> {
+        if !self.consume_keyword("TABLESAMPLE") {
+            return Ok(None);
+        }
+        let method = if self.consume_keyword("BERNOULLI") {
+            SampleMethod::Bernoulli
+        } else if self.consume_keyword("SYSTEM") {
+            SampleMethod::System
+        } else {
+            return Err(self.error());
+        };
+        self.expect(&TokenKind::LeftParen)?;
+        let percent = self.parse_expr()?;
+        self.expect(&TokenKind::RightParen)?;
+        let seed = if self.consume_keyword("REPEATABLE") {
+            self.expect(&TokenKind::LeftParen)?;
+            let seed = self.parse_expr()?;
+            self.expect(&TokenKind::RightParen)?;
+            Some(seed)
+        } else {
+            None
+        };
+        return Ok(Some(TableSample {
+            method: method,
+            percent: percent,
+            seed: seed,
+        }));
+    }
+
+    /// Parses the operator between two tables in a FROM clause, if there
+    /// is one: a comma, `JOIN`, `INNER JOIN`, `CROSS JOIN`, or
+    /// `LEFT [OUTER] JOIN`.
+    fn parse_join_operator(&mut self) -> Result<Option<JoinKind>> {
+        if self.consume(&TokenKind::Comma) {
+            return Ok(Some(JoinKind::Inner));
+        }
+        let kind = if self.consume_keyword("INNER") {
+            JoinKind::Inner
+        } else if self.consume_keyword("CROSS") {
+            JoinKind::Cross
+        } else if self.consume_keyword("LEFT") {
+            self.consume_keyword("OUTER");
+            JoinKind::Left
+        } else if self.at_keyword("JOIN") {
+            JoinKind::Inner
+        } else {
+            return Ok(None);
+        };
+        self.expect_keyword("JOIN")?;
+        return Ok(Some(kind));
+    }
+
+    fn parse_result_column(&mut self) -> Result<ResultColumn> {
+        if self.consume(&TokenKind::Star) {
+            return Ok(ResultColumn::Star);
+        }
+        let is_table_star = matches!(
+            self.peek().kind,
+            TokenKind::Identifier(_) | TokenKind::QuotedIdentifier(_)
+        ) && self.peek_nth(1).kind == TokenKind::Dot
+            && self.peek_nth(2).kind == TokenKind::Star;
+        if is_table_star {
+            let table = self.parse_name()?;
+            self.advance();
+            self.advance();
+            return Ok(ResultColumn::TableStar(table));
+        }
+        let start = self.peek().offset;
+        let expr = self.parse_expr()?;
+        let text = self.sql[start..self.peek().offset].trim_end().to_string();
+        let alias = self.parse_alias()?;
+        return Ok(ResultColumn::Expr {
+            expr: expr,
+            alias: alias,
+            text: text,
+        });
+    }
+
+    fn parse_insert(&mut self) -> Result<Insert> {
+        self.expect_keyword("INSERT")?;
+        self.expect_keyword("INTO")?;
+        let table = self.parse_qualified_name()?;
+        let columns = if self.peek().kind == TokenKind::LeftParen {
+            Some(self.parse_name_list()?)
+        } else {
+            None
+        };
+        let source = if self.at_keyword("SELECT") {
+            InsertSource::Select(Box::new(self.parse_select()?))
+        } else {
+            self.expect_keyword("VALUES")?;
+            let mut rows = vec![self.parse_values_row()?];
+            while self.consume(&TokenKind::Comma) {
+                let row = self.parse_values_row()?;
+                if row.len() != rows[0].len() {
+                    return Err(eyre!("all VALUES must have the same number of terms"));
+                }
+                rows.push(row);
+            }
+            InsertSource::Values(rows)
+        };
+        return Ok(Insert {
+            table: table,
+            columns: columns,
+            source: source,
+        });
+    }
+
+    /// Parses one row of a VALUES list, e.g., `(1, 'a')`.
+    fn parse_values_row(&mut self) -> Result<Vec<Expr>> {
+        self.expect(&TokenKind::LeftParen)?;
+        let mut values = vec![self.parse_expr()?];
+        while self.consume(&TokenKind::Comma) {
+            values.push(self.parse_expr()?);
+        }
+        self.expect(&TokenKind::RightParen)?;
+        return Ok(values);
+    }
+
+    fn parse_delete(&mut self) -> Result<Delete> {
+        self.expect_keyword("DELETE")?;
+        self.expect_keyword("FROM")?;
+        let table = self.parse_qualified_name()?;
+        let where_clause = if self.consume_keyword("WHERE") {
+            Some(self.parse_expr()?)
+        } else {
+            None
+        };
+        return Ok(Delete {
+            table: table,
+            where_clause: where_clause,
+        });
+    }
+
+    fn parse_alter_table(&mut self) -> Result<AlterTable> {
+        self.expect_keyword("ALTER")?;
+        self.expect_keyword("TABLE")?;
+        let table = self.parse_qualified_name()?;
+        let action = if self.consume_keyword("RENAME") {
+            self.consume_keyword("COLUMN");
+            let old = self.parse_name()?;
+            self.expect_keyword("TO")?;
+            AlterAction::RenameColumn {
+                old: old,
+                new: self.parse_name()?,
+            }
+        } else if self.consume_keyword("DROP") {
+            self.consume_keyword("COLUMN");
+            AlterAction::DropColumn(self.parse_name()?)
+        } else {
+            return Err(self.error());
+        };
+        return Ok(AlterTable {
+            table: table,
+            action: action,
+        });
+    }
+
+    fn parse_pragma(&mut self) -> Result<Pragma> {
+        self.expect_keyword("PRAGMA")?;
+        let mut schema = None;
+        let mut name = self.parse_name()?;
+        if self.consume(&TokenKind::Dot) {
+            schema = Some(name);
+            name = self.parse_name()?;
+        }
+        let mut value = None;
+        if self.consume(&TokenKind::Eq) {
+            value = Some(self.parse_pragma_value()?);
+        } else if self.consume(&TokenKind::LeftParen) {
+            value = Some(self.parse_pragma_value()?);
+            self.expect(&TokenKind::RightParen)?;
+        }
+        return Ok(Pragma {
+            schema: schema,
+            name: name,
+            value: value,
+        });
+    }
+
+    /// A pragma's value: a number, which may be signed, a string, or a
+    /// bare word such as `ON` or `FULL`.
+    fn parse_pragma_value(&mut self) -> Result<Value> {
+        let negate = self.peek().kind == TokenKind::Minus;
+        if negate || self.peek().kind == TokenKind::Plus {
+            self.advance();
+        }
+        let value = match self.peek().kind.clone() {
+            TokenKind::Integer(i) if negate => Value::Int64(-i),
+            TokenKind::Integer(i) => Value::Int64(i),
+            TokenKind::Float(f) if negate => Value::Float(-f),
+            TokenKind::Float(f) => Value::Float(f),
+            TokenKind::String(s) | TokenKind::Identifier(s) | TokenKind::QuotedIdentifier(s)
+                if !negate =>
+            {
+                Value::String(s)
+            }
+            _ => return Err(self.error()),
+        };
+        self.advance();
+        return Ok(value);
+    }
+
+    fn parse_create_index(&mut self) -> Result<CreateIndex> {
+        self.expect_keyword("CREATE")?;
+        let unique = self.consume_keyword("UNIQUE");
+        self.expect_keyword("INDEX")?;
+        let mut if_not_exists = false;
+        if self.consume_keyword("IF") {
+            self.expect_keyword("NOT")?;
+            self.expect_keyword("EXISTS")?;
+            if_not_exists = true;
+        }
+        let name = self.parse_qualified_name()?;
+        self.expect_keyword("ON")?;
+        let table = self.parse_name()?;
+        let columns = self.parse_indexed_columns()?;
+        let where_clause = if self.consume_keyword("WHERE") {
+            Some(self.parse_expr()?)
+        } else {
+            None
+        };
+        return Ok(CreateIndex {
+            name: name,
+            table: table,
+            unique: unique,
+            if_not_exists: if_not_exists,
+            columns: columns,
+            where_clause: where_clause,
+        });
+    }
+
+    fn parse_create_view(&mut self) -> Result<CreateView> {
+        self.expect_keyword("CREATE")?;
+        if !self.consume_keyword("TEMP") {
+            self.consume_keyword("TEMPORARY");
+        }
+        self.expect_keyword("VIEW")?;
+        let mut if_not_exists = false;
+        if self.consume_keyword("IF") {
+            self.expect_keyword("NOT")?;
+            self.expect_keyword("EXISTS")?;
+            if_not_exists = true;
+        }
+        let name = self.parse_qualified_name()?;
+        let mut columns = None;
+        if self.consume(&TokenKind::LeftParen) {
+            let mut names = vec![self.parse_name()?];
+            while self.consume(&TokenKind::Comma) {
+                names.push(self.parse_name()?);
+            }
+            self.expect(&TokenKind::RightParen)?;
+            columns = Some(names);
+        }
+        self.expect_keyword("AS")?;
+        return Ok(CreateView {
+            name: name,
+            if_not_exists: if_not_exists,
+            columns: columns,
+            select: self.parse_select()?,
+        });
+    }
+
+    fn parse_create_virtual_table(&mut self) -> Result<CreateVirtualTable> {
+        self.expect_keyword("CREATE")?;
+        self.expect_keyword("VIRTUAL")?;
+        self.expect_keyword("TABLE")?;
+        let mut if_not_exists = false;
+        if self.consume_keyword("IF") {
+            self.expect_keyword("NOT")?;
+            self.expect_keyword("EXISTS")?;
+            if_not_exists = true;
+        }
+        let name = self.parse_qualified_name()?;
+        self.expect_keyword("USING")?;
+        let module = self.parse_name()?;
+
+        // the arguments are kept as text, split at commas that are not
+        // nested in parentheses
+        let mut args = Vec::new();
+        if self.consume(&TokenKind::LeftParen) {
+            let mut depth = 0;
+            let mut start = self.peek().offset;
+            loop {
+                let token = self.advance();
+                match token.kind {
+                    TokenKind::LeftParen => depth += 1,
+                    TokenKind::RightParen if depth > 0 => depth -= 1,
+                    TokenKind::RightParen | TokenKind::Comma if depth == 0 => {
+                        let arg = self.sql[start..token.offset].trim();
+                        if !arg.is_empty() {
+                            args.push(arg.to_string());
+                        }
+                        if token.kind == TokenKind::RightParen {
+                            break;
+                        }
+                        start = self.peek().offset;
+                    }
+                    TokenKind::Eof => return Err(eyre!("incomplete input")),
+                    _ => (),
+                }
+            }
+        }
+        return Ok(CreateVirtualTable {
+            name: name,
+            if_not_exists: if_not_exists,
+            module: module,
+            args: args,
+        });
+    }
+
+    fn parse_create_table(&mut self) -> Result<CreateTable> {
+        self.expect_keyword("CREATE")?;
+        if !self.consume_keyword("TEMP") {
+            self.consume_keyword("TEMPORARY");
+        }
+        self.expect_keyword("TABLE")?;
+        let mut if_not_exists = false;
+        if self.consume_keyword("IF") {
+            self.expect_keyword("NOT")?;
+            self.expect_keyword("EXISTS")?;
+            if_not_exists = true;
+        }
+        let name = self.parse_qualified_name()?;
+
+        self.expect(&TokenKind::LeftParen)?;
+        let mut columns = Vec::new();
+        let mut constraints = Vec::new();
+        loop {
+            if self.at_table_constraint() {
+                constraints.push(self.parse_table_constraint()?);
+            } else if constraints.is_empty() {
+                columns.push(self.parse_column_def()?);
+            } else {
+                // column definitions may not follow table constraints
+                return Err(self.error());
+            }
+            if !self.consume(&TokenKind::Comma) {
+                break;
+            }
+        }
+        self.expect(&TokenKind::RightParen)?;
+
+        let mut without_rowid = false;
+        loop {
+            if self.consume_keyword("WITHOUT") {
+                if !self.consume_keyword("ROWID") {
+                    return Err(self.error());
+                }
+                without_rowid = true;
+            } else if !self.consume_keyword("STRICT") {
+                break;
+            }
+            if !self.consume(&TokenKind::Comma) {
+                break;
+            }
+        }
+
+        return Ok(CreateTable {
+            name: name,
+            if_not_exists: if_not_exists,
+            columns: columns,
+            constraints: constraints,
+            without_rowid: without_rowid,
+        });
+    }
+
+    fn at_table_constraint(&self) -> bool {
+        return ["CONSTRAINT", "PRIMARY", "UNIQUE", "CHECK", "FOREIGN"]
+            .iter()
+            .any(|kw| self.at_keyword(kw));
+    }
+
+    fn parse_column_def(&mut self) -> Result<ColumnDef> {
+        let name = self.parse_name()?;
+
+        let type_name = self.parse_type_name()?;
+
+        let mut constraints = Vec::new();
+        while let Some(constraint) = self.parse_column_constraint()? {
+            constraints.push(constraint);
+        }
+
+        return Ok(ColumnDef {
+            name: name,
+            type_name: type_name,
+            constraints: constraints,
+        });
+    }
+
+    /// Parses a type name (e.g., `VARCHAR(255)`), which is any number of
+    /// words, optionally followed by one or two sizes in parentheses.
+    fn parse_type_name(&mut self) -> Result<Option<String>> {
+        let mut type_words = Vec::new();
+        while let TokenKind::Identifier(word) = &self.peek().kind {
+            if COLUMN_CONSTRAINT_START.iter().any(|kw| self.at_keyword(kw)) {
+                break;
+            }
+            type_words.push(word.clone());
+            self.advance();
+        }
+        if type_words.is_empty() {
+            return Ok(None);
+        }
+        let mut type_name = type_words.join(" ");
+        if self.consume(&TokenKind::LeftParen) {
+            let mut sizes = vec![self.parse_signed_number()?];
+            if self.consume(&TokenKind::Comma) {
+                sizes.push(self.parse_signed_number()?);
+            }
+            self.expect(&TokenKind::RightParen)?;
+            type_name = format!("{}({})", type_name, sizes.join(","));
+        }
+        return Ok(Some(type_name));
+    }
+
+    fn parse_signed_number(&mut self) -> Result<String> {
+        let mut sign = "";
+        if self.consume(&TokenKind::Minus) {
+            sign = "-";
+        } else {
+            self.consume(&TokenKind::Plus);
+        }
+        let token = self.advance();
+        return match token.kind {
+            TokenKind::Integer(_) | TokenKind::Float(_) => {
+                Ok(format!("{}{}", sign, token.describe()))
+            }
+            _ => Err(eyre!("near \"{}\": syntax error", token.describe())),
+        };
+    }
+
+    /// Parses an `ON CONFLICT` clause, which is accepted but ignored.
+    fn parse_conflict_clause(&mut self) -> Result<()> {
+        if self.consume_keyword("ON") {
+            self.expect_keyword("CONFLICT")?;
+            let resolutions = ["ROLLBACK", "ABORT", "FAIL", "IGNORE", "REPLACE"];
+            if !resolutions.iter().any(|kw| self.consume_keyword(kw)) {
+                return Err(self.error());
+            }
+        }
+        return Ok(());
+    }
+
+    fn parse_column_constraint(&mut self) -> Result<Option<ColumnConstraint>> {
+        if self.consume_keyword("CONSTRAINT") {
+            self.parse_name()?;
+        }
+
+        if self.consume_keyword("PRIMARY") {
+            self.expect_keyword("KEY")?;
+            let descending = if self.consume_keyword("DESC") {
+                true
+            } else {
+                self.consume_keyword("ASC");
+                false
+            };
+            self.parse_conflict_clause()?;
+            let autoincrement = self.consume_keyword("AUTOINCREMENT");
+            return Ok(Some(ColumnConstraint::PrimaryKey {
+                descending: descending,
+                autoincrement: autoincrement,
+            }));
+        } else if self.at_keyword("NOT") && self.peek_nth(1).is_keyword("NULL") {
+            self.advance();
+            self.advance();
+            self.parse_conflict_clause()?;
+            return Ok(Some(ColumnConstraint::NotNull));
+        } else if self.consume_keyword("NULL") {
+            // a column explicitly marked as nullable; this is the default
+            self.parse_conflict_clause()?;
+            return self.parse_column_constraint();
+        } else if self.consume_keyword("UNIQUE") {
+            self.parse_conflict_clause()?;
+            return Ok(Some(ColumnConstraint::Unique));
+        } else if self.consume_keyword("CHECK") {
+            self.expect(&TokenKind::LeftParen)?;
+            let expr = self.parse_expr()?;
+            self.expect(&TokenKind::RightParen)?;
+            return Ok(Some(ColumnConstraint::Check(expr)));
+        } else if self.consume_keyword("DEFAULT") {
+            return Ok(Some(ColumnConstraint::Default(self.parse_default()?)));
+        } else if self.consume_keyword("COLLATE") {
+            return Ok(Some(ColumnConstraint::Collate(self.parse_name()?)));
+        } else if self.at_keyword("REFERENCES") {
+            return Ok(Some(ColumnConstraint::References(
+                self.parse_foreign_key_clause()?,
+            )));
+        } else if self.at_keyword("GENERATED") || self.at_keyword("AS") {
+            if self.consume_keyword("GENERATED") {
+                self.expect_keyword("ALWAYS")?;
+            }
+            self.expect_keyword("AS")?;
+            self.expect(&TokenKind::LeftParen)?;
+            let expr = self.parse_expr()?;
+            self.expect(&TokenKind::RightParen)?;
+            let stored = if self.consume_keyword("STORED") {
+                true
+            } else {
+                self.consume_keyword("VIRTUAL");
+                false
+            };
+            return Ok(Some(ColumnConstraint::Generated {
+                expr: expr,
+                stored: stored,
+            }));
+        }
+        return Ok(None);
+    }
+
+    fn parse_default(&mut self) -> Result<Expr> {
+        if self.consume(&TokenKind::LeftParen) {
+            let expr = self.parse_expr()?;
+            self.expect(&TokenKind::RightParen)?;
+            return Ok(expr);
+        }
+        if matches!(self.peek().kind, TokenKind::Minus | TokenKind::Plus) {
+            return self.parse_unary();
+        }
+        if let TokenKind::Identifier(word) = &self.peek().kind {
+            // e.g., DEFAULT CURRENT_TIMESTAMP, or a bare word, which
+            // SQLite treats as a string
+            if !self.at_keyword("NULL") && !self.at_keyword("TRUE") && !self.at_keyword("FALSE") {
+                let word = word.clone();
+                self.advance();
+                return Ok(Expr::Literal(Value::String(word)));
+            }
+        }
+        return self.parse_primary();
+    }
+
+    fn parse_foreign_key_clause(&mut self) -> Result<ForeignKey> {
+        self.expect_keyword("REFERENCES")?;
+        let table = self.parse_name()?;
+        let columns = if self.peek().kind == TokenKind::LeftParen {
+            self.parse_name_list()?
+        } else {
+            Vec::new()
+        };
+        loop {
+            if self.consume_keyword("ON") {
+                if !self.consume_keyword("DELETE") {
+                    self.expect_keyword("UPDATE")?;
+                }
+                if self.consume_keyword("SET") {
+                    if !self.consume_keyword("NULL") {
+                        self.expect_keyword("DEFAULT")?;
+                    }
+                } else if self.consume_keyword("NO") {
+                    self.expect_keyword("ACTION")?;
+                } else if !self.consume_keyword("CASCADE") {
+                    self.expect_keyword("RESTRICT")?;
+                }
+            } else if self.consume_keyword("MATCH") {
+                self.parse_name()?;
+            } else {
+                break;
+            }
+        }
+        if self.at_keyword("DEFERRABLE")
+            || (self.at_keyword("NOT") && self.peek_nth(1).is_keyword("DEFERRABLE"))
+        {
+            self.consume_keyword("NOT");
+            self.advance();
+            if self.consume_keyword("INITIALLY") && !self.consume_keyword("DEFERRED") {
+                self.expect_keyword("IMMEDIATE")?;
+            }
+        }
+        return Ok(ForeignKey {
+            table: table,
+            columns: columns,
+        });
+    }
+
+    fn parse_indexed_column(&mut self) -> Result<IndexedColumn> {
+        let name = self.parse_name()?;
+        let collation = if self.consume_keyword("COLLATE") {
+            Some(self.parse_name()?)
+        } else {
+            None
+        };
+        let descending = if self.consume_keyword("DESC") {
+            true
+        } else {
+            self.consume_keyword("ASC");
+            false
+        };
+        return Ok(IndexedColumn {
+            name: name,
+            collation: collation,
+            descending: descending,
+        });
+    }
+
+    fn parse_indexed_columns(&mut self) -> Result<Vec<IndexedColumn>> {
+        self.expect(&TokenKind::LeftParen)?;
+        let mut columns = vec![self.parse_indexed_column()?];
+        while self.consume(&TokenKind::Comma) {
+            columns.push(self.parse_indexed_column()?);
+        }
+        self.expect(&TokenKind::RightParen)?;
+        return Ok(columns);
+    }
+
+    fn parse_table_constraint(&mut self) -> Result<TableConstraint> {
+        if self.consume_keyword("CONSTRAINT") {
+            self.parse_name()?;
+        }
+        if self.consume_keyword("PRIMARY") {
+            self.expect_keyword("KEY")?;
+            let columns = self.parse_indexed_columns()?;
+            self.parse_conflict_clause()?;
+            return Ok(TableConstraint::PrimaryKey(columns));
+        } else if self.consume_keyword("UNIQUE") {
+            let columns = self.parse_indexed_columns()?;
+            self.parse_conflict_clause()?;
+            return Ok(TableConstraint::Unique(columns));
+        } else if self.consume_keyword("CHECK") {
+            self.expect(&TokenKind::LeftParen)?;
+            let expr = self.parse_expr()?;
+            self.expect(&TokenKind::RightParen)?;
+            return Ok(TableConstraint::Check(expr));
+        } else if self.consume_keyword("FOREIGN") {
+            self.expect_keyword("KEY")?;
+            let columns = self.parse_name_list()?;
+            let references = self.parse_foreign_key_clause()?;
+            return Ok(TableConstraint::ForeignKey {
+                columns: columns,
+                references: references,
+            });
+        }
+        return Err(self.error());
+    }
+
+    pub fn parse_expr(&mut self) -> Result<Expr> {
+        let depth = self.expr_depth;
+        self.deepen()?;
+        let expr = self.parse_or()?;
+        self.expr_depth = depth;
+        return Ok(expr);
+    }
+
+    /// Goes a level deeper into the parser's recursion, checking that
+    /// the expression being parsed is not nested too deeply. The caller
+    /// restores `expr_depth` once it has parsed the level.
+    fn deepen(&mut self) -> Result<()> {
+        self.expr_depth += 1;
+        if self.expr_depth > self.max_expr_depth {
+            return Err(self.too_deep());
+        }
+        return Ok(());
+    }
+
+    /// Records the height of an operator whose last operand was just
+    /// parsed, given the height of its other operands. This is checked as
+    /// each operator is parsed, so that a long chain like `a + b + c ...`
+    /// is stopped before it grows into a tree too tall to evaluate.
+    fn add_level(&mut self, other_height: usize) -> Result<()> {
+        self.height = std::cmp::max(self.height, other_height) + 1;
+        if self.height > self.max_expr_depth {
+            return Err(self.too_deep());
+        }
+        return Ok(());
+    }
+
+    fn too_deep(&self) -> eyre::Report {
+        return eyre!(
+            "Expression tree is too large (maximum depth {})",
+            self.max_expr_depth
+        );
+    }
+
+    fn binary(left: Expr, op: BinaryOp, right: Expr) -> Expr {
+        return Expr::Binary {
+            left: Box::new(left),
+            op: op,
+            right: Box::new(right),
+        };
+    }
+
+    fn parse_or(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_and()?;
+        while self.consume_keyword("OR") {
+            let height = self.height;
+            let right = self.parse_and()?;
+            self.add_level(height)?;
+            expr = Self::binary(expr, BinaryOp::Or, right);
+        }
+        return Ok(expr);
+    }
+
+    fn parse_and(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_not()?;
+        while self.consume_keyword("AND") {
+            let height = self.height;
+            let right = self.parse_not()?;
+            self.add_level(height)?;
+            expr = Self::binary(expr, BinaryOp::And, right);
+        }
+        return Ok(expr);
+    }
+
+    fn parse_not(&mut self) -> Result<Expr> {
+        if self.consume_keyword("NOT") {
+            let depth = self.expr_depth;
+            self.deepen()?;
+            let expr = self.parse_not()?;
+            self.expr_depth = depth;
+            self.add_level(0)?;
+            return Ok(Expr::Unary {
+                op: UnaryOp::Not,
+                expr: Box::new(expr),
+            });
+        }
+        return self.parse_equality();
+    }
+
+    fn parse_equality(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_comparison()?;
+        loop {
+            let token = self.peek().clone();
+            if token.kind == TokenKind::Eq || token.kind == TokenKind::NotEq {
+                self.advance();
+                let op = if token.kind == TokenKind::Eq {
+                    BinaryOp::Eq
+                } else {
+                    BinaryOp::NotEq
+                };
+                let height = self.height;
+                let right = self.parse_comparison()?;
+                self.add_level(height)?;
+                expr = Self::binary(expr, op, right);
+            } else if token.is_keyword("IS") {
+                self.advance();
+                let negated = self.consume_keyword("NOT");
+                if self.consume_keyword("NULL") {
+                    self.add_level(0)?;
+                    expr = Expr::IsNull {
+                        expr: Box::new(expr),
+                        negated: negated,
+                    };
+                } else {
+                    let op = if negated {
+                        BinaryOp::IsNot
+                    } else {
+                        BinaryOp::Is
+                    };
+                    let height = self.height;
+                    let right = self.parse_comparison()?;
+                    self.add_level(height)?;
+                    expr = Self::binary(expr, op, right);
+                }
+            } else if token.is_keyword("ISNULL") || token.is_keyword("NOTNULL") {
+                self.advance();
+                self.add_level(0)?;
+                expr = Expr::IsNull {
+                    expr: Box::new(expr),
+                    negated: token.is_keyword("NOTNULL"),
+                };
+            } else if token.is_keyword("NOT") && self.peek_nth(1).is_keyword("NULL") {
+                self.advance();
+                self.advance();
+                self.add_level(0)?;
+                expr = Expr::IsNull {
+                    expr: Box::new(expr),
+                    negated: true,
+                };
+            } else if let Some(negated) = self.peek_negatable("BETWEEN") {
+                if negated {
+                    self.advance();
+                }
+                self.advance();
+                let mut height = self.height;
+                let low = self.parse_comparison()?;
+                height = std::cmp::max(height, self.height);
+                self.expect_keyword("AND")?;
+                let high = self.parse_comparison()?;
+                self.add_level(height)?;
+                expr = Expr::Between {
+                    expr: Box::new(expr),
+                    low: Box::new(low),
+                    high: Box::new(high),
+                    negated: negated,
+                };
+            } else if let Some(negated) = self.peek_negatable("IN") {
+                if negated {
+                    self.advance();
+                }
+                self.advance();
+                self.expect(&TokenKind::LeftParen)?;
+                let mut height = self.height;
+                let mut list = Vec::new();
+                let mut subquery = None;
+                if self.at_keyword("SELECT") {
+                    subquery = Some(Box::new(self.parse_select()?));
+                } else if self.peek().kind != TokenKind::RightParen {
+                    list.push(self.parse_expr()?);
+                    height = std::cmp::max(height, self.height);
+                    while self.consume(&TokenKind::Comma) {
+                        list.push(self.parse_expr()?);
+                        height = std::cmp::max(height, self.height);
+                    }
+                }
+                self.expect(&TokenKind::RightParen)?;
+                self.height = 0;
+                self.add_level(height)?;
+                expr = Expr::In {
+                    expr: Box::new(expr),
+                    list: list,
+                    subquery: subquery,
+                    negated: negated,
+                };
+            } else if let Some((op, negated)) = self.peek_like_op() {
+                if negated {
+                    self.advance();
+                }
+                self.advance();
+                let mut height = self.height;
+                let pattern = self.parse_comparison()?;
+                let escape = if self.consume_keyword("ESCAPE") {
+                    height = std::cmp::max(height, self.height);
+                    Some(Box::new(self.parse_comparison()?))
+                } else {
+                    None
+                };
+                self.add_level(height)?;
+                expr = Expr::Like {
+                    expr: Box::new(expr),
+                    pattern: Box::new(pattern),
+                    escape: escape,
+                    op: op,
+                    negated: negated,
+                };
+            } else {
+                break;
+            }
+        }
+        return Ok(expr);
+    }
+
+    /// Checks for a keyword that may be preceded by `NOT` (e.g., `NOT IN`)
+    /// without consuming it, returning whether it is negated.
+    fn peek_negatable(&self, keyword: &str) -> Option<bool> {
+        if self.peek().is_keyword(keyword) {
+            return Some(false);
+        } else if self.at_keyword("NOT") && self.peek_nth(1).is_keyword(keyword) {
+            return Some(true);
+        }
+        return None;
+    }
+
+    /// Checks for `[NOT] LIKE` or `[NOT] GLOB` without consuming it.
+    fn peek_like_op(&self) -> Option<(LikeOp, bool)> {
+        let (token, negated) = if self.at_keyword("NOT") {
+            (self.peek_nth(1), true)
+        } else {
+            (self.peek(), false)
+        };
+        if token.is_keyword("LIKE") {
+            return Some((LikeOp::Like, negated));
+        } else if token.is_keyword("GLOB") {
+            return Some((LikeOp::Glob, negated));
+        }
+        return None;
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_bitwise()?;
+        loop {
+            let op = match self.peek().kind {
+                TokenKind::Lt => BinaryOp::Lt,
+                TokenKind::LtEq => BinaryOp::LtEq,
+                TokenKind::Gt => BinaryOp::Gt,
+                TokenKind::GtEq => BinaryOp::GtEq,
+                _ => break,
+            };
+            self.advance();
+            let height = self.height;
+            let right = self.parse_bitwise()?;
+            self.add_level(height)?;
+            expr = Self::binary(expr, op, right);
+        }
+        return Ok(expr);
+    }
+
+    fn parse_bitwise(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_additive()?;
+        loop {
+            let op = match self.peek().kind {
+                TokenKind::BitAnd => BinaryOp::BitAnd,
+                TokenKind::BitOr => BinaryOp::BitOr,
+                TokenKind::ShiftLeft => BinaryOp::ShiftLeft,
+                TokenKind::ShiftRight => BinaryOp::ShiftRight,
+                _ => break,
+            };
+            self.advance();
+            let height = self.height;
+            let right = self.parse_additive()?;
+            self.add_level(height)?;
+            expr = Self::binary(expr, op, right);
+        }
+        return Ok(expr);
+    }
+
+    fn parse_additive(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_multiplicative()?;
+        loop {
+            let op = match self.peek().kind {
+                TokenKind::Plus => BinaryOp::Add,
+                TokenKind::Minus => BinaryOp::Subtract,
+                _ => break,
+            };
+            self.advance();
+            let height = self.height;
+            let right = self.parse_multiplicative()?;
+            self.add_level(height)?;
+            expr = Self::binary(expr, op, right);
+        }
+        return Ok(expr);
+    }
+
+    fn parse_multiplicative(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_concat()?;
+        loop {
+            let op = match self.peek().kind {
+                TokenKind::Star => BinaryOp::Multiply,
+                TokenKind::Slash => BinaryOp::Divide,
+                TokenKind::Percent => BinaryOp::Remainder,
+                _ => break,
+            };
+            self.advance();
+            let height = self.height;
+            let right = self.parse_concat()?;
+            self.add_level(height)?;
+            expr = Self::binary(expr, op, right);
+        }
+        return Ok(expr);
+    }
+
+    fn parse_concat(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_unary()?;
+        while self.consume(&TokenKind::Concat) {
+            let height = self.height;
+            let right = self.parse_unary()?;
+            self.add_level(height)?;
+            expr = Self::binary(expr, BinaryOp::Concat, right);
+        }
+        return Ok(expr);
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr> {
+        let op = match self.peek().kind {
+            TokenKind::Minus => UnaryOp::Negate,
+            TokenKind::Plus => UnaryOp::Plus,
+            TokenKind::BitNot => UnaryOp::BitNot,
+            _ => return self.parse_primary(),
+        };
+        self.advance();
+        // the smallest integer is written as the negation of a number
+        // that is too large to be one
+        let token = self.peek();
+        if op == UnaryOp::Negate
+            && matches!(token.kind, TokenKind::Float(_))
+            && self.sql[token.offset..token.end] == *"9223372036854775808"
+        {
+            self.advance();
+            self.height = 1;
+            return Ok(Expr::Literal(Value::Int64(i64::MIN)));
+        }
+        let depth = self.expr_depth;
+        self.deepen()?;
+        let expr = self.parse_unary()?;
+        self.expr_depth = depth;
+        self.add_level(0)?;
+        return Ok(Expr::Unary {
+            op: op,
+            expr: Box::new(expr),
+        });
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr> {
+        // a single value or column; expressions with operands update it
+        // once they are parsed
+        self.height = 1;
+        let token = self.peek().clone();
+        match token.kind {
+            TokenKind::Integer(i) => {
+                self.advance();
+                return Ok(Expr::Literal(Value::Int64(i)));
+            }
+            TokenKind::Float(f) => {
+                self.advance();
+                return Ok(Expr::Literal(Value::Float(f)));
+            }
+            TokenKind::String(s) => {
+                self.advance();
+                return Ok(Expr::Literal(Value::String(s)));
+            }
+            TokenKind::Blob(b) => {
+                self.advance();
+                return Ok(Expr::Literal(Value::Blob(b)));
+            }
+            TokenKind::Variable(name) => {
+                self.advance();
+                return Ok(Expr::Parameter(self.parameter_index(name)?));
+            }
+            TokenKind::LeftParen => {
+                self.advance();
+                let expr = if self.at_keyword("SELECT") {
+                    let select = self.parse_select()?;
+                    self.height = 1;
+                    Expr::Subquery(Box::new(select))
+                } else {
+                    self.parse_expr()?
+                };
+                self.expect(&TokenKind::RightParen)?;
+                return Ok(expr);
+            }
+            TokenKind::Identifier(_) if token.is_keyword("EXISTS") => {
+                self.advance();
+                self.expect(&TokenKind::LeftParen)?;
+                let select = self.parse_select()?;
+                self.expect(&TokenKind::RightParen)?;
+                self.height = 1;
+                return Ok(Expr::Exists(Box::new(select)));
+            }
+            TokenKind::Identifier(_) if token.is_keyword("NULL") => {
+                self.advance();
+                return Ok(Expr::Literal(Value::Null));
+            }
+            TokenKind::Identifier(_) if token.is_keyword("TRUE") => {
+                self.advance();
+                return Ok(Expr::Literal(Value::Integer1));
+            }
+            TokenKind::Identifier(_) if token.is_keyword("FALSE") => {
+                self.advance();
+                return Ok(Expr::Literal(Value::Integer0));
+            }
+            TokenKind::Identifier(_)
+                if token.is_keyword("CAST") && self.peek_nth(1).kind == TokenKind::LeftParen =>
+            {
+                self.advance();
+                self.advance();
+                let expr = self.parse_expr()?;
+                self.expect_keyword("AS")?;
+                let type_name = match self.parse_type_name()? {
+                    Some(type_name) => type_name,
+                    None => return Err(self.error()),
+                };
+                self.expect(&TokenKind::RightParen)?;
+                self.add_level(0)?;
+                return Ok(Expr::Cast {
+                    expr: Box::new(expr),
+                    type_name: type_name,
+                });
+            }
+            TokenKind::Identifier(_) | TokenKind::QuotedIdentifier(_) => {
+                let is_function = matches!(token.kind, TokenKind::Identifier(_))
+                    && self.peek_nth(1).kind == TokenKind::LeftParen;
+                if is_function {
+                    return self.parse_function();
+                }
+                let mut name = self.parse_name()?;
+                if self.consume(&TokenKind::Dot) {
+                    let mut column = self.parse_name()?;
+                    if self.consume(&TokenKind::Dot) {
+                        // the table is qualified by its database
+                        check_schema(&name)?;
+                        name = column;
+                        column = self.parse_name()?;
+                    }
+                    return Ok(Expr::Column {
+                        table: Some(name),
+                        name: column,
+                    });
+                }
+                return Ok(Expr::Column {
+                    table: None,
+                    name: name,
+                });
+            }
+            _ => return Err(self.error()),
+        }
+    }
+
+    /// Assigns an index to a parameter, following SQLite's rules: `?NNN`
+    /// has index NNN, a bare `?` takes the index after the largest one
+    /// assigned so far, and a named parameter reuses the index of an
+    /// earlier parameter with the same name.
+    fn parameter_index(&mut self, name: String) -> Result<usize> {
+        if name == "?" {
+            self.parameters.push(None);
+            return Ok(self.parameters.len());
+        }
+        let index = if let Some(digits) = name.strip_prefix('?') {
+            match digits.parse::<usize>() {
+                Ok(n) if (1..=MAX_PARAMETER_INDEX).contains(&n) => n,
+                _ => {
+                    return Err(eyre!(
+                        "variable number must be between ?1 and ?{}",
+                        MAX_PARAMETER_INDEX
+                    ))
+                }
+            }
+        } else {
+            let existing = self
+                .parameters
+                .iter()
+                .position(|p| p.as_deref() == Some(name.as_str()));
+            match existing {
+                Some(i) => return Ok(i + 1),
+                None => self.parameters.len() + 1,
+            }
+        };
+        if index > self.parameters.len() {
+            self.parameters.resize(index, None);
+        }
+        if self.parameters[index - 1].is_none() {
+            self.parameters[index - 1] = Some(name);
+        }
+        return Ok(index);
+    }
+
+    fn parse_function(&mut self) -> Result<Expr> {
+        let name = match self.advance().kind {
+            TokenKind::Identifier(s) => s,
+            _ => unreachable!(),
+        };
+        self.expect(&TokenKind::LeftParen)?;
+        let mut args = Vec::new();
+        let mut distinct = false;
+        let mut star = false;
+        let mut height = 0;
+        if self.consume(&TokenKind::Star) {
+            star = true;
+        } else if self.peek().kind != TokenKind::RightParen {
+            distinct = self.consume_keyword("DISTINCT");
+            args.push(self.parse_expr()?);
+            height = self.height;
+            while self.consume(&TokenKind::Comma) {
+                args.push(self.parse_expr()?);
+                height = std::cmp::max(height, self.height);
+            }
+        }
+        self.expect(&TokenKind::RightParen)?;
+        self.height = 0;
+        self.add_level(height)?;
+        return Ok(Expr::Function {
+            name: name,
+            args: args,
+            distinct: distinct,
+            star: star,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn col(name: &str) -> Expr {
+        return Expr::Column {
+            table: None,
+            name: name.to_string(),
+        };
+    }
+
+    #[test]
+    fn parse_select() {
+        let stmt =
+            parse("select a, t.b as bee, * from tbl t where a > 1 and not b is null;").unwrap();
+        let select = match stmt {
+            Stmt::Select(s) => s,
+            _ => panic!("Expected a SELECT"),
+        };
+        assert_eq!(select.columns.len(), 3);
+        assert_eq!(
+            select.columns[1],
+            ResultColumn::Expr {
+                expr: Expr::Column {
+                    table: Some("t".to_string()),
+                    name: "b".to_string()
+                },
+                alias: Some("bee".to_string()),
+                text: "t.b".to_string(),
+            }
+        );
+        assert_eq!(select.columns[2], ResultColumn::Star);
+        assert_eq!(
+            select.from,
+            Some(TableRef {
+                name: "tbl".to_string(),
+                alias: Some("t".to_string()),
+                subquery: None,
+                sample: None,
+            })
+        );
+        assert_eq!(
+            select.where_clause,
+            Some(Parser::binary(
+                Parser::binary(col("a"), BinaryOp::Gt, Expr::Literal(Value::Int64(1))),
+                BinaryOp::And,
+                Expr::Unary {
+                    op: UnaryOp::Not,
+                    expr: Box::new(Expr::IsNull {
+                        expr: Box::new(col("b")),
+                        negated: false
+                    }),
+                },
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_joins() {
+        let stmt = parse("select * from a join b on a.x = b.y, c left outer join d on 1").unwrap();
+        let select = match stmt {
+            Stmt::Select(s) => s,
+            _ => panic!("Expected a SELECT"),
+        };
+        let kinds: Vec<JoinKind> = select.joins.iter().map(|j| j.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![JoinKind::Inner, JoinKind::Inner, JoinKind::Left]
+        );
+        assert_eq!(select.joins[0].table.name, "b");
+        assert!(select.joins[0].on.is_some());
+        assert_eq!(select.joins[1].on, None);
+        assert!(parse("select * from a join b on").is_err());
+    }
+
+    #[test]
+    fn parse_compound_select() {
+        let stmt = parse("select a from t union all select b from u where b > 1 except select 1 order by 1 limit 2").unwrap();
+        let select = match stmt {
+            Stmt::Select(s) => s,
+            _ => panic!("Expected a SELECT"),
+        };
+        let operators: Vec<CompoundOperator> =
+            select.compound.iter().map(|part| part.operator).collect();
+        assert_eq!(
+            operators,
+            vec![CompoundOperator::UnionAll, CompoundOperator::Except]
+        );
+        assert!(select.compound[0].select.where_clause.is_some());
+        // ORDER BY and LIMIT belong to the whole compound
+        assert_eq!(select.order_by.len(), 1);
+        assert!(select.compound[1].select.order_by.is_empty());
+        assert!(select.limit.is_some());
+        assert!(parse("select a from t order by a union select b from u").is_err());
+    }
+
+    #[test]
+    fn parameter_indexes() {
+        let (_, params) =
+            parse_with_parameters("select ?, :a, ?5, ?, :a, @b where x = ?2").unwrap();
+        assert_eq!(
+            params,
+            vec![
+                None,
+                Some(":a".to_string()),
+                None,
+                None,
+                Some("?5".to_string()),
+                None,
+                Some("@b".to_string()),
+            ]
+        );
+        assert!(parse("select ?0").is_err());
+        assert!(parse("select ?32767").is_err());
+    }
+
+    #[test]
+    fn operator_precedence() {
+        let expr = parse_expr("1 + 2 * 3 = 7 OR x || 'a' LIKE 'b%'").unwrap();
+        let expected = Parser::binary(
+            Parser::binary(
+                Parser::binary(
+                    Expr::Literal(Value::Int64(1)),
+                    BinaryOp::Add,
+                    Parser::binary(
+                        Expr::Literal(Value::Int64(2)),
+                        BinaryOp::Multiply,
+                        Expr::Literal(Value::Int64(3)),
+                    ),
+                ),
+                BinaryOp::Eq,
+                Expr::Literal(Value::Int64(7)),
+            ),
+            BinaryOp::Or,
+            Expr::Like {
+                expr: Box::new(Parser::binary(
+                    col("x"),
+                    BinaryOp::Concat,
+                    Expr::Literal(Value::String("a".to_string())),
+                )),
+                pattern: Box::new(Expr::Literal(Value::String("b%".to_string()))),
+                escape: None,
+                op: LikeOp::Like,
+                negated: false,
+            },
+        );
+        assert_eq!(expr, expected);
+    }
+
+    #[test]
+    fn parse_between_and_in() {
+        let int = |i| Expr::Literal(Value::Int64(i));
+        // the AND of BETWEEN binds tighter than a logical AND
+        let expr = parse_expr("a NOT BETWEEN 1 AND 2 AND b IN (3, 4)").unwrap();
+        let expected = Parser::binary(
+            Expr::Between {
+                expr: Box::new(col("a")),
+                low: Box::new(int(1)),
+                high: Box::new(int(2)),
+                negated: true,
+            },
+            BinaryOp::And,
+            Expr::In {
+                expr: Box::new(col("b")),
+                list: vec![int(3), int(4)],
+                subquery: None,
+                negated: false,
+            },
+        );
+        assert_eq!(expr, expected);
+        match parse_expr("a IN (SELECT b FROM t)").unwrap() {
+            Expr::In { list, subquery, .. } => assert!(list.is_empty() && subquery.is_some()),
+            expr => panic!("unexpected expression: {:?}", expr),
+        }
+        assert!(parse_expr("a IN ()").is_ok());
+        assert!(parse_expr("a BETWEEN 1").is_err());
+    }
+
+    #[test]
+    fn parse_cast() {
+        assert_eq!(
+            parse_expr("CAST(a AS DECIMAL(10, 2))").unwrap(),
+            Expr::Cast {
+                expr: Box::new(col("a")),
+                type_name: "DECIMAL(10,2)".to_string(),
+            }
+        );
+        assert!(parse_expr("CAST(a AS)").is_err());
+    }
+
+    #[test]
+    fn parse_insert() {
+        let stmt = parse("INSERT INTO users (id, name) VALUES (1, 'bob')").unwrap();
+        assert_eq!(
+            stmt,
+            Stmt::Insert(Insert {
+                table: "users".to_string(),
+                columns: Some(vec!["id".to_string(), "name".to_string()]),
+                source: InsertSource::Values(vec![vec![
+                    Expr::Literal(Value::Int64(1)),
+                    Expr::Literal(Value::String("bob".to_string()))
+                ]]),
+            })
+        );
+        assert!(parse("INSERT INTO users VALUES (1,").is_err());
+        match parse("INSERT INTO users VALUES (1), (2), (3)").unwrap() {
+            Stmt::Insert(Insert {
+                source: InsertSource::Values(rows),
+                ..
+            }) => assert_eq!(rows.len(), 3),
+            stmt => panic!("unexpected statement: {:?}", stmt),
+        }
+        assert!(parse("INSERT INTO users VALUES (1), (2, 3)").is_err());
+        match parse("INSERT INTO users (id) SELECT id FROM old_users").unwrap() {
+            Stmt::Insert(Insert {
+                source: InsertSource::Select(_),
+                ..
+            }) => (),
+            stmt => panic!("unexpected statement: {:?}", stmt),
+        }
+    }
+
+    #[test]
+    fn parse_delete() {
+        let stmt = parse("DELETE FROM users WHERE id = 1").unwrap();
+        assert_eq!(
+            stmt,
+            Stmt::Delete(Delete {
+                table: "users".to_string(),
+                where_clause: Some(parse_expr("id = 1").unwrap()),
+            })
+        );
+        assert!(parse("DELETE users").is_err());
+    }
+
+    #[test]
+    fn parse_qualified_names() {
+        let stmt = parse("DELETE FROM main.[my table] WHERE MAIN.`my table`.id = 1").unwrap();
+        assert_eq!(
+            stmt,
+            Stmt::Delete(Delete {
+                table: "my table".to_string(),
+                where_clause: Some(parse_expr("\"my table\".id = 1").unwrap()),
+            })
+        );
+        assert!(parse("SELECT * FROM temp.t").is_err());
+        assert!(parse("CREATE TABLE other.t(a)").is_err());
+
+        assert_eq!(quote_name("users"), "users");
+        assert_eq!(quote_name("key"), "\"key\"");
+        assert_eq!(quote_name("a\"b"), "\"a\"\"b\"");
+        assert_eq!(quote_name("café"), "\"café\"");
+    }
+
+    #[test]
+    fn split_script() {
+        let sql = "CREATE TABLE \"a;b\"(x); -- a comment;\nINSERT INTO t VALUES(';');;\n";
+        assert_eq!(
+            split_statements(sql).unwrap(),
+            [
+                "CREATE TABLE \"a;b\"(x)",
+                "-- a comment;\nINSERT INTO t VALUES(';')"
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_subqueries() {
+        let stmt = parse("SELECT * FROM (SELECT a FROM t) AS s WHERE b = (SELECT 1)").unwrap();
+        let select = match stmt {
+            Stmt::Select(select) => select,
+            _ => panic!("expected a SELECT"),
+        };
+        let from = select.from.unwrap();
+        assert_eq!(from.name, "");
+        assert_eq!(from.alias.as_deref(), Some("s"));
+        assert_eq!(from.subquery.unwrap().from.unwrap().name, "t".to_string());
+        match select.where_clause.unwrap() {
+            Expr::Binary { right, .. } => assert!(matches!(*right, Expr::Subquery(_))),
+            expr => panic!("unexpected expression: {:?}", expr),
+        }
+        // a parenthesized expression is not a subquery
+        assert_eq!(parse_expr("(a)").unwrap(), col("a"));
+    }
+
+    #[test]
+    fn parse_alter_table() {
+        let stmt = parse("ALTER TABLE t RENAME COLUMN a TO \"b c\"").unwrap();
+        assert_eq!(
+            stmt,
+            Stmt::AlterTable(AlterTable {
+                table: "t".to_string(),
+                action: AlterAction::RenameColumn {
+                    old: "a".to_string(),
+                    new: "b c".to_string(),
+                },
+            })
+        );
+        let stmt = parse("ALTER TABLE t DROP a").unwrap();
+        assert_eq!(
+            stmt,
+            Stmt::AlterTable(AlterTable {
+                table: "t".to_string(),
+                action: AlterAction::DropColumn("a".to_string()),
+            })
+        );
+        assert!(parse("ALTER TABLE t RENAME a").is_err());
+        assert!(parse("ALTER TABLE t ADD COLUMN b").is_err());
+    }
+
+    #[test]
+    fn parse_create_virtual_table() {
+        let stmt =
+            parse("CREATE VIRTUAL TABLE demo USING rtree(id, minX, maxX, +aux(x, y))").unwrap();
+        assert_eq!(
+            stmt,
+            Stmt::CreateVirtualTable(CreateVirtualTable {
+                name: "demo".to_string(),
+                if_not_exists: false,
+                module: "rtree".to_string(),
+                args: vec![
+                    "id".to_string(),
+                    "minX".to_string(),
+                    "maxX".to_string(),
+                    "+aux(x, y)".to_string()
+                ],
+            })
+        );
+        assert!(parse("CREATE VIRTUAL TABLE demo USING rtree(id, minX").is_err());
+    }
+
+    #[test]
+    fn parse_create_view() {
+        let stmt = parse("CREATE TEMP VIEW IF NOT EXISTS v(x, y) AS SELECT a, b FROM t").unwrap();
+        let view = match stmt {
+            Stmt::CreateView(view) => view,
+            _ => panic!("Expected a CREATE VIEW"),
+        };
+        assert_eq!(view.name, "v");
+        assert!(view.if_not_exists);
+        assert_eq!(view.columns, Some(vec!["x".to_string(), "y".to_string()]));
+        assert_eq!(view.select.columns.len(), 2);
+        assert!(parse("CREATE VIEW v SELECT 1").is_err());
+    }
+
+    #[test]
+    fn parse_create_table() {
+        let sql = "CREATE TABLE IF NOT EXISTS \"podcasts\" (
+            id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+            title VARCHAR(255) NOT NULL DEFAULT 'untitled' COLLATE NOCASE,
+            url TEXT UNIQUE ON CONFLICT REPLACE,
+            owner_id INTEGER REFERENCES owners(id) ON DELETE CASCADE,
+            score,
+            CONSTRAINT pk UNIQUE (title, url DESC),
+            FOREIGN KEY (owner_id) REFERENCES owners (id)
+        ) WITHOUT ROWID";
+        let table = match parse(sql).unwrap() {
+            Stmt::CreateTable(t) => t,
+            _ => panic!("Expected CREATE TABLE"),
+        };
+        assert_eq!(table.name, "podcasts");
+        assert!(table.if_not_exists);
+        assert!(table.without_rowid);
+        let names: Vec<&str> = table.columns.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["id", "title", "url", "owner_id", "score"]);
+        assert_eq!(
+            table.columns[0].constraints,
+            vec![
+                ColumnConstraint::PrimaryKey {
+                    descending: false,
+                    autoincrement: true
+                },
+                ColumnConstraint::NotNull
+            ]
+        );
+        assert_eq!(table.columns[1].type_name, Some("VARCHAR(255)".to_string()));
+        assert_eq!(table.columns[4].type_name, None);
+        assert_eq!(table.constraints.len(), 2);
+    }
+}
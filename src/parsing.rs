@@ -17,7 +17,7 @@ pub fn be_i32(value: &[u8]) -> Result<i32> {
     return Ok(i32::from_be_bytes(value.try_into()?));
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Position {
     pub pos: usize,
 }
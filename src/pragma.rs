@@ -0,0 +1,326 @@
+use eyre::{eyre, Result};
+
+use crate::ast::Pragma;
+use crate::connection::Connection;
+use crate::datatypes::Value;
+use crate::integrity;
+use crate::pagefile::CacheSize;
+use crate::statement::QueryResult;
+use crate::vacuum::{self, AutoVacuum};
+use crate::TextEncoding;
+
+/// A built-in pragma: a setting of the database or the connection, read
+/// with `PRAGMA name` and changed with `PRAGMA name = value`, or a check
+/// run with `PRAGMA name` or `PRAGMA name(argument)`.
+pub struct BuiltinPragma {
+    pub name: &'static str,
+    /// Reads the setting, or runs the check with its argument, if any,
+    /// giving a row for each value.
+    get: fn(&mut Connection, Option<&Value>) -> Result<Vec<Value>>,
+    /// Changes the setting. `None` if it cannot be changed, in which case
+    /// a value given to the pragma is its argument.
+    set: Option<fn(&mut Connection, &Value) -> Result<()>>,
+    /// Whether changing the setting changes the database file, so that the
+    /// change is sent to followers (see `ReplicationLog`).
+    pub persistent: bool,
+}
+
+const BUILTINS: &[BuiltinPragma] = &[
+    BuiltinPragma {
+        name: "application_id",
+        get: |conn, _| Ok(vec![Value::Int64(conn.db_options.app_id as i32 as i64)]),
+        set: Some(|conn, value| {
+            let app_id = int_value(value) as u32;
+            return conn.write_statement(|conn| {
+                conn.db_options.app_id = app_id;
+                return Ok(());
+            });
+        }),
+        persistent: true,
+    },
+    BuiltinPragma {
+        // 0 or none, 1 or full, and 2 or incremental. As in SQLite,
+        // auto-vacuum can only be turned on or off before anything is
+        // written to the database; otherwise the mode is taken on at the
+        // next VACUUM
+        name: "auto_vacuum",
+        get: |conn, _| {
+            let auto_vacuum = conn.pager.read().is_auto_vacuum();
+            let mode = AutoVacuum::from_header(auto_vacuum, &conn.db_options);
+            return Ok(vec![Value::Int64(mode.to_pragma())]);
+        },
+        set: Some(|conn, value| {
+            if conn.is_read_only() {
+                return Err(eyre!("attempt to write a readonly database"));
+            }
+            let mode = AutoVacuum::from_pragma(value);
+            let auto_vacuum = conn.pager.read().is_auto_vacuum();
+            if mode == AutoVacuum::from_header(auto_vacuum, &conn.db_options) {
+                conn.next_auto_vacuum = None;
+                return Ok(());
+            }
+            if !conn
+                .pager
+                .write()
+                .set_auto_vacuum(mode != AutoVacuum::None)?
+            {
+                conn.next_auto_vacuum = Some(mode);
+                return Ok(());
+            }
+            conn.next_auto_vacuum = None;
+            return conn.write_statement(|conn| {
+                mode.set_header(&mut conn.db_options);
+                return Ok(());
+            });
+        }),
+        persistent: false,
+    },
+    BuiltinPragma {
+        name: "cache_size",
+        get: |conn, _| {
+            let size = conn.pager.read().cache_size_setting();
+            return Ok(vec![Value::Int64(size.to_pragma())]);
+        },
+        set: Some(|conn, value| {
+            // as in SQLite, a negative size is in KiB rather than pages
+            let size = CacheSize::from_pragma(int_value(value));
+            conn.pager.write().set_cache_size(size);
+            return Ok(());
+        }),
+        persistent: false,
+    },
+    BuiltinPragma {
+        // the cache size suggested in the header, which is used when the
+        // database is opened unless the configuration gives one
+        name: "default_cache_size",
+        get: |conn, _| Ok(vec![Value::Int64(conn.db_options.cache_size as i64)]),
+        set: Some(|conn, value| {
+            let size = int_value(value) as i32;
+            conn.write_statement(|conn| {
+                conn.db_options.cache_size = size;
+                return Ok(());
+            })?;
+            let size = CacheSize::from_pragma(size as i64);
+            conn.pager.write().set_cache_size(size);
+            return Ok(());
+        }),
+        persistent: true,
+    },
+    BuiltinPragma {
+        name: "encoding",
+        get: |conn, _| {
+            let encoding = match conn.db_options.encoding {
+                TextEncoding::Utf8 => "UTF-8",
+                TextEncoding::Utf16le => "UTF-16le",
+                TextEncoding::Utf16be => "UTF-16be",
+            };
+            return Ok(vec![Value::String(encoding.to_string())]);
+        },
+        set: None,
+        persistent: false,
+    },
+    BuiltinPragma {
+        name: "freelist_count",
+        get: |conn, _| Ok(vec![Value::Int64(conn.db_options.num_freelist as i64)]),
+        set: None,
+        persistent: false,
+    },
+    BuiltinPragma {
+        // frees up to the given number of pages, or all free pages if
+        // there is no number or it is not positive
+        name: "incremental_vacuum",
+        get: |conn, max_pages| {
+            let max_pages = max_pages.map(int_value).filter(|n| *n > 0);
+            vacuum::incremental_vacuum(conn, max_pages.map(|n| n as usize))?;
+            return Ok(Vec::new());
+        },
+        set: None,
+        persistent: false,
+    },
+    BuiltinPragma {
+        name: "integrity_check",
+        get: |conn, max_errors| {
+            let mut problems = integrity::check_structure(conn)?;
+            if problems.is_empty() {
+                problems = integrity::check_indexes(conn)?;
+            }
+            return Ok(check_result(problems, max_errors));
+        },
+        set: None,
+        persistent: false,
+    },
+    BuiltinPragma {
+        name: "max_page_count",
+        get: |conn, _| Ok(vec![Value::Int64(conn.limits().max_page_count as i64)]),
+        set: Some(|conn, value| {
+            let mut limits = conn.limits();
+            limits.max_page_count = std::cmp::max(int_value(value), 0) as usize;
+            conn.set_limits(limits);
+            return Ok(());
+        }),
+        persistent: false,
+    },
+    BuiltinPragma {
+        name: "page_count",
+        get: |conn, _| Ok(vec![Value::Int64(conn.pager.read().num_pages as i64)]),
+        set: None,
+        persistent: false,
+    },
+    BuiltinPragma {
+        name: "page_size",
+        get: |conn, _| Ok(vec![Value::Int64(conn.db_options.page_size as i64)]),
+        set: Some(|conn, value| {
+            // as in SQLite, a size that is not allowed is ignored
+            let page_size = int_value(value);
+            let valid = (512..=65536).contains(&page_size) && page_size.count_ones() == 1;
+            if valid && conn.pager.write().set_page_size(page_size as usize)? {
+                conn.db_options.page_size = page_size as usize;
+            }
+            return Ok(());
+        }),
+        persistent: false,
+    },
+    BuiltinPragma {
+        name: "quick_check",
+        get: |conn, max_errors| {
+            let problems = integrity::check_structure(conn)?;
+            return Ok(check_result(problems, max_errors));
+        },
+        set: None,
+        persistent: false,
+    },
+    BuiltinPragma {
+        name: "schema_version",
+        get: |conn, _| Ok(vec![Value::Int64(conn.db_options.schema_cookie as i64)]),
+        set: None,
+        persistent: false,
+    },
+    BuiltinPragma {
+        name: "user_version",
+        get: |conn, _| {
+            Ok(vec![Value::Int64(
+                conn.db_options.user_version as i32 as i64,
+            )])
+        },
+        set: Some(|conn, value| {
+            let user_version = int_value(value) as u32;
+            return conn.write_statement(|conn| {
+                conn.db_options.user_version = user_version;
+                return Ok(());
+            });
+        }),
+        persistent: true,
+    },
+];
+
+/// Finds the built-in pragma with the given name, if any.
+pub fn lookup(name: &str) -> Option<&'static BuiltinPragma> {
+    return BUILTINS
+        .iter()
+        .find(|pragma| pragma.name.eq_ignore_ascii_case(name));
+}
+
+/// Runs a PRAGMA statement. As in SQLite, an unknown pragma does nothing,
+/// and changing a setting gives no rows.
+pub fn execute(conn: &mut Connection, pragma: &Pragma) -> Result<QueryResult> {
+    if let Some(schema) = &pragma.schema {
+        if !schema.eq_ignore_ascii_case("main") {
+            return Err(eyre!("unknown database {}", schema));
+        }
+    }
+    let builtin = match lookup(&pragma.name) {
+        Some(builtin) => builtin,
+        None => return Ok(QueryResult::default()),
+    };
+    if let (Some(set), Some(value)) = (builtin.set, &pragma.value) {
+        set(conn, value)?;
+        return Ok(QueryResult::default());
+    }
+    let values = (builtin.get)(conn, pragma.value.as_ref())?;
+    return Ok(QueryResult {
+        columns: vec![builtin.name.to_string()],
+        rows: values.into_iter().map(|value| vec![value]).collect(),
+    });
+}
+
+/// The integer a pragma's value stands for. As in SQLite, a value that is
+/// not a number is 0, except for the words used for on and off.
+fn int_value(value: &Value) -> i64 {
+    if let Some(i) = value.get_int_val() {
+        return i;
+    }
+    return match value {
+        Value::Float(f) => *f as i64,
+        Value::String(s) => match s.to_lowercase().as_str() {
+            "on" | "yes" | "true" => 1,
+            _ => s.trim().parse().unwrap_or(0),
+        },
+        _ => 0,
+    };
+}
+
+/// The rows of an integrity check: the problems found, up to the maximum
+/// given (100 by default), or "ok" if there are none.
+fn check_result(mut problems: Vec<String>, max_errors: Option<&Value>) -> Vec<Value> {
+    if problems.is_empty() {
+        return vec![Value::String("ok".to_string())];
+    }
+    let max_errors = max_errors.map_or(100, int_value);
+    problems.truncate(std::cmp::max(max_errors, 1) as usize);
+    return problems.into_iter().map(Value::String).collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pragmas() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        let query = |conn: &mut Connection, sql: &str| conn.execute(sql).unwrap().rows;
+
+        // the page size can only be changed before anything is written
+        assert!(query(&mut conn, "PRAGMA page_size = 1024").is_empty());
+        query(&mut conn, "CREATE TABLE t(a)");
+        query(&mut conn, "PRAGMA page_size = 8192");
+        assert_eq!(query(&mut conn, "PRAGMA page_size"), [[Value::Int64(1024)]]);
+        assert_eq!(std::fs::metadata(file.path()).unwrap().len(), 2 * 1024);
+
+        query(&mut conn, "PRAGMA main.user_version(-7)");
+        query(&mut conn, "PRAGMA cache_size = -64");
+        query(&mut conn, "PRAGMA no_such_pragma = 1");
+        assert_eq!(query(&mut conn, "PRAGMA cache_size"), [[Value::Int64(-64)]]);
+        query(&mut conn, "PRAGMA default_cache_size = 100");
+        let mut reopened = Connection::new(file.path().to_str().unwrap()).unwrap();
+        assert_eq!(
+            query(&mut reopened, "PRAGMA cache_size"),
+            [[Value::Int64(100)]]
+        );
+        assert_eq!(
+            query(&mut reopened, "pragma USER_VERSION"),
+            [[Value::Int64(-7)]]
+        );
+        assert!(query(&mut reopened, "PRAGMA no_such_pragma").is_empty());
+
+        let result = conn.execute("PRAGMA integrity_check").unwrap();
+        assert_eq!(result.columns, ["integrity_check"]);
+        assert_eq!(result.rows, [[Value::String("ok".to_string())]]);
+        assert!(conn.execute("PRAGMA aux.page_size").is_err());
+
+        // auto-vacuum can only be turned on before anything is written,
+        // but can be switched between full and incremental at any time
+        query(&mut conn, "PRAGMA auto_vacuum = 1");
+        assert_eq!(query(&mut conn, "PRAGMA auto_vacuum"), [[Value::Int64(0)]]);
+        let empty = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(empty.path().to_str().unwrap()).unwrap();
+        query(&mut conn, "PRAGMA auto_vacuum = FULL");
+        assert_eq!(query(&mut conn, "PRAGMA auto_vacuum"), [[Value::Int64(1)]]);
+        query(&mut conn, "PRAGMA auto_vacuum = 2");
+        let mut reopened = Connection::new(empty.path().to_str().unwrap()).unwrap();
+        assert_eq!(
+            query(&mut reopened, "PRAGMA auto_vacuum"),
+            [[Value::Int64(2)]]
+        );
+    }
+}
@@ -0,0 +1,30 @@
+//! The types most programs need, so that they can be brought in with a
+//! single `use sqlite_clone::prelude::*;`.
+//!
+//! ```no_run
+//! use sqlite_clone::prelude::*;
+//!
+//! fn main() -> Result<()> {
+//!     let mut conn = Connection::new("my.db")?;
+//!     conn.execute("CREATE TABLE users(id INTEGER PRIMARY KEY, name TEXT)")?;
+//!     let mut stmt = conn.prepare("INSERT INTO users(name) VALUES (?)")?;
+//!     stmt.bind(1, Value::String("Ada".to_string()))?;
+//!     stmt.execute(&mut conn)?;
+//!     for row in conn.execute("SELECT name FROM users")?.rows {
+//!         println!("{:?}", row[0]);
+//!     }
+//!     return Ok(());
+//! }
+//! ```
+
+pub use eyre::Result;
+
+pub use crate::btree::{Btree, BtreeCursor, Record};
+pub use crate::connection::Connection;
+pub use crate::datatypes::Value;
+pub use crate::dump::DumpOptions;
+pub use crate::kvstore::KvStore;
+pub use crate::limits::Limits;
+pub use crate::pagefile::PagerConfig;
+pub use crate::statement::{QueryResult, Statement};
+pub use crate::table::Table;
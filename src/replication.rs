@@ -0,0 +1,536 @@
+use eyre::{eyre, Result};
+use std::fs::File;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+
+use crate::btree::Record;
+use crate::connection::Connection;
+use crate::datatypes::{Value, VarInt};
+use crate::ddl;
+use crate::parsing;
+
+/// A change made to the database by a statement, as recorded for
+/// replication. Rows are given by their row ids and stored values, so
+/// that replaying the changes on a follower gives the same rows; changes
+/// to the schema are given by the SQL of the statement that made them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    Insert {
+        table: String,
+        row_id: i64,
+        values: Vec<Value>,
+    },
+    Delete {
+        table: String,
+        row_id: i64,
+    },
+    Schema(String),
+}
+
+/// The changes of a committed statement, in the order they were made.
+/// Changesets are numbered in the order they are committed, so that a
+/// follower can tell if it has missed any.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangeSet {
+    pub sequence: u64,
+    pub changes: Vec<Change>,
+}
+
+impl Change {
+    /// Stores a change as a record: a number for the kind of change,
+    /// followed by the values that describe it.
+    fn to_record(&self) -> Record {
+        let values = match self {
+            Change::Insert {
+                table,
+                row_id,
+                values,
+            } => {
+                let mut output = vec![
+                    Value::Int64(1),
+                    Value::String(table.clone()),
+                    Value::Int64(*row_id),
+                ];
+                output.extend(values.iter().cloned());
+                output
+            }
+            Change::Delete { table, row_id } => vec![
+                Value::Int64(2),
+                Value::String(table.clone()),
+                Value::Int64(*row_id),
+            ],
+            Change::Schema(sql) => vec![Value::Int64(3), Value::String(sql.clone())],
+        };
+        return Record::from_values(values);
+    }
+
+    fn from_record(record: Record) -> Result<Self> {
+        let mut values = record.values.into_iter();
+        let kind = values.next().and_then(|value| value.get_int_val());
+        let mut text = || match values.next() {
+            Some(Value::String(text)) => Ok(text),
+            _ => Err(eyre!("Invalid change: expected text.")),
+        };
+        return match kind {
+            Some(1) | Some(2) => {
+                let table = text()?;
+                let row_id = values
+                    .next()
+                    .and_then(|value| value.get_int_val())
+                    .ok_or_else(|| eyre!("Invalid change: expected a row id."))?;
+                if kind == Some(1) {
+                    Ok(Change::Insert {
+                        table: table,
+                        row_id: row_id,
+                        values: values.collect(),
+                    })
+                } else {
+                    Ok(Change::Delete {
+                        table: table,
+                        row_id: row_id,
+                    })
+                }
+            }
+            Some(3) => Ok(Change::Schema(text()?)),
+            _ => Err(eyre!("Invalid change: unknown kind.")),
+        };
+    }
+}
+
+impl ChangeSet {
+    /// Encodes the changeset as bytes: the sequence number as a big-endian
+    /// u64, then each change as a record, preceded by its length as a
+    /// big-endian u32.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut output = Vec::new();
+        output.extend(self.sequence.to_be_bytes().iter());
+        for change in &self.changes {
+            let record = change.to_record().serialize();
+            output.extend((record.len() as u32).to_be_bytes().iter());
+            output.extend(record);
+        }
+        return output;
+    }
+
+    pub fn deserialize(i: &[u8]) -> Result<Self> {
+        if i.len() < 8 {
+            return Err(eyre!("Invalid changeset: too short."));
+        }
+        let mut sequence = [0; 8];
+        sequence.copy_from_slice(&i[..8]);
+        let mut pos = 8;
+        let mut changes = Vec::new();
+        while pos < i.len() {
+            if i.len() - pos < 4 {
+                return Err(eyre!("Invalid changeset: truncated change."));
+            }
+            let mut len = [0; 4];
+            len.copy_from_slice(&i[pos..pos + 4]);
+            let len = u32::from_be_bytes(len) as usize;
+            pos += 4;
+            if i.len() - pos < len || len == 0 {
+                return Err(eyre!("Invalid changeset: truncated change."));
+            }
+            let record = Record::deserialize(&i[pos..pos + len])?;
+            changes.push(Change::from_record(record)?);
+            pos += len;
+        }
+        return Ok(Self {
+            sequence: u64::from_be_bytes(sequence),
+            changes: changes,
+        });
+    }
+}
+
+/// Where a `ReplicationLog` sends changesets. Any closure taking a
+/// changeset is a sink, as is a `WriteSink`.
+pub trait ChangeSink {
+    fn append(&mut self, changeset: &ChangeSet) -> Result<()>;
+}
+
+impl<F> ChangeSink for F
+where
+    F: FnMut(&ChangeSet) -> Result<()>,
+{
+    fn append(&mut self, changeset: &ChangeSet) -> Result<()> {
+        return self(changeset);
+    }
+}
+
+/// A sink that writes each changeset to a stream (e.g., a file or a
+/// socket), preceded by its length as a big-endian u32. The changesets
+/// can be read back with `read_changeset`.
+pub struct WriteSink<W: Write> {
+    pub writer: W,
+}
+
+impl<W: Write> ChangeSink for WriteSink<W> {
+    fn append(&mut self, changeset: &ChangeSet) -> Result<()> {
+        let bytes = changeset.serialize();
+        self.writer.write_all(&(bytes.len() as u32).to_be_bytes())?;
+        self.writer.write_all(&bytes)?;
+        self.writer.flush()?;
+        return Ok(());
+    }
+}
+
+/// The largest changeset `read_changeset` accepts, so that a corrupt
+/// length does not have it try to read gigabytes.
+const MAX_CHANGESET_SIZE: usize = 1 << 30;
+
+/// Reads the next changeset written by a `WriteSink`, or `None` at the
+/// end of the stream. A changeset that has only been partly written
+/// (e.g., while the writer is still appending it) is also `None`, and
+/// the stream is left where it was, so that it can be read again once
+/// the rest is there.
+pub fn read_changeset<R: Read + Seek>(reader: &mut R) -> Result<Option<ChangeSet>> {
+    let start = reader.stream_position()?;
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => (),
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+            reader.seek(SeekFrom::Start(start))?;
+            return Ok(None);
+        }
+        Err(err) => return Err(err.into()),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_CHANGESET_SIZE {
+        return Err(eyre!("Changeset too large: {} bytes.", len));
+    }
+    let mut bytes = vec![0; len];
+    match reader.read_exact(&mut bytes) {
+        Ok(()) => (),
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+            reader.seek(SeekFrom::Start(start))?;
+            return Ok(None);
+        }
+        Err(err) => return Err(err.into()),
+    }
+    return Ok(Some(ChangeSet::deserialize(&bytes)?));
+}
+
+/// The file a follower keeps its progress in, alongside its database
+/// file (see `Connection::open_follower`).
+fn progress_path(filename: &str) -> String {
+    return format!("{}-follower", filename);
+}
+
+/// Reads the sequence number of the next changeset a follower is to
+/// apply, as last saved by `save_progress`, or `None` if it has never
+/// been saved. `change_counter` is the file change counter in the
+/// follower's header now: if it is one past the one saved, the follower
+/// applied another changeset but stopped before it could save that.
+pub fn load_progress(filename: &str, change_counter: u32) -> Result<Option<u64>> {
+    let bytes = match std::fs::read(progress_path(filename)) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    if bytes.len() != 12 {
+        return Err(eyre!("Malformed follower progress file."));
+    }
+    let mut next_sequence = [0; 8];
+    next_sequence.copy_from_slice(&bytes[..8]);
+    let next_sequence = u64::from_be_bytes(next_sequence);
+    let saved_counter = parsing::be_u32(&bytes[8..])?;
+    if change_counter == saved_counter.wrapping_add(1) {
+        return Ok(Some(next_sequence + 1));
+    } else if change_counter != saved_counter {
+        return Err(eyre!(
+            "The follower database has been changed other than by applying changesets."
+        ));
+    }
+    return Ok(Some(next_sequence));
+}
+
+/// Saves the sequence number of the next changeset a follower is to
+/// apply, along with the file change counter in its header once the
+/// changesets before it were applied. The progress is written to a new
+/// file that then replaces the old one, so that the old one is left
+/// whole if this is interrupted.
+pub fn save_progress(filename: &str, next_sequence: u64, change_counter: u32) -> Result<()> {
+    let path = progress_path(filename);
+    let temp = format!("{}.tmp", path);
+    let mut bytes = next_sequence.to_be_bytes().to_vec();
+    bytes.extend(&change_counter.to_be_bytes());
+    let mut file = File::create(&temp)?;
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    std::fs::rename(&temp, &path)?;
+    return Ok(());
+}
+
+/// Records the changes made through a connection and, as each statement
+/// commits, sends them to a sink as a changeset. Attach one to the
+/// connection that writes the database (see
+/// `Connection::replication_log`), and replay the changesets on
+/// followers with an `Applier`. Only changes made through the
+/// connection are seen.
+pub struct ReplicationLog {
+    sink: Box<dyn ChangeSink>,
+    next_sequence: u64,
+    pending: Vec<Change>,
+    /// The number of pending changes when each open statement began, so
+    /// that a statement that fails can drop its changes.
+    marks: Vec<usize>,
+}
+
+impl ReplicationLog {
+    /// Creates a log whose first changeset has the given sequence number,
+    /// which for a log taking over from an earlier one is the number
+    /// after the last it sent.
+    pub fn new(sink: Box<dyn ChangeSink>, next_sequence: u64) -> Self {
+        return Self {
+            sink: sink,
+            next_sequence: next_sequence,
+            pending: Vec::new(),
+            marks: Vec::new(),
+        };
+    }
+
+    /// The sequence number the next changeset will have.
+    pub fn next_sequence(&self) -> u64 {
+        return self.next_sequence;
+    }
+
+    pub fn record(&mut self, change: Change) {
+        self.pending.push(change);
+    }
+
+    pub fn begin_statement(&mut self) {
+        self.marks.push(self.pending.len());
+    }
+
+    pub fn commit_statement(&mut self) {
+        self.marks.pop();
+    }
+
+    pub fn rollback_statement(&mut self) {
+        if let Some(mark) = self.marks.pop() {
+            self.pending.truncate(mark);
+        }
+    }
+
+    /// Sends the changes of the statements committed since the last
+    /// changeset. If the sink fails, the changes are kept and sent along
+    /// with those of the next statement, so that none are lost.
+    pub fn flush(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let changeset = ChangeSet {
+            sequence: self.next_sequence,
+            changes: std::mem::take(&mut self.pending),
+        };
+        if let Err(err) = self.sink.append(&changeset) {
+            self.pending = changeset.changes;
+            return Err(err);
+        }
+        self.next_sequence += 1;
+        return Ok(());
+    }
+}
+
+/// Replays changesets from a `ReplicationLog` onto a follower database,
+/// in order. The follower should start as a copy of the database at the
+/// point the log began (e.g., made with `Connection::serialize`), and
+/// should not be written to otherwise.
+pub struct Applier {
+    /// The sequence number of the next changeset to apply.
+    pub next_sequence: u64,
+}
+
+impl Applier {
+    pub fn new(next_sequence: u64) -> Self {
+        return Self {
+            next_sequence: next_sequence,
+        };
+    }
+
+    /// Applies a changeset as a single statement, so that either all of
+    /// its changes are made or none are. A changeset that has already been
+    /// applied is skipped, returning false; one that comes after a
+    /// changeset that has not been applied is an error.
+    pub fn apply(&mut self, conn: &mut Connection, changeset: &ChangeSet) -> Result<bool> {
+        if changeset.sequence < self.next_sequence {
+            return Ok(false);
+        } else if changeset.sequence > self.next_sequence {
+            return Err(eyre!(
+                "Missing changeset: expected {} but got {}.",
+                self.next_sequence,
+                changeset.sequence
+            ));
+        }
+        conn.write_statement(|conn| {
+            for change in &changeset.changes {
+                match change {
+                    Change::Insert {
+                        table,
+                        row_id,
+                        values,
+                    } => {
+                        let table = conn.table(table)?;
+                        let record = Record::from_values(values.clone());
+                        table.btree.insert(*row_id, &record)?;
+                        for index in ddl::table_indexes(conn, &table.schema)? {
+                            index.insert_row(&table.schema, *row_id, &record)?;
+                        }
+                    }
+                    Change::Delete { table, row_id } => {
+                        let table = conn.table(table)?;
+                        if let Some(record) = table.btree.get_row(VarInt::new(*row_id)) {
+                            table.btree.delete(*row_id)?;
+                            for index in ddl::table_indexes(conn, &table.schema)? {
+                                index.delete_row(&table.schema, *row_id, &record)?;
+                            }
+                        }
+                    }
+                    Change::Schema(sql) => {
+                        // which records the change itself
+                        conn.execute(sql)?;
+                        continue;
+                    }
+                }
+                // a follower may in turn be followed
+                if let Some(log) = &mut conn.replication_log {
+                    log.record(change.clone());
+                }
+            }
+            return Ok(());
+        })?;
+        self.next_sequence += 1;
+        return Ok(true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::io::Cursor;
+    use std::rc::Rc;
+
+    #[test]
+    fn replicate_changes() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut leader = Connection::new(file.path().to_str().unwrap()).unwrap();
+        let mut follower = Connection::deserialize(leader.serialize().unwrap()).unwrap();
+
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let sink = sent.clone();
+        leader.replication_log = Some(ReplicationLog::new(
+            Box::new(move |changeset: &ChangeSet| {
+                sink.borrow_mut().push(changeset.clone());
+                return Ok(());
+            }),
+            0,
+        ));
+        leader.execute("CREATE TABLE t(a INTEGER, b)").unwrap();
+        leader
+            .execute_batch("INSERT INTO t VALUES ('1', 'x'); INSERT INTO t VALUES (2, 'y');")
+            .unwrap();
+        leader.execute("INSERT INTO t VALUES (3, 'z')").unwrap();
+        leader.execute("DELETE FROM t WHERE a = 2").unwrap();
+        // a statement that fails sends nothing
+        let failed: Result<()> = leader.write_statement(|conn| {
+            conn.execute("INSERT INTO t VALUES (4, 'w')")?;
+            return Err(eyre!("failed"));
+        });
+        assert!(failed.is_err());
+
+        let sent = sent.borrow();
+        assert_eq!(sent.len(), 4);
+        assert_eq!(
+            sent[1].changes[0],
+            Change::Insert {
+                table: "t".to_string(),
+                row_id: 1,
+                values: vec![Value::Int64(1), Value::String("x".to_string())],
+            }
+        );
+
+        // changesets survive being written to a stream
+        let mut stream = WriteSink { writer: Vec::new() };
+        for changeset in sent.iter() {
+            stream.append(changeset).unwrap();
+        }
+        let mut reader = Cursor::new(&stream.writer[..]);
+        let mut applier = Applier::new(0);
+        assert!(applier.apply(&mut follower, &sent[2]).is_err());
+        while let Some(changeset) = read_changeset(&mut reader).unwrap() {
+            assert!(applier.apply(&mut follower, &changeset).unwrap());
+        }
+        assert!(!applier.apply(&mut follower, &sent[0]).unwrap());
+
+        let query = "SELECT a, b FROM t";
+        assert_eq!(
+            follower.execute(query).unwrap(),
+            leader.execute(query).unwrap()
+        );
+        assert_eq!(follower.execute(query).unwrap().rows.len(), 2);
+    }
+
+    #[test]
+    fn replicate_indexed() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut leader = Connection::new(file.path().to_str().unwrap()).unwrap();
+        leader
+            .execute("CREATE TABLE t(id INTEGER PRIMARY KEY, a UNIQUE, b)")
+            .unwrap();
+        leader.execute("CREATE INDEX t_b ON t(b, a)").unwrap();
+        let mut follower = Connection::deserialize(leader.serialize().unwrap()).unwrap();
+
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let sink = sent.clone();
+        leader.replication_log = Some(ReplicationLog::new(
+            Box::new(move |changeset: &ChangeSet| {
+                sink.borrow_mut().push(changeset.clone());
+                return Ok(());
+            }),
+            0,
+        ));
+        let rows: Vec<String> = (1..=300)
+            .map(|i| format!("({}, '{}')", i, "x".repeat(i % 50)))
+            .collect();
+        leader
+            .execute(&format!("INSERT INTO t (a, b) VALUES {}", rows.join(", ")))
+            .unwrap();
+        leader.execute("DELETE FROM t WHERE a % 3 = 0").unwrap();
+
+        let mut applier = Applier::new(0);
+        for changeset in sent.borrow().iter() {
+            assert!(applier.apply(&mut follower, changeset).unwrap());
+        }
+        let check = follower.execute("PRAGMA integrity_check").unwrap();
+        assert_eq!(check.rows, [[Value::String("ok".to_string())]]);
+        let query = "SELECT id FROM t WHERE a = 200";
+        assert_eq!(follower.execute(query).unwrap().rows, [[Value::Int64(200)]]);
+        assert!(follower.execute("INSERT INTO t (a) VALUES (200)").is_err());
+    }
+
+    #[test]
+    fn read_partial_changeset() {
+        let changeset = ChangeSet {
+            sequence: 3,
+            changes: vec![Change::Schema("CREATE TABLE t(a)".to_string())],
+        };
+        let mut stream = WriteSink { writer: Vec::new() };
+        stream.append(&changeset).unwrap();
+        let bytes = stream.writer;
+
+        // a changeset cut short is read again once it is all there
+        for end in [2, bytes.len() - 1] {
+            let mut reader = Cursor::new(bytes[..end].to_vec());
+            assert_eq!(read_changeset(&mut reader).unwrap(), None);
+            assert_eq!(reader.position(), 0);
+            reader.get_mut().extend_from_slice(&bytes[end..]);
+            assert_eq!(
+                read_changeset(&mut reader).unwrap(),
+                Some(changeset.clone())
+            );
+        }
+
+        let mut reader = Cursor::new(u32::MAX.to_be_bytes().to_vec());
+        assert!(read_changeset(&mut reader).is_err());
+    }
+}
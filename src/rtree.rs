@@ -0,0 +1,276 @@
+use eyre::{eyre, Result};
+use std::convert::TryInto;
+
+use crate::btree::Btree;
+use crate::connection::Connection;
+use crate::datatypes::{Value, VarInt};
+use crate::parsing;
+
+/// The most dimensions an R-tree may have, as in SQLite.
+pub const MAX_DIMENSIONS: usize = 5;
+
+/// The node number of the root node, which is always present.
+const ROOT_NODE: i64 = 1;
+
+/// How the coordinates of an R-tree are stored.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CoordType {
+    /// 32-bit floating point, for tables created with `rtree`.
+    Real32,
+    /// 32-bit signed integers, for tables created with `rtree_i32`.
+    Int32,
+}
+
+/// An axis-aligned box, with one (min, max) pair of coordinates per
+/// dimension.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BoundingBox {
+    pub min: Vec<f64>,
+    pub max: Vec<f64>,
+}
+
+impl BoundingBox {
+    pub fn new(min: Vec<f64>, max: Vec<f64>) -> Result<Self> {
+        if min.len() != max.len() {
+            return Err(eyre!(
+                "Bounding box has {} minimum and {} maximum coordinates",
+                min.len(),
+                max.len()
+            ));
+        }
+        return Ok(Self { min: min, max: max });
+    }
+
+    pub fn dimensions(&self) -> usize {
+        return self.min.len();
+    }
+
+    /// Tests whether the two boxes share at least one point.
+    pub fn overlaps(&self, other: &BoundingBox) -> bool {
+        return (0..self.dimensions())
+            .all(|i| self.min[i] <= other.max[i] && other.min[i] <= self.max[i]);
+    }
+
+    /// Tests whether `other` lies entirely inside this box.
+    pub fn contains(&self, other: &BoundingBox) -> bool {
+        return (0..self.dimensions())
+            .all(|i| self.min[i] <= other.min[i] && other.max[i] <= self.max[i]);
+    }
+}
+
+/// An entry in an R-tree: the ID of a row and its bounding box.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RTreeEntry {
+    pub id: i64,
+    pub bbox: BoundingBox,
+}
+
+/// A node of an R-tree, as stored in the `data` column of the `_node`
+/// shadow table. The blob starts with the depth of the tree (only
+/// meaningful in the root node) and the number of cells, as 2-byte
+/// big-endian integers. Each cell is a 64-bit row ID (in leaves) or
+/// child node number (in interior nodes), followed by the coordinates.
+#[derive(Debug, Clone, PartialEq)]
+struct Node {
+    depth: u16,
+    cells: Vec<RTreeEntry>,
+}
+
+impl Node {
+    fn deserialize(bytes: &[u8], dimensions: usize, coord_type: CoordType) -> Result<Self> {
+        if bytes.len() < 4 {
+            return Err(eyre!("R-tree node is too short"));
+        }
+        let depth = parsing::be_u16(&bytes[0..2])?;
+        let num_cells = parsing::be_u16(&bytes[2..4])? as usize;
+        let cell_size = 8 + 8 * dimensions;
+        if 4 + num_cells * cell_size > bytes.len() {
+            return Err(eyre!("R-tree node has too many cells"));
+        }
+
+        let mut cells = Vec::with_capacity(num_cells);
+        for cell in bytes[4..4 + num_cells * cell_size].chunks(cell_size) {
+            let id = i64::from_be_bytes(cell[0..8].try_into().unwrap());
+            let mut min = Vec::with_capacity(dimensions);
+            let mut max = Vec::with_capacity(dimensions);
+            for (i, coord) in cell[8..].chunks(4).enumerate() {
+                let bits: [u8; 4] = coord.try_into().unwrap();
+                let value = match coord_type {
+                    CoordType::Real32 => f32::from_be_bytes(bits) as f64,
+                    CoordType::Int32 => i32::from_be_bytes(bits) as f64,
+                };
+                if i % 2 == 0 {
+                    min.push(value);
+                } else {
+                    max.push(value);
+                }
+            }
+            cells.push(RTreeEntry {
+                id: id,
+                bbox: BoundingBox { min: min, max: max },
+            });
+        }
+        return Ok(Self {
+            depth: depth,
+            cells: cells,
+        });
+    }
+}
+
+/// Which entries an R-tree query returns.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum QueryMode {
+    /// Entries whose boxes overlap the query box.
+    Overlapping,
+    /// Entries whose boxes lie entirely inside the query box.
+    Within,
+}
+
+/// An R-tree virtual table, read from the shadow tables that SQLite's
+/// rtree module keeps it in: `<name>_node` holds the nodes of the tree,
+/// `<name>_rowid` maps row IDs to leaf nodes, and `<name>_parent` maps
+/// nodes to their parents.
+#[derive(Debug)]
+pub struct RTree {
+    pub name: String,
+    pub dimensions: usize,
+    pub coord_type: CoordType,
+    nodes: Btree,
+}
+
+impl RTree {
+    pub fn open(conn: &Connection, name: &str) -> Result<Self> {
+        let table = conn
+            .schema
+            .virtual_table(name)
+            .ok_or_else(|| eyre!("no such table: {}", name))?;
+        let coord_type = match table.module().to_lowercase().as_str() {
+            "rtree" => CoordType::Real32,
+            "rtree_i32" => CoordType::Int32,
+            module => return Err(eyre!("{} is not an R-tree ({})", name, module)),
+        };
+        // the first argument is the ID column; auxiliary columns, which
+        // are not part of the tree, are marked with a leading '+'
+        let num_coords = table
+            .definition
+            .args
+            .iter()
+            .skip(1)
+            .filter(|arg| !arg.starts_with('+'))
+            .count();
+        if num_coords == 0 || num_coords % 2 != 0 || num_coords / 2 > MAX_DIMENSIONS {
+            return Err(eyre!(
+                "Wrong number of columns for an rtree table: {}",
+                table.definition.args.len()
+            ));
+        }
+
+        let node_table = conn.table(&format!("{}_node", table.name))?;
+        return Ok(Self {
+            name: table.name.clone(),
+            dimensions: num_coords / 2,
+            coord_type: coord_type,
+            nodes: node_table.btree,
+        });
+    }
+
+    fn node(&self, node_num: i64) -> Result<Node> {
+        let record = self
+            .nodes
+            .get_row(VarInt::new(node_num))
+            .ok_or_else(|| eyre!("{}: node {} does not exist", self.name, node_num))?;
+        return match record.values.get(1) {
+            Some(Value::Blob(data)) => Node::deserialize(data, self.dimensions, self.coord_type),
+            _ => Err(eyre!("{}: node {} is malformed", self.name, node_num)),
+        };
+    }
+
+    /// Finds the entries whose boxes overlap the query box.
+    pub fn query(&self, bbox: &BoundingBox) -> Result<Vec<RTreeEntry>> {
+        return self.search(bbox, QueryMode::Overlapping);
+    }
+
+    /// Finds the entries whose boxes lie entirely inside the query box.
+    pub fn query_within(&self, bbox: &BoundingBox) -> Result<Vec<RTreeEntry>> {
+        return self.search(bbox, QueryMode::Within);
+    }
+
+    pub fn search(&self, bbox: &BoundingBox, mode: QueryMode) -> Result<Vec<RTreeEntry>> {
+        if bbox.dimensions() != self.dimensions {
+            return Err(eyre!(
+                "{} has {} dimensions, but the query box has {}",
+                self.name,
+                self.dimensions,
+                bbox.dimensions()
+            ));
+        }
+        let root = self.node(ROOT_NODE)?;
+        let mut output = Vec::new();
+        self.search_rcrs(&root, root.depth, bbox, mode, &mut output)?;
+        return Ok(output);
+    }
+
+    fn search_rcrs(
+        &self,
+        node: &Node,
+        height: u16,
+        bbox: &BoundingBox,
+        mode: QueryMode,
+        output: &mut Vec<RTreeEntry>,
+    ) -> Result<()> {
+        for cell in &node.cells {
+            // the box of an interior cell covers everything beneath it,
+            // so a subtree can only match if its box overlaps the query
+            if !bbox.overlaps(&cell.bbox) {
+                continue;
+            }
+            if height == 0 {
+                if mode == QueryMode::Overlapping || bbox.contains(&cell.bbox) {
+                    output.push(cell.clone());
+                }
+            } else {
+                let child = self.node(cell.id)?;
+                self.search_rcrs(&child, height - 1, bbox, mode, output)?;
+            }
+        }
+        return Ok(());
+    }
+
+    /// Returns every entry in the tree.
+    pub fn entries(&self) -> Result<Vec<RTreeEntry>> {
+        let everything = BoundingBox {
+            min: vec![f64::NEG_INFINITY; self.dimensions],
+            max: vec![f64::INFINITY; self.dimensions],
+        };
+        return self.query(&everything);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deserialize_node() {
+        let mut bytes = vec![0, 2, 0, 2];
+        for (id, coords) in [(7_i64, [1.0_f32, 2.0]), (9, [-3.5, 0.5])].iter() {
+            bytes.extend(id.to_be_bytes().iter());
+            for c in coords {
+                bytes.extend(c.to_be_bytes().iter());
+            }
+        }
+        bytes.resize(64, 0);
+        let node = Node::deserialize(&bytes, 1, CoordType::Real32).unwrap();
+        assert_eq!(node.depth, 2);
+        assert_eq!(node.cells.len(), 2);
+        assert_eq!(node.cells[1].id, 9);
+        assert_eq!(node.cells[1].bbox.min, vec![-3.5]);
+
+        let query = BoundingBox::new(vec![0.0], vec![1.5]).unwrap();
+        assert!(query.overlaps(&node.cells[0].bbox));
+        assert!(!query.contains(&node.cells[0].bbox));
+        assert!(!query.overlaps(&BoundingBox::new(vec![2.0], vec![3.0]).unwrap()));
+
+        assert!(Node::deserialize(&bytes[..20], 1, CoordType::Real32).is_err());
+    }
+}
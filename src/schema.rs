@@ -0,0 +1,406 @@
+use eyre::{eyre, Result, WrapErr};
+
+use crate::ast::{
+    ColumnConstraint, ColumnDef, CreateIndex, CreateTable, CreateView, CreateVirtualTable, Stmt,
+};
+use crate::btree::{Btree, Record};
+use crate::datatypes::Value;
+use crate::ddl;
+use crate::exec::{self, Affinity};
+use crate::pager::SharedPager;
+use crate::parser;
+
+/// The root page of the sqlite_schema table is always page 1.
+pub const SCHEMA_ROOT_PAGE: usize = 1;
+
+// sqlite_schema has the following layout:
+const SCHEMA_TABLE_SQL: &str = "CREATE TABLE sqlite_schema(
+    type text,
+    name text,
+    tbl_name text,
+    rootpage integer,
+    sql text
+)";
+
+#[derive(Debug, Clone)]
+pub struct TableSchema {
+    pub name: String,
+    pub root_page: usize,
+    pub sql: String,
+    pub definition: CreateTable,
+}
+
+impl TableSchema {
+    pub fn new(name: String, root_page: usize, sql: String) -> Result<Self> {
+        let definition = match parser::parse(&sql)? {
+            Stmt::CreateTable(def) => def,
+            _ => return Err(eyre!("Not a CREATE TABLE statement: {}", sql)),
+        };
+        return Ok(Self {
+            name: name,
+            root_page: root_page,
+            sql: sql,
+            definition: definition,
+        });
+    }
+
+    pub fn columns(&self) -> &[ColumnDef] {
+        return &self.definition.columns;
+    }
+
+    pub fn column_names(&self) -> Vec<String> {
+        return self.columns().iter().map(|c| c.name.clone()).collect();
+    }
+
+    /// The collating sequence declared for a column, if any.
+    pub fn column_collation(&self, idx: usize) -> Option<&str> {
+        return self.columns()[idx]
+            .constraints
+            .iter()
+            .find_map(|c| match c {
+                ColumnConstraint::Collate(name) => Some(name.as_str()),
+                _ => None,
+            });
+    }
+
+    /// The type affinity of a column, derived from its declared type.
+    pub fn column_affinity(&self, idx: usize) -> Affinity {
+        return Affinity::from_type_name(self.columns()[idx].type_name.as_deref());
+    }
+
+    /// The value a column takes when none is given for it: its DEFAULT,
+    /// with the column's affinity applied, or NULL.
+    pub fn column_default(&self, idx: usize) -> Result<Value> {
+        let default = self.columns()[idx]
+            .constraints
+            .iter()
+            .find_map(|c| match c {
+                ColumnConstraint::Default(expr) => Some(expr),
+                _ => None,
+            });
+        return match default {
+            Some(expr) => Ok(self
+                .column_affinity(idx)
+                .apply(exec::eval_expr(expr, &exec::NoRow)?)),
+            None => Ok(Value::Null),
+        };
+    }
+
+    /// The value of a column in one of the table's records. A record
+    /// written before the column was added to the table (by ALTER TABLE
+    /// ADD COLUMN) ends before it, and the column has its default value.
+    pub fn column_value(&self, record: &Record, idx: usize) -> Result<Value> {
+        return match record.values.get(idx) {
+            Some(value) => Ok(value.clone()),
+            None => self.column_default(idx),
+        };
+    }
+
+    /// Fills in the value of the column that is an alias for the row ID,
+    /// if the table has one (see `ddl::row_id_alias`), as the record holds
+    /// a NULL in its place.
+    pub fn with_row_id(&self, row_id: i64, mut record: Record) -> Record {
+        if let Some(idx) = ddl::row_id_alias(&self.definition) {
+            if record.values.len() <= idx {
+                record.values.resize(idx + 1, Value::Null);
+                record.col_types.resize(idx + 1, Value::Null.data_type());
+            }
+            record.values[idx] = Value::from_int(row_id);
+            record.col_types[idx] = record.values[idx].data_type();
+        }
+        return record;
+    }
+
+    /// Finds the position of a column in the table's records, matching
+    /// names case-insensitively as SQLite does.
+    pub fn column_index(&self, name: &str) -> Option<usize> {
+        return self
+            .columns()
+            .iter()
+            .position(|c| c.name.eq_ignore_ascii_case(name));
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IndexSchema {
+    pub name: String,
+    pub table_name: String,
+    pub root_page: usize,
+    /// Indexes created automatically for UNIQUE and PRIMARY KEY
+    /// constraints have no SQL.
+    pub sql: Option<String>,
+    pub definition: Option<CreateIndex>,
+}
+
+impl IndexSchema {
+    pub fn new(
+        name: String,
+        table_name: String,
+        root_page: usize,
+        sql: Option<String>,
+    ) -> Result<Self> {
+        let definition = match &sql {
+            Some(sql) => match parser::parse(sql)? {
+                Stmt::CreateIndex(def) => Some(def),
+                _ => return Err(eyre!("Not a CREATE INDEX statement: {}", sql)),
+            },
+            None => None,
+        };
+        return Ok(Self {
+            name: name,
+            table_name: table_name,
+            root_page: root_page,
+            sql: sql,
+            definition: definition,
+        });
+    }
+
+    /// The names of the indexed columns, in index order, if known.
+    pub fn column_names(&self) -> Option<Vec<String>> {
+        return self
+            .definition
+            .as_ref()
+            .map(|def| def.columns.iter().map(|c| c.name.clone()).collect());
+    }
+
+    /// Tests whether this is a partial index, which only holds entries
+    /// for some of the rows of its table.
+    pub fn is_partial(&self) -> bool {
+        return self
+            .definition
+            .as_ref()
+            .is_some_and(|def| def.where_clause.is_some());
+    }
+}
+
+/// A table implemented by a module (e.g., `rtree`) rather than stored in
+/// a B-tree of its own. Modules usually keep their data in ordinary
+/// "shadow" tables.
+#[derive(Debug, Clone)]
+pub struct VirtualTableSchema {
+    pub name: String,
+    pub sql: String,
+    pub definition: CreateVirtualTable,
+}
+
+impl VirtualTableSchema {
+    pub fn new(name: String, sql: String) -> Result<Self> {
+        let definition = match parser::parse(&sql)? {
+            Stmt::CreateVirtualTable(def) => def,
+            _ => return Err(eyre!("Not a CREATE VIRTUAL TABLE statement: {}", sql)),
+        };
+        return Ok(Self {
+            name: name,
+            sql: sql,
+            definition: definition,
+        });
+    }
+
+    pub fn module(&self) -> &str {
+        return &self.definition.module;
+    }
+}
+
+/// A view: a SELECT that is queried as if it were a table, and run each
+/// time it is queried.
+#[derive(Debug, Clone)]
+pub struct ViewSchema {
+    pub name: String,
+    pub sql: String,
+    pub definition: CreateView,
+}
+
+impl ViewSchema {
+    pub fn new(name: String, sql: String) -> Result<Self> {
+        let definition = match parser::parse(&sql)? {
+            Stmt::CreateView(def) => def,
+            _ => return Err(eyre!("Not a CREATE VIEW statement: {}", sql)),
+        };
+        return Ok(Self {
+            name: name,
+            sql: sql,
+            definition: definition,
+        });
+    }
+}
+
+/// The set of tables, indexes and views in a database, as read from the
+/// sqlite_schema table.
+#[derive(Debug, Clone)]
+pub struct Schema {
+    pub tables: Vec<TableSchema>,
+    pub indexes: Vec<IndexSchema>,
+    pub virtual_tables: Vec<VirtualTableSchema>,
+    pub views: Vec<ViewSchema>,
+}
+
+impl Schema {
+    pub fn load(pager: SharedPager) -> Result<Self> {
+        let schema_tree = Btree::new(
+            "sqlite_schema".to_string(),
+            "sqlite_schema".to_string(),
+            SCHEMA_ROOT_PAGE,
+            pager,
+        );
+
+        let mut tables = vec![Self::schema_table()];
+        let mut indexes = Vec::new();
+        let mut virtual_tables = Vec::new();
+        let mut views = Vec::new();
+        for (_, record) in schema_tree.list_records() {
+            let values = record.values;
+            let text = |i: usize| match values.get(i) {
+                Some(Value::String(s)) => Some(s.clone()),
+                _ => None,
+            };
+            let (obj_type, name, table_name) = match (text(0), text(1), text(2)) {
+                (Some(obj_type), Some(name), Some(table_name)) => (obj_type, name, table_name),
+                _ => return Err(eyre!("Malformed database schema")),
+            };
+            // rootpage is 0 or NULL for views, triggers, and virtual
+            // tables
+            let root_page = values.get(3).and_then(|v| v.get_int_val()).unwrap_or(0) as usize;
+            let sql = text(4);
+
+            if obj_type == "table" && root_page > 0 {
+                let sql = sql.ok_or_else(|| eyre!("Malformed database schema ({})", name))?;
+                let table = TableSchema::new(name.clone(), root_page, sql)
+                    .wrap_err_with(|| format!("Malformed database schema ({})", name))?;
+                tables.push(table);
+            } else if obj_type == "table" && sql.as_ref().is_some_and(|s| is_virtual(s)) {
+                let table = VirtualTableSchema::new(name.clone(), sql.unwrap())
+                    .wrap_err_with(|| format!("Malformed database schema ({})", name))?;
+                virtual_tables.push(table);
+            } else if obj_type == "index" {
+                let index = IndexSchema::new(name.clone(), table_name, root_page, sql)
+                    .wrap_err_with(|| format!("Malformed database schema ({})", name))?;
+                indexes.push(index);
+            } else if obj_type == "view" {
+                let sql = sql.ok_or_else(|| eyre!("Malformed database schema ({})", name))?;
+                let view = ViewSchema::new(name.clone(), sql)
+                    .wrap_err_with(|| format!("Malformed database schema ({})", name))?;
+                views.push(view);
+            }
+        }
+
+        return Ok(Self {
+            tables: tables,
+            indexes: indexes,
+            virtual_tables: virtual_tables,
+            views: views,
+        });
+    }
+
+    fn schema_table() -> TableSchema {
+        return TableSchema::new(
+            "sqlite_schema".to_string(),
+            SCHEMA_ROOT_PAGE,
+            SCHEMA_TABLE_SQL.to_string(),
+        )
+        .unwrap();
+    }
+
+    pub fn table(&self, name: &str) -> Option<&TableSchema> {
+        // sqlite_master is the historical name of the schema table
+        let name = if name.eq_ignore_ascii_case("sqlite_master") {
+            "sqlite_schema"
+        } else {
+            name
+        };
+        return self
+            .tables
+            .iter()
+            .find(|t| t.name.eq_ignore_ascii_case(name));
+    }
+
+    pub fn virtual_table(&self, name: &str) -> Option<&VirtualTableSchema> {
+        return self
+            .virtual_tables
+            .iter()
+            .find(|t| t.name.eq_ignore_ascii_case(name));
+    }
+
+    pub fn view(&self, name: &str) -> Option<&ViewSchema> {
+        return self
+            .views
+            .iter()
+            .find(|v| v.name.eq_ignore_ascii_case(name));
+    }
+
+    pub fn index(&self, name: &str) -> Option<&IndexSchema> {
+        return self
+            .indexes
+            .iter()
+            .find(|i| i.name.eq_ignore_ascii_case(name));
+    }
+
+    pub fn indexes_for_table(&self, table_name: &str) -> Vec<&IndexSchema> {
+        return self
+            .indexes
+            .iter()
+            .filter(|i| i.table_name.eq_ignore_ascii_case(table_name))
+            .collect();
+    }
+}
+
+/// Tests whether the SQL of a table in sqlite_schema creates a virtual
+/// table.
+fn is_virtual(sql: &str) -> bool {
+    let mut words = sql.split_whitespace();
+    return words
+        .next()
+        .is_some_and(|w| w.eq_ignore_ascii_case("CREATE"))
+        && words
+            .next()
+            .is_some_and(|w| w.eq_ignore_ascii_case("VIRTUAL"));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::btree::Record;
+    use crate::connection::Connection;
+    use crate::datatypes::Value;
+
+    #[test]
+    fn short_records() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        conn.execute("CREATE TABLE t(a, b INTEGER DEFAULT '7', c TEXT)")
+            .unwrap();
+        conn.execute("INSERT INTO t VALUES (3, 1, 'x')").unwrap();
+        // rows written before b and c were added to the table
+        let table = conn.table("t").unwrap();
+        table
+            .btree
+            .insert(2, &Record::from_values(vec![Value::Int64(2)]))
+            .unwrap();
+        table
+            .btree
+            .insert(5, &Record::from_values(vec![Value::Int64(5), Value::Null]))
+            .unwrap();
+
+        let rows = conn.execute("SELECT * FROM t ORDER BY a").unwrap().rows;
+        assert_eq!(
+            rows,
+            [
+                [Value::Int64(2), Value::Int64(7), Value::Null],
+                [
+                    Value::Int64(3),
+                    Value::Int64(1),
+                    Value::String("x".to_string())
+                ],
+                [Value::Int64(5), Value::Null, Value::Null],
+            ]
+        );
+        let rows = conn.execute("SELECT a FROM t WHERE b = 7").unwrap().rows;
+        assert_eq!(rows, [[Value::Int64(2)]]);
+        let rows = conn.execute("SELECT max(b) FROM t").unwrap().rows;
+        assert_eq!(rows, [[Value::Int64(7)]]);
+        conn.execute("CREATE INDEX t_b ON t(b)").unwrap();
+        let rows = conn.execute("SELECT a FROM t WHERE b > 5").unwrap().rows;
+        assert_eq!(rows, [[Value::Int64(2)]]);
+        conn.execute("DELETE FROM t WHERE c IS NULL").unwrap();
+        let problems = crate::integrity::check_indexes(&conn).unwrap();
+        assert!(problems.is_empty(), "{:?}", problems);
+    }
+}
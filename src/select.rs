@@ -0,0 +1,2493 @@
+use eyre::{eyre, Result};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufReader, Read, Write};
+use std::ops::Bound;
+use std::sync::Arc;
+
+use crate::ast::{
+    BinaryOp, ColumnConstraint, ColumnDef, CompoundOperator, CreateTable, Expr, JoinKind,
+    OrderingTerm, ResultColumn, SampleMethod, Select, TableSample,
+};
+use crate::btree::{Btree, Record, SampleRng};
+use crate::connection::Connection;
+use crate::datatypes::{Value, VarInt};
+use crate::ddl;
+use crate::exec::{self, Affinity, Collation, Collations, RowSource};
+use crate::functions;
+use crate::pager::{TempReader, TempRun, TempStore};
+use crate::schema::TableSchema;
+use crate::sorter::{SortOrder, Sorter};
+use crate::statement::QueryResult;
+use crate::table::Table;
+
+/// A table in the FROM clause of a query, along with how it is joined to
+/// the tables before it.
+struct Source {
+    table: Table,
+    alias: Option<String>,
+    kind: JoinKind,
+    on: Option<Expr>,
+    /// How rows are found for each row of the tables before this one, if
+    /// not by scanning the whole table.
+    probe: Option<IndexProbe>,
+    /// An index to scan the table in the order of, if that is the order
+    /// the query asks for.
+    index_scan: Option<IndexScan>,
+    /// The rows of a subquery in FROM, which are read instead of a
+    /// table. The table then only describes the subquery's columns.
+    rows: Option<SubqueryRows>,
+    /// The sample of the table's rows to read instead of all of them,
+    /// from TABLESAMPLE.
+    sample: Option<Sample>,
+    /// The collating sequences the connection has added, which the
+    /// table's columns may name.
+    collations: Collations,
+}
+
+impl Source {
+    /// The name that columns of this table can be qualified with.
+    fn name(&self) -> &str {
+        return match &self.alias {
+            Some(alias) => alias,
+            None => &self.table.schema.name,
+        };
+    }
+
+    fn matches(&self, qualifier: &str) -> bool {
+        return self.name().eq_ignore_ascii_case(qualifier);
+    }
+
+    /// The collating sequence declared for a column of the table, or
+    /// BINARY if none is.
+    fn column_collation(&self, idx: usize) -> Result<Collation> {
+        return self
+            .table
+            .schema
+            .column_collation(idx)
+            .map_or(Ok(Collation::Binary), |name| {
+                Collation::from_name(name, &self.collations)
+            });
+    }
+
+    /// Iterates over the rows of the table, in row ID order. A sampled
+    /// table gives the same sample each time it is scanned in a query.
+    fn scan(&self) -> Box<dyn Iterator<Item = Result<Record>> + '_> {
+        let btree = &self.table.btree;
+        let schema = &self.table.schema;
+        let record = move |row: Result<(VarInt, Record)>| {
+            row.map(|(row_id, record)| schema.with_row_id(row_id.0, record))
+        };
+        return match (&self.rows, &self.sample) {
+            (Some(rows), _) => Box::new(rows.iter()),
+            (None, None) => Box::new(btree.scan().map(record)),
+            (None, Some(sample)) => match sample.method {
+                SampleMethod::System => {
+                    Box::new(btree.sample_pages(sample.fraction, sample.seed).map(record))
+                }
+                SampleMethod::Bernoulli => {
+                    let mut rng = SampleRng::new(sample.seed);
+                    let fraction = sample.fraction;
+                    Box::new(
+                        btree
+                            .scan()
+                            .filter(move |row| row.is_err() || rng.chance(fraction))
+                            .map(record),
+                    )
+                }
+            },
+        };
+    }
+
+    /// Looks up a row of the table by its row ID.
+    fn get_row(&self, row_id: i64) -> Option<Record> {
+        let record = self.table.btree.get_row(VarInt::new(row_id))?;
+        return Some(self.table.schema.with_row_id(row_id, record));
+    }
+}
+
+/// A TABLESAMPLE clause, with its percentage and seed worked out.
+struct Sample {
+    method: SampleMethod,
+    /// The probability of keeping each row or page.
+    fraction: f64,
+    seed: u64,
+}
+
+impl Sample {
+    fn new(sample: &TableSample) -> Result<Self> {
+        let percent = match exec::eval_expr(&sample.percent, &exec::NoRow)? {
+            Value::Float(f) => f,
+            Value::String(s) => s
+                .trim()
+                .parse::<f64>()
+                .map_err(|_| eyre!("datatype mismatch"))?,
+            value => value
+                .get_int_val()
+                .ok_or_else(|| eyre!("datatype mismatch"))? as f64,
+        };
+        if !(0.0..=100.0).contains(&percent) {
+            return Err(eyre!("TABLESAMPLE percentage must be between 0 and 100"));
+        }
+        let seed = match &sample.seed {
+            Some(expr) => {
+                let value = exec::eval_expr(expr, &exec::NoRow)?;
+                match value {
+                    Value::Float(f) => f.to_bits(),
+                    Value::String(s) => s
+                        .bytes()
+                        .fold(0u64, |hash, b| hash.wrapping_mul(31).wrapping_add(b as u64)),
+                    value => value
+                        .get_int_val()
+                        .ok_or_else(|| eyre!("datatype mismatch"))?
+                        as u64,
+                }
+            }
+            None => std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |time| time.as_nanos() as u64),
+        };
+        return Ok(Self {
+            method: sample.method,
+            fraction: percent / 100.0,
+            seed: seed,
+        });
+    }
+}
+
+/// A lookup of the rows of a table through an index on a column the
+/// query compares with other values.
+struct IndexProbe {
+    index: Btree,
+    /// The name of the column looked up.
+    column: String,
+    keys: ProbeKeys,
+    /// Whether the index holds the column in descending order, so that
+    /// the keys are looked up from largest to smallest.
+    descending: bool,
+    /// Whether the rows are produced in the reverse of index order.
+    reverse: bool,
+}
+
+/// The values of the first column of an index that a probe looks up,
+/// which are computed from the tables before the one probed.
+enum ProbeKeys {
+    /// `col = key`
+    Eq(Expr),
+    /// `col IN (list)`. Each distinct value is looked up in turn, in
+    /// index order, so the rows come out in index order.
+    In(Vec<Expr>),
+    /// `col BETWEEN low AND high`, or a comparison of `col` with `<`,
+    /// `<=`, `>` or `>=`, which leaves one end of the range unbounded
+    /// unless another comparison bounds it.
+    Range { low: Bound<Expr>, high: Bound<Expr> },
+}
+
+impl ProbeKeys {
+    /// The expressions the keys are computed from.
+    fn exprs(&self) -> Vec<&Expr> {
+        return match self {
+            ProbeKeys::Eq(key) => vec![key],
+            ProbeKeys::In(list) => list.iter().collect(),
+            ProbeKeys::Range { low, high } => bound_expr(low)
+                .into_iter()
+                .chain(bound_expr(high))
+                .collect(),
+        };
+    }
+
+    /// Whether the keys are a range with only one end bounded, which
+    /// another comparison with the same column could bound at the other.
+    fn is_half_open(&self) -> bool {
+        return match self {
+            ProbeKeys::Range { low, high } => {
+                matches!(low, Bound::Unbounded) != matches!(high, Bound::Unbounded)
+            }
+            _ => false,
+        };
+    }
+
+    /// Bounds the open end of a range with the same end of another, if
+    /// that has it bounded.
+    fn narrow(&mut self, other: ProbeKeys) {
+        if let (
+            ProbeKeys::Range { low, high },
+            ProbeKeys::Range {
+                low: other_low,
+                high: other_high,
+            },
+        ) = (self, other)
+        {
+            if matches!(low, Bound::Unbounded) {
+                *low = other_low;
+            } else if matches!(high, Bound::Unbounded) {
+                *high = other_high;
+            }
+        }
+    }
+}
+
+fn bound_expr(bound: &Bound<Expr>) -> Option<&Expr> {
+    return match bound {
+        Bound::Included(expr) | Bound::Excluded(expr) => Some(expr),
+        Bound::Unbounded => None,
+    };
+}
+
+/// A scan of a whole table in the order of one of its indexes, forwards
+/// or backwards.
+struct IndexScan {
+    index: Btree,
+    reverse: bool,
+}
+
+impl IndexScan {
+    fn row_ids(&self) -> Result<Vec<i64>> {
+        let mut row_ids = Vec::new();
+        // an empty key matches every entry
+        for entry in self.index.index_lookup(&[])? {
+            // the last value of an index entry is the row ID
+            let row_id = entry
+                .values
+                .last()
+                .and_then(|v| v.get_int_val())
+                .ok_or_else(|| eyre!("Malformed index entry in {}", self.index.name))?;
+            row_ids.push(row_id);
+        }
+        if self.reverse {
+            row_ids.reverse();
+        }
+        return Ok(row_ids);
+    }
+}
+
+struct OutputColumn {
+    name: String,
+    expr: Expr,
+}
+
+/// What an ORDER BY term sorts on.
+enum OrderKey<'a> {
+    /// A column of the output, referred to by number (`ORDER BY 2`) or
+    /// by its alias.
+    Output(usize),
+    Expr(&'a Expr),
+}
+
+/// The row currently being produced by a query: one record for each
+/// table joined so far.
+struct JoinedRow<'a> {
+    sources: &'a [Source],
+    records: &'a [Record],
+}
+
+impl RowSource for JoinedRow<'_> {
+    fn column(&self, table: Option<&str>, name: &str) -> Result<Value> {
+        let (source, idx) = resolve_column(self.sources, table, name)?;
+        let record = self
+            .records
+            .get(source)
+            .ok_or_else(|| eyre!("ON clause references tables to its right"))?;
+        return self.sources[source].table.schema.column_value(record, idx);
+    }
+
+    fn column_affinity(&self, table: Option<&str>, name: &str) -> Result<Affinity> {
+        let (source, idx) = resolve_column(self.sources, table, name)?;
+        return Ok(self.sources[source].table.schema.column_affinity(idx));
+    }
+
+    fn column_collation(&self, table: Option<&str>, name: &str) -> Result<Collation> {
+        let (source, idx) = resolve_column(self.sources, table, name)?;
+        return self.sources[source].column_collation(idx);
+    }
+}
+
+/// Finds the table a column reference refers to, returning the position
+/// of the table in the FROM clause and of the column in its records.
+fn resolve_column(sources: &[Source], table: Option<&str>, name: &str) -> Result<(usize, usize)> {
+    let mut found = None;
+    for (i, source) in sources.iter().enumerate() {
+        if let Some(table) = table {
+            if !source.matches(table) {
+                continue;
+            }
+        }
+        if let Some(idx) = source.table.schema.column_index(name) {
+            if found.is_some() {
+                return Err(eyre!("ambiguous column name: {}", name));
+            }
+            found = Some((i, idx));
+        }
+    }
+    return found.ok_or_else(|| match table {
+        Some(table) => eyre!("no such column: {}.{}", table, name),
+        None => eyre!("no such column: {}", name),
+    });
+}
+
+/// Splits an expression into the terms that are ANDed together.
+fn conjuncts(expr: &Expr) -> Vec<&Expr> {
+    return match expr {
+        Expr::Binary {
+            left,
+            op: BinaryOp::And,
+            right,
+        } => {
+            let mut output = conjuncts(left);
+            output.extend(conjuncts(right));
+            output
+        }
+        _ => vec![expr],
+    };
+}
+
+/// How a SELECT statement is run: which tables are read and how, and
+/// what is done with the rows they produce.
+struct QueryPlan<'a> {
+    sources: Vec<Source>,
+    /// Set for the aggregate queries that are answered in one step
+    /// rather than by producing rows.
+    aggregate: Option<Aggregate>,
+    projection: Vec<OutputColumn>,
+    /// The keys the rows are sorted on, which is empty if they are
+    /// produced in order already.
+    order_by: Vec<OrderKey<'a>>,
+    sort_order: Vec<SortOrder>,
+    distinct: Option<Distinct>,
+    limit: Option<usize>,
+    offset: usize,
+}
+
+/// How the duplicate rows of a `SELECT DISTINCT` are left out. Rows are
+/// duplicates if all their columns are equal, using each column's
+/// collating sequence.
+struct Distinct {
+    collations: Vec<Collation>,
+    /// Whether duplicates are found by sorting on every column after the
+    /// ORDER BY terms, which puts them next to each other. Otherwise,
+    /// every row output so far is remembered in memory. Sorting works
+    /// for any number of rows, as the sort can spill to disk, but is only
+    /// used when the rows are being sorted anyway.
+    sorted: bool,
+}
+
+/// An aggregate query over a single table, producing a single value.
+enum Aggregate {
+    /// `count(*)`
+    Count,
+    /// `min(x)` or `max(x)`, where `column` is the position of `x` in the
+    /// table's records. If there is an index to read the value from, it
+    /// is the first non-NULL entry of `index`.
+    MinMax {
+        column: usize,
+        is_max: bool,
+        index: Option<IndexScan>,
+    },
+}
+
+/// Opens the tables in the FROM clause of a query. Subqueries are run,
+/// with their rows held in memory, unless `run` is false (for EXPLAIN
+/// QUERY PLAN), in which case only their columns are worked out.
+fn open_sources(conn: &Connection, select: &Select, run: bool) -> Result<Vec<Source>> {
+    let mut tables = Vec::new();
+    if let Some(from) = &select.from {
+        tables.push((from, JoinKind::Inner, None));
+    }
+    for join in &select.joins {
+        tables.push((&join.table, join.kind, join.on.clone()));
+    }
+
+    let mut sources = Vec::with_capacity(tables.len());
+    for (i, (table_ref, kind, on)) in tables.into_iter().enumerate() {
+        let view = conn.schema.view(&table_ref.name);
+        let (table, rows) = match (&table_ref.subquery, view) {
+            (Some(subquery), _) => {
+                let name = format!("(subquery-{})", i + 1);
+                let (table, rows) = materialize(conn, subquery, name, None, run)?;
+                (table, Some(rows))
+            }
+            // a view is run like a subquery, under its own name
+            (None, Some(view)) => {
+                let (table, rows) = materialize(
+                    conn,
+                    &view.definition.select,
+                    view.name.clone(),
+                    view.definition.columns.as_deref(),
+                    run,
+                )?;
+                (table, Some(rows))
+            }
+            (None, None) => (conn.table(&table_ref.name)?, None),
+        };
+        let sample = match &table_ref.sample {
+            // the rows of a subquery or view are already materialized, so
+            // there would be nothing to gain
+            Some(_) if rows.is_some() => {
+                return Err(eyre!("TABLESAMPLE can only be used on a table"));
+            }
+            Some(sample) => Some(Sample::new(sample)?),
+            None => None,
+        };
+        sources.push(Source {
+            table: table,
+            alias: table_ref.alias.clone(),
+            kind: kind,
+            on: on,
+            probe: None,
+            index_scan: None,
+            rows: rows,
+            sample: sample,
+            collations: conn.collations.clone(),
+        });
+    }
+    return Ok(sources);
+}
+
+/// The rows of a subquery in FROM, kept as records in a `TempStore`, so
+/// that those that do not fit in as many pages as the page cache holds
+/// go to disk.
+struct SubqueryRows {
+    store: Arc<TempStore>,
+    run: TempRun,
+    len: usize,
+}
+
+impl SubqueryRows {
+    fn new(conn: &Connection, rows: Vec<Vec<Value>>) -> Result<Self> {
+        let (page_size, cache_size) = {
+            let pager = conn.pager.read();
+            (pager.page_size(), pager.cache_size())
+        };
+        let mut store = TempStore::new(page_size, cache_size);
+        let len = rows.len();
+        let mut writer = store.writer();
+        for row in rows {
+            // each record is preceded by its length
+            let bytes = Record::from_values(row).serialize();
+            writer.write_all(&(bytes.len() as u32).to_be_bytes())?;
+            writer.write_all(&bytes)?;
+        }
+        let run = writer.finish()?;
+        return Ok(Self {
+            store: Arc::new(store),
+            run: run,
+            len: len,
+        });
+    }
+
+    fn iter(&self) -> impl Iterator<Item = Result<Record>> {
+        let mut reader = BufReader::new(TempReader::new(self.store.clone(), self.run.clone()));
+        let mut left = self.len;
+        return std::iter::from_fn(move || {
+            if left == 0 {
+                return None;
+            }
+            left -= 1;
+            let mut len = [0; 4];
+            if let Err(e) = reader.read_exact(&mut len) {
+                return Some(Err(e.into()));
+            }
+            let mut bytes = vec![0; u32::from_be_bytes(len) as usize];
+            if let Err(e) = reader.read_exact(&mut bytes) {
+                return Some(Err(e.into()));
+            }
+            return Some(Record::deserialize(&bytes));
+        });
+    }
+}
+
+/// Runs a subquery in FROM, or the query of a view, returning a table
+/// that describes its columns along with its rows. A subquery's table is
+/// given a name that no real table can have, so that none of its indexes
+/// are used. A column that is just a column of a table keeps its declared
+/// type and collating sequence, as in SQLite. `columns` renames the
+/// columns, as the column list of a view does.
+fn materialize(
+    conn: &Connection,
+    select: &Select,
+    name: String,
+    columns: Option<&[String]>,
+    run: bool,
+) -> Result<(Table, SubqueryRows)> {
+    // a compound SELECT takes its columns from its first part
+    let core = compound_core(select);
+    let sources = open_sources(conn, &core, false)?;
+    let plan = plan_select(conn, &core, sources)?;
+    let mut names: Vec<String> = plan.projection.iter().map(|col| col.name.clone()).collect();
+    if let Some(columns) = columns {
+        if columns.len() != names.len() {
+            return Err(eyre!(
+                "expected {} columns for '{}' but got {}",
+                columns.len(),
+                name,
+                names.len()
+            ));
+        }
+        names = columns.to_vec();
+    }
+    let mut declared = Vec::with_capacity(names.len());
+    for col in &plan.projection {
+        declared.push(match &col.expr {
+            Expr::Column { table, name } => {
+                let (source, idx) = resolve_column(&plan.sources, table.as_deref(), name)?;
+                let def = &plan.sources[source].table.schema.columns()[idx];
+                let collation: Vec<ColumnConstraint> = def
+                    .constraints
+                    .iter()
+                    .filter(|c| matches!(c, ColumnConstraint::Collate(_)))
+                    .cloned()
+                    .collect();
+                (def.type_name.clone(), collation)
+            }
+            _ => (None, Vec::new()),
+        });
+    }
+    let rows = if run {
+        SubqueryRows::new(conn, execute_select(conn, select)?.rows)?
+    } else {
+        SubqueryRows::new(conn, Vec::new())?
+    };
+
+    // columns with the same name are told apart by a suffix, as in
+    // SQLite
+    let mut columns: Vec<ColumnDef> = Vec::with_capacity(names.len());
+    for (name, (type_name, constraints)) in names.into_iter().zip(declared) {
+        let mut unique = name.clone();
+        let mut n = 0;
+        while columns.iter().any(|c| c.name.eq_ignore_ascii_case(&unique)) {
+            n += 1;
+            unique = format!("{}:{}", name, n);
+        }
+        columns.push(ColumnDef {
+            name: unique,
+            type_name: type_name,
+            constraints: constraints,
+        });
+    }
+    let schema = TableSchema {
+        name: name.clone(),
+        root_page: 0,
+        sql: String::new(),
+        definition: CreateTable {
+            name: name,
+            if_not_exists: false,
+            columns: columns,
+            constraints: Vec::new(),
+            without_rowid: false,
+        },
+    };
+    return Ok((Table::new(schema, conn.pager.clone()), rows));
+}
+
+/// Decides how a SELECT statement is to be run, checking that the
+/// tables, columns, and functions it refers to exist.
+fn plan_select<'a>(
+    conn: &Connection,
+    select: &'a Select,
+    mut sources: Vec<Source>,
+) -> Result<QueryPlan<'a>> {
+    if let Some((aggregate, column)) = plan_aggregate(conn, select, &sources)? {
+        let (limit, offset) = eval_limits(select)?;
+        return Ok(QueryPlan {
+            sources: sources,
+            aggregate: Some(aggregate),
+            projection: vec![column],
+            order_by: Vec::new(),
+            sort_order: Vec::new(),
+            distinct: None,
+            limit: limit,
+            offset: offset,
+        });
+    }
+
+    let projection = resolve_columns(&select.columns, &sources)?;
+    let order_by = resolve_order_by(&select.order_by, &projection)?;
+
+    // check every column reference and function call up front, so that
+    // errors are reported even if no rows are read
+    let mut exprs: Vec<&Expr> = projection.iter().map(|col| &col.expr).collect();
+    exprs.extend(sources.iter().filter_map(|source| source.on.as_ref()));
+    exprs.extend(select.where_clause.as_ref());
+    for term in &order_by {
+        if let OrderKey::Expr(expr) = term {
+            exprs.push(expr);
+        }
+    }
+    let mut refs = Vec::new();
+    for expr in exprs {
+        exec::column_refs(expr, &mut refs);
+        functions::check_calls(expr)?;
+    }
+    for (table, name) in refs {
+        resolve_column(&sources, table, name)?;
+    }
+
+    plan_index_probes(conn, &mut sources, select.where_clause.as_ref())?;
+
+    let (limit, offset) = eval_limits(select)?;
+    let mut sort_order = Vec::with_capacity(order_by.len());
+    for (term, key) in select.order_by.iter().zip(order_by.iter()) {
+        sort_order.push(SortOrder {
+            descending: term.descending,
+            collation: term_collation(term, key, &projection, &sources, &conn.collations)?,
+        });
+    }
+    // if the rows can be read in order, there is nothing to sort
+    let mut order_by =
+        if plan_ordered_scan(conn, &mut sources, &order_by, &sort_order, &projection)? {
+            Vec::new()
+        } else {
+            order_by
+        };
+
+    let mut distinct = None;
+    if select.distinct {
+        let mut collations = Vec::with_capacity(projection.len());
+        for i in 0..projection.len() {
+            collations.push(key_collation(&OrderKey::Output(i), &projection, &sources)?);
+        }
+        // if the rows are sorted on output columns, with their own
+        // collating sequences, sorting on the rest of the columns as
+        // well puts duplicates next to each other
+        let sorted = !order_by.is_empty()
+            && order_by
+                .iter()
+                .zip(sort_order.iter())
+                .all(|(key, order)| match key {
+                    OrderKey::Output(i) => order.collation == collations[*i],
+                    OrderKey::Expr(_) => false,
+                });
+        if sorted {
+            for (i, collation) in collations.iter().enumerate() {
+                order_by.push(OrderKey::Output(i));
+                sort_order.push(SortOrder {
+                    descending: false,
+                    collation: collation.clone(),
+                });
+            }
+        }
+        distinct = Some(Distinct {
+            collations: collations,
+            sorted: sorted,
+        });
+    }
+    return Ok(QueryPlan {
+        sources: sources,
+        aggregate: None,
+        projection: projection,
+        order_by: order_by,
+        sort_order: sort_order,
+        distinct: distinct,
+        limit: limit,
+        offset: offset,
+    });
+}
+
+/// Runs a SELECT statement.
+pub fn execute_select(conn: &Connection, select: &Select) -> Result<QueryResult> {
+    if !select.compound.is_empty() {
+        return execute_compound(conn, select);
+    }
+
+    // subqueries in expressions cannot refer to the tables of this
+    // query, so each only needs to be run once, before anything else
+    let mut subqueries = Vec::new();
+    for expr in exprs(select) {
+        exec::subqueries(expr, &mut subqueries);
+    }
+    let resolved;
+    let select = if subqueries.is_empty() {
+        select
+    } else {
+        let mut copy = select.clone();
+        for expr in exprs_mut(&mut copy) {
+            eval_subqueries(conn, expr)?;
+        }
+        resolved = copy;
+        &resolved
+    };
+
+    let sources = open_sources(conn, select, true)?;
+    let QueryPlan {
+        sources,
+        aggregate,
+        projection,
+        order_by,
+        sort_order,
+        distinct,
+        limit,
+        offset,
+    } = plan_select(conn, select, sources)?;
+
+    if let Some(aggregate) = aggregate {
+        let where_clause = select.where_clause.as_ref();
+        let value = match aggregate {
+            Aggregate::Count => Value::Int64(count(&sources, where_clause)? as i64),
+            Aggregate::MinMax {
+                column,
+                is_max,
+                index,
+            } => min_max(&sources, column, is_max, index.as_ref(), where_clause)?,
+        };
+        let rows = if limit == Some(0) || offset > 0 {
+            Vec::new()
+        } else {
+            vec![vec![value]]
+        };
+        return Ok(QueryResult {
+            columns: projection.into_iter().map(|col| col.name).collect(),
+            rows: rows,
+        });
+    }
+
+    let mut sorter = Sorter::new(sort_order, conn.sort_memory);
+
+    let mut rows = Vec::new();
+    let mut skipped = 0;
+    let mut seen = HashSet::new();
+    let mut visit = |records: &[Record]| -> Result<bool> {
+        let row_source = JoinedRow {
+            sources: &sources,
+            records: records,
+        };
+        if let Some(where_clause) = &select.where_clause {
+            if exec::truth_value(&exec::eval_expr(where_clause, &row_source)?) != Some(true) {
+                return Ok(true);
+            }
+        }
+        let mut row = Vec::with_capacity(projection.len());
+        for col in &projection {
+            row.push(exec::eval_expr(&col.expr, &row_source)?);
+        }
+        if let Some(distinct) = &distinct {
+            if !distinct.sorted && !seen.insert(distinct_key(&row, &distinct.collations)) {
+                return Ok(true);
+            }
+        }
+        if order_by.is_empty() {
+            if skipped < offset {
+                skipped += 1;
+            } else {
+                rows.push(row);
+            }
+            // without a sort, rows come out in scan order and the scan
+            // can stop as soon as the limit is reached
+            return Ok(limit.is_none_or(|limit| rows.len() < limit));
+        }
+        let mut key = Vec::new();
+        for term in &order_by {
+            key.push(match term {
+                OrderKey::Output(i) => row[*i].clone(),
+                OrderKey::Expr(expr) => exec::eval_expr(expr, &row_source)?,
+            });
+        }
+        sorter.push(key, row)?;
+        return Ok(true);
+    };
+    if limit != Some(0) {
+        scan_level(&sources, 0, &mut Vec::new(), &mut visit)?;
+    }
+
+    if !order_by.is_empty() {
+        let mut previous: Option<Vec<Value>> = None;
+        for row in sorter.finish()? {
+            if limit.is_some_and(|limit| rows.len() >= limit) {
+                break;
+            }
+            let row = row?;
+            if let Some(Distinct {
+                collations,
+                sorted: true,
+            }) = &distinct
+            {
+                if previous
+                    .as_ref()
+                    .is_some_and(|previous| rows_equal(previous, &row, collations))
+                {
+                    continue;
+                }
+                previous = Some(row.clone());
+            }
+            if skipped < offset {
+                skipped += 1;
+            } else {
+                rows.push(row);
+            }
+        }
+    }
+    return Ok(QueryResult {
+        columns: projection.into_iter().map(|col| col.name).collect(),
+        rows: rows,
+    });
+}
+
+/// Runs a compound SELECT, combining the rows of its parts from left to
+/// right, then sorting and limiting the result as its ORDER BY and LIMIT
+/// ask for.
+fn execute_compound(conn: &Connection, select: &Select) -> Result<QueryResult> {
+    let core = compound_core(select);
+    let collations = compound_collations(conn, &core)?;
+    let result = execute_select(conn, &core)?;
+    let columns = result.columns;
+    let mut rows = result.rows;
+    // ORDER BY terms may name the columns of any part
+    let mut names = vec![columns.clone()];
+    for part in &select.compound {
+        let result = execute_select(conn, &part.select)?;
+        if result.columns.len() != columns.len() {
+            return Err(eyre!(
+                "SELECTs to the left and right of {} do not have the same number of result columns",
+                part.operator.name()
+            ));
+        }
+        names.push(result.columns);
+        rows = combine_rows(
+            conn,
+            part.operator,
+            rows,
+            result.rows,
+            &collations,
+            select.order_by.is_empty(),
+        )?;
+    }
+
+    let mut keys = Vec::with_capacity(select.order_by.len());
+    let mut sort_order = Vec::with_capacity(select.order_by.len());
+    for (i, term) in select.order_by.iter().enumerate() {
+        let idx = compound_order_key(i, term, &names)?;
+        keys.push(idx);
+        sort_order.push(SortOrder {
+            descending: term.descending,
+            collation: match &term.collation {
+                Some(name) => Collation::from_name(name, &conn.collations)?,
+                None => collations[idx].clone(),
+            },
+        });
+    }
+    if !keys.is_empty() {
+        rows = sort_rows(conn, rows, &keys, sort_order)?;
+    }
+    let (limit, offset) = eval_limits(select)?;
+    let rows = rows
+        .into_iter()
+        .skip(offset)
+        .take(limit.unwrap_or(usize::MAX))
+        .collect();
+    return Ok(QueryResult {
+        columns: columns,
+        rows: rows,
+    });
+}
+
+/// The first part of a compound SELECT, without the ORDER BY and LIMIT
+/// that apply to the whole.
+fn compound_core(select: &Select) -> Select {
+    return Select {
+        compound: Vec::new(),
+        order_by: Vec::new(),
+        limit: None,
+        offset: None,
+        ..select.clone()
+    };
+}
+
+/// The collating sequences that a compound SELECT compares each column
+/// with, which are those of the columns of its first part.
+fn compound_collations(conn: &Connection, core: &Select) -> Result<Vec<Collation>> {
+    let sources = open_sources(conn, core, false)?;
+    let plan = plan_select(conn, core, sources)?;
+    let mut collations = Vec::with_capacity(plan.projection.len());
+    for i in 0..plan.projection.len() {
+        collations.push(key_collation(
+            &OrderKey::Output(i),
+            &plan.projection,
+            &plan.sources,
+        )?);
+    }
+    return Ok(collations);
+}
+
+/// Combines the rows of the parts of a compound SELECT on either side of
+/// an operator. Except for UNION ALL, duplicate rows are left out and the
+/// rows come out sorted, as they do in SQLite. Of rows that are duplicates
+/// only by their collating sequences, SQLite keeps the last, or the first
+/// if there is an ORDER BY; `keep_last` chooses which.
+fn combine_rows(
+    conn: &Connection,
+    operator: CompoundOperator,
+    left: Vec<Vec<Value>>,
+    right: Vec<Vec<Value>>,
+    collations: &[Collation],
+    keep_last: bool,
+) -> Result<Vec<Vec<Value>>> {
+    let (candidates, right_keys) = match operator {
+        CompoundOperator::UnionAll => {
+            let mut rows = left;
+            rows.extend(right);
+            return Ok(rows);
+        }
+        CompoundOperator::Union => {
+            let mut rows = left;
+            rows.extend(right);
+            (rows, HashSet::new())
+        }
+        CompoundOperator::Intersect | CompoundOperator::Except => {
+            let keys = right
+                .iter()
+                .map(|row| distinct_key(row, collations))
+                .collect::<HashSet<_>>();
+            (left, keys)
+        }
+    };
+    let mut seen = HashMap::new();
+    let mut rows = Vec::new();
+    for row in candidates {
+        let key = distinct_key(&row, collations);
+        let keep = match operator {
+            CompoundOperator::Intersect => right_keys.contains(&key),
+            CompoundOperator::Except => !right_keys.contains(&key),
+            _ => true,
+        };
+        if !keep {
+            continue;
+        }
+        match seen.get(&key) {
+            Some(idx) if keep_last => rows[*idx] = row,
+            Some(_) => (),
+            None => {
+                seen.insert(key, rows.len());
+                rows.push(row);
+            }
+        }
+    }
+    let keys: Vec<usize> = (0..collations.len()).collect();
+    let sort_order = collations
+        .iter()
+        .map(|collation| SortOrder {
+            descending: false,
+            collation: collation.clone(),
+        })
+        .collect();
+    return sort_rows(conn, rows, &keys, sort_order);
+}
+
+/// Sorts rows on some of their columns.
+fn sort_rows(
+    conn: &Connection,
+    rows: Vec<Vec<Value>>,
+    keys: &[usize],
+    sort_order: Vec<SortOrder>,
+) -> Result<Vec<Vec<Value>>> {
+    let mut sorter = Sorter::new(sort_order, conn.sort_memory);
+    for row in rows {
+        let key = keys.iter().map(|idx| row[*idx].clone()).collect();
+        sorter.push(key, row)?;
+    }
+    return sorter.finish()?.collect();
+}
+
+/// Resolves an ORDER BY term of a compound SELECT, which must be either
+/// the number of a result column or the name of one in any of its parts,
+/// to the index of that column.
+fn compound_order_key(i: usize, term: &OrderingTerm, names: &[Vec<String>]) -> Result<usize> {
+    let num_columns = names[0].len();
+    match &term.expr {
+        Expr::Literal(value) if value.get_int_val().is_some() => {
+            let n = value.get_int_val().unwrap();
+            if n < 1 || n as usize > num_columns {
+                return Err(eyre!(
+                    "{} ORDER BY term out of range - should be between 1 and {}",
+                    ordinal(i + 1),
+                    num_columns
+                ));
+            }
+            return Ok(n as usize - 1);
+        }
+        Expr::Column { table: None, name } => {
+            for columns in names {
+                if let Some(idx) = columns
+                    .iter()
+                    .position(|col| col.eq_ignore_ascii_case(name))
+                {
+                    return Ok(idx);
+                }
+            }
+        }
+        _ => {}
+    }
+    return Err(eyre!(
+        "{} ORDER BY term does not match any column in the result set",
+        ordinal(i + 1)
+    ));
+}
+
+/// A key that is the same for rows that `SELECT DISTINCT` treats as
+/// duplicates: numbers that are equal are written the same way, whatever
+/// their type, and text is normalized by its collating sequence.
+fn distinct_key(row: &[Value], collations: &[Collation]) -> Vec<u8> {
+    let values = row
+        .iter()
+        .zip(collations.iter())
+        .map(|(value, collation)| match value {
+            Value::String(text) => Value::String(collation.normalize(text)),
+            Value::Float(f)
+                if f.fract() == 0.0 && (i64::MIN as f64..i64::MAX as f64).contains(f) =>
+            {
+                Value::from_int(*f as i64)
+            }
+            value => match value.get_int_val() {
+                Some(i) => Value::from_int(i),
+                None => value.clone(),
+            },
+        })
+        .collect();
+    return Record::from_values(values).serialize();
+}
+
+/// Tests whether two rows are duplicates, for `SELECT DISTINCT`.
+fn rows_equal(left: &[Value], right: &[Value], collations: &[Collation]) -> bool {
+    return left
+        .iter()
+        .zip(right.iter())
+        .zip(collations.iter())
+        .all(|((l, r), collation)| collation.compare(l, r) == Ordering::Equal);
+}
+
+/// Describes how a SELECT statement would be run, as the depths and
+/// details of the rows of `EXPLAIN QUERY PLAN`: one for each subquery in
+/// FROM, which is run first, then one for each table, in the order they
+/// are read, one for the sort, if the rows need sorting, and one for
+/// each subquery in an expression. The steps of running a subquery
+/// follow it, one level deeper.
+pub fn explain_select(conn: &Connection, select: &Select) -> Result<Vec<(usize, String)>> {
+    let mut details = Vec::new();
+    let nested = |details: &mut Vec<(usize, String)>, subquery: &Select| -> Result<()> {
+        for (depth, detail) in explain_select(conn, subquery)? {
+            details.push((depth + 1, detail));
+        }
+        return Ok(());
+    };
+
+    // the parts of a compound SELECT are run one after another
+    if !select.compound.is_empty() {
+        let mut parts = Vec::new();
+        parts.push(("LEFT-MOST SUBQUERY".to_string(), compound_core(select)));
+        for part in &select.compound {
+            let detail = match part.operator {
+                CompoundOperator::UnionAll => "UNION ALL".to_string(),
+                operator => format!("{} USING TEMP B-TREE", operator.name()),
+            };
+            parts.push((detail, part.select.clone()));
+        }
+        details.push((0, "COMPOUND QUERY".to_string()));
+        for (detail, part) in parts {
+            details.push((1, detail));
+            for (depth, detail) in explain_select(conn, &part)? {
+                details.push((depth + 2, detail));
+            }
+        }
+        if !select.order_by.is_empty() {
+            details.push((0, "USE TEMP B-TREE FOR ORDER BY".to_string()));
+        }
+        return Ok(details);
+    }
+
+    let sources = open_sources(conn, select, false)?;
+    let plan = plan_select(conn, select, sources)?;
+
+    let tables = select
+        .from
+        .iter()
+        .chain(select.joins.iter().map(|join| &join.table));
+    for (table_ref, source) in tables.zip(plan.sources.iter()) {
+        let view = conn.schema.view(&table_ref.name);
+        let subquery = match (&table_ref.subquery, view) {
+            (Some(subquery), _) => subquery.as_ref(),
+            (None, Some(view)) => &view.definition.select,
+            (None, None) => continue,
+        };
+        details.push((0, format!("MATERIALIZE {}", source.name())));
+        nested(&mut details, subquery)?;
+    }
+    if plan.sources.is_empty() {
+        details.push((0, "SCAN CONSTANT ROW".to_string()));
+    }
+    for source in &plan.sources {
+        let mut detail = if let Some(Aggregate::MinMax {
+            index: Some(scan), ..
+        }) = &plan.aggregate
+        {
+            format!(
+                "SEARCH {} USING COVERING INDEX {}",
+                source.name(),
+                scan.index.name
+            )
+        } else if let Some(probe) = &source.probe {
+            let constraint = match probe.keys {
+                ProbeKeys::Eq(_) | ProbeKeys::In(_) => format!("{}=?", probe.column),
+                ProbeKeys::Range { ref low, ref high } => match (low, high) {
+                    (Bound::Unbounded, _) => format!("{}<?", probe.column),
+                    (_, Bound::Unbounded) => format!("{}>?", probe.column),
+                    _ => format!("{0}>? AND {0}<?", probe.column),
+                },
+            };
+            format!(
+                "SEARCH {} USING INDEX {} ({})",
+                source.name(),
+                probe.index.name,
+                constraint
+            )
+        } else if let Some(scan) = &source.index_scan {
+            format!("SCAN {} USING INDEX {}", source.name(), scan.index.name)
+        } else {
+            format!("SCAN {}", source.name())
+        };
+        if source.kind == JoinKind::Left {
+            detail.push_str(" LEFT-JOIN");
+        }
+        details.push((0, detail));
+    }
+    // sorting to find duplicates sorts the rows as well
+    if plan.distinct.is_some() {
+        details.push((0, "USE TEMP B-TREE FOR DISTINCT".to_string()));
+    }
+    if !plan.order_by.is_empty() && !plan.distinct.as_ref().is_some_and(|d| d.sorted) {
+        details.push((0, "USE TEMP B-TREE FOR ORDER BY".to_string()));
+    }
+
+    let mut subqueries = Vec::new();
+    for expr in exprs(select) {
+        exec::subqueries(expr, &mut subqueries);
+    }
+    for (i, (subquery, is_list)) in subqueries.into_iter().enumerate() {
+        let kind = if is_list { "LIST" } else { "SCALAR" };
+        details.push((0, format!("{} SUBQUERY {}", kind, i + 1)));
+        nested(&mut details, subquery)?;
+    }
+    return Ok(details);
+}
+
+/// The expressions of a query, not counting those of its subqueries in
+/// FROM.
+fn exprs(select: &Select) -> Vec<&Expr> {
+    let mut output = Vec::new();
+    for col in &select.columns {
+        if let ResultColumn::Expr { expr, .. } = col {
+            output.push(expr);
+        }
+    }
+    output.extend(select.joins.iter().filter_map(|join| join.on.as_ref()));
+    output.extend(select.where_clause.iter());
+    output.extend(select.order_by.iter().map(|term| &term.expr));
+    output.extend(select.limit.iter());
+    output.extend(select.offset.iter());
+    let tables = select
+        .from
+        .iter()
+        .chain(select.joins.iter().map(|j| &j.table));
+    for sample in tables.filter_map(|table| table.sample.as_ref()) {
+        output.push(&sample.percent);
+        output.extend(sample.seed.iter());
+    }
+    return output;
+}
+
+/// The expressions of a query, as in `exprs`, for modifying them.
+pub fn exprs_mut(select: &mut Select) -> Vec<&mut Expr> {
+    let mut output = Vec::new();
+    for col in select.columns.iter_mut() {
+        if let ResultColumn::Expr { expr, .. } = col {
+            output.push(expr);
+        }
+    }
+    let mut samples: Vec<&mut TableSample> = Vec::new();
+    samples.extend(select.from.as_mut().and_then(|table| table.sample.as_mut()));
+    for join in select.joins.iter_mut() {
+        output.extend(join.on.as_mut());
+        samples.extend(join.table.sample.as_mut());
+    }
+    output.extend(select.where_clause.iter_mut());
+    output.extend(select.order_by.iter_mut().map(|term| &mut term.expr));
+    output.extend(select.limit.iter_mut());
+    output.extend(select.offset.iter_mut());
+    for sample in samples {
+        output.push(&mut sample.percent);
+        output.extend(sample.seed.iter_mut());
+    }
+    return output;
+}
+
+/// Runs a subquery that is used as a value or a list of values, which
+/// must have a single column.
+fn run_subquery(conn: &Connection, select: &Select) -> Result<QueryResult> {
+    let result = execute_select(conn, select)?;
+    if result.columns.len() != 1 {
+        return Err(eyre!(
+            "sub-select returns {} columns - expected 1",
+            result.columns.len()
+        ));
+    }
+    return Ok(result);
+}
+
+/// Tests whether a subquery has any rows, for EXISTS. Unless the subquery
+/// has a LIMIT or OFFSET of its own, the order of its rows makes no
+/// difference, so it is run without its ORDER BY and DISTINCT, stopping
+/// at the first row.
+fn subquery_has_rows(conn: &Connection, select: &Select) -> Result<bool> {
+    if select.limit.is_some() || select.offset.is_some() {
+        return Ok(!execute_select(conn, select)?.rows.is_empty());
+    }
+    let mut select = select.clone();
+    select.distinct = false;
+    select.order_by.clear();
+    select.limit = Some(Expr::Literal(Value::Int64(1)));
+    return Ok(!execute_select(conn, &select)?.rows.is_empty());
+}
+
+/// Runs each subquery in an expression, replacing a scalar subquery with
+/// its value and the subquery of an IN with the list of its values.
+pub fn eval_subqueries(conn: &Connection, expr: &mut Expr) -> Result<()> {
+    match expr {
+        Expr::Subquery(select) => {
+            let result = run_subquery(conn, select)?;
+            let value = result
+                .rows
+                .into_iter()
+                .next()
+                .and_then(|row| row.into_iter().next())
+                .unwrap_or(Value::Null);
+            *expr = Expr::Literal(value);
+        }
+        Expr::Exists(select) => {
+            let value = if subquery_has_rows(conn, select)? {
+                Value::Integer1
+            } else {
+                Value::Integer0
+            };
+            *expr = Expr::Literal(value);
+        }
+        Expr::Literal(_) | Expr::Parameter(_) | Expr::Column { .. } => (),
+        Expr::Unary { expr, .. } | Expr::IsNull { expr, .. } | Expr::Cast { expr, .. } => {
+            eval_subqueries(conn, expr)?
+        }
+        Expr::Binary { left, right, .. } => {
+            eval_subqueries(conn, left)?;
+            eval_subqueries(conn, right)?;
+        }
+        Expr::Like {
+            expr,
+            pattern,
+            escape,
+            ..
+        } => {
+            eval_subqueries(conn, expr)?;
+            eval_subqueries(conn, pattern)?;
+            if let Some(escape) = escape {
+                eval_subqueries(conn, escape)?;
+            }
+        }
+        Expr::Between {
+            expr, low, high, ..
+        } => {
+            eval_subqueries(conn, expr)?;
+            eval_subqueries(conn, low)?;
+            eval_subqueries(conn, high)?;
+        }
+        Expr::In {
+            expr,
+            list,
+            subquery,
+            ..
+        } => {
+            eval_subqueries(conn, expr)?;
+            for item in list.iter_mut() {
+                eval_subqueries(conn, item)?;
+            }
+            if let Some(select) = subquery.take() {
+                let result = run_subquery(conn, &select)?;
+                list.extend(
+                    result
+                        .rows
+                        .into_iter()
+                        .filter_map(|row| row.into_iter().next())
+                        .map(Expr::Literal),
+                );
+            }
+        }
+        Expr::Function { args, .. } => {
+            for arg in args.iter_mut() {
+                eval_subqueries(conn, arg)?;
+            }
+        }
+    }
+    return Ok(());
+}
+
+/// Produces the rows of the join from table `level` onwards, for the
+/// records of the tables before it, passing each complete row to
+/// `visit`. Returns false if `visit` asked to stop.
+fn scan_level(
+    sources: &[Source],
+    level: usize,
+    records: &mut Vec<Record>,
+    visit: &mut dyn FnMut(&[Record]) -> Result<bool>,
+) -> Result<bool> {
+    let source = match sources.get(level) {
+        Some(source) => source,
+        None => return visit(records),
+    };
+
+    let mut matched = false;
+    let mut visit_record = |record: Record, records: &mut Vec<Record>| -> Result<bool> {
+        records.push(record);
+        let row_source = JoinedRow {
+            sources: sources,
+            records: records,
+        };
+        let keep_going = match &source.on {
+            Some(on) if exec::truth_value(&exec::eval_expr(on, &row_source)?) != Some(true) => true,
+            _ => {
+                matched = true;
+                scan_level(sources, level + 1, records, visit)?
+            }
+        };
+        records.pop();
+        return Ok(keep_going);
+    };
+
+    if let Some(probe) = &source.probe {
+        for record in probe_rows(source, probe, sources, records)? {
+            if !visit_record(record, records)? {
+                return Ok(false);
+            }
+        }
+    } else if let Some(scan) = &source.index_scan {
+        for row_id in scan.row_ids()? {
+            if let Some(record) = source.get_row(row_id) {
+                if !visit_record(record, records)? {
+                    return Ok(false);
+                }
+            }
+        }
+    } else {
+        for record in source.scan() {
+            if !visit_record(record?, records)? {
+                return Ok(false);
+            }
+        }
+    }
+
+    if !matched && source.kind == JoinKind::Left {
+        // no rows matched, so the row is extended with NULLs
+        records.push(Record::new(Vec::new(), Vec::new()));
+        let keep_going = scan_level(sources, level + 1, records, visit)?;
+        records.pop();
+        return Ok(keep_going);
+    }
+    return Ok(true);
+}
+
+/// Looks up the rows of a table that match the current rows of the
+/// tables before it, using an index.
+fn probe_rows(
+    source: &Source,
+    probe: &IndexProbe,
+    sources: &[Source],
+    records: &[Record],
+) -> Result<Vec<Record>> {
+    let row_source = JoinedRow {
+        sources: sources,
+        records: records,
+    };
+    // keys are converted as comparing them with the column would
+    let schema = &source.table.schema;
+    let column_affinity = schema
+        .column_index(&probe.column)
+        .map(|idx| schema.column_affinity(idx));
+    let eval_key = |key: &Expr, key_affinity: Option<Affinity>| -> Result<Value> {
+        let value = exec::eval_expr(key, &row_source)?;
+        return Ok(exec::coerce_operands(Value::Null, column_affinity, value, key_affinity).1);
+    };
+    let mut entries = Vec::new();
+    match &probe.keys {
+        ProbeKeys::Eq(key) => {
+            let key = eval_key(key, exec::expr_affinity(key, &row_source)?)?;
+            // NULL is never equal to anything
+            if !matches!(key, Value::Null) {
+                entries = probe.index.index_lookup(&[key])?;
+            }
+        }
+        ProbeKeys::In(list) => {
+            let mut keys = Vec::with_capacity(list.len());
+            for key in list {
+                let key = eval_key(key, None)?;
+                if !matches!(key, Value::Null) {
+                    keys.push(key);
+                }
+            }
+            keys.sort_by(exec::compare);
+            keys.dedup_by(|a, b| exec::compare(a, b) == Ordering::Equal);
+            if probe.descending {
+                keys.reverse();
+            }
+            let keys: Vec<Vec<Value>> = keys.into_iter().map(|key| vec![key]).collect();
+            entries = probe.index.index_lookup_keys(&keys)?;
+        }
+        ProbeKeys::Range { low, high } => {
+            let eval_bound = |bound: &Bound<Expr>| -> Result<Option<Bound<Record>>> {
+                let (key, included) = match bound {
+                    Bound::Included(key) => (key, true),
+                    Bound::Excluded(key) => (key, false),
+                    Bound::Unbounded => return Ok(Some(Bound::Unbounded)),
+                };
+                let key = eval_key(key, exec::expr_affinity(key, &row_source)?)?;
+                // a comparison with NULL is never true
+                if matches!(key, Value::Null) {
+                    return Ok(None);
+                }
+                let key = Record::from_values(vec![key]);
+                return Ok(Some(if included {
+                    Bound::Included(key)
+                } else {
+                    Bound::Excluded(key)
+                }));
+            };
+            if let (Some(low), Some(high)) = (eval_bound(low)?, eval_bound(high)?) {
+                // NULLs sort first, and are never in the range
+                let low = match low {
+                    Bound::Unbounded => Bound::Excluded(Record::from_values(vec![Value::Null])),
+                    low => low,
+                };
+                // a descending index holds the range from its high end
+                entries = if probe.descending {
+                    probe.index.index_range(high, low)
+                } else {
+                    probe.index.index_range(low, high)
+                }
+                .collect::<Result<_>>()?;
+            }
+        }
+    }
+    if probe.reverse {
+        entries.reverse();
+    }
+
+    let mut output = Vec::with_capacity(entries.len());
+    for entry in entries {
+        // the last value of an index entry is the row ID
+        let row_id = entry
+            .values
+            .last()
+            .and_then(|v| v.get_int_val())
+            .ok_or_else(|| eyre!("Malformed index entry in {}", probe.index.name))?;
+        if let Some(record) = source.get_row(row_id) {
+            output.push(record);
+        }
+    }
+    return Ok(output);
+}
+
+/// Chooses, for each table, whether its rows can be found through an
+/// index rather than by scanning the whole table: that is the case when
+/// the join condition (or, for inner joins, the WHERE clause) compares
+/// the first column of an index with expressions over the tables before
+/// it, using `=`, `IN`, `BETWEEN`, `<`, `<=`, `>` or `>=`. A comparison
+/// that bounds one end of a range can be joined by a later one that
+/// bounds the other.
+fn plan_index_probes(
+    conn: &Connection,
+    sources: &mut [Source],
+    where_clause: Option<&Expr>,
+) -> Result<()> {
+    for level in 0..sources.len() {
+        // probing would read rows from outside the sample
+        if sources[level].sample.is_some() {
+            continue;
+        }
+        let mut terms = Vec::new();
+        if let Some(on) = &sources[level].on {
+            terms.extend(conjuncts(on));
+        }
+        if sources[level].kind != JoinKind::Left {
+            if let Some(where_clause) = where_clause {
+                terms.extend(conjuncts(where_clause));
+            }
+        }
+
+        let mut probe: Option<IndexProbe> = None;
+        for term in terms {
+            let candidates = match term {
+                Expr::Binary {
+                    left,
+                    op: BinaryOp::Eq,
+                    right,
+                } => vec![
+                    (left.as_ref(), ProbeKeys::Eq(right.as_ref().clone())),
+                    (right.as_ref(), ProbeKeys::Eq(left.as_ref().clone())),
+                ],
+                Expr::In {
+                    expr,
+                    list,
+                    negated: false,
+                    ..
+                } => vec![(expr.as_ref(), ProbeKeys::In(list.clone()))],
+                Expr::Between {
+                    expr,
+                    low,
+                    high,
+                    negated: false,
+                } => vec![(
+                    expr.as_ref(),
+                    ProbeKeys::Range {
+                        low: Bound::Included(low.as_ref().clone()),
+                        high: Bound::Included(high.as_ref().clone()),
+                    },
+                )],
+                Expr::Binary { left, op, right }
+                    if matches!(
+                        op,
+                        BinaryOp::Lt | BinaryOp::LtEq | BinaryOp::Gt | BinaryOp::GtEq
+                    ) =>
+                {
+                    let (left, right) = (left.as_ref(), right.as_ref());
+                    // the bound the right-hand side puts on the left-hand
+                    // side, and the other way around
+                    let (low, high) = match op {
+                        BinaryOp::Lt => (Bound::Unbounded, Bound::Excluded(right.clone())),
+                        BinaryOp::LtEq => (Bound::Unbounded, Bound::Included(right.clone())),
+                        BinaryOp::Gt => (Bound::Excluded(right.clone()), Bound::Unbounded),
+                        _ => (Bound::Included(right.clone()), Bound::Unbounded),
+                    };
+                    let (flipped_low, flipped_high) = match op {
+                        BinaryOp::Lt => (Bound::Excluded(left.clone()), Bound::Unbounded),
+                        BinaryOp::LtEq => (Bound::Included(left.clone()), Bound::Unbounded),
+                        BinaryOp::Gt => (Bound::Unbounded, Bound::Excluded(left.clone())),
+                        _ => (Bound::Unbounded, Bound::Included(left.clone())),
+                    };
+                    vec![
+                        (left, ProbeKeys::Range { low, high }),
+                        (
+                            right,
+                            ProbeKeys::Range {
+                                low: flipped_low,
+                                high: flipped_high,
+                            },
+                        ),
+                    ]
+                }
+                _ => continue,
+            };
+            for (column, keys) in candidates {
+                let (table, name) = match column {
+                    Expr::Column { table, name } => (table.as_deref(), name),
+                    _ => continue,
+                };
+                let (table_num, idx) = resolve_column(sources, table, name)?;
+                if table_num != level {
+                    continue;
+                }
+                // the keys must be computable before this table is read
+                let mut refs = Vec::new();
+                for key in keys.exprs() {
+                    exec::column_refs(key, &mut refs);
+                }
+                let mut usable = true;
+                for (table, name) in refs {
+                    if resolve_column(sources, table, name)?.0 >= level {
+                        usable = false;
+                    }
+                }
+                // nor can the comparison convert the column's values, as
+                // the index is ordered by their unconverted values
+                let row_source = JoinedRow {
+                    sources: sources,
+                    records: &[],
+                };
+                let column_affinity = sources[level].table.schema.column_affinity(idx);
+                if !column_affinity.is_numeric() {
+                    let mut key_affinities = Vec::new();
+                    if !matches!(keys, ProbeKeys::In(_)) {
+                        for key in keys.exprs() {
+                            key_affinities.push(exec::expr_affinity(key, &row_source)?);
+                        }
+                    }
+                    let converted = key_affinities.into_iter().any(|affinity| {
+                        exec::comparison_affinity(Some(column_affinity), affinity)
+                            .is_some_and(|a| a.is_numeric())
+                    });
+                    if converted {
+                        usable = false;
+                    }
+                }
+                if !usable {
+                    continue;
+                }
+                // and the index has to order text the way the comparison
+                // compares it
+                let collation = match term {
+                    Expr::Binary { left, right, .. } => {
+                        exec::comparison_collation(left, right, &row_source)?
+                    }
+                    _ => sources[level].column_collation(idx)?,
+                };
+                if let Some((index, descending)) =
+                    probe_index(conn, &sources[level], idx, collation)?
+                {
+                    let column = sources[level].table.schema.columns()[idx].name.clone();
+                    match &mut probe {
+                        // a comparison of the same column in the same
+                        // order can bound the other end of a range
+                        Some(found) => {
+                            if found.column == column && found.index.name == index.name {
+                                if let ProbeKeys::Range { .. } = keys {
+                                    found.keys.narrow(keys);
+                                }
+                            }
+                        }
+                        None => {
+                            probe = Some(IndexProbe {
+                                index: index,
+                                column: column,
+                                keys: keys,
+                                descending: descending,
+                                reverse: false,
+                            })
+                        }
+                    }
+                    break;
+                }
+            }
+            if probe
+                .as_ref()
+                .is_some_and(|probe| !probe.keys.is_half_open())
+            {
+                break;
+            }
+        }
+        sources[level].probe = probe;
+    }
+    return Ok(());
+}
+
+/// Finds an index to look up values of a column in: one whose first
+/// column is that column, with the collating sequence the query compares
+/// values by. Returns the index, along with whether it holds the column
+/// in descending order.
+fn probe_index(
+    conn: &Connection,
+    source: &Source,
+    idx: usize,
+    wanted: Collation,
+) -> Result<Option<(Btree, bool)>> {
+    let table = &source.table.schema;
+    for index in conn.schema.indexes_for_table(&table.name) {
+        let first = match &index.definition {
+            Some(definition) if !index.is_partial() => &definition.columns[0],
+            _ => continue,
+        };
+        let collation = match &first.collation {
+            Some(name) => Collation::from_name(name, &conn.collations)?,
+            None => source.column_collation(idx)?,
+        };
+        if table.column_index(&first.name) == Some(idx) && collation == wanted {
+            let (columns, _) = ddl::index_columns(table, index, &conn.collations)?;
+            let btree = Btree::new(
+                index.name.clone(),
+                index.table_name.clone(),
+                index.root_page,
+                conn.pager.clone(),
+            )
+            .with_key_order(ddl::index_key_order(&columns));
+            return Ok(Some((btree, first.descending)));
+        }
+    }
+    return Ok(None);
+}
+
+/// Finds the table column an ORDER BY term sorts on, as the index of
+/// its source and its index within that table, if the term is just a
+/// column.
+fn term_column(
+    key: &OrderKey,
+    projection: &[OutputColumn],
+    sources: &[Source],
+) -> Result<Option<(usize, usize)>> {
+    let expr = match key {
+        OrderKey::Output(i) => &projection[*i].expr,
+        OrderKey::Expr(expr) => expr,
+    };
+    return match expr {
+        Expr::Column { table, name } => Ok(Some(resolve_column(sources, table.as_deref(), name)?)),
+        _ => Ok(None),
+    };
+}
+
+/// The collating sequence an ORDER BY term sorts with: the one given
+/// with `COLLATE`, or else the one declared for the column it sorts on.
+fn term_collation(
+    term: &OrderingTerm,
+    key: &OrderKey,
+    projection: &[OutputColumn],
+    sources: &[Source],
+    collations: &Collations,
+) -> Result<Collation> {
+    if let Some(name) = &term.collation {
+        return Collation::from_name(name, collations);
+    }
+    return key_collation(key, projection, sources);
+}
+
+/// The collating sequence of the column a sort key sorts on, if it is
+/// just a column, or else BINARY.
+fn key_collation(
+    key: &OrderKey,
+    projection: &[OutputColumn],
+    sources: &[Source],
+) -> Result<Collation> {
+    return match term_column(key, projection, sources)? {
+        Some((source, idx)) => sources[source].column_collation(idx),
+        None => Ok(Collation::Binary),
+    };
+}
+
+/// Chooses an index to read a single table in the order the query asks
+/// for, so that the rows do not need to be sorted. That is the case when
+/// every ORDER BY term sorts on a column, and the columns are the
+/// leading columns of the index, with the same collating sequences and
+/// either all the same directions (for a forward scan) or all the
+/// opposite ones (for a reverse scan). If the table's rows are found
+/// through an index, only that index can be used, and the lookups are
+/// made in the order asked for. Returns whether such an index was found.
+fn plan_ordered_scan(
+    conn: &Connection,
+    sources: &mut [Source],
+    order_by: &[OrderKey],
+    sort_order: &[SortOrder],
+    projection: &[OutputColumn],
+) -> Result<bool> {
+    if sources.len() != 1 || order_by.is_empty() || sources[0].sample.is_some() {
+        return Ok(false);
+    }
+    let mut columns = Vec::with_capacity(order_by.len());
+    for key in order_by {
+        match term_column(key, projection, sources)? {
+            Some((_, idx)) => columns.push(idx),
+            None => return Ok(false),
+        }
+    }
+
+    // rows found through an index come out in its order, so no other
+    // index can give them an order
+    let probed = sources[0]
+        .probe
+        .as_ref()
+        .map(|probe| probe.index.name.clone());
+    let table = &sources[0].table.schema;
+    for index in conn.schema.indexes_for_table(&table.name) {
+        if probed.as_ref().is_some_and(|name| name != &index.name) {
+            continue;
+        }
+        let definition = match &index.definition {
+            Some(definition) if !index.is_partial() => definition,
+            _ => continue,
+        };
+        if definition.columns.len() < columns.len() {
+            continue;
+        }
+        let mut reverse = None;
+        let mut usable = true;
+        for ((idx, order), indexed) in columns
+            .iter()
+            .zip(sort_order.iter())
+            .zip(definition.columns.iter())
+        {
+            let collation = match &indexed.collation {
+                Some(name) => Collation::from_name(name, &conn.collations).ok(),
+                None => Some(sources[0].column_collation(*idx)?),
+            };
+            let backwards = order.descending != indexed.descending;
+            if table.column_index(&indexed.name) != Some(*idx)
+                || collation.as_ref() != Some(&order.collation)
+                || reverse.is_some_and(|reverse| reverse != backwards)
+            {
+                usable = false;
+                break;
+            }
+            reverse = Some(backwards);
+        }
+        if usable {
+            match &mut sources[0].probe {
+                Some(probe) => probe.reverse = reverse.unwrap(),
+                None => {
+                    sources[0].index_scan = Some(IndexScan {
+                        index: Btree::new(
+                            index.name.clone(),
+                            index.table_name.clone(),
+                            index.root_page,
+                            conn.pager.clone(),
+                        ),
+                        reverse: reverse.unwrap(),
+                    })
+                }
+            }
+            return Ok(true);
+        }
+    }
+    return Ok(false);
+}
+
+/// Plans `SELECT count(*) FROM t`, `SELECT min(x) FROM t`, or `SELECT
+/// max(x) FROM t`, with an optional WHERE clause: the only aggregate
+/// queries supported so far. Returns the aggregate and its output
+/// column, or `None` if the query is not of this form.
+fn plan_aggregate(
+    conn: &Connection,
+    select: &Select,
+    sources: &[Source],
+) -> Result<Option<(Aggregate, OutputColumn)>> {
+    if sources.len() != 1 || !select.order_by.is_empty() {
+        return Ok(None);
+    }
+    let (expr, alias, text) = match select.columns.as_slice() {
+        [ResultColumn::Expr { expr, alias, text }] => (expr, alias, text),
+        _ => return Ok(None),
+    };
+    let (func, args, star) = match expr {
+        Expr::Function {
+            name, args, star, ..
+        } => (name.to_lowercase(), args, *star),
+        _ => return Ok(None),
+    };
+    let column = match (func.as_str(), args.as_slice(), star) {
+        ("count", [], true) => None,
+        ("min", [Expr::Column { table, name }], false)
+        | ("max", [Expr::Column { table, name }], false) => {
+            Some(resolve_column(sources, table.as_deref(), name)?.1)
+        }
+        _ => return Ok(None),
+    };
+    let where_clause = select.where_clause.as_ref();
+    if let Some(where_clause) = where_clause {
+        functions::check_calls(where_clause)?;
+        let mut refs = Vec::new();
+        exec::column_refs(where_clause, &mut refs);
+        for (table, name) in refs {
+            resolve_column(sources, table, name)?;
+        }
+    }
+    let aggregate = match column {
+        None => Aggregate::Count,
+        Some(idx) => {
+            let is_max = func == "max";
+            // the value can only be read from an index if every row
+            // counts
+            let index = match where_clause {
+                Some(_) => None,
+                None if sources[0].sample.is_some() => None,
+                None => plan_min_max_index(conn, &sources[0], idx, is_max)?,
+            };
+            Aggregate::MinMax {
+                column: idx,
+                is_max: is_max,
+                index: index,
+            }
+        }
+    };
+    let output = OutputColumn {
+        name: alias.clone().unwrap_or_else(|| text.clone()),
+        expr: expr.clone(),
+    };
+    return Ok(Some((aggregate, output)));
+}
+
+/// Finds an index whose first column is the given column, with the same
+/// collating sequence, so that the column's smallest or largest value
+/// can be read from one end of it.
+fn plan_min_max_index(
+    conn: &Connection,
+    source: &Source,
+    idx: usize,
+    is_max: bool,
+) -> Result<Option<IndexScan>> {
+    let table = &source.table.schema;
+    let collation = source.column_collation(idx)?;
+    for index in conn.schema.indexes_for_table(&table.name) {
+        let first = match &index.definition {
+            Some(definition) if !index.is_partial() => &definition.columns[0],
+            _ => continue,
+        };
+        let index_collation = match &first.collation {
+            Some(name) => Collation::from_name(name, &conn.collations)?,
+            None => collation.clone(),
+        };
+        if table.column_index(&first.name) != Some(idx) || index_collation != collation {
+            continue;
+        }
+        return Ok(Some(IndexScan {
+            index: Btree::new(
+                index.name.clone(),
+                index.table_name.clone(),
+                index.root_page,
+                conn.pager.clone(),
+            ),
+            reverse: is_max != first.descending,
+        }));
+    }
+    return Ok(None);
+}
+
+/// Tests whether a row of a single-table query satisfies its WHERE
+/// clause, if any.
+fn matches_where(sources: &[Source], where_clause: Option<&Expr>, record: Record) -> Result<bool> {
+    let where_clause = match where_clause {
+        Some(where_clause) => where_clause,
+        None => return Ok(true),
+    };
+    let records = [record];
+    let row_source = JoinedRow {
+        sources: sources,
+        records: &records,
+    };
+    return Ok(exec::truth_value(&exec::eval_expr(where_clause, &row_source)?) == Some(true));
+}
+
+/// Counts the rows of a single-table query. Without a WHERE clause, only
+/// the number of cells on each leaf page is needed, not the rows
+/// themselves.
+fn count(sources: &[Source], where_clause: Option<&Expr>) -> Result<usize> {
+    let source = &sources[0];
+    if where_clause.is_none() && source.sample.is_none() {
+        return match &source.rows {
+            Some(rows) => Ok(rows.len),
+            None => source.table.btree.count_rows(),
+        };
+    }
+    let mut count = 0;
+    for record in source.scan() {
+        if matches_where(sources, where_clause, record?)? {
+            count += 1;
+        }
+    }
+    return Ok(count);
+}
+
+/// Finds the smallest or largest value of a column over the rows of a
+/// single-table query, ignoring NULLs. If there is an index on the
+/// column, the answer is read from the appropriate end of it rather
+/// than by scanning the table.
+fn min_max(
+    sources: &[Source],
+    idx: usize,
+    is_max: bool,
+    index: Option<&IndexScan>,
+    where_clause: Option<&Expr>,
+) -> Result<Value> {
+    if let Some(scan) = index {
+        // NULLs are skipped over at the start (or the end, for a
+        // descending index)
+        let entry = scan.index.index_find(scan.reverse, &|entry| {
+            return entry.values.first().is_some_and(|v| *v != Value::Null);
+        })?;
+        return Ok(entry.map_or(Value::Null, |entry| entry.values[0].clone()));
+    }
+
+    let collation = sources[0].column_collation(idx)?;
+    let wanted = if is_max {
+        Ordering::Greater
+    } else {
+        Ordering::Less
+    };
+    let mut extreme = Value::Null;
+    for record in sources[0].scan() {
+        let record = record?;
+        let value = sources[0].table.schema.column_value(&record, idx)?;
+        if value == Value::Null || !matches_where(sources, where_clause, record)? {
+            continue;
+        }
+        if extreme == Value::Null || collation.compare(&value, &extreme) == wanted {
+            extreme = value;
+        }
+    }
+    return Ok(extreme);
+}
+
+/// Resolves the result columns of a query, expanding `*` and `t.*`.
+fn resolve_columns(columns: &[ResultColumn], sources: &[Source]) -> Result<Vec<OutputColumn>> {
+    let all_columns = |source: &Source| -> Vec<OutputColumn> {
+        return source
+            .table
+            .schema
+            .column_names()
+            .into_iter()
+            .map(|name| OutputColumn {
+                expr: Expr::Column {
+                    table: Some(source.name().to_string()),
+                    name: name.clone(),
+                },
+                name: name,
+            })
+            .collect();
+    };
+
+    let mut output = Vec::new();
+    for col in columns {
+        match col {
+            ResultColumn::Star => {
+                if sources.is_empty() {
+                    return Err(eyre!("no tables specified"));
+                }
+                for source in sources {
+                    output.extend(all_columns(source));
+                }
+            }
+            ResultColumn::TableStar(table) => {
+                let source = sources
+                    .iter()
+                    .find(|source| source.matches(table))
+                    .ok_or_else(|| eyre!("no such table: {}", table))?;
+                output.extend(all_columns(source));
+            }
+            ResultColumn::Expr { expr, alias, text } => {
+                let name = match (alias, expr) {
+                    (Some(alias), _) => alias.clone(),
+                    (None, Expr::Column { name, .. }) => name.clone(),
+                    (None, _) => text.clone(),
+                };
+                output.push(OutputColumn {
+                    name: name,
+                    expr: expr.clone(),
+                });
+            }
+        }
+    }
+    return Ok(output);
+}
+
+fn resolve_order_by<'a>(
+    terms: &'a [OrderingTerm],
+    projection: &[OutputColumn],
+) -> Result<Vec<OrderKey<'a>>> {
+    let mut output = Vec::new();
+    for (i, term) in terms.iter().enumerate() {
+        let key = match &term.expr {
+            Expr::Literal(value) if value.get_int_val().is_some() => {
+                let n = value.get_int_val().unwrap();
+                if n < 1 || n as usize > projection.len() {
+                    return Err(eyre!(
+                        "{} ORDER BY term out of range - should be between 1 and {}",
+                        ordinal(i + 1),
+                        projection.len()
+                    ));
+                }
+                OrderKey::Output(n as usize - 1)
+            }
+            Expr::Column { table: None, name } => {
+                match projection
+                    .iter()
+                    .position(|col| col.name.eq_ignore_ascii_case(name))
+                {
+                    Some(idx) => OrderKey::Output(idx),
+                    None => OrderKey::Expr(&term.expr),
+                }
+            }
+            expr => OrderKey::Expr(expr),
+        };
+        output.push(key);
+    }
+    return Ok(output);
+}
+
+/// Evaluates the LIMIT and OFFSET clauses of a query.
+fn eval_limits(select: &Select) -> Result<(Option<usize>, usize)> {
+    let limit = match &select.limit {
+        Some(expr) => eval_limit(expr)?,
+        None => None,
+    };
+    let offset = match &select.offset {
+        Some(expr) => eval_limit(expr)?.unwrap_or(0),
+        None => 0,
+    };
+    return Ok((limit, offset));
+}
+
+/// Evaluates a LIMIT or OFFSET expression, which must be an integer.
+/// Returns `None` for a negative value, which means no limit.
+fn eval_limit(expr: &Expr) -> Result<Option<usize>> {
+    let value = exec::eval_expr(expr, &exec::NoRow)?;
+    let n = match &value {
+        Value::Float(f) if f.fract() == 0.0 => *f as i64,
+        // text is accepted if it is exactly an integer
+        Value::String(s) => s
+            .trim()
+            .parse::<i64>()
+            .map_err(|_| eyre!("datatype mismatch"))?,
+        _ => value
+            .get_int_val()
+            .ok_or_else(|| eyre!("datatype mismatch"))?,
+    };
+    return Ok(if n < 0 { None } else { Some(n as usize) });
+}
+
+fn ordinal(n: usize) -> String {
+    let suffix = match (n % 10, n % 100) {
+        (_, 11..=13) => "th",
+        (1, _) => "st",
+        (2, _) => "nd",
+        (3, _) => "rd",
+        _ => "th",
+    };
+    return format!("{}{}", n, suffix);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::connection::Connection;
+    use crate::datatypes::Value;
+
+    #[test]
+    fn count_rows() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let filename = file.path().to_str().unwrap();
+        let mut conn = Connection::new(filename).unwrap();
+        conn.execute("PRAGMA page_size = 512").unwrap();
+        conn.execute("CREATE TABLE t(a, b)").unwrap();
+        // every third row continues on overflow pages
+        let values: Vec<String> = (1..=300)
+            .map(|i| {
+                format!(
+                    "({}, '{}')",
+                    i,
+                    "x".repeat(if i % 3 == 0 { 2000 } else { 10 })
+                )
+            })
+            .collect();
+        conn.execute(&format!("INSERT INTO t VALUES {}", values.join(", ")))
+            .unwrap();
+        let num_pages = conn.pager.read().num_pages() as u64;
+        drop(conn);
+
+        // without a WHERE clause, only the headers of the leaves are
+        // read, and none of the rows or their overflow pages
+        let mut conn = Connection::new(filename).unwrap();
+        let before = conn.pager.read().metrics();
+        let result = conn.execute("SELECT count(*) FROM t").unwrap();
+        let after = conn.pager.read().metrics();
+        let all = conn.execute("SELECT a FROM t").unwrap();
+        assert_eq!(result.rows, [[Value::Int64(all.rows.len() as i64)]]);
+        assert_eq!(after.rows_examined, before.rows_examined);
+        assert!(after.pages_read - before.pages_read < num_pages / 10);
+
+        // with one, each row is read and tested
+        let before = conn.pager.read().metrics();
+        let result = conn
+            .execute("SELECT count(*) AS n FROM t WHERE length(b) > 100 AND a > 30")
+            .unwrap();
+        let after = conn.pager.read().metrics();
+        let all = conn
+            .execute("SELECT a FROM t WHERE length(b) > 100 AND a > 30")
+            .unwrap();
+        assert_eq!(result.columns, ["n"]);
+        assert_eq!(result.rows, [[Value::Int64(all.rows.len() as i64)]]);
+        assert_eq!(all.rows.len(), 90);
+        assert!(after.rows_examined - before.rows_examined >= 300);
+        let result = conn.execute("SELECT count(*) FROM t WHERE a < 0").unwrap();
+        assert_eq!(result.rows, [[Value::Int64(0)]]);
+    }
+
+    #[test]
+    fn min_max_from_index() {
+        let mut conn = Connection::deserialize(Vec::new()).unwrap();
+        let text = |s: &str| Value::String(s.to_string());
+        let plan = |conn: &mut Connection, sql: &str| {
+            let plan = conn.execute(&format!("EXPLAIN QUERY PLAN {}", sql));
+            return plan.unwrap().rows[0][3].clone();
+        };
+        // a WHERE clause that every row satisfies keeps the index from
+        // being used
+        let scanned = |conn: &mut Connection, sql: &str| {
+            return conn.execute(&format!("{} WHERE 1", sql)).unwrap().rows;
+        };
+        conn.execute("CREATE TABLE t(a, b TEXT, c)").unwrap();
+        conn.execute("CREATE INDEX t_a ON t(a)").unwrap();
+        conn.execute("CREATE INDEX t_b ON t(b COLLATE NOCASE)")
+            .unwrap();
+        conn.execute("CREATE INDEX t_c ON t(c DESC)").unwrap();
+
+        // an empty table has no smallest or largest value
+        for sql in &["SELECT min(a) FROM t", "SELECT max(a) FROM t"] {
+            let covering = text("SEARCH t USING COVERING INDEX t_a");
+            assert_eq!(plan(&mut conn, sql), covering);
+            assert_eq!(conn.execute(sql).unwrap().rows, [[Value::Null]]);
+        }
+
+        // the NULLs at the start of the index are skipped, as are those
+        // at the end of a descending one, and a column of only NULLs has
+        // no smallest or largest value either
+        let values: Vec<String> = (0..300)
+            .map(|i| match i % 10 {
+                0..=3 => "(NULL, 'x', NULL)".to_string(),
+                _ => format!("({}, '{}', NULL)", 1000 - i, ["b", "C", "a", "D"][i % 4]),
+            })
+            .collect();
+        conn.execute(&format!("INSERT INTO t VALUES {}", values.join(", ")))
+            .unwrap();
+        for sql in &["SELECT min(a) FROM t", "SELECT max(a) FROM t"] {
+            let rows = conn.execute(sql).unwrap().rows;
+            assert_ne!(rows, [[Value::Null]]);
+            assert_eq!(rows, scanned(&mut conn, sql));
+        }
+        let value = |rows: Vec<Vec<Value>>| rows[0][0].get_int_val();
+        assert_eq!(value(scanned(&mut conn, "SELECT min(a) FROM t")), Some(701));
+        assert_eq!(value(scanned(&mut conn, "SELECT max(a) FROM t")), Some(996));
+        for sql in &["SELECT min(c) FROM t", "SELECT max(c) FROM t"] {
+            let covering = text("SEARCH t USING COVERING INDEX t_c");
+            assert_eq!(plan(&mut conn, sql), covering);
+            assert_eq!(conn.execute(sql).unwrap().rows, [[Value::Null]]);
+        }
+        conn.execute("INSERT INTO t (c) VALUES (5), (NULL), (-3), (8), (NULL)")
+            .unwrap();
+        for sql in &["SELECT min(c) FROM t", "SELECT max(c) FROM t"] {
+            let rows = conn.execute(sql).unwrap().rows;
+            assert_ne!(rows, [[Value::Null]]);
+            assert_eq!(rows, scanned(&mut conn, sql));
+        }
+
+        // an index in another collating sequence is in the wrong order
+        // for the column, so the table is scanned
+        let sql = "SELECT max(b) FROM t";
+        assert_eq!(plan(&mut conn, sql), text("SCAN t"));
+        assert_eq!(conn.execute(sql).unwrap().rows, [[text("x")]]);
+        let result = conn.execute("SELECT min(b) FROM t").unwrap();
+        assert_eq!(result.rows, [[text("C")]]);
+    }
+
+    #[test]
+    fn exists() {
+        let mut conn = Connection::deserialize(Vec::new()).unwrap();
+        conn.execute("CREATE TABLE t(a, b)").unwrap();
+        conn.execute("CREATE TABLE e(a)").unwrap();
+        let values: Vec<String> = (1..=1000)
+            .map(|i| format!("({}, '{}')", i, "x".repeat(i % 50)))
+            .collect();
+        conn.execute(&format!("INSERT INTO t VALUES {}", values.join(", ")))
+            .unwrap();
+
+        let result = conn
+            .execute(
+                "SELECT EXISTS (SELECT * FROM e), NOT EXISTS (SELECT * FROM e), \
+                 EXISTS (SELECT a FROM t), NOT EXISTS (SELECT a FROM t)",
+            )
+            .unwrap();
+        assert_eq!(
+            result.rows,
+            [[
+                Value::Integer0,
+                Value::Integer1,
+                Value::Integer1,
+                Value::Integer0
+            ]]
+        );
+        let result = conn
+            .execute("SELECT count(*) FROM t WHERE a < 10 AND NOT EXISTS (SELECT a FROM e)")
+            .unwrap();
+        assert_eq!(result.rows, [[Value::Int64(9)]]);
+        let result = conn
+            .execute("SELECT a FROM t WHERE a < 10 AND EXISTS (SELECT a FROM t WHERE a > 1000)")
+            .unwrap();
+        assert!(result.rows.is_empty());
+
+        // the subquery stops at its first row, even one that would be
+        // sorted or have duplicates removed, and only reads the rows
+        // before it
+        let examined = |conn: &mut Connection, sql: &str| {
+            let before = conn.pager.read().metrics().rows_examined;
+            let result = conn.execute(sql).unwrap();
+            let examined = conn.pager.read().metrics().rows_examined - before;
+            return (result.rows[0][0].clone(), examined);
+        };
+        let (value, full) = examined(&mut conn, "SELECT count(*) FROM t WHERE b <> ''");
+        assert_eq!(value, Value::Int64(980));
+        assert!(full >= 1000);
+        for (sql, expected) in &[
+            ("SELECT EXISTS (SELECT a FROM t)", Value::Integer1),
+            (
+                "SELECT EXISTS (SELECT DISTINCT b FROM t ORDER BY b)",
+                Value::Integer1,
+            ),
+            (
+                "SELECT NOT EXISTS (SELECT a FROM t WHERE a > 1)",
+                Value::Integer0,
+            ),
+        ] {
+            let (value, rows) = examined(&mut conn, sql);
+            assert_eq!(value, *expected);
+            assert!(rows <= 2, "{} read {} rows", sql, rows);
+        }
+        let (value, rows) = examined(&mut conn, "SELECT EXISTS (SELECT a FROM t WHERE a > 500)");
+        assert_eq!(value, Value::Integer1);
+        assert!(rows <= 501);
+
+        // a LIMIT or OFFSET of its own is respected
+        let result = conn
+            .execute(
+                "SELECT EXISTS (SELECT a FROM t LIMIT 1 OFFSET 999), \
+                 EXISTS (SELECT a FROM t LIMIT 1 OFFSET 1000), \
+                 EXISTS (SELECT a FROM t LIMIT 0)",
+            )
+            .unwrap();
+        assert_eq!(
+            result.rows,
+            [[Value::Integer1, Value::Integer0, Value::Integer0]]
+        );
+    }
+
+    #[test]
+    fn index_ranges() {
+        let mut conn = Connection::deserialize(Vec::new()).unwrap();
+        conn.execute("CREATE TABLE t(a, b)").unwrap();
+        conn.execute("CREATE TABLE u(a, b)").unwrap();
+        conn.execute("CREATE TABLE d(a, b)").unwrap();
+        // even keys only, several rows for each, and some NULLs
+        let values: Vec<String> = (0..400)
+            .map(|i| match i % 9 {
+                0 => format!("(NULL, {})", i),
+                _ => format!("({}, {})", i % 40 / 2 * 2, i),
+            })
+            .collect();
+        for table in &["t", "u", "d"] {
+            conn.execute(&format!(
+                "INSERT INTO {} VALUES {}",
+                table,
+                values.join(", ")
+            ))
+            .unwrap();
+        }
+        conn.execute("CREATE INDEX t_a ON t(a)").unwrap();
+        conn.execute("CREATE INDEX d_a ON d(a DESC)").unwrap();
+
+        let conditions = [
+            "a < 10",
+            "a <= 10",
+            "a > 10",
+            "a >= 10",
+            "a < 11",
+            "a <= 11",
+            "a > 11",
+            "a >= 11",
+            "a < 0",
+            "a <= 0",
+            "a > 38",
+            "a >= 38",
+            "a > -5",
+            "a < 100",
+            "10 > a",
+            "11 <= a",
+            "a BETWEEN 5 AND 15",
+            "a BETWEEN 6 AND 14",
+            "a BETWEEN 15 AND 5",
+            "a > 5 AND a <= 14",
+            "a >= 6 AND a < 15",
+            "a < 15 AND 5 < a",
+            "a > 9 AND a > 5 AND a < 13",
+            "a < 7 AND a BETWEEN 0 AND 30",
+            "a < NULL",
+            "a >= NULL",
+            "a BETWEEN NULL AND 10",
+        ];
+        for condition in &conditions {
+            let query =
+                |table: &str| format!("SELECT b FROM {} WHERE {} ORDER BY b", table, condition);
+            let expected = conn.execute(&query("u")).unwrap().rows;
+            for table in &["t", "d"] {
+                let plan = conn
+                    .execute(&format!("EXPLAIN QUERY PLAN {}", query(table)))
+                    .unwrap();
+                let detail = match &plan.rows[0][3] {
+                    Value::String(detail) => detail.clone(),
+                    other => panic!("{:?} is not a detail", other),
+                };
+                assert!(detail.contains("USING INDEX"), "{}: {}", condition, detail);
+                let before = conn.pager.read().metrics().rows_examined;
+                let rows = conn.execute(&query(table)).unwrap().rows;
+                let examined = conn.pager.read().metrics().rows_examined - before;
+                assert_eq!(rows, expected, "{} on {}", condition, table);
+                // each entry in range and its row, and the entry that
+                // ends the range
+                assert!(
+                    examined <= 2 * rows.len() as u64 + 2,
+                    "{} on {}",
+                    condition,
+                    table
+                );
+            }
+        }
+        let plan = conn
+            .execute("EXPLAIN QUERY PLAN SELECT b FROM t WHERE a > 5 AND b > 0 AND a < 15")
+            .unwrap();
+        let detail = Value::String("SEARCH t USING INDEX t_a (a>? AND a<?)".to_string());
+        assert_eq!(plan.rows[0][3], detail);
+        let count = |conn: &mut Connection, sql: &str| conn.execute(sql).unwrap().rows.len();
+        assert_eq!(count(&mut conn, "SELECT b FROM u WHERE a < 0"), 0);
+        assert_eq!(count(&mut conn, "SELECT b FROM u WHERE a >= NULL"), 0);
+        assert!(count(&mut conn, "SELECT b FROM u WHERE a BETWEEN 6 AND 14") > 0);
+    }
+
+    #[test]
+    fn limit_and_offset() {
+        let mut conn = Connection::deserialize(Vec::new()).unwrap();
+        conn.execute("CREATE TABLE t(a, b)").unwrap();
+        let values: Vec<String> = (1..=1000)
+            .map(|i| format!("({}, '{}')", i, "x".repeat(i % 30)))
+            .collect();
+        conn.execute(&format!("INSERT INTO t VALUES {}", values.join(", ")))
+            .unwrap();
+        let run = |conn: &mut Connection, sql: &str| {
+            let before = conn.pager.read().metrics().rows_examined;
+            let rows = conn.execute(sql).unwrap().rows;
+            let examined = conn.pager.read().metrics().rows_examined - before;
+            let values: Vec<i64> = rows
+                .iter()
+                .map(|row| row[0].get_int_val().unwrap())
+                .collect();
+            return (values, examined);
+        };
+
+        // the scan stops once it has the rows asked for, after skipping
+        // the offset
+        let (rows, examined) = run(&mut conn, "SELECT a FROM t LIMIT 3");
+        assert_eq!(rows, [1, 2, 3]);
+        assert!(examined <= 4);
+        let (rows, examined) = run(&mut conn, "SELECT a FROM t LIMIT 2 OFFSET 10");
+        assert_eq!(rows, [11, 12]);
+        assert!(examined <= 13);
+        let (rows, examined) = run(&mut conn, "SELECT a FROM t WHERE a % 100 = 0 LIMIT 2");
+        assert_eq!(rows, [100, 200]);
+        assert!(examined <= 201);
+        let (rows, examined) = run(&mut conn, "SELECT a FROM t LIMIT 0");
+        assert!(rows.is_empty());
+        assert_eq!(examined, 0);
+        let (rows, _) = run(&mut conn, "SELECT a FROM t LIMIT 0 OFFSET 5");
+        assert!(rows.is_empty());
+
+        // an offset past the end leaves nothing, and a negative limit
+        // means no limit at all
+        let (rows, _) = run(&mut conn, "SELECT a FROM t LIMIT 5 OFFSET 1000");
+        assert!(rows.is_empty());
+        let (rows, _) = run(&mut conn, "SELECT a FROM t LIMIT 5 OFFSET 5000");
+        assert!(rows.is_empty());
+        let (rows, examined) = run(&mut conn, "SELECT a FROM t LIMIT -1");
+        assert_eq!(rows.len(), 1000);
+        assert!(examined >= 1000);
+        let (rows, _) = run(&mut conn, "SELECT a FROM t LIMIT -5 OFFSET 997");
+        assert_eq!(rows, [998, 999, 1000]);
+        let (rows, _) = run(
+            &mut conn,
+            "SELECT a FROM t ORDER BY a DESC LIMIT 2 OFFSET 1",
+        );
+        assert_eq!(rows, [999, 998]);
+
+        let err = conn.execute("SELECT a FROM t LIMIT 2.5").unwrap_err();
+        assert_eq!(err.to_string(), "datatype mismatch");
+    }
+}
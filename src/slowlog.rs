@@ -0,0 +1,141 @@
+//! A log of slow statements, for finding the queries worth tuning. It
+//! is off unless a connection is given one (see `Connection::slow_log`),
+//! and costs nothing for statements that finish under its threshold
+//! beyond timing them.
+
+use eyre::Result;
+use std::time::{Duration, Instant};
+
+use crate::ast::Stmt;
+use crate::connection::Connection;
+use crate::delete;
+use crate::select;
+use crate::statement::{QueryResult, Statement};
+
+/// A statement that took at least as long as the log's threshold.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlowStatement {
+    pub sql: String,
+    /// The steps of running the statement, as `EXPLAIN QUERY PLAN`
+    /// describes them, indented two spaces for each level below the
+    /// top.
+    pub plan: Vec<String>,
+    pub duration: Duration,
+    /// Rows of tables and entries of indexes read, which is much larger
+    /// than the rows returned for a query that scans where it could
+    /// search.
+    pub rows_examined: u64,
+    pub rows_returned: u64,
+    /// Pages read from the file, which does not count pages found in
+    /// the cache.
+    pub pages_read: u64,
+}
+
+pub struct SlowLog {
+    pub threshold: Duration,
+    callback: Box<dyn FnMut(&SlowStatement)>,
+}
+
+impl SlowLog {
+    /// Calls `callback` with each statement that takes at least
+    /// `threshold` to run. Statements that fail are not logged, nor are
+    /// the INSERTs that `Connection::execute_statements` writes together.
+    pub fn new<F>(threshold: Duration, callback: F) -> Self
+    where
+        F: FnMut(&SlowStatement) + 'static,
+    {
+        return Self {
+            threshold: threshold,
+            callback: Box::new(callback),
+        };
+    }
+}
+
+/// Runs a statement with `run`, timing it and passing it to the
+/// connection's slow statement log if it takes long enough.
+pub fn run_logged<F>(conn: &mut Connection, stmt: &Statement, run: F) -> Result<QueryResult>
+where
+    F: FnOnce(&mut Connection) -> Result<QueryResult>,
+{
+    if conn.slow_log.is_none() {
+        return run(conn);
+    }
+    let before = conn.pager.read().metrics();
+    let start = Instant::now();
+    let result = run(conn)?;
+    let duration = start.elapsed();
+    let after = conn.pager.read().metrics();
+
+    // the log is taken while it runs, so a statement run by the callback
+    // (or a nested one) is not logged as well
+    let mut log = match conn.slow_log.take() {
+        Some(log) => log,
+        None => return Ok(result),
+    };
+    if duration >= log.threshold {
+        let entry = SlowStatement {
+            sql: stmt.sql.clone(),
+            plan: plan(conn, &stmt.stmt),
+            duration: duration,
+            rows_examined: after.rows_examined - before.rows_examined,
+            rows_returned: result.rows.len() as u64,
+            pages_read: after.pages_read - before.pages_read,
+        };
+        (log.callback)(&entry);
+    }
+    conn.slow_log = Some(log);
+    return Ok(result);
+}
+
+/// The steps of running a statement, or none if it cannot be explained
+/// (as after a statement that dropped what it read from).
+fn plan(conn: &Connection, stmt: &Stmt) -> Vec<String> {
+    let details = match stmt {
+        Stmt::Select(select) => select::explain_select(conn, select),
+        Stmt::Delete(delete) => delete::explain_delete(conn, delete),
+        Stmt::Explain { stmt, .. } => return plan(conn, stmt),
+        _ => Ok(Vec::new()),
+    };
+    return details
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(depth, detail)| format!("{}{}", "  ".repeat(depth), detail))
+        .collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn logs_slow_statements() {
+        let mut conn = Connection::deserialize(Vec::new()).unwrap();
+        conn.execute("CREATE TABLE t(a, b)").unwrap();
+        let values: Vec<String> = (0..500).map(|i| format!("({}, {})", i, i % 10)).collect();
+        conn.execute(&format!("INSERT INTO t VALUES {}", values.join(", ")))
+            .unwrap();
+
+        let logged: Rc<RefCell<Vec<SlowStatement>>> = Rc::new(RefCell::new(Vec::new()));
+        let sink = logged.clone();
+        conn.slow_log = Some(SlowLog::new(Duration::ZERO, move |stmt| {
+            sink.borrow_mut().push(stmt.clone());
+        }));
+        conn.execute("SELECT a FROM t WHERE b = 3").unwrap();
+        {
+            let logged = logged.borrow();
+            assert_eq!(logged.len(), 1);
+            let stmt = &logged[0];
+            assert_eq!(stmt.sql, "SELECT a FROM t WHERE b = 3");
+            assert_eq!(stmt.plan, vec!["SCAN t".to_string()]);
+            assert_eq!(stmt.rows_examined, 500);
+            assert_eq!(stmt.rows_returned, 50);
+        }
+
+        // nothing is logged under the threshold
+        conn.slow_log.as_mut().unwrap().threshold = Duration::from_secs(3600);
+        conn.execute("SELECT count(*) FROM t").unwrap();
+        assert_eq!(logged.borrow().len(), 1);
+    }
+}
@@ -0,0 +1,318 @@
+use eyre::{eyre, Result};
+use std::cmp::Ordering;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::sync::Arc;
+
+use crate::datatypes::Value;
+use crate::exec::Collation;
+use crate::pager::{TempReader, TempRun, TempStore};
+
+/// The default amount of memory, in bytes, that a sort may use before
+/// spilling sorted runs to disk.
+pub const DEFAULT_SORT_MEMORY: usize = 4 * 1024 * 1024;
+
+/// The size of the pages sorted runs are written in.
+const RUN_PAGE_SIZE: usize = 4096;
+
+/// A row to be sorted: the values of the ORDER BY terms, followed by the
+/// row itself.
+#[derive(Debug, Clone)]
+struct SortEntry {
+    key: Vec<Value>,
+    row: Vec<Value>,
+}
+
+/// How the values of one ORDER BY term are sorted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SortOrder {
+    pub descending: bool,
+    pub collation: Collation,
+}
+
+/// Sorts rows by a key, keeping at most roughly `memory_limit` bytes of
+/// rows in memory. Whenever the limit is exceeded, the rows held in
+/// memory are sorted and written to a `TempStore`, which holds none of
+/// them in memory, as a run; the runs are merged when the output is read.
+pub struct Sorter {
+    order: Vec<SortOrder>,
+    memory_limit: usize,
+    buffer: Vec<SortEntry>,
+    buffer_size: usize,
+    store: TempStore,
+    runs: Vec<TempRun>,
+}
+
+impl Sorter {
+    /// Creates a sorter for keys with one entry per ORDER BY term;
+    /// `order` gives how each is sorted.
+    pub fn new(order: Vec<SortOrder>, memory_limit: usize) -> Self {
+        return Self {
+            order: order,
+            memory_limit: memory_limit,
+            buffer: Vec::new(),
+            buffer_size: 0,
+            store: TempStore::new(RUN_PAGE_SIZE, 0),
+            runs: Vec::new(),
+        };
+    }
+
+    pub fn push(&mut self, key: Vec<Value>, row: Vec<Value>) -> Result<()> {
+        self.buffer_size += key.iter().chain(row.iter()).map(value_size).sum::<usize>();
+        self.buffer.push(SortEntry { key: key, row: row });
+        if self.buffer_size > self.memory_limit {
+            self.spill()?;
+        }
+        return Ok(());
+    }
+
+    /// The number of sorted runs written to disk so far.
+    pub fn num_runs(&self) -> usize {
+        return self.runs.len();
+    }
+
+    fn sort_buffer(&mut self) {
+        let order = &self.order;
+        // a stable sort, so that rows with equal keys keep scan order
+        self.buffer
+            .sort_by(|a, b| compare_keys(&a.key, &b.key, order));
+    }
+
+    fn spill(&mut self) -> Result<()> {
+        self.sort_buffer();
+        let mut writer = BufWriter::new(self.store.writer());
+        for entry in self.buffer.drain(..) {
+            write_entry(&mut writer, &entry)?;
+        }
+        let writer = writer.into_inner().map_err(|e| eyre!(e.to_string()))?;
+        self.runs.push(writer.finish()?);
+        self.buffer_size = 0;
+        return Ok(());
+    }
+
+    /// Finishes the sort, returning the rows in order.
+    pub fn finish(mut self) -> Result<SortedRows> {
+        self.sort_buffer();
+        let store = Arc::new(self.store);
+        let mut sources = Vec::new();
+        for run in self.runs.drain(..) {
+            let reader = TempReader::new(store.clone(), run);
+            sources.push(RunSource::Run(BufReader::new(reader)));
+        }
+        if !self.buffer.is_empty() {
+            sources.push(RunSource::Memory(
+                std::mem::take(&mut self.buffer).into_iter(),
+            ));
+        }
+        let mut heads = Vec::new();
+        for source in sources.iter_mut() {
+            heads.push(source.next()?);
+        }
+        return Ok(SortedRows {
+            order: self.order,
+            sources: sources,
+            heads: heads,
+        });
+    }
+}
+
+enum RunSource {
+    Run(BufReader<TempReader>),
+    Memory(std::vec::IntoIter<SortEntry>),
+}
+
+impl RunSource {
+    fn next(&mut self) -> Result<Option<SortEntry>> {
+        return match self {
+            Self::Run(reader) => read_entry(reader),
+            Self::Memory(iter) => Ok(iter.next()),
+        };
+    }
+}
+
+/// The output of a `Sorter`, produced by merging its sorted runs.
+pub struct SortedRows {
+    order: Vec<SortOrder>,
+    sources: Vec<RunSource>,
+    heads: Vec<Option<SortEntry>>,
+}
+
+impl SortedRows {
+    /// Returns the key and the row of the next entry in order, or `None`
+    /// once all of them have been returned.
+    pub fn next_entry(&mut self) -> Option<Result<(Vec<Value>, Vec<Value>)>> {
+        let mut next: Option<usize> = None;
+        for (i, head) in self.heads.iter().enumerate() {
+            if let Some(entry) = head {
+                let is_smaller = match next {
+                    Some(n) => {
+                        let current = self.heads[n].as_ref().unwrap();
+                        compare_keys(&entry.key, &current.key, &self.order) == Ordering::Less
+                    }
+                    None => true,
+                };
+                if is_smaller {
+                    next = Some(i);
+                }
+            }
+        }
+        let i = next?;
+        let replacement = match self.sources[i].next() {
+            Ok(entry) => entry,
+            Err(e) => return Some(Err(e)),
+        };
+        let entry = std::mem::replace(&mut self.heads[i], replacement).unwrap();
+        return Some(Ok((entry.key, entry.row)));
+    }
+}
+
+impl Iterator for SortedRows {
+    type Item = Result<Vec<Value>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        return self.next_entry().map(|entry| entry.map(|(_, row)| row));
+    }
+}
+
+fn compare_keys(a: &[Value], b: &[Value], order: &[SortOrder]) -> Ordering {
+    for ((a, b), order) in a.iter().zip(b.iter()).zip(order.iter()) {
+        let ordering = order.collation.compare(a, b);
+        if ordering != Ordering::Equal {
+            return if order.descending {
+                ordering.reverse()
+            } else {
+                ordering
+            };
+        }
+    }
+    return Ordering::Equal;
+}
+
+/// An estimate of the memory used by a value.
+fn value_size(value: &Value) -> usize {
+    return std::mem::size_of::<Value>()
+        + match value {
+            Value::String(s) => s.len(),
+            Value::Blob(b) | Value::Internal(b) => b.len(),
+            _ => 0,
+        };
+}
+
+// Runs are stored in a simple private format: each entry is the number
+// of key values and row values, followed by the values, each with a
+// one-byte tag.
+const TAG_NULL: u8 = 0;
+const TAG_INTEGER: u8 = 1;
+const TAG_FLOAT: u8 = 2;
+const TAG_STRING: u8 = 3;
+const TAG_BLOB: u8 = 4;
+
+fn write_entry<W: Write>(writer: &mut W, entry: &SortEntry) -> Result<()> {
+    writer.write_all(&(entry.key.len() as u32).to_be_bytes())?;
+    writer.write_all(&(entry.row.len() as u32).to_be_bytes())?;
+    for value in entry.key.iter().chain(entry.row.iter()) {
+        match value {
+            Value::Null => writer.write_all(&[TAG_NULL])?,
+            Value::Float(f) => {
+                writer.write_all(&[TAG_FLOAT])?;
+                writer.write_all(&f.to_be_bytes())?;
+            }
+            Value::String(s) => {
+                writer.write_all(&[TAG_STRING])?;
+                writer.write_all(&(s.len() as u32).to_be_bytes())?;
+                writer.write_all(s.as_bytes())?;
+            }
+            Value::Blob(b) | Value::Internal(b) => {
+                writer.write_all(&[TAG_BLOB])?;
+                writer.write_all(&(b.len() as u32).to_be_bytes())?;
+                writer.write_all(b)?;
+            }
+            _ => {
+                writer.write_all(&[TAG_INTEGER])?;
+                writer.write_all(&value.get_int_val().unwrap().to_be_bytes())?;
+            }
+        }
+    }
+    return Ok(());
+}
+
+fn read_entry<R: Read>(reader: &mut R) -> Result<Option<SortEntry>> {
+    let mut buf = [0; 4];
+    match reader.read_exact(&mut buf) {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let num_keys = u32::from_be_bytes(buf) as usize;
+    reader.read_exact(&mut buf)?;
+    let num_values = u32::from_be_bytes(buf) as usize;
+
+    let mut values = Vec::with_capacity(num_keys + num_values);
+    for _ in 0..num_keys + num_values {
+        let mut tag = [0; 1];
+        reader.read_exact(&mut tag)?;
+        let value = match tag[0] {
+            TAG_NULL => Value::Null,
+            TAG_INTEGER | TAG_FLOAT => {
+                let mut bytes = [0; 8];
+                reader.read_exact(&mut bytes)?;
+                if tag[0] == TAG_INTEGER {
+                    Value::Int64(i64::from_be_bytes(bytes))
+                } else {
+                    Value::Float(f64::from_be_bytes(bytes))
+                }
+            }
+            TAG_STRING | TAG_BLOB => {
+                reader.read_exact(&mut buf)?;
+                let mut bytes = vec![0; u32::from_be_bytes(buf) as usize];
+                reader.read_exact(&mut bytes)?;
+                if tag[0] == TAG_STRING {
+                    Value::String(String::from_utf8_lossy(&bytes).into())
+                } else {
+                    Value::Blob(bytes)
+                }
+            }
+            t => return Err(eyre!("Corrupt sort run: unknown tag {}", t)),
+        };
+        values.push(value);
+    }
+    let row = values.split_off(num_keys);
+    return Ok(Some(SortEntry {
+        key: values,
+        row: row,
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_spilled_runs() {
+        // a tiny memory limit, so that nearly every row is its own run
+        let order = vec![
+            SortOrder {
+                descending: true,
+                collation: Collation::Binary,
+            },
+            SortOrder {
+                descending: false,
+                collation: Collation::NoCase,
+            },
+        ];
+        let mut sorter = Sorter::new(order, 200);
+        for i in 0..50_i64 {
+            let key = vec![Value::Int64(i % 5), Value::String(format!("{:02}", i))];
+            sorter.push(key, vec![Value::Int64(i)]).unwrap();
+        }
+        assert!(sorter.num_runs() > 1);
+
+        let rows: Vec<i64> = sorter
+            .finish()
+            .unwrap()
+            .map(|row| row.unwrap()[0].get_int_val().unwrap())
+            .collect();
+        let mut expected: Vec<i64> = (0..50).collect();
+        expected.sort_by(|a, b| (b % 5).cmp(&(a % 5)).then(a.cmp(b)));
+        assert_eq!(rows, expected);
+    }
+}
@@ -1,80 +1,674 @@
-use eyre::{eyre, Result, WrapErr};
-use lazy_static::lazy_static;
-use regex::Regex;
+use eyre::{eyre, Result};
 
-use crate::table::{self, Row, Table};
+use crate::ast::{Expr, InsertSource, Select, Stmt};
+use crate::connection::Connection;
+use crate::datatypes::Value;
+use crate::ddl;
+use crate::delete;
+use crate::functions;
+use crate::insert;
+use crate::limits::Limits;
+use crate::parser;
+use crate::pragma;
+use crate::replication::Change;
+use crate::select;
+use crate::slowlog;
+use crate::vacuum;
 
-lazy_static! {
-    static ref RE_INSERT: Regex = Regex::new(r"insert (.+) (.+) (.+)").unwrap();
+/// The output of executing a statement: the names of the result columns
+/// and the rows produced, if any.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QueryResult {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
 }
 
 #[derive(Debug, Clone)]
-pub enum StatementType {
-    INSERT,
-    SELECT,
+pub struct Statement {
+    pub sql: String,
+    pub stmt: Stmt,
+    /// The names of the statement's parameters, by index - 1.
+    parameters: Vec<Option<String>>,
+    /// The values bound to the parameters, by index - 1. Parameters that
+    /// have not been bound are NULL.
+    bindings: Vec<Value>,
 }
 
-#[derive(Debug)]
-pub struct Statement<'a> {
-    stype: StatementType,
-    table: &'a mut Table,
-    row_to_insert: Option<Row>,
-}
+impl Statement {
+    pub fn prepare(input: &str) -> Result<Self> {
+        return Self::prepare_with_limits(input, &Limits::default());
+    }
 
-impl<'a> Statement<'a> {
-    fn new(stmt_type: StatementType, table: &'a mut Table, row_to_insert: Option<Row>) -> Self {
-        return Self {
-            stype: stmt_type,
-            table: table,
-            row_to_insert: row_to_insert,
-        };
+    /// Prepares a statement, checking its length and the depth of its
+    /// expressions against the given limits.
+    pub fn prepare_with_limits(input: &str, limits: &Limits) -> Result<Self> {
+        let (stmt, parameters) = parser::parse_with_limits(input, limits)?;
+        return Ok(Self {
+            sql: input.to_string(),
+            stmt: stmt,
+            bindings: vec![Value::Null; parameters.len()],
+            parameters: parameters,
+        });
+    }
+
+    /// The number of parameters in the statement, which is the largest
+    /// parameter index.
+    pub fn parameter_count(&self) -> usize {
+        return self.parameters.len();
+    }
+
+    /// The name of the parameter with the given 1-based index, including
+    /// its prefix (e.g., `:name` or `?3`). Parameters written as a bare
+    /// `?` have no name.
+    pub fn parameter_name(&self, index: usize) -> Option<&str> {
+        return self
+            .parameters
+            .get(index.checked_sub(1)?)
+            .and_then(|name| name.as_deref());
     }
 
-    pub fn prepare(table: &mut Table, input: String) -> Result<Statement> {
-        if input.starts_with("insert") {
-            let caps = RE_INSERT.captures(&input);
-            match caps {
-                Some(caps) => {
-                    let id = caps[1]
-                        .parse::<u32>()
-                        .wrap_err("ID must be a positive integer.")?;
-
-                    let username = caps[2].to_string();
-                    if username.len() > table::USERNAME_SIZE {
-                        return Err(eyre!("String is too long."));
-                    }
-
-                    let email = caps[3].to_string();
-                    if email.len() > table::EMAIL_SIZE {
-                        return Err(eyre!("String is too long."));
-                    }
-
-                    return Ok(Statement::new(
-                        StatementType::INSERT,
-                        table,
-                        Some(Row::new(id, caps[2].to_string(), caps[3].to_string())),
+    /// The index of the parameter with the given name, including its
+    /// prefix.
+    pub fn parameter_index(&self, name: &str) -> Option<usize> {
+        return self
+            .parameters
+            .iter()
+            .position(|p| p.as_deref() == Some(name))
+            .map(|i| i + 1);
+    }
+
+    /// Binds a value to the parameter with the given 1-based index. The
+    /// value is used by every later execution of the statement, until it
+    /// is bound again.
+    pub fn bind(&mut self, index: usize, value: Value) -> Result<()> {
+        if index == 0 || index > self.bindings.len() {
+            return Err(eyre!("bind or column index out of range"));
+        }
+        self.bindings[index - 1] = value;
+        return Ok(());
+    }
+
+    /// Binds a value to the parameter with the given name, including its
+    /// prefix (e.g., `stmt.bind_named(":id", value)`).
+    pub fn bind_named(&mut self, name: &str, value: Value) -> Result<()> {
+        let index = self
+            .parameter_index(name)
+            .ok_or_else(|| eyre!("no such parameter: {}", name))?;
+        return self.bind(index, value);
+    }
+
+    /// Resets all parameters to NULL.
+    pub fn clear_bindings(&mut self) {
+        for value in self.bindings.iter_mut() {
+            *value = Value::Null;
+        }
+    }
+
+    /// Runs the statement, passing it to the connection's slow
+    /// statement log if it has one (see `slowlog::SlowLog`). random()
+    /// and randomblob() draw from the connection's generator.
+    pub fn execute(&self, conn: &mut Connection) -> Result<QueryResult> {
+        return conn.with_shared_lock(|conn| {
+            let rng = conn.rng.clone();
+            return functions::with_rng(rng, || {
+                slowlog::run_logged(conn, self, |conn| self.run(conn))
+            });
+        });
+    }
+
+    fn run(&self, conn: &mut Connection) -> Result<QueryResult> {
+        let bound;
+        let stmt = if self.parameters.is_empty() {
+            &self.stmt
+        } else {
+            let mut stmt = self.stmt.clone();
+            bind_stmt(&mut stmt, &self.bindings);
+            bound = stmt;
+            &bound
+        };
+        #[cfg(feature = "compat")]
+        if let Some(compat) = conn.compat.as_mut() {
+            compat.check(stmt)?;
+        }
+        match stmt {
+            Stmt::Select(select) => return select::execute_select(conn, select),
+            Stmt::Insert(insert) => return insert::execute_insert(conn, insert),
+            Stmt::Delete(delete) => return delete::execute_delete(conn, delete),
+            Stmt::CreateTable(table) => {
+                ddl::create_table(conn, table, &self.sql)?;
+                return self.record_schema_change(conn);
+            }
+            Stmt::CreateIndex(index) => {
+                ddl::create_index(conn, index, &self.sql)?;
+                return self.record_schema_change(conn);
+            }
+            Stmt::CreateView(view) => {
+                if !self.parameters.is_empty() {
+                    return Err(eyre!("parameters are not allowed in views"));
+                }
+                ddl::create_view(conn, view, &self.sql)?;
+                return self.record_schema_change(conn);
+            }
+            Stmt::CreateVirtualTable(_) => {
+                return Err(eyre!("CREATE VIRTUAL TABLE is not supported yet."))
+            }
+            Stmt::AlterTable(alter) => {
+                ddl::alter_table(conn, alter)?;
+                return self.record_schema_change(conn);
+            }
+            Stmt::Pragma(p) => {
+                let result = pragma::execute(conn, p)?;
+                let persistent = pragma::lookup(&p.name).is_some_and(|p| p.persistent);
+                if persistent && p.value.is_some() {
+                    // followers make the same change to their file
+                    return self.record_schema_change(conn);
+                }
+                return Ok(result);
+            }
+            Stmt::Vacuum => {
+                vacuum::vacuum(conn)?;
+                return Ok(QueryResult::default());
+            }
+            Stmt::Explain { query_plan, stmt } => {
+                if !query_plan {
+                    return Err(eyre!(
+                        "EXPLAIN is not supported yet; use EXPLAIN QUERY PLAN."
                     ));
                 }
-                None => {
-                    return Err(eyre!("Syntax error."));
+                return explain_query_plan(conn, stmt);
+            }
+        }
+    }
+
+    /// Records a statement that changed the schema for the replication
+    /// log, which followers replay as SQL.
+    fn record_schema_change(&self, conn: &mut Connection) -> Result<QueryResult> {
+        conn.record_change(|| Change::Schema(self.sql.clone()))?;
+        return Ok(QueryResult::default());
+    }
+}
+
+/// Runs `EXPLAIN QUERY PLAN`, producing a row for each step of running the
+/// statement, with the same columns as SQLite: an ID for the step, the
+/// ID of the step it is part of (or 0), an unused column, and a
+/// description of the step.
+fn explain_query_plan(conn: &Connection, stmt: &Stmt) -> Result<QueryResult> {
+    let details = match stmt {
+        Stmt::Select(select) => select::explain_select(conn, select)?,
+        Stmt::Delete(delete) => delete::explain_delete(conn, delete)?,
+        // as in SQLite, there are no steps to describe for the others
+        _ => Vec::new(),
+    };
+    // the IDs of the latest step at each depth, the last of which is
+    // the parent of the next step
+    let mut parents: Vec<i64> = Vec::new();
+    let mut rows = Vec::with_capacity(details.len());
+    for (i, (depth, detail)) in details.into_iter().enumerate() {
+        let id = i as i64 + 1;
+        parents.truncate(depth);
+        rows.push(vec![
+            Value::Int64(id),
+            Value::Int64(parents.last().copied().unwrap_or(0)),
+            Value::Int64(0),
+            Value::String(detail),
+        ]);
+        parents.push(id);
+    }
+    return Ok(QueryResult {
+        columns: vec![
+            "id".to_string(),
+            "parent".to_string(),
+            "notused".to_string(),
+            "detail".to_string(),
+        ],
+        rows: rows,
+    });
+}
+
+/// Replaces the parameters in a statement with the values bound to them.
+fn bind_stmt(stmt: &mut Stmt, values: &[Value]) {
+    match stmt {
+        Stmt::Select(select) => bind_select(select, values),
+        Stmt::Insert(insert) => match &mut insert.source {
+            InsertSource::Values(rows) => {
+                for expr in rows.iter_mut().flatten() {
+                    bind_expr(expr, values);
                 }
             }
-        } else if input.starts_with("select") {
-            return Ok(Statement::new(StatementType::SELECT, table, None));
+            InsertSource::Select(select) => bind_select(select, values),
+        },
+        Stmt::Delete(delete) => {
+            if let Some(expr) = &mut delete.where_clause {
+                bind_expr(expr, values);
+            }
+        }
+        Stmt::CreateIndex(index) => {
+            if let Some(expr) = &mut index.where_clause {
+                bind_expr(expr, values);
+            }
         }
-        return Err(eyre!("Unrecognized command {}.", input));
+        Stmt::Explain { stmt, .. } => bind_stmt(stmt, values),
+        // parameters are not allowed in table and view definitions
+        Stmt::CreateTable(_)
+        | Stmt::CreateVirtualTable(_)
+        | Stmt::CreateView(_)
+        | Stmt::AlterTable(_)
+        | Stmt::Pragma(_)
+        | Stmt::Vacuum => (),
     }
+}
 
-    pub fn execute(&mut self) -> Result<String> {
-        let result;
-        match self.stype {
-            StatementType::INSERT => {
-                result = self.table.execute_insert(self.row_to_insert.unwrap());
+fn bind_select(select: &mut Select, values: &[Value]) {
+    for expr in select::exprs_mut(select) {
+        bind_expr(expr, values);
+    }
+    let tables = select
+        .from
+        .iter_mut()
+        .chain(select.joins.iter_mut().map(|j| &mut j.table));
+    for table in tables {
+        if let Some(subquery) = &mut table.subquery {
+            bind_select(subquery, values);
+        }
+    }
+    for part in select.compound.iter_mut() {
+        bind_select(&mut part.select, values);
+    }
+}
+
+fn bind_expr(expr: &mut Expr, values: &[Value]) {
+    match expr {
+        Expr::Parameter(index) => {
+            *expr = Expr::Literal(values[*index - 1].clone());
+        }
+        Expr::Literal(_) | Expr::Column { .. } => (),
+        Expr::Unary { expr, .. } | Expr::IsNull { expr, .. } | Expr::Cast { expr, .. } => {
+            bind_expr(expr, values)
+        }
+        Expr::Binary { left, right, .. } => {
+            bind_expr(left, values);
+            bind_expr(right, values);
+        }
+        Expr::Like {
+            expr,
+            pattern,
+            escape,
+            ..
+        } => {
+            bind_expr(expr, values);
+            bind_expr(pattern, values);
+            if let Some(escape) = escape {
+                bind_expr(escape, values);
+            }
+        }
+        Expr::Between {
+            expr, low, high, ..
+        } => {
+            bind_expr(expr, values);
+            bind_expr(low, values);
+            bind_expr(high, values);
+        }
+        Expr::In {
+            expr,
+            list,
+            subquery,
+            ..
+        } => {
+            bind_expr(expr, values);
+            for item in list.iter_mut() {
+                bind_expr(item, values);
+            }
+            if let Some(select) = subquery {
+                bind_select(select, values);
             }
-            StatementType::SELECT => {
-                result = self.table.execute_select();
+        }
+        Expr::Subquery(select) | Expr::Exists(select) => bind_select(select, values),
+        Expr::Function { args, .. } => {
+            for arg in args.iter_mut() {
+                bind_expr(arg, values);
             }
         }
-        return result;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::connection::Connection;
+    use crate::datatypes::Value;
+
+    #[test]
+    fn explain_query_plan() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        conn.execute("CREATE TABLE t(a, b)").unwrap();
+        conn.execute("CREATE INDEX t_b ON t(b)").unwrap();
+
+        let mut plan = |sql: &str| -> Vec<String> {
+            let result = conn
+                .execute(&format!("EXPLAIN QUERY PLAN {}", sql))
+                .unwrap();
+            assert_eq!(result.columns, ["id", "parent", "notused", "detail"]);
+            return result
+                .rows
+                .into_iter()
+                .map(|row| match &row[3] {
+                    Value::String(detail) => detail.clone(),
+                    value => panic!("unexpected detail: {:?}", value),
+                })
+                .collect();
+        };
+        assert_eq!(plan("SELECT 1"), ["SCAN CONSTANT ROW"]);
+        assert_eq!(plan("SELECT * FROM t WHERE a > 1"), ["SCAN t"]);
+        assert_eq!(
+            plan("SELECT * FROM t ORDER BY b DESC"),
+            ["SCAN t USING INDEX t_b"]
+        );
+        assert_eq!(
+            plan("SELECT * FROM t x LEFT JOIN t y ON y.b = x.a ORDER BY x.a"),
+            [
+                "SCAN x",
+                "SEARCH y USING INDEX t_b (b=?) LEFT-JOIN",
+                "USE TEMP B-TREE FOR ORDER BY"
+            ]
+        );
+        assert_eq!(
+            plan("SELECT min(b) FROM t"),
+            ["SEARCH t USING COVERING INDEX t_b"]
+        );
+        assert_eq!(
+            plan("SELECT * FROM t WHERE b BETWEEN 1 AND 5"),
+            ["SEARCH t USING INDEX t_b (b>? AND b<?)"]
+        );
+        assert_eq!(
+            plan("SELECT * FROM t WHERE 3 >= b"),
+            ["SEARCH t USING INDEX t_b (b<?)"]
+        );
+        // lookups of an IN list are made in index order
+        assert_eq!(
+            plan("SELECT * FROM t WHERE b IN (SELECT a FROM t) ORDER BY b DESC"),
+            [
+                "SEARCH t USING INDEX t_b (b=?)",
+                "LIST SUBQUERY 1",
+                "SCAN t"
+            ]
+        );
+        assert_eq!(plan("SELECT * FROM t WHERE b NOT IN (1, 2)"), ["SCAN t"]);
+        assert_eq!(plan("DELETE FROM t WHERE a = 1"), ["SCAN t"]);
+        assert!(plan("INSERT INTO t VALUES (1, 2)").is_empty());
+        // nothing is run
+        assert!(conn.table("t").unwrap().btree.list_records().is_empty());
+        assert!(conn.execute("EXPLAIN QUERY PLAN SELECT c FROM t").is_err());
+        assert!(conn.execute("EXPLAIN SELECT 1").is_err());
+    }
+
+    #[test]
+    fn subqueries() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        conn.execute("CREATE TABLE t(a, b)").unwrap();
+        for i in 1..=5 {
+            conn.execute(&format!("INSERT INTO t VALUES ({}, {})", i, i * 10))
+                .unwrap();
+        }
+
+        let result = conn
+            .execute("SELECT b FROM t WHERE a = (SELECT max(a) FROM t)")
+            .unwrap();
+        assert_eq!(result.rows, [[Value::Int8(50)]]);
+        let result = conn
+            .execute("SELECT * FROM (SELECT a AS n, b AS n FROM t WHERE a > 3) ORDER BY 1 DESC")
+            .unwrap();
+        assert_eq!(result.columns, ["n", "n:1"]);
+        assert_eq!(
+            result.rows,
+            [
+                [Value::Int8(5), Value::Int8(50)],
+                [Value::Int8(4), Value::Int8(40)]
+            ]
+        );
+        let result = conn
+            .execute("SELECT count(*) FROM (SELECT * FROM t WHERE b > 20)")
+            .unwrap();
+        assert_eq!(result.rows, [[Value::Int64(3)]]);
+
+        // parameters in subqueries are bound along with the rest
+        let mut stmt = conn
+            .prepare("SELECT (SELECT b FROM t WHERE a = ?)")
+            .unwrap();
+        stmt.bind(1, Value::Int64(2)).unwrap();
+        assert_eq!(stmt.execute(&mut conn).unwrap().rows, [[Value::Int8(20)]]);
+
+        let result = conn
+            .execute(
+                "SELECT a FROM t WHERE b IN (SELECT b + 10 FROM t WHERE a < 3) ORDER BY a DESC",
+            )
+            .unwrap();
+        assert_eq!(result.rows, [[Value::Int8(3)], [Value::Int8(2)]]);
+
+        // EXISTS only needs a single row, whatever the columns
+        let result = conn
+            .execute(
+                "SELECT EXISTS (SELECT a, b FROM t ORDER BY b), \
+                 NOT EXISTS (SELECT * FROM t WHERE a > 5), \
+                 EXISTS (SELECT a FROM t LIMIT 1 OFFSET 5)",
+            )
+            .unwrap();
+        assert_eq!(
+            result.rows,
+            [[Value::Integer1, Value::Integer1, Value::Integer0]]
+        );
+
+        conn.execute("DELETE FROM t WHERE a < (SELECT 3)").unwrap();
+        assert_eq!(conn.table("t").unwrap().btree.list_records().len(), 3);
+        assert!(conn.execute("SELECT (SELECT a, b FROM t)").is_err());
+    }
+
+    #[test]
+    fn index_probes() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        conn.execute("CREATE TABLE t(a, b)").unwrap();
+        for i in 0..1000 {
+            conn.execute(&format!("INSERT INTO t VALUES ({}, {})", i, i % 50))
+                .unwrap();
+        }
+        conn.execute("INSERT INTO t VALUES (1000, NULL)").unwrap();
+        // the index spans several pages
+        conn.execute("CREATE INDEX t_b ON t(b)").unwrap();
+
+        let mut column = |sql: &str| -> Vec<i64> {
+            return conn
+                .execute(sql)
+                .unwrap()
+                .rows
+                .iter()
+                .map(|row| row[0].get_int_val().unwrap())
+                .collect();
+        };
+        let expected: Vec<i64> = (0..1000)
+            .filter(|i| (10..=12).contains(&(i % 50)))
+            .collect();
+        let mut found = column("SELECT a FROM t WHERE b BETWEEN 10 AND 12");
+        found.sort();
+        assert_eq!(found, expected);
+        assert_eq!(
+            column("SELECT b FROM t WHERE b IN (49, 0, NULL, 49) AND a < 100 ORDER BY b DESC"),
+            [49, 49, 0, 0]
+        );
+        assert!(column("SELECT a FROM t WHERE b BETWEEN NULL AND 10").is_empty());
+    }
+
+    #[test]
+    fn table_sample() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        conn.execute("PRAGMA page_size = 512").unwrap();
+        conn.execute("CREATE TABLE t(id INTEGER PRIMARY KEY, a)")
+            .unwrap();
+        let values: Vec<String> = (1..=5000).map(|i| format!("({}, {})", i, i % 7)).collect();
+        conn.execute(&format!("INSERT INTO t VALUES {}", values.join(", ")))
+            .unwrap();
+        conn.execute("CREATE VIEW v AS SELECT * FROM t").unwrap();
+
+        let mut ids = |sql: &str| -> Vec<i64> {
+            return conn
+                .execute(sql)
+                .unwrap()
+                .rows
+                .iter()
+                .map(|row| row[0].get_int_val().unwrap())
+                .collect();
+        };
+        assert_eq!(
+            ids("SELECT count(*) FROM t TABLESAMPLE SYSTEM (100)"),
+            [5000]
+        );
+        assert_eq!(ids("SELECT count(*) FROM t TABLESAMPLE BERNOULLI (0)"), [0]);
+        assert_eq!(ids("SELECT count(*) FROM t TABLESAMPLE SYSTEM (0)"), [0]);
+
+        let bernoulli = ids("SELECT id FROM t TABLESAMPLE BERNOULLI (10) REPEATABLE (7)");
+        assert!((350..650).contains(&bernoulli.len()), "{}", bernoulli.len());
+        assert_eq!(
+            ids("SELECT id FROM t x TABLESAMPLE BERNOULLI (10) REPEATABLE (7) WHERE x.id > 0"),
+            bernoulli
+        );
+        // whole pages are kept, in row ID order
+        let system = ids("SELECT id FROM t TABLESAMPLE SYSTEM (20) REPEATABLE (3)");
+        assert!((400..1600).contains(&system.len()), "{}", system.len());
+        assert!(system.windows(2).all(|w| w[0] < w[1]));
+        assert!(system.windows(2).any(|w| w[1] == w[0] + 1));
+
+        assert!(conn
+            .execute("SELECT * FROM t TABLESAMPLE SYSTEM (101)")
+            .is_err());
+        assert!(conn
+            .execute("SELECT * FROM v TABLESAMPLE SYSTEM (10)")
+            .is_err());
+    }
+
+    #[test]
+    fn distinct() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        conn.execute("CREATE TABLE t(a, b COLLATE NOCASE)").unwrap();
+        let mut values = Vec::new();
+        for i in 0..2000 {
+            let b = if i % 2 == 0 { "x" } else { "X" };
+            values.push(format!("({}, '{}')", i % 7, b));
+        }
+        conn.execute(&format!("INSERT INTO t VALUES {}", values.join(", ")))
+            .unwrap();
+        conn.execute("INSERT INTO t VALUES (1.0, 'x'), ('1', 'x')")
+            .unwrap();
+
+        let result = conn.execute("SELECT DISTINCT a, b FROM t").unwrap();
+        // 1 and 1.0 are the same, but not '1'
+        assert_eq!(result.rows.len(), 8);
+        assert_eq!(
+            result.rows[0],
+            [Value::Int64(0), Value::String("x".to_string())]
+        );
+
+        // sorting to find duplicates, with little enough memory that the
+        // sort spills to disk
+        conn.sort_memory = 1024;
+        let result = conn
+            .execute("SELECT DISTINCT a FROM t ORDER BY a DESC LIMIT 3 OFFSET 1")
+            .unwrap();
+        // text sorts after numbers, so '1' is skipped
+        assert_eq!(
+            result.rows,
+            [[Value::Int64(6)], [Value::Int64(5)], [Value::Int64(4)]]
+        );
+        let plan = conn
+            .execute("EXPLAIN QUERY PLAN SELECT DISTINCT a FROM t ORDER BY a")
+            .unwrap();
+        assert_eq!(plan.rows.len(), 2);
+        assert_eq!(
+            plan.rows[1][3],
+            Value::String("USE TEMP B-TREE FOR DISTINCT".to_string())
+        );
+    }
+
+    #[test]
+    fn compound_select() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        conn.execute("CREATE TABLE t(a, b COLLATE NOCASE)").unwrap();
+        conn.execute("INSERT INTO t VALUES (3, 'x'), (1, 'y'), (2, 'X'), (1, 'y')")
+            .unwrap();
+
+        let mut values = |sql: &str| -> Vec<Value> {
+            let result = conn.execute(sql).unwrap();
+            return result
+                .rows
+                .into_iter()
+                .map(|mut row| row.remove(0))
+                .collect();
+        };
+        // duplicates are left out and the rows come out sorted
+        assert_eq!(
+            values("SELECT a FROM t UNION SELECT 2"),
+            [Value::Int64(1), Value::Int64(2), Value::Int64(3)]
+        );
+        assert_eq!(values("SELECT a FROM t UNION ALL SELECT 2").len(), 5);
+        assert_eq!(
+            values("SELECT a FROM t INTERSECT SELECT 1.0 UNION SELECT 3"),
+            [Value::Int64(1), Value::Int64(3)]
+        );
+        // 'x' and 'X' are the same in the collating sequence of b
+        assert_eq!(
+            values("SELECT b FROM t EXCEPT SELECT 'x'"),
+            [Value::String("y".to_string())]
+        );
+        assert_eq!(
+            values("SELECT a AS n FROM t UNION SELECT 5 ORDER BY n DESC LIMIT 2 OFFSET 1"),
+            [Value::Int64(3), Value::Int64(2)]
+        );
+
+        assert!(conn
+            .execute("SELECT a FROM t UNION SELECT a, b FROM t")
+            .is_err());
+        assert!(conn
+            .execute("SELECT a FROM t UNION SELECT 1 ORDER BY 2")
+            .is_err());
+        assert!(conn
+            .execute("SELECT a FROM t UNION SELECT 1 ORDER BY a + 1")
+            .is_err());
+    }
+
+    #[test]
+    fn comparison_affinity() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        conn.execute("CREATE TABLE t(id, i INTEGER, s TEXT, n)")
+            .unwrap();
+        conn.execute("INSERT INTO t VALUES (1, 1, '1', 1), (2, 10, '10', '10'), (3, 2, 2, '2')")
+            .unwrap();
+        conn.execute("CREATE INDEX t_s ON t(s)").unwrap();
+
+        let mut ids = |condition: &str| -> Vec<i64> {
+            let sql = format!("SELECT id FROM t WHERE {} ORDER BY id", condition);
+            return conn
+                .execute(&sql)
+                .unwrap()
+                .rows
+                .iter()
+                .map(|row| row[0].get_int_val().unwrap())
+                .collect();
+        };
+        // a column with numeric affinity converts text compared with it
+        assert_eq!(ids("i = '10'"), [2]);
+        assert_eq!(ids("i < '3'"), [1, 3]);
+        assert_eq!(ids("i IN ('1', '2')"), [1, 3]);
+        // a text column converts numbers, including through its index
+        assert_eq!(ids("s = 10"), [2]);
+        assert_eq!(ids("s BETWEEN 1 AND 2"), [1, 2, 3]);
+        // and numeric affinity wins when comparing columns
+        assert_eq!(ids("i = s"), [1, 2, 3]);
+        // a column with no declared type converts nothing
+        assert_eq!(ids("n = 10"), Vec::<i64>::new());
+        assert_eq!(ids("n = CAST(10 AS TEXT)"), [2]);
+        assert_eq!(ids("+i = '1'"), Vec::<i64>::new());
     }
 }
@@ -0,0 +1,274 @@
+//! Statistics on the columns of a table, for getting to know an
+//! unfamiliar database before writing queries against it. They are
+//! gathered in a single pass over the table, in memory that does not
+//! grow with its size: the number of distinct values is estimated with
+//! HyperLogLog rather than counted exactly.
+
+use eyre::Result;
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+
+use crate::connection::Connection;
+use crate::datatypes::Value;
+use crate::ddl;
+use crate::dump::sql_literal;
+use crate::exec;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableStats {
+    pub table: String,
+    pub rows: usize,
+    pub columns: Vec<ColumnStats>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnStats {
+    pub name: String,
+    pub nulls: usize,
+    /// An estimate of the number of distinct values other than NULL.
+    pub distinct: u64,
+    pub min: Option<Value>,
+    pub max: Option<Value>,
+    /// The average number of bytes the column's values take up in the
+    /// body of a record.
+    pub average_width: f64,
+}
+
+/// Gathers statistics on each column of a table. Values are compared as
+/// with the binary collating sequence, and values that compare equal
+/// (such as 1 and 1.0) count as the same value.
+pub fn table_stats(conn: &Connection, name: &str) -> Result<TableStats> {
+    let table = conn.table(name)?;
+    let names = table.schema.column_names();
+    let row_id_alias = ddl::row_id_alias(&table.schema.definition);
+
+    let mut columns: Vec<ColumnAccumulator> =
+        names.iter().map(|_| ColumnAccumulator::new()).collect();
+    let mut rows = 0;
+    for row in table.btree.scan() {
+        let (row_id, record) = row?;
+        rows += 1;
+        for (idx, column) in columns.iter_mut().enumerate() {
+            // a column missing from the end of the record takes no room
+            let width = record
+                .values
+                .get(idx)
+                .map_or(0, |stored| stored.data_type().get_size().unwrap_or(0));
+            // the value of an INTEGER PRIMARY KEY is the row ID, which
+            // the record holds a NULL in place of
+            let value = if row_id_alias == Some(idx) {
+                Value::Int64(row_id.0)
+            } else {
+                table.schema.column_value(&record, idx)?
+            };
+            column.add(value, width);
+        }
+    }
+
+    return Ok(TableStats {
+        table: table.schema.name.clone(),
+        rows: rows,
+        columns: names
+            .into_iter()
+            .zip(columns)
+            .map(|(name, column)| column.finish(name, rows))
+            .collect(),
+    });
+}
+
+/// Writes statistics as a table, with the columns separated by '|' as in
+/// the shell's output.
+pub fn write_stats(stats: &TableStats, out: &mut dyn Write) -> Result<()> {
+    writeln!(out, "table {}: {} rows", stats.table, stats.rows)?;
+    writeln!(out, "column|nulls|distinct|min|max|avg_width")?;
+    for column in &stats.columns {
+        let literal = |value: &Option<Value>| value.as_ref().map_or(String::new(), sql_literal);
+        writeln!(
+            out,
+            "{}|{}|{}|{}|{}|{:.1}",
+            column.name,
+            column.nulls,
+            column.distinct,
+            literal(&column.min),
+            literal(&column.max),
+            column.average_width
+        )?;
+    }
+    return Ok(());
+}
+
+struct ColumnAccumulator {
+    nulls: usize,
+    distinct: HyperLogLog,
+    min: Option<Value>,
+    max: Option<Value>,
+    total_width: usize,
+}
+
+impl ColumnAccumulator {
+    fn new() -> Self {
+        return Self {
+            nulls: 0,
+            distinct: HyperLogLog::new(),
+            min: None,
+            max: None,
+            total_width: 0,
+        };
+    }
+
+    fn add(&mut self, value: Value, width: usize) {
+        self.total_width += width;
+        if matches!(value, Value::Null) {
+            self.nulls += 1;
+            return;
+        }
+        self.distinct.add(hash_value(&value));
+        if self
+            .min
+            .as_ref()
+            .is_none_or(|min| exec::compare(&value, min) == Ordering::Less)
+        {
+            self.min = Some(value.clone());
+        }
+        if self
+            .max
+            .as_ref()
+            .is_none_or(|max| exec::compare(&value, max) == Ordering::Greater)
+        {
+            self.max = Some(value);
+        }
+    }
+
+    fn finish(self, name: String, rows: usize) -> ColumnStats {
+        return ColumnStats {
+            name: name,
+            nulls: self.nulls,
+            distinct: self.distinct.estimate(),
+            min: self.min,
+            max: self.max,
+            average_width: if rows == 0 {
+                0.0
+            } else {
+                self.total_width as f64 / rows as f64
+            },
+        };
+    }
+}
+
+/// Hashes a value so that values that compare equal have the same hash:
+/// integers, and reals with an integer value, are hashed as integers.
+fn hash_value(value: &Value) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    match value {
+        Value::Float(f) if f.fract() == 0.0 && f.abs() < 9.0e18 => {
+            0u8.hash(&mut hasher);
+            (*f as i64).hash(&mut hasher);
+        }
+        Value::Float(f) => {
+            1u8.hash(&mut hasher);
+            f.to_bits().hash(&mut hasher);
+        }
+        Value::String(s) => {
+            2u8.hash(&mut hasher);
+            s.hash(&mut hasher);
+        }
+        Value::Blob(bytes) | Value::Internal(bytes) => {
+            3u8.hash(&mut hasher);
+            bytes.hash(&mut hasher);
+        }
+        value => {
+            0u8.hash(&mut hasher);
+            value.get_int_val().unwrap_or(0).hash(&mut hasher);
+        }
+    }
+    return hasher.finish();
+}
+
+/// The number of bits of a hash that pick a register.
+const HLL_PRECISION: u32 = 12;
+
+/// A HyperLogLog sketch, which estimates the number of distinct hashes
+/// added to it to within about 2%, in 4 KiB. Each hash picks a register
+/// by its top bits, which keeps the longest run of leading zeros seen in
+/// the rest of the hash.
+struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    fn new() -> Self {
+        return Self {
+            registers: vec![0; 1 << HLL_PRECISION],
+        };
+    }
+
+    fn add(&mut self, hash: u64) {
+        let register = (hash >> (64 - HLL_PRECISION)) as usize;
+        let rest = hash << HLL_PRECISION;
+        let rank = std::cmp::min(rest.leading_zeros(), 64 - HLL_PRECISION) as u8 + 1;
+        if rank > self.registers[register] {
+            self.registers[register] = rank;
+        }
+    }
+
+    fn estimate(&self) -> u64 {
+        let m = self.registers.len() as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|&r| 2f64.powi(-(r as i32))).sum();
+        let estimate = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|&&r| r == 0).count();
+        // small counts are estimated better from the number of registers
+        // still empty
+        if estimate <= 2.5 * m && zeros > 0 {
+            return (m * (m / zeros as f64).ln()).round() as u64;
+        }
+        return estimate.round() as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn column_stats() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        conn.execute("CREATE TABLE t(id INTEGER PRIMARY KEY, a, b)")
+            .unwrap();
+        let values: Vec<String> = (1..=20000)
+            .map(|i| {
+                format!(
+                    "({}, {}, {})",
+                    i,
+                    i % 5000,
+                    if i % 4 == 0 { "NULL" } else { "'x'" }
+                )
+            })
+            .collect();
+        conn.execute(&format!("INSERT INTO t VALUES {}", values.join(", ")))
+            .unwrap();
+        conn.execute("INSERT INTO t VALUES (20001, 4999.0, 1.5)")
+            .unwrap();
+
+        let stats = table_stats(&conn, "t").unwrap();
+        assert_eq!(stats.rows, 20001);
+        let (id, a, b) = (&stats.columns[0], &stats.columns[1], &stats.columns[2]);
+        assert_eq!(id.min, Some(Value::Int64(1)));
+        assert_eq!(id.max, Some(Value::Int64(20001)));
+        assert_eq!(id.nulls, 0);
+        assert!((19000..21000).contains(&id.distinct), "{}", id.distinct);
+        assert!((4900..5100).contains(&a.distinct), "{}", a.distinct);
+        assert_eq!(b.nulls, 5000);
+        assert_eq!(b.distinct, 2);
+        assert_eq!(b.min, Some(Value::Float(1.5)));
+        assert_eq!(b.max, Some(Value::String("x".to_string())));
+
+        let mut out = Vec::new();
+        write_stats(&stats, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("b|5000|2|1.5|'x'|0.8\n"), "{}", out);
+    }
+}
@@ -0,0 +1,325 @@
+//! Per-tenant namespaces in one database file, for programs that keep
+//! the data of each of their customers apart. A tenant's tables, indexes
+//! and views are named `<tenant>__<name>`, and the SQL run for a tenant
+//! is rewritten to use those names, so that every tenant can have the
+//! same schema and queries, but sees only its own rows.
+//!
+//! The tables of the schema (`sqlite_schema` and the like) are shared,
+//! and are not renamed. Names in PRAGMA arguments are not renamed
+//! either.
+
+use eyre::{eyre, Result};
+use std::collections::HashSet;
+
+use crate::btree::Btree;
+use crate::connection::Connection;
+use crate::datatypes::Value;
+use crate::parser;
+use crate::schema::SCHEMA_ROOT_PAGE;
+use crate::statement::{QueryResult, Statement};
+use crate::tokenizer::{self, Token, TokenKind};
+use crate::vacuum;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tenant {
+    pub id: String,
+}
+
+impl Tenant {
+    /// A tenant's ID is made up of ASCII letters and digits only, so that
+    /// the prefix of one tenant's names can never be the start of
+    /// another's.
+    pub fn new(id: &str) -> Result<Self> {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(eyre!("invalid tenant ID: {}", id));
+        }
+        return Ok(Self { id: id.to_string() });
+    }
+
+    /// The name an object of the tenant goes by in the database.
+    pub fn object_name(&self, name: &str) -> String {
+        return format!("{}{}", self.prefix(), name);
+    }
+
+    /// Creates the tenant's tables, indexes and views from their CREATE
+    /// statements, as they would be written for a database of their own.
+    /// If any of them fails, none of them are created.
+    pub fn create(&self, conn: &mut Connection, schema: &[&str]) -> Result<()> {
+        return conn.write_statement(|conn| {
+            for sql in schema {
+                self.execute(conn, sql)?;
+            }
+            return Ok(());
+        });
+    }
+
+    /// Rewrites a statement to use the tenant's names for the tables,
+    /// indexes and views it refers to.
+    pub fn route(&self, sql: &str) -> Result<String> {
+        let tokens = tokenizer::tokenize(sql)?;
+        let name = |token: &Token| match &token.kind {
+            TokenKind::Identifier(s) | TokenKind::QuotedIdentifier(s) => Some(s.clone()),
+            _ => None,
+        };
+
+        let mut renamed = Vec::new();
+        // whether a list of tables is being read, at each level of
+        // parentheses
+        let mut in_from = vec![false];
+        for (i, token) in tokens.iter().enumerate() {
+            match &token.kind {
+                TokenKind::LeftParen => in_from.push(false),
+                TokenKind::RightParen => {
+                    in_from.pop();
+                    if in_from.is_empty() {
+                        in_from.push(false);
+                    }
+                }
+                TokenKind::Semicolon => in_from = vec![false],
+                TokenKind::Identifier(_)
+                    if token.is_keyword("FROM") || token.is_keyword("JOIN") =>
+                {
+                    *in_from.last_mut().unwrap() = true;
+                }
+                TokenKind::Identifier(_)
+                    if FROM_END_KEYWORDS.iter().any(|kw| token.is_keyword(kw)) =>
+                {
+                    *in_from.last_mut().unwrap() = false;
+                }
+                TokenKind::Identifier(_) | TokenKind::QuotedIdentifier(_)
+                    if names_object(&tokens, i, *in_from.last().unwrap()) =>
+                {
+                    renamed.push(i);
+                }
+                _ => (),
+            }
+        }
+        renamed.retain(|&i| {
+            !name(&tokens[i])
+                .unwrap()
+                .to_lowercase()
+                .starts_with("sqlite_")
+        });
+
+        // a table named in the statement may also qualify its columns
+        let tables: HashSet<String> = renamed
+            .iter()
+            .map(|&i| name(&tokens[i]).unwrap().to_lowercase())
+            .collect();
+        for (i, token) in tokens.iter().enumerate() {
+            if tokens.get(i + 1).map(|t| &t.kind) == Some(&TokenKind::Dot)
+                && name(token).is_some_and(|s| tables.contains(&s.to_lowercase()))
+            {
+                renamed.push(i);
+            }
+        }
+        renamed.sort_unstable();
+
+        let mut output = sql.to_string();
+        for i in renamed.into_iter().rev() {
+            let new = parser::quote_name(&self.object_name(&name(&tokens[i]).unwrap()));
+            output.replace_range(tokens[i].offset..tokens[i].end, &new);
+        }
+        return Ok(output);
+    }
+
+    /// Prepares a statement for the tenant (see `route`).
+    pub fn prepare(&self, conn: &Connection, sql: &str) -> Result<Statement> {
+        return conn.prepare(&self.route(sql)?);
+    }
+
+    /// Runs a statement for the tenant (see `route`).
+    pub fn execute(&self, conn: &mut Connection, sql: &str) -> Result<QueryResult> {
+        return conn.execute(&self.route(sql)?);
+    }
+
+    /// The names of the tenant's tables, without the prefix.
+    pub fn tables(&self, conn: &Connection) -> Vec<String> {
+        let prefix = self.prefix().to_lowercase();
+        return conn
+            .schema
+            .tables
+            .iter()
+            .filter(|t| t.name.to_lowercase().starts_with(&prefix))
+            .map(|t| t.name[prefix.len()..].to_string())
+            .collect();
+    }
+
+    /// Removes all of the tenant's tables, indexes and views from the
+    /// schema at once, putting the pages they used on the freelist. As
+    /// there is no DROP statement to replay, the change is not recorded
+    /// in the replication log.
+    pub fn drop(&self, conn: &mut Connection) -> Result<usize> {
+        let prefix = self.prefix().to_lowercase();
+        return conn.write_statement(|conn| {
+            let schema_tree = Btree::new(
+                "sqlite_schema".to_string(),
+                "sqlite_schema".to_string(),
+                SCHEMA_ROOT_PAGE,
+                conn.pager.clone(),
+            );
+            let mut row_ids = Vec::new();
+            let mut trees = Vec::new();
+            for row in schema_tree.scan() {
+                let (row_id, record) = row?;
+                // an index belongs to the tenant if its table does, which
+                // takes in the autoindexes of its tables
+                let owned = record.values[1..3].iter().any(|value| match value {
+                    Value::String(s) => s.to_lowercase().starts_with(&prefix),
+                    _ => false,
+                });
+                if owned {
+                    row_ids.push(row_id.0);
+                    // views have no tree
+                    if let (Value::String(name), Some(root)) =
+                        (&record.values[1], record.values[3].get_int_val())
+                    {
+                        if root > 0 {
+                            trees.push((name.clone(), root as usize));
+                        }
+                    }
+                }
+            }
+            for row_id in &row_ids {
+                schema_tree.delete(*row_id)?;
+            }
+            // from the largest root down, as `vacuum::remove_root` needs
+            trees.sort_by_key(|(_, root_page)| std::cmp::Reverse(*root_page));
+            for (name, root_page) in trees {
+                Btree::new(name.clone(), name, root_page, conn.pager.clone()).destroy()?;
+                vacuum::remove_root(conn, root_page)?;
+            }
+            if !row_ids.is_empty() {
+                conn.db_options.schema_cookie = conn.db_options.schema_cookie.wrapping_add(1);
+            }
+            return Ok(row_ids.len());
+        });
+    }
+
+    fn prefix(&self) -> String {
+        return format!("{}__", self.id);
+    }
+}
+
+/// The IDs of the tenants that have tables in the database.
+pub fn tenants(conn: &Connection) -> Vec<String> {
+    let mut ids: Vec<String> = Vec::new();
+    for table in &conn.schema.tables {
+        if let Some((id, _)) = table.name.split_once("__") {
+            if Tenant::new(id).is_ok() && !ids.contains(&id.to_string()) {
+                ids.push(id.to_string());
+            }
+        }
+    }
+    return ids;
+}
+
+/// The keywords that end a list of tables in a FROM clause.
+const FROM_END_KEYWORDS: &[&str] = &[
+    "WHERE",
+    "GROUP",
+    "HAVING",
+    "ORDER",
+    "LIMIT",
+    "ON",
+    "USING",
+    "UNION",
+    "INTERSECT",
+    "EXCEPT",
+    "SET",
+    "VALUES",
+];
+
+/// Tests whether a token names a table, index or view: one that follows
+/// a keyword that comes before such a name, or one in a list of tables
+/// after a comma.
+fn names_object(tokens: &[Token], i: usize, in_from: bool) -> bool {
+    let prev = match i.checked_sub(1) {
+        Some(p) => &tokens[p],
+        None => return false,
+    };
+    let first = &tokens[0];
+    if tokens[i].is_keyword("IF") && tokens[i + 1].is_keyword("NOT") {
+        return false;
+    }
+    if [
+        "FROM",
+        "JOIN",
+        "INTO",
+        "TABLE",
+        "VIEW",
+        "INDEX",
+        "REFERENCES",
+    ]
+    .iter()
+    .any(|kw| prev.is_keyword(kw))
+    {
+        return true;
+    }
+    // CREATE ... IF NOT EXISTS name
+    if prev.is_keyword("EXISTS") && i >= 2 && tokens[i - 2].is_keyword("NOT") {
+        return true;
+    }
+    // CREATE INDEX name ON table, and ALTER TABLE name RENAME TO new
+    if (prev.is_keyword("ON") && first.is_keyword("CREATE"))
+        || (prev.is_keyword("TO") && first.is_keyword("ALTER"))
+    {
+        return true;
+    }
+    return in_from && prev.kind == TokenKind::Comma;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tenants_are_kept_apart() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::new(file.path().to_str().unwrap()).unwrap();
+        let schema = [
+            "CREATE TABLE users(id INTEGER PRIMARY KEY, name TEXT UNIQUE)",
+            "CREATE TABLE IF NOT EXISTS orders(user_id, total)",
+            "CREATE VIEW big_orders AS SELECT * FROM orders WHERE total > 100",
+        ];
+        let acme = Tenant::new("acme").unwrap();
+        let globex = Tenant::new("globex").unwrap();
+        assert!(Tenant::new("a__b").is_err());
+        acme.create(&mut conn, &schema).unwrap();
+        globex.create(&mut conn, &schema).unwrap();
+        assert_eq!(tenants(&conn), vec!["acme", "globex"]);
+
+        assert_eq!(
+            acme.route("SELECT users.name, o.total FROM users, orders AS o WHERE o.user_id = 1")
+                .unwrap(),
+            "SELECT acme__users.name, o.total FROM acme__users, acme__orders AS o WHERE o.user_id = 1"
+        );
+        acme.execute(&mut conn, "INSERT INTO orders VALUES (1, 150), (1, 50)")
+            .unwrap();
+        globex
+            .execute(&mut conn, "INSERT INTO orders VALUES (2, 500)")
+            .unwrap();
+        acme.execute(&mut conn, "CREATE INDEX orders_user ON orders(user_id)")
+            .unwrap();
+        let rows = acme
+            .execute(&mut conn, "SELECT total FROM big_orders")
+            .unwrap()
+            .rows;
+        assert_eq!(rows, vec![vec![Value::Int64(150)]]);
+
+        assert_eq!(acme.drop(&mut conn).unwrap(), 5);
+        assert!(acme.tables(&conn).is_empty());
+        assert!(acme.execute(&mut conn, "SELECT * FROM orders").is_err());
+        // the pages of the dropped tables and indexes are free for reuse
+        assert!(conn.db_options.num_freelist > 0);
+        assert!(crate::integrity::check_structure(&conn).unwrap().is_empty());
+        assert_eq!(globex.tables(&conn), vec!["users", "orders"]);
+        let rows = globex
+            .execute(&mut conn, "SELECT total FROM orders")
+            .unwrap()
+            .rows;
+        assert_eq!(rows, vec![vec![Value::Int64(500)]]);
+        let problems = crate::integrity::check_indexes(&conn).unwrap();
+        assert!(problems.is_empty(), "{:?}", problems);
+    }
+}
@@ -0,0 +1,380 @@
+use eyre::{eyre, Result};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    Identifier(String),
+    QuotedIdentifier(String),
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Blob(Vec<u8>),
+    /// A parameter placeholder, as written: `?`, `?NNN`, `:name`,
+    /// `@name` or `$name`.
+    Variable(String),
+    LeftParen,
+    RightParen,
+    Comma,
+    Semicolon,
+    Dot,
+    Star,
+    Plus,
+    Minus,
+    Slash,
+    Percent,
+    Concat,
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+    BitAnd,
+    BitOr,
+    BitNot,
+    ShiftLeft,
+    ShiftRight,
+    Eof,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    /// Byte offset of the start of the token in the SQL text.
+    pub offset: usize,
+    /// Byte offset just past the end of the token.
+    pub end: usize,
+}
+
+impl Token {
+    /// Tests whether this token is the given keyword. Keywords are not
+    /// distinguished from identifiers by the tokenizer; it is up to the
+    /// parser to decide which it expects. Quoted identifiers never
+    /// match a keyword.
+    pub fn is_keyword(&self, keyword: &str) -> bool {
+        return match &self.kind {
+            TokenKind::Identifier(s) => s.eq_ignore_ascii_case(keyword),
+            _ => false,
+        };
+    }
+
+    /// A short representation of the token for use in error messages.
+    pub fn describe(&self) -> String {
+        return match &self.kind {
+            TokenKind::Identifier(s) => s.clone(),
+            TokenKind::QuotedIdentifier(s) => format!("\"{}\"", s),
+            TokenKind::String(s) => format!("'{}'", s),
+            TokenKind::Integer(i) => i.to_string(),
+            TokenKind::Float(f) => f.to_string(),
+            TokenKind::Blob(_) => "blob literal".to_string(),
+            TokenKind::Variable(s) => s.clone(),
+            TokenKind::LeftParen => "(".to_string(),
+            TokenKind::RightParen => ")".to_string(),
+            TokenKind::Comma => ",".to_string(),
+            TokenKind::Semicolon => ";".to_string(),
+            TokenKind::Dot => ".".to_string(),
+            TokenKind::Star => "*".to_string(),
+            TokenKind::Plus => "+".to_string(),
+            TokenKind::Minus => "-".to_string(),
+            TokenKind::Slash => "/".to_string(),
+            TokenKind::Percent => "%".to_string(),
+            TokenKind::Concat => "||".to_string(),
+            TokenKind::Eq => "=".to_string(),
+            TokenKind::NotEq => "!=".to_string(),
+            TokenKind::Lt => "<".to_string(),
+            TokenKind::LtEq => "<=".to_string(),
+            TokenKind::Gt => ">".to_string(),
+            TokenKind::GtEq => ">=".to_string(),
+            TokenKind::BitAnd => "&".to_string(),
+            TokenKind::BitOr => "|".to_string(),
+            TokenKind::BitNot => "~".to_string(),
+            TokenKind::ShiftLeft => "<<".to_string(),
+            TokenKind::ShiftRight => ">>".to_string(),
+            TokenKind::Eof => "end of input".to_string(),
+        };
+    }
+}
+
+/// Splits SQL text into tokens. The returned list always ends with an
+/// `Eof` token.
+pub fn tokenize(sql: &str) -> Result<Vec<Token>> {
+    let chars: Vec<(usize, char)> = sql.char_indices().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let (offset, c) = chars[i];
+        let next = chars.get(i + 1).map(|(_, c)| *c);
+
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        // comments
+        if c == '-' && next == Some('-') {
+            while i < chars.len() && chars[i].1 != '\n' {
+                i += 1;
+            }
+            continue;
+        }
+        if c == '/' && next == Some('*') {
+            i += 2;
+            while i < chars.len()
+                && !(chars[i].1 == '*' && chars.get(i + 1).map(|(_, c)| *c) == Some('/'))
+            {
+                i += 1;
+            }
+            i += 2;
+            continue;
+        }
+
+        let (kind, len) = match c {
+            '(' => (TokenKind::LeftParen, 1),
+            ')' => (TokenKind::RightParen, 1),
+            ',' => (TokenKind::Comma, 1),
+            ';' => (TokenKind::Semicolon, 1),
+            '*' => (TokenKind::Star, 1),
+            '+' => (TokenKind::Plus, 1),
+            '-' => (TokenKind::Minus, 1),
+            '/' => (TokenKind::Slash, 1),
+            '%' => (TokenKind::Percent, 1),
+            '&' => (TokenKind::BitAnd, 1),
+            '~' => (TokenKind::BitNot, 1),
+            '|' if next == Some('|') => (TokenKind::Concat, 2),
+            '|' => (TokenKind::BitOr, 1),
+            '=' if next == Some('=') => (TokenKind::Eq, 2),
+            '=' => (TokenKind::Eq, 1),
+            '!' if next == Some('=') => (TokenKind::NotEq, 2),
+            '<' if next == Some('>') => (TokenKind::NotEq, 2),
+            '<' if next == Some('=') => (TokenKind::LtEq, 2),
+            '<' if next == Some('<') => (TokenKind::ShiftLeft, 2),
+            '<' => (TokenKind::Lt, 1),
+            '>' if next == Some('=') => (TokenKind::GtEq, 2),
+            '>' if next == Some('>') => (TokenKind::ShiftRight, 2),
+            '>' => (TokenKind::Gt, 1),
+            '.' if !next.is_some_and(|c| c.is_ascii_digit()) => (TokenKind::Dot, 1),
+            '\'' => {
+                let (s, len) = read_quoted(&chars[i..], '\'')?;
+                (TokenKind::String(s), len)
+            }
+            '"' | '`' => {
+                let (s, len) = read_quoted(&chars[i..], c)?;
+                (TokenKind::QuotedIdentifier(s), len)
+            }
+            '[' => {
+                // as in MS Access and SQL Server, a bracketed name ends at
+                // the first closing bracket, with no way to escape one
+                let len = chars[i..]
+                    .iter()
+                    .position(|(_, c)| *c == ']')
+                    .ok_or_else(|| eyre!("unrecognized token: \"{}\"", &sql[offset..]))?
+                    + 1;
+                let s: String = chars[i + 1..i + len - 1].iter().map(|(_, c)| c).collect();
+                (TokenKind::QuotedIdentifier(s), len)
+            }
+            'x' | 'X' if next == Some('\'') => {
+                let (s, len) = read_quoted(&chars[i + 1..], '\'')?;
+                (TokenKind::Blob(parse_hex(&s)?), len + 1)
+            }
+            '?' => {
+                let mut len = 1;
+                while chars.get(i + len).is_some_and(|(_, c)| c.is_ascii_digit()) {
+                    len += 1;
+                }
+                let s: String = chars[i..i + len].iter().map(|(_, c)| c).collect();
+                (TokenKind::Variable(s), len)
+            }
+            ':' | '@' | '$' if next.is_some_and(|c| c.is_alphanumeric() || c == '_') => {
+                let mut len = 1;
+                while let Some((_, c)) = chars.get(i + len) {
+                    if c.is_alphanumeric() || *c == '_' || *c == '$' {
+                        len += 1;
+                    } else {
+                        break;
+                    }
+                }
+                let s: String = chars[i..i + len].iter().map(|(_, c)| c).collect();
+                (TokenKind::Variable(s), len)
+            }
+            c if c.is_ascii_digit() || c == '.' => read_number(&chars[i..])?,
+            c if c.is_alphabetic() || c == '_' => {
+                let mut len = 0;
+                while let Some((_, c)) = chars.get(i + len) {
+                    if c.is_alphanumeric() || *c == '_' || *c == '$' {
+                        len += 1;
+                    } else {
+                        break;
+                    }
+                }
+                let s: String = chars[i..i + len].iter().map(|(_, c)| c).collect();
+                (TokenKind::Identifier(s), len)
+            }
+            c => return Err(eyre!("unrecognized token: \"{}\"", c)),
+        };
+        i += len;
+        tokens.push(Token {
+            kind: kind,
+            offset: offset,
+            end: chars.get(i).map_or(sql.len(), |(offset, _)| *offset),
+        });
+    }
+
+    tokens.push(Token {
+        kind: TokenKind::Eof,
+        offset: sql.len(),
+        end: sql.len(),
+    });
+    return Ok(tokens);
+}
+
+/// Reads a string delimited by `quote`, where a doubled quote character
+/// stands for a literal one. Returns the unescaped string and the number
+/// of characters consumed, including the delimiters.
+fn read_quoted(chars: &[(usize, char)], quote: char) -> Result<(String, usize)> {
+    let mut output = String::new();
+    let mut i = 1;
+    loop {
+        match chars.get(i) {
+            None => return Err(eyre!("unterminated quoted string")),
+            Some((_, c)) if *c == quote => {
+                if chars.get(i + 1).map(|(_, c)| *c) == Some(quote) {
+                    output.push(quote);
+                    i += 2;
+                } else {
+                    return Ok((output, i + 1));
+                }
+            }
+            Some((_, c)) => {
+                output.push(*c);
+                i += 1;
+            }
+        }
+    }
+}
+
+fn parse_hex(s: &str) -> Result<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(eyre!("malformed blob literal: X'{}'", s));
+    }
+    return Ok((0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+        .collect());
+}
+
+fn read_number(chars: &[(usize, char)]) -> Result<(TokenKind, usize)> {
+    let mut len = 0;
+    let mut is_float = false;
+    let peek = |n: usize| chars.get(n).map(|(_, c)| *c);
+
+    while peek(len).is_some_and(|c| c.is_ascii_digit()) {
+        len += 1;
+    }
+    if peek(len) == Some('.') {
+        is_float = true;
+        len += 1;
+        while peek(len).is_some_and(|c| c.is_ascii_digit()) {
+            len += 1;
+        }
+    }
+    if matches!(peek(len), Some('e') | Some('E')) {
+        let mut exp_len = len + 1;
+        if matches!(peek(exp_len), Some('+') | Some('-')) {
+            exp_len += 1;
+        }
+        if peek(exp_len).is_some_and(|c| c.is_ascii_digit()) {
+            is_float = true;
+            len = exp_len;
+            while peek(len).is_some_and(|c| c.is_ascii_digit()) {
+                len += 1;
+            }
+        }
+    }
+    if peek(len).is_some_and(|c| c.is_alphanumeric() || c == '_') {
+        let text: String = chars[..=len].iter().map(|(_, c)| c).collect();
+        return Err(eyre!("unrecognized token: \"{}\"", text));
+    }
+
+    let text: String = chars[..len].iter().map(|(_, c)| c).collect();
+    if !is_float {
+        // integers too large for 64 bits are treated as floating point,
+        // as SQLite does
+        if let Ok(i) = text.parse::<i64>() {
+            return Ok((TokenKind::Integer(i), len));
+        }
+    }
+    let f = text
+        .parse::<f64>()
+        .map_err(|_| eyre!("unrecognized token: \"{}\"", text))?;
+    return Ok((TokenKind::Float(f), len));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(sql: &str) -> Vec<TokenKind> {
+        return tokenize(sql).unwrap().into_iter().map(|t| t.kind).collect();
+    }
+
+    #[test]
+    fn tokenize_select() {
+        assert_eq!(
+            kinds("SELECT a, \"b c\" FROM t WHERE x >= 1.5 -- comment\n;"),
+            vec![
+                TokenKind::Identifier("SELECT".to_string()),
+                TokenKind::Identifier("a".to_string()),
+                TokenKind::Comma,
+                TokenKind::QuotedIdentifier("b c".to_string()),
+                TokenKind::Identifier("FROM".to_string()),
+                TokenKind::Identifier("t".to_string()),
+                TokenKind::Identifier("WHERE".to_string()),
+                TokenKind::Identifier("x".to_string()),
+                TokenKind::GtEq,
+                TokenKind::Float(1.5),
+                TokenKind::Semicolon,
+                TokenKind::Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenize_literals() {
+        assert_eq!(
+            kinds("'it''s' x'0aFF' 42 9223372036854775808 .5 1e3 <> || /* c */ ? ?12 :a @b $c"),
+            vec![
+                TokenKind::String("it's".to_string()),
+                TokenKind::Blob(vec![0x0a, 0xff]),
+                TokenKind::Integer(42),
+                TokenKind::Float(9223372036854775808.0),
+                TokenKind::Float(0.5),
+                TokenKind::Float(1000.0),
+                TokenKind::NotEq,
+                TokenKind::Concat,
+                TokenKind::Variable("?".to_string()),
+                TokenKind::Variable("?12".to_string()),
+                TokenKind::Variable(":a".to_string()),
+                TokenKind::Variable("@b".to_string()),
+                TokenKind::Variable("$c".to_string()),
+                TokenKind::Eof,
+            ]
+        );
+        assert!(tokenize("'unterminated").is_err());
+        assert!(tokenize("12abc").is_err());
+    }
+
+    #[test]
+    fn tokenize_quoted_identifiers() {
+        assert_eq!(
+            kinds("\"a\"\"b\" [c \"d] `e``f` [[g]"),
+            vec![
+                TokenKind::QuotedIdentifier("a\"b".to_string()),
+                TokenKind::QuotedIdentifier("c \"d".to_string()),
+                TokenKind::QuotedIdentifier("e`f".to_string()),
+                TokenKind::QuotedIdentifier("[g".to_string()),
+                TokenKind::Eof,
+            ]
+        );
+        assert!(tokenize("[unterminated").is_err());
+    }
+}
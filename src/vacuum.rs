@@ -0,0 +1,631 @@
+//! Giving the space of free pages back to the file system. `VACUUM`
+//! rebuilds the whole database into a temporary file, with no free
+//! pages, and copies it back over the database file.
+//! `PRAGMA incremental_vacuum` works on an auto-vacuum database in
+//! place instead: it moves the pages at the end of the file onto free
+//! pages, using the pointer map to find what refers to each one, and
+//! then truncates the file.
+
+use eyre::{eyre, Result};
+use std::collections::BTreeSet;
+
+use crate::btree::{Btree, BtreePage, Cell, PageType, Record};
+use crate::connection::Connection;
+use crate::datatypes::Value;
+use crate::lock::LockLevel;
+use crate::pager::{Pager, PtrmapEntry, PtrmapType, SharedPager};
+use crate::parsing;
+use crate::schema::SCHEMA_ROOT_PAGE;
+use crate::DbOptions;
+
+/// How a database gives free pages back to the file system, as `PRAGMA
+/// auto_vacuum` sets it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AutoVacuum {
+    /// Only by `VACUUM`.
+    None,
+    /// At the end of each transaction.
+    Full,
+    /// By `PRAGMA incremental_vacuum`.
+    Incremental,
+}
+
+impl AutoVacuum {
+    /// The mode a database's header gives, given whether it has a pointer
+    /// map.
+    pub fn from_header(auto_vacuum: bool, header: &DbOptions) -> Self {
+        return match (auto_vacuum, header.incremental_vacuum) {
+            (false, _) => AutoVacuum::None,
+            (true, false) => AutoVacuum::Full,
+            (true, true) => AutoVacuum::Incremental,
+        };
+    }
+
+    /// The mode a pragma's value names, as a number or by name. As in
+    /// SQLite, anything else is taken as none.
+    pub fn from_pragma(value: &Value) -> Self {
+        if let Value::String(s) = value {
+            match s.to_lowercase().as_str() {
+                "full" => return AutoVacuum::Full,
+                "incremental" => return AutoVacuum::Incremental,
+                _ => (),
+            }
+        }
+        return match value.get_int_val() {
+            Some(1) => AutoVacuum::Full,
+            Some(2) => AutoVacuum::Incremental,
+            _ => AutoVacuum::None,
+        };
+    }
+
+    pub fn to_pragma(self) -> i64 {
+        return match self {
+            AutoVacuum::None => 0,
+            AutoVacuum::Full => 1,
+            AutoVacuum::Incremental => 2,
+        };
+    }
+
+    /// Sets the mode in a header. The largest root page is only a marker
+    /// here, that the database has a pointer map, until the trees are
+    /// laid out.
+    pub fn set_header(self, header: &mut DbOptions) {
+        if self == AutoVacuum::None {
+            header.largest_root_page = 0;
+        } else if header.largest_root_page == 0 {
+            header.largest_root_page = SCHEMA_ROOT_PAGE as u32;
+        }
+        header.incremental_vacuum = self == AutoVacuum::Incremental;
+    }
+}
+
+/// Rebuilds the database, as `VACUUM` does. The trees of the tables and
+/// indexes are copied page by page into a new database in a temporary
+/// file, with the free space on each page gathered together, and the
+/// records in sqlite_schema are given the new root pages. The new file
+/// then replaces the contents of the old one. An auto-vacuum database
+/// stays one, with its root pages first and a new pointer map, unless
+/// `PRAGMA auto_vacuum` has since asked for another mode, which the new
+/// database takes on.
+pub fn vacuum(conn: &mut Connection) -> Result<()> {
+    if conn.pager.read().statement_depth() > 0 {
+        return Err(eyre!("cannot VACUUM from within a transaction"));
+    }
+    if conn.is_read_only() {
+        return Err(eyre!("attempt to write a readonly database"));
+    }
+    return conn.with_shared_lock(|conn| {
+        conn.pager.write().lock(LockLevel::Reserved)?;
+        let result = rebuild(conn);
+        conn.pager.write().unlock(LockLevel::Shared)?;
+        return result;
+    });
+}
+
+fn rebuild(conn: &mut Connection) -> Result<()> {
+    let mut header = conn.db_options;
+    if let Some(mode) = conn.next_auto_vacuum {
+        mode.set_header(&mut header);
+    }
+    // the root pages are laid out again from the start of the file
+    if header.largest_root_page != 0 {
+        header.largest_root_page = SCHEMA_ROOT_PAGE as u32;
+    }
+    header.num_pages = 1;
+    header.first_freelist = 0;
+    header.num_freelist = 0;
+    let file = tempfile::NamedTempFile::new()?;
+    let filename = file
+        .path()
+        .to_str()
+        .ok_or_else(|| eyre!("Could not open a temporary file."))?;
+    let target = SharedPager::new(Pager::new(filename, &header)?);
+
+    let schema_tree = Btree::new(
+        "sqlite_schema".to_string(),
+        "sqlite_schema".to_string(),
+        SCHEMA_ROOT_PAGE,
+        conn.pager.clone(),
+    );
+    let mut rows = Vec::new();
+    for row in schema_tree.scan() {
+        rows.push(row?);
+    }
+
+    // the root pages are allocated before any others, as an auto-vacuum
+    // database keeps them at the start of the file
+    let mut roots = Vec::new();
+    for (_, record) in &mut rows {
+        let root = match record.values.get(3).and_then(|value| value.get_int_val()) {
+            // views and triggers have no tree
+            Some(root) if root > 0 => root as usize,
+            _ => continue,
+        };
+        let page_type = conn.pager.read().get_page(root)?.header().page_type;
+        let new_root = allocate_root(&mut target.write(), page_type)?;
+        let mut values = record.values.clone();
+        values[3] = Value::Int64(new_root as i64);
+        *record = Record::from_values(values);
+        roots.push((root, new_root));
+    }
+    let max_depth = conn.pager.read().limits().max_btree_depth;
+    for &(root, new_root) in &roots {
+        let mut source = conn.pager.write();
+        copy_page(&mut source, &mut target.write(), root, new_root, max_depth)?;
+    }
+    let new_schema = Btree::new(
+        "sqlite_schema".to_string(),
+        "sqlite_schema".to_string(),
+        SCHEMA_ROOT_PAGE,
+        target.clone(),
+    );
+    for (row_id, record) in &rows {
+        new_schema.insert(row_id.0, record)?;
+    }
+
+    let mut target = target.write();
+    // as in SQLite, the schema changes as far as other connections can
+    // tell, as the root pages have moved
+    header.schema_cookie = header.schema_cookie.wrapping_add(1);
+    target.flush_all(&mut header)?;
+    let image = target.serialize()?;
+
+    let mut pager = conn.pager.write();
+    pager.lock(LockLevel::Exclusive)?;
+    pager.replace_file(&image)?;
+    pager.schema_changed();
+    drop(pager);
+    conn.db_options = header;
+    return conn.refresh_schema();
+}
+
+/// Copies a page of a tree, and everything below it, to the page `dest`
+/// of another database, allocating pages there for its children and
+/// overflow pages as they are reached.
+fn copy_page(
+    source: &mut Pager,
+    target: &mut Pager,
+    page_num: usize,
+    dest: usize,
+    depth_left: usize,
+) -> Result<()> {
+    if depth_left == 0 {
+        return Err(eyre!(
+            "page {} is too deep in its tree; database disk image is malformed",
+            page_num
+        ));
+    }
+    let page = source.get_page(page_num)?;
+    let page_type = page.header().page_type;
+    let (page_size, reserved_space) = (source.page_size(), source.reserved_space());
+    let mut cells = Vec::new();
+    for bytes in page.cells() {
+        let mut cell = Cell::parse(page_type, &bytes, page_size, reserved_space as usize)
+            .ok_or_else(|| eyre!("page {} is malformed", page_num))?;
+        if let Some(child) = cell.left_child {
+            let new_child = target.allocate_page(page_type)?;
+            copy_page(source, target, child as usize, new_child, depth_left - 1)?;
+            cell.left_child = Some(new_child as u32);
+        }
+        if let Some(first) = cell.overflow_page {
+            let overflow = cell.payload_size - cell.payload.len();
+            cell.overflow_page = Some(copy_overflow(source, target, first as usize, overflow)?);
+        }
+        cells.push(cell.serialize(page_type));
+    }
+    let mut right_pointer = None;
+    if let Some(child) = page.header().right_pointer {
+        let new_child = target.allocate_page(page_type)?;
+        copy_page(source, target, child as usize, new_child, depth_left - 1)?;
+        right_pointer = Some(new_child as u32);
+    }
+    let page = BtreePage::from_cells(
+        page_type,
+        &cells,
+        right_pointer,
+        0,
+        page_size,
+        reserved_space,
+    )
+    .ok_or_else(|| eyre!("page {} is malformed", page_num))?;
+    return target.insert(dest, page);
+}
+
+/// Copies the chain of overflow pages holding `size` bytes of a payload
+/// to new pages of another database, returning the first of them.
+fn copy_overflow(source: &mut Pager, target: &mut Pager, first: usize, size: usize) -> Result<u32> {
+    let per_page = source.page_size() - source.reserved_space() as usize - 4;
+    let mut pages = Vec::new();
+    let mut page_num = first;
+    let mut remaining = size;
+    while remaining > 0 {
+        if page_num == 0 {
+            return Err(eyre!(
+                "overflow chain ends early; database disk image is malformed"
+            ));
+        }
+        let bytes = source.get_raw_page(page_num)?;
+        page_num = parsing::be_u32(&bytes[..4])? as usize;
+        remaining = remaining.saturating_sub(per_page);
+        pages.push(bytes);
+    }
+    let mut page_nums = Vec::with_capacity(pages.len());
+    for bytes in &pages {
+        page_nums.push(target.allocate_raw_page(vec![0; bytes.len()])?);
+    }
+    for (i, mut bytes) in pages.into_iter().enumerate() {
+        let next = page_nums.get(i + 1).map_or(0, |&next| next as u32);
+        bytes[..4].copy_from_slice(&next.to_be_bytes());
+        target.insert_raw_page(page_nums[i], bytes)?;
+        if i > 0 && target.is_auto_vacuum() {
+            let entry = ptrmap_entry(PtrmapType::Overflow2, page_nums[i - 1]);
+            target.ptrmap_put(page_nums[i], entry)?;
+        }
+    }
+    return Ok(page_nums[0] as u32);
+}
+
+/// Allocates the root page of a new tree. An auto-vacuum database keeps
+/// its root pages together at the start of the file, so the new root
+/// goes on the page after the largest one so far: if that page is free,
+/// it is taken off the freelist, and if it is in use, what is on it is
+/// moved to another page first.
+pub fn allocate_root(pager: &mut Pager, page_type: PageType) -> Result<usize> {
+    if !pager.is_auto_vacuum() {
+        return pager.allocate_page(page_type);
+    }
+    let mut root = pager.largest_root + 1;
+    while pager.is_ptrmap_page(root) || root == pager.lock_byte_page() {
+        root += 1;
+    }
+    if root > pager.num_pages {
+        // every page is a root page, so the file grows by one
+        if pager.allocate_page(page_type)? != root {
+            return Err(eyre!("Could not allocate page {} as a root page.", root));
+        }
+    } else {
+        if !pager.take_free_page(root)? {
+            let dest = pager.allocate_page(page_type)?;
+            move_page(pager, root, dest)?;
+        }
+        let page = BtreePage::new(page_type, pager.page_size(), pager.reserved_space());
+        pager.insert(root, page)?;
+    }
+    pager.ptrmap_put(root, ptrmap_entry(PtrmapType::RootPage, 0))?;
+    pager.largest_root = root;
+    return Ok(root);
+}
+
+/// Gives up the root page of a tree that has been destroyed (see
+/// `Btree::destroy`) and removed from the schema. An auto-vacuum
+/// database keeps its root pages together at the start of the file, so
+/// the largest root page is moved into its place, and its record in the
+/// schema changed to match. Several roots given up at once are to be
+/// given up from the largest down, so that the one moved is always one
+/// that is kept.
+pub fn remove_root(conn: &mut Connection, root: usize) -> Result<()> {
+    let mut pager = conn.pager.write();
+    if !pager.is_auto_vacuum() {
+        return Ok(());
+    }
+    let largest = pager.largest_root;
+    let mut largest_left = largest - 1;
+    while pager.is_ptrmap_page(largest_left) || largest_left == pager.lock_byte_page() {
+        largest_left -= 1;
+    }
+    pager.largest_root = largest_left;
+    if root == largest {
+        return Ok(());
+    }
+    if !pager.take_free_page(root)? {
+        return Err(eyre!("Root page {} is still in use.", root));
+    }
+    copy_to(&mut pager, largest, root)?;
+    pager.ptrmap_put(root, ptrmap_entry(PtrmapType::RootPage, 0))?;
+    pager.free_page(largest)?;
+    drop(pager);
+
+    let schema_tree = Btree::new(
+        "sqlite_schema".to_string(),
+        "sqlite_schema".to_string(),
+        SCHEMA_ROOT_PAGE,
+        conn.pager.clone(),
+    );
+    for row in schema_tree.scan() {
+        let (row_id, record) = row?;
+        if record.values.get(3).and_then(|value| value.get_int_val()) == Some(largest as i64) {
+            let mut values = record.values;
+            values[3] = Value::Int64(root as i64);
+            return schema_tree.update(row_id.0, &Record::from_values(values));
+        }
+    }
+    return Err(eyre!("No tree in the schema has root page {}.", largest));
+}
+
+/// Frees pages at the end of an auto-vacuum database, as `PRAGMA
+/// incremental_vacuum(N)` does: up to `max_pages` of them, or as many as
+/// there are on the freelist if `None`. Each page in use at the end of
+/// the file is moved onto the lowest free page, and the pages that refer
+/// to it are changed to match. Returns the number of pages freed. As in
+/// SQLite, this does nothing unless the database is in incremental
+/// vacuum mode.
+pub fn incremental_vacuum(conn: &mut Connection, max_pages: Option<usize>) -> Result<usize> {
+    if !conn.pager.read().is_auto_vacuum() || !conn.db_options.incremental_vacuum {
+        return Ok(0);
+    }
+    return conn.write_statement(|conn| free_pages(&mut conn.pager.write(), max_pages));
+}
+
+/// Frees up to `max_pages` pages at the end of an auto-vacuum database,
+/// or as many as there are on the freelist if `None`, within the
+/// statement being run. This is done for `PRAGMA incremental_vacuum`,
+/// and, in full auto-vacuum mode, as each transaction commits.
+pub fn free_pages(pager: &mut Pager, max_pages: Option<usize>) -> Result<usize> {
+    if pager.num_freelist == 0 {
+        return Ok(0);
+    }
+    let mut free: BTreeSet<usize> = pager.freelist_pages()?.into_iter().collect();
+    let limit = std::cmp::min(max_pages.unwrap_or(free.len()), free.len());
+    let mut num_pages = pager.num_pages;
+    let mut freed = 0;
+    while freed < limit {
+        if pager.is_ptrmap_page(num_pages) || num_pages == pager.lock_byte_page() {
+            num_pages -= 1;
+            continue;
+        }
+        if !free.remove(&num_pages) {
+            let dest = match free.iter().next() {
+                Some(&dest) if dest < num_pages => dest,
+                _ => break,
+            };
+            free.remove(&dest);
+            move_page(pager, num_pages, dest)?;
+        }
+        num_pages -= 1;
+        freed += 1;
+    }
+    // a pointer map page with no pages after it is not needed, and nor
+    // is the lock-byte page
+    while pager.is_ptrmap_page(num_pages) || num_pages == pager.lock_byte_page() {
+        num_pages -= 1;
+    }
+    pager.truncate(num_pages)?;
+
+    // the pages left over make up a new freelist
+    pager.first_freelist = 0;
+    pager.num_freelist = 0;
+    for page_num in free {
+        pager.free_page(page_num)?;
+    }
+    return Ok(freed);
+}
+
+/// Moves a page in use to a free page, updating the pointer map and the
+/// page that refers to it.
+fn move_page(pager: &mut Pager, page_num: usize, dest: usize) -> Result<()> {
+    let entry = pager
+        .ptrmap_get(page_num)?
+        .ok_or_else(|| eyre!("page {} is missing from the pointer map", page_num))?;
+    if entry.kind == PtrmapType::RootPage || entry.kind == PtrmapType::FreePage {
+        return Err(eyre!(
+            "page {} cannot be moved, as the pointer map has it as a {:?}",
+            page_num,
+            entry.kind
+        ));
+    }
+    let bytes = copy_to(pager, page_num, dest)?;
+    pager.ptrmap_put(dest, entry)?;
+    if entry.kind == PtrmapType::Overflow1 || entry.kind == PtrmapType::Overflow2 {
+        let next = parsing::be_u32(&bytes[..4])? as usize;
+        if next != 0 {
+            pager.ptrmap_put(next, ptrmap_entry(PtrmapType::Overflow2, dest))?;
+        }
+    }
+
+    let parent = entry.parent as usize;
+    if entry.kind == PtrmapType::Overflow2 {
+        let mut bytes = pager.get_raw_page(parent)?;
+        if parsing::be_u32(&bytes[..4])? as usize != page_num {
+            return Err(eyre!("page {} does not refer to page {}", parent, page_num));
+        }
+        bytes[..4].copy_from_slice(&(dest as u32).to_be_bytes());
+        return pager.insert_raw_page(parent, bytes);
+    }
+    let page = pager.get_page(parent)?;
+    let header = page.header();
+    let (page_size, reserved_space) = (pager.page_size(), pager.reserved_space());
+    let mut found = false;
+    let mut cells = Vec::new();
+    for bytes in page.cells() {
+        let mut cell = Cell::parse(header.page_type, &bytes, page_size, reserved_space as usize)
+            .ok_or_else(|| eyre!("page {} is malformed", parent))?;
+        if entry.kind == PtrmapType::Btree && cell.left_child == Some(page_num as u32) {
+            cell.left_child = Some(dest as u32);
+            found = true;
+        }
+        if entry.kind == PtrmapType::Overflow1 && cell.overflow_page == Some(page_num as u32) {
+            cell.overflow_page = Some(dest as u32);
+            found = true;
+        }
+        cells.push(cell.serialize(header.page_type));
+    }
+    let mut right_pointer = header.right_pointer;
+    if entry.kind == PtrmapType::Btree && right_pointer == Some(page_num as u32) {
+        right_pointer = Some(dest as u32);
+        found = true;
+    }
+    if !found {
+        return Err(eyre!("page {} does not refer to page {}", parent, page_num));
+    }
+    let page = BtreePage::from_cells(
+        header.page_type,
+        &cells,
+        right_pointer,
+        header.offset,
+        page_size,
+        reserved_space,
+    )
+    .ok_or_else(|| eyre!("page {} is malformed", parent))?;
+    return pager.insert(parent, page);
+}
+
+/// Copies a page to another, returning its bytes. A B-tree page is
+/// written with `Pager::insert`, so that the pages below it are recorded
+/// in the pointer map as being below its new place; for an overflow
+/// page, that is up to the caller.
+fn copy_to(pager: &mut Pager, page_num: usize, dest: usize) -> Result<Vec<u8>> {
+    let bytes = pager.get_raw_page(page_num)?;
+    let is_btree = match pager.ptrmap_get(page_num)? {
+        Some(entry) => entry.kind == PtrmapType::RootPage || entry.kind == PtrmapType::Btree,
+        None => false,
+    };
+    if is_btree {
+        let page = pager.get_page(page_num)?;
+        pager.insert(dest, (*page).clone())?;
+    } else {
+        pager.insert_raw_page(dest, bytes.clone())?;
+    }
+    return Ok(bytes);
+}
+
+fn ptrmap_entry(kind: PtrmapType, parent: usize) -> PtrmapEntry {
+    return PtrmapEntry {
+        kind: kind,
+        parent: parent as u32,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tenant::Tenant;
+
+    #[test]
+    fn vacuum_gives_back_free_pages() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let path = file.path().to_str().unwrap();
+        let mut conn = Connection::new(path).unwrap();
+        let count = |conn: &mut Connection, pragma: &str| {
+            let rows = conn.execute(&format!("PRAGMA {}", pragma)).unwrap().rows;
+            return rows[0][0].get_int_val().unwrap();
+        };
+        conn.execute("CREATE TABLE t(a, b)").unwrap();
+        conn.execute("CREATE TABLE u(a, b)").unwrap();
+        for table in ["t", "u"] {
+            let values: Vec<String> = (0..40)
+                .map(|i| format!("({}, '{}')", i, "x".repeat(i * 300)))
+                .collect();
+            let sql = format!("INSERT INTO {} VALUES {}", table, values.join(", "));
+            conn.execute(&sql).unwrap();
+        }
+        conn.execute("DELETE FROM u WHERE a % 2 = 0").unwrap();
+        let rows = conn.execute("SELECT * FROM u").unwrap().rows;
+        let (pages, free) = (
+            count(&mut conn, "page_count"),
+            count(&mut conn, "freelist_count"),
+        );
+        assert!(free > 0);
+
+        conn.execute("VACUUM").unwrap();
+        assert_eq!(count(&mut conn, "freelist_count"), 0);
+        assert_eq!(count(&mut conn, "page_count"), pages - free);
+        let size = std::fs::metadata(path).unwrap().len();
+        assert_eq!(size, (pages - free) as u64 * 4096);
+        assert_eq!(conn.execute("SELECT * FROM u").unwrap().rows, rows);
+        assert_eq!(count(&mut conn, "auto_vacuum"), 0);
+        assert!(conn.execute("VACUUM aux").is_err());
+
+        // as in SQLite, turning auto-vacuum on takes effect when the
+        // database is next rebuilt
+        conn.execute("PRAGMA auto_vacuum = incremental").unwrap();
+        assert_eq!(count(&mut conn, "auto_vacuum"), 0);
+        conn.execute("VACUUM").unwrap();
+        assert_eq!(count(&mut conn, "auto_vacuum"), 2);
+        assert!(conn.pager.write().is_ptrmap_page(2));
+
+        // dropping t leaves its pages free, with u's root moved into the
+        // place of t's, and the pages of u after them are moved down, a
+        // few at a time
+        conn.write_statement(|conn| {
+            let schema_tree = Btree::new(
+                "sqlite_schema".to_string(),
+                "sqlite_schema".to_string(),
+                SCHEMA_ROOT_PAGE,
+                conn.pager.clone(),
+            );
+            schema_tree.delete(1)?;
+            let root = conn.schema.table("t").unwrap().root_page;
+            let t = Btree::new("t".to_string(), "t".to_string(), root, conn.pager.clone());
+            t.destroy()?;
+            return remove_root(conn, root);
+        })
+        .unwrap();
+        let (pages, free) = (
+            count(&mut conn, "page_count"),
+            count(&mut conn, "freelist_count"),
+        );
+        assert!(free > 10);
+        conn.execute("PRAGMA incremental_vacuum(10)").unwrap();
+        assert_eq!(count(&mut conn, "freelist_count"), free - 10);
+        assert_eq!(count(&mut conn, "page_count"), pages - 10);
+        conn.execute("PRAGMA incremental_vacuum").unwrap();
+        assert_eq!(count(&mut conn, "freelist_count"), 0);
+        assert_eq!(count(&mut conn, "page_count"), pages - free);
+        assert_eq!(conn.execute("SELECT * FROM u").unwrap().rows, rows);
+        let result = conn.execute("PRAGMA integrity_check").unwrap();
+        assert_eq!(result.rows, [[Value::String("ok".to_string())]]);
+
+        let mut reopened = Connection::new(path).unwrap();
+        assert_eq!(reopened.execute("SELECT * FROM u").unwrap().rows, rows);
+    }
+
+    #[test]
+    fn auto_vacuum_writes() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let path = file.path().to_str().unwrap();
+        let mut conn = Connection::new(path).unwrap();
+        let query = |conn: &mut Connection, sql: &str| conn.execute(sql).unwrap().rows;
+        query(&mut conn, "PRAGMA auto_vacuum = full");
+        let acme = Tenant::new("acme").unwrap();
+        let globex = Tenant::new("globex").unwrap();
+        for tenant in [&acme, &globex] {
+            tenant
+                .create(&mut conn, &["CREATE TABLE t(a PRIMARY KEY, b)"])
+                .unwrap();
+            let values: Vec<String> = (0..40)
+                .map(|i| format!("({}, '{}')", i, "x".repeat(i * 300)))
+                .collect();
+            let sql = format!("INSERT INTO t VALUES {}", values.join(", "));
+            tenant.execute(&mut conn, &sql).unwrap();
+        }
+
+        // in full mode, the pages freed are given back as each statement
+        // commits
+        let pages = query(&mut conn, "PRAGMA page_count");
+        globex
+            .execute(&mut conn, "DELETE FROM t WHERE a % 2 = 0")
+            .unwrap();
+        assert_eq!(
+            query(&mut conn, "PRAGMA freelist_count"),
+            [[Value::Int64(0)]]
+        );
+        assert!(query(&mut conn, "PRAGMA page_count") < pages);
+
+        // the roots of globex's table and index take the place of acme's
+        acme.drop(&mut conn).unwrap();
+        assert_eq!(
+            query(&mut conn, "SELECT rootpage FROM sqlite_schema"),
+            [[Value::Int64(3)], [Value::Int64(4)]]
+        );
+        let ok = [[Value::String("ok".to_string())]];
+        assert_eq!(query(&mut conn, "PRAGMA integrity_check"), ok);
+        let sql = "SELECT a, length(b) FROM t";
+        let rows = globex.execute(&mut conn, sql).unwrap().rows;
+        let expected: Vec<Vec<Value>> = (0..20)
+            .map(|i| vec![Value::Int64(i * 2 + 1), Value::Int64((i * 2 + 1) * 300)])
+            .collect();
+        assert_eq!(rows, expected);
+        let mut reopened = Connection::new(path).unwrap();
+        assert_eq!(globex.execute(&mut reopened, sql).unwrap().rows, rows);
+    }
+}